@@ -0,0 +1,96 @@
+//! A cap on the file data the server holds in memory at once, across every
+//! connection, so a few large transfers at the same time can't exhaust it.
+//! It covers both what is read for sending and the frames peers send.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::metrics;
+
+/// Bytes of file data buffered at once by default.
+pub const DEFAULT_MAX_BUFFERED: usize = 64 * 1024 * 1024;
+
+/// Bytes of file data that may be buffered at once.
+///
+/// Clones share the same budget, so one can be handed to every connection.
+/// A budget created with [`Default`] never waits.
+#[derive(Clone, Debug, Default)]
+pub struct BufferBudget {
+    inner: Option<Arc<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    cap: usize,
+    permits: Arc<Semaphore>,
+    in_flight: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl BufferBudget {
+    pub fn new(cap: usize) -> Self {
+        // Semaphores count in u32 and can't hold more than MAX_PERMITS.
+        let cap = cap.clamp(1, u32::MAX as usize).min(Semaphore::MAX_PERMITS);
+        Self {
+            inner: Some(Arc::new(Inner {
+                cap,
+                permits: Arc::new(Semaphore::new(cap)),
+                in_flight: AtomicUsize::new(0),
+                peak: AtomicUsize::new(0),
+            })),
+        }
+    }
+
+    /// Wait until `bytes` more may be buffered, and count them as buffered
+    /// until the returned permit is dropped. Requests larger than the whole
+    /// budget wait for all of it.
+    pub async fn acquire(&self, bytes: usize) -> BufferPermit {
+        let Some(inner) = &self.inner else {
+            return BufferPermit { held: None };
+        };
+        let bytes = bytes.min(inner.cap);
+        // The semaphore is never closed.
+        let permit = inner
+            .permits
+            .clone()
+            .acquire_many_owned(bytes as u32)
+            .await
+            .expect("buffer budget semaphore closed");
+        let in_flight = inner.in_flight.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        inner.peak.fetch_max(in_flight, Ordering::Relaxed);
+        metrics::get().buffered_bytes.set(in_flight as i64);
+        BufferPermit {
+            held: Some((inner.clone(), permit, bytes)),
+        }
+    }
+
+    /// Bytes buffered right now.
+    pub fn in_flight(&self) -> usize {
+        self.inner
+            .as_ref()
+            .map_or(0, |inner| inner.in_flight.load(Ordering::Relaxed))
+    }
+
+    /// Most bytes that were ever buffered at once.
+    pub fn peak(&self) -> usize {
+        self.inner
+            .as_ref()
+            .map_or(0, |inner| inner.peak.load(Ordering::Relaxed))
+    }
+}
+
+/// Bytes counted against a [`BufferBudget`], given back when dropped.
+#[must_use]
+pub struct BufferPermit {
+    held: Option<(Arc<Inner>, OwnedSemaphorePermit, usize)>,
+}
+
+impl Drop for BufferPermit {
+    fn drop(&mut self) {
+        if let Some((inner, _, bytes)) = &self.held {
+            let in_flight = inner.in_flight.fetch_sub(*bytes, Ordering::Relaxed) - bytes;
+            metrics::get().buffered_bytes.set(in_flight as i64);
+        }
+    }
+}
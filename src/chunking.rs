@@ -0,0 +1,146 @@
+//! Content-defined chunking, so that regions several files share split into
+//! the same chunks wherever they sit in each file, and a chunk already
+//! received during a transfer can be copied locally instead of sent again.
+
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::protocol::ChunkRef;
+
+/// No chunk is cut shorter than this, except the last of a file.
+pub const MIN_CDC_CHUNK_LEN: usize = 16 * 1024;
+
+/// Chunks are cut where the rolling hash has this many low bits clear, so
+/// they average about 64 KiB.
+const MASK: u64 = (1 << 16) - 1;
+
+/// No chunk is longer than this. Not to be confused with
+/// [`protocol::MAX_CHUNK_LEN`](crate::protocol::MAX_CHUNK_LEN), which bounds
+/// the file data sent in one frame.
+pub const MAX_CDC_CHUNK_LEN: usize = 256 * 1024;
+
+/// Random values the rolling hash mixes in per byte, the same on every peer.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state = 0x5379_6e63_725f_6364u64;
+    let mut i = 0;
+    while i < 256 {
+        // splitmix64
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Split `data` into chunks whose boundaries depend only on the bytes
+/// around them.
+pub fn split(data: &[u8]) -> Vec<Range<usize>> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        let end = cut_point(&data[start..]) + start;
+        chunks.push(start..end);
+        start = end;
+    }
+    chunks
+}
+
+/// Length of the first chunk of `data`.
+fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_CDC_CHUNK_LEN {
+        return data.len();
+    }
+    let end = data.len().min(MAX_CDC_CHUNK_LEN);
+    let mut hash = 0u64;
+    for (i, &byte) in data[..end].iter().enumerate().skip(MIN_CDC_CHUNK_LEN) {
+        hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        if hash & MASK == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// The chunks of `data` as sent to a peer, with the range each covers.
+pub fn chunk_refs(data: &[u8]) -> Vec<(Range<usize>, ChunkRef)> {
+    split(data)
+        .into_iter()
+        .map(|range| {
+            let chunk = ChunkRef {
+                hash: *blake3::hash(&data[range.clone()]).as_bytes(),
+                len: range.len() as u32,
+            };
+            (range, chunk)
+        })
+        .collect()
+}
+
+/// Where a chunk can be read back from.
+#[derive(Debug, Clone)]
+struct Location {
+    path: PathBuf,
+    offset: u64,
+    len: u32,
+}
+
+/// Chunks of the files written so far in one transfer, by hash. Only their
+/// location is kept; the bytes are read back from the files when reused.
+#[derive(Debug, Clone, Default)]
+pub struct ChunkIndex {
+    chunks: Arc<Mutex<HashMap<[u8; 32], Location>>>,
+}
+
+impl ChunkIndex {
+    /// Remember the chunks of the file just written at `path`.
+    pub fn add(&self, path: &Path, chunks: &[ChunkRef]) {
+        let mut offset = 0;
+        for chunk in chunks {
+            self.insert(path, offset, chunk);
+            offset += u64::from(chunk.len);
+        }
+    }
+
+    /// Remember that `chunk` is at `offset` in the file at `path`, unless
+    /// it is already known elsewhere.
+    pub fn insert(&self, path: &Path, offset: u64, chunk: &ChunkRef) {
+        let mut index = self.chunks.lock().unwrap();
+        index.entry(chunk.hash).or_insert_with(|| Location {
+            path: path.to_path_buf(),
+            offset,
+            len: chunk.len,
+        });
+    }
+
+    pub fn contains(&self, hash: &[u8; 32]) -> bool {
+        self.chunks.lock().unwrap().contains_key(hash)
+    }
+
+    /// The bytes of the chunk with `hash`, or `None` if it isn't known or
+    /// the file it was in has changed since.
+    pub fn read(&self, hash: &[u8; 32]) -> Option<Vec<u8>> {
+        let location = self.chunks.lock().unwrap().get(hash)?.clone();
+        let read = || -> std::io::Result<Vec<u8>> {
+            let mut file = std::fs::File::open(&location.path)?;
+            file.seek(SeekFrom::Start(location.offset))?;
+            let mut data = vec![0; location.len as usize];
+            file.read_exact(&mut data)?;
+            Ok(data)
+        };
+        match read() {
+            Ok(data) if blake3::hash(&data).as_bytes() == hash => Some(data),
+            _ => {
+                self.chunks.lock().unwrap().remove(hash);
+                None
+            }
+        }
+    }
+}
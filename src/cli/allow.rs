@@ -1,12 +1,136 @@
-use crate::store::Store;
+use crate::{
+    store::{AccessMode, Grant, GroupGrant, Store},
+    sync_utils,
+};
 use anyhow::{Context, Result};
+use clap::ValueEnum;
 use iroh::PublicKey;
+use std::collections::BTreeMap;
 use std::path::PathBuf;
+use std::time::Duration;
 
-pub fn run_allow(store: &Store, peer: PublicKey, path: PathBuf) -> Result<()> {
+/// Access level granted by `syncr allow --mode`.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ModeArg {
+    /// Peer may list and download files
+    Read,
+    /// Peer may register syncs that push its changes here
+    Write,
+    /// Both read and write
+    ReadWrite,
+}
+
+impl From<ModeArg> for AccessMode {
+    fn from(mode: ModeArg) -> Self {
+        match mode {
+            ModeArg::Read => AccessMode::Read,
+            ModeArg::Write => AccessMode::Write,
+            ModeArg::ReadWrite => AccessMode::ReadWrite,
+        }
+    }
+}
+
+/// Parse a duration such as `90`, `30m`, `12h`, `7d` or `2w`. A bare number
+/// is seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (digits, unit) = match s.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('s') => (&s[..s.len() - 1], 1),
+        Some('m') => (&s[..s.len() - 1], 60),
+        Some('h') => (&s[..s.len() - 1], 60 * 60),
+        Some('d') => (&s[..s.len() - 1], 24 * 60 * 60),
+        Some('w') => (&s[..s.len() - 1], 7 * 24 * 60 * 60),
+        _ => (s, 1),
+    };
+    let value: u64 = digits
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid duration '{}', expected e.g. 30m, 12h or 7d", s))?;
+    if value == 0 {
+        return Err("duration must be greater than zero".to_string());
+    }
+    value
+        .checked_mul(unit)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration '{}' is too large", s))
+}
+
+/// `secs` as its two largest units, e.g. `2d 3h` or `5m 10s`.
+pub(crate) fn format_duration(secs: u64) -> String {
+    let units = [("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60), ("s", 1)];
+    let parts: Vec<String> = units
+        .iter()
+        .scan(secs, |left, &(name, size)| {
+            let count = *left / size;
+            *left %= size;
+            Some((count, name))
+        })
+        .skip_while(|(count, _)| *count == 0)
+        .take(2)
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, name)| format!("{}{}", count, name))
+        .collect();
+    if parts.is_empty() {
+        "0s".to_string()
+    } else {
+        parts.join(" ")
+    }
+}
+
+/// How a grant's mode is listed, with the time it has left if it expires.
+fn describe_grant(mode: AccessMode, expires_at: Option<u64>, now: u64) -> String {
+    match expires_at {
+        Some(at) if at <= now => format!("{} (expired)", mode),
+        Some(at) => format!("{} (expires in {})", mode, format_duration(at - now)),
+        None => mode.to_string(),
+    }
+}
+
+pub fn run_allow(
+    store: &Store,
+    peer: PublicKey,
+    path: PathBuf,
+    mode: ModeArg,
+    expires: Option<Duration>,
+) -> Result<()> {
     let abs_path = std::fs::canonicalize(&path).context("Failed to resolve path")?;
-    store.allow_peer(&abs_path, peer)?;
-    println!("Allowed peer {} for path {:?}", peer, abs_path);
+    let mode = AccessMode::from(mode);
+    let expires_at = expires.map(|d| sync_utils::unix_timestamp().saturating_add(d.as_secs()));
+    store.allow_peer_until(&abs_path, peer, mode, expires_at)?;
+    match expires {
+        Some(d) => println!(
+            "Allowed peer {} ({}) for path {:?} for {}",
+            peer,
+            mode,
+            abs_path,
+            format_duration(d.as_secs())
+        ),
+        None => println!("Allowed peer {} ({}) for path {:?}", peer, mode, abs_path),
+    }
+    Ok(())
+}
+
+pub fn run_allow_group(
+    store: &Store,
+    group: String,
+    path: PathBuf,
+    mode: ModeArg,
+    expires: Option<Duration>,
+) -> Result<()> {
+    let abs_path = std::fs::canonicalize(&path).context("Failed to resolve path")?;
+    let mode = AccessMode::from(mode);
+    let expires_at = expires.map(|d| sync_utils::unix_timestamp().saturating_add(d.as_secs()));
+    store.allow_group_until(&abs_path, &group, mode, expires_at)?;
+    match expires {
+        Some(d) => println!(
+            "Allowed group {} ({}) for path {:?} for {}",
+            group,
+            mode,
+            abs_path,
+            format_duration(d.as_secs())
+        ),
+        None => println!("Allowed group {} ({}) for path {:?}", group, mode, abs_path),
+    }
     Ok(())
 }
 
@@ -16,3 +140,96 @@ pub fn run_disallow(store: &Store, peer: PublicKey, path: PathBuf) -> Result<()>
     println!("Disallowed peer {} for path {:?}", peer, abs_path);
     Ok(())
 }
+
+pub fn run_disallow_all(store: &Store, peer: PublicKey) -> Result<()> {
+    let removed = store.disallow_peer_everywhere(peer)?;
+    println!(
+        "Disallowed peer {} from {} paths and removed it from its groups",
+        peer, removed
+    );
+    Ok(())
+}
+
+/// Print every grant in the store, grouped by peer or, with `by_path`, by
+/// path, followed by the grants held by groups. Peers are shown with their
+/// alias when one is defined.
+pub fn run_list_access(store: &Store, by_path: bool) -> Result<()> {
+    let permissions = store.list_all_permissions()?;
+    let group_permissions = store.list_all_group_permissions()?;
+    if permissions.iter().all(|(_, grants)| grants.is_empty())
+        && group_permissions
+            .iter()
+            .all(|(_, grants)| grants.is_empty())
+    {
+        println!("No peers have been allowed access.");
+        return Ok(());
+    }
+
+    let aliases: BTreeMap<PublicKey, String> = store
+        .list_aliases()?
+        .into_iter()
+        .map(|(name, peer)| (peer, name))
+        .collect();
+    let now = sync_utils::unix_timestamp();
+    let describe = |peer: &PublicKey| match aliases.get(peer) {
+        Some(name) => format!("{} ({})", peer, name),
+        None => peer.to_string(),
+    };
+
+    if by_path {
+        for (path, grants) in permissions {
+            if grants.is_empty() {
+                continue;
+            }
+            println!("{}", path.display());
+            for grant in grants {
+                println!(
+                    "    {}\t{}",
+                    describe_grant(grant.mode, grant.expires_at, now),
+                    describe(&grant.peer)
+                );
+            }
+        }
+    } else {
+        let mut by_peer: BTreeMap<PublicKey, Vec<(PathBuf, Grant)>> = BTreeMap::new();
+        for (path, grants) in permissions {
+            for grant in grants {
+                by_peer
+                    .entry(grant.peer)
+                    .or_default()
+                    .push((path.clone(), grant));
+            }
+        }
+        for (peer, paths) in by_peer {
+            println!("{}", describe(&peer));
+            for (path, grant) in paths {
+                println!(
+                    "    {}\t{}",
+                    describe_grant(grant.mode, grant.expires_at, now),
+                    path.display()
+                );
+            }
+        }
+    }
+
+    let mut by_group: BTreeMap<String, Vec<(PathBuf, GroupGrant)>> = BTreeMap::new();
+    for (path, grants) in group_permissions {
+        for grant in grants {
+            by_group
+                .entry(grant.group.clone())
+                .or_default()
+                .push((path.clone(), grant));
+        }
+    }
+    for (group, paths) in by_group {
+        println!("group {}", group);
+        for (path, grant) in paths {
+            println!(
+                "    {}\t{}",
+                describe_grant(grant.mode, grant.expires_at, now),
+                path.display()
+            );
+        }
+    }
+    Ok(())
+}
@@ -1,209 +1,2685 @@
 use anyhow::{Context, Result};
+use clap::ValueEnum;
+use indicatif::{HumanBytes, ProgressBar};
 use iroh::{
-    discovery::{dns::DnsDiscovery, mdns::MdnsDiscovery, pkarr::PkarrPublisher},
+    endpoint::{Connection, RecvStream, SendStream},
     Endpoint, PublicKey,
 };
-use std::path::PathBuf;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tracing::info;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::OsString;
+use std::io::SeekFrom;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use walkdir::WalkDir;
 
 use crate::{
-    iroh_utils,
-    protocol::{FileMetadata, Message, ALPN},
-    sync_utils,
+    chunking::ChunkIndex,
+    cli::{
+        allow::format_duration,
+        exit::{CommandError, ErrorKind},
+    },
+    config::{self, Config},
+    iroh_utils, metrics, path_lock,
+    progress::{self, Progress},
+    protocol::{
+        from_wire_path, from_wire_relative, is_hidden, is_included, join_wire_path,
+        leads_to_included, read_message, strip_wire_prefix, to_wire_path, trim_wire_path,
+        write_message, Capabilities, ErrorCode, FileMetadata, FileType, ManifestAction, Message,
+        ProtocolError, RemoteError, CAP_CHUNKS, MAX_CHUNK_LEN,
+    },
+    rate_limit::RateLimiter,
+    store::{
+        Checkpointed, ConflictPolicy, Direction, SignatureStamp, Store, SyncLogEntry, SyncOutcome,
+        SYNC_LOG_PATHS,
+    },
+    sync_manager::SyncEvent,
+    sync_utils::{self, HashQueue, SharedBytes},
+    trash::{Retention, Trash, TRASH_DIR},
+    watcher::EventMask,
 };
 
-pub async fn run(peer: PublicKey, remote_path: String, local_path: PathBuf) -> Result<()> {
-    let secret_key = iroh_utils::load_secret_key().await?;
-    let endpoint = Endpoint::builder()
-        .discovery(PkarrPublisher::n0_dns())
-        .discovery(DnsDiscovery::n0_dns())
-        .discovery(MdnsDiscovery::builder())
-        .secret_key(secret_key)
-        .alpns(vec![ALPN.to_vec()])
-        .bind()
-        .await?;
+/// Extension of files being downloaded, until they are complete.
+const PARTIAL_EXTENSION: &str = "syncr-partial";
 
+/// Number of files transferred concurrently when not overridden.
+pub const DEFAULT_JOBS: usize = 4;
+
+/// Times a file that failed to sync is tried again when not overridden.
+pub const DEFAULT_RETRIES: u32 = 2;
+
+/// Wait before the first retry of a file; it doubles with each further one.
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Bytes of a large file patched per signature and delta when not
+/// overridden. Files no larger are patched in one piece.
+pub const DEFAULT_SEGMENT_LEN: u64 = 8 * 1024 * 1024;
+
+/// Local path that writes a single remote file to stdout instead.
+pub const STDOUT_PATH: &str = "-";
+
+/// Returned by a copy stopped through [`CopyOptions::cancel`].
+#[derive(Debug, thiserror::Error)]
+#[error("Copy was cancelled")]
+pub struct Cancelled;
+
+/// What to do about a local file that differs from the one being copied.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Overwrite {
+    /// Replace it
+    #[default]
+    Always,
+    /// Keep it and skip the incoming file
+    Never,
+    /// Ask on the terminal, showing both versions
+    Ask,
+}
+
+/// How a copy reports its progress as it goes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ProgressFormat {
+    /// Bars on stderr, if `progress` is set
+    #[default]
+    Bars,
+    /// A JSON object per line on stdout for each file started and done
+    Ndjson,
+}
+
+/// One line of [`ProgressFormat::Ndjson`] output.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent<'a> {
+    FileStarted {
+        path: &'a str,
+        len: u64,
+    },
+    /// `bytes` is what was received for the file, less than its length if
+    /// it was patched
+    FileDone {
+        path: &'a str,
+        bytes: u64,
+    },
+    FileFailed {
+        path: &'a str,
+        error: String,
+    },
+    Summary(&'a SyncStats),
+}
+
+impl ProgressEvent<'_> {
+    /// Write the event to stdout as a line of JSON.
+    pub fn print(&self) -> Result<()> {
+        use std::io::Write;
+        let line = serde_json::to_string(self)?;
+        writeln!(std::io::stdout().lock(), "{}", line)?;
+        Ok(())
+    }
+}
+
+/// Options controlling how a copy is performed and reported.
+#[derive(Debug, Clone)]
+pub struct CopyOptions {
+    /// Render progress bars on stderr
+    pub progress: bool,
+    /// Whether progress is drawn as bars or printed as events
+    pub progress_format: ProgressFormat,
+    /// Report what would change without touching the local filesystem
+    pub dry_run: bool,
+    /// Maximum number of files transferred at once, each on its own stream
+    pub jobs: usize,
+    /// Bandwidth cap for the whole copy in bytes per second
+    pub limit_rate: Option<u64>,
+    /// Hash the local file had after the last sync of a single-file path.
+    /// If the file has changed since, `on_conflict` decides which version
+    /// is kept.
+    pub base_hash: Option<[u8; 32]>,
+    /// What to do with a local file changed since the last sync
+    pub on_conflict: ConflictPolicy,
+    /// rsync block size for delta transfers, instead of one picked from the
+    /// file size
+    pub block_size: Option<u32>,
+    /// Bytes of each block's strong hash kept in signatures
+    pub strong_hash_size: u32,
+    /// Store to cache local file signatures in, so unchanged files aren't
+    /// re-read to compute one on every sync
+    pub store: Option<Store>,
+    /// Store to record each completed file of a directory in, so that the
+    /// same copy run again after an interruption skips those still as it
+    /// left them. Cleared once the copy completes.
+    pub checkpoints: Option<Store>,
+    /// Compute every local signature afresh, like rsync's `--checksum`,
+    /// rather than trusting one cached for a file of the same size and
+    /// modification time, which misses changes made without touching either
+    pub checksum: bool,
+    /// Copy what symlinks point to instead of recreating the links
+    pub follow_symlinks: bool,
+    /// Recreate symlinks even if they point outside the local root
+    pub allow_external_links: bool,
+    /// Move local files into the trash directory before overwriting them,
+    /// keeping backups for this long
+    pub backup: Option<Retention>,
+    /// Only copy these wire paths relative to the remote path, and the
+    /// directories leading to them; empty means everything
+    pub include: Vec<String>,
+    /// Copy files and directories whose names start with a dot, which are
+    /// skipped otherwise
+    pub include_hidden: bool,
+    /// Leave out remote files larger than this many bytes
+    pub max_size: Option<u64>,
+    /// Whether local files that differ may be replaced
+    pub overwrite: Overwrite,
+    /// For a sync, whether changes are watched for below the top level of
+    /// the local path
+    pub watch_recursive: bool,
+    /// For a sync, how often the local path is also rescanned for changes
+    /// the file system didn't report
+    pub watch_poll_interval: Option<Duration>,
+    /// For a sync, the kinds of local change that notify the peer
+    pub watch_events: EventMask,
+    /// For a sync, set up the watch and reverse sync on a local path that
+    /// already matches the remote one, without copying anything first
+    pub watch_only: bool,
+    /// Where to report files written and conflicts found, for pulls done by
+    /// the daemon
+    pub events: Option<broadcast::Sender<SyncEvent>>,
+    /// Send the local tree's manifest and let the server answer with only
+    /// what differs, instead of listing everything. Needs protocol version
+    /// 4; older peers are asked for a listing.
+    pub manifest: bool,
+    /// Files larger than this on either side are patched this many bytes at
+    /// a time, each range with its own signature and delta, on peers
+    /// speaking protocol version 5
+    pub segment_len: u64,
+    /// Remote files smaller than this are downloaded whole rather than
+    /// patched
+    pub delta_min_len: u64,
+    /// Times a file that failed to sync is tried again, waiting longer
+    /// before each. Errors the peer reported aren't retried unless it asked
+    /// for that.
+    pub retries: u32,
+    /// Flush each written file and its directory to disk before counting it
+    /// as synced
+    pub fsync: bool,
+    /// Download new files as content-defined chunks, copying chunks already
+    /// received for another file of the same copy instead of fetching them
+    /// again. Costs hashing on both sides and needs a peer with the
+    /// `chunks` capability.
+    pub dedup: bool,
+    /// Remove local files the remote no longer has. Needs `manifest`.
+    pub delete: bool,
+    /// Largest share of the local files `delete` removes without `force`
+    pub max_delete: f64,
+    /// After `delete` removed entries, remove the directories below the
+    /// local path they left empty as well
+    pub prune_empty_dirs: bool,
+    /// Delete however many files the remote lost
+    pub force: bool,
+    /// List deletions over `max_delete` on the terminal and ask, rather than
+    /// refusing them
+    pub confirm_deletes: bool,
+    /// Stops the copy once cancelled: files under way stop after the chunk
+    /// in hand, no more are started, and the copy fails with [`Cancelled`]
+    pub cancel: CancellationToken,
+    /// Leave what a cancelled copy downloaded of a file for the next copy to
+    /// resume from, rather than removing it
+    pub keep_partial: bool,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        Self {
+            progress: false,
+            progress_format: ProgressFormat::default(),
+            dry_run: false,
+            jobs: DEFAULT_JOBS,
+            limit_rate: None,
+            base_hash: None,
+            on_conflict: ConflictPolicy::default(),
+            block_size: None,
+            strong_hash_size: sync_utils::DEFAULT_STRONG_HASH_SIZE,
+            store: None,
+            checkpoints: None,
+            checksum: false,
+            follow_symlinks: false,
+            allow_external_links: false,
+            backup: None,
+            include: Vec::new(),
+            include_hidden: false,
+            max_size: None,
+            overwrite: Overwrite::default(),
+            watch_recursive: true,
+            watch_poll_interval: None,
+            watch_events: EventMask::ALL,
+            watch_only: false,
+            events: None,
+            manifest: false,
+            segment_len: DEFAULT_SEGMENT_LEN,
+            delta_min_len: config::DEFAULT_DELTA_MIN_LEN,
+            retries: DEFAULT_RETRIES,
+            fsync: false,
+            dedup: false,
+            delete: false,
+            max_delete: config::DEFAULT_MAX_DELETE,
+            prune_empty_dirs: false,
+            force: false,
+            confirm_deletes: false,
+            cancel: CancellationToken::new(),
+            keep_partial: false,
+        }
+    }
+}
+
+/// Outcome of a successful copy.
+#[derive(Debug, Clone, Default)]
+pub struct CopyReport {
+    /// Hash of the copied file when the remote path was a single file
+    pub hash: Option<[u8; 32]>,
+    /// Files whose signature was taken from the cache instead of computed
+    pub signatures_reused: usize,
+    /// Local files whose contents were written, in path order
+    pub changed: Vec<PathBuf>,
+    /// Local entries removed because the remote no longer has them
+    pub deleted: Vec<PathBuf>,
+    /// Files an interrupted run of the same copy had completed, skipped
+    /// without being read again
+    pub resumed: usize,
+    /// Remote entries the peer couldn't read, and so left out of its listing
+    pub unlisted: u64,
+    /// What the copy transferred and how long it took
+    pub stats: SyncStats,
+}
+
+impl CopyReport {
+    /// This copy as a pull in the log of the sync of `remote_path` from
+    /// `peer`.
+    pub fn log_entry(&self, peer: PublicKey, remote_path: &str) -> SyncLogEntry {
+        SyncLogEntry {
+            peer,
+            remote_path: remote_path.to_string(),
+            timestamp: sync_utils::unix_timestamp(),
+            direction: Direction::Pull,
+            bytes: self.stats.bytes_transferred,
+            files: self.changed.len() as u64,
+            changed: self
+                .changed
+                .iter()
+                .take(SYNC_LOG_PATHS)
+                .map(|path| path.display().to_string())
+                .collect(),
+            conflicts: self.stats.conflicts as u64,
+            outcome: SyncOutcome::Completed,
+        }
+    }
+}
+
+/// How one pair of a copy or sync of several went.
+#[derive(Debug)]
+pub struct PairReport {
+    pub remote_path: String,
+    pub local_path: PathBuf,
+    pub result: Result<CopyReport>,
+}
+
+/// Counters for a copy, summed over every file it went through.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct SyncStats {
+    /// Files written from a delta or a download
+    pub files_transferred: usize,
+    /// Files left alone because they were already identical
+    pub files_skipped: usize,
+    /// Files left out because they are larger than `max_size`
+    pub files_too_large: usize,
+    /// Incoming files written beside a locally changed one instead of over
+    /// it
+    pub conflicts: usize,
+    /// Delta and file data received from the peer
+    pub bytes_transferred: u64,
+    /// Bytes of written files that didn't have to be sent, thanks to a delta
+    /// or to chunks received earlier in the copy
+    pub bytes_saved: u64,
+    /// Wall-clock time the copy took
+    #[serde(rename = "elapsed_secs", serialize_with = "serialize_secs")]
+    pub elapsed: Duration,
+}
+
+impl SyncStats {
+    fn add(&mut self, other: &SyncStats) {
+        self.files_transferred += other.files_transferred;
+        self.files_skipped += other.files_skipped;
+        self.files_too_large += other.files_too_large;
+        self.conflicts += other.conflicts;
+        self.bytes_transferred += other.bytes_transferred;
+        self.bytes_saved += other.bytes_saved;
+    }
+
+    /// Print a one-line summary, or the counters as JSON.
+    pub fn print(&self, json: bool) -> Result<()> {
+        if json {
+            println!("{}", serde_json::to_string(self)?);
+            return Ok(());
+        }
+        // Only mentioned when some were left out, as most copies have no
+        // size limit.
+        let too_large = match self.files_too_large {
+            0 => String::new(),
+            n => format!(", {} over the size limit", n),
+        };
+        println!(
+            "{} transferred, {} up to date{}, {} received, {} saved by deltas, in {:.1}s",
+            self.files_transferred,
+            self.files_skipped,
+            too_large,
+            HumanBytes(self.bytes_transferred),
+            HumanBytes(self.bytes_saved),
+            self.elapsed.as_secs_f64()
+        );
+        Ok(())
+    }
+}
+
+fn serialize_secs<S: serde::Serializer>(elapsed: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_f64(elapsed.as_secs_f64())
+}
+
+/// What one transfer worker got through.
+#[derive(Default)]
+struct WorkerOutcome {
+    /// Local files written, with their hashes
+    synced: Vec<(PathBuf, [u8; 32])>,
+    failures: Vec<(String, anyhow::Error)>,
+    signatures_reused: usize,
+    changed: Vec<PathBuf>,
+    stats: SyncStats,
+    resumed: usize,
+    /// Whether the worker stopped because the copy was cancelled
+    cancelled: bool,
+}
+
+impl WorkerOutcome {
+    /// Count what `session` did towards the outcome.
+    fn absorb(&mut self, mut session: Session) {
+        self.signatures_reused += session.signatures_reused;
+        self.changed.append(&mut session.changed);
+        self.stats.add(&session.stats);
+    }
+}
+
+/// How far a [`Session`] had got, to tell what a transfer added.
+#[derive(Clone, Copy, Default)]
+struct SessionMark {
+    changed: usize,
+    bytes: u64,
+}
+
+impl SessionMark {
+    fn of(session: &Session) -> Self {
+        Self {
+            changed: session.changed.len(),
+            bytes: session.stats.bytes_transferred,
+        }
+    }
+}
+
+/// A file to transfer.
+struct PendingTransfer {
+    file: FileMetadata,
+    /// Where it goes locally
+    target: PathBuf,
+    /// Hash the target had after the last sync, if known
+    base_hash: Option<[u8; 32]>,
+    /// Download it whole even if there is a local file to patch
+    whole: bool,
+}
+
+/// Files still waiting for a worker.
+type TransferQueue = Arc<Mutex<VecDeque<PendingTransfer>>>;
+
+/// What the transfer workers of one copy share.
+#[derive(Clone)]
+struct Workers {
+    queue: TransferQueue,
+    progress: Progress,
+    limiter: RateLimiter,
+    trash: Option<Trash>,
+    /// Chunks of the files written so far, when deduplicating
+    chunks: Option<ChunkIndex>,
+    checkpoint: Option<Checkpoint>,
+}
+
+/// The files of a copy completed so far, kept in the store so an
+/// interrupted copy picks up where it left off.
+#[derive(Clone)]
+struct Checkpoint {
+    store: Store,
+    local_path: PathBuf,
+    peer: PublicKey,
+    remote_path: String,
+    /// What earlier runs completed
+    done: Arc<HashMap<String, Checkpointed>>,
+}
+
+impl Checkpoint {
+    fn load(store: &Store, local_path: &Path, peer: PublicKey, remote_path: &str) -> Result<Self> {
+        Ok(Self {
+            done: Arc::new(store.checkpoint(local_path, peer, remote_path)?),
+            store: store.clone(),
+            local_path: local_path.to_path_buf(),
+            peer,
+            remote_path: remote_path.to_string(),
+        })
+    }
+
+    /// The hash of `transfer`'s file if an earlier run completed it and
+    /// neither it nor the local copy changed since.
+    fn completed(&self, transfer: &PendingTransfer) -> Option<[u8; 32]> {
+        let done = self.done.get(&transfer.file.path)?;
+        let stamp = signature_stamp(&transfer.target, None).ok()?;
+        (transfer.file.hash == Some(done.hash)
+            && stamp.len == done.len
+            && stamp.modified_nanos == done.modified_nanos)
+            .then_some(done.hash)
+    }
+
+    /// Note that `transfer` left its target with the contents `hash`. Only
+    /// files whose remote hash is known can be checked later.
+    fn record(&self, transfer: &PendingTransfer, hash: [u8; 32]) -> Result<()> {
+        if transfer.file.hash != Some(hash) {
+            return Ok(());
+        }
+        let stamp = signature_stamp(&transfer.target, None)?;
+        let done = Checkpointed {
+            hash,
+            len: stamp.len,
+            modified_nanos: stamp.modified_nanos,
+        };
+        self.store.checkpoint_file(
+            &self.local_path,
+            self.peer,
+            &self.remote_path,
+            &transfer.file.path,
+            done,
+        )?;
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<()> {
+        Ok(self
+            .store
+            .clear_checkpoint(&self.local_path, self.peer, &self.remote_path)?)
+    }
+}
+
+pub async fn run(
+    config: &Config,
+    peer: PublicKey,
+    remote_path: String,
+    local_path: PathBuf,
+    opts: CopyOptions,
+) -> Result<CopyReport> {
+    let endpoint = iroh_utils::build_endpoint(config).await?;
+    if let Some(store) = &opts.store {
+        iroh_utils::add_known_addresses(&endpoint, store.list_addresses()?);
+    }
+    run_with(&endpoint, config, peer, remote_path, local_path, opts).await
+}
+
+/// Like [`run`], but over an existing endpoint.
+pub async fn run_with(
+    endpoint: &Endpoint,
+    config: &Config,
+    peer: PublicKey,
+    remote_path: String,
+    local_path: PathBuf,
+    opts: CopyOptions,
+) -> Result<CopyReport> {
     info!("Connecting to {}...", peer);
 
     // Connect to the peer
-    let connection = endpoint.connect(peer, ALPN).await?;
+    let connection = iroh_utils::connect(endpoint, config.network(), peer).await?;
     info!("Connected!");
 
-    // Open a bi-directional stream
-    let (mut send, mut recv) = connection.open_bi().await?;
+    run_on(&connection, config, remote_path, local_path, opts).await
+}
 
-    // 1. Handshake
-    let handshake = Message::Handshake { version: 1 };
-    write_message(&mut send, &handshake).await?;
+/// Like [`run`], for each `(remote_path, local_path)` of `pairs`, over one
+/// connection.
+pub async fn run_pairs(
+    config: &Config,
+    peer: PublicKey,
+    pairs: Vec<(String, PathBuf)>,
+    opts: CopyOptions,
+) -> Result<Vec<PairReport>> {
+    let endpoint = iroh_utils::build_endpoint(config).await?;
+    if let Some(store) = &opts.store {
+        iroh_utils::add_known_addresses(&endpoint, store.list_addresses()?);
+    }
+    run_pairs_with(&endpoint, config, peer, pairs, opts).await
+}
 
-    let msg = read_message(&mut recv).await?;
-    match msg {
-        Message::Handshake { version } => {
-            info!("Handshake received from server: version {}", version);
+/// Copy each `(remote_path, local_path)` of `pairs` from `peer` in turn,
+/// over a single connection. A pair that fails doesn't stop the rest.
+pub async fn run_pairs_with(
+    endpoint: &Endpoint,
+    config: &Config,
+    peer: PublicKey,
+    pairs: Vec<(String, PathBuf)>,
+    opts: CopyOptions,
+) -> Result<Vec<PairReport>> {
+    info!("Connecting to {}...", peer);
+    let connection = iroh_utils::connect(endpoint, config.network(), peer).await?;
+    info!("Connected!");
+
+    let mut reports = Vec::new();
+    for (remote_path, local_path) in pairs {
+        let result = run_on(
+            &connection,
+            config,
+            remote_path.clone(),
+            local_path.clone(),
+            opts.clone(),
+        )
+        .await;
+        reports.push(PairReport {
+            remote_path,
+            local_path,
+            result,
+        });
+    }
+    Ok(reports)
+}
+
+/// Parse a `--pair` of a remote and a local path, written `REMOTE:LOCAL`.
+/// The remote path ends at the first colon.
+pub fn parse_pair(s: &str) -> Result<(String, PathBuf), String> {
+    match s.split_once(':') {
+        Some((remote, local)) if !remote.is_empty() && !local.is_empty() => {
+            Ok((remote.to_string(), PathBuf::from(local)))
         }
-        _ => anyhow::bail!("Expected handshake, got {:?}", msg),
+        _ => Err(format!(
+            "invalid pair '{}', expected REMOTE:LOCAL, e.g. /srv/docs:docs",
+            s
+        )),
     }
+}
 
-    // Determine if we need directory list or single file
-    // Strategy: Request listing for path. If it's a file, we get 1 entry. If dir, many.
-    // If it fails (path not found), we error.
+/// Like [`run`], but over a connection already open to the peer, e.g. the
+/// one it sent a change notification on. Each transfer opens its own
+/// streams and leaves the connection open.
+pub async fn run_on(
+    connection: &Connection,
+    config: &Config,
+    remote_path: String,
+    local_path: PathBuf,
+    opts: CopyOptions,
+) -> Result<CopyReport> {
+    let started = Instant::now();
+    // Entries are matched against the path as the server lists them.
+    let remote_path = trim_wire_path(&remote_path).to_string();
 
-    info!("Requesting file listing for {}", remote_path);
-    let list_req = Message::ListRequest {
-        path: remote_path.clone(),
+    // Open a bi-directional stream for the listing
+    let mut session = Session::open(connection, config).await?;
+
+    if local_path == Path::new(STDOUT_PATH) {
+        if opts.progress_format == ProgressFormat::Ndjson {
+            anyhow::bail!("Progress events can't be printed while the file is written to stdout");
+        }
+        let hash = stream_file(&mut session, &remote_path, &mut tokio::io::stdout(), &opts).await?;
+        session.send.finish()?;
+        return Ok(CopyReport {
+            hash: Some(hash),
+            ..Default::default()
+        });
+    }
+
+    // The listing of a file is just that file; of a directory, the
+    // directory and everything below it. The type the server gives
+    // remote_path itself decides which one this is.
+
+    // Files in the manifest that the server doesn't mention are up to date.
+    let mut unmentioned = HashSet::new();
+    // The server may only have us delete what the manifest listed.
+    let mut sent = HashMap::new();
+    let mut local_files = 0;
+    let mut manifest = opts.manifest && session.version >= 4;
+    if manifest {
+        let files = local_manifest(&remote_path, &local_path, config.hash_concurrency).await?;
+        info!(
+            "Sending a manifest of {} local entries for {}",
+            files.len(),
+            remote_path
+        );
+        local_files = files
+            .iter()
+            .filter(|f| f.file_type != FileType::Dir)
+            .count();
+        unmentioned.extend(
+            files
+                .iter()
+                .filter(|f| f.file_type == FileType::File)
+                .map(|f| f.path.clone()),
+        );
+        sent.extend(files.iter().map(|f| (f.path.clone(), f.clone())));
+        let manifest_req = Message::ManifestRequest {
+            path: remote_path.clone(),
+            files,
+            follow_symlinks: opts.follow_symlinks,
+        };
+        // Nothing is sent of a manifest too large for a frame.
+        match write_message(&mut session.send, &manifest_req).await {
+            Ok(()) => {}
+            Err(ProtocolError::FrameTooLarge(len)) => {
+                info!(
+                    "A manifest of {} bytes is too large to send, requesting a full listing",
+                    len
+                );
+                (local_files, manifest) = (0, false);
+                unmentioned.clear();
+                sent.clear();
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if !manifest {
+        if opts.manifest && session.version < 4 {
+            info!("Peer predates manifests, requesting a full listing");
+        }
+        info!("Requesting file listing for {}", remote_path);
+        // With hashes, files that are already identical aren't transferred at all.
+        let list_req = Message::ListRequest {
+            path: remote_path.clone(),
+            with_hashes: true,
+            follow_symlinks: opts.follow_symlinks,
+        };
+        session.write(&list_req).await?;
+    }
+
+    // The listing may arrive in several parts. Directories are created as
+    // they are listed so that files can then be transferred by any worker,
+    // in any order. Hard links wait until the files they name are in place.
+    let mut pending = VecDeque::new();
+    let mut hard_links = Vec::new();
+    let mut doomed = Vec::new();
+    let mut root_type = None;
+    let mut too_large = 0;
+    let mut listed = 0;
+    let mut unlisted = 0;
+    loop {
+        let msg = session.read().await?.into_current();
+        let (entries, is_last) = match msg {
+            Message::ListSkipped { count } => {
+                unlisted += count;
+                continue;
+            }
+            Message::ListResponse { files, is_last } => (
+                files
+                    .into_iter()
+                    .map(|file| (file, ManifestAction::Create))
+                    .collect::<Vec<_>>(),
+                is_last,
+            ),
+            Message::ManifestResponse { entries, is_last } => (
+                entries.into_iter().map(|e| (e.file, e.action)).collect(),
+                is_last,
+            ),
+            Message::Error { code, message } => return Err(RemoteError { code, message }.into()),
+            _ => anyhow::bail!("Unexpected message: {:?}", msg),
+        };
+        listed += entries.len();
+        for (file, action) in entries {
+            unmentioned.remove(&file.path);
+            if file.path == remote_path {
+                root_type = Some(file.file_type);
+            } else if root_type.is_some_and(|t| t != FileType::Dir) {
+                warn!(
+                    "Skipping {}, listed along with the file {}",
+                    file.path, remote_path
+                );
+                continue;
+            }
+            if action == ManifestAction::Delete {
+                // Deleted as we listed it, whatever the server says it is.
+                let Some(ours) = sent.remove(&file.path) else {
+                    warn!("Not deleting {}, which we didn't list", file.path);
+                    continue;
+                };
+                let selected = strip_wire_prefix(&ours.path, &remote_path)
+                    .is_some_and(|relative| is_selected(relative, &opts));
+                if opts.delete && selected {
+                    doomed.push(ours);
+                } else {
+                    info!("Keeping {}, which the remote doesn't have", file.path);
+                }
+                continue;
+            }
+            let has_contents = matches!(file.file_type, FileType::File | FileType::HardLink);
+            if has_contents && opts.max_size.is_some_and(|max| file.len > max) {
+                let selected = strip_wire_prefix(&file.path, &remote_path)
+                    .is_some_and(|relative| is_selected(relative, &opts));
+                if selected {
+                    info!("Leaving out {}, which is {} bytes", file.path, file.len);
+                    too_large += 1;
+                }
+                continue;
+            }
+            plan_entry(
+                file,
+                &remote_path,
+                &local_path,
+                &opts,
+                action == ManifestAction::Full,
+                &mut pending,
+                &mut hard_links,
+            )?;
+        }
+        if is_last {
+            break;
+        }
+    }
+
+    info!("Received listing with {} files", listed);
+    if opts.cancel.is_cancelled() {
+        return Err(Cancelled.into());
+    }
+    if unlisted > 0 {
+        // The rest is still worth having.
+        warn!(
+            "{} entries of {} could not be listed by the peer and are not copied",
+            unlisted, remote_path
+        );
+    }
+    if !doomed.is_empty() {
+        approve_deletions(&doomed, local_files, &opts).await?;
+    }
+    // Checkpoint stamps are trusted like cached signatures, so a full
+    // checksum run doesn't use them.
+    let checkpoint = match &opts.checkpoints {
+        Some(store) if !opts.dry_run && !opts.checksum => Some(Checkpoint::load(
+            store,
+            &local_path,
+            session.peer,
+            &remote_path,
+        )?),
+        _ => None,
     };
-    write_message(&mut send, &list_req).await?;
+    if listed == 0 {
+        if manifest {
+            info!("{} is up to date", local_path.display());
+            if let Some(checkpoint) = &checkpoint {
+                checkpoint.clear()?;
+            }
+            session.send.finish()?;
+            return Ok(CopyReport {
+                stats: SyncStats {
+                    files_skipped: unmentioned.len(),
+                    files_too_large: too_large,
+                    elapsed: started.elapsed(),
+                    ..Default::default()
+                },
+                unlisted,
+                ..Default::default()
+            });
+        }
+        // Even an empty directory lists itself.
+        return Err(CommandError::new(
+            ErrorKind::NotFound,
+            format!("Nothing found at {} on the peer", remote_path),
+        )
+        .into());
+    }
+    session.send.finish()?;
+
+    // A single-file sync keeps its backups beside the file.
+    let trash = opts.backup.map(|retention| {
+        let single_file = matches!(root_type, Some(FileType::File | FileType::HardLink));
+        let root = match local_path.parent().filter(|_| single_file) {
+            Some(parent) => parent.to_path_buf(),
+            None => local_path.clone(),
+        };
+        Trash::new(root, retention)
+    });
 
-    let msg = read_message(&mut recv).await?;
-    let files: Vec<FileMetadata> = match msg {
-        Message::ListResponse { files } => files,
-        Message::Error { message } => anyhow::bail!("Remote error: {}", message),
-        _ => anyhow::bail!("Unexpected message: {:?}", msg),
+    let dedup = opts.dedup && session.capabilities.contains(CAP_CHUNKS);
+    if opts.dedup && !dedup {
+        info!("Peer doesn't support chunked downloads, downloading files whole");
+    }
+    let total_files = pending.len();
+    let progress = Progress::new(opts.progress, total_files as u64);
+    let jobs = opts.jobs.clamp(1, total_files.max(1));
+    let shared = Workers {
+        queue: Arc::new(Mutex::new(pending)),
+        progress: progress.clone(),
+        limiter: RateLimiter::new(opts.limit_rate),
+        trash: trash.clone(),
+        chunks: dedup.then(ChunkIndex::default),
+        checkpoint: checkpoint.clone(),
     };
 
-    info!("Received listing with {} files", files.len());
+    let mut workers = JoinSet::new();
+    for _ in 0..jobs {
+        workers.spawn(transfer_worker(
+            connection.clone(),
+            config.clone(),
+            opts.clone(),
+            shared.clone(),
+        ));
+    }
 
-    // Create local root dir if needed (and if multiple files or target implies dir)
-    // If local path doesn't exist, mkdir -p
-    if !local_path.exists() {
-        // If we have >1 file or the single file has a different name than local_path tail?
-        // Usually, if we sync /remote/foo to /local/bar, we treat /local/bar as the target.
-        // If /remote/foo is a dir, /local/bar becomes that dir.
-        // If /remote/foo is a file, /local/bar becomes that file.
+    let mut synced = Vec::new();
+    let mut failures = Vec::new();
+    let mut signatures_reused = 0;
+    let mut changed = Vec::new();
+    let mut stats = SyncStats::default();
+    let mut resumed = 0;
+    let mut cancelled = false;
+    while let Some(result) = workers.join_next().await {
+        let outcome = result?;
+        synced.extend(outcome.synced);
+        failures.extend(outcome.failures);
+        signatures_reused += outcome.signatures_reused;
+        changed.extend(outcome.changed);
+        stats.add(&outcome.stats);
+        resumed += outcome.resumed;
+        cancelled |= outcome.cancelled;
+    }
+    progress.finish();
 
-        // Check first item to see if it's a dir?
-        // Metadata has is_dir.
-        // But listing might be flat list of files.
-        // Let's iterate.
-        if files.is_empty() {
-            info!("Remote path is empty or invalid.");
-            return Ok(());
+    // Nothing is linked or deleted, and the checkpoint stays for the next
+    // run to resume from.
+    if cancelled {
+        info!(
+            "Copy of {} cancelled after {} files",
+            remote_path,
+            synced.len()
+        );
+        return Err(Cancelled.into());
+    }
+
+    if !failures.is_empty() {
+        let mut summary = String::new();
+        for (path, e) in &failures {
+            error!("Failed to sync {}: {:?}", path, e);
+            summary.push_str(&format!("\n  {}: {:#}", path, e));
         }
+        anyhow::bail!(
+            "{} of {} files failed to sync ({} synced):{}",
+            failures.len(),
+            total_files,
+            total_files - failures.len(),
+            summary
+        );
+    }
+    if let Some(checkpoint) = &checkpoint {
+        checkpoint.clear()?;
     }
 
-    // We need to determine the base relative path to strip.
-    // remote_path: /remote/dir
-    // file path: /remote/dir/file.txt
-    // relative: file.txt
-    // local path: /local/dir
-    // target: /local/dir/file.txt
+    for (target, first) in hard_links {
+        if link_file(&target, &first, trash.as_ref())? {
+            changed.push(target);
+        }
+    }
+    let deleted = delete_entries(&doomed, &remote_path, &local_path, &opts, trash.as_ref());
+    changed.sort();
+    stats.files_skipped += unmentioned.len();
+    stats.files_too_large = too_large;
+    stats.elapsed = started.elapsed();
+
+    let hash = synced
+        .iter()
+        .find(|(target, _)| *target == local_path)
+        .map(|(_, hash)| *hash);
+    Ok(CopyReport {
+        hash,
+        signatures_reused,
+        changed,
+        deleted,
+        stats,
+        resumed,
+        unlisted,
+    })
+}
 
-    let remote_base = std::path::Path::new(&remote_path);
+/// Write the single file at `remote_path` to `out` as it arrives, without
+/// touching the local filesystem. Returns the file's hash.
+async fn stream_file<W: AsyncWrite + Unpin>(
+    session: &mut Session,
+    remote_path: &str,
+    out: &mut W,
+    opts: &CopyOptions,
+) -> Result<[u8; 32]> {
+    let list_req = Message::ListRequest {
+        path: remote_path.to_string(),
+        with_hashes: false,
+        follow_symlinks: true,
+    };
+    session.write(&list_req).await?;
+    let file = match session.read().await?.into_current() {
+        Message::ListResponse {
+            mut files,
+            is_last: true,
+        } if files.len() == 1
+            && files[0].file_type == FileType::File
+            && files[0].path == remote_path =>
+        {
+            files.remove(0)
+        }
+        Message::ListResponse { .. } | Message::ListSkipped { .. } => anyhow::bail!(
+            "{} is a directory; only a single file can be written to stdout",
+            remote_path
+        ),
+        Message::Error { code, message } => return Err(RemoteError { code, message }.into()),
+        msg => anyhow::bail!("Unexpected message: {:?}", msg),
+    };
+
+    let req = Message::FileRequest {
+        path: file.path.clone(),
+        offset: 0,
+    };
+    session.write(&req).await?;
+
+    let progress = Progress::new(opts.progress, 1);
+    let bar = progress.start_file(&file.path, file.len);
+    let limiter = RateLimiter::new(opts.limit_rate);
+    let mut hasher = blake3::Hasher::new();
+    let mut expected = 0;
+    let hash = loop {
+        let msg = session
+            .read()
+            .await
+            .with_context(|| format!("Transfer of {} ended before its last chunk", remote_path))?;
+        match msg {
+            Message::FileData {
+                data,
+                offset,
+                is_last,
+                hash,
+                ..
+            } => {
+                // Nothing written can be taken back, so chunks must arrive
+                // in order.
+                if offset != expected {
+                    anyhow::bail!("Received chunk at offset {}, expected {}", offset, expected);
+                }
+                limiter.acquire(data.len()).await;
+                out.write_all(&data).await?;
+                hasher.update(&data);
+                bar.inc(data.len() as u64);
+                expected += data.len() as u64;
+                if is_last {
+                    break hash.context("Server did not send a file hash")?;
+                }
+            }
+            Message::Error { code, message } => {
+                return Err(RemoteError { code, message }.into());
+            }
+            _ => anyhow::bail!("Unexpected message while streaming: {:?}", msg),
+        }
+    };
+    out.flush().await?;
+    progress.file_done(bar);
+    progress.finish();
+
+    if expected != file.len {
+        anyhow::bail!(
+            "Streamed {} bytes of {}, but it was listed at {}",
+            expected,
+            remote_path,
+            file.len
+        );
+    }
+    if *hasher.finalize().as_bytes() != hash {
+        anyhow::bail!("Streamed {} does not match the remote hash", remote_path);
+    }
+    Ok(hash)
+}
+
+/// Whether `relative`, a wire path below the copied root, passes the filters
+/// of `opts`: it is among the included subpaths, and not hidden unless
+/// hidden files are included.
+fn is_selected(relative: &str, opts: &CopyOptions) -> bool {
+    is_visible(relative, opts) && is_included(relative, &opts.include)
+}
+
+fn is_visible(relative: &str, opts: &CopyOptions) -> bool {
+    opts.include_hidden || !is_hidden(relative)
+}
+
+/// Work out where a listed entry goes locally. Directories and symlinks are
+/// created right away; files are queued for the transfer workers.
+fn plan_entry(
+    file: FileMetadata,
+    remote_base: &str,
+    local_path: &PathBuf,
+    opts: &CopyOptions,
+    whole: bool,
+    pending: &mut VecDeque<PendingTransfer>,
+    hard_links: &mut Vec<(PathBuf, PathBuf)>,
+) -> Result<()> {
+    let Some(relative) = strip_wire_prefix(&file.path, remote_base) else {
+        warn!("Skipping {}, which isn't inside {}", file.path, remote_base);
+        return Ok(());
+    };
+    let selected = match file.file_type {
+        FileType::Dir => {
+            is_selected(relative, opts)
+                || (is_visible(relative, opts) && leads_to_included(relative, &opts.include))
+        }
+        FileType::File | FileType::Symlink | FileType::HardLink => is_selected(relative, opts),
+    };
+    if !selected {
+        return Ok(());
+    }
+    let Some(target) = join_relative(local_path, relative) else {
+        return Ok(());
+    };
 
-    for file in files {
-        if file.is_dir {
-            // Ensure dir exists locally
-            let relative = std::path::Path::new(&file.path)
-                .strip_prefix(remote_base)
-                .unwrap_or(std::path::Path::new(""));
-            if relative.as_os_str().is_empty() {
-                // It's the root dir itself
-                std::fs::create_dir_all(&local_path)?;
+    match file.file_type {
+        FileType::Dir => {
+            if opts.dry_run {
+                if target.is_file() {
+                    println!("replace     {} (file with dir)", target.display());
+                } else if !target.exists() {
+                    println!("create dir  {}", target.display());
+                }
             } else {
-                let target = local_path.join(relative);
+                remove_mismatched_entry(local_path, &target, FileType::Dir)?;
                 std::fs::create_dir_all(&target)?;
             }
-            continue;
+            return Ok(());
+        }
+        FileType::Symlink => {
+            let link_target = from_wire_path(
+                file.link_target
+                    .as_deref()
+                    .context("Server did not send a symlink target")?,
+            );
+            if !opts.allow_external_links && !link_stays_inside(local_path, &target, &link_target) {
+                warn!(
+                    "Skipping symlink {:?} -> {:?}, which points outside {:?}",
+                    target, link_target, local_path
+                );
+                return Ok(());
+            }
+            if opts.dry_run {
+                println!(
+                    "link        {} -> {}",
+                    target.display(),
+                    link_target.display()
+                );
+            } else {
+                if let Some(parent) = target.parent().filter(|_| target != *local_path) {
+                    std::fs::create_dir_all(parent)?;
+                }
+                remove_mismatched_entry(local_path, &target, FileType::Symlink)?;
+                create_symlink(&target, &link_target)?;
+            }
+            return Ok(());
         }
+        FileType::HardLink => {
+            let first = file
+                .link_target
+                .as_deref()
+                .context("Server did not send the first name of a hard link")?;
+            // If the first name isn't copied, this one gets the contents.
+            if let Some(first) = strip_wire_prefix(first, remote_base)
+                .filter(|first| is_selected(first, opts))
+                .and_then(|first| join_relative(local_path, first))
+            {
+                if opts.dry_run {
+                    println!("hard link   {} => {}", target.display(), first.display());
+                } else {
+                    if let Some(parent) = target.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                    remove_mismatched_entry(local_path, &target, FileType::File)?;
+                    hard_links.push((target, first));
+                }
+                return Ok(());
+            }
+        }
+        FileType::File => {}
+    }
 
-        // It's a file
-        let relative = std::path::Path::new(&file.path)
-            .strip_prefix(remote_base)
-            .unwrap_or(std::path::Path::new(""));
+    if !opts.dry_run {
+        if let Some(parent) = target.parent().filter(|_| target != *local_path) {
+            std::fs::create_dir_all(parent)?;
+        }
+        remove_mismatched_entry(local_path, &target, FileType::File)?;
+    }
+    let base_hash = opts.base_hash.filter(|_| target == *local_path);
+    pending.push_back(PendingTransfer {
+        file,
+        target,
+        base_hash,
+        whole,
+    });
+    Ok(())
+}
 
-        // If relative is empty, it means remote_path pointed directly to this file.
-        // So target is local_path.
-        let target_path = if relative.as_os_str().is_empty() {
-            local_path.clone()
+/// What is at `local_path`, listed with hashes the way the server lists
+/// `remote_path`, for a `ManifestRequest`. Backups and partial downloads are
+/// left out, and symlinks are listed as links. Up to `hash_concurrency`
+/// files are hashed at once.
+async fn local_manifest(
+    remote_path: &str,
+    local_path: &Path,
+    hash_concurrency: usize,
+) -> Result<Vec<FileMetadata>> {
+    let mut files = Vec::new();
+    if local_path.symlink_metadata().is_err() {
+        return Ok(files);
+    }
+    let mut hashes = HashQueue::new(hash_concurrency);
+    let walk = WalkDir::new(local_path)
+        .into_iter()
+        .filter_entry(|e| e.file_name() != TRASH_DIR);
+    for entry in walk {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Skipping unreadable entry: {}", e);
+                continue;
+            }
+        };
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+        let (file_type, link_target) = if entry.path_is_symlink() {
+            let target = std::fs::read_link(path)?;
+            (FileType::Symlink, Some(to_wire_path(&target)))
+        } else if metadata.is_dir() {
+            (FileType::Dir, None)
+        } else if path.extension().is_some_and(|ext| ext == PARTIAL_EXTENSION) {
+            continue;
         } else {
-            // Ensure parent dir exists
-            let target = local_path.join(relative);
-            if let Some(parent) = target.parent() {
-                std::fs::create_dir_all(parent)?;
+            (FileType::File, None)
+        };
+        let relative = path.strip_prefix(local_path).unwrap_or(path);
+        let file = FileMetadata {
+            path: join_wire_path(remote_path, &to_wire_path(relative)),
+            len: metadata.len(),
+            modified: metadata
+                .modified()?
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs(),
+            file_type,
+            link_target,
+            hash: None,
+            mode: sync_utils::file_mode(&metadata),
+        };
+        let hashed = (file_type == FileType::File).then(|| path.to_path_buf());
+        hashes.push(file, hashed);
+        while hashes.should_pop() {
+            let Some((mut file, hash)) = hashes.pop().await else {
+                break;
+            };
+            file.hash = hash?;
+            files.push(file);
+        }
+    }
+    while let Some((mut file, hash)) = hashes.pop().await {
+        file.hash = hash?;
+        files.push(file);
+    }
+    Ok(files)
+}
+
+/// Where the listed `remote_entry` under `remote_base` goes below `local_path`,
+/// or `None` if the entry isn't `remote_base` or inside it.
+pub(crate) fn local_target(
+    remote_base: &str,
+    remote_entry: &str,
+    local_path: &Path,
+) -> Option<PathBuf> {
+    // remote_base: /remote/dir, file path: /remote/dir/file.txt
+    // relative: file.txt, target: /local/dir/file.txt
+    // A sibling such as /remote/dir.old has no relative part.
+    let relative = strip_wire_prefix(remote_entry, remote_base)?;
+    join_relative(local_path, relative)
+}
+
+/// `local_path` joined with the wire path `relative`, or `None` if that would
+/// lead out of `local_path`. An empty `relative` is the sync root itself,
+/// which maps onto `local_path` whether it's a file or a directory.
+fn join_relative(local_path: &Path, relative: &str) -> Option<PathBuf> {
+    match from_wire_relative(relative) {
+        // Joining nothing would add a trailing separator.
+        Some(relative) if relative.as_os_str().is_empty() => Some(local_path.to_path_buf()),
+        Some(relative) => Some(local_path.join(relative)),
+        None => {
+            warn!("Skipping {}, which leads out of {:?}", relative, local_path);
+            None
+        }
+    }
+}
+
+/// A bi-directional stream to the server on which the handshake is done.
+pub(crate) struct Session {
+    pub(crate) send: SendStream,
+    recv: RecvStream,
+    peer: PublicKey,
+    /// Protocol version negotiated for the connection
+    pub(crate) version: u32,
+    /// Optional features both sides advertised in the handshake
+    pub(crate) capabilities: Capabilities,
+    idle_timeout: Duration,
+    /// Signatures taken from the cache for files synced on this stream
+    signatures_reused: usize,
+    /// Local files written by transfers on this stream
+    changed: Vec<PathBuf>,
+    /// Counters for transfers on this stream
+    stats: SyncStats,
+    /// Ends transfers on this stream after the chunk in hand once cancelled
+    cancel: CancellationToken,
+}
+
+impl Session {
+    /// Open a new bi-directional stream on `connection` and perform the handshake.
+    pub(crate) async fn open(connection: &Connection, config: &Config) -> Result<Self> {
+        let (send, recv) = connection.open_bi().await?;
+        let mut session = Self {
+            send,
+            recv,
+            peer: connection.remote_id(),
+            version: iroh_utils::protocol_version(connection),
+            capabilities: Capabilities::default(),
+            idle_timeout: config.idle_timeout(),
+            signatures_reused: 0,
+            changed: Vec::new(),
+            stats: SyncStats::default(),
+            cancel: CancellationToken::new(),
+        };
+
+        let handshake = Message::handshake(session.version, &Capabilities::ours());
+        session.write(&handshake).await?;
+
+        let msg = read_message(&mut session.recv, config.handshake_timeout())
+            .await
+            .context("Server sent no handshake")?
+            .into_current();
+        match msg {
+            Message::Handshake {
+                version,
+                capabilities,
+            } => {
+                info!(
+                    "Handshake received from server: version {}, capabilities {:?}",
+                    version, capabilities
+                );
+                session.capabilities =
+                    Capabilities::ours().intersection(&Capabilities::from_names(capabilities));
+            }
+            _ => anyhow::bail!("Expected handshake, got {:?}", msg),
+        }
+
+        Ok(session)
+    }
+
+    /// Have transfers on this stream stop once `cancel` is cancelled.
+    fn cancelled_by(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Send a [`SyncEvent::FileCompleted`] for each file written since
+    /// `before`, with the bytes received in between.
+    fn report_written(&self, events: &broadcast::Sender<SyncEvent>, before: SessionMark) {
+        let bytes = self.stats.bytes_transferred - before.bytes;
+        for path in &self.changed[before.changed..] {
+            let _ = events.send(SyncEvent::FileCompleted {
+                path: path.clone(),
+                bytes,
+            });
+        }
+    }
+
+    pub(crate) async fn write(&mut self, msg: &Message) -> Result<()> {
+        Ok(write_message(&mut self.send, msg).await?)
+    }
+
+    pub(crate) async fn read(&mut self) -> Result<Message> {
+        Ok(read_message(&mut self.recv, self.idle_timeout).await?)
+    }
+}
+
+/// Take files off the shared queue until it is empty, transferring them over
+/// a stream owned by this worker. A failed file is recorded and the stream is
+/// replaced, so the remaining files still get their turn.
+async fn transfer_worker(
+    connection: Connection,
+    config: Config,
+    opts: CopyOptions,
+    shared: Workers,
+) -> WorkerOutcome {
+    let Workers {
+        queue,
+        progress,
+        limiter,
+        trash,
+        chunks,
+        checkpoint,
+    } = shared;
+    let mut session: Option<Session> = None;
+    let mut outcome = WorkerOutcome::default();
+
+    loop {
+        let Some(transfer) = queue.lock().unwrap().pop_front() else {
+            break;
+        };
+        if opts.cancel.is_cancelled() {
+            outcome.cancelled = true;
+            break;
+        }
+        if let Some(hash) = checkpoint.as_ref().and_then(|c| c.completed(&transfer)) {
+            info!("{:?} was completed by an earlier run", transfer.target);
+            progress.file_done(progress.start_file(&transfer.file.path, 0));
+            outcome.stats.files_skipped += 1;
+            outcome.resumed += 1;
+            outcome.synced.push((transfer.target, hash));
+            continue;
+        }
+
+        let file = &transfer.file;
+        let bar = progress.start_file(&file.path, file.len);
+        let ndjson = opts.progress_format == ProgressFormat::Ndjson;
+        if ndjson {
+            let started = ProgressEvent::FileStarted {
+                path: &file.path,
+                len: file.len,
+            };
+            if let Err(e) = started.print() {
+                warn!("Failed to print progress: {:#}", e);
+            }
+        }
+        let mut attempt = 0;
+        let mut received = 0;
+        let result = loop {
+            let before = session.as_ref().map(SessionMark::of).unwrap_or_default();
+            let result = async {
+                if session.is_none() {
+                    let opened = Session::open(&connection, &config).await?;
+                    session = Some(opened.cancelled_by(opts.cancel.clone()));
+                }
+                let session = session.as_mut().expect("session was just opened");
+                sync_file(
+                    session,
+                    &transfer,
+                    &opts,
+                    &bar,
+                    &limiter,
+                    trash.as_ref(),
+                    chunks.as_ref(),
+                )
+                .await
+            }
+            .await;
+
+            if let (Some(session), Ok(_)) = (&session, &result) {
+                received = session.stats.bytes_transferred - before.bytes;
+                if let Some(events) = &opts.events {
+                    session.report_written(events, before);
+                }
+            }
+            let e = match result {
+                Err(e) => e,
+                result => break result,
+            };
+            // The stream is in no state to go on; a retry opens another.
+            if let Some(failed) = session.take() {
+                outcome.absorb(failed);
             }
-            target
+            // Errors the peer reported stand, unless it asked to try again.
+            let reported = e
+                .downcast_ref::<RemoteError>()
+                .is_some_and(|e| e.code != ErrorCode::Retry);
+            if attempt >= opts.retries || reported || e.is::<Cancelled>() {
+                break Err(e);
+            }
+            attempt += 1;
+            let backoff = RETRY_BACKOFF * 2u32.pow(attempt - 1);
+            warn!(
+                "Failed to sync {}, retrying in {:?} ({} of {}): {:#}",
+                file.path, backoff, attempt, opts.retries, e
+            );
+            tokio::time::sleep(backoff).await;
+            bar.reset();
         };
+        progress.file_done(bar);
+        if ndjson {
+            let done = match &result {
+                Ok(_) => ProgressEvent::FileDone {
+                    path: &file.path,
+                    bytes: received,
+                },
+                Err(e) => ProgressEvent::FileFailed {
+                    path: &file.path,
+                    error: format!("{:#}", e),
+                },
+            };
+            if let Err(e) = done.print() {
+                warn!("Failed to print progress: {:#}", e);
+            }
+        }
 
-        // Sync the file
-        sync_file(&mut send, &mut recv, &file.path, &target_path).await?;
+        if let (Some(checkpoint), Ok(Some(hash))) = (&checkpoint, &result) {
+            if let Err(e) = checkpoint.record(&transfer, *hash) {
+                warn!("Failed to checkpoint {}: {:#}", transfer.file.path, e);
+            }
+        }
+        let PendingTransfer { file, target, .. } = transfer;
+        match result {
+            Ok(Some(hash)) => outcome.synced.push((target, hash)),
+            Ok(None) => {}
+            Err(e) if e.is::<Cancelled>() => {
+                info!("Stopped syncing {}, the copy was cancelled", file.path);
+                if !opts.keep_partial {
+                    discard_partials(&target, &connection.remote_id()).await;
+                }
+                outcome.cancelled = true;
+                break;
+            }
+            Err(e) => outcome.failures.push((file.path, e)),
+        }
     }
 
-    Ok(())
+    if let Some(mut session) = session {
+        let _ = session.send.finish();
+        outcome.absorb(session);
+    }
+    outcome
 }
 
 async fn sync_file(
-    send: &mut iroh::endpoint::SendStream,
-    recv: &mut iroh::endpoint::RecvStream,
-    remote_file_path: &str,
-    local_target_path: &PathBuf,
-) -> Result<()> {
-    info!("Syncing {} -> {:?}", remote_file_path, local_target_path);
+    session: &mut Session,
+    transfer: &PendingTransfer,
+    opts: &CopyOptions,
+    bar: &ProgressBar,
+    limiter: &RateLimiter,
+    trash: Option<&Trash>,
+    chunks: Option<&ChunkIndex>,
+) -> Result<Option<[u8; 32]>> {
+    let PendingTransfer {
+        file,
+        target: local_target_path,
+        ..
+    } = transfer;
+    if opts.dry_run {
+        print_planned_action(file, local_target_path);
+        return Ok(None);
+    }
+
+    info!("Syncing {} -> {:?}", file.path, local_target_path);
+    // Held until the file is written, so a local change to it is handled
+    // before or after the patch, never in the middle of it.
+    let _lock = path_lock::lock(local_target_path).await;
+    let hash = write_file(session, transfer, opts, bar, limiter, trash, chunks).await?;
+    if let Some(hash) = hash {
+        // Recorded while still locked, so the watcher sees it with the write.
+        path_lock::record_pulled(local_target_path, session.peer, hash);
+    }
+    Ok(hash)
+}
+
+/// Bring the local file of `transfer` in line with the remote one, for
+/// [`sync_file`] once it holds the file.
+async fn write_file(
+    session: &mut Session,
+    transfer: &PendingTransfer,
+    opts: &CopyOptions,
+    bar: &ProgressBar,
+    limiter: &RateLimiter,
+    trash: Option<&Trash>,
+    chunks: Option<&ChunkIndex>,
+) -> Result<Option<[u8; 32]>> {
+    let PendingTransfer {
+        file,
+        target: local_target_path,
+        base_hash,
+        whole,
+    } = transfer;
+    let remote_file_path = file.path.as_str();
 
     if local_target_path.exists() && local_target_path.is_file() {
         info!("Local file exists, attempting rsync delta transfer...");
-        let local_data = tokio::fs::read(local_target_path).await?;
-        let signature = sync_utils::calculate_signature(&local_data)?;
+        // Stamped before reading so a concurrent write can't leave a cached
+        // signature looking current.
+        let stamp = signature_stamp(local_target_path, opts.block_size)?;
+        let local_len = std::fs::metadata(local_target_path)?.len();
+        let segmented = session.version >= 5 && file.len.max(local_len) > opts.segment_len;
+        // Mapped rather than read, so memory use doesn't grow with its size.
+        let local_data: SharedBytes = Arc::new(sync_utils::map_file(local_target_path)?);
+        let local_bytes = (*local_data).as_ref();
+        let local_hash = *blake3::hash(local_bytes).as_bytes();
+        if file.hash == Some(local_hash) {
+            // Only the mode or modification time can differ, which needs no
+            // transfer at all.
+            if sync_utils::set_metadata(local_target_path, file.modified, file.mode)? {
+                info!("Updated metadata of {:?}", local_target_path);
+            } else {
+                info!("{:?} is up to date", local_target_path);
+            }
+            session.stats.files_skipped += 1;
+            return Ok(Some(local_hash));
+        }
 
-        let req = Message::FileSignature {
-            path: remote_file_path.to_string(),
-            signature,
+        // If the local file changed since the last sync, the policy decides
+        // whether the incoming version goes over it, beside it, or nowhere.
+        let changed_locally = base_hash.is_some_and(|base| base != local_hash);
+        if changed_locally && keeps_local(opts.on_conflict, file, local_target_path)? {
+            info!(
+                "Keeping {:?}, which changed locally since the last sync ({})",
+                local_target_path, opts.on_conflict
+            );
+            session.stats.files_skipped += 1;
+            return Ok(None);
+        }
+        let conflicting = changed_locally && opts.on_conflict == ConflictPolicy::ConflictCopy;
+        let dest = if conflicting {
+            conflict_path(local_target_path, &session.peer)
+        } else {
+            local_target_path.clone()
         };
-        write_message(send, &req).await?;
+        // Only the synced file itself is backed up, not a conflict copy.
+        let backup = trash.filter(|_| dest == *local_target_path);
 
-        let msg = read_message(recv).await?;
-        match msg {
-            Message::FileDelta { path: _, delta } => {
-                info!("Received delta ({} bytes)", delta.len());
-                let new_data = sync_utils::apply_delta(&local_data, &delta)?;
-                tokio::fs::write(local_target_path, new_data).await?;
-                info!("File patched and saved.");
+        if !changed_locally && !may_overwrite(opts.overwrite, file, local_target_path).await? {
+            info!(
+                "Keeping {:?}, which differs from the remote",
+                local_target_path
+            );
+            session.stats.files_skipped += 1;
+            return Ok(None);
+        }
+
+        let hash = if file.len == 0 && local_bytes.is_empty() {
+            // Both empty: already identical.
+            session.stats.files_skipped += 1;
+            local_hash
+        } else if file.len == 0 || local_bytes.is_empty() {
+            // An empty file on either side leaves no blocks to match, so a
+            // delta can't save anything; send the remote file as it is.
+            info!(
+                "One side of {} is empty, downloading in full",
+                remote_file_path
+            );
+            download_file(session, file, &dest, bar, limiter, backup, opts.fsync).await?
+        } else if *whole || file.len < opts.delta_min_len {
+            info!(
+                "{} is too small to be worth a delta, downloading in full",
+                remote_file_path
+            );
+            download_file(session, file, &dest, bar, limiter, backup, opts.fsync).await?
+        } else if segmented {
+            let patched =
+                patch_by_range(session, file, &local_data, &dest, opts, bar, limiter).await?;
+            match patched {
+                Some((hash, delta_len)) => {
+                    let partial = partial_path(&dest);
+                    if hash != local_hash {
+                        if let Some(trash) = backup {
+                            trash.keep(&dest).context("Failed to back up local file")?;
+                        }
+                        sync_utils::commit_file(&partial, &dest, opts.fsync).await?;
+                        session.changed.push(dest.clone());
+                        session.stats.files_transferred += 1;
+                        metrics::get().files_delta.inc();
+                        session.stats.bytes_saved += file.len.saturating_sub(delta_len);
+                    } else {
+                        let _ = tokio::fs::remove_file(&partial).await;
+                        session.stats.files_skipped += 1;
+                    }
+                    info!("File patched and saved.");
+                    hash
+                }
+                None => {
+                    download_file(session, file, &dest, bar, limiter, backup, opts.fsync).await?
+                }
             }
-            Message::Error { message } => {
-                anyhow::bail!("Remote error: {}", message);
+        } else {
+            let (block_size, strong_hash_size) = (opts.block_size, opts.strong_hash_size);
+            let calculate = || {
+                sync_utils::blocking(&local_data, move |data| {
+                    sync_utils::calculate_signature(data, block_size, strong_hash_size)
+                })
+            };
+            let signature =
+                match &opts.store {
+                    // A signature cached before the strong hash size changed
+                    // is calculated afresh.
+                    Some(store) => match store.cached_signature(local_target_path, stamp)?.filter(
+                        |signature| {
+                            !opts.checksum
+                                && sync_utils::signature_options(signature)
+                                    .is_ok_and(|o| o.crypto_hash_size == opts.strong_hash_size)
+                        },
+                    ) {
+                        Some(signature) => {
+                            session.signatures_reused += 1;
+                            signature
+                        }
+                        None => {
+                            let signature = calculate().await??;
+                            store.cache_signature(local_target_path, stamp, &signature)?;
+                            signature
+                        }
+                    },
+                    None => calculate().await??,
+                };
+            let req = Message::FileSignature {
+                path: remote_file_path.to_string(),
+                signature,
+            };
+            session.write(&req).await?;
+
+            let msg = session.read().await?;
+            match msg {
+                Message::FileDelta { delta, hash, .. } => {
+                    info!("Received delta ({} bytes)", delta.len());
+                    limiter.acquire(delta.len()).await;
+                    session.stats.bytes_transferred += delta.len() as u64;
+                    metrics::get().bytes_received.inc_by(delta.len() as u64);
+                    bar.set_message(format!(
+                        "{} (delta {} bytes)",
+                        remote_file_path,
+                        delta.len()
+                    ));
+                    // A local file unrelated to the remote one can produce a delta
+                    // that doesn't apply cleanly; a full download still works.
+                    let delta_len = delta.len() as u64;
+                    // Written aside as it's patched and moved into place, so
+                    // the file is never seen half written.
+                    let partial = partial_path(&dest);
+                    let patched = {
+                        let partial = partial.clone();
+                        sync_utils::blocking(&local_data, move |data| {
+                            sync_utils::apply_delta_to_file(data, &delta, &partial)
+                        })
+                        .await?
+                    };
+                    match patched {
+                        Ok(patched) if patched == hash => {
+                            if hash != local_hash {
+                                if let Some(trash) = backup {
+                                    trash.keep(&dest).context("Failed to back up local file")?;
+                                }
+                                sync_utils::commit_file(&partial, &dest, opts.fsync).await?;
+                                session.changed.push(dest.clone());
+                                session.stats.files_transferred += 1;
+                                metrics::get().files_delta.inc();
+                                session.stats.bytes_saved += file.len.saturating_sub(delta_len);
+                            } else {
+                                let _ = tokio::fs::remove_file(&partial).await;
+                                session.stats.files_skipped += 1;
+                            }
+                            info!("File patched and saved.");
+                            hash
+                        }
+                        Ok(_) => {
+                            // Left behind, it would pass for a download to
+                            // resume.
+                            let _ = tokio::fs::remove_file(&partial).await;
+                            warn!(
+                                "Patched {} does not match the remote hash, downloading in full",
+                                remote_file_path
+                            );
+                            download_file(session, file, &dest, bar, limiter, backup, opts.fsync)
+                                .await?
+                        }
+                        Err(e) => {
+                            let _ = tokio::fs::remove_file(&partial).await;
+                            warn!(
+                                "Failed to apply delta for {} ({}), downloading in full",
+                                remote_file_path, e
+                            );
+                            download_file(session, file, &dest, bar, limiter, backup, opts.fsync)
+                                .await?
+                        }
+                    }
+                }
+                Message::Error {
+                    code: ErrorCode::DeltaFailed,
+                    message,
+                } => {
+                    // Also how the server says a delta wouldn't save enough.
+                    info!(
+                        "Server sent no delta for {} ({}), downloading in full",
+                        remote_file_path, message
+                    );
+                    download_file(session, file, &dest, bar, limiter, backup, opts.fsync).await?
+                }
+                Message::Error { code, message } => {
+                    return Err(RemoteError { code, message }.into());
+                }
+                _ => anyhow::bail!("Unexpected message during sync_file: {:?}", msg),
             }
-            _ => anyhow::bail!("Unexpected message during sync_file: {:?}", msg),
+        };
+
+        if let Some(store) = opts.store.as_ref().filter(|_| dest == *local_target_path) {
+            if hash != local_hash {
+                store.invalidate_signature(local_target_path)?;
+            }
+        }
+
+        if conflicting {
+            if hash == local_hash {
+                // Both sides made the same change.
+                let _ = tokio::fs::remove_file(&dest).await;
+                return Ok(Some(hash));
+            }
+            warn!(
+                "Conflict: {:?} changed both locally and on {}; kept the local version and wrote the incoming one to {:?}",
+                local_target_path, session.peer, dest
+            );
+            session.stats.conflicts += 1;
+            if let Some(events) = &opts.events {
+                let _ = events.send(SyncEvent::ConflictDetected {
+                    path: local_target_path.clone(),
+                    incoming: dest,
+                });
+            }
+            return Ok(None);
         }
+        sync_utils::set_metadata(local_target_path, file.modified, file.mode)?;
+        Ok(Some(hash))
     } else {
         info!("Local file not found, requesting full download...");
-        // 3. Request Full File
-        let req = Message::FileRequest {
-            path: remote_file_path.to_string(),
+        let deduplicated = match chunks {
+            Some(chunks) => {
+                download_chunked(
+                    session,
+                    file,
+                    local_target_path,
+                    bar,
+                    limiter,
+                    chunks,
+                    opts.fsync,
+                )
+                .await?
+            }
+            None => None,
+        };
+        let hash = match deduplicated {
+            Some(hash) => hash,
+            None => {
+                download_file(
+                    session,
+                    file,
+                    local_target_path,
+                    bar,
+                    limiter,
+                    None,
+                    opts.fsync,
+                )
+                .await?
+            }
         };
-        write_message(send, &req).await?;
+        sync_utils::set_metadata(local_target_path, file.modified, file.mode)?;
+        info!("File saved.");
+        Ok(Some(hash))
+    }
+}
 
-        // 4. Receive File Data
-        let msg = read_message(recv).await?;
-        match msg {
-            Message::FileData { path: _, data, .. } => {
-                info!("Received file data ({} bytes)", data.len());
-                tokio::fs::write(local_target_path, data)
-                    .await
-                    .context("Failed to write local file")?;
-                info!("File saved.");
+/// Patch `local_data` into the remote `file` one range of `opts.segment_len`
+/// bytes at a time, writing each patched range to the partial file of `dest`
+/// as it arrives. Returns the hash of the patched file and the delta bytes
+/// received, or `None` if the result doesn't match the remote file, which
+/// is then best downloaded in full.
+async fn patch_by_range(
+    session: &mut Session,
+    file: &FileMetadata,
+    local_data: &SharedBytes,
+    dest: &Path,
+    opts: &CopyOptions,
+    bar: &ProgressBar,
+    limiter: &RateLimiter,
+) -> Result<Option<([u8; 32], u64)>> {
+    let remote_file_path = file.path.as_str();
+    let segment_len = opts.segment_len.max(1);
+    let ranges = file.len.div_ceil(segment_len).max(1);
+    let partial = partial_path(dest);
+    let mut out = tokio::fs::File::create(&partial).await?;
+    let mut hasher = blake3::Hasher::new();
+    let mut delta_len = 0;
+    let mut remote_hash = None;
+    info!(
+        "Patching {} in {} ranges of {} bytes",
+        remote_file_path, ranges, segment_len
+    );
+    let result: Result<bool> = async {
+        for range in 0..ranges {
+            if session.cancel.is_cancelled() {
+                return Err(Cancelled.into());
             }
-            Message::Error { message } => {
-                anyhow::bail!("Remote error: {}", message);
+            let offset = range * segment_len;
+            // Past the end of the local file, a range starts from nothing.
+            let local_len = (**local_data).as_ref().len() as u64;
+            let start = offset.min(local_len) as usize;
+            let end = offset.saturating_add(segment_len).min(local_len) as usize;
+            let (block_size, strong_hash_size) = (opts.block_size, opts.strong_hash_size);
+            let signature = sync_utils::blocking(local_data, move |data| {
+                sync_utils::calculate_signature(&data[start..end], block_size, strong_hash_size)
+            })
+            .await??;
+            let req = Message::FileSignatureRange {
+                path: remote_file_path.to_string(),
+                offset,
+                len: segment_len,
+                signature,
+            };
+            session.write(&req).await?;
+
+            let msg = session.read().await?;
+            let (delta, hash) = match msg {
+                Message::FileDeltaRange { delta, hash, .. } => (delta, hash),
+                Message::Error {
+                    code: ErrorCode::DeltaFailed,
+                    message,
+                } => {
+                    warn!(
+                        "Server could not compute a delta for {} ({}), downloading in full",
+                        remote_file_path, message
+                    );
+                    return Ok(false);
+                }
+                Message::Error { code, message } => {
+                    return Err(RemoteError { code, message }.into())
+                }
+                _ => anyhow::bail!("Unexpected message during patch_by_range: {:?}", msg),
+            };
+            limiter.acquire(delta.len()).await;
+            session.stats.bytes_transferred += delta.len() as u64;
+            metrics::get().bytes_received.inc_by(delta.len() as u64);
+            delta_len += delta.len() as u64;
+            bar.set_message(format!(
+                "{} (range {}/{}, delta {} bytes)",
+                remote_file_path,
+                range + 1,
+                ranges,
+                delta.len()
+            ));
+            // A range is at most a segment long, so it is patched in memory.
+            let patched = sync_utils::blocking(local_data, move |data| {
+                let mut new_data = Vec::new();
+                sync_utils::apply_delta(&data[start..end], &delta, &mut new_data).map(|_| new_data)
+            })
+            .await?;
+            let new_data = match patched {
+                Ok(new_data) => new_data,
+                Err(e) => {
+                    warn!(
+                        "Failed to apply delta for {} ({}), downloading in full",
+                        remote_file_path, e
+                    );
+                    return Ok(false);
+                }
+            };
+            hasher.update(&new_data);
+            out.write_all(&new_data).await?;
+            remote_hash = hash;
+        }
+        out.flush().await?;
+        Ok(true)
+    }
+    .await;
+    drop(out);
+
+    let hash = *hasher.finalize().as_bytes();
+    match result {
+        Ok(true) if remote_hash == Some(hash) => {}
+        Ok(true) => {
+            // Also the case if the remote file grew since it was listed.
+            warn!(
+                "Patched {} does not match the remote hash, downloading in full",
+                remote_file_path
+            );
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Ok(None);
+        }
+        // Left behind, the patched ranges would pass for a download to
+        // resume.
+        Ok(false) => {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Ok(None);
+        }
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(e);
+        }
+    }
+    Ok(Some((hash, delta_len)))
+}
+
+/// Whether `policy` keeps `local`, changed since the last sync, rather than
+/// writing the incoming `file` over or beside it.
+fn keeps_local(policy: ConflictPolicy, file: &FileMetadata, local: &Path) -> Result<bool> {
+    Ok(match policy {
+        ConflictPolicy::SourceWins | ConflictPolicy::ConflictCopy => false,
+        ConflictPolicy::Skip => true,
+        ConflictPolicy::NewerWins => {
+            let local_modified = std::fs::metadata(local)?
+                .modified()?
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs();
+            local_modified >= file.modified
+        }
+    })
+}
+
+/// Whether `local`, which differs from the incoming `file`, may be replaced.
+async fn may_overwrite(overwrite: Overwrite, file: &FileMetadata, local: &Path) -> Result<bool> {
+    match overwrite {
+        Overwrite::Always => Ok(true),
+        Overwrite::Never => Ok(false),
+        Overwrite::Ask => {
+            let metadata = std::fs::metadata(local)?;
+            let local_modified = metadata
+                .modified()?
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs();
+            let prompt = format!(
+                "{} differs from the incoming version:\n  \
+                 local:    {} bytes, modified {}\n  \
+                 incoming: {} bytes, modified {}\n\
+                 Overwrite? [y/N] ",
+                local.display(),
+                metadata.len(),
+                describe_age(local_modified),
+                file.len,
+                describe_age(file.modified),
+            );
+            tokio::task::spawn_blocking(move || confirm(&prompt)).await?
+        }
+    }
+}
+
+/// Refuse to delete more than `opts.max_delete` of the `local_files` files
+/// in the manifest, unless `opts.force` is set or the user agrees to it when
+/// asked. A remote that lost that much is more likely to be misconfigured,
+/// e.g. missing a mount, than to have been emptied on purpose.
+async fn approve_deletions(
+    doomed: &[FileMetadata],
+    local_files: usize,
+    opts: &CopyOptions,
+) -> Result<()> {
+    let files: Vec<&str> = doomed
+        .iter()
+        .filter(|f| f.file_type != FileType::Dir)
+        .map(|f| f.path.as_str())
+        .collect();
+    let share = files.len() as f64 / local_files.max(1) as f64;
+    if opts.force || opts.dry_run || share <= opts.max_delete {
+        return Ok(());
+    }
+    let list: String = files.iter().map(|path| format!("\n  {}", path)).collect();
+    if opts.confirm_deletes {
+        let prompt = format!(
+            "The remote no longer has {} of {} local files:{}\nDelete them? [y/N] ",
+            files.len(),
+            local_files,
+            list
+        );
+        if tokio::task::spawn_blocking(move || confirm(&prompt)).await?? {
+            return Ok(());
+        }
+        anyhow::bail!("Sync cancelled; nothing was deleted");
+    }
+    anyhow::bail!(
+        "Refusing to delete {} of {} local files, more than the max_delete share of {}. \
+         Check the remote, or pass --force to delete them anyway:{}",
+        files.len(),
+        local_files,
+        opts.max_delete,
+        list
+    )
+}
+
+/// Remove the local entries the remote no longer has. They are listed in
+/// path order, so going backwards empties each directory before it is
+/// reached. Returns the paths removed.
+fn delete_entries(
+    doomed: &[FileMetadata],
+    remote_base: &str,
+    local_path: &Path,
+    opts: &CopyOptions,
+    trash: Option<&Trash>,
+) -> Vec<PathBuf> {
+    let mut deleted = Vec::new();
+    for file in doomed.iter().rev() {
+        let Some(target) = local_target(remote_base, &file.path, local_path) else {
+            continue;
+        };
+        if opts.dry_run {
+            println!("delete      {}", target.display());
+            continue;
+        }
+        let removed = match (file.file_type, trash) {
+            (FileType::Dir, _) => std::fs::remove_dir(&target),
+            (_, Some(trash)) => trash.keep(&target).map(drop),
+            (_, None) => std::fs::remove_file(&target),
+        };
+        match removed {
+            Ok(()) => {
+                info!("Deleted {:?}, which the remote doesn't have", target);
+                deleted.push(target);
             }
-            _ => anyhow::bail!("Unexpected message during sync_file: {:?}", msg),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to delete {:?}: {}", target, e),
         }
     }
+    if opts.prune_empty_dirs && !opts.dry_run {
+        let pruned = prune_empty_dirs(&deleted, local_path);
+        deleted.extend(pruned);
+    }
+    deleted.sort();
+    deleted
+}
+
+/// Remove the directories that removing `removed` left empty, deepest
+/// first, up to but not including `root`. Directories that were already
+/// empty, or that hold anything else, stay. Returns those removed.
+fn prune_empty_dirs(removed: &[PathBuf], root: &Path) -> Vec<PathBuf> {
+    let mut dirs: Vec<&Path> = removed
+        .iter()
+        .flat_map(|path| {
+            path.ancestors()
+                .skip(1)
+                .take_while(|dir| *dir != root && dir.starts_with(root))
+        })
+        .collect();
+    dirs.sort_by_key(|dir| (std::cmp::Reverse(dir.components().count()), *dir));
+    dirs.dedup();
+
+    let mut pruned = Vec::new();
+    for dir in dirs {
+        let empty = std::fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_none());
+        if !empty {
+            continue;
+        }
+        match std::fs::remove_dir(dir) {
+            Ok(()) => {
+                info!("Removed {:?}, left empty by deletions", dir);
+                pruned.push(dir.to_path_buf());
+            }
+            Err(e) => warn!("Failed to remove empty directory {:?}: {}", dir, e),
+        }
+    }
+    pruned
+}
+
+/// How long ago the Unix timestamp `modified` was.
+pub(crate) fn describe_age(modified: u64) -> String {
+    match sync_utils::unix_timestamp().checked_sub(modified) {
+        Some(age) => format!("{} ago", format_duration(age)),
+        None => "in the future".to_string(),
+    }
+}
+
+/// Show `prompt` on the terminal and read a yes or no, defaulting to no.
+/// Prompts from concurrent transfers are shown one at a time.
+fn confirm(prompt: &str) -> Result<bool> {
+    static PROMPT: Mutex<()> = Mutex::new(());
+    let _prompt = PROMPT.lock().unwrap_or_else(|e| e.into_inner());
+    progress::suspend(|| {
+        use std::io::Write;
+        let mut stderr = std::io::stderr();
+        stderr.write_all(prompt.as_bytes())?;
+        stderr.flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        Ok(matches!(
+            answer.trim().to_ascii_lowercase().as_str(),
+            "y" | "yes"
+        ))
+    })
+}
+
+/// Identify the current state of `path` for the signature cache.
+fn signature_stamp(path: &Path, block_size: Option<u32>) -> Result<SignatureStamp> {
+    let metadata = std::fs::metadata(path)?;
+    let modified = metadata.modified()?.duration_since(std::time::UNIX_EPOCH)?;
+    Ok(SignatureStamp {
+        modified_nanos: modified.as_nanos() as u64,
+        len: metadata.len(),
+        block_size,
+    })
+}
+
+/// Remove what sits at `target` if it isn't the type the remote now has, so
+/// the new entry can take its place. Only entries inside `root` are touched,
+/// and directories only while they are empty.
+fn remove_mismatched_entry(root: &Path, target: &Path, file_type: FileType) -> Result<()> {
+    let Ok(local) = std::fs::symlink_metadata(target) else {
+        return Ok(());
+    };
+    let local_type = if local.file_type().is_symlink() {
+        FileType::Symlink
+    } else if local.is_dir() {
+        FileType::Dir
+    } else {
+        FileType::File
+    };
+    if local_type == file_type {
+        return Ok(());
+    }
+
+    let inside_root = target
+        .strip_prefix(root)
+        .is_ok_and(|rel| rel.components().all(|c| matches!(c, Component::Normal(_))));
+    if !inside_root {
+        anyhow::bail!(
+            "Refusing to replace {:?}, which is outside the sync root {:?}",
+            target,
+            root
+        );
+    }
+
+    match local_type {
+        FileType::Dir => std::fs::remove_dir(target).with_context(|| {
+            format!(
+                "{:?} is no longer a directory on the remote, but is not empty locally",
+                target
+            )
+        })?,
+        // Links to directories are directories to Windows.
+        FileType::Symlink => {
+            std::fs::remove_file(target).or_else(|_| std::fs::remove_dir(target))?
+        }
+        FileType::File | FileType::HardLink => std::fs::remove_file(target)?,
+    }
+    info!("Removed {:?}, which changed type on the remote", target);
     Ok(())
 }
 
-async fn write_message<W: AsyncWriteExt + Unpin>(writer: &mut W, msg: &Message) -> Result<()> {
-    let data = postcard::to_stdvec(msg)?;
-    let len = data.len() as u32;
-    writer.write_u32(len).await?;
-    writer.write_all(&data).await?;
-    writer.flush().await?;
+/// Make `target` another name for `first`, which was copied already. Where
+/// hard links can't be made, `first` is copied instead. Returns whether
+/// `target` changed.
+fn link_file(target: &Path, first: &Path, trash: Option<&Trash>) -> Result<bool> {
+    if is_same_file(target, first) {
+        return Ok(false);
+    }
+    if target.is_file() {
+        match trash {
+            Some(trash) => {
+                trash.keep(target).context("Failed to back up local file")?;
+            }
+            None => std::fs::remove_file(target)?,
+        }
+    }
+    if let Err(e) = std::fs::hard_link(first, target) {
+        warn!(
+            "Failed to link {:?} to {:?} ({}), copying it instead",
+            target, first, e
+        );
+        std::fs::copy(first, target)?;
+    }
+    Ok(true)
+}
+
+/// Whether `a` and `b` are names of the same file.
+#[cfg(unix)]
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (std::fs::metadata(a), std::fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// Whether `a` and `b` are names of the same file. Without a way to tell,
+/// they are linked afresh every time.
+#[cfg(not(unix))]
+fn is_same_file(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+/// Whether a symlink at `link` pointing to `link_target` resolves, without
+/// following any other links, to somewhere inside `root`.
+fn link_stays_inside(root: &Path, link: &Path, link_target: &Path) -> bool {
+    if link_target.is_absolute() {
+        return link_target.starts_with(root);
+    }
+    let mut resolved = link.parent().unwrap_or(root).to_path_buf();
+    for component in link_target.components() {
+        match component {
+            Component::Normal(name) => resolved.push(name),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !resolved.pop() {
+                    return false;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    resolved.starts_with(root)
+}
+
+/// Point a symlink at `link` to `link_target`, replacing a link that points
+/// elsewhere.
+fn create_symlink(link: &Path, link_target: &Path) -> Result<()> {
+    if let Ok(existing) = std::fs::read_link(link) {
+        if existing == link_target {
+            return Ok(());
+        }
+        std::fs::remove_file(link).or_else(|_| std::fs::remove_dir(link))?;
+    }
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(link_target, link)?;
+    #[cfg(windows)]
+    {
+        let resolved = link.parent().unwrap_or(Path::new("")).join(link_target);
+        if resolved.is_dir() {
+            std::os::windows::fs::symlink_dir(link_target, link)?;
+        } else {
+            std::os::windows::fs::symlink_file(link_target, link)?;
+        }
+    }
+    info!("Linked {:?} -> {:?}", link, link_target);
     Ok(())
 }
 
-async fn read_message<R: AsyncReadExt + Unpin>(reader: &mut R) -> Result<Message> {
-    let len = reader.read_u32().await?;
-    let mut buf = vec![0u8; len as usize];
-    reader.read_exact(&mut buf).await?;
-    let msg = postcard::from_bytes(&buf)?;
-    Ok(msg)
+/// Where the incoming version of `target` is written when it conflicts with
+/// local changes, e.g. `notes.txt.conflict-<peer>`.
+fn conflict_path(target: &Path, peer: &PublicKey) -> PathBuf {
+    let mut name = target.file_name().map(OsString::from).unwrap_or_default();
+    name.push(format!(".conflict-{}", peer.fmt_short()));
+    target.with_file_name(name)
+}
+
+/// Download `remote_path` into a partial file next to `target`, resuming from
+/// whatever an interrupted earlier download left behind, and move it into
+/// place once its hash matches the sender's, moving the file it replaces into
+/// `trash` if given. With `fsync` the file is on disk before this returns.
+/// Returns that hash.
+async fn download_file(
+    session: &mut Session,
+    file: &FileMetadata,
+    target: &Path,
+    bar: &ProgressBar,
+    limiter: &RateLimiter,
+    trash: Option<&Trash>,
+    fsync: bool,
+) -> Result<[u8; 32]> {
+    let remote_path = file.path.as_str();
+    let partial = partial_path(target);
+    let resume_from = tokio::fs::metadata(&partial)
+        .await
+        .map(|m| m.len())
+        .unwrap_or(0);
+    if resume_from > 0 {
+        info!("Resuming {} from byte {}", remote_path, resume_from);
+    }
+
+    let mut verified = receive_file(session, file, &partial, resume_from, bar, limiter).await?;
+    if verified.is_none() && resume_from > 0 {
+        // The remote file changed since the partial was written.
+        warn!(
+            "{} changed since the partial download, restarting",
+            remote_path
+        );
+        tokio::fs::remove_file(&partial).await?;
+        verified = receive_file(session, file, &partial, 0, bar, limiter).await?;
+    }
+    let Some(hash) = verified else {
+        anyhow::bail!("Downloaded {} does not match the remote hash", remote_path);
+    };
+
+    if let Some(trash) = trash.filter(|_| target.is_file()) {
+        trash.keep(target).context("Failed to back up local file")?;
+    }
+    sync_utils::commit_file(&partial, target, fsync)
+        .await
+        .context("Failed to write local file")?;
+    session.changed.push(target.to_path_buf());
+    session.stats.files_transferred += 1;
+    metrics::get().files_full.inc();
+    Ok(hash)
+}
+
+/// Download `remote_path` to `target` as content-defined chunks, fetching
+/// only those not in `chunks` and adding the file's own once it is in place.
+/// Returns its hash, or `None` if a chunk couldn't be read back locally or
+/// the result doesn't match, in which case the file is best downloaded whole.
+async fn download_chunked(
+    session: &mut Session,
+    file: &FileMetadata,
+    target: &Path,
+    bar: &ProgressBar,
+    limiter: &RateLimiter,
+    chunks: &ChunkIndex,
+    fsync: bool,
+) -> Result<Option<[u8; 32]>> {
+    let remote_path = file.path.as_str();
+    let req = Message::ChunkRequest {
+        path: remote_path.to_string(),
+    };
+    session.write(&req).await?;
+    let (file_chunks, hash) = match session.read().await? {
+        Message::FileChunks { chunks, hash, .. } => (chunks, hash),
+        Message::Error { code, message } => return Err(RemoteError { code, message }.into()),
+        msg => anyhow::bail!("Unexpected message during sync_file: {:?}", msg),
+    };
+    let len: u64 = file_chunks.iter().map(|chunk| u64::from(chunk.len)).sum();
+    if len != file.len {
+        // Wanting none of them keeps the stream in step.
+        let want = Message::ChunkWant { hashes: Vec::new() };
+        session.write(&want).await?;
+        anyhow::bail!(
+            "{} is {} bytes in chunks but was listed at {}; it may have changed since",
+            remote_path,
+            len,
+            file.len
+        );
+    }
+
+    // Chunks repeated within the file are copied from where they first
+    // appeared, so each is only asked for once.
+    let mut wanted = Vec::new();
+    let mut listed = HashSet::new();
+    for chunk in &file_chunks {
+        if listed.insert(chunk.hash) && !chunks.contains(&chunk.hash) {
+            wanted.push(chunk.hash);
+        }
+    }
+    info!(
+        "{} has {} chunks, fetching {}",
+        remote_path,
+        file_chunks.len(),
+        wanted.len()
+    );
+    let want = Message::ChunkWant {
+        hashes: wanted.clone(),
+    };
+    session.write(&want).await?;
+    let mut wanted: HashSet<_> = wanted.into_iter().collect();
+
+    let partial = partial_path(target);
+    let mut out = tokio::fs::File::create(&partial)
+        .await
+        .context("Failed to open partial file")?;
+    // Chunks written to the partial so far, to copy repeats from.
+    let own = ChunkIndex::default();
+    let mut offset = 0;
+    // Once a chunk can't be had, what is still coming is only read so the
+    // stream stays in step.
+    let mut complete = true;
+    for chunk in &file_chunks {
+        let data = if wanted.remove(&chunk.hash) {
+            let data = match session.read().await? {
+                Message::ChunkData { hash, data } if hash == chunk.hash => data,
+                Message::Error { code, message } => {
+                    return Err(RemoteError { code, message }.into())
+                }
+                msg => anyhow::bail!("Unexpected message during sync_file: {:?}", msg),
+            };
+            limiter.acquire(data.len()).await;
+            session.stats.bytes_transferred += data.len() as u64;
+            metrics::get().bytes_received.inc_by(data.len() as u64);
+            Some(data)
+        } else if complete {
+            if own.contains(&chunk.hash) {
+                out.flush().await?;
+            }
+            let data = own.read(&chunk.hash).or_else(|| chunks.read(&chunk.hash));
+            if let Some(data) = &data {
+                session.stats.bytes_saved += data.len() as u64;
+            }
+            data
+        } else {
+            None
+        };
+        let Some(data) = data.filter(|_| complete) else {
+            complete = false;
+            continue;
+        };
+        out.write_all(&data).await?;
+        bar.inc(data.len() as u64);
+        own.insert(&partial, offset, chunk);
+        offset += data.len() as u64;
+        if session.cancel.is_cancelled() {
+            out.flush().await?;
+            return Err(Cancelled.into());
+        }
+    }
+    out.flush().await?;
+    drop(out);
+
+    if !complete || sync_utils::hash_file(&partial).await? != hash {
+        warn!("Could not assemble {} from chunks", remote_path);
+        tokio::fs::remove_file(&partial).await?;
+        bar.reset();
+        return Ok(None);
+    }
+    sync_utils::commit_file(&partial, target, fsync)
+        .await
+        .context("Failed to write local file")?;
+    chunks.add(target, &file_chunks);
+    session.changed.push(target.to_path_buf());
+    session.stats.files_transferred += 1;
+    metrics::get().files_full.inc();
+    Ok(Some(hash))
+}
+
+/// Request `remote` from `offset` and write the chunks into `partial`.
+/// Returns the server's hash of the file if the completed file matches it.
+/// A file of another length than listed is refused outright.
+async fn receive_file(
+    session: &mut Session,
+    remote: &FileMetadata,
+    partial: &Path,
+    offset: u64,
+    bar: &ProgressBar,
+    limiter: &RateLimiter,
+) -> Result<Option<[u8; 32]>> {
+    let remote_path = remote.path.as_str();
+    let req = Message::FileRequest {
+        path: remote_path.to_string(),
+        offset,
+    };
+    session.write(&req).await?;
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(partial)
+        .await
+        .context("Failed to open partial file")?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    bar.set_position(offset);
+    let mut expected = offset;
+
+    loop {
+        let msg = session
+            .read()
+            .await
+            .with_context(|| format!("Transfer of {} ended before its last chunk", remote_path))?;
+        match msg {
+            Message::FileData {
+                data,
+                offset: chunk_offset,
+                is_last,
+                hash,
+                ..
+            } => {
+                if data.len() > MAX_CHUNK_LEN {
+                    anyhow::bail!(
+                        "Received a {} byte chunk, more than the {} byte maximum",
+                        data.len(),
+                        MAX_CHUNK_LEN
+                    );
+                }
+                if chunk_offset > expected {
+                    anyhow::bail!(
+                        "Received chunk at offset {}, expected {}",
+                        chunk_offset,
+                        expected
+                    );
+                }
+                if chunk_offset < expected {
+                    // The server couldn't resume from our offset and started over.
+                    file.set_len(chunk_offset).await?;
+                    file.seek(SeekFrom::Start(chunk_offset)).await?;
+                    bar.set_position(chunk_offset);
+                }
+
+                limiter.acquire(data.len()).await;
+                file.write_all(&data).await?;
+                bar.inc(data.len() as u64);
+                session.stats.bytes_transferred += data.len() as u64;
+                metrics::get().bytes_received.inc_by(data.len() as u64);
+                expected = chunk_offset + data.len() as u64;
+
+                if is_last {
+                    file.flush().await?;
+                    drop(file);
+                    if expected != remote.len {
+                        // Cut short, or changed since it was listed; either
+                        // way not the file the listing promised.
+                        tokio::fs::remove_file(partial).await?;
+                        anyhow::bail!(
+                            "Received {} bytes of {}, but it was listed at {}",
+                            expected,
+                            remote_path,
+                            remote.len
+                        );
+                    }
+                    let hash = hash.context("Server did not send a file hash")?;
+                    let matches = sync_utils::hash_file(partial).await? == hash;
+                    return Ok(matches.then_some(hash));
+                }
+                if session.cancel.is_cancelled() {
+                    file.flush().await?;
+                    return Err(Cancelled.into());
+                }
+            }
+            Message::Error {
+                code: ErrorCode::Retry,
+                message,
+            } => {
+                // What arrived may mix two versions of the file.
+                drop(file);
+                tokio::fs::remove_file(partial).await?;
+                return Err(RemoteError {
+                    code: ErrorCode::Retry,
+                    message,
+                }
+                .into());
+            }
+            Message::Error { code, message } => {
+                return Err(RemoteError { code, message }.into());
+            }
+            _ => anyhow::bail!("Unexpected message during sync_file: {:?}", msg),
+        }
+    }
+}
+
+/// Remove what a cancelled transfer left of `target`, or of its conflict
+/// copy for `peer`, in partial files.
+async fn discard_partials(target: &Path, peer: &PublicKey) {
+    for dest in [target.to_path_buf(), conflict_path(target, peer)] {
+        let _ = tokio::fs::remove_file(partial_path(&dest)).await;
+    }
+}
+
+/// Where an in-progress download of `target` is kept until it completes.
+pub(crate) fn partial_path(target: &Path) -> PathBuf {
+    let mut name = target.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".");
+    name.push(PARTIAL_EXTENSION);
+    target.with_file_name(name)
+}
+
+/// Whether the local file at `path`, `len` bytes long, has the contents the
+/// listing says `file` has.
+fn is_up_to_date(file: &FileMetadata, path: &Path, len: u64) -> bool {
+    let Some(hash) = file.hash.filter(|_| file.len == len) else {
+        return false;
+    };
+    sync_utils::map_file(path).is_ok_and(|data| *blake3::hash(&data).as_bytes() == hash)
+}
+
+/// Whether `local` has a different modification time or mode than `file`.
+fn metadata_differs(file: &FileMetadata, local: &std::fs::Metadata) -> bool {
+    let modified = local
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs());
+    modified != Some(file.modified)
+        || file
+            .mode
+            .is_some_and(|mode| sync_utils::file_mode(local).is_some_and(|m| m != mode))
+}
+
+/// Describe what `sync_file` would do to `local_target_path` for a dry run.
+fn print_planned_action(file: &FileMetadata, local_target_path: &PathBuf) {
+    match std::fs::metadata(local_target_path) {
+        Ok(local) if local.is_dir() => println!(
+            "replace     {} (dir with file, {} bytes)",
+            local_target_path.display(),
+            file.len
+        ),
+        Ok(local) if local.is_file() && is_up_to_date(file, local_target_path, local.len()) => {
+            if metadata_differs(file, &local) {
+                println!("metadata    {}", local_target_path.display())
+            } else {
+                println!("up to date  {}", local_target_path.display())
+            }
+        }
+        Ok(local) if local.is_file() => {
+            let size_delta = file.len as i64 - local.len() as i64;
+            println!(
+                "overwrite   {} ({:+} bytes)",
+                local_target_path.display(),
+                size_delta
+            );
+        }
+        _ => println!(
+            "create      {} ({} bytes)",
+            local_target_path.display(),
+            file.len
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::CopyOptions;
+    use crate::{path_lock, test_support::Harness};
+
+    #[tokio::test]
+    async fn patch_waits_for_local_handling_of_the_same_file() {
+        let h = Harness::start().await;
+        std::fs::write(h.served.join("data.txt"), b"remote version").unwrap();
+        let target = h.local.join("data.txt");
+        std::fs::write(&target, b"local version").unwrap();
+
+        // Stands in for the watcher handling a local change to the file.
+        let held = path_lock::lock(&h.local.join("./data.txt")).await;
+        let copy = tokio::spawn({
+            let client = h.client.clone();
+            let (server_id, remote, target) = (h.server_id, h.remote("data.txt"), target.clone());
+            async move {
+                client
+                    .copy(server_id, remote, target, CopyOptions::default())
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!copy.is_finished());
+        assert_eq!(std::fs::read(&target).unwrap(), b"local version");
+
+        drop(held);
+        copy.await.unwrap().unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"remote version");
+        h.stop().await;
+    }
 }
@@ -0,0 +1,133 @@
+//! Running `syncr serve` in the background, and finding it again later.
+
+#[cfg(unix)]
+use anyhow::Context;
+use anyhow::Result;
+#[cfg(unix)]
+use std::path::PathBuf;
+
+use crate::config::Config;
+
+/// How long `--stop` waits for the server to finish its graceful shutdown.
+#[cfg(unix)]
+const STOP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15);
+
+/// File holding the PID of the background server.
+#[cfg(unix)]
+pub fn pid_path(config: &Config) -> Result<PathBuf> {
+    Ok(config.home_dir()?.join("syncr.pid"))
+}
+
+/// File the background server logs to.
+#[cfg(unix)]
+pub fn log_path(config: &Config) -> Result<PathBuf> {
+    Ok(config.home_dir()?.join("syncr.log"))
+}
+
+/// Fork into the background. Returns in the detached child only; the parent
+/// exits once the child is running. Must be called before any threads are
+/// started, so before the runtime and the store.
+#[cfg(unix)]
+pub fn detach(config: &Config) -> Result<()> {
+    let home = config.home_dir()?;
+    std::fs::create_dir_all(&home)
+        .with_context(|| format!("Failed to create home directory {:?}", home))?;
+    if let Some(pid) = running_pid(config)? {
+        anyhow::bail!("syncr serve is already running (pid {})", pid);
+    }
+
+    let log_path = log_path(config)?;
+    let log = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .with_context(|| format!("Failed to open log file {:?}", log_path))?;
+    println!(
+        "Serving in the background, logging to {}",
+        log_path.display()
+    );
+
+    daemonize::Daemonize::new()
+        .pid_file(pid_path(config)?)
+        // Keep relative paths in the config and arguments meaning the same.
+        .working_directory(std::env::current_dir()?)
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .start()
+        .context("Failed to start in the background")?;
+    Ok(())
+}
+
+/// Ask the background server to shut down and wait until it has.
+#[cfg(unix)]
+pub fn stop(config: &Config) -> Result<()> {
+    let Some(pid) = running_pid(config)? else {
+        println!("syncr serve is not running");
+        return Ok(());
+    };
+    // SAFETY: kill has no memory safety requirements.
+    if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to signal pid {}", pid));
+    }
+
+    let started = std::time::Instant::now();
+    while is_alive(pid) {
+        if started.elapsed() > STOP_TIMEOUT {
+            anyhow::bail!("syncr serve (pid {}) did not stop in time", pid);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    let _ = std::fs::remove_file(pid_path(config)?);
+    println!("Stopped syncr serve (pid {})", pid);
+    Ok(())
+}
+
+/// Report whether the background server is running.
+#[cfg(unix)]
+pub fn status(config: &Config) -> Result<()> {
+    match running_pid(config)? {
+        Some(pid) => println!("syncr serve is running (pid {})", pid),
+        None => println!("syncr serve is not running"),
+    }
+    Ok(())
+}
+
+/// PID from the PID file, if that process is still alive. A file left by a
+/// server that died without cleaning up counts as not running.
+#[cfg(unix)]
+fn running_pid(config: &Config) -> Result<Option<libc::pid_t>> {
+    let path = pid_path(config)?;
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", path)),
+    };
+    let pid = contents
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid PID file {:?}", path))?;
+    Ok(is_alive(pid).then_some(pid))
+}
+
+#[cfg(unix)]
+fn is_alive(pid: libc::pid_t) -> bool {
+    // SAFETY: signal 0 only checks that the process exists.
+    let alive = unsafe { libc::kill(pid, 0) } == 0;
+    alive || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+pub fn detach(_config: &Config) -> Result<()> {
+    anyhow::bail!("--daemon is only supported on Unix; run syncr serve as a service instead")
+}
+
+#[cfg(not(unix))]
+pub fn stop(_config: &Config) -> Result<()> {
+    anyhow::bail!("--stop is only supported on Unix")
+}
+
+#[cfg(not(unix))]
+pub fn status(_config: &Config) -> Result<()> {
+    anyhow::bail!("--status is only supported on Unix")
+}
@@ -0,0 +1,184 @@
+//! `syncr doctor`: check the pieces a sync depends on, one by one.
+
+use anyhow::Result;
+
+use crate::{
+    cli::{copy::Session, peer::PeerRef},
+    config::Config,
+    iroh_utils,
+    store::{Store, StoreError},
+};
+
+/// Outcome of one check, printed as a line of the checklist.
+struct Check {
+    passed: bool,
+    what: String,
+    /// What to do about a failure
+    hint: Option<String>,
+}
+
+impl Check {
+    fn pass(what: impl Into<String>) -> Self {
+        Self {
+            passed: true,
+            what: what.into(),
+            hint: None,
+        }
+    }
+
+    fn fail(what: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            passed: false,
+            what: what.into(),
+            hint: Some(hint.into()),
+        }
+    }
+
+    fn print(&self) {
+        let mark = if self.passed { "ok" } else { "FAIL" };
+        println!("[{:>4}] {}", mark, self.what);
+        if let Some(hint) = &self.hint {
+            println!("       {}", hint);
+        }
+    }
+}
+
+/// Run every check, printing each as it completes. Checks that depend on an
+/// earlier one that failed are skipped. Fails if any check failed.
+pub async fn run(config: &Config, peer: Option<PeerRef>) -> Result<()> {
+    let mut failed = 0;
+    let mut report = |check: Check| {
+        check.print();
+        if !check.passed {
+            failed += 1;
+        }
+    };
+
+    let home = config.home_dir()?;
+    match iroh_utils::load_secret_key(&home).await {
+        Ok(key) => report(Check::pass(format!(
+            "Secret key loads (Peer ID: {})",
+            key.public()
+        ))),
+        Err(e) => report(Check::fail(
+            format!("Secret key loads from {:?}: {}", home.join("secret_key"), e),
+            "Run `syncr info` to create an identity, or restore the secret_key file",
+        )),
+    }
+
+    let data_dir = config.data_dir()?;
+    let store = match Store::new(&data_dir) {
+        Ok(store) => {
+            report(Check::pass(format!("Store opens at {:?}", data_dir)));
+            Some(store)
+        }
+        Err(e @ StoreError::Locked(_)) => {
+            report(Check::fail(
+                format!("Store opens at {:?}: {}", data_dir, e),
+                "`syncr serve` holds the store while it runs; stop it and try again",
+            ));
+            None
+        }
+        Err(e) => {
+            report(Check::fail(
+                format!("Store opens at {:?}: {}", data_dir, e),
+                "Check that the data directory is readable and writable",
+            ));
+            None
+        }
+    };
+
+    if let Some(store) = &store {
+        match store.list_watches() {
+            Ok(watches) => {
+                for path in watches {
+                    if path.exists() {
+                        report(Check::pass(format!("Watched path {:?} exists", path)));
+                    } else {
+                        report(Check::fail(
+                            format!("Watched path {:?} exists", path),
+                            format!(
+                                "Recreate it, or stop watching it with `syncr watch -d {}`",
+                                path.display()
+                            ),
+                        ));
+                    }
+                }
+            }
+            Err(e) => report(Check::fail(
+                format!("Watches can be read: {}", e),
+                "The store may be damaged; export what you can with `syncr config export`",
+            )),
+        }
+    }
+
+    if let Some(peer) = peer {
+        match &store {
+            Some(store) => report(check_peer(config, store, peer).await),
+            None => report(Check::fail(
+                "Peer is reachable",
+                "Skipped because the store could not be opened",
+            )),
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!("{} check(s) failed", failed);
+    }
+    println!("All checks passed");
+    Ok(())
+}
+
+/// Discover `peer`, connect to it and complete a handshake.
+async fn check_peer(config: &Config, store: &Store, peer: PeerRef) -> Check {
+    let peer = match peer.resolve(store) {
+        Ok(peer) => peer,
+        Err(e) => {
+            return Check::fail(
+                format!("Peer is known: {}", e),
+                "Add it with `syncr peer add`",
+            )
+        }
+    };
+    let endpoint = match iroh_utils::build_endpoint(config).await {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            return Check::fail(
+                format!("Endpoint binds: {}", e),
+                "Check that UDP sockets can be opened on this machine",
+            )
+        }
+    };
+    if let Ok(addrs) = store.list_addresses() {
+        iroh_utils::add_known_addresses(&endpoint, addrs);
+    }
+
+    let connection = match iroh_utils::connect(&endpoint, config.network(), peer).await {
+        Ok(connection) => connection,
+        Err(e) => {
+            return Check::fail(
+                format!("Peer {} is reachable: {}", peer, e),
+                "Check that the peer runs `syncr serve` and that discovery is enabled on \
+                 both sides, or connect with a ticket from its `syncr info`",
+            )
+        }
+    };
+
+    let check = match Session::open(&connection, config).await {
+        Ok(_) => Check::pass(format!(
+            "Peer {} is reachable and answers, {}",
+            peer,
+            iroh_utils::route(&endpoint, peer)
+        )),
+        Err(e) => Check::fail(
+            format!("Peer {} completes a handshake: {}", peer, e),
+            format!(
+                "The peer may refuse unknown peers; ask it to run `syncr allow {} <path>`",
+                endpoint.id()
+            ),
+        ),
+    };
+    connection.close(0u32.into(), b"done");
+    endpoint.close().await;
+    check
+}
@@ -0,0 +1,120 @@
+//! Exit codes of the `syncr` binary, for scripts to tell failures apart.
+//!
+//! | Code | Meaning |
+//! |------|---------|
+//! | 0 | Success |
+//! | 1 | Any other failure, including invalid arguments |
+//! | 2 | The peer denied access to the path |
+//! | 3 | The peer couldn't be found or reached |
+//! | 4 | The path doesn't exist |
+//! | 5 | The local and remote sides conflict, e.g. `verify` found differences |
+//! | 6 | The transfer was cancelled |
+
+use std::process::ExitCode;
+
+use crate::{
+    cli::copy::Cancelled, iroh_utils::IrohUtilsError, protocol::ErrorCode, protocol::RemoteError,
+};
+
+/// What kind of failure ended a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Other,
+    PermissionDenied,
+    PeerUnreachable,
+    NotFound,
+    Conflict,
+    Cancelled,
+}
+
+impl ErrorKind {
+    /// The kind of `error`, told by the first cause in its chain that says.
+    pub fn of(error: &anyhow::Error) -> Self {
+        error
+            .chain()
+            .find_map(|cause| {
+                if let Some(e) = cause.downcast_ref::<CommandError>() {
+                    return Some(e.kind);
+                }
+                if cause.is::<Cancelled>() {
+                    return Some(ErrorKind::Cancelled);
+                }
+                if let Some(e) = cause.downcast_ref::<RemoteError>() {
+                    return match e.code {
+                        ErrorCode::AccessDenied => Some(ErrorKind::PermissionDenied),
+                        ErrorCode::NotFound => Some(ErrorKind::NotFound),
+                        _ => None,
+                    };
+                }
+                match cause.downcast_ref::<IrohUtilsError>()? {
+                    IrohUtilsError::PeerNotFound(_)
+                    | IrohUtilsError::PeerUnreachable(_)
+                    | IrohUtilsError::ConnectTimedOut(..) => Some(ErrorKind::PeerUnreachable),
+                    _ => None,
+                }
+            })
+            .unwrap_or(ErrorKind::Other)
+    }
+
+    pub fn exit_code(self) -> ExitCode {
+        ExitCode::from(match self {
+            ErrorKind::Other => 1,
+            ErrorKind::PermissionDenied => 2,
+            ErrorKind::PeerUnreachable => 3,
+            ErrorKind::NotFound => 4,
+            ErrorKind::Conflict => 5,
+            ErrorKind::Cancelled => 6,
+        })
+    }
+}
+
+/// A failure a command reports itself, with the kind it exits as.
+#[derive(Debug, thiserror::Error)]
+#[error("{message}")]
+pub struct CommandError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl CommandError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::{CommandError, ErrorKind};
+    use crate::{
+        cli::copy::Cancelled,
+        protocol::{ErrorCode, RemoteError},
+    };
+
+    #[test]
+    fn kind_is_told_by_a_cause_below_context() {
+        let denied: anyhow::Result<()> = Err(RemoteError {
+            code: ErrorCode::AccessDenied,
+            message: "not shared".to_string(),
+        }
+        .into());
+        let err = denied.context("Failed to sync").unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::PermissionDenied);
+        let refused = anyhow::Error::new(CommandError::new(
+            ErrorKind::PermissionDenied,
+            "not allowed to sync",
+        ));
+        assert_eq!(ErrorKind::of(&refused), ErrorKind::PermissionDenied);
+
+        let err = anyhow::Error::new(Cancelled).context("Failed to copy");
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Cancelled);
+        assert_eq!(
+            ErrorKind::of(&anyhow::anyhow!("disk full")),
+            ErrorKind::Other
+        );
+    }
+}
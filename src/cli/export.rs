@@ -0,0 +1,205 @@
+use anyhow::{Context, Result};
+use iroh::PublicKey;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::store::{AccessMode, Store, WatchOptions};
+
+/// Version of the export document layout.
+const EXPORT_VERSION: u32 = 1;
+
+/// Everything needed to recreate a setup on another machine. Sync state such
+/// as last-sync hashes is left out since it only holds for this machine.
+#[derive(Debug, Serialize, Deserialize)]
+struct Export {
+    version: u32,
+    watches: Vec<PathBuf>,
+    /// Watches on just the top level of a directory
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    top_level_watches: Vec<PathBuf>,
+    permissions: Vec<ExportedGrant>,
+    syncs: Vec<ExportedSync>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportedGrant {
+    path: PathBuf,
+    peer: String,
+    mode: AccessMode,
+    /// Unix timestamp the grant expires at
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportedSync {
+    local_path: PathBuf,
+    peer: String,
+    remote_path: String,
+    /// Subpaths the sync is limited to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    include: Vec<String>,
+    /// Whether hidden files are synced too
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    include_hidden: bool,
+    /// Whether the peer asked for the sync rather than it being set up here
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    requested_by_peer: bool,
+}
+
+pub fn run_export(store: &Store, output: Option<PathBuf>) -> Result<()> {
+    let mut export = Export {
+        version: EXPORT_VERSION,
+        watches: Vec::new(),
+        top_level_watches: Vec::new(),
+        permissions: Vec::new(),
+        syncs: Vec::new(),
+    };
+    for (path, options) in store.list_watches_with_options()? {
+        if options.recursive {
+            export.watches.push(path);
+        } else {
+            export.top_level_watches.push(path);
+        }
+    }
+    for (path, grants) in store.list_all_permissions()? {
+        for grant in grants {
+            export.permissions.push(ExportedGrant {
+                path: path.clone(),
+                peer: grant.peer.to_string(),
+                mode: grant.mode,
+                expires_at: grant.expires_at,
+            });
+        }
+    }
+    for (local_path, configs) in store.list_syncs()? {
+        for config in configs {
+            export.syncs.push(ExportedSync {
+                local_path: local_path.clone(),
+                peer: config.peer.to_string(),
+                remote_path: config.remote_path,
+                include: config.include,
+                include_hidden: config.include_hidden,
+                requested_by_peer: config.requested_by_peer,
+            });
+        }
+    }
+
+    let json = serde_json::to_string_pretty(&export)?;
+    match output {
+        Some(path) => std::fs::write(&path, json + "\n")
+            .with_context(|| format!("Failed to write {:?}", path))?,
+        None => println!("{}", json),
+    }
+    Ok(())
+}
+
+/// Bytes of the store's fingerprint printed, enough to tell setups apart.
+const FINGERPRINT_LEN: usize = 8;
+
+pub fn run_fingerprint(store: &Store) -> Result<()> {
+    let fingerprint = store.fingerprint()?;
+    let hex: String = fingerprint[..FINGERPRINT_LEN]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    println!("{}", hex);
+    Ok(())
+}
+
+/// Load an export into `store`, keeping existing entries. With `rebase`,
+/// local paths under the first directory are moved under the second.
+pub fn run_import(store: &Store, file: PathBuf, rebase: Option<Vec<PathBuf>>) -> Result<()> {
+    let contents =
+        std::fs::read_to_string(&file).with_context(|| format!("Failed to read {:?}", file))?;
+    let export: Export =
+        serde_json::from_str(&contents).with_context(|| format!("Failed to parse {:?}", file))?;
+    if export.version != EXPORT_VERSION {
+        anyhow::bail!(
+            "Unsupported export version {} (expected {})",
+            export.version,
+            EXPORT_VERSION
+        );
+    }
+
+    let rebase = rebase.as_deref().map(|paths| (&paths[0], &paths[1]));
+    let local = |path: PathBuf| match rebase {
+        Some((old, new)) => rebase_path(path, old, new),
+        None => path,
+    };
+
+    for path in &export.watches {
+        store.add_watch(local(path.clone()))?;
+    }
+    for path in &export.top_level_watches {
+        store.add_watch_with(
+            local(path.clone()),
+            WatchOptions {
+                recursive: false,
+                ..Default::default()
+            },
+        )?;
+    }
+    for grant in &export.permissions {
+        store.allow_peer_until(
+            local(grant.path.clone()),
+            parse_peer(&grant.peer)?,
+            grant.mode,
+            grant.expires_at,
+        )?;
+    }
+
+    let existing = store.list_syncs()?;
+    let mut added_syncs = 0;
+    for sync in export.syncs {
+        let local_path = local(sync.local_path);
+        let peer = parse_peer(&sync.peer)?;
+        let already_present = existing.iter().any(|(path, configs)| {
+            *path == local_path
+                && configs
+                    .iter()
+                    .any(|c| c.peer == peer && c.remote_path == sync.remote_path)
+        });
+        if !already_present {
+            if sync.requested_by_peer {
+                store.add_peer_sync_with_watch(
+                    peer,
+                    sync.remote_path.clone(),
+                    local_path.clone(),
+                    sync.include_hidden,
+                )?;
+            } else {
+                store.add_sync(peer, sync.remote_path.clone(), local_path.clone())?;
+                store.set_sync_include_hidden(
+                    &local_path,
+                    peer,
+                    &sync.remote_path,
+                    sync.include_hidden,
+                )?;
+            }
+            store.set_sync_include(&local_path, peer, &sync.remote_path, sync.include)?;
+            added_syncs += 1;
+        }
+    }
+
+    println!(
+        "Imported {} watches, {} permissions and {} syncs",
+        export.watches.len(),
+        export.permissions.len(),
+        added_syncs
+    );
+    Ok(())
+}
+
+fn parse_peer(peer: &str) -> Result<PublicKey> {
+    PublicKey::from_str(peer).with_context(|| format!("Invalid peer ID in export: {}", peer))
+}
+
+fn rebase_path(path: PathBuf, old: &Path, new: &Path) -> PathBuf {
+    match path.strip_prefix(old) {
+        Ok(rest) if rest.as_os_str().is_empty() => new.to_path_buf(),
+        Ok(rest) => new.join(rest),
+        Err(_) => path,
+    }
+}
@@ -0,0 +1,46 @@
+use crate::store::Store;
+use anyhow::Result;
+
+/// Report the store entries left behind by paths that no longer exist and,
+/// unless `dry_run`, remove them. Entries that can't be decoded are skipped
+/// with a warning rather than stopping the rest from being cleaned up.
+pub fn run(store: &Store, dry_run: bool) -> Result<()> {
+    let store = store.tolerant();
+    let orphans = store.find_orphans()?;
+    if orphans.is_empty() {
+        println!("Nothing to clean up.");
+        return Ok(());
+    }
+
+    for path in &orphans.watches {
+        println!("Watch on missing path: {}", path.display());
+    }
+    for (path, configs) in &orphans.syncs {
+        for config in configs {
+            println!(
+                "Sync of {} from {} into missing path: {}",
+                config.remote_path,
+                config.peer,
+                path.display()
+            );
+        }
+    }
+    for path in &orphans.permissions {
+        println!("Unused permissions on: {}", path.display());
+    }
+
+    let syncs: usize = orphans.syncs.iter().map(|(_, configs)| configs.len()).sum();
+    let summary = format!(
+        "{} watch(es), {} sync(s) and {} permission entry(ies)",
+        orphans.watches.len(),
+        syncs,
+        orphans.permissions.len()
+    );
+    if dry_run {
+        println!("Would remove {}", summary);
+    } else {
+        store.remove_orphans(&orphans)?;
+        println!("Removed {}", summary);
+    }
+    Ok(())
+}
@@ -0,0 +1,64 @@
+use crate::store::Store;
+use anyhow::Result;
+use iroh::PublicKey;
+
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() {
+        anyhow::bail!("Group name must not be empty");
+    }
+    if name.chars().any(char::is_whitespace) {
+        anyhow::bail!("Group name '{}' must not contain whitespace", name);
+    }
+    Ok(())
+}
+
+pub fn run_create(store: &Store, name: String) -> Result<()> {
+    validate_name(&name)?;
+    if store.create_group(&name)? {
+        println!("Created group {}", name);
+    } else {
+        println!("Group {} already exists", name);
+    }
+    Ok(())
+}
+
+pub fn run_delete(store: &Store, name: String) -> Result<()> {
+    if store.delete_group(&name)? {
+        println!("Deleted group {} and its grants", name);
+    } else {
+        println!("No such group: {}", name);
+    }
+    Ok(())
+}
+
+pub fn run_add(store: &Store, name: String, peer: PublicKey) -> Result<()> {
+    if store.add_group_member(&name, peer)? {
+        println!("Added peer {} to group {}", peer, name);
+    } else {
+        println!("Peer {} is already in group {}", peer, name);
+    }
+    Ok(())
+}
+
+pub fn run_remove(store: &Store, name: String, peer: PublicKey) -> Result<()> {
+    if store.remove_group_member(&name, peer)? {
+        println!("Removed peer {} from group {}", peer, name);
+    } else {
+        println!("Peer {} is not in group {}", peer, name);
+    }
+    Ok(())
+}
+
+pub fn run_list(store: &Store) -> Result<()> {
+    let groups = store.list_groups()?;
+    if groups.is_empty() {
+        println!("No groups defined.");
+    }
+    for (name, members) in groups {
+        println!("{}", name);
+        for peer in members {
+            println!("    {}", peer);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::path::Path;
+use walkdir::WalkDir;
+
+use crate::{protocol::to_wire_path, sync_utils, trash::TRASH_DIR};
+
+/// BLAKE3 hash of one file, as `syncr hash --json` prints it.
+#[derive(Debug, Serialize)]
+pub struct FileHash {
+    /// Path below the hashed directory in wire form, or the file as given
+    pub path: String,
+    pub hash: String,
+}
+
+/// Print the BLAKE3 hash of `path`, or of every file below it in path
+/// order, one `hash  path` line each or as JSON. The hashes are the ones
+/// syncs and `verify` compare.
+pub async fn run(path: &Path, json: bool) -> Result<()> {
+    let hashes = hash_tree(path).await?;
+    if json {
+        println!("{}", serde_json::to_string(&hashes)?);
+        return Ok(());
+    }
+    for FileHash { path, hash } in hashes {
+        println!("{}  {}", hash, path);
+    }
+    Ok(())
+}
+
+/// Hashes of `path` if it's a file, or else of the files below it, sorted
+/// by path. The trash is left out, as syncs leave it out.
+pub async fn hash_tree(path: &Path) -> Result<Vec<FileHash>> {
+    if path.is_file() {
+        let hash = sync_utils::hash_file(path).await?;
+        return Ok(vec![FileHash {
+            path: path.display().to_string(),
+            hash: blake3::Hash::from(hash).to_hex().to_string(),
+        }]);
+    }
+
+    let mut hashes = Vec::new();
+    let entries = WalkDir::new(path)
+        .into_iter()
+        .filter_entry(|e| e.file_name() != TRASH_DIR);
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let hash = sync_utils::hash_file(entry.path()).await?;
+        hashes.push(FileHash {
+            path: to_wire_path(entry.path().strip_prefix(path)?),
+            hash: blake3::Hash::from(hash).to_hex().to_string(),
+        });
+    }
+    // Directories list in no particular order.
+    hashes.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(hashes)
+}
@@ -0,0 +1,49 @@
+use anyhow::Result;
+use indicatif::HumanBytes;
+use std::path::Path;
+
+use crate::{
+    cli::copy,
+    store::{Store, SyncOutcome},
+};
+
+/// Print the logged transfers of the syncs into `local_path`, oldest first,
+/// or as JSON.
+pub fn run(store: &Store, local_path: &Path, json: bool) -> Result<()> {
+    let entries = store.sync_log(local_path)?;
+    if json {
+        println!("{}", serde_json::to_string(&entries)?);
+        return Ok(());
+    }
+    if entries.is_empty() {
+        println!("Nothing has been synced into {:?} yet.", local_path);
+        return Ok(());
+    }
+    for entry in entries {
+        let outcome = match &entry.outcome {
+            SyncOutcome::Completed => format!(
+                "{} files, {}, {} conflicts",
+                entry.files,
+                HumanBytes(entry.bytes),
+                entry.conflicts
+            ),
+            SyncOutcome::Failed(error) => format!("failed: {}", error),
+        };
+        println!(
+            "{:>12}  {:<4}  {}:{}  {}",
+            copy::describe_age(entry.timestamp),
+            entry.direction,
+            entry.peer.fmt_short(),
+            entry.remote_path,
+            outcome
+        );
+        for path in &entry.changed {
+            println!("      {}", path);
+        }
+        let unlisted = entry.files.saturating_sub(entry.changed.len() as u64);
+        if unlisted > 0 {
+            println!("      and {} more", unlisted);
+        }
+    }
+    Ok(())
+}
@@ -1,24 +1,57 @@
 use anyhow::Result;
-use iroh::{
-    discovery::{dns::DnsDiscovery, mdns::MdnsDiscovery, pkarr::PkarrPublisher},
-    Endpoint,
-};
-
-use crate::iroh_utils;
-
-pub async fn run() -> Result<()> {
-    let secret_key = iroh_utils::load_secret_key().await?;
-    // Create an endpoint with a random secret key and default configuration
-    let endpoint = Endpoint::builder()
-        .discovery(PkarrPublisher::n0_dns())
-        .discovery(DnsDiscovery::n0_dns())
-        .discovery(MdnsDiscovery::builder())
-        .secret_key(secret_key)
-        .bind()
-        .await?;
-
-    println!("Version: {}", env!("CARGO_PKG_VERSION"));
-    println!("Peer ID: {}", endpoint.id());
+use iroh::{EndpointAddr, PublicKey};
+use iroh_tickets::endpoint::EndpointTicket;
+use std::time::Duration;
+
+use crate::{config::Config, iroh_utils};
+
+/// How long to wait for a relay before describing the node with only direct
+/// addresses.
+const ONLINE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What identifies this node to its peers.
+#[derive(Debug, Clone)]
+pub struct NodeInfo {
+    /// Version of syncr running here.
+    pub version: &'static str,
+    /// Peer ID others allow and connect to.
+    pub peer_id: PublicKey,
+    /// Addresses the node could be reached at, as put in its ticket.
+    pub node_addr: EndpointAddr,
+}
+
+impl NodeInfo {
+    /// Ticket peers can pass to `syncr connect`.
+    pub fn ticket(&self) -> EndpointTicket {
+        EndpointTicket::new(self.node_addr.clone())
+    }
+}
+
+/// Describe the node for the identity in `config`'s home, generating one if
+/// there is none yet.
+pub async fn node_info(config: &Config) -> Result<NodeInfo> {
+    let endpoint = iroh_utils::build_endpoint(config).await?;
+    if config.relay.enabled {
+        let _ = tokio::time::timeout(ONLINE_TIMEOUT, endpoint.online()).await;
+    }
+    let info = NodeInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        peer_id: endpoint.id(),
+        node_addr: endpoint.addr(),
+    };
+    endpoint.close().await;
+    Ok(info)
+}
+
+pub async fn run(config: &Config, profile: Option<&str>) -> Result<()> {
+    let info = node_info(config).await?;
+
+    println!("Version: {}", info.version);
+    if let Some(profile) = profile {
+        println!("Profile: {}", profile);
+    }
+    println!("Peer ID: {}", info.peer_id);
+    println!("Ticket: {}", info.ticket());
 
     Ok(())
 }
@@ -0,0 +1,38 @@
+use anyhow::{Context, Result};
+use std::path::Path;
+
+use super::KeyCommands;
+use crate::{config::Config, iroh_utils};
+
+pub async fn run(config: &Config, command: KeyCommands) -> Result<()> {
+    let home = config.home_dir()?;
+    match command {
+        KeyCommands::Show => {
+            iroh_utils::init_secret_key(&home).await?;
+            let secret_key = iroh_utils::load_secret_key(&home).await?;
+            println!("{}", secret_key.public());
+        }
+        KeyCommands::Rotate => {
+            let (secret_key, backup) = iroh_utils::rotate_secret_key(&home)
+                .await
+                .context("Failed to rotate secret key")?;
+            report(&secret_key, backup.as_deref());
+        }
+        KeyCommands::Import { file } => {
+            let (secret_key, backup) = iroh_utils::import_secret_key(&home, &file)
+                .await
+                .with_context(|| format!("Failed to import secret key from {}", file.display()))?;
+            report(&secret_key, backup.as_deref());
+        }
+    }
+    Ok(())
+}
+
+fn report(secret_key: &iroh::SecretKey, backup: Option<&Path>) {
+    if let Some(backup) = backup {
+        println!("Previous key backed up to {}", backup.display());
+    }
+    println!("Peer ID: {}", secret_key.public());
+    println!("Peers that allowed the old ID must `syncr allow` this one before it can reach them.");
+    println!("Restart a running server to use the new key.");
+}
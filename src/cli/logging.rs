@@ -0,0 +1,74 @@
+//! Where `tracing` output goes: stderr, a daily rotated file, or both.
+
+use anyhow::{Context, Result};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+use crate::{config::Config, progress};
+
+/// The filter directive for `-v` given `verbose` times, or for `-q`. `None`
+/// leaves the default filter in place.
+pub fn directive(verbose: u8, quiet: bool) -> Option<&'static str> {
+    if quiet {
+        return Some("off");
+    }
+    match verbose {
+        0 => None,
+        1 => Some("syncr=info"),
+        2 => Some("syncr=debug"),
+        _ => Some("syncr=trace"),
+    }
+}
+
+/// Install the global subscriber, writing to stderr and/or to files named
+/// `syncr.<date>.log` in the config's log dir. `directive` sets the level
+/// unless `RUST_LOG` does.
+pub fn init(config: &Config, file: bool, stderr: bool, directive: Option<&str>) -> Result<()> {
+    let stderr_layer =
+        stderr.then(|| tracing_subscriber::fmt::layer().with_writer(|| progress::LogWriter));
+
+    let file_layer = if file {
+        let dir = config.log_dir()?;
+        let appender = RollingFileAppender::builder()
+            .rotation(Rotation::DAILY)
+            .filename_prefix("syncr")
+            .filename_suffix("log")
+            .build(&dir)
+            .with_context(|| format!("Failed to open log directory {:?}", dir))?;
+        Some(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(appender),
+        )
+    } else {
+        None
+    };
+
+    let filter = match directive {
+        Some(directive) if std::env::var_os(EnvFilter::DEFAULT_ENV).is_none() => {
+            EnvFilter::new(directive)
+        }
+        _ => EnvFilter::from_default_env(),
+    };
+    tracing_subscriber::registry()
+        .with(stderr_layer)
+        .with(file_layer)
+        .with(filter)
+        .try_init()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::directive;
+
+    #[test]
+    fn verbosity_flags_map_to_levels() {
+        assert_eq!(directive(0, false), None);
+        assert_eq!(directive(1, false), Some("syncr=info"));
+        assert_eq!(directive(2, false), Some("syncr=debug"));
+        assert_eq!(directive(3, false), Some("syncr=trace"));
+        assert_eq!(directive(5, false), Some("syncr=trace"));
+        assert_eq!(directive(0, true), Some("off"));
+    }
+}
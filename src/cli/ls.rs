@@ -0,0 +1,127 @@
+use anyhow::Result;
+use indicatif::HumanBytes;
+use iroh::{Endpoint, PublicKey};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{
+    cli::copy::{self, Session},
+    config::Config,
+    iroh_utils,
+    protocol::{trim_wire_path, FileMetadata, FileType, Message, RemoteError},
+    store::Store,
+};
+
+/// One entry of a remote listing, as `syncr ls --json` prints it.
+#[derive(Debug, Serialize)]
+pub struct ListedEntry<'a> {
+    pub path: &'a str,
+    pub size: u64,
+    /// Unix timestamp of the last modification
+    pub modified: u64,
+    #[serde(rename = "type")]
+    pub file_type: &'static str,
+    /// Where a symlink points, or the path a hard link shares contents with
+    pub link_target: Option<&'a str>,
+}
+
+impl<'a> From<&'a FileMetadata> for ListedEntry<'a> {
+    fn from(file: &'a FileMetadata) -> Self {
+        ListedEntry {
+            path: &file.path,
+            size: file.len,
+            modified: file.modified,
+            file_type: type_name(file.file_type),
+            link_target: file.link_target.as_deref(),
+        }
+    }
+}
+
+/// Print what `remote_path` on `peer` lists, one entry per line or as JSON.
+pub async fn run(
+    config: &Config,
+    store: &Store,
+    peer: PublicKey,
+    remote_path: String,
+    json: bool,
+) -> Result<()> {
+    let endpoint = iroh_utils::build_endpoint(config).await?;
+    iroh_utils::add_known_addresses(&endpoint, store.list_addresses()?);
+    let files = run_with(&endpoint, config, peer, remote_path).await?;
+
+    if json {
+        let entries: Vec<ListedEntry> = files.iter().map(ListedEntry::from).collect();
+        println!("{}", serde_json::to_string(&entries)?);
+        return Ok(());
+    }
+    for file in &files {
+        let size = match file.file_type {
+            FileType::File | FileType::HardLink => HumanBytes(file.len).to_string(),
+            FileType::Dir | FileType::Symlink => "-".to_string(),
+        };
+        let path = match &file.link_target {
+            Some(target) => format!("{} -> {}", file.path, target),
+            None => file.path.clone(),
+        };
+        println!(
+            "{:<8}  {:>10}  {:>12}  {}",
+            type_name(file.file_type),
+            size,
+            copy::describe_age(file.modified),
+            path
+        );
+    }
+    Ok(())
+}
+
+/// List `remote_path` on `peer` without transferring any file contents.
+pub async fn run_with(
+    endpoint: &Endpoint,
+    config: &Config,
+    peer: PublicKey,
+    remote_path: String,
+) -> Result<Vec<FileMetadata>> {
+    let remote_path = trim_wire_path(&remote_path).to_string();
+    let connection = iroh_utils::connect(endpoint, config.network(), peer).await?;
+    let mut session = Session::open(&connection, config).await?;
+
+    info!("Requesting listing for {}", remote_path);
+    let list_req = Message::ListRequest {
+        path: remote_path.clone(),
+        with_hashes: false,
+        follow_symlinks: false,
+    };
+    session.write(&list_req).await?;
+
+    let mut listing = Vec::new();
+    loop {
+        let msg = session.read().await?.into_current();
+        match msg {
+            Message::ListResponse { files, is_last } => {
+                listing.extend(files);
+                if is_last {
+                    break;
+                }
+            }
+            Message::ListSkipped { count } => {
+                warn!(
+                    "{} entries of {} could not be listed by the peer",
+                    count, remote_path
+                );
+            }
+            Message::Error { code, message } => return Err(RemoteError { code, message }.into()),
+            _ => anyhow::bail!("Unexpected message: {:?}", msg),
+        }
+    }
+    session.send.finish()?;
+    Ok(listing)
+}
+
+fn type_name(file_type: FileType) -> &'static str {
+    match file_type {
+        FileType::File => "file",
+        FileType::Dir => "dir",
+        FileType::Symlink => "symlink",
+        FileType::HardLink => "hardlink",
+    }
+}
@@ -1,20 +1,189 @@
-use anyhow::Result;
-use clap::{Parser, Subcommand};
-use iroh::PublicKey;
-use std::path::PathBuf;
+use anyhow::{Context, Result};
+use clap::{Args, Parser, Subcommand};
+use iroh::{EndpointAddr, PublicKey, RelayUrl};
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use tokio_util::sync::CancellationToken;
 
-use crate::store::Store;
+use crate::{
+    config::{self, Config},
+    rate_limit,
+    store::{Store, WatchOptions},
+};
+use peer::PeerRef;
 
 mod allow;
 pub mod copy; // Make public for sync to use
-mod info;
+mod daemon;
+mod doctor;
+pub mod exit;
+mod export;
+mod gc;
+mod group;
+mod hash;
+mod history;
+pub mod info;
+mod key;
+pub mod logging;
+pub mod ls;
+mod peer;
+mod profile;
+pub mod push;
+pub mod resync;
 pub mod serve;
-mod sync;
+pub mod shares;
+mod status;
+pub(crate) mod sync;
+pub mod verify;
 mod watch;
 
+/// Entry point of the `syncr` binary, exiting with the code for how the
+/// command ended.
+pub fn run() -> ExitCode {
+    match main() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            exit::ErrorKind::of(&e).exit_code()
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::try_parse().unwrap_or_else(|e| {
+        // clap's own exit code for a usage error is taken by denied access.
+        let _ = e.print();
+        std::process::exit(if e.use_stderr() { 1 } else { 0 });
+    });
+    let config = cli.load_config()?;
+    logging::init(
+        &config,
+        cli.log_file || config.log.file,
+        !cli.no_log_stderr && config.log.stderr,
+        logging::directive(cli.verbose, cli.quiet),
+    )?;
+    tracing::debug!("Running {:?}", cli.command);
+
+    // These run before the store is opened: a background server holds its
+    // lock, and forking has to happen before any threads are started.
+    if let Commands::Serve {
+        daemon: detach,
+        stop,
+        status,
+        ..
+    } = cli.command
+    {
+        if stop {
+            return daemon::stop(&config);
+        }
+        if status {
+            return daemon::status(&config);
+        }
+        if detach {
+            daemon::detach(&config)?;
+        }
+    }
+
+    tokio::runtime::Runtime::new()?.block_on(async {
+        // Doctor opens the store itself, to report if it can't.
+        if let Commands::Doctor { peer } = cli.command {
+            return doctor::run(&config, peer).await;
+        }
+        // The identity doesn't live in the store, which a running server
+        // holds locked.
+        if let Commands::Key { command } = cli.command {
+            return key::run(&config, command).await;
+        }
+        // Hashing only reads local files.
+        if let Commands::Hash { path, json } = &cli.command {
+            return hash::run(path, *json).await;
+        }
+        // Asked of the running server, which holds the store.
+        match cli.command {
+            Commands::Status {
+                health,
+                sort_by_health,
+            } => return status::run_status(&config, health, sort_by_health).await,
+            Commands::Reload => return status::run_reload(&config).await,
+            Commands::Cancel => return status::run_cancel(&config).await,
+            _ => {}
+        }
+        // Profiles are listed from the home they are all in.
+        if let Commands::Profile { command } = &cli.command {
+            return match command {
+                ProfileCommands::List => {
+                    profile::run_list(&cli.base_home()?, cli.profile.as_deref())
+                }
+            };
+        }
+
+        // Initialize store
+        let data_dir = config.data_dir()?;
+        let store = Store::new(&data_dir).context("Failed to initialize store")?;
+
+        cli.run(config, store).await
+    })
+}
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
+    /// Path to the config file (defaults to config.toml in the syncr config dir)
+    #[arg(long, global = true, env = crate::config::CONFIG_ENV)]
+    pub config: Option<PathBuf>,
+    /// Directory holding the config, identity and database (defaults to the
+    /// syncr config dir)
+    #[arg(long, global = true, env = crate::config::HOME_ENV)]
+    pub home: Option<PathBuf>,
+    /// Use the named profile, with its own config, identity and database in
+    /// `profiles/<name>` in the syncr home
+    #[arg(
+        long,
+        global = true,
+        env = crate::config::PROFILE_ENV,
+        value_parser = config::parse_profile
+    )]
+    pub profile: Option<String>,
+    /// Also log to a daily rotated file in the log dir (`logs` in the syncr
+    /// home by default)
+    #[arg(long, global = true)]
+    pub log_file: bool,
+    /// Don't log to stderr
+    #[arg(long, global = true)]
+    pub no_log_stderr: bool,
+    /// Log more: info with -v, debug with -vv, trace with -vvv (RUST_LOG,
+    /// if set, takes precedence)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+    /// Don't log at all (RUST_LOG, if set, takes precedence)
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// Bytes of a file sent per message (e.g. 64K, 1M; default from the
+    /// config, or 256K)
+    #[arg(long, global = true, value_parser = crate::config::parse_chunk_size)]
+    pub chunk_size: Option<usize>,
+    /// Chunks a peer may have in flight to us per stream (default from the
+    /// config, or 8)
+    #[arg(long, global = true, value_parser = crate::config::parse_window)]
+    pub window: Option<u32>,
+    /// Local address to bind to, e.g. 192.168.1.5:0 (default from the
+    /// config, or every interface)
+    #[arg(long, global = true)]
+    pub bind: Option<SocketAddr>,
+    /// Only connect to peers on the isolated network of this name, e.g. a
+    /// staging one (default from the config, or none)
+    #[arg(long, global = true, value_parser = config::parse_network)]
+    pub network: Option<String>,
+    /// Don't use relays; peers are only reached directly, found via mDNS on
+    /// the local network or from tickets
+    #[arg(long, global = true, conflicts_with = "relay_url")]
+    pub no_relay: bool,
+    /// Relay to use instead of the public n0 ones; endpoints fail to start
+    /// if it can't be reached
+    #[arg(long, global = true, value_parser = config::parse_relay_url)]
+    pub relay_url: Option<RelayUrl>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -23,59 +192,867 @@ pub struct Cli {
 enum Commands {
     /// Get peer id and version info
     Info,
+    /// Show, rotate or import this node's identity
+    Key {
+        #[command(subcommand)]
+        command: KeyCommands,
+    },
+    /// List the profiles kept in the syncr home
+    Profile {
+        #[command(subcommand)]
+        command: ProfileCommands,
+    },
     /// Manage watched files
     Watch {
-        /// The path to watch. If omitted, lists watched paths.
-        path: Option<PathBuf>,
-        /// Delete the watch for the specified path
+        /// The paths to watch. If omitted, lists watched paths.
+        paths: Vec<PathBuf>,
+        /// Delete the watches for the specified paths
         #[arg(short, long)]
         delete: bool,
+        /// Only watch the top level of a directory, not what is below it
+        #[arg(long, conflicts_with = "delete")]
+        no_recursive: bool,
+        /// Also rescan the paths this often (e.g. 30s, 5m) for changes the
+        /// file system doesn't report, as on network mounts
+        #[arg(long, value_parser = allow::parse_duration, conflicts_with = "delete")]
+        poll_interval: Option<std::time::Duration>,
+        /// Only poll, without listening for file system notifications
+        #[arg(long, requires = "poll_interval")]
+        no_notify: bool,
+    },
+    /// Allow a peer, or every member of a group, to access a path
+    #[command(allow_missing_positional = true)]
+    Allow {
+        #[arg(value_parser = PeerRef::parse, required_unless_present = "group")]
+        peer: Option<PeerRef>,
+        path: PathBuf,
+        /// Grant the access to this group instead of a single peer
+        #[arg(long, conflicts_with = "peer")]
+        group: Option<String>,
+        /// What the peer may do with the path
+        #[arg(long, value_enum, default_value_t = allow::ModeArg::ReadWrite)]
+        mode: allow::ModeArg,
+        /// Revoke the access automatically after this long (e.g. 30m, 12h, 7d)
+        #[arg(long, value_parser = allow::parse_duration)]
+        expires: Option<std::time::Duration>,
     },
-    /// Allow a peer to access a path
-    Allow { peer: PublicKey, path: PathBuf },
     /// Disallow a peer from accessing a path
-    Disallow { peer: PublicKey, path: PathBuf },
+    Disallow {
+        #[arg(value_parser = PeerRef::parse)]
+        peer: PeerRef,
+        #[arg(required_unless_present = "all")]
+        path: Option<PathBuf>,
+        /// Revoke the peer's access to every path
+        #[arg(long, conflicts_with = "path")]
+        all: bool,
+    },
+    /// List every peer with access and the paths it may use
+    Peers {
+        /// Group by peer (the default)
+        #[arg(long, conflicts_with = "by_path")]
+        by_peer: bool,
+        /// Group by path instead of by peer
+        #[arg(long)]
+        by_path: bool,
+    },
+    /// Store a peer's addresses from a ticket, to reach it without discovery
+    Connect {
+        /// Ticket printed by `syncr info` on the peer
+        #[arg(long, value_parser = peer::parse_ticket)]
+        ticket: EndpointAddr,
+    },
+    /// Manage peer aliases
+    Peer {
+        #[command(subcommand)]
+        command: PeerCommands,
+    },
+    /// Manage groups of peers that can be allowed access together
+    Group {
+        #[command(subcommand)]
+        command: GroupCommands,
+    },
+    /// Export or import watches, permissions and syncs
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+    /// Remove watches, syncs and permissions for paths that no longer exist
+    Gc {
+        /// Only report what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
     /// Run the syncr daemon/server to accept connections
-    Serve,
+    Serve {
+        /// Cap bandwidth per connection, in bytes per second (e.g. 500K, 1M)
+        #[arg(long, value_parser = rate_limit::parse_rate)]
+        limit_rate: Option<u64>,
+        /// Refuse connections from peers with no grants or syncs
+        #[arg(long)]
+        strict_peers: bool,
+        /// Detach and keep serving in the background, logging to syncr.log
+        /// and writing syncr.pid in the syncr home (Unix only)
+        #[arg(long)]
+        daemon: bool,
+        /// Stop the server started with --daemon
+        #[arg(long, conflicts_with_all = ["daemon", "status"])]
+        stop: bool,
+        /// Report whether a server started with --daemon is running
+        #[arg(long, conflicts_with = "daemon")]
+        status: bool,
+        /// Serve a single connection, then shut down
+        #[arg(long, conflicts_with_all = ["daemon", "stop", "status"])]
+        one_shot: bool,
+        /// Only serve this peer (peer ID, ticket or alias), refusing a
+        /// connection from any other; with --one-shot, that ends the serve
+        #[arg(long, value_parser = PeerRef::parse, requires = "one_shot")]
+        expect_peer: Option<PeerRef>,
+        /// Serve metrics for Prometheus at http://<addr>/metrics
+        #[arg(long)]
+        metrics_addr: Option<SocketAddr>,
+    },
+    /// Show what the running server is doing and how each sync is faring
+    Status {
+        /// Only list syncs in this health (repeatable)
+        #[arg(long, value_enum)]
+        health: Vec<status::HealthArg>,
+        /// List failing syncs first, then stale ones
+        #[arg(long)]
+        sort_by_health: bool,
+    },
+    /// Have the running server pick up watch changes right away
+    Reload,
+    /// Stop the pulls the running server has under way
+    Cancel,
+    /// Check the identity, store, watched paths and, optionally, a peer
+    Doctor {
+        /// Also check that this peer can be reached (peer ID, ticket or alias)
+        #[arg(long, value_parser = PeerRef::parse)]
+        peer: Option<PeerRef>,
+    },
     /// Copy a file from a remote peer
     Copy {
-        /// The peer to copy from
-        peer: PublicKey,
+        /// The peer to copy from (peer ID, ticket or alias)
+        #[arg(value_parser = PeerRef::parse)]
+        peer: PeerRef,
         /// The remote path to copy
         remote_path: String,
-        /// The local destination path
+        /// The local destination path, or `-` to write a single file to stdout
+        local_path: PathBuf,
+        /// Also copy this remote path to this local one, over the same
+        /// connection (repeatable)
+        #[arg(long, value_name = "REMOTE:LOCAL", value_parser = copy::parse_pair)]
+        pair: Vec<(String, PathBuf)>,
+        /// Print the transfer summary as JSON
+        #[arg(long)]
+        json: bool,
+        /// Overwrite differing local files without asking
+        #[arg(long, short = 'y')]
+        yes: bool,
+        /// Never overwrite local files that differ from the remote
+        #[arg(long, conflicts_with = "yes")]
+        no_clobber: bool,
+        #[command(flatten)]
+        transfer: TransferArgs,
+    },
+    /// Push a local file or folder to a remote peer
+    Push {
+        /// The peer to push to (peer ID, ticket or alias)
+        #[arg(value_parser = PeerRef::parse)]
+        peer: PeerRef,
+        /// The local file or folder to push
         local_path: PathBuf,
+        /// Where to write it on the peer, which must grant us write access
+        remote_path: String,
+        /// Cap bandwidth, in bytes per second (e.g. 500K, 1M)
+        #[arg(long, value_parser = rate_limit::parse_rate)]
+        limit_rate: Option<u64>,
     },
     /// Sync a file/folder with a remote peer
     Sync {
-        /// The peer to sync with
-        peer: PublicKey,
+        /// The peer to sync with (peer ID, ticket or alias)
+        #[arg(value_parser = PeerRef::parse)]
+        peer: PeerRef,
         /// The remote path to sync
         remote_path: String,
         /// The local destination path
         local_path: PathBuf,
+        /// Also sync this remote path into this local one, over the same
+        /// connection (repeatable)
+        #[arg(long, value_name = "REMOTE:LOCAL", value_parser = copy::parse_pair)]
+        pair: Vec<(String, PathBuf)>,
+        /// Only sync this path inside the remote path (repeatable)
+        #[arg(long, value_parser = sync::parse_subpath)]
+        only: Vec<String>,
+        /// Only watch the top level of the local directory for changes
+        #[arg(long)]
+        no_recursive: bool,
+        /// Also rescan the local path this often (e.g. 30s, 5m) for changes
+        /// the file system doesn't report, as on network mounts
+        #[arg(long, value_parser = allow::parse_duration)]
+        poll_interval: Option<std::time::Duration>,
+        /// What a later pull does with a local file changed since the last
+        /// sync
+        #[arg(long, value_enum, default_value_t = sync::ConflictArg::ConflictCopy)]
+        on_conflict: sync::ConflictArg,
+        /// Only notify the peer of these kinds of local change
+        /// (comma-separated; all kinds if not given)
+        #[arg(long, value_enum, value_delimiter = ',')]
+        events: Vec<sync::EventArg>,
+        /// Skip the initial copy and only start watching, for a local path
+        /// already known to match the remote one
+        #[arg(long, conflicts_with = "dry_run")]
+        watch_only: bool,
+        /// Print the transfer summary as JSON
+        #[arg(long)]
+        json: bool,
+        #[command(flatten)]
+        transfer: TransferArgs,
+    },
+    /// Copy configured syncs again in full, overwriting local drift
+    Resync {
+        /// The synced local path to restore
+        #[arg(required_unless_present = "all")]
+        local_path: Option<PathBuf>,
+        /// Re-sync every configured path
+        #[arg(long, conflicts_with = "local_path")]
+        all: bool,
+        #[command(flatten)]
+        transfer: TransferArgs,
+    },
+    /// Compare a local path with a remote one without transferring files
+    Verify {
+        /// The peer to compare with (peer ID, ticket or alias)
+        #[arg(value_parser = PeerRef::parse)]
+        peer: PeerRef,
+        /// The remote path to compare
+        remote_path: String,
+        /// The local path to compare
+        local_path: PathBuf,
+    },
+    /// Print the BLAKE3 hash of a file, or of each file below a directory
+    Hash {
+        /// The file or directory to hash
+        path: PathBuf,
+        /// Print the hashes as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show the latest transfers of the syncs into a local path
+    Log {
+        /// The local path of the syncs
+        local_path: PathBuf,
+        /// Print the log as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// List the paths a peer has allowed you to access
+    Shares {
+        /// The peer to ask (peer ID, ticket or alias)
+        #[arg(value_parser = PeerRef::parse)]
+        peer: PeerRef,
+    },
+    /// Print a remote path's listing without transferring any files
+    Ls {
+        /// The peer to ask (peer ID, ticket or alias)
+        #[arg(value_parser = PeerRef::parse)]
+        peer: PeerRef,
+        /// The remote path to list
+        remote_path: String,
+        /// Print the listing as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommands {
+    /// Write all watches, permissions and syncs as JSON
+    Export {
+        /// File to write to instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Load an export, merging it with the existing setup
+    Import {
+        file: PathBuf,
+        /// Rewrite local paths starting with OLD to start with NEW
+        #[arg(long, num_args = 2, value_names = ["OLD", "NEW"])]
+        rebase: Option<Vec<PathBuf>>,
+    },
+    /// Print a short hash of all watches, permissions and syncs, to compare
+    /// setups
+    Fingerprint,
+}
+
+#[derive(Subcommand, Debug)]
+enum KeyCommands {
+    /// Print this node's peer ID
+    Show,
+    /// Replace the secret key with a new one, backing up the old key
+    Rotate,
+    /// Use the secret key in a file, e.g. one copied from another machine
+    Import { file: PathBuf },
+}
+
+#[derive(Subcommand, Debug)]
+enum ProfileCommands {
+    /// List the profiles, marking the one in use
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+enum PeerCommands {
+    /// Give a peer a short name usable wherever a peer ID is expected
+    Add { name: String, peer: PublicKey },
+    /// List peer aliases
+    List,
+    /// Remove a peer alias
+    Rm { name: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum GroupCommands {
+    /// Create an empty group
+    Create { name: String },
+    /// Add a peer to a group; it gets the group's access right away
+    Add {
+        name: String,
+        #[arg(value_parser = PeerRef::parse)]
+        peer: PeerRef,
+    },
+    /// Remove a peer from a group, revoking the access it had through it
+    Remove {
+        name: String,
+        #[arg(value_parser = PeerRef::parse)]
+        peer: PeerRef,
     },
+    /// Delete a group and every grant it holds
+    Delete { name: String },
+    /// List groups and their members
+    List,
+}
+
+/// Flags shared by commands that pull files from a peer
+#[derive(Args, Debug)]
+struct TransferArgs {
+    /// Show progress bars (default when stderr is a terminal)
+    #[arg(long, overrides_with = "no_progress")]
+    progress: bool,
+    /// Never show progress bars
+    #[arg(long)]
+    no_progress: bool,
+    /// Draw progress as bars, or print an NDJSON event on stdout for each
+    /// file started and done and a summary at the end
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = copy::ProgressFormat::Bars,
+        conflicts_with_all = ["progress", "dry_run"]
+    )]
+    progress_format: copy::ProgressFormat,
+    /// Show what would be transferred without writing anything locally
+    #[arg(long)]
+    dry_run: bool,
+    /// Number of files to transfer concurrently
+    #[arg(short, long, default_value_t = copy::DEFAULT_JOBS)]
+    jobs: usize,
+    /// Cap bandwidth in bytes per second (e.g. 500K, 1M)
+    #[arg(long, value_parser = rate_limit::parse_rate)]
+    limit_rate: Option<u64>,
+    /// rsync block size in bytes (picked from each file's size by default)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    block_size: Option<u32>,
+    /// Read every local file to compare it, even if its size and
+    /// modification time haven't changed since it was last read
+    #[arg(long)]
+    checksum: bool,
+    /// Copy the files symlinks point to instead of recreating the links
+    #[arg(long)]
+    follow_symlinks: bool,
+    /// Recreate symlinks that point outside the local path
+    #[arg(long, conflicts_with = "follow_symlinks")]
+    allow_external_links: bool,
+    /// Also copy files and directories whose names start with a dot
+    /// (default from the `include_hidden` config)
+    #[arg(long)]
+    include_hidden: bool,
+    /// Leave out remote files larger than this many bytes (e.g. 100M, 2G)
+    #[arg(long, value_parser = crate::config::parse_max_size)]
+    max_size: Option<u64>,
+    /// Move local files into .syncr-trash before overwriting them (default
+    /// from the `backup` config)
+    #[arg(long, overrides_with = "no_backup")]
+    backup: bool,
+    /// Overwrite local files without backing them up
+    #[arg(long)]
+    no_backup: bool,
+    /// Send the local tree's manifest so the peer answers with only what
+    /// differs, saving round trips on large, mostly synced trees
+    #[arg(long)]
+    manifest: bool,
+    /// Times to retry a file that failed to sync before giving up on it
+    #[arg(long, default_value_t = copy::DEFAULT_RETRIES)]
+    retries: u32,
+    /// Flush each synced file to disk before reporting it done (default from
+    /// the `fsync` config)
+    #[arg(long)]
+    fsync: bool,
+    /// Fetch new files in chunks, sending chunks shared between files only
+    /// once. Uses more CPU and memory on both sides.
+    #[arg(long)]
+    dedup: bool,
+    /// Delete local files the remote no longer has (implies --manifest)
+    #[arg(long)]
+    delete: bool,
+    /// With --delete, delete even more than the `max_delete` share of the
+    /// local files without asking
+    #[arg(long, requires = "delete")]
+    force: bool,
+    /// With --delete, also remove the directories the deletions left empty
+    /// (default from the `prune_empty_dirs` config)
+    #[arg(long, requires = "delete")]
+    prune_empty_dirs: bool,
+    /// On Ctrl-C, leave partly downloaded files for the next run to resume
+    /// instead of removing them
+    #[arg(long)]
+    keep_partial: bool,
+}
+
+impl TransferArgs {
+    fn into_options(self, config: &Config, store: &Store) -> copy::CopyOptions {
+        let ndjson = self.progress_format == copy::ProgressFormat::Ndjson;
+        let progress = if self.no_progress || ndjson {
+            false
+        } else {
+            self.progress || std::io::stderr().is_terminal()
+        };
+        let backup = !self.no_backup && (self.backup || config.backup.enabled);
+        copy::CopyOptions {
+            progress,
+            progress_format: self.progress_format,
+            dry_run: self.dry_run,
+            jobs: self.jobs,
+            limit_rate: self.limit_rate.or(config.limit_rate),
+            base_hash: None,
+            block_size: self.block_size.or(config.block_size),
+            strong_hash_size: config.strong_hash_size,
+            store: Some(store.clone()),
+            checkpoints: Some(store.clone()),
+            checksum: self.checksum,
+            follow_symlinks: self.follow_symlinks,
+            allow_external_links: self.allow_external_links,
+            backup: backup.then(|| config.backup.retention()),
+            include: Vec::new(),
+            include_hidden: self.include_hidden || config.include_hidden,
+            max_size: self.max_size,
+            overwrite: copy::Overwrite::Always,
+            watch_recursive: true,
+            events: None,
+            manifest: self.manifest || self.delete,
+            retries: self.retries,
+            fsync: self.fsync || config.fsync,
+            dedup: self.dedup,
+            delete: self.delete,
+            max_delete: config.max_delete,
+            prune_empty_dirs: self.prune_empty_dirs || config.prune_empty_dirs,
+            delta_min_len: config.delta_min_len,
+            force: self.force,
+            confirm_deletes: std::io::stdin().is_terminal(),
+            cancel: cancel_on_interrupt(),
+            keep_partial: self.keep_partial,
+            ..Default::default()
+        }
+    }
+}
+
+/// Print the counters of a finished copy: as a summary event with
+/// `--progress-format ndjson`, otherwise as a line or as JSON.
+/// A token cancelled by the first Ctrl-C, so a transfer stops cleanly. A
+/// second one ends the process as usual.
+fn cancel_on_interrupt() -> CancellationToken {
+    let cancel = CancellationToken::new();
+    let token = cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            eprintln!("Stopping after the chunks under way; press Ctrl-C again to quit now");
+            token.cancel();
+            if tokio::signal::ctrl_c().await.is_ok() {
+                std::process::exit(130);
+            }
+        }
+    });
+    cancel
+}
+
+fn print_stats(stats: &copy::SyncStats, ndjson: bool, json: bool) -> Result<()> {
+    if ndjson {
+        copy::ProgressEvent::Summary(stats).print()
+    } else {
+        stats.print(json)
+    }
+}
+
+/// Print how each pair of a copy or sync of several went, failing if any
+/// of them did.
+fn report_pairs(
+    reports: &[copy::PairReport],
+    dry_run: bool,
+    ndjson: bool,
+    json: bool,
+) -> Result<()> {
+    let mut failed = 0;
+    for report in reports {
+        let pair = format!("{} -> {}", report.remote_path, report.local_path.display());
+        match &report.result {
+            Ok(copied) if !dry_run => {
+                if !json && !ndjson {
+                    print!("{}: ", pair);
+                }
+                print_stats(&copied.stats, ndjson, json)?;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("{}: {:#}", pair, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("{} of {} pairs failed", failed, reports.len());
+    }
+    Ok(())
 }
 
 impl Cli {
-    pub async fn run(self, store: Store) -> Result<()> {
+    /// The syncr home profiles are kept in: `--home`, or the default one.
+    fn base_home(&self) -> Result<PathBuf> {
+        match &self.home {
+            Some(home) => Ok(home.clone()),
+            None => Ok(config::config_dir()?),
+        }
+    }
+
+    /// Home to load the config from, if not the default: the profile's when
+    /// `--profile` is given, else `--home`.
+    fn profile_home(&self) -> Result<Option<PathBuf>> {
+        match &self.profile {
+            Some(profile) => Ok(Some(config::profile_dir(&self.base_home()?, profile))),
+            None => Ok(self.home.clone()),
+        }
+    }
+
+    /// The config file with the global flags applied.
+    fn load_config(&self) -> Result<Config> {
+        let home = self.profile_home()?;
+        let mut config = Config::load(self.config.as_deref(), home.as_deref())
+            .context("Failed to load config")?;
+        self.override_config(&mut config);
+        Ok(config)
+    }
+
+    /// Apply the global flags that override settings from the config file.
+    pub fn override_config(&self, config: &mut Config) {
+        config.chunk_size = self.chunk_size.unwrap_or(config.chunk_size);
+        config.window = self.window.unwrap_or(config.window);
+        config.bind = self.bind.or(config.bind);
+        if let Some(network) = &self.network {
+            config.network = Some(network.clone());
+        }
+        if self.no_relay {
+            config.relay.enabled = false;
+        }
+        if let Some(url) = &self.relay_url {
+            config.relay.enabled = true;
+            config.relay.url = Some(url.clone());
+        }
+    }
+
+    pub async fn run(self, config: Config, store: Store) -> Result<()> {
         match self.command {
-            Commands::Info => info::run().await?,
-            Commands::Watch { path, delete } => watch::run(&store, path, delete)?,
-            Commands::Allow { peer, path } => allow::run_allow(&store, peer, path)?,
-            Commands::Disallow { peer, path } => allow::run_disallow(&store, peer, path)?,
-            Commands::Serve => serve::run(store).await?,
+            Commands::Info => info::run(&config, self.profile.as_deref()).await?,
+            Commands::Watch {
+                paths,
+                delete,
+                no_recursive,
+                poll_interval,
+                no_notify,
+            } => {
+                let options = WatchOptions {
+                    recursive: !no_recursive,
+                    poll_interval_secs: poll_interval.map(|interval| interval.as_secs()),
+                    notify: !no_notify,
+                };
+                watch::run(&store, paths, delete, options)?
+            }
+            Commands::Allow {
+                peer,
+                path,
+                group,
+                mode,
+                expires,
+            } => match (peer, group) {
+                (_, Some(group)) => allow::run_allow_group(&store, group, path, mode, expires)?,
+                (Some(peer), None) => {
+                    allow::run_allow(&store, peer.resolve(&store)?, path, mode, expires)?
+                }
+                // clap requires one of the peer and --group.
+                (None, None) => unreachable!("allow without a peer or group"),
+            },
+            Commands::Disallow { peer, path, all: _ } => {
+                let peer = peer.resolve(&store)?;
+                // clap ensures exactly one of the path and --all is given.
+                match path {
+                    Some(path) => allow::run_disallow(&store, peer, path)?,
+                    None => allow::run_disallow_all(&store, peer)?,
+                }
+            }
+            Commands::Peers {
+                by_peer: _,
+                by_path,
+            } => allow::run_list_access(&store, by_path)?,
+            Commands::Connect { ticket } => peer::run_connect(&store, ticket)?,
+            Commands::Peer { command } => match command {
+                PeerCommands::Add { name, peer } => peer::run_add(&store, name, peer)?,
+                PeerCommands::List => peer::run_list(&store)?,
+                PeerCommands::Rm { name } => peer::run_remove(&store, name)?,
+            },
+            Commands::Group { command } => match command {
+                GroupCommands::Create { name } => group::run_create(&store, name)?,
+                GroupCommands::Add { name, peer } => {
+                    group::run_add(&store, name, peer.resolve(&store)?)?
+                }
+                GroupCommands::Remove { name, peer } => {
+                    group::run_remove(&store, name, peer.resolve(&store)?)?
+                }
+                GroupCommands::Delete { name } => group::run_delete(&store, name)?,
+                GroupCommands::List => group::run_list(&store)?,
+            },
+            Commands::Config { command } => match command {
+                ConfigCommands::Export { output } => export::run_export(&store, output)?,
+                ConfigCommands::Import { file, rebase } => {
+                    export::run_import(&store, file, rebase)?
+                }
+                ConfigCommands::Fingerprint => export::run_fingerprint(&store)?,
+            },
+            Commands::Gc { dry_run } => gc::run(&store, dry_run)?,
+            Commands::Serve {
+                limit_rate,
+                strict_peers,
+                one_shot,
+                expect_peer,
+                metrics_addr,
+                ..
+            } => {
+                let expect_peer = expect_peer.map(|peer| peer.resolve(&store)).transpose()?;
+                let options = move |config: &Config| serve::ServeOptions {
+                    limit_rate: limit_rate.or(config.limit_rate),
+                    strict_peers,
+                    one_shot,
+                    expect_peer,
+                };
+                let opts = options(&config);
+                // The command is spent; the global flags still apply on reload.
+                let flags = Cli {
+                    command: Commands::Info,
+                    ..self
+                };
+                let reload = move || {
+                    let config = flags.load_config()?;
+                    let opts = options(&config);
+                    Ok((config, opts))
+                };
+                serve::run(config, store, opts, metrics_addr, reload).await?
+            }
+            Commands::Doctor { .. } => unreachable!("doctor runs before the store is opened"),
+            Commands::Key { .. } => unreachable!("key runs before the store is opened"),
+            Commands::Hash { .. } => unreachable!("hash runs before the store is opened"),
+            Commands::Profile { .. } => {
+                unreachable!("profile runs before the store is opened")
+            }
+            Commands::Status { .. } | Commands::Reload | Commands::Cancel => {
+                unreachable!("status, reload and cancel run before the store is opened")
+            }
             Commands::Copy {
                 peer,
                 remote_path,
                 local_path,
-            } => copy::run(peer, remote_path, local_path).await?,
+                pair,
+                json,
+                yes,
+                no_clobber,
+                transfer,
+            } => {
+                let peer = peer.resolve(&store)?;
+                let mut opts = transfer.into_options(&config, &store);
+                // Only ask when someone is there to answer.
+                opts.overwrite = if no_clobber {
+                    copy::Overwrite::Never
+                } else if !yes && std::io::stdin().is_terminal() {
+                    copy::Overwrite::Ask
+                } else {
+                    copy::Overwrite::Always
+                };
+                let dry_run = opts.dry_run;
+                let ndjson = opts.progress_format == copy::ProgressFormat::Ndjson;
+                let to_stdout = local_path == Path::new(copy::STDOUT_PATH);
+                if !pair.is_empty() {
+                    if to_stdout || pair.iter().any(|(_, local)| local == Path::new("-")) {
+                        anyhow::bail!("Only a single pair can be copied to stdout");
+                    }
+                    let pairs = std::iter::once((remote_path, local_path)).chain(pair);
+                    let reports = copy::run_pairs(&config, peer, pairs.collect(), opts).await?;
+                    return report_pairs(&reports, dry_run, ndjson, json);
+                }
+                let report = copy::run(&config, peer, remote_path, local_path, opts).await?;
+                // File contents written to stdout can't be followed by a summary.
+                if !dry_run && !to_stdout {
+                    print_stats(&report.stats, ndjson, json)?;
+                }
+            }
+            Commands::Push {
+                peer,
+                local_path,
+                remote_path,
+                limit_rate,
+            } => {
+                let peer = peer.resolve(&store)?;
+                let limit_rate = limit_rate.or(config.limit_rate);
+                let report =
+                    push::run(&config, store, peer, local_path, remote_path, limit_rate).await?;
+                println!(
+                    "Pushed {} file(s), {} already up to date",
+                    report.pushed.len(),
+                    report.unchanged
+                );
+            }
             Commands::Sync {
                 peer,
                 remote_path,
                 local_path,
-            } => sync::run(store, peer, remote_path, local_path).await?,
+                pair,
+                only,
+                no_recursive,
+                poll_interval,
+                on_conflict,
+                events,
+                watch_only,
+                json,
+                transfer,
+            } => {
+                let peer = peer.resolve(&store)?;
+                let opts = copy::CopyOptions {
+                    include: only,
+                    watch_recursive: !no_recursive,
+                    watch_poll_interval: poll_interval,
+                    watch_events: sync::event_mask(&events),
+                    watch_only,
+                    on_conflict: on_conflict.into(),
+                    ..transfer.into_options(&config, &store)
+                };
+                let dry_run = opts.dry_run;
+                let ndjson = opts.progress_format == copy::ProgressFormat::Ndjson;
+                if !pair.is_empty() {
+                    let pairs = std::iter::once((remote_path, local_path)).chain(pair);
+                    let reports =
+                        sync::run_pairs(&config, store, peer, pairs.collect(), opts).await?;
+                    return report_pairs(&reports, dry_run, ndjson, json);
+                }
+                let report = sync::run(&config, store, peer, remote_path, local_path, opts).await?;
+                if !dry_run {
+                    print_stats(&report.stats, ndjson, json)?;
+                }
+            }
+            Commands::Resync {
+                local_path,
+                all: _,
+                transfer,
+            } => {
+                // clap ensures exactly one of the path and --all is given.
+                let opts = transfer.into_options(&config, &store);
+                resync::run(&config, &store, local_path, opts).await?
+            }
+            Commands::Verify {
+                peer,
+                remote_path,
+                local_path,
+            } => {
+                let peer = peer.resolve(&store)?;
+                verify::run(&config, &store, peer, remote_path, local_path).await?
+            }
+            Commands::Log { local_path, json } => history::run(&store, &local_path, json)?,
+            Commands::Shares { peer } => {
+                let peer = peer.resolve(&store)?;
+                shares::run(&config, &store, peer).await?
+            }
+            Commands::Ls {
+                peer,
+                remote_path,
+                json,
+            } => {
+                let peer = peer.resolve(&store)?;
+                ls::run(&config, &store, peer, remote_path, json).await?
+            }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use iroh::{RelayMap, RelayMode, RelayUrl};
+
+    use super::Cli;
+    use crate::config::Config;
+
+    #[test]
+    fn no_relay_flag_disables_relays_but_not_mdns() {
+        let cli =
+            Cli::try_parse_from(["syncr", "--no-relay", "--bind", "127.0.0.1:0", "info"]).unwrap();
+        let mut config = Config::default();
+        cli.override_config(&mut config);
+
+        assert!(matches!(config.relay_mode(), RelayMode::Disabled));
+        assert!(config.discovery.mdns);
+        assert_eq!(config.bind, Some("127.0.0.1:0".parse().unwrap()));
+    }
+
+    #[test]
+    fn relay_url_flag_replaces_the_default_relays() {
+        let cli =
+            Cli::try_parse_from(["syncr", "--relay-url", "https://relay.example.com", "info"])
+                .unwrap();
+        let mut config = Config::default();
+        config.relay.enabled = false;
+        cli.override_config(&mut config);
+
+        let url: RelayUrl = "https://relay.example.com".parse().unwrap();
+        assert_eq!(config.relay_mode(), RelayMode::Custom(RelayMap::from(url)));
+        assert!(
+            Cli::try_parse_from(["syncr", "--relay-url", "ftp://relay.example.com", "info"])
+                .is_err()
+        );
+        assert!(Cli::try_parse_from([
+            "syncr",
+            "--no-relay",
+            "--relay-url",
+            "https://relay.example.com",
+            "info"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn network_flag_overrides_the_configured_network() {
+        let cli = Cli::try_parse_from(["syncr", "--network", "staging", "info"]).unwrap();
+        let mut config = Config {
+            network: Some("production".to_string()),
+            ..Config::default()
+        };
+        cli.override_config(&mut config);
+
+        assert_eq!(config.network(), Some("staging"));
+        assert!(Cli::try_parse_from(["syncr", "--network", "stag/ing", "info"]).is_err());
+    }
+}
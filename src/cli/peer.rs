@@ -0,0 +1,111 @@
+use crate::store::Store;
+use anyhow::{Context, Result};
+use iroh::{EndpointAddr, PublicKey};
+use iroh_tickets::endpoint::EndpointTicket;
+use std::str::FromStr;
+
+/// A peer given on the command line, either as a public key, as a ticket
+/// printed by `syncr info`, or as an alias registered with `syncr peer add`.
+#[derive(Debug, Clone)]
+pub enum PeerRef {
+    Key(PublicKey),
+    /// Identity and addresses of a peer, usable without discovery
+    Ticket(EndpointAddr),
+    Alias(String),
+}
+
+impl PeerRef {
+    /// Parse a command-line argument. Anything that isn't a valid public key
+    /// is treated as an alias and looked up later, once the store is open.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        if let Ok(key) = PublicKey::from_str(s) {
+            return Ok(Self::Key(key));
+        }
+        if let Ok(ticket) = EndpointTicket::from_str(s) {
+            return Ok(Self::Ticket(ticket.into()));
+        }
+        validate_alias(s)?;
+        Ok(Self::Alias(s.to_string()))
+    }
+
+    /// The peer's ID. A ticket's addresses are stored so that later
+    /// connections to the peer can use them.
+    pub fn resolve(&self, store: &Store) -> Result<PublicKey> {
+        match self {
+            Self::Key(key) => Ok(*key),
+            Self::Ticket(addr) => {
+                store
+                    .add_address(addr)
+                    .context("Failed to store peer addresses")?;
+                Ok(addr.id)
+            }
+            Self::Alias(name) => store
+                .resolve_alias(name)
+                .context("Failed to look up peer alias")?
+                .with_context(|| {
+                    format!(
+                        "'{}' is neither a peer ID nor a known alias (see `syncr peer list`)",
+                        name
+                    )
+                }),
+        }
+    }
+}
+
+fn validate_alias(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("peer alias must not be empty".to_string());
+    }
+    if name.chars().any(char::is_whitespace) {
+        return Err(format!("peer alias '{}' must not contain whitespace", name));
+    }
+    Ok(())
+}
+
+/// Parse a ticket printed by `syncr info`.
+pub fn parse_ticket(s: &str) -> Result<EndpointAddr, String> {
+    EndpointTicket::from_str(s)
+        .map(Into::into)
+        .map_err(|e| format!("invalid ticket: {}", e))
+}
+
+pub fn run_connect(store: &Store, addr: EndpointAddr) -> Result<()> {
+    store.add_address(&addr)?;
+    println!("Peer ID: {}", addr.id);
+    println!(
+        "Stored {} address(es); copy and sync can now reach the peer without discovery",
+        addr.addrs.len()
+    );
+    Ok(())
+}
+
+pub fn run_add(store: &Store, name: String, peer: PublicKey) -> Result<()> {
+    validate_alias(&name).map_err(anyhow::Error::msg)?;
+    if PublicKey::from_str(&name).is_ok() {
+        anyhow::bail!("Alias '{}' looks like a peer ID; pick another name", name);
+    }
+    store.add_alias(&name, peer)?;
+    println!("Added alias {} for peer {}", name, peer);
+    Ok(())
+}
+
+pub fn run_list(store: &Store) -> Result<()> {
+    let aliases = store.list_aliases()?;
+    if aliases.is_empty() {
+        println!("No peer aliases defined.");
+    } else {
+        for (name, peer) in aliases {
+            println!("{}\t{}", name, peer);
+        }
+    }
+    Ok(())
+}
+
+pub fn run_remove(store: &Store, name: String) -> Result<()> {
+    if store.remove_alias(&name)? {
+        println!("Removed alias: {}", name);
+    } else {
+        println!("No such alias: {}", name);
+    }
+    Ok(())
+}
@@ -0,0 +1,22 @@
+use anyhow::{Context, Result};
+use std::path::Path;
+
+use crate::config;
+
+/// Print the profiles in the syncr home `base`, marking `active`.
+pub fn run_list(base: &Path, active: Option<&str>) -> Result<()> {
+    let profiles = config::list_profiles(base)
+        .with_context(|| format!("Failed to list profiles in {}", base.display()))?;
+    if profiles.is_empty() {
+        println!("No profiles defined.");
+    }
+    for name in profiles {
+        let marker = if Some(name.as_str()) == active {
+            "*"
+        } else {
+            " "
+        };
+        println!("{} {}", marker, name);
+    }
+    Ok(())
+}
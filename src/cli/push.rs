@@ -0,0 +1,249 @@
+//! `syncr push`: write local files to a peer, the reverse of `syncr copy`.
+
+use anyhow::Result;
+use iroh::{Endpoint, PublicKey};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::{
+    buffer_budget::BufferBudget,
+    cli::{copy::Session, serve::send_file},
+    config::Config,
+    iroh_utils, metrics,
+    protocol::{join_wire_path, to_wire_path, ErrorCode, Message, RemoteError, MAX_CHUNK_LEN},
+    rate_limit::RateLimiter,
+    store::{Direction, Store, SyncLogEntry, SyncOutcome, SYNC_LOG_PATHS},
+    sync_utils::{self, SharedBytes},
+    trash::TRASH_DIR,
+};
+
+/// What a push did.
+#[derive(Debug, Default)]
+pub struct PushReport {
+    /// Remote paths that were written, in the order they were pushed
+    pub pushed: Vec<String>,
+    /// Files the peer already had as they are here
+    pub unchanged: usize,
+    /// Delta and file data sent to the peer
+    pub bytes_sent: u64,
+}
+
+impl PushReport {
+    /// This push as an entry in the log of the sync of `remote_path` from
+    /// `peer`.
+    pub fn log_entry(&self, peer: PublicKey, remote_path: &str) -> SyncLogEntry {
+        SyncLogEntry {
+            peer,
+            remote_path: remote_path.to_string(),
+            timestamp: sync_utils::unix_timestamp(),
+            direction: Direction::Push,
+            bytes: self.bytes_sent,
+            files: self.pushed.len() as u64,
+            changed: self.pushed.iter().take(SYNC_LOG_PATHS).cloned().collect(),
+            conflicts: 0,
+            outcome: SyncOutcome::Completed,
+        }
+    }
+}
+
+pub async fn run(
+    config: &Config,
+    store: Store,
+    peer: PublicKey,
+    local_path: PathBuf,
+    remote_path: String,
+    limit_rate: Option<u64>,
+) -> Result<PushReport> {
+    let endpoint = iroh_utils::build_endpoint(config).await?;
+    iroh_utils::add_known_addresses(&endpoint, store.list_addresses()?);
+    let result = run_with(
+        &endpoint,
+        config,
+        peer,
+        local_path.clone(),
+        remote_path.clone(),
+        limit_rate,
+    )
+    .await;
+
+    // A push into a synced path goes in the log of that sync.
+    let entry = match &result {
+        Ok(report) => report.log_entry(peer, &remote_path),
+        Err(e) => SyncLogEntry::failed(peer, &remote_path, Direction::Push, format!("{:#}", e)),
+    };
+    store.log_sync(&local_path, entry)?;
+    result
+}
+
+/// Like [`run`], but over an existing endpoint. A directory is pushed file
+/// by file below `remote_path`; the peer needs write access to all of it.
+pub async fn run_with(
+    endpoint: &Endpoint,
+    config: &Config,
+    peer: PublicKey,
+    local_path: PathBuf,
+    remote_path: String,
+    limit_rate: Option<u64>,
+) -> Result<PushReport> {
+    let files = if local_path.is_dir() {
+        local_files(&local_path, &remote_path)
+    } else if local_path.is_file() {
+        vec![(local_path.clone(), remote_path.clone())]
+    } else {
+        anyhow::bail!("{:?} is not a file or directory", local_path);
+    };
+
+    info!("Connecting to {}...", peer);
+    let connection = iroh_utils::connect(endpoint, config.network(), peer).await?;
+    let mut session = Session::open(&connection, config).await?;
+    let limiter = RateLimiter::new(limit_rate);
+
+    let mut report = PushReport::default();
+    for (local, remote) in files {
+        match push_file(&mut session, &local, &remote, config.chunk_size, &limiter).await? {
+            Some(sent) => {
+                info!("Pushed {:?} to {}", local, remote);
+                report.pushed.push(remote);
+                report.bytes_sent += sent;
+            }
+            None => report.unchanged += 1,
+        }
+    }
+    session.send.finish()?;
+    Ok(report)
+}
+
+/// Files below `dir` paired with their remote paths below `remote_path`.
+/// Backups and symlinks are left out.
+fn local_files(dir: &Path, remote_path: &str) -> Vec<(PathBuf, String)> {
+    let mut files = Vec::new();
+    let walk = walkdir::WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| e.file_name() != TRASH_DIR);
+    for entry in walk {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Skipping unreadable entry: {}", e);
+                continue;
+            }
+        };
+        if entry.path_is_symlink() {
+            warn!("Not pushing symlink {:?}", entry.path());
+            continue;
+        }
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let Ok(relative) = entry.path().strip_prefix(dir) else {
+            continue;
+        };
+        let remote = join_wire_path(remote_path, &to_wire_path(relative));
+        files.push((entry.into_path(), remote));
+    }
+    files
+}
+
+/// Push `local` to `remote`, as a delta if the peer has an older version.
+/// Returns the bytes sent, or `None` if nothing had to be written.
+async fn push_file(
+    session: &mut Session,
+    local: &Path,
+    remote: &str,
+    chunk_size: usize,
+    limiter: &RateLimiter,
+) -> Result<Option<u64>> {
+    let data: SharedBytes = Arc::new(sync_utils::map_file(local)?);
+    let len = (*data).as_ref().len() as u64;
+    let hash = *blake3::hash((*data).as_ref()).as_bytes();
+    let offer = Message::FilePush {
+        path: remote.to_string(),
+        hash,
+    };
+    session.write(&offer).await?;
+
+    let signature = match session.read().await? {
+        Message::FilePushComplete { .. } => return Ok(None),
+        Message::FilePushSignature { signature, .. } => signature,
+        Message::Error { code, message } => return Err(RemoteError { code, message }.into()),
+        msg => anyhow::bail!("Unexpected response to FilePush: {:?}", msg),
+    };
+
+    let mut whole = true;
+    let mut sent = 0;
+    if let Some(signature) = signature {
+        let delta = sync_utils::blocking(&data, move |data| {
+            sync_utils::calculate_delta(&signature, data)
+        })
+        .await?;
+        match delta {
+            // Sent in chunks instead, as one frame couldn't hold it.
+            Ok(delta) if delta.len() > MAX_CHUNK_LEN => info!(
+                "Delta for {} is {} bytes, sending it whole",
+                remote,
+                delta.len()
+            ),
+            Ok(delta) => {
+                limiter.acquire(delta.len()).await;
+                metrics::get().bytes_sent.inc_by(delta.len() as u64);
+                sent += delta.len() as u64;
+                let msg = Message::FileDelta {
+                    path: remote.to_string(),
+                    delta,
+                    hash,
+                };
+                session.write(&msg).await?;
+                whole = false;
+            }
+            Err(e) => warn!(
+                "Failed to compute delta for {}, sending it whole: {}",
+                remote, e
+            ),
+        }
+    }
+    drop(data);
+    if whole {
+        sent += len;
+        send_file(
+            &mut session.send,
+            local,
+            remote,
+            0,
+            chunk_size,
+            limiter,
+            &BufferBudget::default(),
+        )
+        .await?;
+    }
+
+    loop {
+        match session.read().await? {
+            Message::FilePushComplete { .. } => return Ok(Some(sent)),
+            Message::Error {
+                code: ErrorCode::DeltaFailed,
+                ..
+            } if !whole => {
+                warn!(
+                    "Peer could not apply delta for {}, sending it whole",
+                    remote
+                );
+                sent += len;
+                send_file(
+                    &mut session.send,
+                    local,
+                    remote,
+                    0,
+                    chunk_size,
+                    limiter,
+                    &BufferBudget::default(),
+                )
+                .await?;
+                whole = true;
+            }
+            Message::Error { code, message } => return Err(RemoteError { code, message }.into()),
+            msg => anyhow::bail!("Unexpected response to pushed file: {:?}", msg),
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use anyhow::{Context, Result};
+use iroh::{Endpoint, PublicKey};
+use std::path::PathBuf;
+use tracing::info;
+
+use crate::{
+    cli::copy::{self, CopyOptions},
+    config::Config,
+    iroh_utils,
+    store::{Direction, Store, SyncLogEntry},
+    sync_utils,
+};
+
+/// Outcome of re-syncing one configured sync.
+#[derive(Debug, Clone)]
+pub struct ResyncReport {
+    pub local_path: PathBuf,
+    pub peer: PublicKey,
+    pub remote_path: String,
+    /// Local files that were rewritten to match the remote
+    pub changed: Vec<PathBuf>,
+    pub stats: copy::SyncStats,
+}
+
+pub async fn run(
+    config: &Config,
+    store: &Store,
+    local_path: Option<PathBuf>,
+    opts: CopyOptions,
+) -> Result<()> {
+    let endpoint = iroh_utils::build_endpoint(config).await?;
+    iroh_utils::add_known_addresses(&endpoint, store.list_addresses()?);
+    let ndjson = opts.progress_format == copy::ProgressFormat::Ndjson;
+    let reports = run_with(&endpoint, config, store, local_path, opts).await?;
+
+    for report in &reports {
+        if ndjson {
+            copy::ProgressEvent::Summary(&report.stats).print()?;
+            continue;
+        }
+        println!(
+            "{} <- {}:{}",
+            report.local_path.display(),
+            report.peer.fmt_short(),
+            report.remote_path
+        );
+        if report.changed.is_empty() {
+            println!("    up to date");
+        }
+        for path in &report.changed {
+            println!("    updated  {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Copy every sync configured for `local_path` (or for every local path if
+/// `None`) from its peer in full. Cached hashes and signatures are ignored and
+/// local changes are overwritten, so the local copy ends up matching the
+/// remote whatever state incremental sync left it in.
+pub async fn run_with(
+    endpoint: &Endpoint,
+    config: &Config,
+    store: &Store,
+    local_path: Option<PathBuf>,
+    opts: CopyOptions,
+) -> Result<Vec<ResyncReport>> {
+    let local_path = local_path
+        .map(|path| {
+            std::fs::canonicalize(&path).with_context(|| format!("Failed to resolve {:?}", path))
+        })
+        .transpose()?;
+
+    let mut reports = Vec::new();
+    for (root, configs) in store.list_syncs()? {
+        if local_path.as_ref().is_some_and(|path| *path != root) {
+            continue;
+        }
+        for sync_config in configs {
+            info!(
+                "Re-syncing {:?} from {}:{}",
+                root, sync_config.peer, sync_config.remote_path
+            );
+            let copy_opts = CopyOptions {
+                base_hash: None,
+                store: None,
+                include: sync_config.include.clone(),
+                include_hidden: sync_config.include_hidden,
+                max_size: opts.max_size.or(sync_config.max_size),
+                ..opts.clone()
+            };
+            let result = copy::run_with(
+                endpoint,
+                config,
+                sync_config.peer,
+                sync_config.remote_path.clone(),
+                root.clone(),
+                copy_opts,
+            )
+            .await;
+            let report = match result {
+                Ok(report) => report,
+                Err(e) => {
+                    if !opts.dry_run {
+                        let error = format!("{:#}", e);
+                        store.record_sync_failure(
+                            &root,
+                            sync_config.peer,
+                            &sync_config.remote_path,
+                            &error,
+                        )?;
+                        store.log_sync(
+                            &root,
+                            SyncLogEntry::failed(
+                                sync_config.peer,
+                                &sync_config.remote_path,
+                                Direction::Pull,
+                                error,
+                            ),
+                        )?;
+                    }
+                    return Err(e.context(format!("Failed to re-sync {:?}", root)));
+                }
+            };
+
+            if !opts.dry_run {
+                store.record_sync_result(
+                    &root,
+                    sync_config.peer,
+                    &sync_config.remote_path,
+                    report.hash,
+                    sync_utils::unix_timestamp(),
+                )?;
+                store.log_sync(
+                    &root,
+                    report.log_entry(sync_config.peer, &sync_config.remote_path),
+                )?;
+            }
+            reports.push(ResyncReport {
+                local_path: root.clone(),
+                peer: sync_config.peer,
+                remote_path: sync_config.remote_path,
+                changed: report.changed,
+                stats: report.stats,
+            });
+        }
+    }
+
+    if let Some(path) = local_path.filter(|_| reports.is_empty()) {
+        anyhow::bail!("No syncs are configured for {:?}", path);
+    }
+    Ok(reports)
+}
@@ -1,184 +1,1163 @@
 use anyhow::{Context, Result};
-use iroh::{
-    discovery::{dns::DnsDiscovery, mdns::MdnsDiscovery, pkarr::PkarrPublisher},
-    Endpoint, PublicKey,
-};
-use std::path::PathBuf;
+use iroh::{endpoint::Connection, Endpoint, PublicKey};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc, watch, Semaphore};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info, warn};
 use walkdir::WalkDir;
 
 use crate::{
+    buffer_budget::BufferBudget,
+    chunking,
+    cli::copy::{self, CopyOptions, CopyReport},
+    config::Config,
+    control::{self, ControlRequest, ControlResponse, DaemonStatus},
+    heartbeat::HeartbeatOptions,
     iroh_utils,
-    protocol::{FileMetadata, Message, ALPN},
-    store::Store,
-    sync_manager::SyncManager,
-    sync_utils,
+    listing_cache::{ListingCache, ListingKey},
+    metrics, path_lock,
+    protocol::{
+        from_wire_relative, includes_below, is_hidden, join_wire_path, read_message_within,
+        strip_wire_prefix, to_wire_path, write_message, Capabilities, ErrorCode, FileMetadata,
+        FileType, ManifestAction, ManifestEntry, Message, ProtocolError, Share, CAP_BATCH_NOTIFY,
+        CAP_CHECK_ACCESS, CAP_CHUNKS, CAP_RETRY, CAP_SHARES, CAP_SKIPPED_ENTRIES, LIST_CHUNK_LEN,
+        MANIFEST_DELTA_MIN_LEN, MAX_CHUNK_LEN, REFUSED_CODE,
+    },
+    rate_limit::RateLimiter,
+    sandbox,
+    schedule::{self, ActiveLimit},
+    store::{AccessMode, Direction, Store, SyncLogEntry},
+    sync_manager::{ReconcileTrigger, SyncEvent, SyncManager},
+    sync_utils::{self, HashQueue, SharedBytes},
+    trash::{Trash, TRASH_DIR},
     watcher::FileWatcher,
 };
 
-pub async fn run(store: Store) -> Result<()> {
-    let secret_key = iroh_utils::load_secret_key().await?;
-    let endpoint = Endpoint::builder()
-        .discovery(PkarrPublisher::n0_dns())
-        .discovery(DnsDiscovery::n0_dns())
-        .discovery(MdnsDiscovery::builder())
-        .secret_key(secret_key)
-        .alpns(vec![ALPN.to_vec()])
-        .bind()
-        .await?;
+/// How long shutdown waits for in-flight connections before aborting them.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often expired grants are removed from the store.
+const GRANT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Options for the serve loop.
+#[derive(Debug, Clone, Default)]
+pub struct ServeOptions {
+    /// Per-connection bandwidth cap in bytes per second, outside the windows
+    /// of the config's schedule
+    pub limit_rate: Option<u64>,
+    /// Refuse connections from peers with no grants or syncs
+    pub strict_peers: bool,
+    /// Stop accepting after the first connection and shut down once it
+    /// closes
+    pub one_shot: bool,
+    /// Refuse connections from any peer but this one, e.g. for a one-shot
+    /// serve meant for a single peer
+    pub expect_peer: Option<PublicKey>,
+}
+
+/// Connections currently open, per peer.
+#[derive(Clone, Default)]
+struct PeerConnections(Arc<std::sync::Mutex<HashMap<PublicKey, usize>>>);
+
+impl PeerConnections {
+    /// Count a new connection from `peer`, unless it already has `cap` open.
+    /// The connection counts until the returned slot is dropped.
+    fn try_acquire(&self, peer: PublicKey, cap: usize) -> Option<PeerSlot> {
+        let mut counts = self.0.lock().unwrap();
+        let count = counts.entry(peer).or_default();
+        if *count >= cap {
+            return None;
+        }
+        *count += 1;
+        Some(PeerSlot {
+            connections: self.clone(),
+            peer,
+        })
+    }
+}
+
+/// One open connection counted in [`PeerConnections`].
+struct PeerSlot {
+    connections: PeerConnections,
+    peer: PublicKey,
+}
+
+impl Drop for PeerSlot {
+    fn drop(&mut self) {
+        let mut counts = self.connections.0.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.peer) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.peer);
+            }
+        }
+    }
+}
+
+/// Ways into a running server besides its peers.
+#[derive(Default)]
+pub struct Control {
+    /// Requests from the control socket
+    pub requests: Option<mpsc::Receiver<control::Pending>>,
+    /// Settings to switch to, loaded again on SIGHUP
+    pub reloads: Option<mpsc::Receiver<(Config, ServeOptions)>>,
+    /// Cap on buffered file data to use instead of one from the config,
+    /// e.g. to watch how much is buffered
+    pub buffers: Option<BufferBudget>,
+}
+
+/// Serve until shut down, loading the config again with `reload` on SIGHUP,
+/// and metrics on `metrics_addr` if given.
+pub async fn run(
+    config: Config,
+    store: Store,
+    opts: ServeOptions,
+    metrics_addr: Option<SocketAddr>,
+    reload: impl Fn() -> Result<(Config, ServeOptions)> + Send + 'static,
+) -> Result<()> {
+    let metrics = match metrics_addr {
+        Some(addr) => {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("Failed to serve metrics on {}", addr))?;
+            Some(tokio::spawn(metrics::serve(listener)))
+        }
+        None => None,
+    };
+    let endpoint = iroh_utils::build_endpoint(&config).await?;
+    iroh_utils::add_known_addresses(&endpoint, store.list_addresses()?);
+    // Other commands can't open the store while we hold it, so they ask
+    // over the control socket instead.
+    let (_listener, requests) = match control::listen(&config.data_dir()?) {
+        Ok((listener, requests)) => (Some(listener), Some(requests)),
+        Err(e) => {
+            warn!("Not taking local commands, no control socket: {}", e);
+            (None, None)
+        }
+    };
+    let (reloaded, reloads) = mpsc::channel(1);
+    let hangup = tokio::spawn(reload_on_hangup(reload, reloaded));
+    let control = Control {
+        requests,
+        reloads: Some(reloads),
+        buffers: None,
+    };
+    let result = serve(
+        endpoint,
+        config,
+        store,
+        opts,
+        SyncEvent::channel(),
+        control,
+        shutdown_signal(),
+    )
+    .await;
+    hangup.abort();
+    if let Some(metrics) = metrics {
+        metrics.abort();
+    }
+    result
+}
 
+/// Load the settings again with `reload` each time SIGHUP arrives, passing
+/// them on to the server. A config that fails to load is logged and skipped.
+async fn reload_on_hangup(
+    reload: impl Fn() -> Result<(Config, ServeOptions)>,
+    reloaded: mpsc::Sender<(Config, ServeOptions)>,
+) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                warn!("Not reloading the config on SIGHUP: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            info!("SIGHUP received, reloading the config");
+            match reload() {
+                Ok(settings) => {
+                    if reloaded.send(settings).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => error!("Keeping the current config: {:#}", e),
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (reload, reloaded);
+    }
+}
+
+/// Completes on Ctrl-C, or on SIGTERM where there is one (as sent by
+/// `syncr serve --stop` and service managers).
+pub(crate) async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Accept connections on `endpoint` until `shutdown` completes, then wait
+/// briefly for in-flight connections and close the endpoint. What syncing
+/// goes on meanwhile is reported on `events`, and `control` requests and
+/// reloads are handled as they come.
+pub async fn serve(
+    endpoint: Endpoint,
+    mut config: Config,
+    store: Store,
+    mut opts: ServeOptions,
+    events: broadcast::Sender<SyncEvent>,
+    mut control: Control,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
     info!("Listening on Peer ID: {}", endpoint.id());
+    let started = Instant::now();
 
     // Initialize watcher
     let watcher = FileWatcher::new()?;
 
+    // Transfers are held to the limit the schedule sets for the time of day.
+    let (scheduled, limit) = watch::channel(schedule::active_limit(
+        &config.schedule,
+        opts.limit_rate,
+        schedule::now(),
+    ));
+
     // Initialize SyncManager
-    let sync_manager = SyncManager::new(store.clone(), endpoint.clone(), watcher);
+    let mut sync_manager = SyncManager::new(
+        store.clone(),
+        endpoint.clone(),
+        watcher,
+        config.idle_timeout(),
+        HeartbeatOptions::from(&config),
+        config.watch_reconcile_interval(),
+        events.clone(),
+    )
+    .with_schedule(limit.clone())
+    .with_pool_idle(config.pool_idle())
+    .with_handshake_timeout(config.handshake_timeout())
+    .with_notify_batch(config.notify_batch())
+    .with_network(config.network.clone());
     sync_manager.run().await?; // Starts watcher loop
+    let mut dialed = sync_manager
+        .dialed_connections()
+        .context("Sync manager connections already taken")?;
+    let reconcile = sync_manager.reconcile_trigger();
+    let sweep = tokio::spawn(sweep_expired_grants(store.clone()));
+    let (permissions_changed, _) = watch::channel(());
+    // Every connection draws file reads from the same budget.
+    let buffers = control
+        .buffers
+        .take()
+        .unwrap_or_else(|| BufferBudget::new(config.max_buffered));
+    // Notified pulls queue up here, whichever connection brought them.
+    let pulls = Arc::new(Semaphore::new(config.max_concurrent_pulls.max(1)));
+    let (settings, _) = watch::channel((config.clone(), opts.clone()));
+    let (cancels, _) = watch::channel(CancellationToken::new());
+    let follow = tokio::spawn(follow_schedule(settings.subscribe(), scheduled));
+    let permissions: Vec<_> = store
+        .watch_permissions()
+        .into_iter()
+        .map(|changes| {
+            tokio::spawn(forward_permission_changes(
+                changes,
+                permissions_changed.clone(),
+            ))
+        })
+        .collect();
+
+    tokio::pin!(shutdown);
+    let mut connections = JoinSet::new();
+    // Connections we opened ourselves aren't waited for at shutdown; they
+    // are only kept open for the next notification.
+    let mut dialed_connections = JoinSet::new();
+    let peer_connections = PeerConnections::default();
+    let listings = ListingCache::new(config.listing_cache_min_entries);
+    // Reloads don't turn one-shot serving on or off.
+    let one_shot = opts.one_shot;
+    let mut accepting = true;
+
+    // Loop to accept incoming connections until asked to stop
+    loop {
+        let open = connections.len() + dialed_connections.len();
+        metrics::get().active_connections.set(open as i64);
+        tokio::select! {
+            incoming = endpoint.accept(), if accepting => {
+                let Some(incoming) = incoming else {
+                    break;
+                };
+                if connections.len() >= config.max_connections {
+                    warn!(
+                        "Refusing connection from {}: {} connections already open",
+                        incoming.remote_address(),
+                        connections.len()
+                    );
+                    incoming.refuse();
+                    continue;
+                }
+                let shared = Shared {
+                    peer_connections: peer_connections.clone(),
+                    listings: listings.clone(),
+                    reconcile: reconcile.clone(),
+                    events: events.clone(),
+                    permissions_changed: permissions_changed.subscribe(),
+                    settings: settings.subscribe(),
+                    limit: limit.clone(),
+                    buffers: buffers.clone(),
+                    pulls: pulls.clone(),
+                    cancel: cancels.subscribe(),
+                };
+                let store = store.clone();
+                let config = config.clone();
+                let opts = opts.clone();
+                let endpoint = endpoint.clone();
+                connections.spawn(async move {
+                    if let Err(e) =
+                        handle_connection(incoming, endpoint, config, store, opts, shared).await
+                    {
+                        error!("Connection error: {:?}", e);
+                    }
+                });
+                if one_shot {
+                    info!("Serving one connection, no longer accepting others");
+                    accepting = false;
+                }
+            }
+            Some(connection) = dialed.recv() => {
+                // A peer we notified pulls the change over this connection.
+                let shared = Shared {
+                    peer_connections: peer_connections.clone(),
+                    listings: listings.clone(),
+                    reconcile: reconcile.clone(),
+                    events: events.clone(),
+                    permissions_changed: permissions_changed.subscribe(),
+                    settings: settings.subscribe(),
+                    limit: limit.clone(),
+                    buffers: buffers.clone(),
+                    pulls: pulls.clone(),
+                    cancel: cancels.subscribe(),
+                };
+                let store = store.clone();
+                let config = config.clone();
+                dialed_connections.spawn(async move {
+                    if let Err(e) = serve_connection(connection, config, store, shared).await {
+                        error!("Connection error: {:?}", e);
+                    }
+                });
+            }
+            Some((new_config, new_opts)) = next(&mut control.reloads) => {
+                log_reload(&config, &new_config, &opts, &new_opts);
+                config = new_config;
+                opts = new_opts;
+                settings.send_replace((config.clone(), opts.clone()));
+                // Watches may have changed in the store meanwhile too.
+                reconcile.fire();
+            }
+            Some(control::Pending { request, reply }) = next(&mut control.requests) => {
+                let response = match request {
+                    ControlRequest::Status => {
+                        let now = sync_utils::unix_timestamp();
+                        match control::sync_statuses(&store, now, config.sync_stale_secs) {
+                            Ok(syncs) => ControlResponse::Status(DaemonStatus {
+                                peer_id: endpoint.id().to_string(),
+                                pid: std::process::id(),
+                                uptime_secs: started.elapsed().as_secs(),
+                                connections: connections.len() + dialed_connections.len(),
+                                watched: sync_manager.watched_paths().await,
+                                syncs,
+                                limit: *limit.borrow(),
+                            }),
+                            Err(e) => ControlResponse::Error(format!("Failed to list syncs: {}", e)),
+                        }
+                    }
+                    ControlRequest::Reload => {
+                        reconcile.fire();
+                        ControlResponse::Reloaded
+                    }
+                    ControlRequest::Cancel => {
+                        // Pulls started from now on get a fresh token.
+                        cancels.send_replace(CancellationToken::new()).cancel();
+                        info!("Cancelled the pulls under way");
+                        ControlResponse::Cancelled
+                    }
+                };
+                let _ = reply.send(response);
+            }
+            _ = &mut shutdown => {
+                info!("Shutdown requested, no longer accepting connections");
+                break;
+            }
+            // Reap finished connection tasks as we go
+            Some(_) = connections.join_next(), if !connections.is_empty() => {
+                if !accepting && connections.is_empty() {
+                    info!("The one connection closed, shutting down");
+                    break;
+                }
+            }
+            Some(_) = dialed_connections.join_next(), if !dialed_connections.is_empty() => {}
+        }
+    }
+
+    if !connections.is_empty() {
+        info!(
+            "Waiting for {} connection(s) to finish...",
+            connections.len()
+        );
+        let drained = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            warn!(
+                "Timed out waiting for {} connection(s), aborting them",
+                connections.len()
+            );
+            connections.abort_all();
+        }
+    }
+
+    dialed_connections.abort_all();
+    sweep.abort();
+    for forward in permissions {
+        forward.abort();
+    }
+    follow.abort();
+    endpoint.close().await;
+    info!("Server stopped");
+
+    Ok(())
+}
+
+/// The next item from `receiver`, or never if there is none.
+async fn next<T>(receiver: &mut Option<mpsc::Receiver<T>>) -> Option<T> {
+    match receiver {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
 
-    // Loop to accept incoming connections
-    while let Some(incoming) = endpoint.accept().await {
-        let store = store.clone();
-        let endpoint_clone = endpoint.clone();
-        tokio::spawn(async move {
-            if let Err(e) = handle_connection(incoming, store, endpoint_clone).await {
-                error!("Connection error: {:?}", e);
+/// Log which settings a reload changed, warning about those that only take
+/// effect on restart.
+fn log_reload(old: &Config, new: &Config, old_opts: &ServeOptions, new_opts: &ServeOptions) {
+    let live = [
+        ("limit_rate", old_opts.limit_rate != new_opts.limit_rate),
+        (
+            "strict_peers",
+            old_opts.strict_peers != new_opts.strict_peers,
+        ),
+        (
+            "idle_timeout_secs",
+            old.idle_timeout_secs != new.idle_timeout_secs,
+        ),
+        (
+            "handshake_timeout_secs",
+            old.handshake_timeout_secs != new.handshake_timeout_secs,
+        ),
+        ("block_size", old.block_size != new.block_size),
+        (
+            "strong_hash_size",
+            old.strong_hash_size != new.strong_hash_size,
+        ),
+        (
+            "max_connections",
+            old.max_connections != new.max_connections,
+        ),
+        (
+            "max_connections_per_peer",
+            old.max_connections_per_peer != new.max_connections_per_peer,
+        ),
+        ("chunk_size", old.chunk_size != new.chunk_size),
+        ("window", old.window != new.window),
+        (
+            "hash_concurrency",
+            old.hash_concurrency != new.hash_concurrency,
+        ),
+        ("fsync", old.fsync != new.fsync),
+        (
+            "delta_min_savings",
+            old.delta_min_savings != new.delta_min_savings,
+        ),
+        ("schedule", old.schedule != new.schedule),
+        (
+            "sync_stale_secs",
+            old.sync_stale_secs != new.sync_stale_secs,
+        ),
+        ("backup", old.backup != new.backup),
+        (
+            "snapshot_command",
+            old.snapshot_command != new.snapshot_command,
+        ),
+    ];
+    let on_restart = [
+        ("bind", old.bind != new.bind),
+        ("network", old.network != new.network),
+        ("discovery", old.discovery != new.discovery),
+        ("relay", old.relay != new.relay),
+        ("data_dir", old.data_dir != new.data_dir),
+        ("log", old.log != new.log),
+        (
+            "heartbeat_interval_secs",
+            old.heartbeat_interval_secs != new.heartbeat_interval_secs,
+        ),
+        (
+            "heartbeat_max_missed",
+            old.heartbeat_max_missed != new.heartbeat_max_missed,
+        ),
+        ("pool_idle_secs", old.pool_idle_secs != new.pool_idle_secs),
+        (
+            "notify_batch_ms",
+            old.notify_batch_ms != new.notify_batch_ms,
+        ),
+        (
+            "watch_reconcile_secs",
+            old.watch_reconcile_secs != new.watch_reconcile_secs,
+        ),
+        (
+            "listing_cache_min_entries",
+            old.listing_cache_min_entries != new.listing_cache_min_entries,
+        ),
+        ("max_buffered", old.max_buffered != new.max_buffered),
+        (
+            "max_concurrent_pulls",
+            old.max_concurrent_pulls != new.max_concurrent_pulls,
+        ),
+    ];
+    let changed = |settings: &[(&str, bool)]| -> Vec<String> {
+        settings
+            .iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(name, _)| name.to_string())
+            .collect()
+    };
+    let (live, on_restart) = (changed(&live), changed(&on_restart));
+    if live.is_empty() && on_restart.is_empty() {
+        info!("Config reloaded, nothing changed");
+    }
+    if !live.is_empty() {
+        info!("Config reloaded, now using new {}", live.join(", "));
+    }
+    if !on_restart.is_empty() {
+        warn!(
+            "Restart syncr serve to apply the new {}",
+            on_restart.join(", ")
+        );
+    }
+}
+
+/// Keep `limit` at what the schedule in `settings` allows, checking again
+/// as each minute starts and whenever the settings change.
+async fn follow_schedule(
+    mut settings: watch::Receiver<(Config, ServeOptions)>,
+    limit: watch::Sender<ActiveLimit>,
+) {
+    loop {
+        let active = {
+            let (config, opts) = &*settings.borrow_and_update();
+            schedule::active_limit(&config.schedule, opts.limit_rate, schedule::now())
+        };
+        limit.send_if_modified(|current| {
+            if *current == active {
+                return false;
             }
+            info!("Transfer limit is now {}", active);
+            *current = active;
+            true
         });
+        tokio::select! {
+            _ = tokio::time::sleep(schedule::until_next_minute()) => {}
+            changed = settings.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+            }
+        }
     }
+}
 
-    Ok(())
+/// Periodically drop expired grants. Requests check expiry themselves, so
+/// this only keeps the store and `syncr peers` tidy.
+async fn sweep_expired_grants(store: Store) {
+    let mut interval = tokio::time::interval(GRANT_SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match store.prune_expired_grants() {
+            Ok(0) => {}
+            Ok(removed) => info!("Removed {} expired grant(s)", removed),
+            Err(e) => warn!("Failed to remove expired grants: {}", e),
+        }
+    }
+}
+
+/// Tell open connections whenever a grant changes, so they can drop peers
+/// whose access was revoked.
+async fn forward_permission_changes(mut changes: sled::Subscriber, changed: watch::Sender<()>) {
+    while (&mut changes).await.is_some() {
+        changed.send_replace(());
+    }
+}
+
+/// Server-wide state handed to each connection.
+struct Shared {
+    peer_connections: PeerConnections,
+    listings: ListingCache,
+    reconcile: ReconcileTrigger,
+    events: broadcast::Sender<SyncEvent>,
+    permissions_changed: watch::Receiver<()>,
+    /// Config and options, replaced when reloaded
+    settings: watch::Receiver<(Config, ServeOptions)>,
+    /// Transfer limit the schedule sets right now
+    limit: watch::Receiver<ActiveLimit>,
+    /// File data read and not yet sent, across every connection
+    buffers: BufferBudget,
+    /// Pulls of notified changes running at once, across every connection
+    pulls: Arc<Semaphore>,
+    /// Cancels the pulls under way, replaced each time it is used
+    cancel: watch::Receiver<CancellationToken>,
 }
 
 async fn handle_connection(
     incoming: iroh::endpoint::Incoming,
-    store: Store,
     endpoint: Endpoint,
+    config: Config,
+    store: Store,
+    opts: ServeOptions,
+    shared: Shared,
 ) -> Result<()> {
     let connection = incoming.accept()?;
     let connection = connection.await?;
     let remote_id = connection.remote_id();
-    info!("Accepted connection from {}", remote_id);
 
-    // Accept a bi-directional stream for control messages
-    let (mut send, mut recv) = connection.accept_bi().await?;
-    info!("Bi-directional stream established with {}", remote_id);
+    if let Some(expected) = opts.expect_peer.filter(|&expected| expected != remote_id) {
+        warn!(
+            "Refusing connection from {}: only {} is expected",
+            remote_id, expected
+        );
+        connection.close(REFUSED_CODE.into(), b"unexpected peer");
+        return Ok(());
+    }
+    if opts.strict_peers && !store.is_known_peer(remote_id)? {
+        warn!("Refusing connection from unknown peer {}", remote_id);
+        connection.close(REFUSED_CODE.into(), b"unknown peer");
+        return Ok(());
+    }
+    let Some(_slot) = shared
+        .peer_connections
+        .try_acquire(remote_id, config.max_connections_per_peer)
+    else {
+        warn!(
+            "Refusing connection from {}: {} connections already open",
+            remote_id, config.max_connections_per_peer
+        );
+        connection.close(REFUSED_CODE.into(), b"too many connections");
+        return Ok(());
+    };
+    info!(
+        "Accepted connection from {}, reached {}",
+        remote_id,
+        iroh_utils::route(&endpoint, remote_id)
+    );
+
+    serve_connection(connection, config, store, shared).await
+}
+
+/// Handle the streams the peer opens on `connection` until it closes,
+/// whichever side opened the connection.
+async fn serve_connection(
+    connection: Connection,
+    config: Config,
+    store: Store,
+    shared: Shared,
+) -> Result<()> {
+    let Shared {
+        listings,
+        reconcile,
+        events,
+        mut permissions_changed,
+        mut settings,
+        mut limit,
+        buffers,
+        pulls,
+        cancel,
+        ..
+    } = shared;
+    let remote_id = connection.remote_id();
+    // Requests check access themselves; a peer that had some and then lost
+    // all of it is also dropped, ending transfers already under way.
+    let was_known = store.is_known_peer(remote_id)?;
+    let rate = limit.borrow_and_update().rate();
+
+    let mut ctx = ConnectionContext {
+        remote_id,
+        version: iroh_utils::protocol_version(&connection),
+        // All streams on a connection share one bandwidth budget.
+        limiter: RateLimiter::new(rate),
+        limit: limit.clone(),
+        buffers,
+        config,
+        store,
+        connection: connection.clone(),
+        listings,
+        pulls,
+        reconcile,
+        events,
+        cancel,
+        snapshots: Snapshots::default(),
+    };
+
+    // Clients may open several bi-directional streams to transfer files in
+    // parallel. Each stream is an independent session with its own handshake.
+    let mut streams = JoinSet::new();
+    loop {
+        let (send, recv) = tokio::select! {
+            accepted = connection.accept_bi() => match accepted {
+                Ok(stream) => stream,
+                Err(_) => break,
+            },
+            Ok(()) = permissions_changed.changed(), if was_known => {
+                if !ctx.store.is_known_peer(remote_id)? {
+                    info!("Access for {} was revoked, closing its connection", remote_id);
+                    connection.close(REFUSED_CODE.into(), b"access revoked");
+                    break;
+                }
+                continue;
+            }
+            // Streams from here on use the new settings; those under way
+            // finish with the old.
+            Ok(()) = settings.changed() => {
+                ctx.config = settings.borrow_and_update().0.clone();
+                continue;
+            }
+            // The rate limit, which the settings and the time of day decide.
+            Ok(()) = limit.changed() => {
+                ctx.limiter = RateLimiter::new(limit.borrow_and_update().rate());
+                continue;
+            }
+        };
+        info!("Bi-directional stream established with {}", remote_id);
+        let ctx = ctx.clone();
+        streams.spawn(async move {
+            match handle_stream(send, recv, ctx).await {
+                Ok(()) => {}
+                // A peer that goes quiet is treated like one that hung up.
+                Err(e) if matches!(e.downcast_ref(), Some(ProtocolError::Timeout(_))) => {
+                    info!("Closing idle stream from {}: {}", remote_id, e);
+                }
+                Err(e) => error!("Stream error: {:?}", e),
+            }
+        });
+    }
+    while streams.join_next().await.is_some() {}
+    info!("Connection with {} closed", remote_id);
+
+    Ok(())
+}
+
+/// State shared by every stream on one connection.
+#[derive(Clone)]
+struct ConnectionContext {
+    remote_id: PublicKey,
+    /// Protocol version negotiated by ALPN
+    version: u32,
+    config: Config,
+    store: Store,
+    /// Pulls triggered by notifications go over it too
+    connection: Connection,
+    limiter: RateLimiter,
+    /// Pulls wait while this pauses transfers
+    limit: watch::Receiver<ActiveLimit>,
+    buffers: BufferBudget,
+    /// Pulls triggered by notifications wait for one of these
+    pulls: Arc<Semaphore>,
+    listings: ListingCache,
+    /// Gets new watches installed without waiting for the next reconcile
+    reconcile: ReconcileTrigger,
+    /// Where pulls triggered by notifications report their progress
+    events: broadcast::Sender<SyncEvent>,
+    /// Stops pulls triggered by notifications
+    cancel: watch::Receiver<CancellationToken>,
+    /// Where files listed on this connection are read from
+    snapshots: Snapshots,
+}
+
+/// Snapshots the `snapshot_command` took for one connection, by the
+/// canonical root each is of. Files below a root are read from its latest
+/// snapshot, so they match what was last listed rather than the live tree.
+#[derive(Clone, Default)]
+struct Snapshots(Arc<std::sync::Mutex<HashMap<PathBuf, PathBuf>>>);
+
+impl Snapshots {
+    /// Take a new snapshot with `command` of the root `peer` was granted that
+    /// holds `local`, and return where `local` is in it.
+    async fn take(
+        &self,
+        command: &str,
+        store: &Store,
+        peer: PublicKey,
+        local: &Path,
+    ) -> Result<PathBuf> {
+        let root = store
+            .granted_roots(peer)?
+            .iter()
+            .filter_map(|root| std::fs::canonicalize(root).ok())
+            .filter(|root| local.starts_with(root))
+            .max_by_key(|root| root.components().count())
+            .with_context(|| format!("{:?} is outside every shared root", local))?;
+        let snapshot = run_snapshot_command(command, &root).await?;
+        info!("Serving {:?} from the snapshot at {:?}", root, snapshot);
+        self.0.lock().unwrap().insert(root, snapshot);
+        Ok(self.resolve(local.to_path_buf()))
+    }
+
+    /// Where `local` is read from: below the latest snapshot of its root, or
+    /// `local` itself if none was taken.
+    fn resolve(&self, local: PathBuf) -> PathBuf {
+        let taken = self.0.lock().unwrap();
+        let found = taken
+            .iter()
+            .filter_map(|(root, snapshot)| Some((root, snapshot, local.strip_prefix(root).ok()?)))
+            .max_by_key(|(root, _, _)| root.components().count());
+        match found {
+            Some((_, snapshot, rest)) if rest.as_os_str().is_empty() => snapshot.clone(),
+            Some((_, snapshot, rest)) => snapshot.join(rest),
+            None => local,
+        }
+    }
+}
+
+/// Run `command` for the shared `root` and return the directory it printed
+/// last, which holds the consistent copy of `root` to read from.
+async fn run_snapshot_command(command: &str, root: &Path) -> Result<PathBuf> {
+    let output = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .arg("sh")
+        .arg(root)
+        .stdin(std::process::Stdio::null())
+        .output()
+        .await
+        .with_context(|| format!("Failed to run the snapshot command {:?}", command))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "Snapshot command for {:?} failed ({}): {}",
+            root,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let printed = stdout
+        .lines()
+        .map(str::trim)
+        .rfind(|line| !line.is_empty())
+        .with_context(|| format!("Snapshot command for {:?} printed no directory", root))?;
+    std::fs::canonicalize(printed)
+        .with_context(|| format!("Snapshot of {:?} not found at {}", root, printed))
+}
+
+/// `root_path`, authorized for the listing of `path`, as the listing reads
+/// it: from a new snapshot if a `snapshot_command` is configured. Tells the
+/// client and returns `None` if the snapshot couldn't be taken.
+async fn listing_root(
+    send: &mut iroh::endpoint::SendStream,
+    ctx: &ConnectionContext,
+    path: &str,
+    root_path: PathBuf,
+) -> Result<Option<PathBuf>> {
+    let Some(command) = &ctx.config.snapshot_command else {
+        return Ok(Some(root_path));
+    };
+    match ctx
+        .snapshots
+        .take(command, &ctx.store, ctx.remote_id, &root_path)
+        .await
+    {
+        Ok(snapshot) => Ok(Some(snapshot)),
+        Err(e) => {
+            // The live tree is exactly what the snapshot was meant to avoid.
+            error!("Not listing {} for {}: {:#}", path, ctx.remote_id, e);
+            let err = Message::Error {
+                code: ErrorCode::Internal,
+                message: format!("Failed to snapshot {}", path),
+            };
+            write_message(send, &err).await?;
+            Ok(None)
+        }
+    }
+}
+
+async fn handle_stream(
+    mut send: iroh::endpoint::SendStream,
+    mut recv: iroh::endpoint::RecvStream,
+    ctx: ConnectionContext,
+) -> Result<()> {
+    let ConnectionContext {
+        remote_id,
+        version: protocol_version,
+        config,
+        store,
+        connection,
+        limiter,
+        buffers,
+        reconcile,
+        snapshots,
+        ..
+    } = ctx.clone();
 
     // Send Handshake
-    let handshake = Message::Handshake { version: 1 };
+    let handshake = Message::handshake(protocol_version, &Capabilities::ours());
     write_message(&mut send, &handshake).await?;
 
-    // Read Handshake
-    let msg = read_message(&mut recv).await?;
-    match msg {
-        Message::Handshake { version } => {
-            info!("Handshake received from {}: version {}", remote_id, version);
+    // Read Handshake; only features both sides named are used from here on.
+    let idle_timeout = config.idle_timeout();
+    let msg = match read_message_within(&mut recv, config.handshake_timeout(), &buffers).await {
+        Ok(msg) => msg.into_current(),
+        Err(e @ ProtocolError::Timeout(_)) => {
+            // Connected but silent, e.g. stuck behind a half-open NAT
+            // mapping; nothing on the connection is going anywhere.
+            warn!("Closing connection from {}: no handshake: {}", remote_id, e);
+            connection.close(0u32.into(), b"no handshake");
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let capabilities = match msg {
+        Message::Handshake {
+            version,
+            capabilities,
+        } => {
+            info!(
+                "Handshake received from {}: version {}, capabilities {:?}",
+                remote_id, version, capabilities
+            );
+            Capabilities::ours().intersection(&Capabilities::from_names(capabilities))
         }
         _ => {
             anyhow::bail!("Expected handshake, got {:?}", msg);
         }
-    }
+    };
 
     // Loop to handle requests
     loop {
-        // Read next message (might be EOF if client closes)
-        let msg = match read_message(&mut recv).await {
+        let msg = match read_message_within(&mut recv, idle_timeout, &buffers).await {
             Ok(m) => m,
-            Err(_) => break, // Assume disconnection or error means stop
+            Err(e @ ProtocolError::Timeout(_)) => return Err(e.into()),
+            Err(e) if e.is_disconnect() => {
+                info!("Peer {} closed the stream: {}", remote_id, e);
+                break;
+            }
+            // Most likely a peer speaking another protocol version, or a
+            // bug; either way the stream can't be read any further.
+            Err(e) => {
+                error!("Failed to read request from {}: {}", remote_id, e);
+                break;
+            }
         };
 
         match msg {
-            Message::ListRequest { path } => {
-                info!("Client {} requested listing for: {}", remote_id, path);
-                let root_path = PathBuf::from(&path);
-
+            Message::ListRequest {
+                path,
+                with_hashes,
+                follow_symlinks,
+            } => {
+                let request = ListingRequest {
+                    path,
+                    with_hashes,
+                    follow_symlinks,
+                    generations: false,
+                    since: None,
+                    report_skipped: capabilities.contains(CAP_SKIPPED_ENTRIES),
+                };
+                send_listing(&mut send, request, &ctx).await?;
+            }
+            Message::ListIfChanged { path, .. } if protocol_version < 6 => {
+                // Not part of the version this connection negotiated.
+                let err = Message::Error {
+                    code: ErrorCode::Unsupported,
+                    message: format!("Listing generations of {} need protocol version 6", path),
+                };
+                write_message(&mut send, &err).await?;
+            }
+            Message::ListIfChanged {
+                path,
+                with_hashes,
+                follow_symlinks,
+                since,
+            } => {
+                let request = ListingRequest {
+                    path,
+                    with_hashes,
+                    follow_symlinks,
+                    generations: true,
+                    since,
+                    report_skipped: capabilities.contains(CAP_SKIPPED_ENTRIES),
+                };
+                send_listing(&mut send, request, &ctx).await?;
+            }
+            Message::ManifestRequest { path, .. } if protocol_version < 4 => {
+                // Not part of the version this connection negotiated.
+                let err = Message::Error {
+                    code: ErrorCode::Unsupported,
+                    message: format!("Diffing a manifest of {} needs protocol version 4", path),
+                };
+                write_message(&mut send, &err).await?;
+            }
+            Message::ManifestRequest {
+                path,
+                files,
+                follow_symlinks,
+            } => {
+                info!(
+                    "Client {} sent a manifest of {} entries for: {}",
+                    remote_id,
+                    files.len(),
+                    path
+                );
+                let Some(root_path) = authorize(&store, remote_id, &path, AccessMode::Read)? else {
+                    deny(&mut send, remote_id, &path).await?;
+                    continue;
+                };
+                let Some(root_path) = listing_root(&mut send, &ctx, &path, root_path).await? else {
+                    continue;
+                };
                 if !root_path.exists() {
                     let err = Message::Error {
+                        code: ErrorCode::NotFound,
                         message: format!("Path not found: {}", path),
                     };
                     write_message(&mut send, &err).await?;
                     continue;
                 }
 
-                if root_path.is_file() {
-                    // Just return the single file
-                    let metadata = std::fs::metadata(&root_path)?;
-                    let files = vec![FileMetadata {
-                        path: path.clone(),
-                        len: metadata.len(),
-                        modified: metadata
-                            .modified()?
-                            .duration_since(std::time::UNIX_EPOCH)?
-                            .as_secs(),
-                        is_dir: false,
-                    }];
-                    let resp = Message::ListResponse { files };
-                    write_message(&mut send, &resp).await?;
-                } else {
-                    // It's a directory, walk it
-                    let mut files = Vec::new();
-                    // Use blocking WalkDir inside spawn_blocking if large, but for now direct
-                    for entry in WalkDir::new(&root_path) {
-                        match entry {
-                            Ok(e) => {
-                                // Skip the root dir itself in the listing if we want relative paths from it?
-                                // Protocol definition needs clarity.
-                                // If I request "/tmp/foo", and it contains "bar.txt".
-                                // Do I want "bar.txt" or "/tmp/foo/bar.txt"?
-                                // Sync logic usually wants paths relative to the sync root, OR absolute paths if we are keeping absolute structure.
-                                // But `FileRequest` uses the path string.
-                                // Let's send back the path string that `FileRequest` expects.
-                                // If I request "/tmp/foo", I expect "bar.txt" to be requested as "/tmp/foo/bar.txt"?
-                                // Yes, `serve.rs` just `PathBuf::from(&path)`. So we should send full paths (as provided or absolute).
-                                // `WalkDir` yields paths relative to the `root_path` if given relative, or absolute if given absolute.
-                                // `root_path` comes from `path` string.
-
-                                let entry_path = e.path();
-                                let metadata = e.metadata()?;
-                                let p_str = entry_path.to_string_lossy().to_string();
-
-                                files.push(FileMetadata {
-                                    path: p_str,
-                                    len: metadata.len(),
-                                    modified: metadata
-                                        .modified()?
-                                        .duration_since(std::time::UNIX_EPOCH)?
-                                        .as_secs(),
-                                    is_dir: metadata.is_dir(),
-                                });
-                            }
-                            Err(e) => warn!("Error walking dir: {}", e),
-                        }
+                let mut theirs: HashMap<String, FileMetadata> =
+                    files.into_iter().map(|f| (f.path.clone(), f)).collect();
+                let mut entries = Vec::new();
+                let mut hard_links = HardLinks::default();
+                let mut skipped = 0;
+                for entry in walk_shared(&root_path, &path, follow_symlinks, &store, remote_id) {
+                    let Some((e, mut file)) = listed(entry, &root_path, &path, &mut hard_links)
+                    else {
+                        skipped += 1;
+                        continue;
+                    };
+                    let local = theirs.remove(&file.path);
+                    let Some(action) = manifest_action(&mut file, local.as_ref(), e.path()).await?
+                    else {
+                        continue;
+                    };
+                    entries.push(ManifestEntry { file, action });
+                    if entries.len() == LIST_CHUNK_LEN {
+                        let resp = Message::ManifestResponse {
+                            entries: std::mem::take(&mut entries),
+                            is_last: false,
+                        };
+                        write_message(&mut send, &resp).await?;
+                    }
+                }
+                // Whatever wasn't walked over exists only on the client,
+                // unless it is one of the entries that couldn't be read.
+                if skipped > 0 {
+                    warn!(
+                        "Not deleting anything below {} for {}: {} entries couldn't be read",
+                        path, remote_id, skipped
+                    );
+                    theirs.clear();
+                    if capabilities.contains(CAP_SKIPPED_ENTRIES) {
+                        let msg = Message::ListSkipped { count: skipped };
+                        write_message(&mut send, &msg).await?;
                     }
-                    let resp = Message::ListResponse { files };
-                    write_message(&mut send, &resp).await?;
                 }
+                let mut gone: Vec<FileMetadata> = theirs.into_values().collect();
+                gone.sort_by(|a, b| a.path.cmp(&b.path));
+                for file in gone {
+                    entries.push(ManifestEntry {
+                        file,
+                        action: ManifestAction::Delete,
+                    });
+                    if entries.len() == LIST_CHUNK_LEN {
+                        let resp = Message::ManifestResponse {
+                            entries: std::mem::take(&mut entries),
+                            is_last: false,
+                        };
+                        write_message(&mut send, &resp).await?;
+                    }
+                }
+                let resp = Message::ManifestResponse {
+                    entries,
+                    is_last: true,
+                };
+                write_message(&mut send, &resp).await?;
             }
-            Message::FileRequest { path } => {
-                info!("Client {} requested file: {}", remote_id, path);
+            Message::FileRequest { path, offset } => {
+                info!(
+                    "Client {} requested file: {} (from byte {})",
+                    remote_id, path, offset
+                );
+                let Some(path_buf) = authorize(&store, remote_id, &path, AccessMode::Read)? else {
+                    deny(&mut send, remote_id, &path).await?;
+                    continue;
+                };
+                let path_buf = snapshots.resolve(path_buf);
 
-                let path_buf = std::path::PathBuf::from(&path);
                 if path_buf.exists() {
                     if path_buf.is_dir() {
                         // Should use ListRequest for dirs, but if requested here, maybe error?
                         // Or just empty data?
                         let err = Message::Error {
+                            code: ErrorCode::InvalidRequest,
                             message: format!("{} is a directory, use ListRequest", path),
                         };
                         write_message(&mut send, &err).await?;
                     } else {
-                        let data = tokio::fs::read(&path_buf).await?;
-                        let resp = Message::FileData {
-                            path: path.clone(),
-                            data,
-                            offset: 0,
-                            is_last: true,
-                        };
-                        write_message(&mut send, &resp).await?;
+                        let sent = send_file(
+                            &mut send,
+                            &path_buf,
+                            &path,
+                            offset,
+                            config.chunk_size,
+                            &limiter,
+                            &buffers,
+                        )
+                        .await;
+                        match sent {
+                            Err(e) if e.is::<ChangedWhileRead>() => {
+                                warn!("Not sending the rest of {}: {}", path, e);
+                                let err = changed_error(&path, &capabilities);
+                                write_message(&mut send, &err).await?;
+                            }
+                            sent => sent?,
+                        }
                     }
                 } else {
                     let err = Message::Error {
+                        code: ErrorCode::NotFound,
                         message: format!("File not found: {}", path),
                     };
                     write_message(&mut send, &err).await?;
@@ -186,23 +1165,84 @@ async fn handle_connection(
             }
             Message::FileSignature { path, signature } => {
                 info!("Client {} sent signature for: {}", remote_id, path);
+                let Some(path_buf) = authorize(&store, remote_id, &path, AccessMode::Read)? else {
+                    deny(&mut send, remote_id, &path).await?;
+                    continue;
+                };
+                let path_buf = snapshots.resolve(path_buf);
 
-                let path_buf = std::path::PathBuf::from(&path);
                 if path_buf.exists() && path_buf.is_file() {
-                    let data = tokio::fs::read(&path_buf).await?;
+                    let before = file_state(&path_buf);
+                    // Mapped rather than read so memory use doesn't grow with
+                    // the file size.
+                    let data: SharedBytes = match sync_utils::map_file(&path_buf) {
+                        Ok(data) => Arc::new(data),
+                        Err(e) => {
+                            let err = Message::Error {
+                                code: ErrorCode::Internal,
+                                message: format!("Failed to read {}: {:#}", path, e),
+                            };
+                            write_message(&mut send, &err).await?;
+                            continue;
+                        }
+                    };
 
-                    // Calculate delta
-                    match sync_utils::calculate_delta(&signature, &data) {
-                        Ok(delta) => {
+                    let delta = sync_utils::blocking(&data, move |data| {
+                        sync_utils::calculate_delta(&signature, data)
+                            .map(|delta| (delta, *blake3::hash(data).as_bytes()))
+                    })
+                    .await?;
+                    let file_len = (*data).as_ref().len() as f64;
+                    match delta {
+                        // A write while the delta was computed may have torn
+                        // what it was computed from.
+                        Ok(_) if file_state(&path_buf) != before => {
+                            let err = changed_error(&path, &capabilities);
+                            write_message(&mut send, &err).await?;
+                        }
+                        Ok((delta, _)) if delta.len() > MAX_CHUNK_LEN => {
+                            // Too large for one frame; the client downloads
+                            // the file in chunks instead.
+                            let err = Message::Error {
+                                code: ErrorCode::DeltaFailed,
+                                message: format!(
+                                    "A {} byte delta of {} is too large to send",
+                                    delta.len(),
+                                    path
+                                ),
+                            };
+                            write_message(&mut send, &err).await?;
+                        }
+                        Ok((delta, _))
+                            if delta.len() as f64 > file_len * (1.0 - config.delta_min_savings) =>
+                        {
+                            // Not worth patching; the client downloads the
+                            // file instead.
+                            let err = Message::Error {
+                                code: ErrorCode::DeltaFailed,
+                                message: format!(
+                                    "A {} byte delta saves too little of {}",
+                                    delta.len(),
+                                    path
+                                ),
+                            };
+                            write_message(&mut send, &err).await?;
+                        }
+                        Ok((delta, hash)) => {
                             info!("Calculated delta size: {} bytes", delta.len());
+                            let _buffered = buffers.acquire(delta.len()).await;
+                            limiter.acquire(delta.len()).await;
+                            metrics::get().bytes_sent.inc_by(delta.len() as u64);
                             let resp = Message::FileDelta {
                                 path: path.clone(),
                                 delta,
+                                hash,
                             };
                             write_message(&mut send, &resp).await?;
                         }
                         Err(e) => {
                             let err = Message::Error {
+                                code: ErrorCode::DeltaFailed,
                                 message: format!("Delta calculation failed: {}", e),
                             };
                             write_message(&mut send, &err).await?;
@@ -210,161 +1250,1283 @@ async fn handle_connection(
                     }
                 } else {
                     let err = Message::Error {
+                        code: ErrorCode::NotFound,
                         message: format!("File not found: {}", path),
                     };
                     write_message(&mut send, &err).await?;
                 }
             }
-            Message::FileUpdateNotification { path } => {
-                info!("Peer {} notified update for: {}", remote_id, path);
+            Message::FileSignatureRange { path, .. } if protocol_version < 5 => {
+                // Not part of the version this connection negotiated.
+                let err = Message::Error {
+                    code: ErrorCode::Unsupported,
+                    message: format!("Patching {} by range needs protocol version 5", path),
+                };
+                write_message(&mut send, &err).await?;
+            }
+            Message::FileSignatureRange {
+                path,
+                offset,
+                len,
+                signature,
+            } => {
+                info!(
+                    "Client {} sent signature for {} bytes of {} at {}",
+                    remote_id, len, path, offset
+                );
+                let Some(path_buf) = authorize(&store, remote_id, &path, AccessMode::Read)? else {
+                    deny(&mut send, remote_id, &path).await?;
+                    continue;
+                };
+                let path_buf = snapshots.resolve(path_buf);
+                if !path_buf.is_file() {
+                    let err = Message::Error {
+                        code: ErrorCode::NotFound,
+                        message: format!("File not found: {}", path),
+                    };
+                    write_message(&mut send, &err).await?;
+                    continue;
+                }
+                let before = file_state(&path_buf);
+                let data: SharedBytes = match sync_utils::map_file(&path_buf) {
+                    Ok(data) => Arc::new(data),
+                    Err(e) => {
+                        let err = Message::Error {
+                            code: ErrorCode::Internal,
+                            message: format!("Failed to read {}: {:#}", path, e),
+                        };
+                        write_message(&mut send, &err).await?;
+                        continue;
+                    }
+                };
 
-                // Trigger Pull (Sync)
-                // We need to find where this file maps to locally.
-                // This requires a reverse lookup: RemotePath + Peer -> LocalPath.
-                // Currently `store` doesn't support efficient reverse lookup, we have to scan.
-
-                // TODO: Optimize this
-                // For now, iterate all syncs
-                if let Ok(syncs) = store.list_syncs() {
-                    for (local_root, configs) in syncs {
-                        for config in configs {
-                            if config.peer == remote_id {
-                                // Check if this notification matches the configured remote path
-                                // Case 1: Notification path == Config remote path (Exact file match)
-                                // Case 2: Notification path is inside Config remote path (Directory sync)
-
-                                // Simplified logic for exact match first
-                                if path == config.remote_path {
-                                    info!(
-                                        "Found matching sync config. Syncing to {:?}",
-                                        local_root
-                                    );
-
-                                    // Spawn a task to perform the pull to avoid blocking the server loop
-                                    let endpoint_clone = endpoint.clone();
-                                    let remote_id_clone = remote_id;
-                                    let path_clone = path.clone();
-                                    let local_root_clone = local_root.clone();
-
-                                    tokio::spawn(async move {
-                                        if let Err(e) = crate::cli::copy::run(
-                                            remote_id_clone,
-                                            path_clone,
-                                            local_root_clone,
-                                        )
-                                        .await
-                                        {
-                                            error!("Failed to sync update: {:?}", e);
-                                        }
-                                    });
-                                } else if path.starts_with(&config.remote_path) {
-                                    // Directory match
-                                    // We need to map the subpath
-                                    // e.g. Config Remote: /remote/dir -> Local: /local/dir
-                                    // Update: /remote/dir/subdir/file.txt
-                                    // Relative: subdir/file.txt
-                                    // Target: /local/dir/subdir/file.txt
-
-                                    if let Ok(relative) = std::path::Path::new(&path)
-                                        .strip_prefix(&config.remote_path)
-                                    {
-                                        let target_local = local_root.join(relative);
-                                        info!(
-                                            "Found matching dir sync. Syncing to {:?}",
-                                            target_local
-                                        );
-
-                                        let endpoint_clone = endpoint.clone();
-                                        let remote_id_clone = remote_id;
-                                        let path_clone = path.clone();
-
-                                        tokio::spawn(async move {
-                                            if let Err(e) = crate::cli::copy::run(
-                                                remote_id_clone,
-                                                path_clone,
-                                                target_local,
-                                            )
-                                            .await
-                                            {
-                                                error!("Failed to sync update: {:?}", e);
-                                            }
-                                        });
-                                    }
-                                }
-                            }
-                        }
+                // Ranges are capped like file chunks, which bounds the delta.
+                let file_len = (*data).as_ref().len() as u64;
+                let start = offset.min(file_len);
+                let end = start
+                    .saturating_add(len.min(MAX_CHUNK_LEN as u64))
+                    .min(file_len);
+                let delta = sync_utils::blocking(&data, move |data| {
+                    sync_utils::calculate_delta(&signature, &data[start as usize..end as usize])
+                        .map(|delta| {
+                            (
+                                delta,
+                                (end == file_len).then(|| *blake3::hash(data).as_bytes()),
+                            )
+                        })
+                })
+                .await?;
+                match delta {
+                    Ok(_) if file_state(&path_buf) != before => {
+                        let err = changed_error(&path, &capabilities);
+                        write_message(&mut send, &err).await?;
+                    }
+                    Ok((delta, hash)) => {
+                        let _buffered = buffers.acquire(delta.len()).await;
+                        limiter.acquire(delta.len()).await;
+                        metrics::get().bytes_sent.inc_by(delta.len() as u64);
+                        let resp = Message::FileDeltaRange {
+                            path: path.clone(),
+                            offset: start,
+                            delta,
+                            hash,
+                        };
+                        write_message(&mut send, &resp).await?;
                     }
+                    Err(e) => {
+                        let err = Message::Error {
+                            code: ErrorCode::DeltaFailed,
+                            message: format!("Delta calculation failed: {}", e),
+                        };
+                        write_message(&mut send, &err).await?;
+                    }
+                }
+            }
+            Message::ChunkRequest { path } if !capabilities.contains(CAP_CHUNKS) => {
+                // Not among the capabilities this connection negotiated.
+                let err = Message::Error {
+                    code: ErrorCode::Unsupported,
+                    message: format!(
+                        "Sending {} in chunks needs the {} capability",
+                        path, CAP_CHUNKS
+                    ),
+                };
+                write_message(&mut send, &err).await?;
+            }
+            Message::ChunkRequest { path } => {
+                info!("Client {} requested {} in chunks", remote_id, path);
+                let Some(path_buf) = authorize(&store, remote_id, &path, AccessMode::Read)? else {
+                    deny(&mut send, remote_id, &path).await?;
+                    continue;
+                };
+                let path_buf = snapshots.resolve(path_buf);
+                if !path_buf.is_file() {
+                    let err = Message::Error {
+                        code: ErrorCode::NotFound,
+                        message: format!("File not found: {}", path),
+                    };
+                    write_message(&mut send, &err).await?;
+                    continue;
                 }
+                send_chunks(
+                    &mut send,
+                    &mut recv,
+                    &path_buf,
+                    &path,
+                    config.idle_timeout(),
+                    &limiter,
+                    &buffers,
+                )
+                .await?;
+            }
+            Message::FileUpdateNotification { path } => {
+                info!("Peer {} notified update for: {}", remote_id, path);
+                pull_notified(&ctx, vec![path]);
+            }
+            Message::BatchUpdateNotification { paths }
+                if !capabilities.contains(CAP_BATCH_NOTIFY) =>
+            {
+                // Not among the capabilities this connection negotiated.
+                let err = Message::Error {
+                    code: ErrorCode::Unsupported,
+                    message: format!(
+                        "Notifying {} updates at once needs the {} capability",
+                        paths.len(),
+                        CAP_BATCH_NOTIFY
+                    ),
+                };
+                write_message(&mut send, &err).await?;
+            }
+            Message::BatchUpdateNotification { paths } => {
+                info!(
+                    "Peer {} notified updates for {} paths: {:?}",
+                    remote_id,
+                    paths.len(),
+                    paths
+                );
+                pull_notified(&ctx, paths);
+            }
+            Message::CheckAccess { path } if !capabilities.contains(CAP_CHECK_ACCESS) => {
+                // Not among the capabilities this connection negotiated.
+                let err = Message::Error {
+                    code: ErrorCode::Unsupported,
+                    message: format!(
+                        "Checking access to {} needs the {} capability",
+                        path, CAP_CHECK_ACCESS
+                    ),
+                };
+                write_message(&mut send, &err).await?;
+            }
+            Message::CheckAccess { path } => {
+                // Syncing reads the path and registers a reverse sync,
+                // which needs write access, as StartSync checks.
+                let reason = if authorize(&store, remote_id, &path, AccessMode::Write)?.is_some() {
+                    None
+                } else if authorize(&store, remote_id, &path, AccessMode::Read)?.is_some() {
+                    Some("it is shared read-only, and syncing needs read-write access")
+                } else {
+                    Some("it is not shared with you")
+                };
+                info!(
+                    "Peer {} checked access to {}: {}",
+                    remote_id,
+                    path,
+                    reason.unwrap_or("allowed")
+                );
+                let result = Message::AccessResult {
+                    allowed: reason.is_none(),
+                    reason: reason.map(str::to_string),
+                };
+                write_message(&mut send, &result).await?;
+            }
+            Message::ListShares if !capabilities.contains(CAP_SHARES) => {
+                let err = Message::Error {
+                    code: ErrorCode::Unsupported,
+                    message: format!("Listing shares needs the {} capability", CAP_SHARES),
+                };
+                write_message(&mut send, &err).await?;
+            }
+            Message::ListShares => {
+                // Only the peer's own grants; who else has access stays
+                // private.
+                let shares: Vec<Share> = store
+                    .shares_of(remote_id)?
+                    .into_iter()
+                    .map(|(path, mode)| Share {
+                        path: to_wire_path(&path),
+                        read: mode.covers(AccessMode::Read),
+                        write: mode.covers(AccessMode::Write),
+                    })
+                    .collect();
+                info!("Peer {} listed its {} shares", remote_id, shares.len());
+                write_message(&mut send, &Message::Shares { shares }).await?;
             }
             Message::StartSync { path } => {
                 info!("Peer {} requesting to sync path: {}", remote_id, path);
 
                 // 1. Check if allowed
-                // We check if this path is in the `permissions` list for this peer.
-                // Store permissions are keyed by path.
+                // Registering a reverse sync lets the peer push its changes
+                // into this path, so it needs write access.
                 // NOTE: 'path' here is the path on THIS machine (Server).
-                // Protocol assumes Client requests known path.
-
-                let path_buf = std::path::PathBuf::from(&path);
-                // We need to handle relative/absolute path resolution correctly relative to what was allowed.
-                // If I allowed `/tmp/foo`, and client asks for `/tmp/foo`, it matches.
-                // If client asks for `foo` (relative), we might fail if we stored absolute.
-                // Let's try canonicalizing if possible, or just exact string match against allowed.
-
-                // Best effort resolution
-                let abs_path = if path_buf.is_absolute() {
-                    path_buf.clone()
-                } else {
-                    std::fs::canonicalize(&path_buf).unwrap_or(path_buf.clone())
-                };
-
-                let allowed_peers = store.get_permissions(&abs_path)?;
-                if allowed_peers.contains(&remote_id) {
+                if let Some(abs_path) = authorize(&store, remote_id, &path, AccessMode::Write)? {
                     info!("Access granted. Registering reverse sync config.");
 
-                    // 2. Add Sync Config
+                    // 2. Add Sync Config and Watch
                     // peer: remote_id
                     // remote_path: path (This is tricky. We are registering that WE want to notify Remote about 'path'.
                     // So 'remote_path' in SyncConfig effectively becomes the identifier we send in FileUpdateNotification.
                     // If we use 'path', we notify Remote about 'path'. Remote must have mapped 'path' to its local.
                     // This matches the current logic.)
 
-                    store.add_sync(remote_id, path.clone(), abs_path.clone())?;
-
-                    // 3. Add Watch
-                    store.add_watch(&abs_path)?;
+                    // Changes to hidden files are only sent if our config
+                    // includes them. The sync goes again when the peer loses
+                    // write access.
+                    store.add_peer_sync_with_watch(
+                        remote_id,
+                        path.clone(),
+                        abs_path,
+                        config.include_hidden,
+                    )?;
+                    reconcile.fire();
 
                     // TODO: Send success response?
                 } else {
-                    warn!("Access denied for peer {} on path {}", remote_id, path);
+                    deny(&mut send, remote_id, &path).await?;
+                }
+            }
+            Message::FilePush { path, hash } => {
+                info!("Client {} is pushing: {}", remote_id, path);
+                let Some(path_buf) = authorize(&store, remote_id, &path, AccessMode::Write)? else {
+                    deny(&mut send, remote_id, &path).await?;
+                    continue;
+                };
+                if path_buf.is_dir() {
                     let err = Message::Error {
-                        message: "Access denied or path not allowed".to_string(),
+                        code: ErrorCode::InvalidRequest,
+                        message: format!("{} is a directory", path),
                     };
                     write_message(&mut send, &err).await?;
+                    continue;
                 }
+                receive_push(
+                    &mut send, &mut recv, &path, &path_buf, hash, &config, &buffers,
+                )
+                .await?;
+            }
+            Message::Ping { nonce } => {
+                write_message(&mut send, &Message::Pong { nonce }).await?;
             }
             _ => {
                 info!("Received unexpected message: {:?}", msg);
+                let err = Message::Error {
+                    code: ErrorCode::Unsupported,
+                    message: "Unsupported message".to_string(),
+                };
+                write_message(&mut send, &err).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Pull the changes the peer on `ctx` notified us of at `paths` over its
+/// connection. Every local path syncing a notified path gets its own pull;
+/// pulls into overlapping local paths wait for each other rather than
+/// interleave, and no more than `max_concurrent_pulls` run at once.
+fn pull_notified(ctx: &ConnectionContext, mut paths: Vec<String>) {
+    let remote_id = ctx.remote_id;
+    let (config, store, connection) = (&ctx.config, &ctx.store, &ctx.connection);
+    let (limit, events, cancel) = (&ctx.limit, &ctx.events, &ctx.cancel);
+    let pulls = &ctx.pulls;
+    // A path changed several times in a batch is pulled once.
+    paths.sort();
+    paths.dedup();
+
+    // We need to find where each path maps to locally. This requires a
+    // reverse lookup: RemotePath + Peer -> LocalPath. The store doesn't
+    // support an efficient one, so we scan every sync, once per batch.
+    let Ok(syncs) = store.list_syncs() else {
+        return;
+    };
+    for path in paths {
+        for (local_root, configs) in &syncs {
+            for sync_config in configs {
+                if sync_config.peer != remote_id {
+                    continue;
+                }
+
+                // Check if this notification matches the configured remote path
+                // Case 1: Notification path == Config remote path (Exact file match)
+                // Case 2: Notification path is inside Config remote path (Directory sync)
+
+                // Simplified logic for exact match first
+                if path == sync_config.remote_path {
+                    info!("Found matching sync config. Syncing to {:?}", local_root);
+
+                    // Spawn a task to perform the pull to avoid blocking the server loop
+                    let connection = connection.clone();
+                    let remote_id_clone = remote_id;
+                    let path_clone = path.clone();
+                    let local_root_clone = local_root.clone();
+                    let mut copy_opts = CopyOptions {
+                        base_hash: sync_config.last_hash,
+                        on_conflict: sync_config.on_conflict,
+                        block_size: config.block_size,
+                        strong_hash_size: config.strong_hash_size,
+                        include: sync_config.include.clone(),
+                        include_hidden: sync_config.include_hidden,
+                        max_size: sync_config.max_size,
+                        backup: config.backup.enabled.then(|| config.backup.retention()),
+                        store: Some(store.clone()),
+                        events: Some(events.clone()),
+                        fsync: config.fsync,
+                        delta_min_len: config.delta_min_len,
+                        cancel: cancel.borrow().clone(),
+                        ..Default::default()
+                    };
+                    let config = config.clone();
+                    let store = store.clone();
+                    let events = events.clone();
+                    let mut limit = limit.clone();
+                    let pulls = pulls.clone();
+
+                    tokio::spawn(async move {
+                        let Ok(_pull) = pulls.acquire_owned().await else {
+                            return;
+                        };
+                        copy_opts.limit_rate = wait_for_schedule(&mut limit, &path_clone).await;
+                        let _ = events.send(SyncEvent::PullStarted {
+                            peer: remote_id_clone,
+                            path: path_clone.clone(),
+                            local: local_root_clone.clone(),
+                        });
+                        let _tree = path_lock::lock_tree(&local_root_clone).await;
+                        // Pulled as the identity the peer notified.
+                        let result = copy::run_on(
+                            &connection,
+                            &config,
+                            path_clone.clone(),
+                            local_root_clone.clone(),
+                            copy_opts,
+                        )
+                        .await;
+                        let hash = result.as_ref().ok().and_then(|report| report.hash);
+                        if let Err(e) = record_pull(
+                            &store,
+                            &local_root_clone,
+                            remote_id_clone,
+                            &path_clone,
+                            hash,
+                            &result,
+                        ) {
+                            error!("Failed to record sync: {:?}", e);
+                        }
+                        pull_finished(
+                            &events,
+                            remote_id_clone,
+                            path_clone,
+                            local_root_clone,
+                            result,
+                        );
+                    });
+                } else if let Some(relative) = strip_wire_prefix(&path, &sync_config.remote_path) {
+                    // Changes outside the subpaths the sync is
+                    // limited to are not pulled.
+                    let Some(include) = includes_below(relative, &sync_config.include) else {
+                        info!("{} is not included in the sync, ignoring", path);
+                        continue;
+                    };
+                    if !sync_config.include_hidden && is_hidden(relative) {
+                        info!("{} is hidden, ignoring", path);
+                        continue;
+                    }
+
+                    // Directory match
+                    // We need to map the subpath
+                    // e.g. Config Remote: /remote/dir -> Local: /local/dir
+                    // Update: /remote/dir/subdir/file.txt
+                    // Relative: subdir/file.txt
+                    // Target: /local/dir/subdir/file.txt
+
+                    let Some(relative_local) = from_wire_relative(relative) else {
+                        warn!(
+                            "{} from {} leads out of the sync, ignoring",
+                            path, remote_id
+                        );
+                        continue;
+                    };
+                    let target_local = local_root.join(relative_local);
+                    info!("Found matching dir sync. Syncing to {:?}", target_local);
+
+                    let connection = connection.clone();
+                    let remote_id_clone = remote_id;
+                    let path_clone = path.clone();
+                    let mut copy_opts = CopyOptions {
+                        block_size: config.block_size,
+                        strong_hash_size: config.strong_hash_size,
+                        include,
+                        include_hidden: sync_config.include_hidden,
+                        max_size: sync_config.max_size,
+                        backup: config.backup.enabled.then(|| config.backup.retention()),
+                        store: Some(store.clone()),
+                        events: Some(events.clone()),
+                        fsync: config.fsync,
+                        delta_min_len: config.delta_min_len,
+                        cancel: cancel.borrow().clone(),
+                        ..Default::default()
+                    };
+                    let config = config.clone();
+                    let store = store.clone();
+                    let local_root_clone = local_root.clone();
+                    let remote_root = sync_config.remote_path.clone();
+                    let events = events.clone();
+                    let mut limit = limit.clone();
+                    let pulls = pulls.clone();
+
+                    tokio::spawn(async move {
+                        let Ok(_pull) = pulls.acquire_owned().await else {
+                            return;
+                        };
+                        copy_opts.limit_rate = wait_for_schedule(&mut limit, &path_clone).await;
+                        let _ = events.send(SyncEvent::PullStarted {
+                            peer: remote_id_clone,
+                            path: path_clone.clone(),
+                            local: target_local.clone(),
+                        });
+                        let _tree = path_lock::lock_tree(&target_local).await;
+                        let result = copy::run_on(
+                            &connection,
+                            &config,
+                            path_clone.clone(),
+                            target_local.clone(),
+                            copy_opts,
+                        )
+                        .await;
+                        if let Err(e) = record_pull(
+                            &store,
+                            &local_root_clone,
+                            remote_id_clone,
+                            &remote_root,
+                            None,
+                            &result,
+                        ) {
+                            error!("Failed to record sync: {:?}", e);
+                        }
+                        pull_finished(&events, remote_id_clone, path_clone, target_local, result);
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Report how the pull of `path` from `peer` into `local` went.
+fn pull_finished(
+    events: &broadcast::Sender<SyncEvent>,
+    peer: PublicKey,
+    path: String,
+    local: PathBuf,
+    result: Result<CopyReport>,
+) {
+    let event = match result {
+        Ok(_) => SyncEvent::PullCompleted { peer, path, local },
+        Err(e) => {
+            error!("Failed to sync update: {:?}", e);
+            SyncEvent::PullFailed {
+                peer,
+                path,
+                error: format!("{:#}", e),
+            }
+        }
+    };
+    let _ = events.send(event);
+}
+
+/// Record how the pull of `remote` from `peer` into the sync at `local`
+/// went, both on the sync and in its log. A `None` hash keeps the one
+/// recorded before.
+fn record_pull(
+    store: &Store,
+    local: &Path,
+    peer: PublicKey,
+    remote: &str,
+    hash: Option<[u8; 32]>,
+    result: &Result<CopyReport>,
+) -> Result<()> {
+    match result {
+        Ok(report) => {
+            store.record_sync_result(local, peer, remote, hash, sync_utils::unix_timestamp())?;
+            store.log_sync(local, report.log_entry(peer, remote))?;
+        }
+        Err(e) => {
+            let error = format!("{:#}", e);
+            store.record_sync_failure(local, peer, remote, &error)?;
+            store.log_sync(
+                local,
+                SyncLogEntry::failed(peer, remote, Direction::Pull, error),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Wait out a pause `limit` puts on transfers before pulling `path`,
+/// returning the rate the pull may then use.
+async fn wait_for_schedule(limit: &mut watch::Receiver<ActiveLimit>, path: &str) -> Option<u64> {
+    if limit.borrow().is_paused() {
+        info!(
+            "Transfers are paused by the schedule, pulling {} once they resume",
+            path
+        );
+        schedule::wait_unpaused(limit).await;
+    }
+    limit.borrow().rate()
+}
+
+/// Names files with several hard links were first listed under, by device
+/// and inode, so the others can be listed as links to them.
+#[derive(Default)]
+struct HardLinks(HashMap<(u64, u64), String>);
+
+impl HardLinks {
+    /// The name the file behind `metadata` was first listed under, or `None`
+    /// if this is the first time, in which case `path` is remembered.
+    #[cfg(unix)]
+    fn first_name(&mut self, metadata: &std::fs::Metadata, path: &str) -> Option<String> {
+        use std::collections::hash_map::Entry;
+        use std::os::unix::fs::MetadataExt;
+
+        if metadata.nlink() < 2 {
+            return None;
+        }
+        match self.0.entry((metadata.dev(), metadata.ino())) {
+            Entry::Occupied(first) => Some(first.get().clone()),
+            Entry::Vacant(first) => {
+                first.insert(path.to_string());
+                None
+            }
+        }
+    }
+
+    /// Hard links aren't detected here, so every name is listed as a file.
+    #[cfg(not(unix))]
+    fn first_name(&mut self, _metadata: &std::fs::Metadata, _path: &str) -> Option<String> {
+        None
+    }
+}
+
+/// Take the file the peer is pushing to `target`, known to it as `path`,
+/// as a delta against the file already there if there is one, or whole.
+/// It is written beside `target` and only moved into place once it has the
+/// announced `hash`, after backing up the old one if `config` says to.
+async fn receive_push(
+    send: &mut iroh::endpoint::SendStream,
+    recv: &mut iroh::endpoint::RecvStream,
+    path: &str,
+    target: &Path,
+    hash: [u8; 32],
+    config: &Config,
+    buffers: &BufferBudget,
+) -> Result<()> {
+    let _lock = path_lock::lock(target).await;
+    let idle_timeout = config.idle_timeout();
+    let existing: Option<SharedBytes> = if target.is_file() {
+        Some(Arc::new(sync_utils::map_file(target)?))
+    } else {
+        None
+    };
+    if existing
+        .as_ref()
+        .is_some_and(|data| *blake3::hash((**data).as_ref()).as_bytes() == hash)
+    {
+        let done = Message::FilePushComplete {
+            path: path.to_string(),
+        };
+        return Ok(write_message(send, &done).await?);
+    }
+
+    // An empty file has no blocks a delta could reuse.
+    let signature = match &existing {
+        Some(data) if !(**data).as_ref().is_empty() => {
+            let strong_hash_size = config.strong_hash_size;
+            Some(
+                sync_utils::blocking(data, move |data| {
+                    sync_utils::calculate_signature(data, None, strong_hash_size)
+                })
+                .await??,
+            )
+        }
+        _ => None,
+    };
+    // One too large for a frame has the client send the file whole.
+    let signature = signature.filter(|signature| signature.len() <= MAX_CHUNK_LEN);
+    let resp = Message::FilePushSignature {
+        path: path.to_string(),
+        signature,
+    };
+    write_message(send, &resp).await?;
+
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let partial = copy::partial_path(target);
+    let mut file = tokio::fs::File::create(&partial).await?;
+    let mut written = 0u64;
+    loop {
+        match read_message_within(recv, idle_timeout, buffers).await? {
+            Message::FileDelta { delta, .. } if written == 0 => {
+                // Patched straight into the partial, which is emptied again
+                // if the delta doesn't apply.
+                let patched = match &existing {
+                    Some(old) => {
+                        let partial = partial.clone();
+                        Some(
+                            sync_utils::blocking(old, move |old| {
+                                sync_utils::apply_delta_to_file(old, &delta, &partial)
+                            })
+                            .await?,
+                        )
+                    }
+                    None => None,
+                };
+                match patched {
+                    Some(Ok(patched)) if patched == hash => break,
+                    _ => {
+                        file.set_len(0).await?;
+                        warn!(
+                            "Delta pushed for {} does not apply, asking for the whole file",
+                            path
+                        );
+                        let err = Message::Error {
+                            code: ErrorCode::DeltaFailed,
+                            message: format!("Delta for {} does not apply", path),
+                        };
+                        write_message(send, &err).await?;
+                    }
+                }
+            }
+            Message::FileData {
+                data,
+                offset,
+                is_last,
+                ..
+            } if offset == written => {
+                file.write_all(&data).await?;
+                written += data.len() as u64;
+                if is_last {
+                    break;
+                }
+            }
+            msg => {
+                drop(file);
+                let _ = tokio::fs::remove_file(&partial).await;
+                anyhow::bail!("Unexpected message during push of {}: {:?}", path, msg);
             }
         }
     }
+    file.flush().await?;
+    drop(file);
+
+    if sync_utils::hash_file(&partial).await? != hash {
+        let _ = tokio::fs::remove_file(&partial).await;
+        let err = Message::Error {
+            code: ErrorCode::InvalidRequest,
+            message: format!("Pushed {} does not match its hash", path),
+        };
+        return Ok(write_message(send, &err).await?);
+    }
+    drop(existing);
+    if config.backup.enabled && target.is_file() {
+        let root = target.parent().unwrap_or(target);
+        Trash::new(root, config.backup.retention())
+            .keep(target)
+            .context("Failed to back up file")?;
+    }
+    sync_utils::commit_file(&partial, target, config.fsync)
+        .await
+        .context("Failed to write pushed file")?;
+    info!("Wrote pushed file {:?}", target);
 
+    let done = Message::FilePushComplete {
+        path: path.to_string(),
+    };
+    Ok(write_message(send, &done).await?)
+}
+
+/// Send the chunks of the file at `local_path`, known to the peer as `path`,
+/// then the bytes of each chunk the peer says it lacks.
+async fn send_chunks(
+    send: &mut iroh::endpoint::SendStream,
+    recv: &mut iroh::endpoint::RecvStream,
+    local_path: &Path,
+    path: &str,
+    idle_timeout: Duration,
+    limiter: &RateLimiter,
+    buffers: &BufferBudget,
+) -> Result<()> {
+    // Mapped for the whole exchange, so the chunks sent are the ones listed.
+    let data = match sync_utils::map_file(local_path) {
+        Ok(data) => data,
+        Err(e) => {
+            let err = Message::Error {
+                code: ErrorCode::Internal,
+                message: format!("Failed to read {}: {:#}", path, e),
+            };
+            return Ok(write_message(send, &err).await?);
+        }
+    };
+    let chunks = chunking::chunk_refs(&data);
+    let ranges: HashMap<[u8; 32], Range<usize>> = chunks
+        .iter()
+        .map(|(range, chunk)| (chunk.hash, range.clone()))
+        .collect();
+    let resp = Message::FileChunks {
+        path: path.to_string(),
+        chunks: chunks.into_iter().map(|(_, chunk)| chunk).collect(),
+        hash: *blake3::hash(&data).as_bytes(),
+    };
+    write_message(send, &resp).await?;
+
+    let hashes = match read_message_within(recv, idle_timeout, buffers).await? {
+        Message::ChunkWant { hashes } => hashes,
+        msg => anyhow::bail!("Unexpected message after the chunks of {}: {:?}", path, msg),
+    };
+    for hash in hashes {
+        let Some(range) = ranges.get(&hash) else {
+            let err = Message::Error {
+                code: ErrorCode::InvalidRequest,
+                message: format!("{} has no such chunk", path),
+            };
+            return Ok(write_message(send, &err).await?);
+        };
+        let _buffered = buffers.acquire(range.len()).await;
+        limiter.acquire(range.len()).await;
+        metrics::get().bytes_sent.inc_by(range.len() as u64);
+        let chunk = Message::ChunkData {
+            hash,
+            data: data[range.clone()].to_vec(),
+        };
+        write_message(send, &chunk).await?;
+    }
     Ok(())
 }
 
-async fn write_message<W: AsyncWriteExt + Unpin>(writer: &mut W, msg: &Message) -> Result<()> {
-    let data = postcard::to_stdvec(msg)?;
-    let len = data.len() as u32;
-    writer.write_u32(len).await?;
-    writer.write_all(&data).await?;
-    writer.flush().await?;
+/// A file that was written to while it was read, so what was read may mix
+/// its old and new contents.
+#[derive(Debug, thiserror::Error)]
+#[error("{0} changed while it was read")]
+pub(crate) struct ChangedWhileRead(String);
+
+/// Length and modification time of the file at `path`, which writes change.
+fn file_state(path: &Path) -> Option<(u64, Option<SystemTime>)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.len(), metadata.modified().ok()))
+}
+
+/// The error telling the peer `path` changed while it was read: to ask
+/// again if it understands that, a plain failure otherwise.
+fn changed_error(path: &str, capabilities: &Capabilities) -> Message {
+    let code = if capabilities.contains(CAP_RETRY) {
+        ErrorCode::Retry
+    } else {
+        ErrorCode::Internal
+    };
+    Message::Error {
+        code,
+        message: format!("{} changed while it was read", path),
+    }
+}
+
+/// Stream the file at `local_path`, known to the peer as `path`, as `FileData`
+/// chunks of up to `chunk_size` bytes starting at `offset`, or from the
+/// beginning if `offset` is past its end. The bytes before `offset`
+/// are still read so the last chunk can carry the hash of the whole file.
+/// Fails with [`ChangedWhileRead`] instead of sending the last chunk if the
+/// file was written to meanwhile.
+pub(crate) async fn send_file(
+    send: &mut iroh::endpoint::SendStream,
+    local_path: &Path,
+    path: &str,
+    offset: u64,
+    chunk_size: usize,
+    limiter: &RateLimiter,
+    buffers: &BufferBudget,
+) -> Result<()> {
+    let mut file = tokio::fs::File::open(local_path).await?;
+    let metadata = file.metadata().await?;
+    let (len, modified) = (metadata.len(), metadata.modified().ok());
+    let start = if offset > len {
+        warn!(
+            "Resume offset {} is past the end of {} ({} bytes), sending whole file",
+            offset, path, len
+        );
+        0
+    } else {
+        offset
+    };
+
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0u8; chunk_size];
+    let mut pos = 0u64;
+    while pos < start {
+        let n = (start - pos).min(chunk_size as u64) as usize;
+        file.read_exact(&mut buf[..n]).await?;
+        hasher.update(&buf[..n]);
+        pos += n as u64;
+    }
+
+    loop {
+        let n = (len - pos).min(chunk_size as u64) as usize;
+        // Held until the chunk is sent, so reads wait while others' are
+        // still buffered.
+        let _buffered = buffers.acquire(n).await;
+        file.read_exact(&mut buf[..n]).await?;
+        hasher.update(&buf[..n]);
+        let chunk_offset = pos;
+        pos += n as u64;
+        let is_last = pos == len;
+        if is_last {
+            // The hash only vouches for what was read, so a file written to
+            // meanwhile mustn't get one.
+            let metadata = file.metadata().await?;
+            if metadata.len() != len || metadata.modified().ok() != modified {
+                return Err(ChangedWhileRead(path.to_string()).into());
+            }
+        }
+
+        limiter.acquire(n).await;
+        metrics::get().bytes_sent.inc_by(n as u64);
+        let resp = Message::FileData {
+            path: path.to_string(),
+            data: buf[..n].to_vec(),
+            offset: chunk_offset,
+            is_last,
+            hash: is_last.then(|| *hasher.finalize().as_bytes()),
+        };
+        write_message(send, &resp).await?;
+        if is_last {
+            return Ok(());
+        }
+    }
+}
+
+/// What a `ListRequest` or `ListIfChanged` asks for.
+struct ListingRequest {
+    path: String,
+    with_hashes: bool,
+    follow_symlinks: bool,
+    /// Whether to send a `ListGeneration` ahead of the listing
+    generations: bool,
+    /// Generation of the listing the client already has
+    since: Option<u64>,
+    /// Whether to send a `ListSkipped` when entries had to be left out
+    report_skipped: bool,
+}
+
+/// Answer `request` from the connection's peer. A directory's listing is
+/// kept in the connection's `listings` and served from there until something
+/// in it changes. Up to `hash_concurrency` files are hashed at once.
+async fn send_listing(
+    send: &mut iroh::endpoint::SendStream,
+    request: ListingRequest,
+    ctx: &ConnectionContext,
+) -> Result<()> {
+    let ConnectionContext {
+        remote_id,
+        version: protocol_version,
+        store,
+        listings,
+        ..
+    } = ctx;
+    let (remote_id, protocol_version) = (*remote_id, *protocol_version);
+    let ListingRequest {
+        path,
+        with_hashes,
+        follow_symlinks,
+        generations,
+        since,
+        report_skipped,
+    } = request;
+    info!("Client {} requested listing for: {}", remote_id, path);
+    let Some(root_path) = authorize(store, remote_id, &path, AccessMode::Read)? else {
+        return deny(send, remote_id, &path).await;
+    };
+    let Some(root_path) = listing_root(send, ctx, &path, root_path).await? else {
+        return Ok(());
+    };
+
+    if !root_path.exists() {
+        let err = Message::Error {
+            code: ErrorCode::NotFound,
+            message: format!("Path not found: {}", path),
+        };
+        write_message(send, &err).await?;
+        return Ok(());
+    }
+
+    if root_path.is_file() {
+        if generations {
+            let msg = Message::ListGeneration {
+                generation: None,
+                unchanged: false,
+            };
+            write_message(send, &msg).await?;
+        }
+        // Just return the single file
+        let metadata = std::fs::metadata(&root_path)?;
+        let files = vec![FileMetadata {
+            path: path.clone(),
+            len: metadata.len(),
+            modified: metadata
+                .modified()?
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs(),
+            file_type: FileType::File,
+            link_target: None,
+            hash: if with_hashes {
+                Some(sync_utils::hash_file(&root_path).await?)
+            } else {
+                None
+            },
+            mode: sync_utils::file_mode(&metadata),
+        }];
+        let resp = Message::list_response(files, true, protocol_version);
+        write_message(send, &resp).await?;
+        return Ok(());
+    }
+
+    // Links being followed lead out of the watched tree, and snapshots are
+    // new trees every time, so those listings aren't kept.
+    let kept_listing = !follow_symlinks && ctx.config.snapshot_command.is_none();
+    let key = ListingKey {
+        root: root_path.clone(),
+        path: path.clone(),
+        with_hashes,
+    };
+    if let Some(listing) = listings.get(&key).filter(|_| kept_listing) {
+        info!(
+            "Listing of {} unchanged since generation {}",
+            path, listing.generation
+        );
+        if generations {
+            let unchanged = since == Some(listing.generation);
+            let msg = Message::ListGeneration {
+                generation: Some(listing.generation),
+                unchanged,
+            };
+            write_message(send, &msg).await?;
+            if unchanged {
+                return Ok(());
+            }
+        }
+        let files = &listing.files;
+        let mut start = 0;
+        loop {
+            let end = (start + LIST_CHUNK_LEN).min(files.len());
+            let is_last = end == files.len();
+            let resp =
+                Message::list_response(files[start..end].to_vec(), is_last, protocol_version);
+            write_message(send, &resp).await?;
+            if is_last {
+                return Ok(());
+            }
+            start = end;
+        }
+    }
+
+    let generation = if kept_listing {
+        listings.begin(&root_path)
+    } else {
+        None
+    };
+    if generations {
+        let msg = Message::ListGeneration {
+            generation,
+            unchanged: false,
+        };
+        write_message(send, &msg).await?;
+    }
+
+    // It's a directory, walk it
+    info!("Walking {:?} to list {}", root_path, path);
+    let mut kept = Vec::new();
+    let mut files = Vec::new();
+    let mut hard_links = HardLinks::default();
+    // Entries are sent as their hashes come in, while later files are still
+    // being hashed.
+    let mut hashes = HashQueue::new(ctx.config.hash_concurrency);
+    let mut walk = walk_shared(&root_path, &path, follow_symlinks, store, remote_id).fuse();
+    // Entries that can't be read are left out rather than failing the
+    // whole listing.
+    let mut skipped = 0;
+    loop {
+        if !hashes.should_pop() {
+            if let Some(entry) = walk.next() {
+                let Some((e, file)) = listed(entry, &root_path, &path, &mut hard_links) else {
+                    skipped += 1;
+                    continue;
+                };
+                let hashed =
+                    with_hashes && matches!(file.file_type, FileType::File | FileType::HardLink);
+                hashes.push(file, hashed.then(|| e.path().to_path_buf()));
+                continue;
+            }
+        }
+        let Some((mut file, hash)) = hashes.pop().await else {
+            break;
+        };
+        file.hash = match hash {
+            Ok(hash) => hash,
+            Err(e) => {
+                warn!("Leaving {} out of the listing: {:#}", file.path, e);
+                skipped += 1;
+                continue;
+            }
+        };
+        if generation.is_some() {
+            kept.push(file.clone());
+        }
+        files.push(file);
+        if files.len() == LIST_CHUNK_LEN {
+            let resp = Message::list_response(std::mem::take(&mut files), false, protocol_version);
+            write_message(send, &resp).await?;
+        }
+    }
+    if skipped > 0 {
+        warn!(
+            "Left {} unreadable entries out of the listing of {}",
+            skipped, path
+        );
+        if report_skipped {
+            write_message(send, &Message::ListSkipped { count: skipped }).await?;
+        }
+    }
+    let resp = Message::list_response(files, true, protocol_version);
+    write_message(send, &resp).await?;
+    // A listing with holes is walked again next time, in case they heal.
+    if let Some(generation) = generation.filter(|_| skipped == 0) {
+        listings.insert(key, generation, kept);
+    }
     Ok(())
 }
 
-async fn read_message<R: AsyncReadExt + Unpin>(reader: &mut R) -> Result<Message> {
-    let len = reader.read_u32().await?;
-    let mut buf = vec![0u8; len as usize];
-    reader.read_exact(&mut buf).await?;
-    let msg = postcard::from_bytes(&buf)?;
-    Ok(msg)
+/// Entries of the tree at `root_path`, which the client asked for as `path`,
+/// in the order a listing reports them, or why one couldn't be read.
+fn walk_shared<'a>(
+    root_path: &'a Path,
+    path: &'a str,
+    follow_symlinks: bool,
+    store: &'a Store,
+    remote_id: PublicKey,
+) -> impl Iterator<Item = walkdir::Result<walkdir::DirEntry>> + 'a {
+    // Links being followed must still lead somewhere the peer may read, or
+    // they would leak the rest of the disk.
+    WalkDir::new(root_path)
+        .follow_links(follow_symlinks)
+        .into_iter()
+        .filter_entry(move |e| {
+            // Backups stay with the peer that made them.
+            if e.file_name() == TRASH_DIR {
+                return false;
+            }
+            if !follow_symlinks || !e.path_is_symlink() {
+                return true;
+            }
+            let relative = e.path().strip_prefix(root_path).unwrap_or(e.path());
+            let wire = join_wire_path(path, &to_wire_path(relative));
+            let allowed = matches!(
+                authorize(store, remote_id, &wire, AccessMode::Read),
+                Ok(Some(_))
+            );
+            if !allowed {
+                warn!(
+                    "Not following {:?}, which leads outside the shared paths",
+                    e.path()
+                );
+            }
+            allowed
+        })
+}
+
+/// The walked `entry` with how it is listed, or `None`, logged, if it
+/// couldn't be read.
+fn listed(
+    entry: walkdir::Result<walkdir::DirEntry>,
+    root_path: &Path,
+    path: &str,
+    hard_links: &mut HardLinks,
+) -> Option<(walkdir::DirEntry, FileMetadata)> {
+    let listed = entry.map_err(anyhow::Error::from).and_then(|e| {
+        let file = list_entry(&e, root_path, path, hard_links)?;
+        Ok((e, file))
+    });
+    listed
+        .map_err(|e| warn!("Skipping an entry below {}: {:#}", path, e))
+        .ok()
+}
+
+/// How the walked entry `e` is listed, without a hash.
+fn list_entry(
+    e: &walkdir::DirEntry,
+    root_path: &Path,
+    path: &str,
+    hard_links: &mut HardLinks,
+) -> Result<FileMetadata> {
+    // We walk the canonical path, but entries are reported below the path
+    // the client asked for, which is what it will request them by.
+    let entry_path = e.path();
+    let metadata = e.metadata()?;
+    let relative = entry_path.strip_prefix(root_path).unwrap_or(entry_path);
+    let p_str = join_wire_path(path, &to_wire_path(relative));
+    let (file_type, link_target) = if e.file_type().is_symlink() {
+        let target = std::fs::read_link(entry_path)?;
+        (FileType::Symlink, Some(to_wire_path(&target)))
+    } else if metadata.is_dir() {
+        (FileType::Dir, None)
+    } else if let Some(first) = hard_links.first_name(&metadata, &p_str) {
+        (FileType::HardLink, Some(first))
+    } else {
+        (FileType::File, None)
+    };
+    Ok(FileMetadata {
+        path: p_str,
+        len: metadata.len(),
+        modified: metadata
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs(),
+        file_type,
+        link_target,
+        hash: None,
+        mode: sync_utils::file_mode(&metadata),
+    })
+}
+
+/// What a client whose manifest lists `theirs` at the path of our `file`
+/// must do to match it, or `None` if it already does. Files are only hashed
+/// when the sizes don't already tell them apart, and the hash is kept in
+/// `file`.
+async fn manifest_action(
+    file: &mut FileMetadata,
+    theirs: Option<&FileMetadata>,
+    local: &Path,
+) -> Result<Option<ManifestAction>> {
+    let Some(theirs) = theirs else {
+        return Ok(Some(ManifestAction::Create));
+    };
+    let action = match (file.file_type, theirs.file_type) {
+        (FileType::Dir, FileType::Dir) => None,
+        (FileType::Symlink, FileType::Symlink) if file.link_target == theirs.link_target => None,
+        (FileType::File, FileType::File) => {
+            if file.len == theirs.len {
+                file.hash = Some(sync_utils::hash_file(local).await?);
+            }
+            if file.len == theirs.len && file.hash == theirs.hash {
+                // Only the mode or modification time can differ.
+                (file.modified != theirs.modified || file.mode != theirs.mode)
+                    .then_some(ManifestAction::Delta)
+            } else if theirs.len >= MANIFEST_DELTA_MIN_LEN {
+                Some(ManifestAction::Delta)
+            } else {
+                Some(ManifestAction::Full)
+            }
+        }
+        // Anything else, including a hard link, which is recreated from its
+        // first name at no cost, is made anew.
+        _ => Some(ManifestAction::Create),
+    };
+    Ok(action)
+}
+
+/// Canonical local path for `path` if `peer` may use it with `required`
+/// access. Paths resolving outside every root the peer was granted are
+/// refused before anything else touches the filesystem.
+fn authorize(
+    store: &Store,
+    peer: PublicKey,
+    path: &str,
+    required: AccessMode,
+) -> Result<Option<PathBuf>> {
+    let roots = store.granted_roots(peer)?;
+    let local = match sandbox::resolve_within_roots(path, &roots) {
+        Ok(local) => local,
+        Err(e) => {
+            warn!("Rejected path from {}: {}", peer, e);
+            return Ok(None);
+        }
+    };
+    Ok(store
+        .is_peer_allowed(&local, peer, required)?
+        .then_some(local))
+}
+
+async fn deny(send: &mut iroh::endpoint::SendStream, peer: PublicKey, path: &str) -> Result<()> {
+    warn!("Access denied for peer {} on path {}", peer, path);
+    let err = Message::Error {
+        code: ErrorCode::AccessDenied,
+        message: "Access denied or path not allowed".to_string(),
+    };
+    Ok(write_message(send, &err).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use tokio::sync::mpsc;
+
+    use super::{Control, ServeOptions};
+    use crate::{
+        cli::copy::{self, CopyOptions},
+        protocol::ALPN,
+        test_support::{test_config, Harness},
+    };
+
+    #[tokio::test]
+    async fn reload_applies_a_new_rate_limit_to_open_connections() {
+        let (reload, reloads) = mpsc::channel(1);
+        let opts = ServeOptions {
+            limit_rate: Some(64 * 1024),
+            ..Default::default()
+        };
+        let control = Control {
+            reloads: Some(reloads),
+            ..Default::default()
+        };
+        let h = Harness::start_with(opts, control).await;
+        std::fs::write(h.served.join("big.bin"), vec![7u8; 256 * 1024]).unwrap();
+        let connection = h
+            .client
+            .endpoint()
+            .connect(h.server_id, ALPN)
+            .await
+            .unwrap();
+        let config = test_config();
+        let copy = |local: &str| {
+            copy::run_on(
+                &connection,
+                &config,
+                h.remote("big.bin"),
+                h.local.join(local),
+                CopyOptions::default(),
+            )
+        };
+
+        // The first 64K go out at once, the rest at 64K a second.
+        let started = Instant::now();
+        copy("limited.bin").await.unwrap();
+        assert!(started.elapsed() >= Duration::from_secs(2));
+
+        reload
+            .send((test_config(), ServeOptions::default()))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let started = Instant::now();
+        copy("unlimited.bin").await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(connection.close_reason().is_none());
+        h.stop().await;
+    }
 }
@@ -0,0 +1,52 @@
+use anyhow::Result;
+use iroh::{Endpoint, PublicKey};
+use tracing::info;
+
+use crate::{
+    cli::copy::Session,
+    config::Config,
+    iroh_utils,
+    protocol::{Message, RemoteError, Share, CAP_SHARES},
+    store::Store,
+};
+
+pub async fn run(config: &Config, store: &Store, peer: PublicKey) -> Result<()> {
+    let endpoint = iroh_utils::build_endpoint(config).await?;
+    iroh_utils::add_known_addresses(&endpoint, store.list_addresses()?);
+    let shares = run_with(&endpoint, config, peer).await?;
+
+    if shares.is_empty() {
+        println!("{} shares nothing with you.", peer.fmt_short());
+    }
+    for share in shares {
+        let mode = match (share.read, share.write) {
+            (true, true) => "read-write",
+            (true, false) => "read",
+            _ => "write",
+        };
+        println!("{:<10}  {}", mode, share.path);
+    }
+    Ok(())
+}
+
+/// Ask `peer` which of its paths it has granted us access to.
+pub async fn run_with(endpoint: &Endpoint, config: &Config, peer: PublicKey) -> Result<Vec<Share>> {
+    let connection = iroh_utils::connect(endpoint, config.network(), peer).await?;
+    let mut session = Session::open(&connection, config).await?;
+    if !session.capabilities.contains(CAP_SHARES) {
+        anyhow::bail!(
+            "{} can't list what it shares; it needs a newer version of syncr",
+            peer.fmt_short()
+        );
+    }
+
+    info!("Requesting the paths {} shares with us", peer);
+    session.write(&Message::ListShares).await?;
+    let shares = match session.read().await? {
+        Message::Shares { shares } => shares,
+        Message::Error { code, message } => return Err(RemoteError { code, message }.into()),
+        msg => anyhow::bail!("Unexpected response to ListShares: {:?}", msg),
+    };
+    session.send.finish()?;
+    Ok(shares)
+}
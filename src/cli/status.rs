@@ -0,0 +1,141 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+
+use crate::{
+    cli::allow::format_duration,
+    config::Config,
+    control::{self, ControlRequest, ControlResponse, SyncStatus},
+    schedule,
+    store::{Store, SyncHealth},
+    sync_utils,
+};
+
+/// Sync health picked by `syncr status --health`.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum HealthArg {
+    /// Syncing as expected
+    Ok,
+    /// Changes waiting on a sync that hasn't completed in a while
+    Stale,
+    /// The last attempt failed
+    Failing,
+}
+
+impl From<HealthArg> for SyncHealth {
+    fn from(health: HealthArg) -> Self {
+        match health {
+            HealthArg::Ok => SyncHealth::Ok,
+            HealthArg::Stale => SyncHealth::Stale,
+            HealthArg::Failing => SyncHealth::Failing,
+        }
+    }
+}
+
+/// Print what the running server is doing, or what it would watch if none
+/// is running, followed by the syncs in `health` (all if empty), worst
+/// first if `sort_by_health`.
+pub async fn run_status(
+    config: &Config,
+    health: Vec<HealthArg>,
+    sort_by_health: bool,
+) -> Result<()> {
+    let data_dir = config.data_dir()?;
+    let syncs = match control::request(&data_dir, ControlRequest::Status).await? {
+        Some(ControlResponse::Status(status)) => {
+            println!(
+                "syncr serve is running (pid {}, up {})",
+                status.pid,
+                format_duration(status.uptime_secs)
+            );
+            println!("Peer ID: {}", status.peer_id);
+            println!("Connections: {}", status.connections);
+            println!("Transfer limit: {}", status.limit);
+            println!("Watching {} path(s):", status.watched.len());
+            for path in status.watched {
+                println!("  {}", path.display());
+            }
+            status.syncs
+        }
+        Some(ControlResponse::Error(message)) => anyhow::bail!("Server error: {}", message),
+        Some(response) => anyhow::bail!("Unexpected response from the server: {:?}", response),
+        None => {
+            let store = Store::new(&data_dir)
+                .context("Failed to initialize store")?
+                .tolerant();
+            println!("syncr serve is not running");
+            println!(
+                "{} watch(es) and {} sync(s) take effect once it is",
+                store.list_watches()?.len(),
+                store.list_syncs()?.len()
+            );
+            let limit =
+                schedule::active_limit(&config.schedule, config.limit_rate, schedule::now());
+            println!("Transfer limit: {}", limit);
+            control::sync_statuses(&store, sync_utils::unix_timestamp(), config.sync_stale_secs)?
+        }
+    };
+    print_syncs(select_syncs(syncs, &health, sort_by_health));
+    Ok(())
+}
+
+/// The syncs in one of `health`, or all if it's empty, failing ones first if
+/// `sort_by_health`.
+fn select_syncs(
+    mut syncs: Vec<SyncStatus>,
+    health: &[HealthArg],
+    sort_by_health: bool,
+) -> Vec<SyncStatus> {
+    let wanted: Vec<SyncHealth> = health.iter().map(|&h| h.into()).collect();
+    syncs.retain(|sync| wanted.is_empty() || wanted.contains(&sync.health));
+    if sort_by_health {
+        // Stable, so syncs of the same health stay in path order.
+        syncs.sort_by_key(|sync| std::cmp::Reverse(sync.health));
+    }
+    syncs
+}
+
+fn print_syncs(syncs: Vec<SyncStatus>) {
+    if syncs.is_empty() {
+        return;
+    }
+    let now = sync_utils::unix_timestamp();
+    println!("Syncs:");
+    for sync in syncs {
+        let synced = match sync.last_synced {
+            Some(at) => format!("synced {} ago", format_duration(now.saturating_sub(at))),
+            None => "never synced".to_string(),
+        };
+        println!(
+            "  [{}] {} <- {}:{} ({}, {} pending)",
+            sync.health,
+            sync.local_path.display(),
+            sync.peer,
+            sync.remote_path,
+            synced,
+            sync.pending
+        );
+        if let Some(error) = sync.last_error {
+            println!("      last error: {}", error);
+        }
+    }
+}
+
+/// Have the running server pick up watches changed in the store now.
+pub async fn run_reload(config: &Config) -> Result<()> {
+    match control::request(&config.data_dir()?, ControlRequest::Reload).await? {
+        Some(ControlResponse::Reloaded) => println!("Reloaded watches"),
+        Some(response) => anyhow::bail!("Unexpected response from the server: {:?}", response),
+        None => println!("syncr serve is not running; watches are loaded when it starts"),
+    }
+    Ok(())
+}
+
+/// Have the running server stop the pulls it has under way.
+pub async fn run_cancel(config: &Config) -> Result<()> {
+    match control::request(&config.data_dir()?, ControlRequest::Cancel).await? {
+        Some(ControlResponse::Cancelled) => println!("Cancelled the pulls under way"),
+        Some(response) => anyhow::bail!("Unexpected response from the server: {:?}", response),
+        None => println!("syncr serve is not running"),
+    }
+    Ok(())
+}
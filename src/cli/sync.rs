@@ -1,40 +1,279 @@
 use anyhow::{Context, Result};
-use iroh::{
-    discovery::{dns::DnsDiscovery, mdns::MdnsDiscovery, pkarr::PkarrPublisher},
-    Endpoint, PublicKey,
-};
-use std::path::PathBuf;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tracing::info;
+use clap::ValueEnum;
+use iroh::{endpoint::Connection, Endpoint, PublicKey};
+use std::path::{Component, Path, PathBuf};
+use tracing::{info, warn};
 
 use crate::{
-    cli::copy,
+    cli::{
+        copy,
+        exit::{CommandError, ErrorKind},
+    },
+    config::Config,
     iroh_utils,
-    protocol::{Message, ALPN},
-    store::Store,
+    protocol::{
+        read_message, read_message_or_eof, to_wire_path, write_message, Capabilities, Message,
+        RemoteError, CAP_CHECK_ACCESS,
+    },
+    store::{ConflictPolicy, Store, WatchOptions},
+    sync_utils,
+    watcher::EventMask,
 };
 
+/// Policy picked by `syncr sync --on-conflict`.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ConflictArg {
+    /// Overwrite the local file
+    SourceWins,
+    /// Keep whichever version was modified last
+    NewerWins,
+    /// Keep the local file and leave the incoming one out
+    Skip,
+    /// Keep the local file and write the incoming one to a .conflict file
+    ConflictCopy,
+}
+
+/// Kind of change picked by `syncr sync --events`.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum EventArg {
+    /// A file or directory was created
+    Create,
+    /// A file's contents were written
+    Content,
+    /// Permissions, timestamps or other metadata changed
+    Metadata,
+    /// Something was renamed or moved
+    Rename,
+    /// Something was removed
+    Remove,
+    /// Accesses and changes the platform doesn't classify
+    Other,
+}
+
+impl From<EventArg> for EventMask {
+    fn from(kind: EventArg) -> Self {
+        match kind {
+            EventArg::Create => EventMask::CREATE,
+            EventArg::Content => EventMask::CONTENT,
+            EventArg::Metadata => EventMask::METADATA,
+            EventArg::Rename => EventMask::RENAME,
+            EventArg::Remove => EventMask::REMOVE,
+            EventArg::Other => EventMask::OTHER,
+        }
+    }
+}
+
+/// The kinds of change `kinds` picks, or all of them if it's empty.
+pub fn event_mask(kinds: &[EventArg]) -> EventMask {
+    if kinds.is_empty() {
+        return EventMask::ALL;
+    }
+    kinds
+        .iter()
+        .fold(EventMask::NONE, |mask, &kind| mask.union(kind.into()))
+}
+
+impl From<ConflictArg> for ConflictPolicy {
+    fn from(policy: ConflictArg) -> Self {
+        match policy {
+            ConflictArg::SourceWins => ConflictPolicy::SourceWins,
+            ConflictArg::NewerWins => ConflictPolicy::NewerWins,
+            ConflictArg::Skip => ConflictPolicy::Skip,
+            ConflictArg::ConflictCopy => ConflictPolicy::ConflictCopy,
+        }
+    }
+}
+
 pub async fn run(
+    config: &Config,
     store: Store,
     peer: PublicKey,
     remote_path: String,
     local_path: PathBuf,
-) -> Result<()> {
+    opts: copy::CopyOptions,
+) -> Result<copy::CopyReport> {
+    let endpoint = iroh_utils::build_endpoint(config).await?;
+    iroh_utils::add_known_addresses(&endpoint, store.list_addresses()?);
+    run_with(
+        &endpoint,
+        config,
+        store,
+        peer,
+        remote_path,
+        local_path,
+        opts,
+    )
+    .await
+}
+
+/// Like [`run`], for each `(remote_path, local_path)` of `pairs`, over one
+/// connection.
+pub async fn run_pairs(
+    config: &Config,
+    store: Store,
+    peer: PublicKey,
+    pairs: Vec<(String, PathBuf)>,
+    opts: copy::CopyOptions,
+) -> Result<Vec<copy::PairReport>> {
+    let endpoint = iroh_utils::build_endpoint(config).await?;
+    iroh_utils::add_known_addresses(&endpoint, store.list_addresses()?);
+    run_pairs_with(&endpoint, config, store, peer, pairs, opts).await
+}
+
+/// Like [`run`], but over an existing endpoint. A sync limited to subpaths
+/// with `opts.include` stays limited to them when changes come in later, and
+/// later pulls meeting local changes follow `opts.on_conflict`.
+pub async fn run_with(
+    endpoint: &Endpoint,
+    config: &Config,
+    store: Store,
+    peer: PublicKey,
+    remote_path: String,
+    local_path: PathBuf,
+    opts: copy::CopyOptions,
+) -> Result<copy::CopyReport> {
+    if local_path == Path::new(copy::STDOUT_PATH) {
+        anyhow::bail!("Can't sync to stdout; use `syncr copy` instead");
+    }
+    let connection = iroh_utils::connect(endpoint, config.network(), peer).await?;
+    run_on(
+        endpoint,
+        &connection,
+        config,
+        &store,
+        remote_path,
+        local_path,
+        opts,
+    )
+    .await
+}
+
+/// Sync each `(remote_path, local_path)` of `pairs` from `peer` in turn, as
+/// [`run_with`] does, over a single connection. A pair that fails doesn't
+/// stop the rest.
+pub async fn run_pairs_with(
+    endpoint: &Endpoint,
+    config: &Config,
+    store: Store,
+    peer: PublicKey,
+    pairs: Vec<(String, PathBuf)>,
+    opts: copy::CopyOptions,
+) -> Result<Vec<copy::PairReport>> {
+    if pairs
+        .iter()
+        .any(|(_, local_path)| local_path == Path::new(copy::STDOUT_PATH))
+    {
+        anyhow::bail!("Can't sync to stdout; use `syncr copy` instead");
+    }
+    let connection = iroh_utils::connect(endpoint, config.network(), peer).await?;
+    let mut reports = Vec::new();
+    for (remote_path, local_path) in pairs {
+        let result = run_on(
+            endpoint,
+            &connection,
+            config,
+            &store,
+            remote_path.clone(),
+            local_path.clone(),
+            opts.clone(),
+        )
+        .await;
+        reports.push(copy::PairReport {
+            remote_path,
+            local_path,
+            result,
+        });
+    }
+    Ok(reports)
+}
+
+/// Like [`run_with`], but over a connection `endpoint` already has open to
+/// the peer.
+async fn run_on(
+    endpoint: &Endpoint,
+    connection: &Connection,
+    config: &Config,
+    store: &Store,
+    remote_path: String,
+    local_path: PathBuf,
+    opts: copy::CopyOptions,
+) -> Result<copy::CopyReport> {
+    let peer = connection.remote_id();
+
+    // 0. Make sure the peer lets us sync the path, before copying anything
+    check_access(endpoint.id(), connection, config, &remote_path).await?;
+
     // 1. Perform initial sync (copy)
-    info!("Performing initial sync...");
-    copy::run(peer, remote_path.clone(), local_path.clone()).await?;
+    let dry_run = opts.dry_run;
+    let watch_only = opts.watch_only;
+    let include = opts.include.clone();
+    let on_conflict = opts.on_conflict;
+    let include_hidden = opts.include_hidden;
+    let events = opts.watch_events;
+    let max_size = opts.max_size;
+    let watch = WatchOptions {
+        recursive: opts.watch_recursive,
+        poll_interval_secs: opts.watch_poll_interval.map(|interval| interval.as_secs()),
+        ..Default::default()
+    };
+    let report = if watch_only {
+        if !local_path.exists() {
+            anyhow::bail!(
+                "{:?} doesn't exist; --watch-only needs a local copy already in place",
+                local_path
+            );
+        }
+        info!("Skipping the initial sync; the local path is taken as up to date");
+        copy::CopyReport::default()
+    } else {
+        info!("Performing initial sync...");
+        copy::run_on(
+            connection,
+            config,
+            remote_path.clone(),
+            local_path.clone(),
+            opts,
+        )
+        .await?
+    };
+    if dry_run {
+        // Nothing was written, so there is nothing to persist or watch yet.
+        return Ok(report);
+    }
 
-    // 2. Persist sync config locally
+    // 2. Persist sync config and watch the file/directory locally
     info!("Saving sync configuration...");
     let abs_local_path = std::fs::canonicalize(&local_path)?;
-    store.add_sync(peer, remote_path.clone(), abs_local_path.clone())?;
-
-    // 3. Add watch for this file/directory locally
-    store.add_watch(&abs_local_path)?;
+    // Allowed, but each is pulled into separately when the peer reports a
+    // change, one after another where their local paths overlap.
+    for (root, other) in store.overlapping_syncs(&abs_local_path, peer, &remote_path)? {
+        warn!(
+            "{:?} overlaps the sync of {} from {} into {:?}; changes are pulled into each in turn",
+            abs_local_path, other.remote_path, other.peer, root
+        );
+    }
+    store.add_sync_with_watch(peer, remote_path.clone(), abs_local_path.clone())?;
+    store.add_watch_with(&abs_local_path, watch)?;
+    store.set_sync_include(&abs_local_path, peer, &remote_path, include)?;
+    store.set_sync_on_conflict(&abs_local_path, peer, &remote_path, on_conflict)?;
+    store.set_sync_include_hidden(&abs_local_path, peer, &remote_path, include_hidden)?;
+    store.set_sync_events(&abs_local_path, peer, &remote_path, events)?;
+    store.set_sync_max_size(&abs_local_path, peer, &remote_path, max_size)?;
+    // Without a copy there is nothing to say the paths matched at any time.
+    if !watch_only {
+        store.record_sync_result(
+            &abs_local_path,
+            peer,
+            &remote_path,
+            report.hash,
+            sync_utils::unix_timestamp(),
+        )?;
+        store.log_sync(&abs_local_path, report.log_entry(peer, &remote_path))?;
+    }
 
-    // 4. Register sync on remote peer (Reverse Sync)
+    // 3. Register sync on remote peer (Reverse Sync)
     info!("Registering reverse sync on remote peer...");
-    register_reverse_sync(peer, remote_path).await?;
+    register_reverse_sync(connection, config, remote_path).await?;
 
     info!(
         "Sync established! Watching for changes at {:?}",
@@ -42,60 +281,114 @@ pub async fn run(
     );
     info!("Note: You must keep 'syncr serve' running to sync changes.");
 
-    Ok(())
+    Ok(report)
+}
+
+/// Ask the peer whether we, `our_id`, may sync `remote_path`, failing with
+/// how to get access if not. Peers without the `check-access` capability
+/// aren't asked; registering the reverse sync fails instead.
+async fn check_access(
+    our_id: PublicKey,
+    connection: &Connection,
+    config: &Config,
+    remote_path: &str,
+) -> Result<()> {
+    let version = iroh_utils::protocol_version(connection);
+    if version < 8 {
+        // Capabilities beyond those of the version arrived with version 8.
+        return Ok(());
+    }
+    let (mut send, mut recv) = connection.open_bi().await?;
+    let handshake = Message::handshake(version, &Capabilities::ours());
+    write_message(&mut send, &handshake).await?;
+    let capabilities = match read_message(&mut recv, config.handshake_timeout())
+        .await
+        .context("Server sent no handshake")?
+        .into_current()
+    {
+        Message::Handshake { capabilities, .. } => {
+            Capabilities::ours().intersection(&Capabilities::from_names(capabilities))
+        }
+        msg => anyhow::bail!("Expected handshake, got {:?}", msg),
+    };
+    if !capabilities.contains(CAP_CHECK_ACCESS) {
+        send.finish()?;
+        return Ok(());
+    }
+
+    let msg = Message::CheckAccess {
+        path: remote_path.to_string(),
+    };
+    write_message(&mut send, &msg).await?;
+    send.finish()?;
+    match read_message(&mut recv, config.idle_timeout()).await? {
+        Message::AccessResult { allowed: true, .. } => Ok(()),
+        Message::AccessResult { reason, .. } => Err(CommandError::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "{} has not allowed you to sync {}{}; ask them to run `syncr allow {} {}`",
+                connection.remote_id().fmt_short(),
+                remote_path,
+                reason.map(|r| format!(" ({})", r)).unwrap_or_default(),
+                our_id,
+                remote_path
+            ),
+        )
+        .into()),
+        Message::Error { code, message } => Err(RemoteError { code, message }.into()),
+        msg => anyhow::bail!("Unexpected response to CheckAccess: {:?}", msg),
+    }
 }
 
-async fn register_reverse_sync(peer: PublicKey, remote_path: String) -> Result<()> {
-    let secret_key = iroh_utils::load_secret_key().await?;
-    let endpoint = Endpoint::builder()
-        .discovery(PkarrPublisher::n0_dns())
-        .discovery(DnsDiscovery::n0_dns())
-        .discovery(MdnsDiscovery::builder())
-        .secret_key(secret_key)
-        .alpns(vec![ALPN.to_vec()])
-        .bind()
-        .await?;
-
-    let connection = endpoint.connect(peer, ALPN).await?;
+async fn register_reverse_sync(
+    connection: &Connection,
+    config: &Config,
+    remote_path: String,
+) -> Result<()> {
     let (mut send, mut recv) = connection.open_bi().await?;
 
-    // Handshake
-    let msg = read_message(&mut recv).await?;
+    // Handshake. The server only sees the stream once we write to it, so we
+    // have to speak first.
+    let handshake = Message::handshake(
+        iroh_utils::protocol_version(connection),
+        &Capabilities::ours(),
+    );
+    write_message(&mut send, &handshake).await?;
+    let msg = read_message(&mut recv, config.handshake_timeout())
+        .await
+        .context("Server sent no handshake")?;
     match msg {
-        Message::Handshake { .. } => {}
+        Message::Handshake { .. } | Message::LegacyHandshake { .. } => {}
         _ => anyhow::bail!("Expected handshake, got {:?}", msg),
     }
-    let handshake = Message::Handshake { version: 1 };
-    write_message(&mut send, &handshake).await?;
 
     // Send StartSync
     let msg = Message::StartSync { path: remote_path };
     write_message(&mut send, &msg).await?;
-
-    // Wait for acknowledgement?
-    // Protocol doesn't have explicit Ack for this yet, but we can assume success if no error is sent back immediately.
-    // Ideally we'd add `SyncStarted` response. For now, we just close.
-    // Let's verify no error comes back.
-
-    // Short timeout read? Or just finish.
     send.finish()?;
 
-    Ok(())
-}
-
-async fn write_message<W: AsyncWriteExt + Unpin>(writer: &mut W, msg: &Message) -> Result<()> {
-    let data = postcard::to_stdvec(msg)?;
-    let len = data.len() as u32;
-    writer.write_u32(len).await?;
-    writer.write_all(&data).await?;
-    writer.flush().await?;
-    Ok(())
+    // There is no explicit acknowledgement; the server finishes the stream
+    // once it has handled our request, or sends an error first.
+    match read_message_or_eof(&mut recv, config.idle_timeout())
+        .await
+        .context("Failed to register sync on remote peer")?
+    {
+        None => Ok(()),
+        Some(Message::Error { code, message }) => Err(RemoteError { code, message }.into()),
+        Some(msg) => anyhow::bail!("Unexpected response to StartSync: {:?}", msg),
+    }
 }
 
-async fn read_message<R: AsyncReadExt + Unpin>(reader: &mut R) -> Result<Message> {
-    let len = reader.read_u32().await?;
-    let mut buf = vec![0u8; len as usize];
-    reader.read_exact(&mut buf).await?;
-    let msg = postcard::from_bytes(&buf)?;
-    Ok(msg)
+/// Parse a `--only` subpath into wire form. It must stay inside the synced
+/// path, so absolute paths and `..` are refused.
+pub fn parse_subpath(s: &str) -> Result<String, String> {
+    let path = Path::new(s);
+    if path.has_root() || path.components().any(|c| c == Component::ParentDir) {
+        return Err(format!("'{}' must be a path inside the synced path", s));
+    }
+    let wire = to_wire_path(path);
+    if wire.is_empty() {
+        return Err("subpath must not be empty".to_string());
+    }
+    Ok(wire)
 }
@@ -0,0 +1,168 @@
+use anyhow::{Context, Result};
+use iroh::{Endpoint, PublicKey};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use tracing::{info, warn};
+use walkdir::WalkDir;
+
+use crate::{
+    cli::{
+        copy::{self, Session},
+        exit::{CommandError, ErrorKind},
+    },
+    config::Config,
+    iroh_utils,
+    protocol::{trim_wire_path, FileType, Message, RemoteError},
+    store::Store,
+    sync_utils,
+    trash::TRASH_DIR,
+};
+
+/// How a local file compares with its remote counterpart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStatus {
+    /// Same contents on both sides
+    InSync,
+    /// Present on both sides with different contents
+    Differs,
+    /// Only present on the remote
+    MissingLocally,
+    /// Only present locally
+    LocalOnly,
+}
+
+impl fmt::Display for FileStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            FileStatus::InSync => "in sync",
+            FileStatus::Differs => "differs",
+            FileStatus::MissingLocally => "missing",
+            FileStatus::LocalOnly => "local only",
+        })
+    }
+}
+
+/// Result of comparing a local path with a remote one.
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    /// Status of every file found on either side, keyed by local path
+    pub files: BTreeMap<PathBuf, FileStatus>,
+}
+
+impl VerifyReport {
+    /// Files that are not in sync.
+    pub fn mismatches(&self) -> impl Iterator<Item = (&PathBuf, FileStatus)> {
+        self.files
+            .iter()
+            .map(|(path, status)| (path, *status))
+            .filter(|(_, status)| *status != FileStatus::InSync)
+    }
+
+    pub fn is_in_sync(&self) -> bool {
+        self.mismatches().next().is_none()
+    }
+}
+
+pub async fn run(
+    config: &Config,
+    store: &Store,
+    peer: PublicKey,
+    remote_path: String,
+    local_path: PathBuf,
+) -> Result<()> {
+    let endpoint = iroh_utils::build_endpoint(config).await?;
+    iroh_utils::add_known_addresses(&endpoint, store.list_addresses()?);
+    let report = run_with(&endpoint, config, peer, remote_path, local_path).await?;
+
+    for (path, status) in &report.files {
+        println!("{:<10}  {}", status, path.display());
+    }
+    let mismatched = report.mismatches().count();
+    if mismatched > 0 {
+        let message = format!(
+            "{} of {} files are out of sync",
+            mismatched,
+            report.files.len()
+        );
+        return Err(CommandError::new(ErrorKind::Conflict, message).into());
+    }
+    Ok(())
+}
+
+/// Compare `local_path` with `remote_path` on `peer` by hash, transferring
+/// only the listing.
+pub async fn run_with(
+    endpoint: &Endpoint,
+    config: &Config,
+    peer: PublicKey,
+    remote_path: String,
+    local_path: PathBuf,
+) -> Result<VerifyReport> {
+    let remote_path = trim_wire_path(&remote_path).to_string();
+    let connection = iroh_utils::connect(endpoint, config.network(), peer).await?;
+    let mut session = Session::open(&connection, config).await?;
+
+    info!("Requesting hashed listing for {}", remote_path);
+    let list_req = Message::ListRequest {
+        path: remote_path.clone(),
+        with_hashes: true,
+        follow_symlinks: false,
+    };
+    session.write(&list_req).await?;
+
+    let mut report = VerifyReport::default();
+    loop {
+        let msg = session.read().await?.into_current();
+        let (files, is_last) = match msg {
+            Message::ListResponse { files, is_last } => (files, is_last),
+            Message::ListSkipped { count } => {
+                warn!(
+                    "{} entries of {} could not be listed by the peer and are not compared",
+                    count, remote_path
+                );
+                continue;
+            }
+            Message::Error { code, message } => return Err(RemoteError { code, message }.into()),
+            _ => anyhow::bail!("Unexpected message: {:?}", msg),
+        };
+        for file in files
+            .into_iter()
+            .filter(|f| matches!(f.file_type, FileType::File | FileType::HardLink))
+        {
+            let remote_hash = file.hash.context("Server did not send file hashes")?;
+            let Some(target) = copy::local_target(&remote_path, &file.path, &local_path) else {
+                warn!("Skipping {}, which isn't inside {}", file.path, remote_path);
+                continue;
+            };
+            let status = if !target.exists() {
+                FileStatus::MissingLocally
+            } else if target.is_file() && sync_utils::hash_file(&target).await? == remote_hash {
+                FileStatus::InSync
+            } else {
+                FileStatus::Differs
+            };
+            report.files.insert(target, status);
+        }
+        if is_last {
+            break;
+        }
+    }
+    session.send.finish()?;
+
+    // Whatever the listing didn't cover only exists on our side.
+    if local_path.exists() {
+        let entries = WalkDir::new(&local_path)
+            .into_iter()
+            .filter_entry(|e| e.file_name() != TRASH_DIR);
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type().is_file() && !report.files.contains_key(entry.path()) {
+                report
+                    .files
+                    .insert(entry.path().to_path_buf(), FileStatus::LocalOnly);
+            }
+        }
+    }
+    Ok(report)
+}
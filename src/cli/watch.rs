@@ -1,29 +1,91 @@
-use crate::store::Store;
+use crate::cli::allow::format_duration;
+use crate::sandbox;
+use crate::store::{Store, WatchOptions};
 use anyhow::{Context, Result};
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 
-pub fn run(store: &Store, path: Option<PathBuf>, delete: bool) -> Result<()> {
-    if let Some(p) = path {
-        let abs_path = std::fs::canonicalize(&p).context("Failed to resolve path")?;
-        if delete {
-            if store.remove_watch(&abs_path)? {
-                println!("Removed watch: {:?}", abs_path);
-            } else {
-                println!("Path was not being watched: {:?}", abs_path);
+/// Add a watch on each of `paths` as `options` say or, with `delete`, remove
+/// it, or list the watches if there are none. A path that fails doesn't stop
+/// the rest, but makes the whole run fail once they're done.
+pub fn run(store: &Store, paths: Vec<PathBuf>, delete: bool, options: WatchOptions) -> Result<()> {
+    if paths.is_empty() {
+        let watches = store.list_watches_with_cover()?;
+        if watches.is_empty() {
+            println!("No paths are being watched.");
+        } else {
+            for (w, options, cover) in watches {
+                let covered = cover
+                    .map(|c| format!(" [covered by {}]", c.display()))
+                    .unwrap_or_default();
+                println!("{}{}{}", w.display(), describe(options), covered);
             }
+        }
+        return Ok(());
+    }
+
+    let mut failed = 0;
+    for path in &paths {
+        if let Err(e) = update_watch(store, path, delete, options) {
+            eprintln!("{:?}: {:#}", path, e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("Failed to update {} of {} watches", failed, paths.len());
+    }
+    Ok(())
+}
+
+/// How a watch differs from the default, e.g. ` (top level only)`.
+fn describe(options: WatchOptions) -> String {
+    let mut notes = Vec::new();
+    if !options.recursive {
+        notes.push("top level only".to_string());
+    }
+    match (options.poll_interval_secs, options.notify) {
+        (Some(secs), true) => notes.push(format!("also polled every {}", format_duration(secs))),
+        (Some(secs), false) => notes.push(format!("polled every {}", format_duration(secs))),
+        (None, _) => {}
+    }
+    if notes.is_empty() {
+        String::new()
+    } else {
+        format!(" ({})", notes.join(", "))
+    }
+}
+
+fn update_watch(store: &Store, path: &Path, delete: bool, options: WatchOptions) -> Result<()> {
+    let abs_path = resolve(path)?;
+    if delete {
+        if store.remove_watch(&abs_path)? {
+            println!("Removed watch: {:?}", abs_path);
         } else {
-            store.add_watch(&abs_path)?;
-            println!("Added watch: {:?}", abs_path);
+            println!("Path was not being watched: {:?}", abs_path);
         }
     } else {
-        let watches = store.list_watches()?;
-        if watches.is_empty() {
-            println!("No paths are being watched.");
+        let cover = store.add_watch_with(&abs_path, options)?;
+        if let Some(cover) = cover {
+            println!(
+                "Added watch: {:?}, already covered by the watch on {:?}",
+                abs_path, cover
+            );
+        } else if !abs_path.exists() {
+            println!("Added watch: {:?}, to start once it exists", abs_path);
         } else {
-            for w in watches {
-                println!("{}", w.display());
-            }
+            println!("Added watch: {:?}{}", abs_path, describe(options));
         }
     }
     Ok(())
 }
+
+/// `path` made absolute with the part of it that exists canonicalized, so
+/// a path can be watched before it's created.
+fn resolve(path: &Path) -> Result<PathBuf> {
+    let absolute = std::path::absolute(path).context("Failed to resolve path")?;
+    let resolved =
+        sandbox::canonicalize_existing_prefix(&absolute).context("Failed to resolve path")?;
+    if resolved.components().any(|c| c == Component::ParentDir) {
+        anyhow::bail!("Can't resolve `..` below a directory that doesn't exist");
+    }
+    Ok(resolved)
+}
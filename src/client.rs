@@ -0,0 +1,258 @@
+use anyhow::Result;
+use iroh::{Endpoint, EndpointAddr, PublicKey};
+use std::path::{Path, PathBuf};
+
+use crate::{
+    cli::{
+        copy::{self, CopyOptions, CopyReport, PairReport, SyncStats},
+        ls,
+        push::{self, PushReport},
+        resync::{self, ResyncReport},
+        shares, sync,
+        verify::{self, VerifyReport},
+    },
+    config::Config,
+    iroh_utils::{self, Route},
+    protocol::{FileMetadata, Share},
+    store::{ConflictPolicy, Store},
+};
+
+/// How [`pull`] copies.
+#[derive(Debug, Clone)]
+pub struct PullOptions {
+    /// Report what would change without touching the local filesystem
+    pub dry_run: bool,
+    /// Compute every local signature afresh instead of using cached ones
+    pub checksum: bool,
+    /// Most files transferred at once
+    pub jobs: usize,
+    /// What to do with a local file changed since it was last pulled
+    pub on_conflict: ConflictPolicy,
+}
+
+impl Default for PullOptions {
+    fn default() -> Self {
+        Self {
+            dry_run: false,
+            checksum: false,
+            jobs: copy::DEFAULT_JOBS,
+            on_conflict: ConflictPolicy::default(),
+        }
+    }
+}
+
+impl From<PullOptions> for CopyOptions {
+    fn from(opts: PullOptions) -> Self {
+        CopyOptions {
+            dry_run: opts.dry_run,
+            checksum: opts.checksum,
+            jobs: opts.jobs,
+            on_conflict: opts.on_conflict,
+            ..Default::default()
+        }
+    }
+}
+
+/// Copy `remote` on `peer` to `local` once over `endpoint`, with the default
+/// [`Config`], and return what was transferred. For anything beyond
+/// [`PullOptions`], use a [`SyncClient`] with [`CopyOptions`].
+pub async fn pull(
+    endpoint: &Endpoint,
+    peer: PublicKey,
+    remote: &str,
+    local: &Path,
+    opts: PullOptions,
+) -> Result<SyncStats> {
+    let report = copy::run_with(
+        endpoint,
+        &Config::default(),
+        peer,
+        remote.to_string(),
+        local.to_path_buf(),
+        opts.into(),
+    )
+    .await?;
+    Ok(report.stats)
+}
+
+/// Pulls files from, and pushes them to, peers running a [`SyncServer`](crate::SyncServer).
+#[derive(Debug, Clone)]
+pub struct SyncClient {
+    endpoint: Endpoint,
+    config: Config,
+}
+
+impl SyncClient {
+    /// Bind an endpoint for our identity, configured from `config`.
+    pub async fn bind(config: Config) -> Result<Self> {
+        let endpoint = iroh_utils::build_endpoint(&config).await?;
+        Ok(Self::from_endpoint(endpoint, config))
+    }
+
+    /// Use an endpoint set up by the caller. It must accept the syncr ALPNs
+    /// for the network in `config` (see
+    /// [`advertised_alpns`](crate::advertised_alpns)) and be able
+    /// to reach the peers it is asked to copy from.
+    pub fn from_endpoint(endpoint: Endpoint, config: Config) -> Self {
+        Self { endpoint, config }
+    }
+
+    /// Peer ID servers see this client as, e.g. for `syncr allow`.
+    pub fn id(&self) -> PublicKey {
+        self.endpoint.id()
+    }
+
+    /// The endpoint this client dials from.
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    /// Addresses peers can currently reach this client at.
+    pub fn addr(&self) -> EndpointAddr {
+        self.endpoint.addr()
+    }
+
+    /// How this client reaches `peer` right now, e.g. to tell whether
+    /// transfers go over the local network or through a relay.
+    pub fn route(&self, peer: PublicKey) -> Route {
+        iroh_utils::route(&self.endpoint, peer)
+    }
+
+    /// Copy `remote_path` on `peer` to `local_path` once.
+    pub async fn copy(
+        &self,
+        peer: PublicKey,
+        remote_path: impl Into<String>,
+        local_path: impl Into<PathBuf>,
+        opts: CopyOptions,
+    ) -> Result<CopyReport> {
+        copy::run_with(
+            &self.endpoint,
+            &self.config,
+            peer,
+            remote_path.into(),
+            local_path.into(),
+            opts,
+        )
+        .await
+    }
+
+    /// Copy each `(remote_path, local_path)` of `pairs` from `peer`, one
+    /// after another over a single connection. A pair that fails doesn't
+    /// stop the rest.
+    pub async fn copy_pairs(
+        &self,
+        peer: PublicKey,
+        pairs: Vec<(String, PathBuf)>,
+        opts: CopyOptions,
+    ) -> Result<Vec<PairReport>> {
+        copy::run_pairs_with(&self.endpoint, &self.config, peer, pairs, opts).await
+    }
+
+    /// Copy `remote_path` on `peer` to `local_path`, then record the sync in
+    /// `store` and ask the peer to notify us of further changes.
+    pub async fn sync(
+        &self,
+        store: &Store,
+        peer: PublicKey,
+        remote_path: impl Into<String>,
+        local_path: impl Into<PathBuf>,
+        opts: CopyOptions,
+    ) -> Result<CopyReport> {
+        sync::run_with(
+            &self.endpoint,
+            &self.config,
+            store.clone(),
+            peer,
+            remote_path.into(),
+            local_path.into(),
+            opts,
+        )
+        .await
+    }
+
+    /// Sync each `(remote_path, local_path)` of `pairs` from `peer` as
+    /// [`sync`](Self::sync) does, one after another over a single
+    /// connection. A pair that fails doesn't stop the rest.
+    pub async fn sync_pairs(
+        &self,
+        store: &Store,
+        peer: PublicKey,
+        pairs: Vec<(String, PathBuf)>,
+        opts: CopyOptions,
+    ) -> Result<Vec<PairReport>> {
+        sync::run_pairs_with(
+            &self.endpoint,
+            &self.config,
+            store.clone(),
+            peer,
+            pairs,
+            opts,
+        )
+        .await
+    }
+
+    /// Write `local_path` to `remote_path` on `peer`, which must grant us
+    /// write access there.
+    pub async fn push(
+        &self,
+        peer: PublicKey,
+        local_path: impl Into<PathBuf>,
+        remote_path: impl Into<String>,
+        limit_rate: Option<u64>,
+    ) -> Result<PushReport> {
+        push::run_with(
+            &self.endpoint,
+            &self.config,
+            peer,
+            local_path.into(),
+            remote_path.into(),
+            limit_rate,
+        )
+        .await
+    }
+
+    /// Copy every sync recorded in `store` for `local_path`, or for all local
+    /// paths if `None`, in full from its peer, overwriting local changes.
+    pub async fn resync(
+        &self,
+        store: &Store,
+        local_path: Option<PathBuf>,
+        opts: CopyOptions,
+    ) -> Result<Vec<ResyncReport>> {
+        resync::run_with(&self.endpoint, &self.config, store, local_path, opts).await
+    }
+
+    /// Compare `local_path` with `remote_path` on `peer` by hash, without
+    /// transferring any file contents.
+    pub async fn verify(
+        &self,
+        peer: PublicKey,
+        remote_path: impl Into<String>,
+        local_path: impl Into<PathBuf>,
+    ) -> Result<VerifyReport> {
+        verify::run_with(
+            &self.endpoint,
+            &self.config,
+            peer,
+            remote_path.into(),
+            local_path.into(),
+        )
+        .await
+    }
+
+    /// The paths `peer` has granted us access to.
+    pub async fn shares(&self, peer: PublicKey) -> Result<Vec<Share>> {
+        shares::run_with(&self.endpoint, &self.config, peer).await
+    }
+
+    /// What `remote_path` on `peer` lists, without transferring any file
+    /// contents.
+    pub async fn list(
+        &self,
+        peer: PublicKey,
+        remote_path: impl Into<String>,
+    ) -> Result<Vec<FileMetadata>> {
+        ls::run_with(&self.endpoint, &self.config, peer, remote_path.into()).await
+    }
+}
@@ -0,0 +1,713 @@
+use iroh::{RelayMap, RelayMode, RelayUrl};
+use serde::{Deserialize, Deserializer};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::{
+    buffer_budget,
+    protocol::{FILE_CHUNK_LEN, MAX_CHUNK_LEN},
+    rate_limit,
+    schedule::ScheduleWindow,
+    sync_utils,
+    trash::Retention,
+};
+
+/// Environment variable pointing at an alternative config file.
+pub const CONFIG_ENV: &str = "SYNCR_CONFIG";
+
+/// Environment variable overriding the syncr home directory, which holds the
+/// config file, the secret key and (by default) the database.
+pub const HOME_ENV: &str = "SYNCR_HOME";
+
+/// Environment variable naming the profile to use, as `--profile` does.
+pub const PROFILE_ENV: &str = "SYNCR_PROFILE";
+
+/// Directory in the syncr home holding one home per profile.
+pub const PROFILES_DIR: &str = "profiles";
+
+/// Seconds to wait for a peer's next message when the config doesn't say.
+pub const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 60;
+
+/// Seconds to wait for a peer's handshake when the config doesn't say.
+pub const DEFAULT_HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+
+/// Seconds between heartbeats on long-lived connections by default.
+pub const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 15;
+
+/// Unanswered heartbeats in a row after which a peer is considered gone.
+pub const DEFAULT_HEARTBEAT_MAX_MISSED: u32 = 3;
+
+/// Seconds a pooled connection to a peer may go unused before it is closed,
+/// by default.
+pub const DEFAULT_POOL_IDLE_SECS: u64 = 5 * 60;
+
+/// Milliseconds changes are gathered for before a peer is notified of them
+/// together, by default.
+pub const DEFAULT_NOTIFY_BATCH_MS: u64 = 250;
+
+/// Seconds between checks of watched paths against the store by default.
+pub const DEFAULT_WATCH_RECONCILE_SECS: u64 = 30;
+
+/// Chunks a peer may have in flight to us on one stream by default.
+pub const DEFAULT_WINDOW: u32 = 8;
+
+/// Connections the server keeps open at once by default.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 256;
+
+/// Connections one peer may keep open to the server at once by default.
+pub const DEFAULT_MAX_CONNECTIONS_PER_PEER: usize = 8;
+
+/// Entries a directory listing needs before the server keeps it by default.
+pub const DEFAULT_LISTING_CACHE_MIN_ENTRIES: usize = 10_000;
+
+/// Files hashed at once for a listing or manifest by default.
+pub const DEFAULT_HASH_CONCURRENCY: usize = 4;
+
+/// Pulls of notified changes the server runs at once by default.
+pub const DEFAULT_MAX_CONCURRENT_PULLS: usize = 4;
+
+/// Seconds a sync with changes waiting may go without completing before
+/// `syncr status` calls it stale, by default.
+pub const DEFAULT_SYNC_STALE_SECS: u64 = 60 * 60;
+
+/// Share of the local files a sync with `--delete` may remove without
+/// `--force`, by default.
+pub const DEFAULT_MAX_DELETE: f64 = 0.5;
+
+/// Smallest remote file patched with a delta rather than downloaded whole,
+/// by default.
+pub const DEFAULT_DELTA_MIN_LEN: u64 = 4 * 1024;
+
+/// Share of a file a delta must save for the server to send it rather than
+/// have the file downloaded whole, by default.
+pub const DEFAULT_DELTA_MIN_SAVINGS: f64 = 0.1;
+
+/// Longest network name, which keeps every ALPN well below its 255-byte
+/// limit.
+pub const MAX_NETWORK_LEN: usize = 64;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Failed to read config file {0}: {1}")]
+    ReadError(PathBuf, std::io::Error),
+    #[error("Failed to parse config file {0}: {1}")]
+    ParseError(PathBuf, toml::de::Error),
+    #[error(
+        "Could not find a config directory; set SYNCR_HOME or XDG_CONFIG_HOME, or pass --home"
+    )]
+    NoConfigDir,
+}
+
+pub type Result<T> = std::result::Result<T, ConfigError>;
+
+/// Settings read from `config.toml` in the syncr config directory.
+///
+/// Every field is optional in the file; anything left out keeps its default.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub discovery: DiscoveryConfig,
+    pub relay: RelayConfig,
+    /// Local address endpoints bind to instead of every interface, e.g.
+    /// `"192.168.1.5:0"`
+    pub bind: Option<SocketAddr>,
+    /// Name of an isolated network, e.g. `"staging"`, appended to the
+    /// protocol's ALPNs. Nodes only connect to nodes on the same network, or
+    /// without one to nodes without one, even when they find each other on
+    /// the local network.
+    #[serde(deserialize_with = "deserialize_network")]
+    pub network: Option<String>,
+    /// Bandwidth cap in bytes per second used when `--limit-rate` isn't given.
+    /// Accepts a number or a string with a suffix, e.g. `"1M"`.
+    #[serde(deserialize_with = "deserialize_rate")]
+    pub limit_rate: Option<u64>,
+    /// Times of day the running server transfers under another limit, or not
+    /// at all, each a `[[schedule]]` table such as `start = "09:00"`,
+    /// `end = "18:00"`, `limit = "256K"` (or `"paused"`). The first window
+    /// holding the local time applies; outside them all `limit_rate` does.
+    pub schedule: Vec<ScheduleWindow>,
+    /// Directory holding the database. Defaults to the syncr config directory.
+    pub data_dir: Option<PathBuf>,
+    /// Seconds to wait for a peer to send its next message before dropping
+    /// the stream.
+    pub idle_timeout_secs: u64,
+    /// Seconds to wait for the handshake a peer sends first on each stream,
+    /// so one that connects but never speaks is given up on early.
+    pub handshake_timeout_secs: u64,
+    /// rsync block size in bytes for delta transfers. When unset it is picked
+    /// from each file's size.
+    pub block_size: Option<u32>,
+    /// Bytes of each block's strong hash kept in rsync signatures, from 8 to
+    /// 16. Longer hashes make signatures bigger but wrong matches rarer.
+    pub strong_hash_size: u32,
+    /// Seconds between heartbeats on connections kept open to peers. Should
+    /// stay below the peer's `idle_timeout_secs`.
+    pub heartbeat_interval_secs: u64,
+    /// Unanswered heartbeats in a row after which a peer is considered gone
+    pub heartbeat_max_missed: u32,
+    /// Seconds a connection kept open to notify a peer may go without
+    /// sending a notification before it is closed; the next one dials
+    /// again. Heartbeats don't count. 0 keeps connections open.
+    pub pool_idle_secs: u64,
+    /// Milliseconds changes for one peer are gathered for, from the first,
+    /// before they are sent in one notification, so a bulk change such as
+    /// a checkout is pulled as a set. 0 notifies of each change right away.
+    pub notify_batch_ms: u64,
+    /// Connections the server keeps open at once; more are refused
+    pub max_connections: usize,
+    /// Connections one peer may keep open to the server at once
+    pub max_connections_per_peer: usize,
+    /// Pulls the server runs at once for changes peers notified it of,
+    /// across every connection; the rest wait their turn
+    pub max_concurrent_pulls: usize,
+    /// Bytes of a file sent per `FileData` message. Accepts a number or a
+    /// string with a suffix, e.g. `"1M"`, up to 16M.
+    #[serde(deserialize_with = "deserialize_chunk_size")]
+    pub chunk_size: usize,
+    /// Chunks a peer may send us on one stream before we have taken them in.
+    /// Larger windows keep high-latency links busy at the cost of memory.
+    #[serde(deserialize_with = "deserialize_window")]
+    pub window: u32,
+    /// Seconds between checks that the watched paths match the store, which
+    /// also picks up paths that appeared or disappeared since
+    pub watch_reconcile_secs: u64,
+    /// Entries a directory listing needs before the server keeps it, to
+    /// answer later requests without walking the tree again until something
+    /// in it changes
+    pub listing_cache_min_entries: usize,
+    /// Files hashed at once when listing or building a manifest. Set it to 1
+    /// on spinning disks, where reading several files at once is slower.
+    pub hash_concurrency: usize,
+    /// Seconds a sync with changes waiting for its peer may go without
+    /// completing before `syncr status` reports it as stale
+    pub sync_stale_secs: u64,
+    /// Flush each synced file and its directory to disk before reporting it
+    /// done, so a crash right after can't leave it empty or cut short.
+    /// Slower, particularly with many small files.
+    pub fsync: bool,
+    /// Largest share of the local files, from 0 to 1, a sync with `--delete`
+    /// removes without `--force`. A remote that suddenly lost more, e.g.
+    /// because a mount wasn't ready, is more likely wrong than emptied.
+    #[serde(deserialize_with = "deserialize_fraction")]
+    pub max_delete: f64,
+    /// After a sync with `--delete` removed files, also remove the
+    /// directories below the local path they left empty
+    pub prune_empty_dirs: bool,
+    /// Remote files smaller than this many bytes are downloaded whole even
+    /// when a local copy could be patched, as the signature and delta round
+    /// trip costs more than it saves.
+    pub delta_min_len: u64,
+    /// Share of a file, from 0 to 1, a delta must save before the server
+    /// sends it. Less than that and the file is downloaded whole instead.
+    #[serde(deserialize_with = "deserialize_fraction")]
+    pub delta_min_savings: f64,
+    /// Bytes of file data the server reads ahead of sending, and of requests
+    /// it is reading, across every connection at once. Transfers wait their
+    /// turn beyond that. Accepts a
+    /// number or a string with a suffix, e.g. `"64M"`.
+    #[serde(deserialize_with = "deserialize_max_buffered")]
+    pub max_buffered: usize,
+    /// Shell command the server runs before listing a shared root, with the
+    /// root as `$1`, e.g. to take a filesystem snapshot of it. It prints the
+    /// directory holding a consistent copy of the root, which the listing
+    /// and the files fetched after it are read from instead of the live tree.
+    pub snapshot_command: Option<String>,
+    /// Copy and sync files and directories whose names start with a dot,
+    /// which are skipped unless `--include-hidden` is given
+    pub include_hidden: bool,
+    /// Refuse to use a secret key file others may read, rather than only
+    /// warning about it
+    pub strict_key_permissions: bool,
+    pub backup: BackupConfig,
+    pub log: LogConfig,
+    /// Home directory this config was loaded for; not read from the file.
+    #[serde(skip)]
+    pub home: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            discovery: DiscoveryConfig::default(),
+            relay: RelayConfig::default(),
+            bind: None,
+            network: None,
+            limit_rate: None,
+            schedule: Vec::new(),
+            data_dir: None,
+            idle_timeout_secs: DEFAULT_IDLE_TIMEOUT_SECS,
+            handshake_timeout_secs: DEFAULT_HANDSHAKE_TIMEOUT_SECS,
+            block_size: None,
+            strong_hash_size: sync_utils::DEFAULT_STRONG_HASH_SIZE,
+            heartbeat_interval_secs: DEFAULT_HEARTBEAT_INTERVAL_SECS,
+            heartbeat_max_missed: DEFAULT_HEARTBEAT_MAX_MISSED,
+            pool_idle_secs: DEFAULT_POOL_IDLE_SECS,
+            notify_batch_ms: DEFAULT_NOTIFY_BATCH_MS,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_connections_per_peer: DEFAULT_MAX_CONNECTIONS_PER_PEER,
+            max_concurrent_pulls: DEFAULT_MAX_CONCURRENT_PULLS,
+            chunk_size: FILE_CHUNK_LEN,
+            window: DEFAULT_WINDOW,
+            watch_reconcile_secs: DEFAULT_WATCH_RECONCILE_SECS,
+            listing_cache_min_entries: DEFAULT_LISTING_CACHE_MIN_ENTRIES,
+            hash_concurrency: DEFAULT_HASH_CONCURRENCY,
+            sync_stale_secs: DEFAULT_SYNC_STALE_SECS,
+            fsync: false,
+            max_delete: DEFAULT_MAX_DELETE,
+            prune_empty_dirs: false,
+            delta_min_len: DEFAULT_DELTA_MIN_LEN,
+            delta_min_savings: DEFAULT_DELTA_MIN_SAVINGS,
+            max_buffered: buffer_budget::DEFAULT_MAX_BUFFERED,
+            snapshot_command: None,
+            include_hidden: false,
+            strict_key_permissions: false,
+            backup: BackupConfig::default(),
+            log: LogConfig::default(),
+            home: None,
+        }
+    }
+}
+
+/// Which discovery mechanisms endpoints are built with.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// Publish our address to the n0 pkarr relay
+    pub pkarr: bool,
+    /// Resolve peers via n0 DNS
+    pub dns: bool,
+    /// Find peers on the local network via mDNS
+    pub mdns: bool,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            pkarr: true,
+            dns: true,
+            mdns: true,
+        }
+    }
+}
+
+/// Which relays endpoints use to reach peers they can't connect to directly.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelayConfig {
+    /// Use relays at all. Without them peers are only reached directly, at
+    /// addresses found via mDNS on the local network or taken from tickets.
+    pub enabled: bool,
+    /// Relay to use instead of the public n0 ones, e.g.
+    /// `"https://relay.example.com"`
+    #[serde(deserialize_with = "deserialize_relay_url")]
+    pub url: Option<RelayUrl>,
+    /// Seconds an endpoint waits to reach the relay given by `url` before
+    /// giving up on it
+    pub timeout_secs: u64,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            url: None,
+            timeout_secs: 10,
+        }
+    }
+}
+
+impl RelayConfig {
+    /// How long to wait for the relay given by `url`.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+/// Parse the URL of a relay, which must be an `http` or `https` URL with a
+/// host.
+pub fn parse_relay_url(s: &str) -> std::result::Result<RelayUrl, String> {
+    let url: RelayUrl = s
+        .parse()
+        .map_err(|e| format!("invalid relay URL '{}': {}", s, e))?;
+    if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+        return Err(format!(
+            "invalid relay URL '{}', expected e.g. https://relay.example.com",
+            s
+        ));
+    }
+    Ok(url)
+}
+
+/// Whether files are backed up before a sync overwrites them, and for how
+/// long the backups are kept.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupConfig {
+    /// Back up by default, as if `--backup` was given
+    pub enabled: bool,
+    /// Days a backup is kept
+    pub keep_days: u64,
+    /// Backups kept of any one file
+    pub keep_versions: usize,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        let retention = Retention::default();
+        Self {
+            enabled: false,
+            keep_days: retention.max_age.as_secs() / (24 * 60 * 60),
+            keep_versions: retention.max_versions,
+        }
+    }
+}
+
+impl BackupConfig {
+    pub fn retention(&self) -> Retention {
+        Retention {
+            max_age: Duration::from_secs(self.keep_days * 24 * 60 * 60),
+            max_versions: self.keep_versions,
+        }
+    }
+}
+
+/// Where log lines go. The level is set with `-v`/`-q` or `RUST_LOG` either
+/// way.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Also log to a file rotated daily, as if `--log-file` was given
+    pub file: bool,
+    /// Directory the log files go in. Defaults to `logs` in the syncr home.
+    pub dir: Option<PathBuf>,
+    /// Log to stderr
+    pub stderr: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            file: false,
+            dir: None,
+            stderr: true,
+        }
+    }
+}
+
+impl Config {
+    /// Load the config from `path`, falling back to `$SYNCR_CONFIG` and then
+    /// `config.toml` in `home` (or the default home). A missing default file
+    /// yields the default config; a missing explicitly named file is an error.
+    pub fn load(path: Option<&Path>, home: Option<&Path>) -> Result<Self> {
+        let home = match home {
+            Some(home) => home.to_path_buf(),
+            None => config_dir()?,
+        };
+        let explicit = path
+            .map(Path::to_path_buf)
+            .or_else(|| std::env::var_os(CONFIG_ENV).map(PathBuf::from));
+
+        let is_explicit = explicit.is_some();
+        let path = explicit.unwrap_or_else(|| home.join("config.toml"));
+
+        let mut config: Self = if !is_explicit && !path.exists() {
+            Self::default()
+        } else {
+            let contents = std::fs::read_to_string(&path)
+                .map_err(|e| ConfigError::ReadError(path.clone(), e))?;
+            toml::from_str(&contents).map_err(|e| ConfigError::ParseError(path, e))?
+        };
+        config.home = Some(home);
+        Ok(config)
+    }
+
+    /// Home directory holding the secret key and, by default, the database.
+    pub fn home_dir(&self) -> Result<PathBuf> {
+        match &self.home {
+            Some(home) => Ok(home.clone()),
+            None => config_dir(),
+        }
+    }
+
+    /// Directory the database lives in.
+    pub fn data_dir(&self) -> Result<PathBuf> {
+        match &self.data_dir {
+            Some(dir) => Ok(dir.clone()),
+            None => self.home_dir(),
+        }
+    }
+
+    /// Directory log files are written to.
+    pub fn log_dir(&self) -> Result<PathBuf> {
+        match &self.log.dir {
+            Some(dir) => Ok(dir.clone()),
+            None => Ok(self.home_dir()?.join("logs")),
+        }
+    }
+
+    /// Relays endpoints are built with.
+    pub fn relay_mode(&self) -> RelayMode {
+        if !self.relay.enabled {
+            return RelayMode::Disabled;
+        }
+        match &self.relay.url {
+            Some(url) => RelayMode::Custom(RelayMap::from(url.clone())),
+            None => RelayMode::Default,
+        }
+    }
+
+    /// How long to wait for a peer's next message.
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+
+    /// How long to wait for a peer's handshake.
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.handshake_timeout_secs)
+    }
+
+    /// Bytes a peer may have in flight to us on one stream: the window in
+    /// chunks.
+    pub fn receive_window(&self) -> u64 {
+        self.chunk_size as u64 * u64::from(self.window)
+    }
+
+    /// How often watched paths are checked against the store, at most once
+    /// a second.
+    pub fn watch_reconcile_interval(&self) -> Duration {
+        Duration::from_secs(self.watch_reconcile_secs.max(1))
+    }
+
+    /// How long pooled connections are kept unused, or `None` to keep them
+    /// until they fail.
+    pub fn pool_idle(&self) -> Option<Duration> {
+        (self.pool_idle_secs > 0).then(|| Duration::from_secs(self.pool_idle_secs))
+    }
+
+    /// How long changes are gathered before peers are notified of them, or
+    /// `None` to notify of each right away.
+    pub fn notify_batch(&self) -> Option<Duration> {
+        (self.notify_batch_ms > 0).then(|| Duration::from_millis(self.notify_batch_ms))
+    }
+
+    /// Name of the isolated network the node is on, if any.
+    pub fn network(&self) -> Option<&str> {
+        self.network.as_deref()
+    }
+}
+
+/// The default syncr home directory, e.g. `~/.config/syncr`:
+/// `syncr` in `$XDG_CONFIG_HOME` on every platform when that is set, else in
+/// the platform's config directory.
+pub fn config_dir() -> Result<PathBuf> {
+    config_dir_from(std::env::var_os("XDG_CONFIG_HOME"), dirs::config_dir())
+}
+
+/// [`config_dir`] given `$XDG_CONFIG_HOME` and the platform's config
+/// directory. A relative `xdg_config_home` is ignored, as the XDG spec says;
+/// with neither, there is no default home and it has to be given.
+pub fn config_dir_from(
+    xdg_config_home: Option<std::ffi::OsString>,
+    platform: Option<PathBuf>,
+) -> Result<PathBuf> {
+    xdg_config_home
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or(platform)
+        .map(|dir| dir.join("syncr"))
+        .ok_or(ConfigError::NoConfigDir)
+}
+
+/// Home of the profile `name` in the syncr home `base`, with its own config,
+/// identity and database.
+pub fn profile_dir(base: &Path, name: &str) -> PathBuf {
+    base.join(PROFILES_DIR).join(name)
+}
+
+/// Names of the profiles in the syncr home `base`, sorted.
+pub fn list_profiles(base: &Path) -> std::io::Result<Vec<String>> {
+    let entries = match std::fs::read_dir(base.join(PROFILES_DIR)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut names = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        if let Some(name) = entry.file_name().to_str() {
+            names.push(name.to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Check that `s` can name a profile: letters, digits, `-`, `_` and `.`,
+/// not starting with a dot, so it is one plain directory name.
+pub fn parse_profile(s: &str) -> std::result::Result<String, String> {
+    let valid = !s.is_empty()
+        && !s.starts_with('.')
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if valid {
+        Ok(s.to_string())
+    } else {
+        Err(format!(
+            "profile name '{}' may only hold letters, digits, '-', '_' and '.', \
+             and not start with '.'",
+            s
+        ))
+    }
+}
+
+/// Check a network name, which may only hold letters, digits, `-`, `_` and
+/// `.`, as it ends up in every ALPN.
+pub fn parse_network(s: &str) -> std::result::Result<String, String> {
+    let valid = !s.is_empty()
+        && s.len() <= MAX_NETWORK_LEN
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if valid {
+        Ok(s.to_string())
+    } else {
+        Err(format!(
+            "network name '{}' may only hold up to {} letters, digits, '-', '_' and '.'",
+            s, MAX_NETWORK_LEN
+        ))
+    }
+}
+
+/// Parse a chunk size such as `65536`, `64K` or `1M` into bytes, which must
+/// not exceed [`MAX_CHUNK_LEN`].
+pub fn parse_chunk_size(s: &str) -> std::result::Result<usize, String> {
+    let bytes = rate_limit::parse_rate(s).map_err(|e| e.replace("rate", "chunk size"))?;
+    usize::try_from(bytes)
+        .ok()
+        .filter(|&bytes| bytes <= MAX_CHUNK_LEN)
+        .ok_or_else(|| {
+            format!(
+                "chunk size '{}' is larger than the {} byte maximum",
+                s, MAX_CHUNK_LEN
+            )
+        })
+}
+
+/// Parse a cap on buffered file data such as `16M`.
+pub fn parse_max_buffered(s: &str) -> std::result::Result<usize, String> {
+    let bytes = rate_limit::parse_rate(s).map_err(|e| e.replace("rate", "buffer size"))?;
+    usize::try_from(bytes).map_err(|_| format!("buffer size '{}' is too large", s))
+}
+
+/// Parse a file size limit such as `500M` or `2G`.
+pub fn parse_max_size(s: &str) -> std::result::Result<u64, String> {
+    rate_limit::parse_rate(s).map_err(|e| e.replace("rate", "size"))
+}
+
+/// Parse a window of in-flight chunks, which must be at least one.
+pub fn parse_window(s: &str) -> std::result::Result<u32, String> {
+    match s.trim().parse() {
+        Ok(0) => Err("window must be at least one chunk".to_string()),
+        Ok(window) => Ok(window),
+        Err(_) => Err(format!(
+            "invalid window '{}', expected a number of chunks",
+            s
+        )),
+    }
+}
+
+fn deserialize_chunk_size<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<usize, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+
+    let text = match Size::deserialize(deserializer)? {
+        Size::Bytes(bytes) => bytes.to_string(),
+        Size::Text(text) => text,
+    };
+    parse_chunk_size(&text).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_network<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<String>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|network| parse_network(&network).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+fn deserialize_window<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<u32, D::Error> {
+    match u32::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom(
+            "window must be at least one chunk",
+        )),
+        window => Ok(window),
+    }
+}
+
+fn deserialize_fraction<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<f64, D::Error> {
+    match f64::deserialize(deserializer)? {
+        fraction if (0.0..=1.0).contains(&fraction) => Ok(fraction),
+        fraction => Err(serde::de::Error::custom(format!(
+            "{} is not a fraction from 0 to 1",
+            fraction
+        ))),
+    }
+}
+
+fn deserialize_max_buffered<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<usize, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+
+    let text = match Size::deserialize(deserializer)? {
+        Size::Bytes(bytes) => bytes.to_string(),
+        Size::Text(text) => text,
+    };
+    parse_max_buffered(&text).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_relay_url<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<RelayUrl>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|url| parse_relay_url(&url).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+fn deserialize_rate<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Rate {
+        Bytes(u64),
+        Text(String),
+    }
+
+    match Option::<Rate>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Rate::Bytes(0)) => Err(serde::de::Error::custom("rate must be greater than zero")),
+        Some(Rate::Bytes(bytes)) => Ok(Some(bytes)),
+        Some(Rate::Text(text)) => rate_limit::parse_rate(&text)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
@@ -0,0 +1,214 @@
+//! Local control channel to a running `syncr serve`, so other commands can
+//! ask it things without opening the store it holds. It is a Unix socket in
+//! the data directory, framed like the peer protocol. Elsewhere there is no
+//! channel and commands always use the store.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    schedule::ActiveLimit,
+    store::{self, Store, SyncHealth},
+};
+
+/// Name of the socket in the data directory.
+pub const SOCKET_NAME: &str = "control.sock";
+
+/// How long either side waits for the other's frame.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ControlRequest {
+    /// What the server is doing
+    Status,
+    /// Bring the installed watches in line with the store now
+    Reload,
+    /// Stop the pulls under way, as if they had failed
+    Cancel,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ControlResponse {
+    Status(DaemonStatus),
+    Reloaded,
+    Cancelled,
+    Error(String),
+}
+
+/// A running server, as reported by [`ControlRequest::Status`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DaemonStatus {
+    pub peer_id: String,
+    pub pid: u32,
+    pub uptime_secs: u64,
+    /// Peer connections open, whichever side opened them
+    pub connections: usize,
+    /// Paths with a live watch
+    pub watched: Vec<PathBuf>,
+    pub syncs: Vec<SyncStatus>,
+    /// Transfer limit in force, as the schedule sets it for now
+    pub limit: ActiveLimit,
+}
+
+/// A configured sync and how it is doing.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SyncStatus {
+    pub local_path: PathBuf,
+    pub peer: String,
+    pub remote_path: String,
+    /// Unix timestamp of the last completed sync
+    pub last_synced: Option<u64>,
+    pub last_error: Option<String>,
+    /// Notifications about changes still to be delivered to the peer
+    pub pending: usize,
+    pub health: SyncHealth,
+}
+
+/// Every sync in `store` with its health at `now`, see
+/// [`SyncConfig::health`](crate::store::SyncConfig::health).
+pub fn sync_statuses(store: &Store, now: u64, stale_after: u64) -> store::Result<Vec<SyncStatus>> {
+    let mut statuses = Vec::new();
+    for (local_path, configs) in store.list_syncs()? {
+        for config in configs {
+            let pending = store.pending_notifications_below(config.peer, &config.remote_path)?;
+            statuses.push(SyncStatus {
+                local_path: local_path.clone(),
+                peer: config.peer.to_string(),
+                remote_path: config.remote_path.clone(),
+                last_synced: config.last_synced,
+                last_error: config.last_error.clone(),
+                pending,
+                health: config.health(pending, now, stale_after),
+            });
+        }
+    }
+    Ok(statuses)
+}
+
+/// A request taken off the socket, to be answered on `reply`.
+pub struct Pending {
+    pub request: ControlRequest,
+    pub reply: oneshot::Sender<ControlResponse>,
+}
+
+/// Where the server using `data_dir` listens.
+pub fn socket_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(SOCKET_NAME)
+}
+
+/// Send `request` to the server using `data_dir`. Returns `None` if no
+/// server is listening there.
+pub async fn request(data_dir: &Path, request: ControlRequest) -> Result<Option<ControlResponse>> {
+    imp::request(&socket_path(data_dir), request).await
+}
+
+/// Listen for requests on the socket in `data_dir` until the returned
+/// listener is dropped, which removes the socket again. Requests come out
+/// of the receiver.
+pub fn listen(data_dir: &Path) -> Result<(Listener, mpsc::Receiver<Pending>)> {
+    imp::listen(&socket_path(data_dir))
+}
+
+pub use imp::Listener;
+
+#[cfg(unix)]
+mod imp {
+    use super::*;
+    use crate::protocol::{read_frame, write_frame};
+    use tokio::net::{UnixListener, UnixStream};
+    use tokio::task::JoinHandle;
+    use tracing::{info, warn};
+
+    pub struct Listener {
+        path: PathBuf,
+        task: JoinHandle<()>,
+    }
+
+    impl Drop for Listener {
+        fn drop(&mut self) {
+            self.task.abort();
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    pub fn listen(path: &Path) -> Result<(Listener, mpsc::Receiver<Pending>)> {
+        // Left behind by a server that didn't stop cleanly; a running one
+        // would hold the store this one just opened.
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        info!("Control socket at {:?}", path);
+        let (pending, requests) = mpsc::channel(16);
+        let task = tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!("Failed to accept on the control socket: {}", e);
+                        continue;
+                    }
+                };
+                let pending = pending.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = answer(stream, pending).await {
+                        warn!("Control request failed: {}", e);
+                    }
+                });
+            }
+        });
+        let path = path.to_path_buf();
+        Ok((Listener { path, task }, requests))
+    }
+
+    async fn answer(mut stream: UnixStream, pending: mpsc::Sender<Pending>) -> Result<()> {
+        let request = read_frame(&mut stream, TIMEOUT).await?;
+        let (reply, response) = oneshot::channel();
+        pending
+            .send(Pending { request, reply })
+            .await
+            .map_err(|_| anyhow::anyhow!("Server is stopping"))?;
+        let response = response
+            .await
+            .unwrap_or_else(|_| ControlResponse::Error("Server is stopping".to_string()));
+        write_frame(&mut stream, &response).await?;
+        Ok(())
+    }
+
+    pub async fn request(path: &Path, request: ControlRequest) -> Result<Option<ControlResponse>> {
+        let mut stream = match UnixStream::connect(path).await {
+            Ok(stream) => stream,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        write_frame(&mut stream, &request).await?;
+        Ok(Some(read_frame(&mut stream, TIMEOUT).await?))
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use super::*;
+
+    pub struct Listener;
+
+    pub fn listen(_path: &Path) -> Result<(Listener, mpsc::Receiver<Pending>)> {
+        let (_, requests) = mpsc::channel(1);
+        Ok((Listener, requests))
+    }
+
+    pub async fn request(
+        _path: &Path,
+        _request: ControlRequest,
+    ) -> Result<Option<ControlResponse>> {
+        Ok(None)
+    }
+}
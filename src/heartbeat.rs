@@ -0,0 +1,143 @@
+//! Liveness checks for connections kept open to peers.
+
+use iroh::endpoint::Connection;
+use std::time::Duration;
+
+use crate::{
+    config::Config,
+    iroh_utils,
+    protocol::{read_message, write_message, Capabilities, Message, ProtocolError},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum HeartbeatError {
+    #[error("Peer missed {0} heartbeats in a row")]
+    Missed(u32),
+    #[error("Failed to open heartbeat stream: {0}")]
+    Stream(String),
+    #[error("Unexpected reply to heartbeat: {0}")]
+    Unexpected(String),
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+}
+
+pub type Result<T> = std::result::Result<T, HeartbeatError>;
+
+/// How often to ping a peer and how many unanswered pings mean it is gone.
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatOptions {
+    pub interval: Duration,
+    pub max_missed: u32,
+}
+
+impl From<&Config> for HeartbeatOptions {
+    fn from(config: &Config) -> Self {
+        Self {
+            interval: Duration::from_secs(config.heartbeat_interval_secs),
+            max_missed: config.heartbeat_max_missed,
+        }
+    }
+}
+
+/// Ping the peer on its own stream of `connection` every interval, waiting
+/// up to one interval for each answer. Only returns once the peer has missed
+/// `max_missed` pings in a row or the stream fails, either of which means
+/// the connection should be treated as dead.
+pub async fn monitor(connection: &Connection, opts: HeartbeatOptions) -> HeartbeatError {
+    match run(connection, opts).await {
+        Ok(never) => match never {},
+        Err(e) => e,
+    }
+}
+
+async fn run(connection: &Connection, opts: HeartbeatOptions) -> Result<std::convert::Infallible> {
+    let (mut send, mut recv) = connection
+        .open_bi()
+        .await
+        .map_err(|e| HeartbeatError::Stream(e.to_string()))?;
+
+    let version = iroh_utils::protocol_version(connection);
+    let handshake = Message::handshake(version, &Capabilities::ours());
+    write_message(&mut send, &handshake).await?;
+    match read_message(&mut recv, opts.interval * opts.max_missed.max(1)).await {
+        Ok(Message::Handshake { .. } | Message::LegacyHandshake { .. }) => {}
+        Ok(msg) => return Err(HeartbeatError::Unexpected(format!("{:?}", msg))),
+        Err(ProtocolError::Timeout(_)) => return Err(HeartbeatError::Missed(opts.max_missed)),
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut missed = 0;
+    let mut nonce = 0;
+    loop {
+        tokio::time::sleep(opts.interval).await;
+        nonce += 1;
+        write_message(&mut send, &Message::Ping { nonce }).await?;
+        match read_message(&mut recv, opts.interval).await {
+            // A late answer to an earlier ping still shows the peer is there.
+            Ok(Message::Pong { .. }) => missed = 0,
+            Ok(msg) => return Err(HeartbeatError::Unexpected(format!("{:?}", msg))),
+            Err(ProtocolError::Timeout(_)) => {
+                missed += 1;
+                if missed >= opts.max_missed {
+                    return Err(HeartbeatError::Missed(missed));
+                }
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::discovery::static_provider::StaticProvider;
+    use std::time::{Duration, Instant};
+
+    use super::{monitor, HeartbeatError, HeartbeatOptions};
+    use crate::{
+        protocol::{read_message, write_message, Message, ALPN},
+        test_support::{loopback_addr, loopback_endpoint},
+    };
+
+    #[tokio::test]
+    async fn silent_peer_is_declared_dead() {
+        // A peer that completes the handshake and then never answers again.
+        let silent = loopback_endpoint(None).await;
+        let addrs = StaticProvider::new();
+        addrs.add_endpoint_info(loopback_addr(&silent));
+        let client = loopback_endpoint(Some(addrs)).await;
+
+        let silent_id = silent.id();
+        let peer = tokio::spawn(async move {
+            let connection = silent
+                .accept()
+                .await
+                .unwrap()
+                .accept()
+                .unwrap()
+                .await
+                .unwrap();
+            let (mut send, mut recv) = connection.accept_bi().await.unwrap();
+            read_message(&mut recv, Duration::from_secs(10))
+                .await
+                .unwrap();
+            write_message(&mut send, &Message::LegacyHandshake { version: 1 })
+                .await
+                .unwrap();
+            // Keep the connection open without reading any further.
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            drop((send, recv, connection, silent));
+        });
+
+        let connection = client.connect(silent_id, ALPN).await.unwrap();
+        let opts = HeartbeatOptions {
+            interval: Duration::from_millis(100),
+            max_missed: 3,
+        };
+        let started = Instant::now();
+        let err = monitor(&connection, opts).await;
+
+        assert!(matches!(err, HeartbeatError::Missed(3)), "{:?}", err);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        peer.abort();
+    }
+}
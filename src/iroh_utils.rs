@@ -1,5 +1,22 @@
-use iroh::SecretKey;
+use iroh::{
+    discovery::{
+        dns::DnsDiscovery, mdns::MdnsDiscovery, pkarr::PkarrPublisher,
+        static_provider::StaticProvider,
+    },
+    endpoint::{Builder, ConnectOptions, Connection, ConnectionType, TransportConfig, VarInt},
+    Endpoint, EndpointAddr, PublicKey, RelayUrl, SecretKey, Watcher,
+};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tokio::fs;
+use tracing::{info, warn};
+
+use crate::{
+    config::{Config, ConfigError},
+    protocol::{alpn_version, network_alpn, ALPN, SUPPORTED_ALPNS},
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum IrohUtilsError {
@@ -7,32 +24,153 @@ pub enum IrohUtilsError {
     SecretKeyGenerationError(String),
     #[error("Failed to load secret key {0}")]
     SecretKeyLoadError(String),
+    #[error("Secret key {0:?} is readable by others (mode {1:o}); run `chmod 600` on it")]
+    InsecureKeyPermissions(PathBuf, u32),
+    #[error("Failed to bind endpoint: {0}")]
+    BindFailed(String),
+    #[error(
+        "Relay {0} could not be reached within {1:?}; check the URL and that the relay is running"
+    )]
+    RelayUnreachable(RelayUrl, Duration),
+    #[error("Peer {0} not found via discovery")]
+    PeerNotFound(PublicKey),
+    #[error("Peer {0} found but not reachable (is it running `syncr serve`?)")]
+    PeerUnreachable(PublicKey),
+    #[error("Connection to peer {0} timed out after {1:?}")]
+    ConnectTimedOut(PublicKey, Duration),
+    #[error("Dialed peer {expected} but reached {actual}; refusing to transfer with it")]
+    PeerMismatch {
+        expected: PublicKey,
+        actual: PublicKey,
+    },
+    #[error(transparent)]
+    Config(#[from] ConfigError),
 }
 
 pub type Result<T> = std::result::Result<T, IrohUtilsError>;
 
-pub async fn init_secret_key() -> Result<()> {
+/// Generate a secret key in `home` unless one is already there.
+pub async fn init_secret_key(home: &Path) -> Result<()> {
     // Only init if the file is not already present
-    if let Ok(_) = load_secret_key().await {
+    if read_secret_key(&home.join("secret_key")).await.is_ok() {
         return Ok(());
     }
     let secret_key = iroh::SecretKey::generate(&mut rand::rng());
     let sk_bytes = secret_key.to_bytes();
-    // make ~/.config/syncr if it doesn't exist
-    let iroh_config_dir = dirs::config_dir().unwrap().join("syncr");
-    fs::create_dir_all(&iroh_config_dir)
+    fs::create_dir_all(home)
         .await
         .map_err(|e| IrohUtilsError::SecretKeyGenerationError(e.to_string()))?;
-    fs::write(iroh_config_dir.join("secret_key"), &sk_bytes)
+    write_key_file(&home.join("secret_key"), &sk_bytes)
         .await
         .map_err(|e| IrohUtilsError::SecretKeyGenerationError(e.to_string()))?;
     Ok(())
 }
 
-pub async fn load_secret_key() -> Result<iroh::SecretKey> {
-    let iroh_config_dir = dirs::config_dir().unwrap().join("syncr");
-    let sk_path = iroh_config_dir.join("secret_key");
-    let sk_vec = fs::read(sk_path)
+/// Load the secret key in `home`, warning if others may read its file.
+pub async fn load_secret_key(home: &Path) -> Result<iroh::SecretKey> {
+    load_secret_key_with(home, false).await
+}
+
+/// Like [`load_secret_key`], but refusing a key file others may read when
+/// `strict`, as ssh does for private keys.
+pub async fn load_secret_key_with(home: &Path, strict: bool) -> Result<iroh::SecretKey> {
+    let path = home.join("secret_key");
+    check_key_permissions(&path, strict).await?;
+    read_secret_key(&path).await
+}
+
+/// Complain about a key file with group or world permissions. A file that
+/// can't be read is left for reading it to report.
+#[cfg(unix)]
+async fn check_key_permissions(path: &Path, strict: bool) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let Ok(metadata) = fs::metadata(path).await else {
+        return Ok(());
+    };
+    let mode = metadata.permissions().mode() & 0o777;
+    if mode & 0o077 == 0 {
+        return Ok(());
+    }
+    let err = IrohUtilsError::InsecureKeyPermissions(path.to_path_buf(), mode);
+    if strict {
+        return Err(err);
+    }
+    warn!("{}", err);
+    Ok(())
+}
+
+/// Files have no Unix permission bits here.
+#[cfg(not(unix))]
+async fn check_key_permissions(_path: &Path, _strict: bool) -> Result<()> {
+    Ok(())
+}
+
+/// Write a new key file that only its owner may read.
+async fn write_key_file(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    use tokio::io::AsyncWriteExt;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).await?;
+    // A file that was already there keeps its mode otherwise.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))
+            .await?;
+    }
+    file.write_all(bytes).await?;
+    file.flush().await
+}
+
+/// Replace the secret key in `home` with a new one. Returns the new key and
+/// where the old one was backed up, if there was one.
+pub async fn rotate_secret_key(home: &Path) -> Result<(SecretKey, Option<PathBuf>)> {
+    let secret_key = iroh::SecretKey::generate(&mut rand::rng());
+    let backup = replace_secret_key(home, &secret_key).await?;
+    Ok((secret_key, backup))
+}
+
+/// Make the secret key in the file at `from` the identity in `home`. Returns
+/// the key and where the previous one was backed up, if there was one.
+pub async fn import_secret_key(home: &Path, from: &Path) -> Result<(SecretKey, Option<PathBuf>)> {
+    let secret_key = read_secret_key(from).await?;
+    let backup = replace_secret_key(home, &secret_key).await?;
+    Ok((secret_key, backup))
+}
+
+/// Write `secret_key` to `home`, first moving any existing key aside to a
+/// timestamped backup.
+async fn replace_secret_key(home: &Path, secret_key: &SecretKey) -> Result<Option<PathBuf>> {
+    let write_err = |e: std::io::Error| IrohUtilsError::SecretKeyGenerationError(e.to_string());
+    fs::create_dir_all(home).await.map_err(write_err)?;
+    let sk_path = home.join("secret_key");
+    let new_path = home.join("secret_key.new");
+    write_key_file(&new_path, &secret_key.to_bytes())
+        .await
+        .map_err(write_err)?;
+
+    let backup = if fs::try_exists(&sk_path).await.map_err(write_err)? {
+        let stamp = crate::sync_utils::unix_timestamp();
+        let mut backup = home.join(format!("secret_key.{}.bak", stamp));
+        let mut n = 1;
+        while fs::try_exists(&backup).await.map_err(write_err)? {
+            backup = home.join(format!("secret_key.{}-{}.bak", stamp, n));
+            n += 1;
+        }
+        fs::rename(&sk_path, &backup).await.map_err(write_err)?;
+        Some(backup)
+    } else {
+        None
+    };
+    fs::rename(&new_path, &sk_path).await.map_err(write_err)?;
+    Ok(backup)
+}
+
+/// Read a secret key from `path`, which must hold exactly its 32 bytes.
+async fn read_secret_key(path: &Path) -> Result<SecretKey> {
+    let sk_vec = fs::read(path)
         .await
         .map_err(|e| IrohUtilsError::SecretKeyLoadError(e.to_string()))?;
 
@@ -49,3 +187,233 @@ pub async fn load_secret_key() -> Result<iroh::SecretKey> {
 
     Ok(SecretKey::from_bytes(&sk_bytes))
 }
+
+/// Endpoint builder with the discovery mechanisms, relays and bind address
+/// set in `config`. iroh's own default discovery is left out, so only what
+/// `config` enables is used.
+pub fn endpoint_builder(config: &Config, secret_key: SecretKey) -> Builder {
+    let mut builder = Endpoint::builder()
+        .clear_discovery()
+        .relay_mode(config.relay_mode());
+    match config.bind {
+        Some(SocketAddr::V4(addr)) => builder = builder.bind_addr_v4(addr),
+        Some(SocketAddr::V6(addr)) => builder = builder.bind_addr_v6(addr),
+        None => {}
+    }
+    if config.discovery.pkarr {
+        builder = builder.discovery(PkarrPublisher::n0_dns());
+    }
+    if config.discovery.dns {
+        builder = builder.discovery(DnsDiscovery::n0_dns());
+    }
+    if config.discovery.mdns {
+        builder = builder.discovery(MdnsDiscovery::builder());
+    }
+    builder
+        .secret_key(secret_key)
+        .alpns(advertised_alpns(config.network()))
+        .transport_config(transport_config(config))
+}
+
+/// QUIC settings from `config`. Flow control is what bounds the chunks in
+/// flight: a peer can't send more on a stream than its receive window.
+fn transport_config(config: &Config) -> TransportConfig {
+    let window = VarInt::from_u64(config.receive_window()).unwrap_or(VarInt::MAX);
+    let mut transport = TransportConfig::default();
+    transport.stream_receive_window(window);
+    transport
+}
+
+/// Protocols every syncr endpoint on `network` accepts, whether it serves
+/// or only dials.
+pub fn advertised_alpns(network: Option<&str>) -> Vec<Vec<u8>> {
+    SUPPORTED_ALPNS
+        .iter()
+        .map(|alpn| network_alpn(alpn, network))
+        .collect()
+}
+
+/// Bind an endpoint for our identity, configured from `config`. The identity
+/// is generated on first use. If a relay URL is configured, the endpoint
+/// waits to reach that relay, as nothing else may get through.
+pub async fn build_endpoint(config: &Config) -> Result<Endpoint> {
+    let home = config.home_dir()?;
+    init_secret_key(&home).await?;
+    let secret_key = load_secret_key_with(&home, config.strict_key_permissions).await?;
+    let endpoint = endpoint_builder(config, secret_key)
+        .bind()
+        .await
+        .map_err(|e| IrohUtilsError::BindFailed(e.to_string()))?;
+    if let Some(url) = config.relay.url.as_ref().filter(|_| config.relay.enabled) {
+        let timeout = config.relay.timeout();
+        if tokio::time::timeout(timeout, endpoint.online())
+            .await
+            .is_err()
+        {
+            endpoint.close().await;
+            return Err(IrohUtilsError::RelayUnreachable(url.clone(), timeout));
+        }
+        info!("Connected to relay {}", url);
+    }
+    Ok(endpoint)
+}
+
+/// Let `endpoint` reach peers at the given addresses without discovery, e.g.
+/// ones learned from tickets.
+pub fn add_known_addresses(endpoint: &Endpoint, addrs: impl IntoIterator<Item = EndpointAddr>) {
+    let provider = StaticProvider::new();
+    for addr in addrs {
+        provider.add_endpoint_info(addr);
+    }
+    endpoint.discovery().add(provider);
+}
+
+/// How long [`connect`] waits for a peer to be found and to answer.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Attempts [`connect`] makes within [`CONNECT_TIMEOUT`], since discovery
+/// can miss a peer for a moment.
+const CONNECT_ATTEMPTS: u32 = 3;
+
+/// Pause before [`connect`] tries again, doubled each time.
+const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Connect to `peer` using the newest syncr protocol version it speaks on
+/// `network`. A peer that isn't found or doesn't answer, or is on another
+/// network, is tried again a few times. Failures are reported by what went
+/// wrong; iroh's own error is logged at info level, i.e. with `-v`.
+pub async fn connect(
+    endpoint: &Endpoint,
+    network: Option<&str>,
+    peer: PublicKey,
+) -> Result<Connection> {
+    let attempts = async {
+        let mut delay = CONNECT_RETRY_DELAY;
+        let mut attempt = 1;
+        loop {
+            match connect_once(endpoint, network, peer).await {
+                Err(e) if attempt < CONNECT_ATTEMPTS => {
+                    info!(
+                        "Attempt {} to connect to {} failed, trying again: {}",
+                        attempt, peer, e
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    };
+    let connection = tokio::time::timeout(CONNECT_TIMEOUT, attempts)
+        .await
+        .map_err(|_| IrohUtilsError::ConnectTimedOut(peer, CONNECT_TIMEOUT))??;
+    if let Err(e) = check_remote_id(peer, connection.remote_id()) {
+        connection.close(0u32.into(), b"unexpected peer");
+        return Err(e);
+    }
+    info!("Connected to {} {}", peer, route(endpoint, peer));
+    Ok(connection)
+}
+
+/// Make sure the node a connection reached, as authenticated by its
+/// handshake, is the peer we meant to reach. iroh only completes a handshake
+/// with the key dialed, so this guards against a mistake in the layers
+/// resolving peers rather than against an attacker.
+pub fn check_remote_id(expected: PublicKey, actual: PublicKey) -> Result<()> {
+    if actual != expected {
+        return Err(IrohUtilsError::PeerMismatch { expected, actual });
+    }
+    Ok(())
+}
+
+/// One attempt of [`connect`].
+async fn connect_once(
+    endpoint: &Endpoint,
+    network: Option<&str>,
+    peer: PublicKey,
+) -> Result<Connection> {
+    let older = SUPPORTED_ALPNS[1..]
+        .iter()
+        .map(|alpn| network_alpn(alpn, network))
+        .collect();
+    let opts = ConnectOptions::new().with_additional_alpns(older);
+    let connecting = endpoint
+        .connect_with_opts(peer, &network_alpn(ALPN, network), opts)
+        .await
+        .map_err(|e| {
+            info!("No address found for peer {}: {}", peer, e);
+            IrohUtilsError::PeerNotFound(peer)
+        })?;
+    connecting.await.map_err(|e| {
+        info!("Connecting to peer {} failed: {}", peer, e);
+        IrohUtilsError::PeerUnreachable(peer)
+    })
+}
+
+/// How traffic to a peer travels: straight to one of its addresses, through
+/// a relay, or through a relay while a direct path is tried.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Route {
+    Direct(SocketAddr),
+    Relay(RelayUrl),
+    Mixed(SocketAddr, RelayUrl),
+    /// No connection to the peer, or iroh hasn't picked a path yet
+    Unknown,
+}
+
+impl Route {
+    /// Whether the peer is reached directly at an address on this machine
+    /// or the local network, e.g. one found by mDNS.
+    pub fn is_local(&self) -> bool {
+        matches!(self, Route::Direct(addr) if is_local_ip(addr.ip()))
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Route::Direct(addr) if self.is_local() => {
+                write!(f, "directly on the local network at {}", addr)
+            }
+            Route::Direct(addr) => write!(f, "directly at {}", addr),
+            Route::Relay(url) => write!(f, "through relay {}", url),
+            Route::Mixed(addr, url) => write!(f, "through relay {} while trying {}", url, addr),
+            Route::Unknown => write!(f, "by no known path yet"),
+        }
+    }
+}
+
+/// How `endpoint` reaches `peer` right now.
+pub fn route(endpoint: &Endpoint, peer: PublicKey) -> Route {
+    let Some(mut conn_type) = endpoint.conn_type(peer) else {
+        return Route::Unknown;
+    };
+    match conn_type.get() {
+        ConnectionType::Direct(addr) => Route::Direct(addr),
+        ConnectionType::Relay(url) => Route::Relay(url),
+        ConnectionType::Mixed(addr, url) => Route::Mixed(addr, url),
+        _ => Route::Unknown,
+    }
+}
+
+/// Loopback, private and link-local addresses, which don't leave the LAN.
+fn is_local_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_local_ip(ip.into()),
+            // fc00::/7 is unique local, fe80::/10 link-local.
+            None => {
+                ip.is_loopback()
+                    || ip.segments()[0] & 0xfe00 == 0xfc00
+                    || ip.segments()[0] & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+/// Protocol version spoken on `connection`, as negotiated by ALPN.
+pub fn protocol_version(connection: &Connection) -> u32 {
+    alpn_version(connection.alpn()).unwrap_or(1)
+}
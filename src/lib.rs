@@ -0,0 +1,54 @@
+//! Peer-to-peer file sync over iroh.
+//!
+//! [`SyncServer`] serves local paths to allowed peers and [`SyncClient`]
+//! pulls them, using the same code paths as the `syncr` binary.
+
+mod buffer_budget;
+mod chunking;
+mod cli;
+mod client;
+pub mod config;
+mod control;
+mod heartbeat;
+mod iroh_utils;
+mod listing_cache;
+mod metrics;
+mod path_lock;
+mod poller;
+mod progress;
+pub mod protocol;
+mod rate_limit;
+mod sandbox;
+mod schedule;
+mod server;
+pub mod store;
+mod sync_manager;
+pub mod sync_utils;
+#[cfg(test)]
+mod test_support;
+mod trash;
+mod watcher;
+
+pub use buffer_budget::BufferBudget;
+pub use cli::copy::{
+    Cancelled, CopyOptions, CopyReport, Overwrite, PairReport, ProgressFormat, SyncStats,
+};
+pub use cli::info::{node_info, NodeInfo};
+pub use cli::push::PushReport;
+pub use cli::resync::ResyncReport;
+pub use cli::serve::ServeOptions;
+pub use cli::verify::{FileStatus, VerifyReport};
+pub use client::{pull, PullOptions, SyncClient};
+pub use config::Config;
+pub use iroh_utils::{advertised_alpns, check_remote_id, IrohUtilsError, Route};
+pub use schedule::{ScheduleWindow, TimeOfDay, WindowLimit};
+pub use server::SyncServer;
+pub use store::Store;
+pub use sync_manager::SyncEvent;
+pub use tokio_util::sync::CancellationToken;
+pub use trash::{Retention, TRASH_DIR};
+pub use watcher::EventMask;
+
+/// Entry point of the `syncr` binary.
+#[doc(hidden)]
+pub use cli::run;
@@ -0,0 +1,173 @@
+//! Listings of shared directories, kept between requests until the watcher
+//! reports a change below them.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use tracing::{info, warn};
+
+use crate::{
+    protocol::FileMetadata,
+    watcher::{FileWatcher, RecursiveMode, WatchEvents},
+};
+
+/// Most directories with listings kept at once; others are walked every time.
+const MAX_CACHED_ROOTS: usize = 16;
+
+/// What a listing was made of, besides the tree it lists.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ListingKey {
+    /// Canonical directory walked
+    pub root: PathBuf,
+    /// Wire path entries are listed below
+    pub path: String,
+    pub with_hashes: bool,
+}
+
+/// A kept listing and the generation it was made at.
+#[derive(Debug, Clone)]
+pub struct Listing {
+    pub generation: u64,
+    pub files: Arc<Vec<FileMetadata>>,
+}
+
+/// Listings made by walking directories, valid until something changes
+/// below the directory. Changes are only known once the watcher reports them,
+/// so one made just before a listing may not be in it.
+#[derive(Clone)]
+pub struct ListingCache {
+    inner: Arc<Mutex<Inner>>,
+    /// Listings with fewer entries are cheap to make again
+    min_entries: usize,
+}
+
+struct Inner {
+    /// `None` if there is no watcher to invalidate listings with
+    watcher: Option<FileWatcher>,
+    listings: HashMap<ListingKey, Listing>,
+    /// Generation each watched directory is at
+    roots: HashMap<PathBuf, u64>,
+    next_generation: u64,
+}
+
+impl ListingCache {
+    /// A cache keeping listings of at least `min_entries` entries. Must be
+    /// called within a Tokio runtime.
+    pub fn new(min_entries: usize) -> Self {
+        let mut watcher = FileWatcher::new()
+            .map_err(|e| warn!("Not keeping listings, no watcher: {}", e))
+            .ok();
+        let events = watcher.as_mut().and_then(FileWatcher::take_events);
+        let inner = Arc::new(Mutex::new(Inner {
+            watcher,
+            listings: HashMap::new(),
+            roots: HashMap::new(),
+            // Generations from an earlier run mustn't match ones from this.
+            next_generation: u64::from(rand::random::<u32>()) << 32,
+        }));
+        if let Some(events) = events {
+            tokio::spawn(invalidate_on_change(Arc::downgrade(&inner), events));
+        }
+        Self { inner, min_entries }
+    }
+
+    /// The kept listing for `key`, if nothing changed since it was made.
+    pub fn get(&self, key: &ListingKey) -> Option<Listing> {
+        self.inner.lock().unwrap().listings.get(key).cloned()
+    }
+
+    /// Start watching `root` ahead of walking it. Returns the generation a
+    /// listing made by the walk will have, or `None` if it won't be kept.
+    pub fn begin(&self, root: &Path) -> Option<u64> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(&generation) = inner.roots.get(root) {
+            return Some(generation);
+        }
+        if inner.roots.len() >= MAX_CACHED_ROOTS {
+            return None;
+        }
+        let watcher = inner.watcher.as_mut()?;
+        if let Err(e) = watcher.watch(root, RecursiveMode::Recursive) {
+            info!("Not keeping the listing of {:?}: {}", root, e);
+            return None;
+        }
+        let generation = inner.next_generation;
+        inner.next_generation += 1;
+        inner.roots.insert(root.to_path_buf(), generation);
+        Some(generation)
+    }
+
+    /// Keep `files` as the listing for `key` if it is big enough and nothing
+    /// changed since [`begin`](Self::begin) returned `generation`. Returns
+    /// whether it was kept.
+    pub fn insert(&self, key: ListingKey, generation: u64, files: Vec<FileMetadata>) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if files.len() < self.min_entries {
+            // Nothing else is kept for the root, so stop watching it.
+            if !inner.listings.keys().any(|k| k.root == key.root) {
+                inner.forget(&key.root);
+            }
+            return false;
+        }
+        if inner.roots.get(&key.root) != Some(&generation) {
+            return false;
+        }
+        let files = Arc::new(files);
+        inner.listings.insert(key, Listing { generation, files });
+        true
+    }
+}
+
+impl Inner {
+    /// Drop the listings of `root` and stop watching it.
+    fn forget(&mut self, root: &Path) {
+        self.listings.retain(|key, _| key.root != root);
+        self.roots.remove(root);
+        if let Some(watcher) = self.watcher.as_mut() {
+            let _ = watcher.unwatch(root);
+        }
+    }
+
+    /// Move every watched directory `path` is in, or that is in `path`, to
+    /// a new generation, dropping its listings.
+    fn invalidate(&mut self, path: &Path, removed: bool) {
+        let roots: Vec<PathBuf> = self
+            .roots
+            .keys()
+            .filter(|root| path.starts_with(root) || root.starts_with(path))
+            .cloned()
+            .collect();
+        for root in roots {
+            // A removed directory's watch is gone; the next walk adds it back.
+            if removed && root.starts_with(path) {
+                self.forget(&root);
+                continue;
+            }
+            self.listings.retain(|key, _| key.root != root);
+            let generation = self.next_generation;
+            self.next_generation += 1;
+            self.roots.insert(root, generation);
+        }
+    }
+}
+
+/// Drop listings as the watcher reports changes, until the cache is gone.
+async fn invalidate_on_change(cache: Weak<Mutex<Inner>>, mut events: WatchEvents) {
+    while let Some(event) = events.recv().await {
+        let Some(cache) = cache.upgrade() else {
+            break;
+        };
+        let mut inner = cache.lock().unwrap();
+        match event {
+            Ok(change) => inner.invalidate(&change.path, change.removed),
+            // Changes may have been missed; trust no listing.
+            Err(e) => {
+                warn!("Dropping kept listings: {}", e);
+                let roots: Vec<PathBuf> = inner.roots.keys().cloned().collect();
+                for root in roots {
+                    inner.forget(&root);
+                }
+            }
+        }
+    }
+}
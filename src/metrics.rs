@@ -0,0 +1,143 @@
+//! Counters and gauges of what this process synced, served in the
+//! OpenMetrics text format by `syncr serve --metrics-addr`.
+
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+
+use iroh_metrics::{Counter, Gauge, MetricsGroup, Registry};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{info, warn};
+
+/// Longest request head read before giving up on a scraper.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+/// How long a scraper gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Everything syncr counts. The share of files patched rather than sent
+/// whole is `files_delta / (files_delta + files_full)`.
+#[derive(Debug, Default, MetricsGroup)]
+#[metrics(name = "syncr")]
+pub struct Metrics {
+    /// File, delta and chunk bytes sent to peers
+    pub bytes_sent: Counter,
+    /// File, delta and chunk bytes received from peers
+    pub bytes_received: Counter,
+    /// Files written from a delta against the local copy
+    pub files_delta: Counter,
+    /// Files written from a download of the whole file
+    pub files_full: Counter,
+    /// Peer connections currently open, whichever side opened them
+    pub active_connections: Gauge,
+    /// Filesystem events the watcher passed on
+    pub watcher_events: Counter,
+    /// Change notifications that couldn't be delivered and were queued
+    pub notification_failures: Counter,
+    /// Change notifications queued for peers that couldn't be reached
+    pub pending_notifications: Gauge,
+    /// File data read and waiting to be sent, across every connection
+    pub buffered_bytes: Gauge,
+}
+
+static METRICS: LazyLock<Arc<Metrics>> = LazyLock::new(Default::default);
+
+/// The counters of this process.
+pub fn get() -> &'static Metrics {
+    &METRICS
+}
+
+/// The counters in the OpenMetrics text format.
+pub fn encode() -> String {
+    let mut registry = Registry::default();
+    registry.register(METRICS.clone());
+    let mut text = String::new();
+    // Writing to a String can't fail.
+    let _ = registry.encode_openmetrics_to_writer(&mut text);
+    text
+}
+
+/// Answer `GET /metrics` on `listener` until the task is dropped.
+pub async fn serve(listener: TcpListener) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Serving metrics on http://{}/metrics", addr);
+    }
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Failed to accept a metrics request: {}", e);
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            if let Err(e) = answer(stream).await {
+                warn!("Metrics request failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn answer(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_REQUEST_LEN {
+        let n = tokio::time::timeout(REQUEST_TIMEOUT, stream.read(&mut buf))
+            .await
+            .map_err(|_| std::io::ErrorKind::TimedOut)??;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let request_line = head.split(|&b| b == b'\r').next().unwrap_or_default();
+    let mut parts = request_line.split(|&b| b == b' ');
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => ("200 OK", encode()),
+        _ => ("404 Not Found", "Not found\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{metrics, test_support::Harness};
+
+    #[tokio::test]
+    async fn metrics_count_bytes_sent() {
+        let h = Harness::start().await;
+        let payload = vec![42u8; 300_000];
+        std::fs::write(h.served.join("payload.bin"), &payload).unwrap();
+
+        let before = metrics::get().bytes_sent.get();
+        h.copy(&h.remote("payload.bin"), &h.local.join("payload.bin"))
+            .await
+            .unwrap();
+        // Other tests in this process may send at the same time.
+        assert!(metrics::get().bytes_sent.get() >= before + payload.len() as u64);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(metrics::serve(listener));
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.contains("syncr_bytes_sent"), "{}", response);
+        server.abort();
+        h.stop().await;
+    }
+}
@@ -0,0 +1,218 @@
+//! Advisory locks on local files, so a file isn't patched from the network
+//! and handled as a local change at the same time, and on whole trees, so
+//! pulls into overlapping local paths take turns. Pulls also leave a note of
+//! what they wrote, so the change the watcher then reports isn't sent back
+//! to the peer it came from.
+
+use iroh::PublicKey;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, OwnedMutexGuard};
+
+/// How long after a pull writes a file the watcher may still report it.
+const PULLED_WINDOW: Duration = Duration::from_secs(10);
+
+type Lock = tokio::sync::Mutex<()>;
+
+/// Lock of every path currently held or waited for in this process.
+fn locks() -> &'static Mutex<HashMap<PathBuf, Weak<Lock>>> {
+    static LOCKS: OnceLock<Mutex<HashMap<PathBuf, Weak<Lock>>>> = OnceLock::new();
+    LOCKS.get_or_init(Mutex::default)
+}
+
+/// Holds the lock on a path until dropped.
+pub struct PathGuard {
+    _guard: OwnedMutexGuard<()>,
+}
+
+/// Wait until no one else in this process holds `path`, then hold it. Paths
+/// are compared in canonical form, so different spellings of one file share
+/// a lock, including for files that don't exist yet.
+pub async fn lock(path: &Path) -> PathGuard {
+    let key = canonical_key(path);
+    let lock: Arc<Lock> = {
+        let mut locks = locks().lock().unwrap();
+        // Drop entries no one holds any more, so the map stays small.
+        locks.retain(|_, lock| lock.strong_count() > 0);
+        match locks.get(&key).and_then(Weak::upgrade) {
+            Some(lock) => lock,
+            None => {
+                let lock = Arc::<Lock>::default();
+                locks.insert(key, Arc::downgrade(&lock));
+                lock
+            }
+        }
+    };
+    PathGuard {
+        _guard: lock.lock_owned().await,
+    }
+}
+
+/// Trees currently held by [`lock_tree`], and a way to wake those waiting
+/// for one.
+fn trees() -> &'static (Mutex<Vec<PathBuf>>, Notify) {
+    static TREES: OnceLock<(Mutex<Vec<PathBuf>>, Notify)> = OnceLock::new();
+    TREES.get_or_init(Default::default)
+}
+
+/// Holds the lock on a tree until dropped.
+pub struct TreeGuard {
+    key: PathBuf,
+}
+
+impl Drop for TreeGuard {
+    fn drop(&mut self) {
+        let (held, released) = trees();
+        let mut held = held.lock().unwrap();
+        if let Some(pos) = held.iter().position(|tree| *tree == self.key) {
+            held.swap_remove(pos);
+        }
+        released.notify_waiters();
+    }
+}
+
+/// Wait until no one else in this process holds `path`, a directory
+/// containing it, or anything inside it, then hold it. Paths are compared
+/// in canonical form, as for [`lock`]. Independent of [`lock`], which only
+/// ever covers single files.
+pub async fn lock_tree(path: &Path) -> TreeGuard {
+    let key = canonical_key(path);
+    let (held, released) = trees();
+    loop {
+        // Listen before looking, so a release in between isn't missed.
+        let notified = released.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        {
+            let mut held = held.lock().unwrap();
+            if !held
+                .iter()
+                .any(|tree| tree.starts_with(&key) || key.starts_with(tree))
+            {
+                held.push(key.clone());
+                return TreeGuard { key };
+            }
+        }
+        notified.await;
+    }
+}
+
+/// A file a pull wrote: the peer it came from, its hash and when.
+type Pulled = (PublicKey, [u8; 32], Instant);
+
+fn pulled() -> &'static Mutex<HashMap<PathBuf, Pulled>> {
+    static PULLED: OnceLock<Mutex<HashMap<PathBuf, Pulled>>> = OnceLock::new();
+    PULLED.get_or_init(Mutex::default)
+}
+
+/// Note that a pull from `peer` just wrote `path` with contents hashing to
+/// `hash`.
+pub fn record_pulled(path: &Path, peer: PublicKey, hash: [u8; 32]) {
+    let mut pulled = pulled().lock().unwrap();
+    pulled.retain(|_, (_, _, at)| at.elapsed() < PULLED_WINDOW);
+    pulled.insert(canonical_key(path), (peer, hash, Instant::now()));
+}
+
+/// The peer a pull lately wrote `path` from, and the hash it wrote.
+pub fn pulled_from(path: &Path) -> Option<(PublicKey, [u8; 32])> {
+    let pulled = pulled().lock().unwrap();
+    match pulled.get(&canonical_key(path)) {
+        Some(&(peer, hash, at)) if at.elapsed() < PULLED_WINDOW => Some((peer, hash)),
+        _ => None,
+    }
+}
+
+/// `path` with its parent resolved, falling back to `path` itself if the
+/// parent doesn't exist either.
+fn canonical_key(path: &Path) -> PathBuf {
+    if let Ok(canonical) = std::fs::canonicalize(path) {
+        return canonical;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => std::fs::canonicalize(parent)
+            .map(|parent| parent.join(name))
+            .unwrap_or_else(|_| path.to_path_buf()),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    use crate::path_lock;
+
+    #[tokio::test]
+    async fn spellings_of_one_path_share_a_lock() {
+        let dir = TempDir::new().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        // Doesn't exist yet, as for a file about to be downloaded.
+        let file = dir.path().join("new.txt");
+
+        let held = path_lock::lock(&file).await;
+        let other = dir.path().join("sub/../new.txt");
+        let waiter = tokio::spawn(async move {
+            let _lock = path_lock::lock(&other).await;
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!waiter.is_finished());
+
+        drop(held);
+        tokio::time::timeout(Duration::from_secs(5), waiter)
+            .await
+            .expect("lock was not handed over")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn different_paths_do_not_block_each_other() {
+        let dir = TempDir::new().unwrap();
+        let _a = path_lock::lock(&dir.path().join("a.txt")).await;
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            path_lock::lock(&dir.path().join("b.txt")),
+        )
+        .await
+        .expect("unrelated path was blocked");
+    }
+
+    #[tokio::test]
+    async fn nested_trees_take_turns() {
+        let dir = TempDir::new().unwrap();
+        let outer = dir.path().join("docs");
+        let inner = outer.join("notes");
+        std::fs::create_dir_all(&inner).unwrap();
+
+        for (held, wanted) in [(&outer, &inner), (&inner, &outer)] {
+            let guard = path_lock::lock_tree(held).await;
+            let wanted = wanted.clone();
+            let waiter = tokio::spawn(async move {
+                let _tree = path_lock::lock_tree(&wanted).await;
+            });
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            assert!(!waiter.is_finished());
+
+            drop(guard);
+            tokio::time::timeout(Duration::from_secs(5), waiter)
+                .await
+                .expect("tree was not handed over")
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn sibling_trees_do_not_block_each_other() {
+        let dir = TempDir::new().unwrap();
+        // `docs2` starts with the same characters but isn't inside `docs`.
+        let _docs = path_lock::lock_tree(&dir.path().join("docs")).await;
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            path_lock::lock_tree(&dir.path().join("docs2")),
+        )
+        .await
+        .expect("unrelated tree was blocked");
+    }
+}
@@ -0,0 +1,159 @@
+//! Rescanning watched paths at an interval, for file systems whose changes
+//! never reach the watcher, such as network mounts.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::warn;
+use walkdir::WalkDir;
+
+use crate::{store::WatchOptions, trash::TRASH_DIR};
+
+/// What a scan notes about a file, to tell later whether it changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stamp {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Stamp {
+    fn of(metadata: &std::fs::Metadata) -> Self {
+        Self {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        }
+    }
+}
+
+/// The files below a polled path, by path.
+pub type Snapshot = HashMap<PathBuf, Stamp>;
+
+#[derive(Debug)]
+struct Polled {
+    interval: Duration,
+    recursive: bool,
+    due: Instant,
+    /// What the last scan found; `None` until the first one
+    seen: Option<Snapshot>,
+}
+
+/// The watched paths that are polled, each on its own interval. Changes
+/// the watcher reports are [`record`](Self::record)ed too, so the next scan
+/// doesn't report them again.
+#[derive(Debug, Default)]
+pub struct Poller {
+    paths: HashMap<PathBuf, Polled>,
+}
+
+impl Poller {
+    /// Poll the paths in `wanted` that have an interval, and stop polling
+    /// the rest.
+    pub fn reconcile(&mut self, wanted: &[(PathBuf, WatchOptions)]) {
+        self.paths.retain(|path, _| {
+            wanted
+                .iter()
+                .any(|(wanted, options)| wanted == path && options.poll_interval_secs.is_some())
+        });
+        for (path, options) in wanted {
+            let Some(secs) = options.poll_interval_secs else {
+                continue;
+            };
+            let interval = Duration::from_secs(secs);
+            let polled = self.paths.entry(path.clone()).or_insert_with(|| Polled {
+                interval,
+                recursive: options.recursive,
+                due: Instant::now(),
+                seen: None,
+            });
+            if polled.recursive != options.recursive {
+                polled.seen = None;
+            }
+            polled.interval = interval;
+            polled.recursive = options.recursive;
+        }
+    }
+
+    /// The paths due a scan at `now`, with whether to scan below their top
+    /// level. Each is next due an interval from now.
+    pub fn due(&mut self, now: Instant) -> Vec<(PathBuf, bool)> {
+        let mut due = Vec::new();
+        for (path, polled) in &mut self.paths {
+            if polled.due <= now {
+                polled.due = now + polled.interval;
+                due.push((path.clone(), polled.recursive));
+            }
+        }
+        due
+    }
+
+    /// Take in a scan of `root`, returning the files created, changed or
+    /// removed since the last one. The first scan only sets the baseline.
+    pub fn update(&mut self, root: &Path, scanned: Snapshot) -> Vec<PathBuf> {
+        let Some(polled) = self.paths.get_mut(root) else {
+            return Vec::new();
+        };
+        let previous = polled.seen.replace(scanned);
+        let (Some(seen), Some(scanned)) = (previous, &polled.seen) else {
+            return Vec::new();
+        };
+        let mut changed: Vec<PathBuf> = scanned
+            .iter()
+            .filter(|(path, stamp)| seen.get(*path) != Some(stamp))
+            .map(|(path, _)| path.clone())
+            .chain(
+                seen.keys()
+                    .filter(|path| !scanned.contains_key(*path))
+                    .cloned(),
+            )
+            .collect();
+        changed.sort();
+        changed
+    }
+
+    /// Note `path` as it is now in the scans covering it, as the watcher
+    /// already reported its change.
+    pub fn record(&mut self, path: &Path) {
+        let stamp = std::fs::symlink_metadata(path).ok().filter(|m| !m.is_dir());
+        for (root, polled) in &mut self.paths {
+            let Some(seen) = polled.seen.as_mut().filter(|_| path.starts_with(root)) else {
+                continue;
+            };
+            match &stamp {
+                Some(metadata) => seen.insert(path.to_path_buf(), Stamp::of(metadata)),
+                None => seen.remove(path),
+            };
+        }
+    }
+}
+
+/// The files below `root`, or `root` itself if it is a file, skipping the
+/// trash. Blocks while the tree is read.
+pub fn scan(root: &Path, recursive: bool) -> Snapshot {
+    let mut walk = WalkDir::new(root);
+    if !recursive {
+        walk = walk.max_depth(1);
+    }
+    let mut snapshot = Snapshot::new();
+    for entry in walk
+        .into_iter()
+        .filter_entry(|e| e.file_name() != TRASH_DIR)
+    {
+        let entry = match entry {
+            Ok(entry) => entry,
+            // A missing root is an empty one; it may come back.
+            Err(e) if e.depth() == 0 => break,
+            Err(e) => {
+                warn!("Skipping unreadable entry while polling: {}", e);
+                continue;
+            }
+        };
+        match entry.metadata() {
+            Ok(metadata) if !metadata.is_dir() => {
+                snapshot.insert(entry.into_path(), Stamp::of(&metadata));
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Skipping unreadable entry while polling: {}", e),
+        }
+    }
+    snapshot
+}
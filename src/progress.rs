@@ -0,0 +1,81 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::io::{self, Write};
+use std::sync::OnceLock;
+
+/// Shared draw target for every progress bar so log lines can be printed
+/// above the bars instead of tearing through them.
+fn multi() -> &'static MultiProgress {
+    static MULTI: OnceLock<MultiProgress> = OnceLock::new();
+    MULTI.get_or_init(|| MultiProgress::with_draw_target(ProgressDrawTarget::stderr()))
+}
+
+/// Run `f` with every progress bar hidden, e.g. to prompt on the terminal.
+pub fn suspend<R>(f: impl FnOnce() -> R) -> R {
+    multi().suspend(f)
+}
+
+/// Stderr writer for `tracing` that hides active progress bars while a log
+/// line is written.
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        multi().suspend(|| io::stderr().write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// Progress for a copy: one overall bar counting files plus a bar per file
+/// counting bytes. When disabled every bar is hidden and updates are no-ops.
+#[derive(Clone)]
+pub struct Progress {
+    enabled: bool,
+    overall: ProgressBar,
+}
+
+impl Progress {
+    pub fn new(enabled: bool, total_files: u64) -> Self {
+        let overall = if enabled {
+            let bar = multi().add(ProgressBar::new(total_files));
+            bar.set_style(
+                ProgressStyle::with_template("{prefix:>8} [{bar:30}] {pos}/{len} files")
+                    .expect("valid template")
+                    .progress_chars("=> "),
+            );
+            bar.set_prefix("Total");
+            bar
+        } else {
+            ProgressBar::hidden()
+        };
+        Self { enabled, overall }
+    }
+
+    /// Start a byte-level bar for a single file of `len` bytes.
+    pub fn start_file(&self, name: &str, len: u64) -> ProgressBar {
+        if !self.enabled {
+            return ProgressBar::hidden();
+        }
+        let bar = multi().insert_before(&self.overall, ProgressBar::new(len));
+        bar.set_style(
+            ProgressStyle::with_template(
+                "{msg:40!} [{bar:30}] {bytes}/{total_bytes} ({bytes_per_sec})",
+            )
+            .expect("valid template")
+            .progress_chars("=> "),
+        );
+        bar.set_message(name.to_string());
+        bar
+    }
+
+    pub fn file_done(&self, bar: ProgressBar) {
+        bar.finish_and_clear();
+        self.overall.inc(1);
+    }
+
+    pub fn finish(&self) {
+        self.overall.finish_and_clear();
+    }
+}
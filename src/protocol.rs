@@ -1,10 +1,118 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-pub const ALPN: &[u8] = b"syncr/1";
+use crate::buffer_budget::BufferBudget;
+
+/// ALPN of the newest protocol version, picked whenever both peers speak it.
+pub const ALPN: &[u8] = b"syncr/8";
+
+/// ALPN of the seventh protocol version, whose handshake carries no
+/// capabilities.
+pub const ALPN_V7: &[u8] = b"syncr/7";
+
+/// ALPN of the sixth protocol version, which lacks chunked downloads.
+pub const ALPN_V6: &[u8] = b"syncr/6";
+
+/// ALPN of the fifth protocol version, which can't skip unchanged listings.
+pub const ALPN_V5: &[u8] = b"syncr/5";
+
+/// ALPN of the fourth protocol version, which patches files in one piece.
+pub const ALPN_V4: &[u8] = b"syncr/4";
+
+/// ALPN of the third protocol version, which lacks manifest requests.
+pub const ALPN_V3: &[u8] = b"syncr/3";
+
+/// ALPN of the second protocol version, which lists entries without modes.
+/// The oldest still spoken.
+pub const ALPN_V2: &[u8] = b"syncr/2";
+
+/// Every ALPN we speak, most preferred first. The first version, `syncr/1`,
+/// isn't among them: its messages differ from the ones decoded here, so a
+/// node speaking only that can't connect and has to be upgraded.
+pub const SUPPORTED_ALPNS: &[&[u8]] = &[ALPN, ALPN_V7, ALPN_V6, ALPN_V5, ALPN_V4, ALPN_V3, ALPN_V2];
+
+/// `alpn` as spoken on the isolated network named `network`, e.g.
+/// `syncr/8/staging`, or as it is without one. Nodes only connect over an
+/// ALPN both accept, so nodes on different networks never do.
+pub fn network_alpn(alpn: &[u8], network: Option<&str>) -> Vec<u8> {
+    let mut alpn = alpn.to_vec();
+    if let Some(network) = network {
+        alpn.push(b'/');
+        alpn.extend_from_slice(network.as_bytes());
+    }
+    alpn
+}
+
+/// Most entries the server puts in a single `ListResponse` or
+/// `ManifestResponse`.
+pub const LIST_CHUNK_LEN: usize = 1024;
+
+/// Most paths put in a single `BatchUpdateNotification`.
+pub const NOTIFY_BATCH_LEN: usize = 1024;
+
+/// Smallest client file a `ManifestResponse` suggests patching; smaller ones
+/// cost about as much to sign as to send whole.
+pub const MANIFEST_DELTA_MIN_LEN: u64 = 64 * 1024;
+
+/// Most bytes of a file put in a single `FileData` unless configured
+/// otherwise.
+pub const FILE_CHUNK_LEN: usize = 256 * 1024;
+
+/// Largest chunk that may be configured or received, which bounds the size
+/// of a `FileData` frame.
+pub const MAX_CHUNK_LEN: usize = 16 * 1024 * 1024;
+
+/// Largest frame sent or received: a `FileData` or delta of [`MAX_CHUNK_LEN`]
+/// bytes with plenty to spare, or the manifest or signature of a large tree
+/// or file, which aren't split. Bounds what a peer can make us allocate.
+pub const MAX_FRAME_LEN: usize = 4 * MAX_CHUNK_LEN;
+
+/// Application error code a server closes connections with when it refuses
+/// them, e.g. for exceeding a connection limit.
+pub const REFUSED_CODE: u32 = 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProtocolError {
+    #[error("Peer sent nothing for {0:?}")]
+    Timeout(Duration),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] postcard::Error),
+    #[error("Frame of {0} bytes is over the limit of {MAX_FRAME_LEN}")]
+    FrameTooLarge(usize),
+}
+
+pub type Result<T> = std::result::Result<T, ProtocolError>;
+
+impl ProtocolError {
+    /// Whether the peer simply went away, by finishing the stream or closing
+    /// or losing the connection, rather than sending something broken.
+    pub fn is_disconnect(&self) -> bool {
+        use std::io::ErrorKind;
+        match self {
+            ProtocolError::Io(e) => matches!(
+                e.kind(),
+                ErrorKind::UnexpectedEof
+                    | ErrorKind::NotConnected
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+            ),
+            _ => false,
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Message {
-    Handshake {
+    /// `Handshake` as sent on connections before version 8, without
+    /// capabilities.
+    LegacyHandshake {
         version: u32,
     },
     /// Request to open a path for syncing
@@ -16,10 +124,17 @@ pub enum Message {
     /// Request file list for a specific path (recursive)
     ListRequest {
         path: String,
+        /// Include the BLAKE3 hash of every file in the listing
+        with_hashes: bool,
+        /// List what symlinks point to instead of the links themselves
+        follow_symlinks: bool,
     },
-    /// File list response
-    ListResponse {
-        files: Vec<FileMetadata>,
+    /// `ListResponse` as sent on version 2 connections, whose entries
+    /// carry no mode.
+    LegacyListResponse {
+        files: Vec<LegacyFileMetadata>,
+        /// Whether this is the final part of the listing
+        is_last: bool,
     },
     /// Send file signature (from Receiver to Sender) to request delta
     FileSignature {
@@ -30,17 +145,25 @@ pub enum Message {
     FileDelta {
         path: String,
         delta: Vec<u8>,
+        /// BLAKE3 hash the patched file should have
+        hash: [u8; 32],
     },
-    /// Request full file (if no local copy)
+    /// Request full file (if no local copy), starting at `offset` to resume
+    /// a partial download
     FileRequest {
         path: String,
+        offset: u64,
     },
-    /// Send full file data (simple chunking or full blob for now)
+    /// One chunk of file data. The sender restarts from zero if the requested
+    /// offset is past the end of the file, so `offset` of the first chunk may
+    /// differ from the one requested.
     FileData {
         path: String,
         data: Vec<u8>,
         offset: u64,
         is_last: bool,
+        /// BLAKE3 hash of the whole file, sent with the last chunk
+        hash: Option<[u8; 32]>,
     },
     /// Notification that a file has been updated on the peer
     FileUpdateNotification {
@@ -51,14 +174,706 @@ pub enum Message {
         path: String,
     },
     Error {
+        code: ErrorCode,
         message: String,
     },
+    /// Liveness check on a long-lived connection; answered with `Pong`
+    Ping {
+        nonce: u64,
+    },
+    Pong {
+        nonce: u64,
+    },
+    /// Offer a file for the receiver to write at `path`. Needs write access.
+    FilePush {
+        path: String,
+        /// BLAKE3 hash the file should have once written
+        hash: [u8; 32],
+    },
+    /// Answer to `FilePush`: a signature of the file already at the path to
+    /// send a `FileDelta` against, or `None` to send it whole as `FileData`.
+    /// An unusable delta is answered with a `DeltaFailed` error, after which
+    /// the whole file is expected.
+    FilePushSignature {
+        path: String,
+        signature: Option<Vec<u8>>,
+    },
+    /// The pushed file is in place, or already was
+    FilePushComplete {
+        path: String,
+    },
+    /// Part of the listing for a `ListRequest`. Large trees are split across
+    /// several responses, sent as the server walks them.
+    ListResponse {
+        files: Vec<FileMetadata>,
+        /// Whether this is the final part of the listing
+        is_last: bool,
+    },
+    /// Everything the client has below `path`, listed like a `ListRequest`
+    /// with hashes would list it, for the server to diff against its own
+    /// tree. Only on version 4 connections.
+    ManifestRequest {
+        path: String,
+        files: Vec<FileMetadata>,
+        /// Compare what symlinks point to instead of the links themselves
+        follow_symlinks: bool,
+    },
+    /// Part of the answer to a `ManifestRequest`: only the entries the
+    /// client lacks or has in another form, sent like a `ListResponse`.
+    ManifestResponse {
+        entries: Vec<ManifestEntry>,
+        /// Whether this is the final part of the answer
+        is_last: bool,
+    },
+    /// Like `FileSignature`, for the `len` bytes of the file at `offset`
+    /// only, so a large file is patched a range at a time. Only on version
+    /// 5 connections.
+    FileSignatureRange {
+        path: String,
+        offset: u64,
+        len: u64,
+        signature: Vec<u8>,
+    },
+    /// Answer to `FileSignatureRange`: a delta producing the sender's bytes
+    /// of the same range
+    FileDeltaRange {
+        path: String,
+        offset: u64,
+        delta: Vec<u8>,
+        /// BLAKE3 hash of the whole file, sent once the range reaches its end
+        hash: Option<[u8; 32]>,
+    },
+    /// Like `ListRequest`, but answered with a `ListGeneration` first, and
+    /// with nothing more if the listing is still at generation `since`.
+    /// Only on version 6 connections.
+    ListIfChanged {
+        path: String,
+        with_hashes: bool,
+        follow_symlinks: bool,
+        /// Generation of the listing the client already has
+        since: Option<u64>,
+    },
+    /// Generation of the listing that follows, or of the one the client
+    /// already has when `unchanged`, in which case no `ListResponse` follows.
+    /// A listing the server didn't keep is sent again even if unchanged.
+    ListGeneration {
+        /// `None` if the server can't keep this listing at all
+        generation: Option<u64>,
+        unchanged: bool,
+    },
+    /// Request a whole file as content-defined chunks, answered with
+    /// `FileChunks`, so chunks the client already has needn't be sent. Only
+    /// on connections with the `chunks` capability.
+    ChunkRequest {
+        path: String,
+    },
+    /// The chunks the requested file consists of, in order. The client
+    /// answers with `ChunkWant`.
+    FileChunks {
+        path: String,
+        chunks: Vec<ChunkRef>,
+        /// BLAKE3 hash of the whole file
+        hash: [u8; 32],
+    },
+    /// The chunks of the last `FileChunks` the client lacks, each listed
+    /// once, in the order they first appear in the file. Answered with a
+    /// `ChunkData` for each.
+    ChunkWant {
+        hashes: Vec<[u8; 32]>,
+    },
+    /// The bytes of one wanted chunk
+    ChunkData {
+        hash: [u8; 32],
+        data: Vec<u8>,
+    },
+    /// The first message each side sends on a stream
+    Handshake {
+        version: u32,
+        /// Names of the optional features the sender supports. Only those
+        /// both sides name are used on the connection.
+        capabilities: Vec<String>,
+    },
+    /// Ask whether the path may be synced, i.e. both read and written by
+    /// us, answered with `AccessResult`. Only on connections with the
+    /// `check-access` capability.
+    CheckAccess {
+        path: String,
+    },
+    AccessResult {
+        allowed: bool,
+        /// Why not, if not
+        reason: Option<String>,
+    },
+    /// Ask which paths are shared with us, answered with `Shares`. Only on
+    /// connections with the `shares` capability.
+    ListShares,
+    /// The paths the asking peer has been granted, and nothing about
+    /// anyone else's
+    Shares {
+        shares: Vec<Share>,
+    },
+    /// How many entries of the listing being sent couldn't be read and
+    /// were left out of it. Sent before its last `ListResponse`, and only
+    /// on connections with the `skipped-entries` capability.
+    ListSkipped {
+        count: u64,
+    },
+    /// Like `FileUpdateNotification`, for several paths changed together,
+    /// which the receiver pulls over the connection the notification came
+    /// on. Only on connections with the `batch-notify` capability.
+    BatchUpdateNotification {
+        paths: Vec<String>,
+    },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl Message {
+    /// The handshake advertising `capabilities`, in the form a peer
+    /// speaking `version` reads.
+    pub fn handshake(version: u32, capabilities: &Capabilities) -> Self {
+        if version >= 8 {
+            Message::Handshake {
+                version,
+                capabilities: capabilities.names(),
+            }
+        } else {
+            Message::LegacyHandshake { version }
+        }
+    }
+
+    /// A part of a listing in the form a peer speaking `version` reads.
+    pub fn list_response(files: Vec<FileMetadata>, is_last: bool, version: u32) -> Self {
+        if version >= 3 {
+            Message::ListResponse { files, is_last }
+        } else {
+            Message::LegacyListResponse {
+                files: files.into_iter().map(Into::into).collect(),
+                is_last,
+            }
+        }
+    }
+
+    /// `self`, with a `LegacyListResponse` or `LegacyHandshake` turned into
+    /// the `ListResponse` or `Handshake` it stands for, so readers only need
+    /// to match the latter.
+    pub fn into_current(self) -> Self {
+        match self {
+            Message::LegacyHandshake { version } => Message::Handshake {
+                version,
+                capabilities: Capabilities::implied_by(version).names(),
+            },
+            Message::LegacyListResponse { files, is_last } => Message::ListResponse {
+                files: files.into_iter().map(Into::into).collect(),
+                is_last,
+            },
+            msg => msg,
+        }
+    }
+}
+
+/// Why a request failed, so the other side can decide whether to retry,
+/// fall back, or give up without parsing the message text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// The requested path doesn't exist
+    NotFound,
+    /// The peer lacks permission for the path
+    AccessDenied,
+    /// A delta couldn't be computed from the given signature
+    DeltaFailed,
+    /// The request doesn't make sense for the path, e.g. a directory
+    /// requested as a file
+    InvalidRequest,
+    /// The message isn't handled by this peer
+    Unsupported,
+    /// Anything else that went wrong on the remote side
+    Internal,
+    /// The file changed while it was being read; asking again may succeed.
+    /// Only sent to peers with the `retry` capability.
+    Retry,
+}
+
+/// A `Message::Error` received from the peer.
+#[derive(Debug, thiserror::Error)]
+#[error("Remote error ({code:?}): {message}")]
+pub struct RemoteError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileMetadata {
     pub path: String,
     pub len: u64,
     pub modified: u64, // Unix timestamp
-    pub is_dir: bool,
+    pub file_type: FileType,
+    /// Where a symlink points, or the name a hard link was first listed
+    /// under, in wire form
+    pub link_target: Option<String>,
+    /// BLAKE3 hash of a file's contents, if the listing asked for hashes
+    pub hash: Option<[u8; 32]>,
+    /// Unix permission bits, if the listing peer has them
+    pub mode: Option<u32>,
+}
+
+/// `FileMetadata` as listed on version 2 connections.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LegacyFileMetadata {
+    pub path: String,
+    pub len: u64,
+    pub modified: u64,
+    pub file_type: FileType,
+    pub link_target: Option<String>,
+    pub hash: Option<[u8; 32]>,
+}
+
+impl From<FileMetadata> for LegacyFileMetadata {
+    fn from(file: FileMetadata) -> Self {
+        Self {
+            path: file.path,
+            len: file.len,
+            modified: file.modified,
+            file_type: file.file_type,
+            link_target: file.link_target,
+            hash: file.hash,
+        }
+    }
+}
+
+impl From<LegacyFileMetadata> for FileMetadata {
+    fn from(file: LegacyFileMetadata) -> Self {
+        Self {
+            path: file.path,
+            len: file.len,
+            modified: file.modified,
+            file_type: file.file_type,
+            link_target: file.link_target,
+            hash: file.hash,
+            mode: None,
+        }
+    }
+}
+
+/// An entry of a `ManifestResponse` and what the client should do about it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// The server's entry, or for `Delete` the client's
+    pub file: FileMetadata,
+    pub action: ManifestAction,
+}
+
+/// A path the server shares with the peer that asked, from `Shares`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Share {
+    pub path: String,
+    /// Whether the peer may list and download files below it
+    pub read: bool,
+    /// Whether the peer may register syncs pushing its changes into it
+    pub write: bool,
+}
+
+/// How a client brings one entry of its manifest in line with the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ManifestAction {
+    /// The client has nothing of this type at the path
+    Create,
+    /// The client's file differs; patch it from a signature
+    Delta,
+    /// The client's file differs but is too small to be worth a signature;
+    /// download it whole
+    Full,
+    /// The server has nothing at the path
+    Delete,
+}
+
+/// Downloading files as content-defined chunks, with `ChunkRequest`.
+pub const CAP_CHUNKS: &str = "chunks";
+
+/// Asking whether a path may be synced before syncing it, with
+/// `CheckAccess`.
+pub const CAP_CHECK_ACCESS: &str = "check-access";
+
+/// Asking which paths are shared with us, with `ListShares`.
+pub const CAP_SHARES: &str = "shares";
+
+/// Being told how many entries a listing left out, with `ListSkipped`.
+pub const CAP_SKIPPED_ENTRIES: &str = "skipped-entries";
+
+/// Being told to ask for a file again, with `ErrorCode::Retry`, when it
+/// changed while it was read.
+pub const CAP_RETRY: &str = "retry";
+
+/// Being notified of several changed paths at once, with
+/// `BatchUpdateNotification`.
+pub const CAP_BATCH_NOTIFY: &str = "batch-notify";
+
+/// Optional features a peer supports, as named in its `Handshake`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities(BTreeSet<String>);
+
+impl Capabilities {
+    /// Everything this build supports.
+    pub fn ours() -> Self {
+        Self::from_names([
+            CAP_BATCH_NOTIFY,
+            CAP_CHUNKS,
+            CAP_CHECK_ACCESS,
+            CAP_RETRY,
+            CAP_SHARES,
+            CAP_SKIPPED_ENTRIES,
+        ])
+    }
+
+    /// What a peer speaking `version` supports without saying so, for
+    /// versions whose handshake carries no capabilities.
+    pub fn implied_by(version: u32) -> Self {
+        if version >= 7 {
+            Self::from_names([CAP_CHUNKS])
+        } else {
+            Self::default()
+        }
+    }
+
+    /// Capabilities with these names. Names this build doesn't know are
+    /// kept, so they just never match one of ours.
+    pub fn from_names<I: IntoIterator<Item = S>, S: Into<String>>(names: I) -> Self {
+        Self(names.into_iter().map(Into::into).collect())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.0.iter().cloned().collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.contains(name)
+    }
+
+    /// The capabilities both `self` and `other` have, i.e. those a
+    /// connection between them may use.
+    pub fn intersection(&self, other: &Self) -> Self {
+        Self(self.0.intersection(&other.0).cloned().collect())
+    }
+}
+
+/// A chunk of a file in a `FileChunks`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkRef {
+    /// BLAKE3 hash of the chunk
+    pub hash: [u8; 32],
+    pub len: u32,
+}
+
+/// Kind of entry in a listing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileType {
+    File,
+    Dir,
+    /// A symbolic link, listed as such unless the listing follows links
+    Symlink,
+    /// Another name for a file listed earlier in the same listing, which is
+    /// in `link_target`. Only listed on version 2 connections.
+    HardLink,
+}
+
+/// Protocol version a connection negotiated as `alpn` speaks, or `None` if
+/// it isn't one of ours. Version 2 lists entries without modes, versions before 4 lack manifest
+/// requests, versions before 5 lack ranged signatures, versions before 6
+/// lack listing generations, versions before 7 lack chunked downloads, and
+/// versions before 8 don't exchange capabilities. A network name after the version is ignored.
+pub fn alpn_version(alpn: &[u8]) -> Option<u32> {
+    let mut slashes = alpn
+        .iter()
+        .enumerate()
+        .filter(|(_, &b)| b == b'/')
+        .map(|(i, _)| i);
+    let base = match slashes.nth(1) {
+        Some(end) => &alpn[..end],
+        None => alpn,
+    };
+    match base {
+        ALPN => Some(8),
+        ALPN_V7 => Some(7),
+        ALPN_V6 => Some(6),
+        ALPN_V5 => Some(5),
+        ALPN_V4 => Some(4),
+        ALPN_V3 => Some(3),
+        ALPN_V2 => Some(2),
+        _ => None,
+    }
+}
+
+/// Write `msg` as a length-prefixed postcard frame.
+pub async fn write_message<W: AsyncWriteExt + Unpin>(writer: &mut W, msg: &Message) -> Result<()> {
+    write_frame(writer, msg).await
+}
+
+/// Write any `value` framed like a [`Message`].
+pub async fn write_frame<W: AsyncWriteExt + Unpin, T: Serialize>(
+    writer: &mut W,
+    value: &T,
+) -> Result<()> {
+    let data = postcard::to_stdvec(value)?;
+    if data.len() > MAX_FRAME_LEN {
+        return Err(ProtocolError::FrameTooLarge(data.len()));
+    }
+    writer.write_u32(data.len() as u32).await?;
+    writer.write_all(&data).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Read the next frame, giving up with [`ProtocolError::Timeout`] if the
+/// peer sends nothing for `idle_timeout`.
+pub async fn read_message<R: AsyncReadExt + Unpin>(
+    reader: &mut R,
+    idle_timeout: Duration,
+) -> Result<Message> {
+    read_frame(reader, idle_timeout).await
+}
+
+/// Like [`read_message`], counting the frame against `budget` while it is
+/// read and decoded, so frames arriving on many streams at once can't take
+/// more memory than it allows. Waiting for the budget doesn't count towards
+/// `idle_timeout`.
+pub async fn read_message_within<R: AsyncReadExt + Unpin>(
+    reader: &mut R,
+    idle_timeout: Duration,
+    budget: &BufferBudget,
+) -> Result<Message> {
+    read_frame_within(reader, idle_timeout, Some(budget)).await
+}
+
+/// Read a value written by [`write_frame`], with the timeout of
+/// [`read_message`]. A frame over [`MAX_FRAME_LEN`] is refused before any
+/// of it is read.
+pub async fn read_frame<R: AsyncReadExt + Unpin, T: serde::de::DeserializeOwned>(
+    reader: &mut R,
+    idle_timeout: Duration,
+) -> Result<T> {
+    read_frame_within(reader, idle_timeout, None).await
+}
+
+async fn read_frame_within<R: AsyncReadExt + Unpin, T: serde::de::DeserializeOwned>(
+    reader: &mut R,
+    idle_timeout: Duration,
+    budget: Option<&BufferBudget>,
+) -> Result<T> {
+    let timed_out = |_| ProtocolError::Timeout(idle_timeout);
+    let len = tokio::time::timeout(idle_timeout, reader.read_u32())
+        .await
+        .map_err(timed_out)?? as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(ProtocolError::FrameTooLarge(len));
+    }
+    let _buffered = match budget {
+        Some(budget) => Some(budget.acquire(len).await),
+        None => None,
+    };
+    tokio::time::timeout(idle_timeout, async {
+        let mut buf = vec![0u8; len];
+        reader.read_exact(&mut buf).await?;
+        Ok(postcard::from_bytes(&buf)?)
+    })
+    .await
+    .map_err(timed_out)?
+}
+
+/// Like [`read_message`], but returns `None` if the peer finished the stream
+/// instead of sending another message.
+pub async fn read_message_or_eof<R: AsyncReadExt + Unpin>(
+    reader: &mut R,
+    idle_timeout: Duration,
+) -> Result<Option<Message>> {
+    match read_message(reader, idle_timeout).await {
+        Ok(msg) => Ok(Some(msg)),
+        Err(ProtocolError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Wire form of `path`: components joined by `/`, with a leading `/` if the
+/// path is absolute. Windows drive prefixes are dropped, so peers on any
+/// platform agree on the string for a path.
+///
+/// Names are sent as UTF-8. Bytes that aren't valid UTF-8, and `%` itself,
+/// are percent-encoded so [`from_wire_path`] gets the exact name back.
+pub fn to_wire_path(path: &Path) -> String {
+    let mut wire = String::new();
+    for component in path.components() {
+        let segment = match component {
+            Component::Prefix(_) | Component::CurDir => continue,
+            Component::RootDir => {
+                wire.push('/');
+                continue;
+            }
+            Component::ParentDir => "..".to_string(),
+            Component::Normal(name) => encode_wire_name(name),
+        };
+        if !wire.is_empty() && !wire.ends_with('/') {
+            wire.push('/');
+        }
+        wire.push_str(&segment);
+    }
+    wire
+}
+
+/// `name` as a wire path segment, with `%` and invalid UTF-8 percent-encoded.
+fn encode_wire_name(name: &OsStr) -> String {
+    let mut segment = String::new();
+    for chunk in name.as_encoded_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '%' => segment.push_str("%25"),
+                c => segment.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            segment.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    segment
+}
+
+/// The name a wire path segment stands for. A `%` not followed by two hex
+/// digits is taken literally, as older peers send it unencoded.
+fn decode_wire_name(segment: &str) -> OsString {
+    let bytes = segment.as_bytes();
+    let mut name = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes[i] {
+            b'%' => segment
+                .get(i + 1..i + 3)
+                .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(byte) => {
+                name.push(byte);
+                i += 3;
+            }
+            None => {
+                name.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    os_string_from_bytes(name)
+}
+
+#[cfg(unix)]
+fn os_string_from_bytes(bytes: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes)
+}
+
+/// Elsewhere names must be Unicode, so invalid bytes can't be kept.
+#[cfg(not(unix))]
+fn os_string_from_bytes(bytes: Vec<u8>) -> OsString {
+    String::from_utf8_lossy(&bytes).into_owned().into()
+}
+
+/// Local path for a path received on the wire, `..` segments and all. One
+/// below a local root goes through [`from_wire_relative`] instead.
+pub fn from_wire_path(wire: &str) -> PathBuf {
+    let mut path = PathBuf::new();
+    if wire.starts_with('/') {
+        path.push(std::path::MAIN_SEPARATOR_STR);
+    }
+    for segment in wire.split('/').filter(|s| !s.is_empty()) {
+        path.push(decode_wire_name(segment));
+    }
+    path
+}
+
+/// Local path for `relative`, a wire path below some root, or `None` if it
+/// could lead out of that root: it is absolute, or a segment is `..` or
+/// decodes to a name with a separator in it. An empty `relative` is the root.
+pub fn from_wire_relative(relative: &str) -> Option<PathBuf> {
+    if relative.starts_with('/') {
+        return None;
+    }
+    let mut path = PathBuf::new();
+    for segment in relative.split('/').filter(|s| !s.is_empty()) {
+        let name = decode_wire_name(segment);
+        let mut components = Path::new(&name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(normal)), None) if normal == name.as_os_str() => {
+                path.push(name)
+            }
+            _ => return None,
+        }
+    }
+    Some(path)
+}
+
+/// Wire path of `relative` (itself in wire form) below `base`.
+pub fn join_wire_path(base: &str, relative: &str) -> String {
+    if relative.is_empty() {
+        base.to_string()
+    } else {
+        format!("{}/{}", base.trim_end_matches('/'), relative)
+    }
+}
+
+/// Wire path `path` without trailing slashes, so `/dir/` names the same
+/// entry as `/dir`. The root stays `/`.
+pub fn trim_wire_path(path: &str) -> &str {
+    match path.trim_end_matches('/') {
+        "" if path.starts_with('/') => "/",
+        trimmed => trimmed,
+    }
+}
+
+/// The part of wire path `wire` below `base`, or `None` if it isn't inside
+/// it. `base` itself yields an empty string.
+pub fn strip_wire_prefix<'a>(wire: &'a str, base: &str) -> Option<&'a str> {
+    let base = base.trim_end_matches('/');
+    let rest = wire.strip_prefix(base)?;
+    if rest.is_empty() {
+        Some(rest)
+    } else {
+        rest.strip_prefix('/')
+    }
+}
+
+/// Whether `relative`, a wire path below a sync root, is selected by the
+/// sync's `include` subpaths: it is one of them or inside one. An empty list
+/// selects everything.
+pub fn is_included(relative: &str, include: &[String]) -> bool {
+    include.is_empty()
+        || include
+            .iter()
+            .any(|subpath| strip_wire_prefix(relative, subpath).is_some())
+}
+
+/// Whether `relative`, a wire path below a sync root, is hidden: its name
+/// or that of a directory it is in starts with a dot.
+pub fn is_hidden(relative: &str) -> bool {
+    relative.split('/').any(|name| name.starts_with('.'))
+}
+
+/// Whether `relative` is a directory on the way to one of the `include`
+/// subpaths, which has to exist for them to be created.
+pub fn leads_to_included(relative: &str, include: &[String]) -> bool {
+    relative.is_empty()
+        || include
+            .iter()
+            .any(|subpath| strip_wire_prefix(subpath, relative).is_some())
+}
+
+/// The `include` subpaths as seen from `relative`, for copying just that
+/// part of a sync: empty if everything below it is included, `None` if
+/// nothing is.
+pub fn includes_below(relative: &str, include: &[String]) -> Option<Vec<String>> {
+    if is_included(relative, include) {
+        return Some(Vec::new());
+    }
+    let below: Vec<String> = include
+        .iter()
+        .filter_map(|subpath| strip_wire_prefix(subpath, relative))
+        .filter(|rest| !rest.is_empty())
+        .map(str::to_string)
+        .collect();
+    (!below.is_empty()).then_some(below)
 }
@@ -0,0 +1,81 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Token bucket capping throughput at a fixed number of bytes per second.
+///
+/// Clones share the same bucket, so one limiter can be handed to every task
+/// working on a connection. A limiter created without a rate never waits.
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    bucket: Option<Arc<Mutex<Bucket>>>,
+}
+
+struct Bucket {
+    rate: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: Option<u64>) -> Self {
+        let bucket = bytes_per_sec.filter(|rate| *rate > 0).map(|rate| {
+            Arc::new(Mutex::new(Bucket {
+                rate,
+                tokens: rate as f64,
+                last_refill: Instant::now(),
+            }))
+        });
+        Self { bucket }
+    }
+
+    /// Wait until `bytes` may be sent or received without exceeding the rate.
+    ///
+    /// Requests larger than the bucket put it into debt, which later callers
+    /// pay off by waiting longer, so the long-run average stays at the limit.
+    pub async fn acquire(&self, bytes: usize) {
+        let Some(bucket) = &self.bucket else {
+            return;
+        };
+
+        let wait = {
+            let mut bucket = bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            let rate = bucket.rate as f64;
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+            bucket.last_refill = now;
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Parse a rate such as `500000`, `512K`, `1M` or `2G` into bytes per second.
+/// Suffixes are binary multiples and case-insensitive.
+pub fn parse_rate(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1024),
+        Some('M') => (&s[..s.len() - 1], 1024 * 1024),
+        Some('G') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    let value: u64 = digits
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid rate '{}', expected e.g. 500K or 1M", s))?;
+    if value == 0 {
+        return Err("rate must be greater than zero".to_string());
+    }
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("rate '{}' is too large", s))
+}
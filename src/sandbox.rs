@@ -0,0 +1,140 @@
+//! Confining paths requested by peers to the directories they were granted.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::protocol::from_wire_path;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SandboxError {
+    #[error("{0:?} is outside every allowed root")]
+    OutsideRoots(PathBuf),
+    #[error("Failed to resolve {0:?}: {1}")]
+    Resolve(PathBuf, io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, SandboxError>;
+
+/// Canonical local form of the wire path `path`, provided it lies inside one
+/// of `roots`. Symlinks are resolved first, so a link pointing out of a root
+/// is rejected just like a `..` escape. A path that doesn't exist yet is
+/// resolved through its closest existing ancestor.
+pub fn resolve_within_roots(path: &str, roots: &[PathBuf]) -> Result<PathBuf> {
+    let requested = from_wire_path(path);
+    if !requested.is_absolute() {
+        return Err(SandboxError::OutsideRoots(requested));
+    }
+    let resolved = canonicalize_existing_prefix(&requested)?;
+    let inside = roots
+        .iter()
+        .filter_map(|root| std::fs::canonicalize(root).ok())
+        .any(|root| resolved.starts_with(root));
+    if inside {
+        Ok(resolved)
+    } else {
+        Err(SandboxError::OutsideRoots(requested))
+    }
+}
+
+/// Canonicalize the longest existing ancestor of `path` and append the rest.
+/// The missing part can't contain `..`, as there is nothing to resolve it
+/// against.
+pub fn canonicalize_existing_prefix(path: &Path) -> Result<PathBuf> {
+    let mut existing = path;
+    let mut missing = Vec::new();
+    loop {
+        match std::fs::canonicalize(existing) {
+            Ok(mut resolved) => {
+                resolved.extend(missing.iter().rev());
+                return Ok(resolved);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                match (existing.parent(), existing.file_name()) {
+                    (Some(parent), Some(name)) => {
+                        missing.push(name);
+                        existing = parent;
+                    }
+                    _ => return Err(SandboxError::Resolve(path.to_path_buf(), e)),
+                }
+            }
+            Err(e) => return Err(SandboxError::Resolve(path.to_path_buf(), e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    use super::{resolve_within_roots, SandboxError};
+    use crate::protocol::to_wire_path;
+
+    struct Dirs {
+        root: PathBuf,
+        outside: PathBuf,
+        _tmp: TempDir,
+    }
+
+    fn dirs() -> Dirs {
+        let tmp = TempDir::new().unwrap();
+        let base = std::fs::canonicalize(tmp.path()).unwrap();
+        let root = base.join("root");
+        let outside = base.join("outside");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(root.join("sub/file.txt"), b"inside").unwrap();
+        std::fs::write(outside.join("secret.txt"), b"outside").unwrap();
+        Dirs {
+            root,
+            outside,
+            _tmp: tmp,
+        }
+    }
+
+    #[test]
+    fn path_inside_root_resolves() {
+        let d = dirs();
+        let roots = vec![d.root.clone()];
+
+        let file = d.root.join("sub/file.txt");
+        assert_eq!(
+            resolve_within_roots(&to_wire_path(&file), &roots).unwrap(),
+            file
+        );
+        // Not existing yet is fine as long as it would be inside.
+        let new = d.root.join("sub/new/file.txt");
+        assert_eq!(
+            resolve_within_roots(&to_wire_path(&new), &roots).unwrap(),
+            new
+        );
+    }
+
+    #[test]
+    fn parent_dir_escape_is_rejected() {
+        let d = dirs();
+        let roots = vec![d.root.clone()];
+
+        let escape = format!("{}/sub/../../outside/secret.txt", to_wire_path(&d.root));
+        assert!(matches!(
+            resolve_within_roots(&escape, &roots),
+            Err(SandboxError::OutsideRoots(_))
+        ));
+        let missing_escape = format!("{}/missing/../../outside", to_wire_path(&d.root));
+        assert!(resolve_within_roots(&missing_escape, &roots).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_escape_is_rejected() {
+        let d = dirs();
+        let roots = vec![d.root.clone()];
+        std::os::unix::fs::symlink(&d.outside, d.root.join("link")).unwrap();
+
+        let through_link = d.root.join("link/secret.txt");
+        assert!(matches!(
+            resolve_within_roots(&to_wire_path(&through_link), &roots),
+            Err(SandboxError::OutsideRoots(_))
+        ));
+    }
+}
@@ -0,0 +1,170 @@
+//! Bandwidth limits that change with the time of day, e.g. transferring
+//! freely at night and throttled or not at all during work hours.
+
+use indicatif::HumanBytes;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+use std::time::Duration;
+use tokio::sync::watch;
+
+use crate::{rate_limit, sync_utils};
+
+/// Minutes in a day.
+const DAY_MINUTES: u16 = 24 * 60;
+
+/// A time of day in minutes since midnight, written `HH:MM`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimeOfDay(pub u16);
+
+impl TimeOfDay {
+    pub fn new(hour: u16, minute: u16) -> Option<Self> {
+        (hour < 24 && minute < 60).then_some(Self(hour * 60 + minute))
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 60, self.0 % 60)
+    }
+}
+
+/// Parse a time of day such as `9:30` or `18:00`.
+pub fn parse_time_of_day(s: &str) -> Result<TimeOfDay, String> {
+    let invalid = || format!("invalid time '{}', expected e.g. 09:00 or 18:30", s);
+    let (hour, minute) = s.trim().split_once(':').ok_or_else(invalid)?;
+    let hour = hour.parse().map_err(|_| invalid())?;
+    let minute = minute.parse().map_err(|_| invalid())?;
+    TimeOfDay::new(hour, minute).ok_or_else(invalid)
+}
+
+impl<'de> Deserialize<'de> for TimeOfDay {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        parse_time_of_day(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+/// What transfers may do during a [`ScheduleWindow`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowLimit {
+    /// At most this many bytes per second
+    Rate(u64),
+    /// No transfers at all; they wait for the window to end
+    Paused,
+}
+
+impl<'de> Deserialize<'de> for WindowLimit {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Limit {
+            Bytes(u64),
+            Text(String),
+        }
+
+        match Limit::deserialize(deserializer)? {
+            Limit::Bytes(0) => Err(serde::de::Error::custom("rate must be greater than zero")),
+            Limit::Bytes(bytes) => Ok(Self::Rate(bytes)),
+            Limit::Text(text) if text.trim().eq_ignore_ascii_case("paused") => Ok(Self::Paused),
+            Limit::Text(text) => rate_limit::parse_rate(&text)
+                .map(Self::Rate)
+                .map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// A stretch of each day with its own limit, from `start` up to `end`. It
+/// runs past midnight if `end` comes before `start`, and covers the whole
+/// day if they are equal.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleWindow {
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+    /// A rate such as `"512K"`, or `"paused"`
+    pub limit: WindowLimit,
+}
+
+impl ScheduleWindow {
+    pub fn contains(&self, time: TimeOfDay) -> bool {
+        match self.start.cmp(&self.end) {
+            std::cmp::Ordering::Equal => true,
+            std::cmp::Ordering::Less => self.start <= time && time < self.end,
+            std::cmp::Ordering::Greater => time >= self.start || time < self.end,
+        }
+    }
+}
+
+/// The limit transfers are under at some moment.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActiveLimit {
+    Unlimited,
+    /// Bytes per second
+    Rate(u64),
+    Paused,
+}
+
+impl ActiveLimit {
+    /// Bytes per second transfers may use while they aren't paused.
+    pub fn rate(self) -> Option<u64> {
+        match self {
+            Self::Rate(rate) => Some(rate),
+            Self::Unlimited | Self::Paused => None,
+        }
+    }
+
+    pub fn is_paused(self) -> bool {
+        self == Self::Paused
+    }
+}
+
+impl fmt::Display for ActiveLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unlimited => write!(f, "unlimited"),
+            Self::Rate(rate) => write!(f, "{}/s", HumanBytes(*rate)),
+            Self::Paused => write!(f, "paused"),
+        }
+    }
+}
+
+/// The limit at `time`: that of the first window of `schedule` holding it,
+/// or `base` (bytes per second, `None` for none) outside them all.
+pub fn active_limit(
+    schedule: &[ScheduleWindow],
+    base: Option<u64>,
+    time: TimeOfDay,
+) -> ActiveLimit {
+    let window = schedule.iter().find(|window| window.contains(time));
+    match window.map(|window| window.limit) {
+        Some(WindowLimit::Rate(rate)) => ActiveLimit::Rate(rate),
+        Some(WindowLimit::Paused) => ActiveLimit::Paused,
+        None => base.map_or(ActiveLimit::Unlimited, ActiveLimit::Rate),
+    }
+}
+
+/// The time of day by the local clock. Time zones are only known on unix;
+/// elsewhere this is UTC.
+pub fn now() -> TimeOfDay {
+    let now = sync_utils::unix_timestamp();
+    #[cfg(unix)]
+    {
+        let time = now as libc::time_t;
+        // SAFETY: localtime_r only reads `time` and writes `tm`.
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        if !unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+            return TimeOfDay((tm.tm_hour * 60 + tm.tm_min) as u16);
+        }
+    }
+    TimeOfDay(((now / 60) % u64::from(DAY_MINUTES)) as u16)
+}
+
+/// How long until the next minute starts, when the active limit may change.
+pub fn until_next_minute() -> Duration {
+    Duration::from_secs(60 - sync_utils::unix_timestamp() % 60)
+}
+
+/// Wait until `limit` no longer pauses transfers. Returns right away if it
+/// can't change any more.
+pub async fn wait_unpaused(limit: &mut watch::Receiver<ActiveLimit>) {
+    let _ = limit.wait_for(|limit| !limit.is_paused()).await;
+}
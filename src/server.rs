@@ -0,0 +1,97 @@
+use anyhow::Result;
+use iroh::{Endpoint, EndpointAddr, PublicKey};
+use std::future::Future;
+use tokio::sync::broadcast;
+
+use crate::{
+    buffer_budget::BufferBudget,
+    cli::serve::{self, ServeOptions},
+    config::Config,
+    iroh_utils,
+    store::Store,
+    sync_manager::SyncEvent,
+};
+
+/// Serves paths allowed in a [`Store`] to peers and pushes change
+/// notifications for configured syncs.
+pub struct SyncServer {
+    endpoint: Endpoint,
+    config: Config,
+    store: Store,
+    opts: ServeOptions,
+    events: broadcast::Sender<SyncEvent>,
+    buffers: BufferBudget,
+}
+
+impl SyncServer {
+    /// Bind an endpoint for our identity, ready to accept peers.
+    pub async fn bind(config: Config, store: Store, opts: ServeOptions) -> Result<Self> {
+        let endpoint = iroh_utils::build_endpoint(&config).await?;
+        Ok(Self::from_endpoint(endpoint, config, store, opts))
+    }
+
+    /// Serve on an endpoint set up by the caller. It must accept at least one
+    /// syncr ALPN (see [`protocol::SUPPORTED_ALPNS`](crate::protocol::SUPPORTED_ALPNS)),
+    /// on the network in `config` if it names one (see
+    /// [`advertised_alpns`](crate::advertised_alpns)).
+    pub fn from_endpoint(
+        endpoint: Endpoint,
+        config: Config,
+        store: Store,
+        opts: ServeOptions,
+    ) -> Self {
+        Self {
+            buffers: BufferBudget::new(config.max_buffered),
+            endpoint,
+            config,
+            store,
+            opts,
+            events: SyncEvent::channel(),
+        }
+    }
+
+    /// Peer ID clients connect to.
+    pub fn id(&self) -> PublicKey {
+        self.endpoint.id()
+    }
+
+    /// Addresses clients can currently reach this server at.
+    pub fn addr(&self) -> EndpointAddr {
+        self.endpoint.addr()
+    }
+
+    /// Follow the syncing the server does once running: local changes,
+    /// peers notified, and pulls of their changes. A subscriber that falls
+    /// too far behind misses the oldest events.
+    pub fn subscribe(&self) -> broadcast::Receiver<SyncEvent> {
+        self.events.subscribe()
+    }
+
+    /// The cap on file data read ahead of sending, shared by every
+    /// connection, to see how much is buffered.
+    pub fn buffers(&self) -> BufferBudget {
+        self.buffers.clone()
+    }
+
+    /// Serve until `shutdown` completes.
+    pub async fn run_until(self, shutdown: impl Future<Output = ()>) -> Result<()> {
+        serve::serve(
+            self.endpoint,
+            self.config,
+            self.store,
+            self.opts,
+            self.events,
+            serve::Control {
+                buffers: Some(self.buffers),
+                ..Default::default()
+            },
+            shutdown,
+        )
+        .await
+    }
+
+    /// Serve until Ctrl-C or SIGTERM.
+    pub async fn run(self) -> Result<()> {
+        self.run_until(serve::shutdown_signal()).await
+    }
+}
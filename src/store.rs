@@ -1,7 +1,16 @@
-use iroh::PublicKey;
-use serde::{Deserialize, Serialize};
-use sled::{Db, Tree};
-use std::path::{Path, PathBuf};
+use iroh::{EndpointAddr, PublicKey};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError, Transactional},
+    Db, Tree,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::sandbox;
+use crate::watcher::EventMask;
+use tracing::warn;
 
 #[derive(Debug, thiserror::Error)]
 pub enum StoreError {
@@ -9,53 +18,201 @@ pub enum StoreError {
     DbError(#[from] sled::Error),
     #[error("Serialization error: {0}")]
     SerializationError(#[from] postcard::Error),
+    #[error("Can't decode the entry for {key:?} in {tree}: {source}")]
+    Corrupt {
+        tree: String,
+        key: String,
+        source: postcard::Error,
+    },
     #[error("System error: {0}")]
     SystemError(String),
+    #[error("Database schema v{0} is newer than this version of syncr supports (v{1})")]
+    UnsupportedSchema(u32, u32),
+    #[error("Another syncr process is using the database at {0:?}; is a daemon running?")]
+    Locked(PathBuf),
+    #[error("No group named '{0}'")]
+    UnknownGroup(String),
+    #[error("{0:?} must be an absolute path without `..` below what exists")]
+    InvalidPath(PathBuf),
 }
 
 pub type Result<T> = std::result::Result<T, StoreError>;
 
-#[derive(Clone)]
+impl From<TransactionError<StoreError>> for StoreError {
+    fn from(e: TransactionError<StoreError>) -> Self {
+        match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => StoreError::DbError(e),
+        }
+    }
+}
+
+/// Abort the enclosing transaction with `e`.
+fn abort(e: impl Into<StoreError>) -> ConflictableTransactionError<StoreError> {
+    ConflictableTransactionError::Abort(e.into())
+}
+
+/// Migrations applied in order when opening a database; entry `i` upgrades
+/// the schema from version `i` to `i + 1`.
+const MIGRATIONS: &[fn(&Store) -> Result<()>] = &[
+    // v0 -> v1: permissions gained access modes
+    Store::migrate_legacy_permissions,
+    // v1 -> v2: syncs remember their last result
+    Store::migrate_legacy_syncs,
+    // v2 -> v3: grants may expire
+    Store::migrate_grant_expiry,
+    // v3 -> v4: syncs may be limited to subpaths
+    Store::migrate_sync_includes,
+    // v4 -> v5: watches may be limited to the top level
+    Store::migrate_watch_options,
+    // v5 -> v6: paths are keyed by their canonical form
+    Store::migrate_canonical_keys,
+    // v6 -> v7: syncs remember why they last failed
+    Store::migrate_sync_errors,
+    // v7 -> v8: syncs have a policy for local changes a pull would overwrite
+    Store::migrate_conflict_policy,
+    // v8 -> v9: watches may also be polled
+    Store::migrate_watch_polling,
+    // v9 -> v10: syncs may skip hidden files
+    Store::migrate_sync_hidden,
+    // v10 -> v11: syncs record whether the peer asked for them
+    Store::migrate_sync_origin,
+    // v11 -> v12: syncs pick the kinds of change that set them off
+    Store::migrate_sync_events,
+    // v12 -> v13: syncs may skip files over a size
+    Store::migrate_sync_max_size,
+];
+
+/// Schema version written by this build.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
+
+/// How long opening the database waits for its lock. sled releases the lock
+/// from a background thread, so a store just closed by this process may
+/// still hold it for a moment.
+const LOCK_WAIT: Duration = Duration::from_secs(1);
+const LOCK_RETRY_DELAY: Duration = Duration::from_millis(20);
+
+#[derive(Clone, Debug)]
 pub struct Store {
-    #[allow(dead_code)]
     db: Db,
     watches: Tree,
     permissions: Tree,
+    aliases: Tree,
+    meta: Tree,
+    signatures: Tree,
+    addresses: Tree,
+    /// Change notifications that couldn't be delivered, by peer
+    pending_notifications: Tree,
+    /// Files an unfinished copy or sync already completed, by sync and path
+    checkpoints: Tree,
+    /// Members of each group, by name
+    groups: Tree,
+    /// Grants held by groups, by path
+    group_permissions: Tree,
+    /// Syncs configured into each local path
+    syncs: Tree,
+    /// Latest transfers of each sync, by sync
+    sync_log: Tree,
+    /// Whether listings skip entries that can't be decoded
+    tolerant: bool,
 }
 
 impl Store {
-    pub fn new() -> Result<Self> {
-        let config_dir = dirs::config_dir()
-            .ok_or_else(|| StoreError::SystemError("Could not find config directory".into()))?
-            .join("syncr");
-
-        std::fs::create_dir_all(&config_dir).map_err(|e| StoreError::SystemError(e.to_string()))?;
+    pub fn new(data_dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(data_dir).map_err(|e| StoreError::SystemError(e.to_string()))?;
 
-        let db_path = config_dir.join("db");
-        let db = sled::open(db_path)?;
+        let db = open_db(&data_dir.join("db"))?;
 
         let watches = db.open_tree("watches")?;
         let permissions = db.open_tree("permissions")?;
+        let aliases = db.open_tree("aliases")?;
+        let meta = db.open_tree("meta")?;
+        let signatures = db.open_tree("signatures")?;
+        let addresses = db.open_tree("addresses")?;
+        let pending_notifications = db.open_tree("pending_notifications")?;
+        let checkpoints = db.open_tree("checkpoints")?;
+        let groups = db.open_tree("groups")?;
+        let group_permissions = db.open_tree("group_permissions")?;
+        let syncs = db.open_tree("syncs")?;
+        let sync_log = db.open_tree("sync_log")?;
 
-        Ok(Self {
+        let store = Self {
             db,
             watches,
             permissions,
-        })
+            aliases,
+            meta,
+            signatures,
+            addresses,
+            pending_notifications,
+            checkpoints,
+            groups,
+            group_permissions,
+            syncs,
+            sync_log,
+            tolerant: false,
+        };
+        let version = store.schema_version()?;
+        if version > SCHEMA_VERSION {
+            return Err(StoreError::UnsupportedSchema(version, SCHEMA_VERSION));
+        }
+        store.migrate(version, SCHEMA_VERSION)?;
+
+        Ok(store)
     }
 
-    pub fn add_watch<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+    /// A handle on the same database whose listings log and skip entries
+    /// that can't be decoded instead of failing, so one corrupt entry doesn't
+    /// stop reporting on or cleaning up the rest.
+    pub fn tolerant(&self) -> Store {
+        Store {
+            tolerant: true,
+            ..self.clone()
+        }
+    }
+
+    /// Decode an entry met while listing `tree`, or skip it if it can't be
+    /// and this store is tolerant.
+    fn decode_listed<T: DeserializeOwned>(
+        &self,
+        tree: &str,
+        key: &[u8],
+        bytes: &[u8],
+    ) -> Result<Option<T>> {
+        match decode(tree, key, bytes) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if self.tolerant => {
+                warn!("Skipping an entry: {}", e);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn add_watch<P: AsRef<Path>>(&self, path: P) -> Result<Option<PathBuf>> {
+        self.add_watch_with(path, WatchOptions::default())
+    }
+
+    /// Watch `path` as `options` say, replacing the options of an existing
+    /// watch on it. Returns the watch on an ancestor that already reports
+    /// everything this one would, if there is one; the new watch is kept but
+    /// not installed for as long as that one is there.
+    pub fn add_watch_with<P: AsRef<Path>>(
+        &self,
+        path: P,
+        options: WatchOptions,
+    ) -> Result<Option<PathBuf>> {
         let path = path.as_ref();
-        // Normalize path? For now just store absolute path string
-        let path_str = path.to_string_lossy();
-        self.watches.insert(path_str.as_bytes(), &[])?;
-        Ok(())
+        self.watches
+            .insert(checked_key(path)?, postcard::to_stdvec(&options)?)?;
+        let watches = self.list_watches_with_options()?;
+        Ok(covering_watch(&watches, path, &options).cloned())
     }
 
     pub fn remove_watch<P: AsRef<Path>>(&self, path: P) -> Result<bool> {
-        let path = path.as_ref();
-        let path_str = path.to_string_lossy();
-        let old = self.watches.remove(path_str.as_bytes())?;
+        let old = self.watches.remove(path_to_key(path.as_ref()))?;
         Ok(old.is_some())
     }
 
@@ -63,61 +220,584 @@ impl Store {
         let mut paths = Vec::new();
         for item in self.watches.iter() {
             let (key, _) = item?;
-            let path_str = String::from_utf8(key.to_vec())
-                .map_err(|e| StoreError::SystemError(format!("Invalid path encoding: {}", e)))?;
-            paths.push(PathBuf::from(path_str));
+            paths.push(key_to_path(&key));
         }
         Ok(paths)
     }
 
-    pub fn allow_peer<P: AsRef<Path>>(&self, path: P, peer: PublicKey) -> Result<()> {
+    /// Every watched path along with how it is watched.
+    pub fn list_watches_with_options(&self) -> Result<Vec<(PathBuf, WatchOptions)>> {
+        let mut watches = Vec::new();
+        for item in self.watches.iter() {
+            let (key, value) = item?;
+            if let Some(options) = self.decode_listed("watches", &key, &value)? {
+                watches.push((key_to_path(&key), options));
+            }
+        }
+        Ok(watches)
+    }
+
+    /// Every watch with the top-most watch on an ancestor that already
+    /// reports everything it would, if any, making it redundant.
+    pub fn list_watches_with_cover(&self) -> Result<Vec<(PathBuf, WatchOptions, Option<PathBuf>)>> {
+        let watches = self.list_watches_with_options()?;
+        Ok(watches
+            .iter()
+            .map(|(path, options)| {
+                let cover = covering_watch(&watches, path, options).cloned();
+                (path.clone(), *options, cover)
+            })
+            .collect())
+    }
+
+    /// The watches to install: all but the redundant ones.
+    pub fn list_active_watches(&self) -> Result<Vec<(PathBuf, WatchOptions)>> {
+        Ok(self
+            .list_watches_with_cover()?
+            .into_iter()
+            .filter(|(_, _, cover)| cover.is_none())
+            .map(|(path, options, _)| (path, options))
+            .collect())
+    }
+
+    pub fn allow_peer<P: AsRef<Path>>(
+        &self,
+        path: P,
+        peer: PublicKey,
+        mode: AccessMode,
+    ) -> Result<()> {
+        self.allow_peer_until(path, peer, mode, None)
+    }
+
+    /// Grant `peer` access to `path` until the Unix timestamp `expires_at`,
+    /// or for good if `None`. Replaces any grant the peer already has there.
+    pub fn allow_peer_until<P: AsRef<Path>>(
+        &self,
+        path: P,
+        peer: PublicKey,
+        mode: AccessMode,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
         let path = path.as_ref();
-        let path_key = path.to_string_lossy().as_bytes().to_vec();
+        let path_key = path_to_key(path);
 
         // Load existing permissions
-        let mut allowed: Vec<PublicKey> = match self.permissions.get(&path_key)? {
-            Some(bytes) => postcard::from_bytes(&bytes)?,
+        let mut grants: Vec<Grant> = match self.permissions.get(&path_key)? {
+            Some(bytes) => decode("permissions", &path_key, &bytes)?,
             None => Vec::new(),
         };
 
-        if !allowed.contains(&peer) {
-            allowed.push(peer);
-            let bytes = postcard::to_stdvec(&allowed)?;
-            self.permissions.insert(path_key, bytes)?;
+        let new = Grant {
+            peer,
+            mode,
+            expires_at,
+        };
+        match grants.iter_mut().find(|g| g.peer == peer) {
+            Some(grant) if *grant == new => return Ok(()),
+            Some(grant) => *grant = new,
+            None => grants.push(new),
         }
+        self.permissions
+            .insert(path_key, postcard::to_stdvec(&grants)?)?;
 
         Ok(())
     }
 
     pub fn disallow_peer<P: AsRef<Path>>(&self, path: P, peer: PublicKey) -> Result<()> {
         let path = path.as_ref();
-        let path_key = path.to_string_lossy().as_bytes().to_vec();
+        let path_key = path_to_key(path);
 
-        let mut allowed: Vec<PublicKey> = match self.permissions.get(&path_key)? {
-            Some(bytes) => postcard::from_bytes(&bytes)?,
+        let mut grants: Vec<Grant> = match self.permissions.get(&path_key)? {
+            Some(bytes) => decode("permissions", &path_key, &bytes)?,
             None => return Ok(()),
         };
 
-        if let Some(pos) = allowed.iter().position(|x| *x == peer) {
-            allowed.remove(pos);
-            let bytes = postcard::to_stdvec(&allowed)?;
+        if let Some(pos) = grants.iter().position(|g| g.peer == peer) {
+            grants.remove(pos);
+            let bytes = postcard::to_stdvec(&grants)?;
             self.permissions.insert(path_key, bytes)?;
+            self.prune_syncs_of(peer)?;
         }
 
         Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn get_permissions<P: AsRef<Path>>(&self, path: P) -> Result<Vec<PublicKey>> {
+    /// Remove every grant held by `peer` and take it out of every group,
+    /// returning how many paths it lost its own grants on.
+    pub fn disallow_peer_everywhere(&self, peer: PublicKey) -> Result<usize> {
+        for name in self.groups_of(peer)? {
+            self.remove_group_member(&name, peer)?;
+        }
+        let mut removed = 0;
+        for item in self.permissions.iter() {
+            let (key, value) = item?;
+            let mut grants: Vec<Grant> = decode("permissions", &key, &value)?;
+            let before = grants.len();
+            grants.retain(|g| g.peer != peer);
+            if grants.len() != before {
+                self.permissions
+                    .insert(key, postcard::to_stdvec(&grants)?)?;
+                removed += 1;
+            }
+        }
+        self.prune_syncs_of(peer)?;
+        Ok(removed)
+    }
+
+    pub fn get_permissions<P: AsRef<Path>>(&self, path: P) -> Result<Vec<Grant>> {
         let path = path.as_ref();
-        let path_key = path.to_string_lossy().as_bytes().to_vec();
+        let path_key = path_to_key(path);
 
         match self.permissions.get(&path_key)? {
-            Some(bytes) => Ok(postcard::from_bytes(&bytes)?),
+            Some(bytes) => decode("permissions", &path_key, &bytes),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Subscribe to grants being added, changed or removed through any handle
+    /// on this store, and to group grants and members changing.
+    pub fn watch_permissions(&self) -> Vec<sled::Subscriber> {
+        vec![
+            self.permissions.watch_prefix(Vec::new()),
+            self.group_permissions.watch_prefix(Vec::new()),
+            self.groups.watch_prefix(Vec::new()),
+        ]
+    }
+
+    /// Every permission entry, keyed by path.
+    pub fn list_all_permissions(&self) -> Result<Vec<(PathBuf, Vec<Grant>)>> {
+        let mut entries = Vec::new();
+        for item in self.permissions.iter() {
+            let (key, value) = item?;
+            if let Some(grants) = self.decode_listed("permissions", &key, &value)? {
+                entries.push((key_to_path(&key), grants));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Remove grants that have expired, returning how many were removed.
+    /// Expired grants are ignored by the checks below whether or not they
+    /// have been pruned yet.
+    pub fn prune_expired_grants(&self) -> Result<usize> {
+        let now = crate::sync_utils::unix_timestamp();
+        let mut removed = 0;
+        for item in self.permissions.iter() {
+            let (key, value) = item?;
+            let mut grants: Vec<Grant> = decode("permissions", &key, &value)?;
+            let before = grants.len();
+            grants.retain(|g| !g.is_expired(now));
+            if grants.len() != before {
+                removed += before - grants.len();
+                self.permissions
+                    .insert(key, postcard::to_stdvec(&grants)?)?;
+            }
+        }
+        for item in self.group_permissions.iter() {
+            let (key, value) = item?;
+            let mut grants: Vec<GroupGrant> = decode("group_permissions", &key, &value)?;
+            let before = grants.len();
+            grants.retain(|g| !g.is_expired(now));
+            if grants.len() != before {
+                removed += before - grants.len();
+                self.group_permissions
+                    .insert(key, postcard::to_stdvec(&grants)?)?;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Whether `peer`, or a group it is a member of, holds an unexpired grant
+    /// covering `required` on `path` or on any directory containing it.
+    /// Groups are looked up on every call, so membership changes count
+    /// right away.
+    pub fn is_peer_allowed<P: AsRef<Path>>(
+        &self,
+        path: P,
+        peer: PublicKey,
+        required: AccessMode,
+    ) -> Result<bool> {
+        let now = crate::sync_utils::unix_timestamp();
+        let groups = self.groups_of(peer)?;
+        for ancestor in path.as_ref().ancestors() {
+            let granted = self
+                .get_permissions(ancestor)?
+                .into_iter()
+                .any(|g| g.peer == peer && g.mode.covers(required) && !g.is_expired(now));
+            if granted {
+                return Ok(true);
+            }
+            if groups.is_empty() {
+                continue;
+            }
+            let granted_to_group = self.get_group_permissions(ancestor)?.into_iter().any(|g| {
+                groups.contains(&g.group) && g.mode.covers(required) && !g.is_expired(now)
+            });
+            if granted_to_group {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Whether `peer` holds any unexpired grant, directly or through a group,
+    /// or takes part in any sync.
+    pub fn is_known_peer(&self, peer: PublicKey) -> Result<bool> {
+        let now = crate::sync_utils::unix_timestamp();
+        for (_, grants) in self.list_all_permissions()? {
+            if grants.iter().any(|g| g.peer == peer && !g.is_expired(now)) {
+                return Ok(true);
+            }
+        }
+        let groups = self.groups_of(peer)?;
+        for (_, grants) in self.list_all_group_permissions()? {
+            if grants
+                .iter()
+                .any(|g| groups.contains(&g.group) && !g.is_expired(now))
+            {
+                return Ok(true);
+            }
+        }
+        for (_, configs) in self.list_syncs()? {
+            if configs.iter().any(|c| c.peer == peer) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Schema version the database was last written with. Databases created
+    /// before versioning was introduced are version 0.
+    pub fn schema_version(&self) -> Result<u32> {
+        match self.meta.get(SCHEMA_VERSION_KEY)? {
+            Some(bytes) => {
+                let bytes: [u8; 4] = bytes.as_ref().try_into().map_err(|_| {
+                    StoreError::SystemError("Invalid schema version encoding".to_string())
+                })?;
+                Ok(u32::from_be_bytes(bytes))
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// Run the registered migrations taking the schema from `from` to `to`,
+    /// recording the version after each step so an interrupted upgrade
+    /// resumes where it stopped.
+    fn migrate(&self, from: u32, to: u32) -> Result<()> {
+        for version in from..to {
+            MIGRATIONS[version as usize](self)?;
+            self.meta
+                .insert(SCHEMA_VERSION_KEY, &(version + 1).to_be_bytes())?;
+        }
+        if from != to {
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite permission entries still stored as a bare list of peers,
+    /// which are read as read-write.
+    fn migrate_legacy_permissions(&self) -> Result<()> {
+        for item in self.permissions.iter() {
+            let (key, value) = item?;
+            if postcard::from_bytes::<Vec<GrantV1>>(&value).is_err() {
+                let peers: Vec<PublicKey> = postcard::from_bytes(&value)?;
+                let grants: Vec<GrantV1> = peers
+                    .into_iter()
+                    .map(|peer| GrantV1 {
+                        peer,
+                        mode: AccessMode::ReadWrite,
+                    })
+                    .collect();
+                self.permissions
+                    .insert(key, postcard::to_stdvec(&grants)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrite grants stored without an expiry as grants that never expire.
+    fn migrate_grant_expiry(&self) -> Result<()> {
+        for item in self.permissions.iter() {
+            let (key, value) = item?;
+            let grants: Vec<Grant> = postcard::from_bytes::<Vec<GrantV1>>(&value)?
+                .into_iter()
+                .map(|g| Grant {
+                    peer: g.peer,
+                    mode: g.mode,
+                    expires_at: None,
+                })
+                .collect();
+            self.permissions
+                .insert(key, postcard::to_stdvec(&grants)?)?;
+        }
+        Ok(())
+    }
+
+    /// Create an empty group, returning whether it didn't exist yet.
+    pub fn create_group(&self, name: &str) -> Result<bool> {
+        let members: Vec<PublicKey> = Vec::new();
+        let created = self
+            .groups
+            .compare_and_swap(
+                name.as_bytes(),
+                None::<&[u8]>,
+                Some(postcard::to_stdvec(&members)?),
+            )?
+            .is_ok();
+        Ok(created)
+    }
+
+    /// Remove a group and every grant it holds, returning whether it existed.
+    pub fn delete_group(&self, name: &str) -> Result<bool> {
+        let Some(members) = self.group_members(name)? else {
+            return Ok(false);
+        };
+        self.groups.remove(name.as_bytes())?;
+        for item in self.group_permissions.iter() {
+            let (key, value) = item?;
+            let mut grants: Vec<GroupGrant> = decode("group_permissions", &key, &value)?;
+            let before = grants.len();
+            grants.retain(|g| g.group != name);
+            if grants.len() != before {
+                self.group_permissions
+                    .insert(key, postcard::to_stdvec(&grants)?)?;
+            }
+        }
+        for peer in members {
+            self.prune_syncs_of(peer)?;
+        }
+        Ok(true)
+    }
+
+    /// The members of group `name`, or `None` if there is no such group.
+    pub fn group_members(&self, name: &str) -> Result<Option<Vec<PublicKey>>> {
+        match self.groups.get(name.as_bytes())? {
+            Some(bytes) => Ok(Some(decode("groups", name.as_bytes(), &bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Add `peer` to group `name`, returning whether it wasn't a member yet.
+    pub fn add_group_member(&self, name: &str, peer: PublicKey) -> Result<bool> {
+        let mut members = self
+            .group_members(name)?
+            .ok_or_else(|| StoreError::UnknownGroup(name.to_string()))?;
+        if members.contains(&peer) {
+            return Ok(false);
+        }
+        members.push(peer);
+        self.groups
+            .insert(name.as_bytes(), postcard::to_stdvec(&members)?)?;
+        Ok(true)
+    }
+
+    /// Remove `peer` from group `name`, returning whether it was a member.
+    pub fn remove_group_member(&self, name: &str, peer: PublicKey) -> Result<bool> {
+        let mut members = self
+            .group_members(name)?
+            .ok_or_else(|| StoreError::UnknownGroup(name.to_string()))?;
+        let before = members.len();
+        members.retain(|member| *member != peer);
+        if members.len() == before {
+            return Ok(false);
+        }
+        self.groups
+            .insert(name.as_bytes(), postcard::to_stdvec(&members)?)?;
+        self.prune_syncs_of(peer)?;
+        Ok(true)
+    }
+
+    /// Every group with its members, by name.
+    pub fn list_groups(&self) -> Result<Vec<(String, Vec<PublicKey>)>> {
+        let mut groups = Vec::new();
+        for item in self.groups.iter() {
+            let (key, value) = item?;
+            let name = String::from_utf8(key.to_vec())
+                .map_err(|e| StoreError::SystemError(format!("Invalid group encoding: {}", e)))?;
+            if let Some(members) = self.decode_listed("groups", &key, &value)? {
+                groups.push((name, members));
+            }
+        }
+        Ok(groups)
+    }
+
+    /// The names of the groups `peer` is a member of. A group that can't be
+    /// decoded is skipped, so it only costs its own members their access.
+    pub fn groups_of(&self, peer: PublicKey) -> Result<Vec<String>> {
+        Ok(self
+            .tolerant()
+            .list_groups()?
+            .into_iter()
+            .filter(|(_, members)| members.contains(&peer))
+            .map(|(name, _)| name)
+            .collect())
+    }
+
+    /// Grant every member of group `group` access to `path` until the Unix
+    /// timestamp `expires_at`, or for good if `None`. Replaces any grant the
+    /// group already has there.
+    pub fn allow_group_until<P: AsRef<Path>>(
+        &self,
+        path: P,
+        group: &str,
+        mode: AccessMode,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        if self.group_members(group)?.is_none() {
+            return Err(StoreError::UnknownGroup(group.to_string()));
+        }
+        let path_key = path_to_key(path.as_ref());
+        let mut grants = self.get_group_permissions(path)?;
+        let new = GroupGrant {
+            group: group.to_string(),
+            mode,
+            expires_at,
+        };
+        match grants.iter_mut().find(|g| g.group == group) {
+            Some(grant) if *grant == new => return Ok(()),
+            Some(grant) => *grant = new,
+            None => grants.push(new),
+        }
+        self.group_permissions
+            .insert(path_key, postcard::to_stdvec(&grants)?)?;
+        Ok(())
+    }
+
+    pub fn disallow_group<P: AsRef<Path>>(&self, path: P, group: &str) -> Result<()> {
+        let path_key = path_to_key(path.as_ref());
+        let mut grants = self.get_group_permissions(path)?;
+        if let Some(pos) = grants.iter().position(|g| g.group == group) {
+            grants.remove(pos);
+            self.group_permissions
+                .insert(path_key, postcard::to_stdvec(&grants)?)?;
+            for peer in self.group_members(group)?.unwrap_or_default() {
+                self.prune_syncs_of(peer)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_group_permissions<P: AsRef<Path>>(&self, path: P) -> Result<Vec<GroupGrant>> {
+        let path_key = path_to_key(path.as_ref());
+        match self.group_permissions.get(&path_key)? {
+            Some(bytes) => decode("group_permissions", &path_key, &bytes),
             None => Ok(Vec::new()),
         }
     }
 
+    /// Every group permission entry, keyed by path.
+    pub fn list_all_group_permissions(&self) -> Result<Vec<(PathBuf, Vec<GroupGrant>)>> {
+        let mut entries = Vec::new();
+        for item in self.group_permissions.iter() {
+            let (key, value) = item?;
+            if let Some(grants) = self.decode_listed("group_permissions", &key, &value)? {
+                entries.push((key_to_path(&key), grants));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// The paths `peer` holds grants on, itself or through its groups,
+    /// whether or not they have expired.
+    pub fn granted_roots(&self, peer: PublicKey) -> Result<Vec<PathBuf>> {
+        let mut roots: Vec<PathBuf> = self
+            .list_all_permissions()?
+            .into_iter()
+            .filter(|(_, grants)| grants.iter().any(|g| g.peer == peer))
+            .map(|(root, _)| root)
+            .collect();
+        let groups = self.groups_of(peer)?;
+        if !groups.is_empty() {
+            roots.extend(
+                self.list_all_group_permissions()?
+                    .into_iter()
+                    .filter(|(_, grants)| grants.iter().any(|g| groups.contains(&g.group)))
+                    .map(|(root, _)| root),
+            );
+        }
+        Ok(roots)
+    }
+
+    /// The paths `peer` may access right now, itself or through its groups,
+    /// with everything it may do there, in path order.
+    pub fn shares_of(&self, peer: PublicKey) -> Result<Vec<(PathBuf, AccessMode)>> {
+        let now = crate::sync_utils::unix_timestamp();
+        let mut shares: BTreeMap<PathBuf, AccessMode> = BTreeMap::new();
+        let mut share = |root: PathBuf, mode: AccessMode| {
+            let combined = shares.get(&root).map_or(mode, |held| held.union(mode));
+            shares.insert(root, combined);
+        };
+        for (root, grants) in self.list_all_permissions()? {
+            for grant in grants {
+                if grant.peer == peer && !grant.is_expired(now) {
+                    share(root.clone(), grant.mode);
+                }
+            }
+        }
+        let groups = self.groups_of(peer)?;
+        if !groups.is_empty() {
+            for (root, grants) in self.list_all_group_permissions()? {
+                for grant in grants {
+                    if groups.contains(&grant.group) && !grant.is_expired(now) {
+                        share(root.clone(), grant.mode);
+                    }
+                }
+            }
+        }
+        Ok(shares.into_iter().collect())
+    }
+
+    pub fn add_alias(&self, name: &str, peer: PublicKey) -> Result<()> {
+        self.aliases
+            .insert(name.as_bytes(), postcard::to_stdvec(&peer)?)?;
+        Ok(())
+    }
+
+    pub fn remove_alias(&self, name: &str) -> Result<bool> {
+        let old = self.aliases.remove(name.as_bytes())?;
+        Ok(old.is_some())
+    }
+
+    pub fn resolve_alias(&self, name: &str) -> Result<Option<PublicKey>> {
+        match self.aliases.get(name.as_bytes())? {
+            Some(bytes) => Ok(Some(decode("aliases", name.as_bytes(), &bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn list_aliases(&self) -> Result<Vec<(String, PublicKey)>> {
+        let mut aliases = Vec::new();
+        for item in self.aliases.iter() {
+            let (key, value) = item?;
+            let name = String::from_utf8(key.to_vec())
+                .map_err(|e| StoreError::SystemError(format!("Invalid alias encoding: {}", e)))?;
+            if let Some(peer) = self.decode_listed("aliases", &key, &value)? {
+                aliases.push((name, peer));
+            }
+        }
+        Ok(aliases)
+    }
+
+    /// Remember how to reach a peer directly, e.g. from a ticket, replacing
+    /// any addresses stored for it before.
+    pub fn add_address(&self, addr: &EndpointAddr) -> Result<()> {
+        self.addresses
+            .insert(addr.id.as_bytes(), postcard::to_stdvec(addr)?)?;
+        Ok(())
+    }
+
+    pub fn list_addresses(&self) -> Result<Vec<EndpointAddr>> {
+        let mut addrs = Vec::new();
+        for item in self.addresses.iter() {
+            let (key, value) = item?;
+            if let Some(addr) = self.decode_listed("addresses", &key, &value)? {
+                addrs.push(addr);
+            }
+        }
+        Ok(addrs)
+    }
+
     pub fn add_sync(
         &self,
         peer: PublicKey,
@@ -129,42 +809,1504 @@ impl Store {
         // We probably need a better schema to list all syncs.
         // syncs: <local_path> -> Vec<(Peer, RemotePath)>
         // But for now let's just use a dedicated tree
-        let syncs = self.db.open_tree("syncs")?;
 
         // Let's store by local path so we can lookup when watcher fires
-        let local_key = local_path.to_string_lossy().as_bytes().to_vec();
+        let local_key = checked_key(&local_path)?;
 
-        let mut existing: Vec<SyncConfig> = match syncs.get(&local_key)? {
-            Some(bytes) => postcard::from_bytes(&bytes)?,
+        let mut existing: Vec<SyncConfig> = match self.syncs.get(&local_key)? {
+            Some(bytes) => decode("syncs", &local_key, &bytes)?,
             None => Vec::new(),
         };
 
-        // Dedup?
-        existing.push(SyncConfig { peer, remote_path });
+        if !push_sync_config(&mut existing, peer, remote_path) {
+            return Ok(());
+        }
+
+        self.syncs
+            .insert(local_key, postcard::to_stdvec(&existing)?)?;
+        Ok(())
+    }
+
+    /// Add a sync into `local_path` and a watch on it in one transaction, so a
+    /// crash can't leave a sync that is never watched.
+    pub fn add_sync_with_watch(
+        &self,
+        peer: PublicKey,
+        remote_path: String,
+        local_path: PathBuf,
+    ) -> Result<()> {
+        let local_key = checked_key(&local_path)?;
+
+        let watch = postcard::to_stdvec(&WatchOptions::default())?;
+
+        (&self.watches, &self.syncs).transaction(|(watches, syncs)| {
+            // A watch already set up for the path keeps its options.
+            if watches.get(local_key.as_slice())?.is_none() {
+                watches.insert(local_key.as_slice(), watch.as_slice())?;
+            }
+            let mut existing: Vec<SyncConfig> = match syncs.get(&local_key)? {
+                Some(bytes) => decode("syncs", &local_key, &bytes).map_err(abort)?,
+                None => Vec::new(),
+            };
+            if push_sync_config(&mut existing, peer, remote_path.clone()) {
+                syncs.insert(
+                    local_key.as_slice(),
+                    postcard::to_stdvec(&existing).map_err(abort)?,
+                )?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// Remove the sync of `remote_path` from `peer` into `local_path`, along
+    /// with the watch on `local_path` once no other sync needs it, in one
+    /// transaction. Returns false if no such sync was configured.
+    pub fn remove_sync_with_watch<P: AsRef<Path>>(
+        &self,
+        local_path: P,
+        peer: PublicKey,
+        remote_path: &str,
+    ) -> Result<bool> {
+        let local_key = path_to_key(local_path.as_ref());
+
+        let removed = (&self.watches, &self.syncs).transaction(|(watches, syncs)| {
+            let mut configs: Vec<SyncConfig> = match syncs.get(&local_key)? {
+                Some(bytes) => decode("syncs", &local_key, &bytes).map_err(abort)?,
+                None => return Ok(false),
+            };
+            let before = configs.len();
+            configs.retain(|c| !(c.peer == peer && c.remote_path == remote_path));
+            if configs.len() == before {
+                return Ok(false);
+            }
+            if configs.is_empty() {
+                syncs.remove(local_key.as_slice())?;
+                watches.remove(local_key.as_slice())?;
+            } else {
+                syncs.insert(
+                    local_key.as_slice(),
+                    postcard::to_stdvec(&configs).map_err(abort)?,
+                )?;
+            }
+            Ok(true)
+        })?;
+        if removed {
+            self.sync_log
+                .remove(sync_key(local_path.as_ref(), peer, remote_path)?)?;
+        }
+        Ok(removed)
+    }
+
+    /// Register the sync `peer` asked for with `StartSync`, notifying it of
+    /// changes to `local_path` as `remote_path`, and watch `local_path`. A
+    /// sync already set up here for the same peer and path stays one set up
+    /// here.
+    pub fn add_peer_sync_with_watch(
+        &self,
+        peer: PublicKey,
+        remote_path: String,
+        local_path: PathBuf,
+        include_hidden: bool,
+    ) -> Result<()> {
+        let local_key = checked_key(&local_path)?;
+        let watch = postcard::to_stdvec(&WatchOptions::default())?;
+
+        (&self.watches, &self.syncs).transaction(|(watches, syncs)| {
+            if watches.get(local_key.as_slice())?.is_none() {
+                watches.insert(local_key.as_slice(), watch.as_slice())?;
+            }
+            let mut existing: Vec<SyncConfig> = match syncs.get(&local_key)? {
+                Some(bytes) => decode("syncs", &local_key, &bytes).map_err(abort)?,
+                None => Vec::new(),
+            };
+            let added = push_sync_config(&mut existing, peer, remote_path.clone());
+            if let Some(config) = existing
+                .iter_mut()
+                .find(|c| c.peer == peer && c.remote_path == remote_path)
+            {
+                config.include_hidden = include_hidden;
+                config.requested_by_peer |= added;
+            }
+            syncs.insert(
+                local_key.as_slice(),
+                postcard::to_stdvec(&existing).map_err(abort)?,
+            )?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// Remove the syncs peers asked for whose path is gone or that the peer
+    /// may no longer write to, with their watches and the notifications
+    /// queued for them. Returns what was removed.
+    pub fn prune_peer_syncs(&self) -> Result<Vec<(PathBuf, SyncConfig)>> {
+        self.remove_peer_syncs(|local, config| {
+            Ok(is_gone(local) || !self.is_peer_allowed(local, config.peer, AccessMode::Write)?)
+        })
+    }
 
-        syncs.insert(local_key, postcard::to_stdvec(&existing)?)?;
+    /// Remove the syncs `peer` asked for that it may no longer write to.
+    fn prune_syncs_of(&self, peer: PublicKey) -> Result<()> {
+        self.remove_peer_syncs(|local, config| {
+            Ok(config.peer == peer && !self.is_peer_allowed(local, peer, AccessMode::Write)?)
+        })?;
         Ok(())
     }
 
+    fn remove_peer_syncs(
+        &self,
+        stale: impl Fn(&Path, &SyncConfig) -> Result<bool>,
+    ) -> Result<Vec<(PathBuf, SyncConfig)>> {
+        let mut removed = Vec::new();
+        for (local, configs) in self.list_syncs()? {
+            for config in configs {
+                if !config.requested_by_peer || !stale(&local, &config)? {
+                    continue;
+                }
+                self.remove_sync_with_watch(&local, config.peer, &config.remote_path)?;
+                for path in self.pending_notifications(config.peer)? {
+                    if is_wire_path_below(&path, &config.remote_path) {
+                        self.remove_pending_notification(config.peer, &path)?;
+                    }
+                }
+                removed.push((local.clone(), config));
+            }
+        }
+        Ok(removed)
+    }
+
     pub fn list_syncs(&self) -> Result<Vec<(PathBuf, Vec<SyncConfig>)>> {
-        let syncs = self.db.open_tree("syncs")?;
         let mut results = Vec::new();
-        for item in syncs.iter() {
+        for item in self.syncs.iter() {
             let (key, value) = item?;
-            // Key is bytes of local_path string
-            let path_str = String::from_utf8(key.to_vec())
-                .map_err(|e| StoreError::SystemError(format!("Invalid path key: {}", e)))?;
-            let path = PathBuf::from(path_str);
-
-            let configs: Vec<SyncConfig> = postcard::from_bytes(&value)?;
-            results.push((path, configs));
+            if let Some(configs) = self.decode_listed("syncs", &key, &value)? {
+                results.push((key_to_path(&key), configs));
+            }
         }
         Ok(results)
     }
-}
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct SyncConfig {
-    pub peer: PublicKey,
-    pub remote_path: String,
+    /// A hash of the watches, permissions and syncs, to tell whether two
+    /// stores are set up alike. Sync state such as last-sync hashes is left
+    /// out, as is the order grants and syncs were added in.
+    pub fn fingerprint(&self) -> Result<[u8; 32]> {
+        let mut hasher = blake3::Hasher::new();
+        let mut add = |entry: &[u8]| {
+            hasher.update(&(entry.len() as u64).to_le_bytes());
+            hasher.update(entry);
+        };
+
+        // Trees iterate in key order, so only the entries under one key
+        // need sorting.
+        add(b"watches");
+        for (path, options) in self.list_watches_with_options()? {
+            add(&postcard::to_stdvec(&(path_bytes(&path), options))?);
+        }
+        add(b"permissions");
+        for (path, grants) in self.list_all_permissions()? {
+            let mut entries = Vec::new();
+            for grant in grants {
+                entries.push(postcard::to_stdvec(&(
+                    grant.peer.as_bytes(),
+                    grant.mode,
+                    grant.expires_at,
+                ))?);
+            }
+            entries.sort();
+            add(&postcard::to_stdvec(&(path_bytes(&path), entries))?);
+        }
+        add(b"syncs");
+        for (path, configs) in self.list_syncs()? {
+            let mut entries = Vec::new();
+            for mut config in configs {
+                config.include.sort();
+                entries.push(postcard::to_stdvec(&(
+                    config.peer.as_bytes(),
+                    config.remote_path,
+                    config.include,
+                ))?);
+            }
+            entries.sort();
+            add(&postcard::to_stdvec(&(path_bytes(&path), entries))?);
+        }
+        Ok(*hasher.finalize().as_bytes())
+    }
+
+    /// Watches, syncs and permissions left behind by local paths that no
+    /// longer exist, and permission entries with no grants left. A path
+    /// only counts as gone if looking it up says it doesn't exist; one that
+    /// can't be looked up for another reason is kept.
+    pub fn find_orphans(&self) -> Result<Orphans> {
+        let mut orphans = Orphans::default();
+        for path in self.list_watches()? {
+            if is_gone(&path) {
+                orphans.watches.push(path);
+            }
+        }
+        for (path, configs) in self.list_syncs()? {
+            if is_gone(&path) {
+                orphans.syncs.push((path, configs));
+            }
+        }
+        for (path, grants) in self.list_all_permissions()? {
+            if grants.is_empty() || is_gone(&path) {
+                orphans.permissions.push(path);
+            }
+        }
+        Ok(orphans)
+    }
+
+    /// Remove the entries `find_orphans` reported.
+    pub fn remove_orphans(&self, orphans: &Orphans) -> Result<()> {
+        // The paths came from keys, so they are removed as they are rather
+        // than canonicalized again.
+        for path in &orphans.watches {
+            self.watches.remove(path_bytes(path))?;
+        }
+        for (path, _) in &orphans.syncs {
+            self.syncs.remove(path_bytes(path))?;
+        }
+        for path in &orphans.permissions {
+            self.permissions.remove(path_bytes(path))?;
+        }
+        Ok(())
+    }
+
+    /// Syncs besides the one of `remote_path` from `peer` into `local` that
+    /// could write the same files: ones of that remote path from that peer
+    /// into another local path, and any into a local path containing or
+    /// inside `local`.
+    pub fn overlapping_syncs(
+        &self,
+        local: &Path,
+        peer: PublicKey,
+        remote_path: &str,
+    ) -> Result<Vec<(PathBuf, SyncConfig)>> {
+        let local = canonical_path(local);
+        let local = local.as_path();
+        let mut overlapping = Vec::new();
+        for (root, configs) in self.list_syncs()? {
+            let nested = root.starts_with(local) || local.starts_with(&root);
+            for config in configs {
+                let same_remote = config.peer == peer && config.remote_path == remote_path;
+                if (nested || same_remote) && !(root == local && same_remote) {
+                    overlapping.push((root.clone(), config));
+                }
+            }
+        }
+        Ok(overlapping)
+    }
+
+    /// Remember that the sync of `remote` from `peer` into `local` completed
+    /// at `timestamp`, settling on `hash` if the sync is of a single file.
+    /// A `None` hash keeps the previously recorded one. Returns false if no
+    /// such sync is configured.
+    pub fn record_sync_result<P: AsRef<Path>>(
+        &self,
+        local: P,
+        peer: PublicKey,
+        remote: &str,
+        hash: Option<[u8; 32]>,
+        timestamp: u64,
+    ) -> Result<bool> {
+        let local_key = path_to_key(local.as_ref());
+
+        let mut configs: Vec<SyncConfig> = match self.syncs.get(&local_key)? {
+            Some(bytes) => decode("syncs", &local_key, &bytes)?,
+            None => return Ok(false),
+        };
+        let mut found = false;
+        for config in configs
+            .iter_mut()
+            .filter(|c| c.peer == peer && c.remote_path == remote)
+        {
+            config.last_synced = Some(timestamp);
+            config.last_error = None;
+            if hash.is_some() {
+                config.last_hash = hash;
+            }
+            found = true;
+        }
+        if found {
+            self.syncs
+                .insert(local_key, postcard::to_stdvec(&configs)?)?;
+        }
+        Ok(found)
+    }
+
+    /// Remember that the latest attempt at the sync of `remote` from `peer`
+    /// into `local` failed with `error`, until one completes. Returns false
+    /// if no such sync is configured.
+    pub fn record_sync_failure<P: AsRef<Path>>(
+        &self,
+        local: P,
+        peer: PublicKey,
+        remote: &str,
+        error: &str,
+    ) -> Result<bool> {
+        let local_key = path_to_key(local.as_ref());
+
+        let mut configs: Vec<SyncConfig> = match self.syncs.get(&local_key)? {
+            Some(bytes) => decode("syncs", &local_key, &bytes)?,
+            None => return Ok(false),
+        };
+        let Some(config) = configs
+            .iter_mut()
+            .find(|c| c.peer == peer && c.remote_path == remote)
+        else {
+            return Ok(false);
+        };
+        config.last_error = Some(error.to_string());
+        self.syncs
+            .insert(local_key, postcard::to_stdvec(&configs)?)?;
+        Ok(true)
+    }
+
+    /// Add `entry` to the log of the sync of its remote path from its peer
+    /// into `local`, dropping the oldest entries beyond [`SYNC_LOG_LEN`].
+    /// Returns false if no such sync is configured.
+    pub fn log_sync<P: AsRef<Path>>(&self, local: P, mut entry: SyncLogEntry) -> Result<bool> {
+        let local = local.as_ref();
+        let local_key = path_to_key(local);
+        let configs: Vec<SyncConfig> = match self.syncs.get(&local_key)? {
+            Some(bytes) => decode("syncs", &local_key, &bytes)?,
+            None => return Ok(false),
+        };
+        if !configs
+            .iter()
+            .any(|c| c.peer == entry.peer && c.remote_path == entry.remote_path)
+        {
+            return Ok(false);
+        }
+        entry.changed.truncate(SYNC_LOG_PATHS);
+        let key = sync_key(local, entry.peer, &entry.remote_path)?;
+        let mut entries: Vec<SyncLogEntry> = match self.sync_log.get(&key)? {
+            Some(bytes) => decode("sync_log", &key, &bytes)?,
+            None => Vec::new(),
+        };
+        entries.push(entry);
+        let excess = entries.len().saturating_sub(SYNC_LOG_LEN);
+        entries.drain(..excess);
+        self.sync_log.insert(key, postcard::to_stdvec(&entries)?)?;
+        Ok(true)
+    }
+
+    /// The logged transfers of every sync into `local`, oldest first.
+    pub fn sync_log<P: AsRef<Path>>(&self, local: P) -> Result<Vec<SyncLogEntry>> {
+        // Sync keys start with the length-prefixed local path.
+        let prefix = postcard::to_stdvec(&path_to_key(local.as_ref()))?;
+        let mut entries = Vec::new();
+        for item in self.sync_log.scan_prefix(&prefix) {
+            let (key, value) = item?;
+            let logged: Option<Vec<SyncLogEntry>> = self.decode_listed("sync_log", &key, &value)?;
+            entries.extend(logged.into_iter().flatten());
+        }
+        entries.sort_by_key(|e| e.timestamp);
+        Ok(entries)
+    }
+
+    /// Limit the sync of `remote` from `peer` into `local` to the `include`
+    /// subpaths, or lift the limit with an empty list. Returns false if no
+    /// such sync is configured.
+    pub fn set_sync_include<P: AsRef<Path>>(
+        &self,
+        local: P,
+        peer: PublicKey,
+        remote: &str,
+        include: Vec<String>,
+    ) -> Result<bool> {
+        let local_key = path_to_key(local.as_ref());
+
+        let mut configs: Vec<SyncConfig> = match self.syncs.get(&local_key)? {
+            Some(bytes) => decode("syncs", &local_key, &bytes)?,
+            None => return Ok(false),
+        };
+        let Some(config) = configs
+            .iter_mut()
+            .find(|c| c.peer == peer && c.remote_path == remote)
+        else {
+            return Ok(false);
+        };
+        config.include = include;
+        self.syncs
+            .insert(local_key, postcard::to_stdvec(&configs)?)?;
+        Ok(true)
+    }
+
+    /// Have the sync of `remote` from `peer` into `local` follow `policy`
+    /// when a pull meets local changes. Returns false if no such sync is
+    /// configured.
+    pub fn set_sync_on_conflict<P: AsRef<Path>>(
+        &self,
+        local: P,
+        peer: PublicKey,
+        remote: &str,
+        policy: ConflictPolicy,
+    ) -> Result<bool> {
+        let local_key = path_to_key(local.as_ref());
+
+        let mut configs: Vec<SyncConfig> = match self.syncs.get(&local_key)? {
+            Some(bytes) => decode("syncs", &local_key, &bytes)?,
+            None => return Ok(false),
+        };
+        let Some(config) = configs
+            .iter_mut()
+            .find(|c| c.peer == peer && c.remote_path == remote)
+        else {
+            return Ok(false);
+        };
+        config.on_conflict = policy;
+        self.syncs
+            .insert(local_key, postcard::to_stdvec(&configs)?)?;
+        Ok(true)
+    }
+
+    /// Have the sync of `remote` from `peer` into `local` include hidden
+    /// files, or skip them. Returns false if no such sync is configured.
+    pub fn set_sync_include_hidden<P: AsRef<Path>>(
+        &self,
+        local: P,
+        peer: PublicKey,
+        remote: &str,
+        include_hidden: bool,
+    ) -> Result<bool> {
+        let local_key = path_to_key(local.as_ref());
+
+        let mut configs: Vec<SyncConfig> = match self.syncs.get(&local_key)? {
+            Some(bytes) => decode("syncs", &local_key, &bytes)?,
+            None => return Ok(false),
+        };
+        let Some(config) = configs
+            .iter_mut()
+            .find(|c| c.peer == peer && c.remote_path == remote)
+        else {
+            return Ok(false);
+        };
+        config.include_hidden = include_hidden;
+        self.syncs
+            .insert(local_key, postcard::to_stdvec(&configs)?)?;
+        Ok(true)
+    }
+
+    /// Set the kinds of local change that notify `peer` of the sync of
+    /// `remote` into `local`. Returns whether there is such a sync.
+    pub fn set_sync_events<P: AsRef<Path>>(
+        &self,
+        local: P,
+        peer: PublicKey,
+        remote: &str,
+        events: EventMask,
+    ) -> Result<bool> {
+        let local_key = path_to_key(local.as_ref());
+
+        let mut configs: Vec<SyncConfig> = match self.syncs.get(&local_key)? {
+            Some(bytes) => decode("syncs", &local_key, &bytes)?,
+            None => return Ok(false),
+        };
+        let Some(config) = configs
+            .iter_mut()
+            .find(|c| c.peer == peer && c.remote_path == remote)
+        else {
+            return Ok(false);
+        };
+        config.events = events;
+        self.syncs
+            .insert(local_key, postcard::to_stdvec(&configs)?)?;
+        Ok(true)
+    }
+
+    /// Have the sync of `remote` from `peer` into `local` skip files larger
+    /// than `max_size` bytes, or none with `None`. Returns whether there is
+    /// such a sync.
+    pub fn set_sync_max_size<P: AsRef<Path>>(
+        &self,
+        local: P,
+        peer: PublicKey,
+        remote: &str,
+        max_size: Option<u64>,
+    ) -> Result<bool> {
+        let local_key = path_to_key(local.as_ref());
+
+        let mut configs: Vec<SyncConfig> = match self.syncs.get(&local_key)? {
+            Some(bytes) => decode("syncs", &local_key, &bytes)?,
+            None => return Ok(false),
+        };
+        let Some(config) = configs
+            .iter_mut()
+            .find(|c| c.peer == peer && c.remote_path == remote)
+        else {
+            return Ok(false);
+        };
+        config.max_size = max_size;
+        self.syncs
+            .insert(local_key, postcard::to_stdvec(&configs)?)?;
+        Ok(true)
+    }
+
+    /// Signature cached for `path`, if it was computed from a file matching
+    /// `stamp`.
+    pub fn cached_signature<P: AsRef<Path>>(
+        &self,
+        path: P,
+        stamp: SignatureStamp,
+    ) -> Result<Option<Vec<u8>>> {
+        let path_key = path_to_key(path.as_ref());
+        match self.signatures.get(&path_key)? {
+            // One that can't be decoded is computed afresh.
+            Some(bytes) => {
+                match decode::<(SignatureStamp, Vec<u8>)>("signatures", &path_key, &bytes) {
+                    Ok((cached_stamp, signature)) => {
+                        Ok((cached_stamp == stamp).then_some(signature))
+                    }
+                    Err(e) => {
+                        warn!("Ignoring a cached signature: {}", e);
+                        Ok(None)
+                    }
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Remember the signature computed for `path` as it was at `stamp`.
+    pub fn cache_signature<P: AsRef<Path>>(
+        &self,
+        path: P,
+        stamp: SignatureStamp,
+        signature: &[u8],
+    ) -> Result<()> {
+        let path_key = path_to_key(path.as_ref());
+        let bytes = postcard::to_stdvec(&(stamp, signature))?;
+        self.signatures.insert(path_key, bytes)?;
+        Ok(())
+    }
+
+    /// Drop the cached signature for `path`, e.g. after rewriting the file.
+    pub fn invalidate_signature<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path_key = path_to_key(path.as_ref());
+        self.signatures.remove(path_key)?;
+        Ok(())
+    }
+
+    /// Remember that `peer` couldn't be told about a change to `remote_path`,
+    /// to tell it once it can be reached. A path already waiting for the peer
+    /// is only queued once.
+    pub fn queue_notification(&self, peer: PublicKey, remote_path: &str) -> Result<()> {
+        let mut paths = self.pending_notifications(peer)?;
+        if !paths.iter().any(|p| p == remote_path) {
+            paths.push(remote_path.to_string());
+            self.pending_notifications
+                .insert(peer.as_bytes(), postcard::to_stdvec(&paths)?)?;
+        }
+        Ok(())
+    }
+
+    /// Paths `peer` is still to be notified about, oldest first.
+    pub fn pending_notifications(&self, peer: PublicKey) -> Result<Vec<String>> {
+        match self.pending_notifications.get(peer.as_bytes())? {
+            Some(bytes) => decode("pending_notifications", peer.to_string().as_bytes(), &bytes),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Notifications waiting for `peer` about `remote_path` or anything below
+    /// it.
+    pub fn pending_notifications_below(&self, peer: PublicKey, remote_path: &str) -> Result<usize> {
+        Ok(self
+            .pending_notifications(peer)?
+            .iter()
+            .filter(|path| is_wire_path_below(path, remote_path))
+            .count())
+    }
+
+    /// Peers with notifications waiting for them.
+    pub fn peers_with_pending_notifications(&self) -> Result<Vec<PublicKey>> {
+        let mut peers = Vec::new();
+        for item in self.pending_notifications.iter() {
+            let (key, _) = item?;
+            let bytes: [u8; 32] = key.as_ref().try_into().map_err(|_| {
+                StoreError::SystemError("Invalid peer key in pending notifications".to_string())
+            })?;
+            let peer = PublicKey::from_bytes(&bytes)
+                .map_err(|e| StoreError::SystemError(format!("Invalid peer key: {}", e)))?;
+            peers.push(peer);
+        }
+        Ok(peers)
+    }
+
+    /// Drop `remote_path` from the notifications waiting for `peer`, e.g.
+    /// once it was delivered.
+    pub fn remove_pending_notification(&self, peer: PublicKey, remote_path: &str) -> Result<()> {
+        let mut paths = self.pending_notifications(peer)?;
+        paths.retain(|p| p != remote_path);
+        if paths.is_empty() {
+            self.pending_notifications.remove(peer.as_bytes())?;
+        } else {
+            self.pending_notifications
+                .insert(peer.as_bytes(), postcard::to_stdvec(&paths)?)?;
+        }
+        Ok(())
+    }
+
+    /// Note that the copy of `remote_path` from `peer` into `local_path`
+    /// completed the remote file `path`, leaving the local one at `done`.
+    pub fn checkpoint_file<P: AsRef<Path>>(
+        &self,
+        local_path: P,
+        peer: PublicKey,
+        remote_path: &str,
+        path: &str,
+        done: Checkpointed,
+    ) -> Result<()> {
+        let mut key = sync_key(local_path.as_ref(), peer, remote_path)?;
+        key.extend_from_slice(path.as_bytes());
+        self.checkpoints.insert(key, postcard::to_stdvec(&done)?)?;
+        Ok(())
+    }
+
+    /// The files the copy of `remote_path` from `peer` into `local_path`
+    /// completed before it was interrupted, by remote path.
+    pub fn checkpoint<P: AsRef<Path>>(
+        &self,
+        local_path: P,
+        peer: PublicKey,
+        remote_path: &str,
+    ) -> Result<HashMap<String, Checkpointed>> {
+        let prefix = sync_key(local_path.as_ref(), peer, remote_path)?;
+        let mut done = HashMap::new();
+        for item in self.checkpoints.scan_prefix(&prefix) {
+            let (key, value) = item?;
+            let path = String::from_utf8(key[prefix.len()..].to_vec()).map_err(|e| {
+                StoreError::SystemError(format!("Invalid checkpoint encoding: {}", e))
+            })?;
+            if let Some(checkpointed) = self.decode_listed("checkpoints", &key, &value)? {
+                done.insert(path, checkpointed);
+            }
+        }
+        Ok(done)
+    }
+
+    /// Forget what the copy of `remote_path` from `peer` into `local_path`
+    /// completed, once it has all been.
+    pub fn clear_checkpoint<P: AsRef<Path>>(
+        &self,
+        local_path: P,
+        peer: PublicKey,
+        remote_path: &str,
+    ) -> Result<()> {
+        let prefix = sync_key(local_path.as_ref(), peer, remote_path)?;
+        for key in self.checkpoints.scan_prefix(&prefix).keys() {
+            self.checkpoints.remove(key?)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite sync entries written before syncs tracked their last result.
+    fn migrate_legacy_syncs(&self) -> Result<()> {
+        #[derive(Deserialize)]
+        struct LegacySyncConfig {
+            peer: PublicKey,
+            remote_path: String,
+        }
+
+        for item in self.syncs.iter() {
+            let (key, value) = item?;
+            let legacy: Vec<LegacySyncConfig> = postcard::from_bytes(&value)?;
+            let configs: Vec<SyncConfigV2> = legacy
+                .into_iter()
+                .map(|c| SyncConfigV2 {
+                    peer: c.peer,
+                    remote_path: c.remote_path,
+                    last_synced: None,
+                    last_hash: None,
+                })
+                .collect();
+            self.syncs.insert(key, postcard::to_stdvec(&configs)?)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite sync entries written before syncs could be limited to
+    /// subpaths as syncs of everything.
+    fn migrate_sync_includes(&self) -> Result<()> {
+        for item in self.syncs.iter() {
+            let (key, value) = item?;
+            let configs: Vec<SyncConfigV4> = postcard::from_bytes::<Vec<SyncConfigV2>>(&value)?
+                .into_iter()
+                .map(|c| SyncConfigV4 {
+                    peer: c.peer,
+                    remote_path: c.remote_path,
+                    last_synced: c.last_synced,
+                    last_hash: c.last_hash,
+                    include: Vec::new(),
+                })
+                .collect();
+            self.syncs.insert(key, postcard::to_stdvec(&configs)?)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite watches stored without options, which were all recursive.
+    fn migrate_watch_options(&self) -> Result<()> {
+        let options = postcard::to_stdvec(&WatchOptionsV5 { recursive: true })?;
+        for item in self.watches.iter() {
+            let (key, _) = item?;
+            self.watches.insert(key, options.as_slice())?;
+        }
+        Ok(())
+    }
+
+    /// Move entries stored under a path as it was given, e.g. through a
+    /// symlink, to the canonical path, merging them with any entry already
+    /// there.
+    fn migrate_canonical_keys(&self) -> Result<()> {
+        rekey_canonical(&self.watches, |existing, _| Ok(existing.to_vec()))?;
+        rekey_canonical(&self.permissions, |existing, moved| {
+            let mut grants: Vec<Grant> = postcard::from_bytes(existing)?;
+            for grant in postcard::from_bytes::<Vec<Grant>>(moved)? {
+                if !grants.iter().any(|g| g.peer == grant.peer) {
+                    grants.push(grant);
+                }
+            }
+            Ok(postcard::to_stdvec(&grants)?)
+        })?;
+        rekey_canonical(&self.syncs, |existing, moved| {
+            let mut configs: Vec<SyncConfigV4> = postcard::from_bytes(existing)?;
+            for config in postcard::from_bytes::<Vec<SyncConfigV4>>(moved)? {
+                if !configs
+                    .iter()
+                    .any(|c| c.peer == config.peer && c.remote_path == config.remote_path)
+                {
+                    configs.push(config);
+                }
+            }
+            Ok(postcard::to_stdvec(&configs)?)
+        })?;
+        rekey_canonical(&self.signatures, |existing, _| Ok(existing.to_vec()))?;
+        Ok(())
+    }
+
+    /// Rewrite sync entries written before failures were recorded as syncs
+    /// that haven't failed.
+    fn migrate_sync_errors(&self) -> Result<()> {
+        for item in self.syncs.iter() {
+            let (key, value) = item?;
+            let configs: Vec<SyncConfigV7> = postcard::from_bytes::<Vec<SyncConfigV4>>(&value)?
+                .into_iter()
+                .map(|c| SyncConfigV7 {
+                    peer: c.peer,
+                    remote_path: c.remote_path,
+                    last_synced: c.last_synced,
+                    last_hash: c.last_hash,
+                    include: c.include,
+                    last_error: None,
+                })
+                .collect();
+            self.syncs.insert(key, postcard::to_stdvec(&configs)?)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite sync entries written before conflict policies with the one
+    /// they followed, writing the incoming version to a conflict copy.
+    fn migrate_conflict_policy(&self) -> Result<()> {
+        for item in self.syncs.iter() {
+            let (key, value) = item?;
+            let configs: Vec<SyncConfigV8> = postcard::from_bytes::<Vec<SyncConfigV7>>(&value)?
+                .into_iter()
+                .map(|c| SyncConfigV8 {
+                    peer: c.peer,
+                    remote_path: c.remote_path,
+                    last_synced: c.last_synced,
+                    last_hash: c.last_hash,
+                    include: c.include,
+                    last_error: c.last_error,
+                    on_conflict: ConflictPolicy::ConflictCopy,
+                })
+                .collect();
+            self.syncs.insert(key, postcard::to_stdvec(&configs)?)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite watches stored before polling as watches that rely on file
+    /// system notifications alone.
+    fn migrate_watch_polling(&self) -> Result<()> {
+        for item in self.watches.iter() {
+            let (key, value) = item?;
+            let old: WatchOptionsV5 = postcard::from_bytes(&value)?;
+            let options = WatchOptions {
+                recursive: old.recursive,
+                ..Default::default()
+            };
+            self.watches.insert(key, postcard::to_stdvec(&options)?)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite sync entries written before hidden files could be skipped as
+    /// syncs that include them, as they always did.
+    fn migrate_sync_hidden(&self) -> Result<()> {
+        for item in self.syncs.iter() {
+            let (key, value) = item?;
+            let configs: Vec<SyncConfigV10> = postcard::from_bytes::<Vec<SyncConfigV8>>(&value)?
+                .into_iter()
+                .map(|c| SyncConfigV10 {
+                    peer: c.peer,
+                    remote_path: c.remote_path,
+                    last_synced: c.last_synced,
+                    last_hash: c.last_hash,
+                    include: c.include,
+                    last_error: c.last_error,
+                    on_conflict: c.on_conflict,
+                    include_hidden: true,
+                })
+                .collect();
+            self.syncs.insert(key, postcard::to_stdvec(&configs)?)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite sync entries written before syncs recorded who asked for
+    /// them as syncs set up here, so they are never pruned with the peer's
+    /// access.
+    fn migrate_sync_origin(&self) -> Result<()> {
+        for item in self.syncs.iter() {
+            let (key, value) = item?;
+            let configs: Vec<SyncConfigV11> = postcard::from_bytes::<Vec<SyncConfigV10>>(&value)?
+                .into_iter()
+                .map(|c| SyncConfigV11 {
+                    peer: c.peer,
+                    remote_path: c.remote_path,
+                    last_synced: c.last_synced,
+                    last_hash: c.last_hash,
+                    include: c.include,
+                    last_error: c.last_error,
+                    on_conflict: c.on_conflict,
+                    include_hidden: c.include_hidden,
+                    requested_by_peer: false,
+                })
+                .collect();
+            self.syncs.insert(key, postcard::to_stdvec(&configs)?)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite sync entries written before syncs picked the changes that set
+    /// them off as set off by any change, as they were.
+    fn migrate_sync_events(&self) -> Result<()> {
+        for item in self.syncs.iter() {
+            let (key, value) = item?;
+            let configs: Vec<SyncConfigV12> = postcard::from_bytes::<Vec<SyncConfigV11>>(&value)?
+                .into_iter()
+                .map(|c| SyncConfigV12 {
+                    peer: c.peer,
+                    remote_path: c.remote_path,
+                    last_synced: c.last_synced,
+                    last_hash: c.last_hash,
+                    include: c.include,
+                    last_error: c.last_error,
+                    on_conflict: c.on_conflict,
+                    include_hidden: c.include_hidden,
+                    requested_by_peer: c.requested_by_peer,
+                    events: EventMask::ALL,
+                })
+                .collect();
+            self.syncs.insert(key, postcard::to_stdvec(&configs)?)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite sync entries written before syncs could skip large files as
+    /// syncs that pull files of any size.
+    fn migrate_sync_max_size(&self) -> Result<()> {
+        for item in self.syncs.iter() {
+            let (key, value) = item?;
+            let configs: Vec<SyncConfig> = postcard::from_bytes::<Vec<SyncConfigV12>>(&value)?
+                .into_iter()
+                .map(|c| SyncConfig {
+                    peer: c.peer,
+                    remote_path: c.remote_path,
+                    last_synced: c.last_synced,
+                    last_hash: c.last_hash,
+                    include: c.include,
+                    last_error: c.last_error,
+                    on_conflict: c.on_conflict,
+                    include_hidden: c.include_hidden,
+                    requested_by_peer: c.requested_by_peer,
+                    events: c.events,
+                    max_size: None,
+                })
+                .collect();
+            self.syncs.insert(key, postcard::to_stdvec(&configs)?)?;
+        }
+        Ok(())
+    }
+}
+
+/// Move every entry of `tree` whose key isn't canonical to the canonical key,
+/// combining it with an entry already there through
+/// `merge(existing, moved)`.
+fn rekey_canonical(tree: &Tree, merge: impl Fn(&[u8], &[u8]) -> Result<Vec<u8>>) -> Result<()> {
+    let entries = tree.iter().collect::<sled::Result<Vec<_>>>()?;
+    for (key, value) in entries {
+        let canonical = path_to_key(&key_to_path(&key));
+        if canonical == key.as_ref() {
+            continue;
+        }
+        let merged = match tree.get(&canonical)? {
+            Some(existing) => merge(&existing, &value)?,
+            None => value.to_vec(),
+        };
+        tree.insert(canonical, merged)?;
+        tree.remove(key)?;
+    }
+    Ok(())
+}
+
+/// Add a new sync of `remote_path` from `peer` to `configs`, unless it's
+/// already there. Returns whether it was added.
+fn push_sync_config(configs: &mut Vec<SyncConfig>, peer: PublicKey, remote_path: String) -> bool {
+    if configs
+        .iter()
+        .any(|c| c.peer == peer && c.remote_path == remote_path)
+    {
+        return false;
+    }
+    configs.push(SyncConfig {
+        peer,
+        remote_path,
+        last_synced: None,
+        last_hash: None,
+        include: Vec::new(),
+        last_error: None,
+        on_conflict: ConflictPolicy::default(),
+        include_hidden: false,
+        requested_by_peer: false,
+        events: EventMask::ALL,
+        max_size: None,
+    });
+    true
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SyncConfig {
+    pub peer: PublicKey,
+    pub remote_path: String,
+    /// Unix timestamp of the last completed sync
+    pub last_synced: Option<u64>,
+    /// BLAKE3 hash the local file had after the last completed sync, for
+    /// single-file syncs
+    pub last_hash: Option<[u8; 32]>,
+    /// Wire paths relative to `remote_path` the sync is limited to; empty
+    /// means everything
+    pub include: Vec<String>,
+    /// Why the last attempt to sync failed, until one completes again
+    pub last_error: Option<String>,
+    /// What a pull does with a local file changed since the last sync
+    pub on_conflict: ConflictPolicy,
+    /// Whether files and directories whose names start with a dot are
+    /// synced too
+    pub include_hidden: bool,
+    /// Whether the peer registered the sync with `StartSync` rather than it
+    /// being set up here. Such syncs go once the peer can no longer write to
+    /// the path or the path is gone.
+    pub requested_by_peer: bool,
+    /// Kinds of local change that notify the peer
+    pub events: EventMask,
+    /// Files larger than this many bytes are neither pulled nor notified
+    /// about
+    pub max_size: Option<u64>,
+}
+
+/// Transfers kept in the log of each sync; older ones are dropped.
+pub const SYNC_LOG_LEN: usize = 50;
+
+/// Changed paths kept with each logged transfer.
+pub const SYNC_LOG_PATHS: usize = 20;
+
+/// One transfer of a sync, as `syncr log` shows it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SyncLogEntry {
+    pub peer: PublicKey,
+    pub remote_path: String,
+    /// Unix timestamp the transfer ended at
+    pub timestamp: u64,
+    pub direction: Direction,
+    /// Delta and file data sent or received
+    pub bytes: u64,
+    /// Files written
+    pub files: u64,
+    /// The first [`SYNC_LOG_PATHS`] files written: local paths for a pull,
+    /// remote ones for a push
+    pub changed: Vec<String>,
+    /// Incoming files written beside a locally changed one instead of over it
+    pub conflicts: u64,
+    pub outcome: SyncOutcome,
+}
+
+impl SyncLogEntry {
+    /// A transfer of the sync of `remote_path` from `peer` that failed with
+    /// `error` just now.
+    pub fn failed(
+        peer: PublicKey,
+        remote_path: &str,
+        direction: Direction,
+        error: impl Into<String>,
+    ) -> Self {
+        SyncLogEntry {
+            peer,
+            remote_path: remote_path.to_string(),
+            timestamp: crate::sync_utils::unix_timestamp(),
+            direction,
+            bytes: 0,
+            files: 0,
+            changed: Vec::new(),
+            conflicts: 0,
+            outcome: SyncOutcome::Failed(error.into()),
+        }
+    }
+}
+
+/// Which way a logged transfer went.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the peer into the local path
+    Pull,
+    /// From the local path to the peer
+    Push,
+}
+
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Direction::Pull => "pull",
+            Direction::Push => "push",
+        })
+    }
+}
+
+/// How a logged transfer ended.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum SyncOutcome {
+    Completed,
+    Failed(String),
+}
+
+/// What a pull does when the local file it would overwrite was changed
+/// since the last sync.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Overwrite it
+    SourceWins,
+    /// Keep whichever of the two was modified last
+    NewerWins,
+    /// Keep it and leave the incoming version out
+    Skip,
+    /// Keep it and write the incoming version to a `.conflict-<peer>` file
+    /// beside it
+    #[default]
+    ConflictCopy,
+}
+
+impl std::fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ConflictPolicy::SourceWins => "source-wins",
+            ConflictPolicy::NewerWins => "newer-wins",
+            ConflictPolicy::Skip => "skip",
+            ConflictPolicy::ConflictCopy => "conflict-copy",
+        })
+    }
+}
+
+/// How a sync is doing, as shown by `syncr status`. Ordered from healthy to
+/// failing.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SyncHealth {
+    Ok,
+    /// Changes are waiting for the peer, and the sync hasn't completed for
+    /// longer than it should have
+    Stale,
+    /// The last attempt failed
+    Failing,
+}
+
+impl std::fmt::Display for SyncHealth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SyncHealth::Ok => "ok",
+            SyncHealth::Stale => "stale",
+            SyncHealth::Failing => "failing",
+        })
+    }
+}
+
+impl SyncConfig {
+    /// The health of this sync at `now`, with `pending` notifications still
+    /// to deliver for it. It turns stale once changes have waited on a sync
+    /// that hasn't completed within `stale_after` seconds.
+    pub fn health(&self, pending: usize, now: u64, stale_after: u64) -> SyncHealth {
+        if self.last_error.is_some() {
+            return SyncHealth::Failing;
+        }
+        let overdue = self
+            .last_synced
+            .is_none_or(|synced| now.saturating_sub(synced) > stale_after);
+        if pending > 0 && overdue {
+            SyncHealth::Stale
+        } else {
+            SyncHealth::Ok
+        }
+    }
+}
+
+/// How a path is watched.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchOptions {
+    /// Watch everything below a directory, not just its direct entries
+    pub recursive: bool,
+    /// Also rescan the path this often, in seconds, for changes the file
+    /// system didn't report, as happens on network file systems
+    pub poll_interval_secs: Option<u64>,
+    /// Listen for file system notifications. Only off for polled paths
+    /// where they never arrive.
+    pub notify: bool,
+}
+
+impl WatchOptions {
+    /// Whether a watch with these options reports every change below it that
+    /// a watch on a path below it, with `other`, would.
+    pub fn covers(&self, other: &WatchOptions) -> bool {
+        let polled_enough = match (other.poll_interval_secs, self.poll_interval_secs) {
+            (None, _) => true,
+            (Some(theirs), Some(ours)) => ours <= theirs,
+            (Some(_), None) => false,
+        };
+        self.recursive && (self.notify || !other.notify) && polled_enough
+    }
+}
+
+/// The top-most of `watches` on an ancestor of `path` that covers a watch
+/// on it with `options`.
+fn covering_watch<'a>(
+    watches: &'a [(PathBuf, WatchOptions)],
+    path: &Path,
+    options: &WatchOptions,
+) -> Option<&'a PathBuf> {
+    watches
+        .iter()
+        .filter(|(root, root_options)| {
+            root != path && path.starts_with(root) && root_options.covers(options)
+        })
+        .map(|(root, _)| root)
+        .min_by_key(|root| root.components().count())
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        Self {
+            recursive: true,
+            poll_interval_secs: None,
+            notify: true,
+        }
+    }
+}
+
+/// Watch options as stored by schema v5 to v8, before polling.
+#[derive(Serialize, Deserialize)]
+struct WatchOptionsV5 {
+    recursive: bool,
+}
+
+/// A sync as stored by schema v12, before syncs could skip large files.
+#[derive(Serialize, Deserialize)]
+struct SyncConfigV12 {
+    peer: PublicKey,
+    remote_path: String,
+    last_synced: Option<u64>,
+    last_hash: Option<[u8; 32]>,
+    include: Vec<String>,
+    last_error: Option<String>,
+    on_conflict: ConflictPolicy,
+    include_hidden: bool,
+    requested_by_peer: bool,
+    events: EventMask,
+}
+
+/// A sync as stored by schema v11, before syncs picked the changes that set
+/// them off.
+#[derive(Serialize, Deserialize)]
+struct SyncConfigV11 {
+    peer: PublicKey,
+    remote_path: String,
+    last_synced: Option<u64>,
+    last_hash: Option<[u8; 32]>,
+    include: Vec<String>,
+    last_error: Option<String>,
+    on_conflict: ConflictPolicy,
+    include_hidden: bool,
+    requested_by_peer: bool,
+}
+
+/// A sync as stored by schema v10, before syncs recorded who asked for them.
+#[derive(Serialize, Deserialize)]
+struct SyncConfigV10 {
+    peer: PublicKey,
+    remote_path: String,
+    last_synced: Option<u64>,
+    last_hash: Option<[u8; 32]>,
+    include: Vec<String>,
+    last_error: Option<String>,
+    on_conflict: ConflictPolicy,
+    include_hidden: bool,
+}
+
+/// A sync as stored by schema v8 and v9, before hidden files were skipped.
+#[derive(Serialize, Deserialize)]
+struct SyncConfigV8 {
+    peer: PublicKey,
+    remote_path: String,
+    last_synced: Option<u64>,
+    last_hash: Option<[u8; 32]>,
+    include: Vec<String>,
+    last_error: Option<String>,
+    on_conflict: ConflictPolicy,
+}
+
+/// A sync as stored by schema v7, before conflict policies.
+#[derive(Serialize, Deserialize)]
+struct SyncConfigV7 {
+    peer: PublicKey,
+    remote_path: String,
+    last_synced: Option<u64>,
+    last_hash: Option<[u8; 32]>,
+    include: Vec<String>,
+    last_error: Option<String>,
+}
+
+/// A sync as stored by schema v4 to v6, before failures were recorded.
+#[derive(Serialize, Deserialize)]
+struct SyncConfigV4 {
+    peer: PublicKey,
+    remote_path: String,
+    last_synced: Option<u64>,
+    last_hash: Option<[u8; 32]>,
+    include: Vec<String>,
+}
+
+/// A sync as stored by schema v2 and v3, before includes.
+#[derive(Serialize, Deserialize)]
+struct SyncConfigV2 {
+    peer: PublicKey,
+    remote_path: String,
+    last_synced: Option<u64>,
+    last_hash: Option<[u8; 32]>,
+}
+
+/// The state of a file a signature was computed from. A cached signature is
+/// only reused while the file still matches.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignatureStamp {
+    /// Modification time in nanoseconds since the Unix epoch
+    pub modified_nanos: u64,
+    pub len: u64,
+    /// Block size override the signature was computed with, if any
+    pub block_size: Option<u32>,
+}
+
+/// What a peer may do with a path it has been granted.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessMode {
+    /// List and download files
+    Read,
+    /// Register syncs that pull the peer's changes into the path
+    Write,
+    ReadWrite,
+}
+
+impl AccessMode {
+    /// Whether holding `self` satisfies a check for `required`.
+    pub fn covers(self, required: AccessMode) -> bool {
+        self == AccessMode::ReadWrite || self == required
+    }
+
+    /// Everything either `self` or `other` allows.
+    pub fn union(self, other: AccessMode) -> AccessMode {
+        if self == other {
+            self
+        } else {
+            AccessMode::ReadWrite
+        }
+    }
+}
+
+impl std::fmt::Display for AccessMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccessMode::Read => write!(f, "read"),
+            AccessMode::Write => write!(f, "write"),
+            AccessMode::ReadWrite => write!(f, "read-write"),
+        }
+    }
+}
+
+/// Entries found by [`Store::find_orphans`].
+#[derive(Debug, Clone, Default)]
+pub struct Orphans {
+    /// Watched paths that don't exist
+    pub watches: Vec<PathBuf>,
+    /// Local paths that don't exist, with the syncs into them
+    pub syncs: Vec<(PathBuf, Vec<SyncConfig>)>,
+    /// Paths with permission entries that don't exist or grant nothing
+    pub permissions: Vec<PathBuf>,
+}
+
+impl Orphans {
+    pub fn is_empty(&self) -> bool {
+        self.watches.is_empty() && self.syncs.is_empty() && self.permissions.is_empty()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Grant {
+    pub peer: PublicKey,
+    pub mode: AccessMode,
+    /// Unix timestamp after which the grant no longer counts
+    pub expires_at: Option<u64>,
+}
+
+impl Grant {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+/// A grant held by a group, counting for each of its members.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GroupGrant {
+    pub group: String,
+    pub mode: AccessMode,
+    /// Unix timestamp after which the grant no longer counts
+    pub expires_at: Option<u64>,
+}
+
+impl GroupGrant {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+/// A grant as stored by schema v1 and v2, before grants could expire.
+#[derive(Serialize, Deserialize)]
+struct GrantV1 {
+    peer: PublicKey,
+    mode: AccessMode,
+}
+
+/// Open the database at `db_path`, waiting up to [`LOCK_WAIT`] for a lock
+/// held on it to be released.
+fn open_db(db_path: &Path) -> Result<Db> {
+    let started = Instant::now();
+    loop {
+        match sled::open(db_path) {
+            Ok(db) => return Ok(db),
+            // sled only says so in the message of the error it makes.
+            Err(sled::Error::Io(io)) if io.to_string().contains("could not acquire lock") => {
+                if started.elapsed() >= LOCK_WAIT {
+                    return Err(StoreError::Locked(db_path.to_path_buf()));
+                }
+                std::thread::sleep(LOCK_RETRY_DELAY);
+            }
+            Err(e) => return Err(StoreError::DbError(e)),
+        }
+    }
+}
+
+/// Decode the value stored under `key` in `tree`, naming both if it can't
+/// be.
+fn decode<T: DeserializeOwned>(tree: &str, key: &[u8], bytes: &[u8]) -> Result<T> {
+    postcard::from_bytes(bytes).map_err(|source| StoreError::Corrupt {
+        tree: tree.to_string(),
+        key: String::from_utf8_lossy(key).into_owned(),
+        source,
+    })
+}
+
+/// A remote file a copy completed, as the local file was left.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpointed {
+    /// BLAKE3 hash of the remote file that was copied
+    pub hash: [u8; 32],
+    pub len: u64,
+    /// Modification time in nanoseconds since the Unix epoch
+    pub modified_nanos: u64,
+}
+
+/// Key of the sync or copy of `remote_path` from `peer` into `local_path`,
+/// which every file it checkpointed starts with. Its parts are
+/// length-prefixed, so no sync's key starts another's.
+fn sync_key(local_path: &Path, peer: PublicKey, remote_path: &str) -> Result<Vec<u8>> {
+    Ok(postcard::to_stdvec(&(
+        path_to_key(local_path),
+        peer,
+        remote_path,
+    ))?)
+}
+
+/// The form paths are stored and looked up in: with symlinks, `.` and `..`
+/// resolved as far as the path exists, so every way of naming a path finds
+/// the same entry. A path none of which resolves is used as given.
+pub fn canonical_path(path: &Path) -> PathBuf {
+    sandbox::canonicalize_existing_prefix(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Whether `path` is known not to exist, as opposed to merely not being
+/// reachable right now.
+fn is_gone(path: &Path) -> bool {
+    matches!(std::fs::symlink_metadata(path), Err(e) if e.kind() == std::io::ErrorKind::NotFound)
+}
+
+/// Whether wire path `path` is `root` or below it.
+fn is_wire_path_below(path: &str, root: &str) -> bool {
+    let root = root.trim_end_matches('/');
+    root.is_empty()
+        || path == root
+        || path
+            .strip_prefix(root)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+fn path_bytes(path: &Path) -> &[u8] {
+    path.as_os_str().as_encoded_bytes()
+}
+
+/// Key a path is stored under: the exact bytes of its canonical form, so
+/// names that aren't valid UTF-8 are kept as they are.
+fn path_to_key(path: &Path) -> Vec<u8> {
+    path_bytes(&canonical_path(path)).to_vec()
+}
+
+/// [`path_to_key`] for a path being added, refusing one that is relative or
+/// keeps a `..` that couldn't be resolved, as lookups by the canonical paths
+/// the watcher reports would never find it.
+fn checked_key(path: &Path) -> Result<Vec<u8>> {
+    let canonical = canonical_path(path);
+    if !path.is_absolute() || canonical.components().any(|c| c == Component::ParentDir) {
+        return Err(StoreError::InvalidPath(path.to_path_buf()));
+    }
+    Ok(path_bytes(&canonical).to_vec())
+}
+
+#[cfg(unix)]
+fn key_to_path(key: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(key))
+}
+
+/// Elsewhere keys that aren't valid UTF-8 can't be mapped back exactly.
+#[cfg(not(unix))]
+fn key_to_path(key: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(key).into_owned())
 }
@@ -1,73 +1,398 @@
 use anyhow::{Context, Result};
-use iroh::{Endpoint, PublicKey};
+use iroh::{endpoint::Connection, Endpoint, PublicKey};
+use std::collections::{BTreeSet, HashMap};
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, watch, Mutex, Notify};
 use tracing::{error, info, warn};
 
 use crate::{
-    protocol::{Message, ALPN},
-    store::Store,
-    watcher::FileWatcher,
+    config::DEFAULT_HANDSHAKE_TIMEOUT_SECS,
+    heartbeat::{self, HeartbeatOptions},
+    iroh_utils, metrics, path_lock,
+    poller::{self, Poller},
+    protocol::{
+        is_hidden, is_included, join_wire_path, read_message, read_message_or_eof, to_wire_path,
+        write_message, Capabilities, Message, CAP_BATCH_NOTIFY, NOTIFY_BATCH_LEN,
+    },
+    schedule::ActiveLimit,
+    store::{self, Store, WatchOptions},
+    sync_utils,
+    trash::TRASH_DIR,
+    watcher::{EventMask, FileWatcher, RecursiveMode},
 };
 
+/// How often notifications that couldn't be delivered are tried again.
+const PENDING_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often polled paths are checked for being due a rescan.
+const POLL_TICK: Duration = Duration::from_secs(1);
+
+/// Events a subscriber may fall behind by before it misses the oldest.
+const EVENT_CAPACITY: usize = 256;
+
+/// Something the daemon did, for a UI or status indicator to follow along.
+/// Each is logged as well.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncEvent {
+    /// A watched file changed on this machine
+    LocalChangeDetected { path: PathBuf },
+    /// `peer` was told that its sync of wire path `path` has changes to pull
+    NotifiedPeer { peer: PublicKey, path: String },
+    /// `peer` said `path` changed, and pulling it into `local` began
+    PullStarted {
+        peer: PublicKey,
+        path: String,
+        local: PathBuf,
+    },
+    /// A pull wrote the local file `path`, receiving `bytes` for it
+    FileCompleted { path: PathBuf, bytes: u64 },
+    /// Pulling `path` from `peer` into `local` completed
+    PullCompleted {
+        peer: PublicKey,
+        path: String,
+        local: PathBuf,
+    },
+    /// Pulling `path` from `peer` failed
+    PullFailed {
+        peer: PublicKey,
+        path: String,
+        error: String,
+    },
+    /// `path` changed both here and on the peer; the incoming version was
+    /// written to `incoming` instead
+    ConflictDetected { path: PathBuf, incoming: PathBuf },
+}
+
+impl SyncEvent {
+    /// A channel to publish events on. Sending is fine with no subscribers.
+    pub fn channel() -> broadcast::Sender<SyncEvent> {
+        broadcast::channel(EVENT_CAPACITY).0
+    }
+}
+
 /// Manages active syncs, watches, and peer communication
 pub struct SyncManager {
     store: Store,
-    endpoint: Endpoint,
     watcher: Arc<Mutex<FileWatcher>>,
+    /// Rescans watched paths that have a poll interval
+    poller: Arc<std::sync::Mutex<Poller>>,
+    notifier: Notifier,
+    reconcile_interval: Duration,
+    reconcile: ReconcileTrigger,
+    events: broadcast::Sender<SyncEvent>,
+    dialed: Option<mpsc::UnboundedReceiver<Connection>>,
+}
+
+/// Asks the watch reconcile loop to run now rather than at its next tick,
+/// e.g. right after a watch was added to the store.
+#[derive(Clone, Default)]
+pub struct ReconcileTrigger(Arc<Notify>);
+
+impl ReconcileTrigger {
+    pub fn fire(&self) {
+        self.0.notify_one();
+    }
+}
+
+/// Sends change notifications to peers over connections kept open between
+/// notifications. A heartbeat runs on each one, and a connection whose peer
+/// stops answering is closed and dropped, so the next notification dials
+/// afresh instead of waiting on a dead peer. One no notification used for
+/// `pool_idle` is closed too.
+///
+/// With a `batch` window, changes for a peer are gathered from the first one
+/// on and sent together once it has passed. The watcher already reports a
+/// path changed several times while we were busy once, so the window is
+/// not extended by further changes; that would only delay notifications
+/// further during a long burst.
+#[derive(Clone)]
+struct Notifier {
+    endpoint: Endpoint,
+    /// Isolated network peers are dialed on
+    network: Option<String>,
+    idle_timeout: Duration,
+    handshake_timeout: Duration,
+    heartbeat: HeartbeatOptions,
+    pool_idle: Option<Duration>,
+    batch: Option<Duration>,
+    /// Paths gathered for each peer whose batch window is open
+    batches: Arc<std::sync::Mutex<HashMap<PublicKey, BTreeSet<String>>>>,
+    connections: Arc<Mutex<HashMap<PublicKey, Pooled>>>,
+    events: broadcast::Sender<SyncEvent>,
+    /// Where new connections go to have the peer's requests on them served
+    dialed: mpsc::UnboundedSender<Connection>,
+    /// While this pauses transfers, notifications are queued rather than
+    /// sent, so peers don't pull
+    limit: watch::Receiver<ActiveLimit>,
+}
+
+/// A connection kept open to a peer, and when a notification last used it.
+struct Pooled {
+    connection: Connection,
+    last_used: Instant,
 }
 
 impl SyncManager {
-    pub fn new(store: Store, endpoint: Endpoint, watcher: FileWatcher) -> Self {
+    pub fn new(
+        store: Store,
+        endpoint: Endpoint,
+        watcher: FileWatcher,
+        idle_timeout: Duration,
+        heartbeat: HeartbeatOptions,
+        reconcile_interval: Duration,
+        events: broadcast::Sender<SyncEvent>,
+    ) -> Self {
+        let (dialed_tx, dialed) = mpsc::unbounded_channel();
         Self {
             store,
-            endpoint,
             watcher: Arc::new(Mutex::new(watcher)),
+            poller: Arc::default(),
+            notifier: Notifier {
+                endpoint,
+                network: None,
+                idle_timeout,
+                handshake_timeout: Duration::from_secs(DEFAULT_HANDSHAKE_TIMEOUT_SECS),
+                heartbeat,
+                pool_idle: None,
+                batch: None,
+                batches: Arc::default(),
+                connections: Arc::default(),
+                events: events.clone(),
+                dialed: dialed_tx,
+                limit: watch::channel(ActiveLimit::Unlimited).1,
+            },
+            reconcile_interval,
+            reconcile: ReconcileTrigger::default(),
+            events,
+            dialed: Some(dialed),
         }
     }
 
+    /// Follow the transfer limit in `limit`, holding notifications back
+    /// while it pauses transfers.
+    pub fn with_schedule(mut self, limit: watch::Receiver<ActiveLimit>) -> Self {
+        self.notifier.limit = limit;
+        self
+    }
+
+    /// Close connections to peers once no notification has used them for
+    /// `idle`, rather than keeping them until they fail.
+    pub fn with_pool_idle(mut self, idle: Option<Duration>) -> Self {
+        self.notifier.pool_idle = idle;
+        self
+    }
+
+    /// Give up on a peer that doesn't send its handshake within `timeout`.
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.notifier.handshake_timeout = timeout;
+        self
+    }
+
+    /// Gather changes for each peer for `window` and notify it of them
+    /// together, rather than of each as it happens.
+    pub fn with_notify_batch(mut self, window: Option<Duration>) -> Self {
+        self.notifier.batch = window;
+        self
+    }
+
+    /// Dial peers on the isolated network named `network`, which the
+    /// endpoint must accept too.
+    pub fn with_network(mut self, network: Option<String>) -> Self {
+        self.notifier.network = network;
+        self
+    }
+
+    /// Connections opened to notify peers. A notified peer pulls the change
+    /// over the same connection, so its requests there need serving. Only
+    /// the first call gets them.
+    pub fn dialed_connections(&mut self) -> Option<mpsc::UnboundedReceiver<Connection>> {
+        self.dialed.take()
+    }
+
+    /// Follow what the manager does from here on.
+    #[cfg(test)]
+    pub fn subscribe(&self) -> broadcast::Receiver<SyncEvent> {
+        self.events.subscribe()
+    }
+
+    /// A handle to make the reconcile loop started by [`run`](Self::run)
+    /// go again right away.
+    pub fn reconcile_trigger(&self) -> ReconcileTrigger {
+        self.reconcile.clone()
+    }
+
+    /// Paths the watcher currently has a live watch on.
+    pub async fn watched_paths(&self) -> Vec<PathBuf> {
+        self.watcher.lock().await.watched_paths()
+    }
+
     pub async fn run(&self) -> Result<()> {
-        let mut watcher = self.watcher.lock().await;
-
-        // Load existing watches
-        let watched_paths = self.store.list_watches()?;
-        for path in &watched_paths {
-            if path.exists() {
-                info!("Watching path: {:?}", path);
-                watcher.watch(path)?;
-            } else {
-                warn!("Watched path does not exist: {:?}", path);
+        let mut events = self
+            .watcher
+            .lock()
+            .await
+            .take_events()
+            .context("Watcher events are already being handled")?;
+
+        // The store alone says what is watched: this loop installs watches
+        // on wanted paths as they appear, starting right away, and drops
+        // those on paths that disappeared or are no longer wanted. It does
+        // the same for the paths polled, after dropping the syncs peers asked
+        // for that they lost access to. The tasks end once the manager is
+        // dropped.
+        let watcher_clone = Arc::downgrade(&self.watcher);
+        let poller = self.poller.clone();
+        let store_clone = self.store.clone();
+        let reconcile_interval = self.reconcile_interval;
+        let trigger = self.reconcile.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(reconcile_interval);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = trigger.0.notified() => {}
+                }
+                let Some(watcher) = watcher_clone.upgrade() else {
+                    break;
+                };
+                match store_clone.prune_peer_syncs() {
+                    Ok(pruned) => {
+                        for (local, config) in pruned {
+                            info!(
+                                "Dropped the sync {} asked for on {:?}; its access or the path is gone",
+                                config.peer, local
+                            );
+                        }
+                    }
+                    Err(e) => error!("Failed to prune syncs peers asked for: {}", e),
+                }
+                // Watches nested in another that reports all they would are left to it.
+                match store_clone.list_active_watches() {
+                    Ok(wanted) => {
+                        poller.lock().unwrap().reconcile(&wanted);
+                        watcher.lock().await.reconcile(&notified_watches(&wanted));
+                    }
+                    Err(e) => error!("Failed to list watches: {}", e),
+                }
             }
+        });
+
+        // Rescan polled paths as they fall due, handling what changed as if
+        // the watcher had reported it.
+        let watcher_clone = Arc::downgrade(&self.watcher);
+        let poller = self.poller.clone();
+        let store_clone = self.store.clone();
+        let notifier = self.notifier.clone();
+        let sync_events = self.events.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(POLL_TICK);
+            loop {
+                interval.tick().await;
+                if watcher_clone.strong_count() == 0 {
+                    break;
+                }
+                let due = poller.lock().unwrap().due(Instant::now());
+                for (root, recursive) in due {
+                    let scan_root = root.clone();
+                    let scanned = match tokio::task::spawn_blocking(move || {
+                        poller::scan(&scan_root, recursive)
+                    })
+                    .await
+                    {
+                        Ok(scanned) => scanned,
+                        Err(e) => {
+                            error!("Failed to poll {:?}: {}", root, e);
+                            continue;
+                        }
+                    };
+                    let changed = poller.lock().unwrap().update(&root, scanned);
+                    for path in changed {
+                        info!("Polling found a change: {:?}", path);
+                        // A scan can't tell what kind of change it found.
+                        if let Err(e) = Self::handle_local_change(
+                            &store_clone,
+                            &notifier,
+                            &sync_events,
+                            path,
+                            EventMask::ALL,
+                        )
+                        .await
+                        {
+                            error!("Failed to handle local change: {:?}", e);
+                        }
+                    }
+                }
+            }
+        });
+
+        // Let go of connections to peers we have stopped notifying. Heartbeats
+        // don't keep them, so a peer that is still there is let go as well.
+        if let Some(idle) = self.notifier.pool_idle {
+            let watcher_clone = Arc::downgrade(&self.watcher);
+            let notifier = self.notifier.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(idle / 2);
+                loop {
+                    interval.tick().await;
+                    if watcher_clone.strong_count() == 0 {
+                        break;
+                    }
+                    notifier.close_idle(idle).await;
+                }
+            });
         }
-        drop(watcher); // Unlock
 
-        let watcher_clone = self.watcher.clone();
+        // Deliver notifications left over from before a restart, and those
+        // that failed since, as their peers become reachable.
+        let watcher_clone = Arc::downgrade(&self.watcher);
+        let store_clone = self.store.clone();
+        let notifier = self.notifier.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PENDING_RETRY_INTERVAL);
+            loop {
+                interval.tick().await;
+                if watcher_clone.strong_count() == 0 {
+                    break;
+                }
+                if let Err(e) = notifier.flush_pending(&store_clone).await {
+                    error!("Failed to deliver pending notifications: {}", e);
+                }
+            }
+        });
+
+        let watcher_clone = Arc::downgrade(&self.watcher);
+        let poller = self.poller.clone();
         let store_clone = self.store.clone();
-        let endpoint_clone = self.endpoint.clone();
+        let notifier = self.notifier.clone();
+        let sync_events = self.events.clone();
 
         // Spawn the watcher event loop
         tokio::spawn(async move {
-            loop {
-                let mut w = watcher_clone.lock().await;
-                let event = w.next_event().await;
-                drop(w); // Unlock during processing
-
-                if let Some(res) = event {
-                    match res {
-                        Ok(path) => {
-                            info!("File changed locally: {:?}", path);
-                            if let Err(e) =
-                                Self::handle_local_change(&store_clone, &endpoint_clone, path).await
-                            {
-                                error!("Failed to handle local change: {:?}", e);
-                            }
+            while let Some(res) = events.recv().await {
+                match res {
+                    Ok(change) => {
+                        metrics::get().watcher_events.inc();
+                        if let Some(watcher) = watcher_clone.upgrade().filter(|_| change.removed) {
+                            watcher.lock().await.rearm(&change.path);
+                        }
+                        info!("File changed locally: {:?}", change.path);
+                        // The next scan of a polled path needn't report it.
+                        poller.lock().unwrap().record(&change.path);
+                        if let Err(e) = Self::handle_local_change(
+                            &store_clone,
+                            &notifier,
+                            &sync_events,
+                            change.path,
+                            change.kinds,
+                        )
+                        .await
+                        {
+                            error!("Failed to handle local change: {:?}", e);
                         }
-                        Err(e) => error!("Watcher error: {}", e),
                     }
-                } else {
-                    break;
+                    Err(e) => error!("Watcher error: {}", e),
                 }
             }
         });
@@ -75,98 +400,771 @@ impl SyncManager {
         Ok(())
     }
 
-    async fn handle_local_change(store: &Store, endpoint: &Endpoint, path: PathBuf) -> Result<()> {
+    async fn handle_local_change(
+        store: &Store,
+        notifier: &Notifier,
+        events: &broadcast::Sender<SyncEvent>,
+        path: PathBuf,
+        kinds: EventMask,
+    ) -> Result<()> {
+        if path.components().any(|c| c.as_os_str() == TRASH_DIR) {
+            // Backups taken while syncing aren't synced themselves.
+            return Ok(());
+        }
+        let _ = events.send(SyncEvent::LocalChangeDetected { path: path.clone() });
+        // Wait out a patch of this file from a peer, so it's hashed once
+        // whole rather than notified about halfway through.
+        let _lock = path_lock::lock(&path).await;
+        // A file a pull just wrote isn't sent back to the peer it came from,
+        // or two peers syncing it both ways would pass it back and forth.
+        let pulled_from = match path_lock::pulled_from(&path) {
+            Some((peer, hash)) if sync_utils::hash_file(&path).await.ok() == Some(hash) => {
+                Some(peer)
+            }
+            _ => None,
+        };
+        let file_len = std::fs::metadata(&path)
+            .ok()
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len());
         let syncs = store.list_syncs()?;
         for (local_root, configs) in syncs {
             // Check if 'path' is inside 'local_root'
             if path.starts_with(&local_root) {
                 // Calculate relative path
-                let relative_path = path.strip_prefix(&local_root)?.to_string_lossy();
-
-                for config in configs {
-                    // Construct remote path
-                    // If local_root was "/tmp/a.txt" and path is "/tmp/a.txt", relative is "".
-                    // remote_path should be config.remote_path.
-
-                    // If local_root was "/tmp/dir" and path is "/tmp/dir/file.txt", relative is "file.txt".
-                    // remote_path should be config.remote_path + "/" + relative.
+                let relative_path = to_wire_path(path.strip_prefix(&local_root)?);
 
-                    let target_remote_path = if relative_path.is_empty() {
-                        config.remote_path.clone()
+                // Only single-file syncs record a hash to compare against.
+                let current_hash =
+                    if path == local_root && configs.iter().any(|c| c.last_hash.is_some()) {
+                        sync_utils::hash_file(&path).await.ok()
                     } else {
-                        // naive path join, assuming unix style forward slashes for wire protocol
-                        if config.remote_path.ends_with('/') {
-                            format!("{}{}", config.remote_path, relative_path)
-                        } else {
-                            format!("{}/{}", config.remote_path, relative_path)
-                        }
+                        None
                     };
 
+                for config in configs {
+                    if !config.events.intersects(kinds) {
+                        // Not a kind of change this sync is set off by.
+                        continue;
+                    }
+                    if !is_included(&relative_path, &config.include) {
+                        // Outside the subpaths this sync is limited to.
+                        continue;
+                    }
+                    if !config.include_hidden && is_hidden(&relative_path) {
+                        continue;
+                    }
+                    if file_len.is_some_and(|len| config.max_size.is_some_and(|max| len > max)) {
+                        info!(
+                            "{:?} is over the size limit of the sync with {}, not notifying",
+                            path, config.peer
+                        );
+                        continue;
+                    }
+                    if pulled_from == Some(config.peer) {
+                        info!(
+                            "{:?} was just pulled from {}, not notifying it back",
+                            path, config.peer
+                        );
+                        continue;
+                    }
+                    if current_hash.is_some() && config.last_hash == current_hash {
+                        // Most likely our own write from pulling this file.
+                        info!(
+                            "{:?} unchanged since last sync with {}, not notifying",
+                            path, config.peer
+                        );
+                        continue;
+                    }
+
+                    // "/tmp/dir/file.txt" under a local root of "/tmp/dir"
+                    // is "file.txt" below the remote root; the root itself
+                    // maps onto remote_path.
+                    let target_remote_path = join_wire_path(&config.remote_path, &relative_path);
+
                     info!(
                         "Notifying peer {} about update to {}",
                         config.peer, target_remote_path
                     );
-                    if let Err(e) =
-                        Self::notify_peer(endpoint, config.peer, target_remote_path).await
-                    {
-                        error!("Failed to notify peer {}: {}", config.peer, e);
-                    }
+                    notifier
+                        .submit(store, config.peer, target_remote_path)
+                        .await?;
                 }
             }
         }
         Ok(())
     }
+}
 
-    async fn notify_peer(endpoint: &Endpoint, peer: PublicKey, remote_path: String) -> Result<()> {
-        // Connect to the peer
-        // TODO: Reuse connections if possible
-        let connection = endpoint
-            .connect(peer, ALPN)
-            .await
-            .context("Failed to connect to peer")?;
+/// Show how many notifications are queued in `store` in the metrics.
+fn record_pending_depth(store: &Store) -> store::Result<()> {
+    let mut depth = 0;
+    for peer in store.peers_with_pending_notifications()? {
+        depth += store.pending_notifications(peer)?.len();
+    }
+    metrics::get().pending_notifications.set(depth as i64);
+    Ok(())
+}
+
+/// The watches in `wanted` that listen for notifications, in the form the
+/// watcher takes them.
+fn notified_watches(wanted: &[(PathBuf, WatchOptions)]) -> Vec<(PathBuf, RecursiveMode)> {
+    wanted
+        .iter()
+        .filter(|(_, options)| options.notify)
+        .map(|(path, options)| {
+            let mode = if options.recursive {
+                RecursiveMode::Recursive
+            } else {
+                RecursiveMode::NonRecursive
+            };
+            (path.clone(), mode)
+        })
+        .collect()
+}
+
+impl Notifier {
+    /// Whether the schedule pauses transfers right now.
+    fn paused(&self) -> bool {
+        self.limit.borrow().is_paused()
+    }
 
+    /// Notify `peer` of a change at `remote_path`, right away or along with
+    /// the rest of its batch once the window has passed.
+    async fn submit(&self, store: &Store, peer: PublicKey, remote_path: String) -> Result<()> {
+        let Some(window) = self.batch else {
+            return self.deliver(store, peer, vec![remote_path]).await;
+        };
+        {
+            let mut batches = self.batches.lock().unwrap();
+            if let Some(batch) = batches.get_mut(&peer) {
+                batch.insert(remote_path);
+                return Ok(());
+            }
+            batches.insert(peer, BTreeSet::from([remote_path]));
+        }
+        let notifier = self.clone();
+        let store = store.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(window).await;
+            let paths = notifier.batches.lock().unwrap().remove(&peer);
+            let paths = paths.into_iter().flatten().collect();
+            if let Err(e) = notifier.deliver(&store, peer, paths).await {
+                error!("Failed to handle notifications for {}: {:?}", peer, e);
+            }
+        });
+        Ok(())
+    }
+
+    /// Notify `peer` of `paths`, queueing them to try again later if that
+    /// fails, or if the schedule pauses transfers.
+    async fn deliver(&self, store: &Store, peer: PublicKey, paths: Vec<String>) -> Result<()> {
+        if self.paused() {
+            info!(
+                "Transfers are paused by the schedule, queueing notification of {} about {:?}",
+                peer, paths
+            );
+        } else if let Err(e) = self.notify(peer, &paths).await {
+            warn!(
+                "Failed to notify peer {}, will try again later: {}",
+                peer, e
+            );
+            metrics::get().notification_failures.inc();
+        } else {
+            return Ok(());
+        }
+        for path in &paths {
+            store.queue_notification(peer, path)?;
+        }
+        record_pending_depth(store)?;
+        Ok(())
+    }
+
+    /// Try to deliver every queued notification, dropping each peer's queue
+    /// once it is. A peer that can't be reached keeps its queue. Nothing is
+    /// delivered while the schedule pauses transfers.
+    async fn flush_pending(&self, store: &Store) -> Result<()> {
+        if self.paused() {
+            return Ok(());
+        }
+        for peer in store.peers_with_pending_notifications()? {
+            let paths = store.pending_notifications(peer)?;
+            if let Err(e) = self.notify(peer, &paths).await {
+                info!("Peer {} still unreachable: {}", peer, e);
+                continue;
+            }
+            info!(
+                "Delivered {} pending notifications to {}",
+                paths.len(),
+                peer
+            );
+            for path in &paths {
+                store.remove_pending_notification(peer, path)?;
+            }
+        }
+        record_pending_depth(store)?;
+        Ok(())
+    }
+
+    /// Tell `peer` its syncs of `paths` have changes to pull, in as few
+    /// messages as it understands.
+    async fn notify(&self, peer: PublicKey, paths: &[String]) -> Result<()> {
+        let connection = self.connection(peer).await?;
         let (mut send, mut recv) = connection
             .open_bi()
             .await
             .context("Failed to open stream")?;
 
         // 1. Handshake
-        // Server speaks first (see serve.rs)
-        let msg = read_message(&mut recv).await?;
-        match msg {
-            Message::Handshake { .. } => {}
-            _ => anyhow::bail!("Expected handshake from server"),
-        }
-
-        let handshake = Message::Handshake { version: 1 };
+        // The server only sees the stream once we write to it, so we speak first.
+        let handshake = Message::handshake(
+            iroh_utils::protocol_version(&connection),
+            &Capabilities::ours(),
+        );
         write_message(&mut send, &handshake).await?;
 
-        // 2. Send Notification
-        let msg = Message::FileUpdateNotification { path: remote_path };
-        write_message(&mut send, &msg).await?;
+        let msg = read_message(&mut recv, self.handshake_timeout)
+            .await
+            .context("Peer sent no handshake")?;
+        let capabilities = match msg.into_current() {
+            Message::Handshake { capabilities, .. } => {
+                Capabilities::ours().intersection(&Capabilities::from_names(capabilities))
+            }
+            _ => anyhow::bail!("Expected handshake from server"),
+        };
 
-        // We don't expect a response to notification immediately?
-        // Or maybe we should wait for ack?
-        // For now, fire and close.
+        // 2. Send Notifications
+        // A peer that can't take batches is sent each path on the stream.
+        if paths.len() > 1 && capabilities.contains(CAP_BATCH_NOTIFY) {
+            for chunk in paths.chunks(NOTIFY_BATCH_LEN) {
+                let msg = Message::BatchUpdateNotification {
+                    paths: chunk.to_vec(),
+                };
+                write_message(&mut send, &msg).await?;
+            }
+        } else {
+            for path in paths {
+                let msg = Message::FileUpdateNotification { path: path.clone() };
+                write_message(&mut send, &msg).await?;
+            }
+        }
         send.finish()?;
 
+        // Wait for the server to finish its side so the notifications
+        // aren't lost if the connection is dropped.
+        read_message_or_eof(&mut recv, self.idle_timeout).await?;
+        for path in paths {
+            let _ = self.events.send(SyncEvent::NotifiedPeer {
+                peer,
+                path: path.clone(),
+            });
+        }
+
         Ok(())
     }
-}
 
-async fn write_message<W: AsyncWriteExt + Unpin>(writer: &mut W, msg: &Message) -> Result<()> {
-    let data = postcard::to_stdvec(msg)?;
-    let len = data.len() as u32;
-    writer.write_u32(len).await?;
-    writer.write_all(&data).await?;
-    writer.flush().await?;
-    Ok(())
+    /// An open connection to `peer`, reusing the last one while it's alive.
+    async fn connection(&self, peer: PublicKey) -> Result<Connection> {
+        let mut connections = self.connections.lock().await;
+        if let Some(pooled) = connections.get_mut(&peer) {
+            if pooled.connection.close_reason().is_none() {
+                pooled.last_used = Instant::now();
+                return Ok(pooled.connection.clone());
+            }
+        }
+
+        let connection = iroh_utils::connect(&self.endpoint, self.network.as_deref(), peer)
+            .await
+            .context("Failed to connect to peer")?;
+        connections.insert(
+            peer,
+            Pooled {
+                connection: connection.clone(),
+                last_used: Instant::now(),
+            },
+        );
+        let _ = self.dialed.send(connection.clone());
+
+        let pool = self.connections.clone();
+        let heartbeat = self.heartbeat;
+        let monitored = connection.clone();
+        tokio::spawn(async move {
+            let reason = tokio::select! {
+                biased;
+                // Closed for being idle, or by the peer; the next
+                // notification dials again either way.
+                _ = monitored.closed() => return,
+                reason = heartbeat::monitor(&monitored, heartbeat) => reason,
+            };
+            warn!("Dropping connection to {}: {}", peer, reason);
+            let mut connections = pool.lock().await;
+            if connections
+                .get(&peer)
+                .is_some_and(|p| p.connection.stable_id() == monitored.stable_id())
+            {
+                connections.remove(&peer);
+            }
+            monitored.close(0u32.into(), b"heartbeat failed");
+        });
+
+        Ok(connection)
+    }
+
+    /// Close and drop the pooled connections no notification has used for
+    /// `idle`.
+    async fn close_idle(&self, idle: Duration) {
+        let mut connections = self.connections.lock().await;
+        connections.retain(|peer, pooled| {
+            if pooled.last_used.elapsed() < idle {
+                return true;
+            }
+            info!("Closing connection to {}, unused for {:?}", peer, idle);
+            pooled.connection.close(0u32.into(), b"idle");
+            false
+        });
+    }
 }
 
-async fn read_message<R: AsyncReadExt + Unpin>(reader: &mut R) -> Result<Message> {
-    let len = reader.read_u32().await?;
-    let mut buf = vec![0u8; len as usize];
-    reader.read_exact(&mut buf).await?;
-    let msg = postcard::from_bytes(&buf)?;
-    Ok(msg)
+#[cfg(test)]
+mod tests {
+    use iroh::{discovery::static_provider::StaticProvider, Endpoint, SecretKey};
+    use std::path::Path;
+    use std::time::Duration;
+    use tempfile::TempDir;
+    use tokio::sync::mpsc;
+    use tokio::task::JoinHandle;
+
+    use super::{SyncEvent, SyncManager};
+    use crate::{
+        cli::serve::ServeOptions,
+        config::Config,
+        heartbeat::HeartbeatOptions,
+        protocol::{read_message, read_message_or_eof, write_message, Capabilities, Message},
+        schedule::ActiveLimit,
+        server::SyncServer,
+        store::{Store, WatchOptions},
+        test_support::{loopback_addr, loopback_endpoint},
+        watcher::{EventMask, FileWatcher},
+    };
+
+    async fn manager(store: &Store, reconcile_interval: Duration) -> SyncManager {
+        manager_on(loopback_endpoint(None).await, store, reconcile_interval)
+    }
+
+    fn manager_on(endpoint: Endpoint, store: &Store, reconcile_interval: Duration) -> SyncManager {
+        let heartbeat = HeartbeatOptions {
+            interval: Duration::from_secs(15),
+            max_missed: 3,
+        };
+        SyncManager::new(
+            store.clone(),
+            endpoint,
+            FileWatcher::new().unwrap(),
+            Duration::from_secs(10),
+            heartbeat,
+            reconcile_interval,
+            SyncEvent::channel(),
+        )
+    }
+
+    /// Wait a few seconds at most for `path` to be watched or not.
+    async fn wait_for_watch(manager: &SyncManager, path: &Path, watched: bool) -> bool {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while manager.watched_paths().await.iter().any(|p| p == path) != watched {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    #[tokio::test]
+    async fn watches_follow_the_store_on_each_tick() {
+        let home = TempDir::new().unwrap();
+        let store = Store::new(&home.path().join("data")).unwrap();
+        let watched = std::fs::canonicalize(home.path()).unwrap();
+        let manager = manager(&store, Duration::from_millis(100)).await;
+        manager.run().await.unwrap();
+        assert!(manager.watched_paths().await.is_empty());
+
+        store.add_watch(&watched).unwrap();
+        assert!(wait_for_watch(&manager, &watched, true).await);
+
+        store.remove_watch(&watched).unwrap();
+        assert!(wait_for_watch(&manager, &watched, false).await);
+    }
+
+    #[tokio::test]
+    async fn trigger_reconciles_without_waiting_for_a_tick() {
+        let home = TempDir::new().unwrap();
+        let store = Store::new(&home.path().join("data")).unwrap();
+        let watched = std::fs::canonicalize(home.path()).unwrap();
+        let manager = manager(&store, Duration::from_secs(3600)).await;
+        manager.run().await.unwrap();
+
+        store.add_watch(&watched).unwrap();
+        manager.reconcile_trigger().fire();
+        assert!(wait_for_watch(&manager, &watched, true).await);
+    }
+
+    #[tokio::test]
+    async fn missing_path_is_watched_once_created() {
+        let home = TempDir::new().unwrap();
+        let store = Store::new(&home.path().join("data")).unwrap();
+        let watched = std::fs::canonicalize(home.path()).unwrap().join("later");
+        let manager = manager(&store, Duration::from_millis(100)).await;
+        let mut events = manager.subscribe();
+        manager.run().await.unwrap();
+
+        store.add_watch(&watched).unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!manager.watched_paths().await.contains(&watched));
+
+        std::fs::create_dir(&watched).unwrap();
+        assert!(wait_for_watch(&manager, &watched, true).await);
+
+        let file = watched.join("file.txt");
+        std::fs::write(&file, b"hello").unwrap();
+        let seen = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(SyncEvent::LocalChangeDetected { path }) = events.recv().await {
+                    if path == file {
+                        break;
+                    }
+                }
+            }
+        })
+        .await;
+        assert!(seen.is_ok(), "no change reported for {:?}", file);
+    }
+
+    #[tokio::test]
+    async fn polling_finds_changes_without_notifications() {
+        let home = TempDir::new().unwrap();
+        let store = Store::new(&home.path().join("data")).unwrap();
+        let watched = std::fs::canonicalize(home.path()).unwrap().join("mount");
+        std::fs::create_dir(&watched).unwrap();
+        let options = WatchOptions {
+            poll_interval_secs: Some(1),
+            notify: false,
+            ..Default::default()
+        };
+        store.add_watch_with(&watched, options).unwrap();
+        let manager = manager(&store, Duration::from_millis(100)).await;
+        let mut events = manager.subscribe();
+        manager.run().await.unwrap();
+
+        // The first scan only takes stock of what is there.
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert!(manager.watched_paths().await.is_empty());
+
+        let file = watched.join("file.txt");
+        std::fs::write(&file, b"hello").unwrap();
+        let seen = tokio::time::timeout(Duration::from_secs(3), async {
+            loop {
+                if let Ok(SyncEvent::LocalChangeDetected { path }) = events.recv().await {
+                    if path == file {
+                        break;
+                    }
+                }
+            }
+        })
+        .await;
+        assert!(seen.is_ok(), "no change polled for {:?}", file);
+    }
+
+    #[tokio::test]
+    async fn nested_watch_is_left_to_its_parent() {
+        let home = TempDir::new().unwrap();
+        let store = Store::new(&home.path().join("data")).unwrap();
+        let parent = std::fs::canonicalize(home.path()).unwrap();
+        let child = parent.join("child");
+        std::fs::create_dir(&child).unwrap();
+        let manager = manager(&store, Duration::from_millis(100)).await;
+        manager.run().await.unwrap();
+
+        assert_eq!(store.add_watch(&parent).unwrap(), None);
+        assert_eq!(store.add_watch(&child).unwrap(), Some(parent.clone()));
+        assert!(wait_for_watch(&manager, &parent, true).await);
+        assert!(!manager.watched_paths().await.contains(&child));
+        assert_eq!(store.list_watches().unwrap().len(), 2);
+
+        // Once the parent goes, the child it covered is installed in its place.
+        store.remove_watch(&parent).unwrap();
+        assert!(wait_for_watch(&manager, &child, true).await);
+        assert!(wait_for_watch(&manager, &parent, false).await);
+    }
+
+    /// Wait a few seconds at most for `peer` to have a notification queued,
+    /// doing `change` meanwhile until it does.
+    async fn wait_for_queued(store: &Store, peer: iroh::PublicKey, change: impl Fn()) -> bool {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while store.pending_notifications(peer).unwrap().is_empty() {
+                change();
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn syncs_on_one_root_are_set_off_by_their_own_kinds_of_change() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let home = TempDir::new().unwrap();
+        let store = Store::new(&home.path().join("data")).unwrap();
+        let root = std::fs::canonicalize(home.path()).unwrap().join("shared");
+        std::fs::create_dir(&root).unwrap();
+        let file = root.join("file.txt");
+        std::fs::write(&file, b"v1").unwrap();
+        let every_change = SecretKey::generate(&mut rand::rng()).public();
+        let content_only = SecretKey::generate(&mut rand::rng()).public();
+        for peer in [every_change, content_only] {
+            store
+                .add_sync_with_watch(peer, "/remote".to_string(), root.clone())
+                .unwrap();
+        }
+        store
+            .set_sync_events(&root, content_only, "/remote", EventMask::CONTENT)
+            .unwrap();
+
+        // Paused, changes are queued for each peer rather than sent.
+        let (_limit, paused) = tokio::sync::watch::channel(ActiveLimit::Paused);
+        let manager = manager(&store, Duration::from_millis(100))
+            .await
+            .with_schedule(paused);
+        manager.run().await.unwrap();
+        assert!(wait_for_watch(&manager, &root, true).await);
+
+        let mode = std::cell::Cell::new(0o600);
+        let chmod = || {
+            mode.set(mode.get() ^ 0o040);
+            std::fs::set_permissions(&file, std::fs::Permissions::from_mode(mode.get())).unwrap();
+        };
+        assert!(wait_for_queued(&store, every_change, chmod).await);
+        assert!(store
+            .pending_notifications(content_only)
+            .unwrap()
+            .is_empty());
+
+        let write = || std::fs::write(&file, b"v2").unwrap();
+        assert!(wait_for_queued(&store, content_only, write).await);
+    }
+
+    /// Keep writing `contents` to `file` until a peer is notified of the change,
+    /// for a few seconds at most.
+    async fn notify_by_writing(
+        file: &Path,
+        contents: &[u8],
+        events: &mut tokio::sync::broadcast::Receiver<SyncEvent>,
+    ) -> bool {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                std::fs::write(file, contents).unwrap();
+                let wait = tokio::time::timeout(Duration::from_millis(300), events.recv());
+                if let Ok(Ok(SyncEvent::NotifiedPeer { .. })) = wait.await {
+                    break;
+                }
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    #[tokio::test]
+    async fn idle_pooled_connection_is_closed_and_redialed_when_needed() {
+        // A peer to notify, which has no sync to pull the changes into.
+        let peer_endpoint = loopback_endpoint(None).await;
+        let peer = peer_endpoint.id();
+        let peer_addr = loopback_addr(&peer_endpoint);
+        let peer_home = TempDir::new().unwrap();
+        let mut config = Config::default();
+        config.discovery.pkarr = false;
+        config.discovery.dns = false;
+        config.discovery.mdns = false;
+        let server = SyncServer::from_endpoint(
+            peer_endpoint,
+            config,
+            Store::new(peer_home.path()).unwrap(),
+            ServeOptions::default(),
+        );
+        let (shutdown, stop) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(server.run_until(async {
+            let _ = stop.await;
+        }));
+
+        let home = TempDir::new().unwrap();
+        let store = Store::new(&home.path().join("data")).unwrap();
+        let root = std::fs::canonicalize(home.path()).unwrap().join("shared");
+        std::fs::create_dir(&root).unwrap();
+        store
+            .add_sync_with_watch(peer, "/remote".to_string(), root.clone())
+            .unwrap();
+        let endpoint = loopback_endpoint(None).await;
+        let known = StaticProvider::new();
+        known.add_endpoint_info(peer_addr);
+        endpoint.discovery().add(known);
+        let mut manager = manager_on(endpoint, &store, Duration::from_millis(100))
+            .with_pool_idle(Some(Duration::from_millis(500)));
+        let mut dialed = manager.dialed_connections().unwrap();
+        let mut events = manager.subscribe();
+        manager.run().await.unwrap();
+        assert!(wait_for_watch(&manager, &root, true).await);
+
+        let file = root.join("file.txt");
+        assert!(notify_by_writing(&file, b"first", &mut events).await);
+        let first = dialed.recv().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), first.closed())
+            .await
+            .expect("idle connection was left open");
+
+        assert!(notify_by_writing(&file, b"second", &mut events).await);
+        let second = dialed.recv().await.unwrap();
+        assert_ne!(first.stable_id(), second.stable_id());
+        assert!(second.close_reason().is_none());
+
+        let _ = shutdown.send(());
+        server.await.unwrap().unwrap();
+    }
+
+    /// Have `peer` answer heartbeats and report the paths of each notification
+    /// message it gets, batched or not.
+    fn notification_listener(
+        peer: Endpoint,
+    ) -> (JoinHandle<()>, mpsc::UnboundedReceiver<Vec<String>>) {
+        let (notified_tx, notified) = mpsc::unbounded_channel();
+        let listener = tokio::spawn(async move {
+            let connection = peer
+                .accept()
+                .await
+                .unwrap()
+                .accept()
+                .unwrap()
+                .await
+                .unwrap();
+            while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+                let notified_tx = notified_tx.clone();
+                tokio::spawn(async move {
+                    let timeout = Duration::from_secs(10);
+                    read_message(&mut recv, timeout).await?;
+                    let handshake = Message::handshake(8, &Capabilities::ours());
+                    write_message(&mut send, &handshake).await?;
+                    while let Some(msg) = read_message_or_eof(&mut recv, timeout).await? {
+                        match msg {
+                            Message::Ping { nonce } => {
+                                write_message(&mut send, &Message::Pong { nonce }).await?
+                            }
+                            Message::FileUpdateNotification { path } => {
+                                let _ = notified_tx.send(vec![path]);
+                            }
+                            Message::BatchUpdateNotification { paths } => {
+                                let _ = notified_tx.send(paths);
+                            }
+                            msg => anyhow::bail!("Unexpected message: {:?}", msg),
+                        }
+                    }
+                    let _ = send.finish();
+                    anyhow::Ok(())
+                });
+            }
+            drop(peer);
+        });
+        (listener, notified)
+    }
+
+    #[tokio::test]
+    async fn rapid_changes_reach_the_peer_in_one_batch() {
+        let peer_endpoint = loopback_endpoint(None).await;
+        let peer = peer_endpoint.id();
+        let peer_addr = loopback_addr(&peer_endpoint);
+        let (listener, mut notified) = notification_listener(peer_endpoint);
+
+        let home = TempDir::new().unwrap();
+        let store = Store::new(&home.path().join("data")).unwrap();
+        let root = std::fs::canonicalize(home.path()).unwrap().join("shared");
+        std::fs::create_dir(&root).unwrap();
+        store
+            .add_sync_with_watch(peer, "/remote".to_string(), root.clone())
+            .unwrap();
+        let endpoint = loopback_endpoint(None).await;
+        let known = StaticProvider::new();
+        known.add_endpoint_info(peer_addr);
+        endpoint.discovery().add(known);
+        let manager = manager_on(endpoint, &store, Duration::from_millis(100))
+            .with_notify_batch(Some(Duration::from_secs(1)));
+        manager.run().await.unwrap();
+        assert!(wait_for_watch(&manager, &root, true).await);
+
+        // Like a checkout writing a tree.
+        for i in 0..50 {
+            std::fs::write(root.join(format!("file{}.txt", i)), b"checked out").unwrap();
+        }
+
+        let paths = tokio::time::timeout(Duration::from_secs(10), notified.recv())
+            .await
+            .expect("peer was not notified")
+            .unwrap();
+        for i in 0..50 {
+            let path = format!("/remote/file{}.txt", i);
+            assert!(paths.contains(&path), "{} missing from {:?}", path, paths);
+        }
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(notified.try_recv().is_err(), "changes were notified again");
+        listener.abort();
+    }
+
+    #[tokio::test]
+    async fn file_unchanged_since_its_last_sync_is_not_notified() {
+        let peer_endpoint = loopback_endpoint(None).await;
+        let peer = peer_endpoint.id();
+        let peer_addr = loopback_addr(&peer_endpoint);
+        let (listener, mut notified) = notification_listener(peer_endpoint);
+
+        let home = TempDir::new().unwrap();
+        let store = Store::new(&home.path().join("data")).unwrap();
+        let file = std::fs::canonicalize(home.path())
+            .unwrap()
+            .join("notes.txt");
+        std::fs::write(&file, b"synced").unwrap();
+        store
+            .add_sync_with_watch(peer, "/remote/notes.txt".to_string(), file.clone())
+            .unwrap();
+        let synced = *blake3::hash(b"synced").as_bytes();
+        store
+            .record_sync_result(&file, peer, "/remote/notes.txt", Some(synced), 1)
+            .unwrap();
+        let endpoint = loopback_endpoint(None).await;
+        let known = StaticProvider::new();
+        known.add_endpoint_info(peer_addr);
+        endpoint.discovery().add(known);
+        let manager = manager_on(endpoint, &store, Duration::from_millis(100));
+        let mut events = manager.subscribe();
+        manager.run().await.unwrap();
+        assert!(wait_for_watch(&manager, &file, true).await);
+
+        // Rewriting what was last synced is seen, but not passed on.
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                std::fs::write(&file, b"synced").unwrap();
+                let wait = tokio::time::timeout(Duration::from_millis(300), events.recv());
+                if let Ok(Ok(SyncEvent::LocalChangeDetected { .. })) = wait.await {
+                    break;
+                }
+            }
+        })
+        .await
+        .expect("local change was not detected");
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(notified.try_recv().is_err(), "unchanged file was notified");
+
+        assert!(notify_by_writing(&file, b"edited", &mut events).await);
+        let paths = tokio::time::timeout(Duration::from_secs(10), notified.recv())
+            .await
+            .expect("peer was not notified")
+            .unwrap();
+        assert_eq!(paths, vec!["/remote/notes.txt".to_string()]);
+        listener.abort();
+    }
 }
@@ -1,20 +1,96 @@
 use anyhow::{Context, Result};
 use fast_rsync::{Signature, SignatureOptions};
+use memmap2::Mmap;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::io::AsyncReadExt;
+use tokio::task::JoinHandle;
 
 // Constants for rsync
-const BLOCK_SIZE: u32 = 1024; // 1KB blocks
-const CRYPTO_HASH_SIZE: u32 = 8; // 8 bytes for strong hash
+/// Smallest block size [`block_size_for`] picks.
+pub const MIN_BLOCK_SIZE: u32 = 512;
+/// Largest block size [`block_size_for`] picks.
+pub const MAX_BLOCK_SIZE: u32 = 128 * 1024;
+/// Bytes of each block's strong hash kept in a signature by default.
+pub const DEFAULT_STRONG_HASH_SIZE: u32 = 16;
+/// Longest strong hash a signature can hold: a whole MD4 digest.
+pub const MAX_STRONG_HASH_SIZE: u32 = 16;
+/// Shortest strong hash a delta is computed against. Shorter ones make it
+/// too likely that a delta applies cleanly yet yields the wrong contents.
+pub const MIN_STRONG_HASH_SIZE: u32 = 8;
 
-pub fn calculate_signature(data: &[u8]) -> Result<Vec<u8>> {
+/// Block size for a file of `len` bytes: roughly `sqrt(len)`, rounded up to a
+/// power of two and kept between [`MIN_BLOCK_SIZE`] and [`MAX_BLOCK_SIZE`].
+pub fn block_size_for(len: u64) -> u32 {
+    let root = (len as f64).sqrt() as u64;
+    root.next_power_of_two()
+        .clamp(MIN_BLOCK_SIZE as u64, MAX_BLOCK_SIZE as u64) as u32
+}
+
+/// Signature of `data`, using `block_size` if given and otherwise one picked
+/// by [`block_size_for`], keeping `strong_hash_size` bytes of each block's
+/// strong hash. Both are recorded in the serialized signature (see
+/// [`signature_options`]), so [`calculate_delta`] always uses the same ones.
+pub fn calculate_signature(
+    data: &[u8],
+    block_size: Option<u32>,
+    strong_hash_size: u32,
+) -> Result<Vec<u8>> {
+    let block_size = block_size.unwrap_or_else(|| block_size_for(data.len() as u64));
+    if block_size == 0 {
+        anyhow::bail!("Block size must be greater than zero");
+    }
+    if !(MIN_STRONG_HASH_SIZE..=MAX_STRONG_HASH_SIZE).contains(&strong_hash_size) {
+        anyhow::bail!(
+            "Strong hash size must be between {} and {} bytes, not {}",
+            MIN_STRONG_HASH_SIZE,
+            MAX_STRONG_HASH_SIZE,
+            strong_hash_size
+        );
+    }
     let options = SignatureOptions {
-        block_size: BLOCK_SIZE,
-        crypto_hash_size: CRYPTO_HASH_SIZE,
+        block_size,
+        crypto_hash_size: strong_hash_size,
     };
     let signature = Signature::calculate(data, options);
     Ok(signature.serialized().to_vec())
 }
 
+/// Options the serialized signature was calculated with, read from its
+/// header: a magic number followed by the block size and strong hash size,
+/// each a big-endian `u32`.
+pub fn signature_options(signature_data: &[u8]) -> Result<SignatureOptions> {
+    let field = |at: usize| {
+        signature_data
+            .get(at..at + 4)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().expect("four bytes")))
+            .context("Signature is too short to hold its options")
+    };
+    Ok(SignatureOptions {
+        block_size: field(4)?,
+        crypto_hash_size: field(8)?,
+    })
+}
+
+/// Delta turning the data `signature_data` was calculated from into
+/// `new_data`. Signatures with a strong hash shorter than
+/// [`MIN_STRONG_HASH_SIZE`] are refused rather than trusted.
 pub fn calculate_delta(signature_data: &[u8], new_data: &[u8]) -> Result<Vec<u8>> {
+    let options = signature_options(signature_data)?;
+    if options.block_size == 0 {
+        anyhow::bail!("Signature has a block size of zero");
+    }
+    if options.crypto_hash_size < MIN_STRONG_HASH_SIZE {
+        anyhow::bail!(
+            "Signature's {}-byte strong hash is shorter than the {} bytes required",
+            options.crypto_hash_size,
+            MIN_STRONG_HASH_SIZE
+        );
+    }
     let signature = Signature::deserialize(signature_data.to_vec())
         .map_err(|e| anyhow::anyhow!("Invalid signature: {:?}", e))?;
 
@@ -24,9 +100,232 @@ pub fn calculate_delta(signature_data: &[u8], new_data: &[u8]) -> Result<Vec<u8>
     Ok(delta)
 }
 
-pub fn apply_delta(old_data: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
-    let mut out = Vec::new();
+/// Apply `delta` to `old_data`, writing the patched data to `out` as it is
+/// produced rather than collecting it, and return its BLAKE3 hash.
+pub fn apply_delta(old_data: &[u8], delta: &[u8], out: impl Write) -> Result<[u8; 32]> {
+    let mut out = HashingWriter {
+        inner: out,
+        hasher: blake3::Hasher::new(),
+    };
     fast_rsync::apply(old_data, delta, &mut out)
         .map_err(|e| anyhow::anyhow!("Failed to apply delta: {:?}", e))?;
-    Ok(out)
+    out.inner.flush()?;
+    Ok(*out.hasher.finalize().as_bytes())
+}
+
+/// Apply `delta` to `old_data` into a file created at `path`, so that
+/// neither side of a large patch is held on the heap. Pass mapped old data
+/// (see [`map_file`]) to have it read on demand.
+pub fn apply_delta_to_file(old_data: &[u8], delta: &[u8], path: &Path) -> Result<[u8; 32]> {
+    let file =
+        std::fs::File::create(path).with_context(|| format!("Failed to create {:?}", path))?;
+    apply_delta(old_data, delta, std::io::BufWriter::new(file))
+}
+
+/// Writer hashing whatever passes through it.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: blake3::Hasher,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// File contents, read onto the heap or mapped, that blocking tasks share
+/// without copying.
+pub type SharedBytes = Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+/// Run `f` over `data` on a blocking thread, so signatures and deltas of
+/// large files don't stall other transfers on the runtime.
+pub async fn blocking<T: Send + 'static>(
+    data: &SharedBytes,
+    f: impl FnOnce(&[u8]) -> T + Send + 'static,
+) -> Result<T> {
+    let data = data.clone();
+    Ok(tokio::task::spawn_blocking(move || f((*data).as_ref())).await?)
+}
+
+/// Map the file at `path` into memory read-only, so that large files can be
+/// diffed and hashed without copying them onto the heap.
+pub fn map_file(path: &Path) -> Result<Mmap> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    // SAFETY: the mapping is only read. If another process truncates the file
+    // meanwhile we may fault, the same risk every mmap-based reader takes.
+    let map = unsafe { Mmap::map(&file) }.with_context(|| format!("Failed to map {:?}", path))?;
+    Ok(map)
+}
+
+/// BLAKE3 hash of the file at `path`, read in chunks.
+pub async fn hash_file(path: &Path) -> Result<[u8; 32]> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("Failed to open {:?} for hashing", path))?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(*hasher.finalize().as_bytes())
+}
+
+/// BLAKE3 hash of the file at `path`, read on the calling thread.
+pub fn hash_file_blocking(path: &Path) -> Result<[u8; 32]> {
+    let mut file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to open {:?} for hashing", path))?;
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(*hasher.finalize().as_bytes())
+}
+
+/// Most items a [`HashQueue`] holds behind files still being hashed.
+const MAX_QUEUED: usize = 1024;
+
+/// A file being hashed on a blocking thread.
+type Hashing = JoinHandle<Result<[u8; 32]>>;
+
+/// Items handed back in the order they were queued, with the hashes of the
+/// files queued along with them. Up to `limit` files are hashed at once on
+/// blocking threads, so results can be used while later files are still
+/// being read.
+pub struct HashQueue<T> {
+    limit: usize,
+    queued: VecDeque<(T, Option<Hashing>)>,
+    /// Queued items with a file being hashed
+    hashing: usize,
+}
+
+impl<T> HashQueue<T> {
+    /// A queue hashing at most `limit` files at once; 1 hashes them one by
+    /// one.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit: limit.max(1),
+            queued: VecDeque::new(),
+            hashing: 0,
+        }
+    }
+
+    /// Queue `item`, starting to hash `file` for it if there is one.
+    pub fn push(&mut self, item: T, file: Option<PathBuf>) {
+        let task = file.map(|file| {
+            self.hashing += 1;
+            tokio::task::spawn_blocking(move || hash_file_blocking(&file))
+        });
+        self.queued.push_back((item, task));
+    }
+
+    /// Whether to take the oldest item with [`pop`](Self::pop) before
+    /// queuing more: it needs no hash, or as many files are being hashed or
+    /// items queued as may be.
+    pub fn should_pop(&self) -> bool {
+        self.hashing >= self.limit
+            || self.queued.len() >= MAX_QUEUED
+            || self.queued.front().is_some_and(|(_, task)| task.is_none())
+    }
+
+    /// The oldest item and its file's hash, once hashed, or `None` if
+    /// nothing is queued. The item comes back even if its file couldn't be
+    /// hashed.
+    pub async fn pop(&mut self) -> Option<(T, Result<Option<[u8; 32]>>)> {
+        let (item, task) = self.queued.pop_front()?;
+        let Some(task) = task else {
+            return Some((item, Ok(None)));
+        };
+        self.hashing -= 1;
+        let hash = match task.await {
+            Ok(Ok(hash)) => Ok(Some(hash)),
+            Ok(Err(e)) => Err(e),
+            Err(e) => Err(e.into()),
+        };
+        Some((item, hash))
+    }
+}
+
+/// Unix permission bits of `metadata`.
+#[cfg(unix)]
+pub fn file_mode(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+/// Files have no Unix permission bits here.
+#[cfg(not(unix))]
+pub fn file_mode(_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Give the file at `path` the modification time and mode a peer listed for
+/// it, without touching its contents. Returns whether anything had to change.
+/// Modes are left alone on platforms without them.
+pub fn set_metadata(path: &Path, modified: u64, mode: Option<u32>) -> Result<bool> {
+    let metadata = std::fs::metadata(path)?;
+    let mut changed = false;
+    if metadata.modified()?.duration_since(UNIX_EPOCH)?.as_secs() != modified {
+        // Owners may set times through a read-only handle, so this works
+        // whatever the mode is about to become.
+        std::fs::File::open(path)?.set_modified(UNIX_EPOCH + Duration::from_secs(modified))?;
+        changed = true;
+    }
+    #[cfg(unix)]
+    if let Some(mode) = mode.filter(|&mode| file_mode(&metadata) != Some(mode)) {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        changed = true;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    Ok(changed)
+}
+
+/// Files and directories flushed by [`commit_file`] so far.
+static FLUSHES: AtomicU64 = AtomicU64::new(0);
+
+/// How many times [`commit_file`] has flushed a file or directory to disk in
+/// this process.
+pub fn flush_count() -> u64 {
+    FLUSHES.load(Ordering::Relaxed)
+}
+
+/// Move the fully written `partial` into place at `target`. With `fsync` the
+/// contents are flushed first and the directory entry after, so the file
+/// survives a crash once this returns.
+pub async fn commit_file(partial: &Path, target: &Path, fsync: bool) -> Result<()> {
+    if fsync {
+        tokio::fs::File::open(partial).await?.sync_all().await?;
+        FLUSHES.fetch_add(1, Ordering::Relaxed);
+    }
+    tokio::fs::rename(partial, target).await?;
+    // Directories can't be opened for syncing elsewhere; there the rename is
+    // as durable as the platform makes it.
+    #[cfg(unix)]
+    if fsync {
+        let dir = match target.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        tokio::fs::File::open(dir).await?.sync_all().await?;
+        FLUSHES.fetch_add(1, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Seconds since the Unix epoch.
+pub fn unix_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
 }
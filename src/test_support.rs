@@ -0,0 +1,135 @@
+//! A server and client talking over loopback, for tests of internals.
+
+use iroh::{
+    discovery::static_provider::StaticProvider, Endpoint, EndpointAddr, PublicKey, RelayMode,
+    SecretKey,
+};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use tokio::{sync::oneshot, task::JoinHandle};
+
+use crate::{
+    cli::{
+        copy::CopyOptions,
+        serve::{self, Control, ServeOptions},
+    },
+    client::SyncClient,
+    config::Config,
+    protocol::SUPPORTED_ALPNS,
+    store::{AccessMode, Store},
+    sync_manager::SyncEvent,
+};
+
+/// A server sharing `served` and a client allowed to read and write it.
+pub struct Harness {
+    pub served: PathBuf,
+    pub local: PathBuf,
+    pub server_id: PublicKey,
+    pub client: SyncClient,
+    shutdown: Option<oneshot::Sender<()>>,
+    server_task: Option<JoinHandle<anyhow::Result<()>>>,
+    _dirs: Vec<TempDir>,
+}
+
+impl Harness {
+    pub async fn start() -> Self {
+        Self::start_with(ServeOptions::default(), Control::default()).await
+    }
+
+    /// Like [`Harness::start`], with the server run with `opts` and `control`.
+    pub async fn start_with(opts: ServeOptions, control: Control) -> Self {
+        let config = test_config();
+        let served_dir = TempDir::new().unwrap();
+        let local_dir = TempDir::new().unwrap();
+        let server_data = TempDir::new().unwrap();
+        let served = std::fs::canonicalize(served_dir.path()).unwrap();
+        let local = std::fs::canonicalize(local_dir.path()).unwrap();
+
+        let server_endpoint = loopback_endpoint(None).await;
+        let server_id = server_endpoint.id();
+        let addrs = StaticProvider::new();
+        addrs.add_endpoint_info(loopback_addr(&server_endpoint));
+        let client_endpoint = loopback_endpoint(Some(addrs)).await;
+
+        let server_store = Store::new(server_data.path()).unwrap();
+        server_store
+            .allow_peer(&served, client_endpoint.id(), AccessMode::ReadWrite)
+            .unwrap();
+
+        let (shutdown, stop) = oneshot::channel();
+        let server_task = tokio::spawn(serve::serve(
+            server_endpoint,
+            config.clone(),
+            server_store,
+            opts,
+            SyncEvent::channel(),
+            control,
+            async {
+                let _ = stop.await;
+            },
+        ));
+
+        Self {
+            served,
+            local,
+            server_id,
+            client: SyncClient::from_endpoint(client_endpoint, config),
+            shutdown: Some(shutdown),
+            server_task: Some(server_task),
+            _dirs: vec![served_dir, local_dir, server_data],
+        }
+    }
+
+    pub fn remote(&self, relative: &str) -> String {
+        self.served.join(relative).to_string_lossy().into_owned()
+    }
+
+    pub async fn copy(&self, remote: &str, local: &Path) -> anyhow::Result<()> {
+        self.client
+            .copy(self.server_id, remote, local, CopyOptions::default())
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn stop(mut self) {
+        let _ = self.shutdown.take().unwrap().send(());
+        self.server_task.take().unwrap().await.unwrap().unwrap();
+    }
+}
+
+pub fn test_config() -> Config {
+    let mut config = Config::default();
+    config.discovery.pkarr = false;
+    config.discovery.dns = false;
+    config.discovery.mdns = false;
+    config.idle_timeout_secs = 10;
+    config
+}
+
+pub async fn loopback_endpoint(discovery: Option<StaticProvider>) -> Endpoint {
+    let mut builder = Endpoint::builder()
+        .clear_discovery()
+        .secret_key(SecretKey::generate(&mut rand::rng()))
+        .alpns(SUPPORTED_ALPNS.iter().map(|alpn| alpn.to_vec()).collect())
+        .relay_mode(RelayMode::Disabled)
+        .bind_addr_v4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
+    if let Some(discovery) = discovery {
+        builder = builder.discovery(discovery);
+    }
+    builder.bind().await.unwrap()
+}
+
+/// Address of `endpoint` reachable over loopback.
+pub fn loopback_addr(endpoint: &Endpoint) -> EndpointAddr {
+    let mut addr = EndpointAddr::new(endpoint.id());
+    for socket in endpoint.bound_sockets() {
+        let ip: IpAddr = if socket.is_ipv4() {
+            Ipv4Addr::LOCALHOST.into()
+        } else {
+            Ipv6Addr::LOCALHOST.into()
+        };
+        addr = addr.with_ip_addr(SocketAddr::new(ip, socket.port()));
+    }
+    addr
+}
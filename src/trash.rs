@@ -0,0 +1,105 @@
+//! Backups of local files taken before a sync overwrites them.
+
+use std::cmp::Reverse;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Directory under a sync root that overwritten files are moved into.
+pub const TRASH_DIR: &str = ".syncr-trash";
+
+/// How long backups are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    /// Backups older than this are deleted
+    pub max_age: Duration,
+    /// Most backups kept of any one file; the oldest go first
+    pub max_versions: usize,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            max_age: Duration::from_secs(30 * 24 * 60 * 60),
+            max_versions: 10,
+        }
+    }
+}
+
+/// The trash directory of one sync root. A file at `<root>/a/b.txt` is kept
+/// as `<root>/.syncr-trash/a/b.txt.<unix millis>`.
+#[derive(Debug, Clone)]
+pub struct Trash {
+    root: PathBuf,
+    retention: Retention,
+}
+
+impl Trash {
+    pub fn new(root: impl Into<PathBuf>, retention: Retention) -> Self {
+        Self {
+            root: root.into(),
+            retention,
+        }
+    }
+
+    pub fn dir(&self) -> PathBuf {
+        self.root.join(TRASH_DIR)
+    }
+
+    /// Move `file` into the trash, then prune old backups of it. Moving
+    /// rather than copying means the file is either still in place or
+    /// already backed up, whenever the caller's write happens.
+    pub fn keep(&self, file: &Path) -> std::io::Result<PathBuf> {
+        let relative = file.strip_prefix(&self.root).unwrap_or(file);
+        let name = relative.file_name().unwrap_or_default().to_string_lossy();
+        let dir = match relative.parent() {
+            Some(parent) => self.dir().join(parent),
+            None => self.dir(),
+        };
+        std::fs::create_dir_all(&dir)?;
+
+        let stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let backup = dir.join(format!("{}.{}", name, stamp));
+        std::fs::rename(file, &backup)?;
+        info!("Backed up {:?} to {:?}", file, backup);
+
+        if let Err(e) = self.prune(&dir, &name) {
+            warn!("Failed to prune old backups of {:?}: {}", file, e);
+        }
+        Ok(backup)
+    }
+
+    /// Delete backups of `name` in `dir` beyond the retention limits.
+    fn prune(&self, dir: &Path, name: &str) -> std::io::Result<()> {
+        let prefix = format!("{}.", name);
+        let mut versions = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let stamp = file_name
+                .to_str()
+                .and_then(|n| n.strip_prefix(&prefix))
+                .and_then(|s| s.parse::<u64>().ok());
+            if let Some(stamp) = stamp {
+                versions.push((stamp, entry.path()));
+            }
+        }
+        // Newest first
+        versions.sort_by_key(|(stamp, _)| Reverse(*stamp));
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let max_age = self.retention.max_age.as_millis() as u64;
+        for (i, (stamp, path)) in versions.into_iter().enumerate() {
+            if i >= self.retention.max_versions || now.saturating_sub(stamp) > max_age {
+                std::fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
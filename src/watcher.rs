@@ -1,49 +1,692 @@
 use anyhow::Result;
-use notify::{Config, RecommendedWatcher, RecursiveMode, Watcher};
+use notify::{
+    event::{AccessKind, AccessMode, ModifyKind},
+    Config, EventKind, RecommendedWatcher, Watcher,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
 use tokio::sync::mpsc;
+use tracing::{info, warn};
 
+pub use notify::RecursiveMode;
+
+/// A change reported by the watcher.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub path: PathBuf,
+    /// The path was removed or renamed away, which ends a watch on it
+    pub removed: bool,
+    /// What kind of change it was; more than one when changes to the path
+    /// were merged
+    pub kinds: EventMask,
+}
+
+/// Whether notify's `kind` is only something being opened or read.
+fn is_read(kind: &EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::Access(
+            AccessKind::Open(_) | AccessKind::Read | AccessKind::Close(AccessMode::Read)
+        )
+    )
+}
+
+/// A set of kinds of change, which syncs use to pick the changes that set
+/// them off.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EventMask(u8);
+
+impl EventMask {
+    pub const NONE: EventMask = EventMask(0);
+    pub const CREATE: EventMask = EventMask(1);
+    /// The contents were written
+    pub const CONTENT: EventMask = EventMask(1 << 1);
+    /// Permissions, timestamps or other metadata changed
+    pub const METADATA: EventMask = EventMask(1 << 2);
+    pub const RENAME: EventMask = EventMask(1 << 3);
+    pub const REMOVE: EventMask = EventMask(1 << 4);
+    /// Accesses and changes the platform doesn't classify
+    pub const OTHER: EventMask = EventMask(1 << 5);
+    pub const ALL: EventMask = EventMask((1 << 6) - 1);
+
+    /// The kind of change notify reported as `kind`.
+    pub fn of(kind: &EventKind) -> EventMask {
+        match kind {
+            EventKind::Create(_) => EventMask::CREATE,
+            EventKind::Modify(ModifyKind::Metadata(_)) => EventMask::METADATA,
+            EventKind::Modify(ModifyKind::Name(_)) => EventMask::RENAME,
+            EventKind::Modify(_) => EventMask::CONTENT,
+            EventKind::Remove(_) => EventMask::REMOVE,
+            EventKind::Access(_) | EventKind::Any | EventKind::Other => EventMask::OTHER,
+        }
+    }
+
+    pub fn union(self, other: EventMask) -> EventMask {
+        EventMask(self.0 | other.0)
+    }
+
+    pub fn intersects(self, other: EventMask) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl Default for EventMask {
+    fn default() -> Self {
+        EventMask::ALL
+    }
+}
+
+impl std::fmt::Display for EventMask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if *self == EventMask::ALL {
+            return f.write_str("all");
+        }
+        let names = [
+            (EventMask::CREATE, "create"),
+            (EventMask::CONTENT, "content"),
+            (EventMask::METADATA, "metadata"),
+            (EventMask::RENAME, "rename"),
+            (EventMask::REMOVE, "remove"),
+            (EventMask::OTHER, "other"),
+        ];
+        let names: Vec<&str> = names
+            .iter()
+            .filter(|(kind, _)| self.intersects(*kind))
+            .map(|(_, name)| *name)
+            .collect();
+        if names.is_empty() {
+            f.write_str("none")
+        } else {
+            f.write_str(&names.join(","))
+        }
+    }
+}
+
+/// Changes as they are reported, taken from the watcher once with
+/// [`FileWatcher::take_events`].
+///
+/// Up to [`EVENT_BUFFER`] changes wait here for the consumer. Past that,
+/// further changes are held back and merged per path until there is room:
+/// a burst is never dropped and never stalls notify, but a path that
+/// changed several times meanwhile is reported once, with the kinds of all
+/// those changes, and as removed if any of them removed it.
+pub type WatchEvents = mpsc::Receiver<Result<Change>>;
+
+/// Changes waiting in [`WatchEvents`] before they are merged per path.
+pub const EVENT_BUFFER: usize = 100;
+
+/// Why a path couldn't be watched.
+#[derive(Debug, thiserror::Error)]
+pub enum WatchError {
+    #[error(
+        "Ran out of file watches while watching {0:?}; other paths are still watched. \
+         On Linux, raise fs.inotify.max_user_watches, e.g. with \
+         `sudo sysctl fs.inotify.max_user_watches=524288`"
+    )]
+    LimitReached(PathBuf),
+    #[error(transparent)]
+    Notify(notify::Error),
+}
+
+impl WatchError {
+    /// Classify `error`, hit while watching `path`.
+    pub fn new(path: &Path, error: notify::Error) -> Self {
+        if is_watch_limit(&error) {
+            WatchError::LimitReached(path.to_path_buf())
+        } else {
+            WatchError::Notify(error)
+        }
+    }
+}
+
+/// Whether `error` means the OS has no watches left to hand out. inotify
+/// signals that with `ENOSPC`, which notify usually, but not always,
+/// translates.
+fn is_watch_limit(error: &notify::Error) -> bool {
+    match &error.kind {
+        notify::ErrorKind::MaxFilesWatch => true,
+        #[cfg(target_os = "linux")]
+        notify::ErrorKind::Io(e) => e.raw_os_error() == Some(libc::ENOSPC),
+        _ => false,
+    }
+}
+
+/// What the installed watches are for, so events a directory watch reports
+/// only on behalf of a file in it can be told apart.
+#[derive(Debug, Default)]
+struct Targets {
+    /// Watched directories, and how far below them each watch reaches
+    dirs: HashMap<PathBuf, RecursiveMode>,
+    /// Watched files, by the directory watched to see them
+    files: HashMap<PathBuf, HashSet<PathBuf>>,
+}
+
+impl Targets {
+    /// Whether a directory watch already reports the entries of `dir`.
+    fn covers(&self, dir: &Path) -> bool {
+        self.dirs.iter().any(|(watched, mode)| {
+            dir == watched || (*mode == RecursiveMode::Recursive && dir.starts_with(watched))
+        })
+    }
+
+    /// Whether an event about `path` concerns something that is watched.
+    fn wants(&self, path: &Path) -> bool {
+        let parent = path.parent();
+        let via_file = self.files.contains_key(path)
+            || parent.is_some_and(|parent| self.files.contains_key(parent));
+        if !via_file {
+            return true;
+        }
+        let is_file = parent
+            .and_then(|parent| self.files.get(parent))
+            .is_some_and(|files| files.contains(path));
+        is_file || self.covers(path) || parent.is_some_and(|parent| self.covers(parent))
+    }
+}
+
+/// Changes notify reported that the consumer hasn't room for yet: errors in
+/// order, and changes once per path in the order they first came.
+#[derive(Default)]
+struct Backlog {
+    errors: VecDeque<anyhow::Error>,
+    order: VecDeque<PathBuf>,
+    /// The changes to each path in `order`, merged
+    changes: HashMap<PathBuf, Change>,
+    /// Set once the watcher is dropped
+    closed: bool,
+}
+
+impl Backlog {
+    fn push(&mut self, change: Change) {
+        match self.changes.get_mut(&change.path) {
+            Some(merged) => {
+                merged.removed |= change.removed;
+                merged.kinds = merged.kinds.union(change.kinds);
+            }
+            None => {
+                self.order.push_back(change.path.clone());
+                self.changes.insert(change.path.clone(), change);
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<Result<Change>> {
+        if let Some(error) = self.errors.pop_front() {
+            return Some(Err(error));
+        }
+        let path = self.order.pop_front()?;
+        self.changes.remove(&path).map(Ok)
+    }
+}
+
+/// Hands changes from notify's thread, which must not wait on the consumer,
+/// to the [`WatchEvents`] channel.
+#[derive(Default)]
+struct Bridge {
+    backlog: Mutex<Backlog>,
+    ready: Condvar,
+}
+
+impl Bridge {
+    fn send(&self, item: Result<Change>) {
+        let mut backlog = self.backlog.lock().unwrap();
+        match item {
+            Ok(change) => backlog.push(change),
+            Err(e) => backlog.errors.push_back(e),
+        }
+        self.ready.notify_one();
+    }
+
+    fn close(&self) {
+        self.backlog.lock().unwrap().closed = true;
+        self.ready.notify_one();
+    }
+
+    /// Pass the backlog on to `tx` as it has room, until the watcher or the
+    /// receiver is dropped.
+    fn forward(&self, tx: mpsc::Sender<Result<Change>>) {
+        loop {
+            let item = {
+                let mut backlog = self.backlog.lock().unwrap();
+                loop {
+                    if let Some(item) = backlog.pop() {
+                        break item;
+                    }
+                    if backlog.closed {
+                        return;
+                    }
+                    backlog = self.ready.wait(backlog).unwrap();
+                }
+            };
+            // Meanwhile notify adds to the backlog instead of waiting.
+            if tx.blocking_send(item).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Watches a set of paths. Paths that don't exist yet, or that disappear, are
+/// picked up again by [`reconcile`](Self::reconcile) once they are back.
+///
+/// A file is watched through its directory, which every platform supports
+/// alike, with events about the rest of the directory left out.
 pub struct FileWatcher {
     watcher: RecommendedWatcher,
-    rx: mpsc::Receiver<Result<PathBuf>>,
+    rx: Option<WatchEvents>,
+    bridge: Arc<Bridge>,
+    /// Shared with the event handler, which filters by it
+    targets: Arc<Mutex<Targets>>,
+    /// Paths with a live watch, and how far below them it reaches
+    active: HashMap<PathBuf, RecursiveMode>,
+    /// Wanted paths already reported missing, so they're only warned about once
+    missing: HashSet<PathBuf>,
+    /// Wanted paths that couldn't be watched for lack of watches, likewise
+    limited: HashSet<PathBuf>,
 }
 
 impl FileWatcher {
     pub fn new() -> Result<Self> {
-        let (tx, rx) = mpsc::channel(100);
+        let (tx, rx) = mpsc::channel(EVENT_BUFFER);
+        let bridge = Arc::new(Bridge::default());
+        let forwarder = bridge.clone();
+        std::thread::Builder::new()
+            .name("syncr-watch-events".to_string())
+            .spawn(move || forwarder.forward(tx))?;
+        let targets = Arc::new(Mutex::new(Targets::default()));
 
+        let handler_targets = targets.clone();
+        let handler_bridge = bridge.clone();
         let watcher = RecommendedWatcher::new(
             move |res: Result<notify::Event, notify::Error>| {
                 match res {
+                    // Reading a file or walking a directory changes nothing.
+                    Ok(event) if is_read(&event.kind) => {}
                     Ok(event) => {
-                        // Filter for Modify, Create, Remove?
-                        // For now, just send the first path affected
-                        if let Some(path) = event.paths.first() {
-                            let _ = tx.blocking_send(Ok(path.clone()));
+                        let removed = matches!(
+                            event.kind,
+                            EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(_))
+                        );
+                        // For now, just send the first watched path affected
+                        let path = {
+                            let targets = handler_targets.lock().unwrap();
+                            event.paths.iter().find(|path| targets.wants(path)).cloned()
+                        };
+                        if let Some(path) = path {
+                            let kinds = EventMask::of(&event.kind);
+                            handler_bridge.send(Ok(Change {
+                                path,
+                                removed,
+                                kinds,
+                            }));
                         }
                     }
+                    // Watching a directory that appears below a recursive
+                    // watch can run out of watches too.
+                    Err(e) if is_watch_limit(&e) => {
+                        let path = e.paths.first().cloned().unwrap_or_default();
+                        handler_bridge.send(Err(WatchError::new(&path, e).into()));
+                    }
                     Err(e) => {
-                        let _ = tx.blocking_send(Err(anyhow::anyhow!("Watch error: {}", e)));
+                        handler_bridge.send(Err(anyhow::anyhow!("Watch error: {}", e)));
                     }
                 }
             },
             Config::default(),
         )?;
 
-        Ok(Self { watcher, rx })
+        Ok(Self {
+            watcher,
+            rx: Some(rx),
+            bridge,
+            targets,
+            active: HashMap::new(),
+            missing: HashSet::new(),
+            limited: HashSet::new(),
+        })
     }
 
-    pub fn watch(&mut self, path: &Path) -> Result<()> {
-        self.watcher.watch(path, RecursiveMode::Recursive)?;
+    /// The stream of changes. Only the first call returns it.
+    pub fn take_events(&mut self) -> Option<WatchEvents> {
+        self.rx.take()
+    }
+
+    /// Watch `path`, and with [`RecursiveMode::Recursive`] everything below
+    /// it; otherwise only its direct entries. A file is watched through its
+    /// directory, whatever `mode` says.
+    pub fn watch(&mut self, path: &Path, mode: RecursiveMode) -> Result<(), WatchError> {
+        // The handler takes the lock for every event, so it isn't held while
+        // notify is called.
+        match path.parent().filter(|_| path.is_file()) {
+            Some(parent) => {
+                let watched = {
+                    let targets = self.targets.lock().unwrap();
+                    targets.files.contains_key(parent) || targets.covers(parent)
+                };
+                if !watched {
+                    self.watcher
+                        .watch(parent, RecursiveMode::NonRecursive)
+                        .map_err(|e| WatchError::new(path, e))?;
+                }
+                let mut targets = self.targets.lock().unwrap();
+                targets
+                    .files
+                    .entry(parent.to_path_buf())
+                    .or_default()
+                    .insert(path.to_path_buf());
+            }
+            None => {
+                self.watcher
+                    .watch(path, mode)
+                    .map_err(|e| WatchError::new(path, e))?;
+                let mut targets = self.targets.lock().unwrap();
+                targets.dirs.insert(path.to_path_buf(), mode);
+            }
+        }
+        self.active.insert(path.to_path_buf(), mode);
+        self.missing.remove(path);
+        self.limited.remove(path);
         Ok(())
     }
 
+    /// Paths with a live watch.
+    pub fn watched_paths(&self) -> Vec<PathBuf> {
+        self.active.keys().cloned().collect()
+    }
+
     pub fn unwatch(&mut self, path: &Path) -> Result<()> {
+        self.active.remove(path);
+        let mut targets = self.targets.lock().unwrap();
+        let parent = path.parent().filter(|parent| {
+            targets
+                .files
+                .get(*parent)
+                .is_some_and(|files| files.contains(path))
+        });
+        if let Some(parent) = parent {
+            let files = targets.files.get_mut(parent).expect("checked above");
+            files.remove(path);
+            let emptied = files.is_empty();
+            if emptied {
+                targets.files.remove(parent);
+            }
+            // The directory stays watched for its other files, or for itself.
+            let unused = emptied && !targets.covers(parent);
+            drop(targets);
+            if unused {
+                self.watcher.unwatch(parent)?;
+            }
+            return Ok(());
+        }
+
+        targets.dirs.remove(path);
+        // Directories of watched files this watch reported for need a watch
+        // of their own again.
+        let uncovered: Vec<PathBuf> = targets
+            .files
+            .keys()
+            .filter(|dir| dir.starts_with(path) && !targets.covers(dir))
+            .cloned()
+            .collect();
+        drop(targets);
         self.watcher.unwatch(path)?;
+        for dir in uncovered {
+            self.watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+        }
         Ok(())
     }
 
-    pub async fn next_event(&mut self) -> Option<Result<PathBuf>> {
-        self.rx.recv().await
+    /// Bring the active watches in line with `wanted`: watch paths that now
+    /// exist, drop watches on paths that are gone or no longer wanted, and
+    /// reinstall those wanted with a different mode.
+    pub fn reconcile(&mut self, wanted: &[(PathBuf, RecursiveMode)]) {
+        let stale: Vec<PathBuf> = self
+            .active
+            .iter()
+            .filter(|&(path, mode)| !wanted.contains(&(path.clone(), *mode)) || !path.exists())
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in stale {
+            // The watch may already have ended with the path.
+            let _ = self.unwatch(&path);
+        }
+        let is_wanted = |path: &PathBuf| wanted.iter().any(|(wanted, _)| wanted == path);
+        self.missing.retain(is_wanted);
+        self.limited.retain(is_wanted);
+
+        for (path, mode) in wanted {
+            if self.active.contains_key(path) {
+                continue;
+            }
+            if !path.exists() {
+                if self.missing.insert(path.clone()) {
+                    warn!(
+                        "Watched path does not exist: {:?}, will watch it once it does",
+                        path
+                    );
+                }
+                continue;
+            }
+            match self.watch(path, *mode) {
+                Ok(()) => info!("Watching path: {:?}", path),
+                Err(e @ WatchError::LimitReached(_)) => {
+                    // Retried like any failure, in case the limit is raised.
+                    if self.limited.insert(path.clone()) {
+                        warn!("{}", e);
+                    }
+                }
+                Err(e) => warn!("Failed to watch {:?}, will retry: {}", path, e),
+            }
+        }
+    }
+
+    /// Re-establish the watch on `path` after it was removed, if it's one of
+    /// the watched paths. Without this a path deleted and recreated between
+    /// two reconciles would keep a watch that no longer delivers events.
+    pub fn rearm(&mut self, path: &Path) {
+        let Some(&mode) = self.active.get(path) else {
+            return;
+        };
+        let _ = self.unwatch(path);
+        if path.exists() {
+            if let Err(e) = self.watch(path, mode) {
+                warn!("Failed to watch {:?} again, will retry: {}", path, e);
+            }
+        }
+    }
+}
+
+impl Drop for FileWatcher {
+    fn drop(&mut self) {
+        self.bridge.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::Path;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    use super::{Change, FileWatcher, RecursiveMode, WatchError, WatchEvents, EVENT_BUFFER};
+
+    /// The next change to `path`, waiting at most a few seconds.
+    async fn next_change(events: &mut WatchEvents, path: &Path) -> Option<Change> {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(res) = events.recv().await {
+                if let Ok(change) = res {
+                    if change.path == path {
+                        return Some(change);
+                    }
+                }
+            }
+            None
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    #[tokio::test]
+    async fn recreated_file_is_watched_again() {
+        let dir = TempDir::new().unwrap();
+        let file = std::fs::canonicalize(dir.path())
+            .unwrap()
+            .join("watched.txt");
+        std::fs::write(&file, b"v1").unwrap();
+
+        let mut watcher = FileWatcher::new().unwrap();
+        let mut events = watcher.take_events().unwrap();
+        watcher.reconcile(&[(file.clone(), RecursiveMode::Recursive)]);
+
+        std::fs::remove_file(&file).unwrap();
+        loop {
+            let change = next_change(&mut events, &file)
+                .await
+                .expect("no removal event");
+            if change.removed {
+                watcher.rearm(&change.path);
+                break;
+            }
+        }
+
+        std::fs::write(&file, b"v2").unwrap();
+        watcher.reconcile(&[(file.clone(), RecursiveMode::Recursive)]);
+        // Let anything from recreating the file arrive before the real check.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        while events.try_recv().is_ok() {}
+
+        std::fs::write(&file, b"v3").unwrap();
+        assert!(next_change(&mut events, &file).await.is_some());
+    }
+
+    #[test]
+    fn missing_path_is_watched_once_it_appears() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("later");
+
+        let mut watcher = FileWatcher::new().unwrap();
+        watcher.reconcile(&[(path.clone(), RecursiveMode::Recursive)]);
+        assert!(watcher.unwatch(&path).is_err());
+
+        std::fs::create_dir(&path).unwrap();
+        watcher.reconcile(&[(path.clone(), RecursiveMode::Recursive)]);
+        watcher.unwatch(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn non_recursive_watch_ignores_nested_changes() {
+        let dir = TempDir::new().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        std::fs::create_dir(root.join("nested")).unwrap();
+        let nested = root.join("nested").join("deep.txt");
+        let top = root.join("top.txt");
+
+        let mut watcher = FileWatcher::new().unwrap();
+        let mut events = watcher.take_events().unwrap();
+        watcher.reconcile(&[(root.clone(), RecursiveMode::NonRecursive)]);
+
+        std::fs::write(&nested, b"below").unwrap();
+        assert!(next_change(&mut events, &nested).await.is_none());
+
+        std::fs::write(&top, b"on top").unwrap();
+        assert!(next_change(&mut events, &top).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn single_file_watch_reports_only_that_file() {
+        let dir = TempDir::new().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        let file = root.join("watched.txt");
+        let sibling = root.join("sibling.txt");
+        std::fs::write(&file, b"v1").unwrap();
+
+        let mut watcher = FileWatcher::new().unwrap();
+        let mut events = watcher.take_events().unwrap();
+        watcher.reconcile(&[(file.clone(), RecursiveMode::Recursive)]);
+        assert_eq!(watcher.watched_paths(), vec![file.clone()]);
+
+        std::fs::write(&sibling, b"next door").unwrap();
+        std::fs::write(&file, b"v2").unwrap();
+        // The sibling changed first, but only the file is reported.
+        let change = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("no change reported")
+            .unwrap()
+            .unwrap();
+        assert_eq!(change.path, file);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        while let Ok(res) = events.try_recv() {
+            assert_eq!(res.unwrap().path, file);
+        }
+
+        // Dropping the file's watch drops the directory's with it.
+        watcher.unwatch(&file).unwrap();
+        std::fs::write(&file, b"v3").unwrap();
+        assert!(next_change(&mut events, &file).await.is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn running_out_of_watches_names_the_limit_and_path() {
+        let path = Path::new("/srv/big");
+        let errors = [
+            notify::Error::new(notify::ErrorKind::MaxFilesWatch),
+            notify::Error::io(std::io::Error::from_raw_os_error(libc::ENOSPC)),
+        ];
+        for error in errors {
+            let error = WatchError::new(path, error);
+            assert!(matches!(error, WatchError::LimitReached(_)), "{:?}", error);
+            let message = error.to_string();
+            assert!(
+                message.contains("fs.inotify.max_user_watches"),
+                "{}",
+                message
+            );
+            assert!(message.contains("/srv/big"), "{}", message);
+        }
+
+        let other = notify::Error::io(std::io::Error::from_raw_os_error(libc::EACCES));
+        assert!(matches!(
+            WatchError::new(path, other),
+            WatchError::Notify(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn burst_beyond_the_buffer_reports_every_path() {
+        let dir = TempDir::new().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        let mut watcher = FileWatcher::new().unwrap();
+        let mut events = watcher.take_events().unwrap();
+        watcher.reconcile(&[(root.clone(), RecursiveMode::Recursive)]);
+
+        // Nothing is read while the burst comes in, so it overflows the buffer.
+        let count = EVENT_BUFFER * 3;
+        let mut expected: HashSet<_> = (0..count)
+            .map(|i| root.join(format!("file-{}.txt", i)))
+            .collect();
+        for path in &expected {
+            std::fs::write(path, b"burst").unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let drained = tokio::time::timeout(Duration::from_secs(10), async {
+            while !expected.is_empty() {
+                let Some(res) = events.recv().await else {
+                    break;
+                };
+                if let Ok(change) = res {
+                    expected.remove(&change.path);
+                }
+                // A slow consumer.
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await;
+        assert!(drained.is_ok(), "{} paths never reported", expected.len());
     }
 }
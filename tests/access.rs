@@ -0,0 +1,184 @@
+//! Auditing peer access from the command line.
+
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+use syncr::store::{AccessMode, Store};
+
+fn syncr(home: &Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .env("SYNCR_HOME", home)
+        .env_remove("SYNCR_CONFIG")
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "syncr {:?} failed", args);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn peers_groups_grants() {
+    let home = TempDir::new().unwrap();
+    let alice = iroh::SecretKey::generate(&mut rand::rng()).public();
+    let bob = iroh::SecretKey::generate(&mut rand::rng()).public();
+
+    {
+        let store = Store::new(home.path()).unwrap();
+        store.allow_peer("/srv/a", alice, AccessMode::Read).unwrap();
+        store
+            .allow_peer("/srv/b", alice, AccessMode::ReadWrite)
+            .unwrap();
+        store.allow_peer("/srv/b", bob, AccessMode::Write).unwrap();
+        store.add_alias("bob", bob).unwrap();
+    }
+
+    let output = syncr(home.path(), &["peers"]);
+    let alice_section = format!("{}\n    read\t/srv/a\n    read-write\t/srv/b\n", alice);
+    let bob_section = format!("{} (bob)\n    write\t/srv/b\n", bob);
+    assert!(output.contains(&alice_section), "{}", output);
+    assert!(output.contains(&bob_section), "{}", output);
+    assert_eq!(output.lines().count(), 5);
+
+    let output = syncr(home.path(), &["peers", "--by-path"]);
+    assert!(output.starts_with(&format!("/srv/a\n    read\t{}\n/srv/b\n", alice)));
+    assert!(output.contains(&format!("    read-write\t{}\n", alice)));
+    assert!(output.contains(&format!("    write\t{} (bob)\n", bob)));
+}
+
+#[test]
+fn disallow_all_revokes_every_grant() {
+    let home = TempDir::new().unwrap();
+    let alice = iroh::SecretKey::generate(&mut rand::rng()).public();
+    let bob = iroh::SecretKey::generate(&mut rand::rng()).public();
+
+    {
+        let store = Store::new(home.path()).unwrap();
+        for path in ["/srv/a", "/srv/b", "/srv/c"] {
+            store.allow_peer(path, alice, AccessMode::Read).unwrap();
+        }
+        store.allow_peer("/srv/b", bob, AccessMode::Read).unwrap();
+    }
+
+    let output = syncr(home.path(), &["disallow", "--all", &alice.to_string()]);
+    assert!(output.contains("from 3 paths"), "{}", output);
+
+    let store = Store::new(home.path()).unwrap();
+    for (path, grants) in store.list_all_permissions().unwrap() {
+        assert!(grants.iter().all(|g| g.peer != alice), "{:?}", path);
+    }
+    assert_eq!(store.get_permissions("/srv/b").unwrap().len(), 1);
+}
+
+#[test]
+fn unexpired_grant_allows_access() {
+    let home = TempDir::new().unwrap();
+    let alice = iroh::SecretKey::generate(&mut rand::rng()).public();
+    let store = Store::new(home.path()).unwrap();
+
+    let in_an_hour = syncr::sync_utils::unix_timestamp() + 60 * 60;
+    store
+        .allow_peer_until("/srv/a", alice, AccessMode::Read, Some(in_an_hour))
+        .unwrap();
+
+    assert!(store
+        .is_peer_allowed("/srv/a/file.txt", alice, AccessMode::Read)
+        .unwrap());
+    assert!(store.is_known_peer(alice).unwrap());
+    assert_eq!(store.prune_expired_grants().unwrap(), 0);
+}
+
+#[test]
+fn expired_grant_denies_access() {
+    let home = TempDir::new().unwrap();
+    let alice = iroh::SecretKey::generate(&mut rand::rng()).public();
+    let store = Store::new(home.path()).unwrap();
+
+    let an_hour_ago = syncr::sync_utils::unix_timestamp() - 60 * 60;
+    store
+        .allow_peer_until("/srv/a", alice, AccessMode::Read, Some(an_hour_ago))
+        .unwrap();
+
+    // Denied before the sweep has removed the grant...
+    assert!(!store
+        .is_peer_allowed("/srv/a/file.txt", alice, AccessMode::Read)
+        .unwrap());
+    assert!(!store.is_known_peer(alice).unwrap());
+
+    // ...and gone after it.
+    assert_eq!(store.prune_expired_grants().unwrap(), 1);
+    assert!(store.get_permissions("/srv/a").unwrap().is_empty());
+}
+
+#[test]
+fn allow_with_expiry_is_listed() {
+    let home = TempDir::new().unwrap();
+    let dir = home.path().join("shared");
+    std::fs::create_dir(&dir).unwrap();
+    let alice = iroh::SecretKey::generate(&mut rand::rng()).public();
+
+    let alice_id = alice.to_string();
+    syncr(
+        home.path(),
+        &["allow", &alice_id, dir.to_str().unwrap(), "--expires", "2d"],
+    );
+
+    // Listed within the second it was granted, nothing has run out yet.
+    let output = syncr(home.path(), &["peers"]);
+    assert!(
+        output.contains("read-write (expires in 1d 23h)")
+            || output.contains("read-write (expires in 2d)"),
+        "{}",
+        output
+    );
+}
+
+#[test]
+fn group_members_are_allowed_while_they_belong() {
+    let home = TempDir::new().unwrap();
+    let dir = home.path().join("shared");
+    std::fs::create_dir(&dir).unwrap();
+    let file = dir.join("notes.txt");
+    let alice = iroh::SecretKey::generate(&mut rand::rng()).public();
+    let bob = iroh::SecretKey::generate(&mut rand::rng()).public();
+    let (alice_id, bob_id) = (alice.to_string(), bob.to_string());
+    let allowed = |peer| {
+        Store::new(home.path())
+            .unwrap()
+            .is_peer_allowed(&file, peer, AccessMode::Read)
+            .unwrap()
+    };
+
+    syncr(home.path(), &["group", "create", "team"]);
+    syncr(home.path(), &["group", "add", "team", &alice_id]);
+    syncr(
+        home.path(),
+        &[
+            "allow",
+            dir.to_str().unwrap(),
+            "--group",
+            "team",
+            "--mode",
+            "read",
+        ],
+    );
+    assert!(allowed(alice));
+    assert!(!allowed(bob));
+
+    // Members added after the grant get it too.
+    syncr(home.path(), &["group", "add", "team", &bob_id]);
+    assert!(allowed(bob));
+    let output = syncr(home.path(), &["peers"]);
+    assert!(
+        output.contains(&format!(
+            "group team\n    read\t{}\n",
+            std::fs::canonicalize(&dir).unwrap().display()
+        )),
+        "{}",
+        output
+    );
+
+    syncr(home.path(), &["group", "remove", "team", &alice_id]);
+    assert!(!allowed(alice));
+    assert!(allowed(bob));
+}
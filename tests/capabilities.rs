@@ -0,0 +1,60 @@
+//! Negotiating optional protocol features between peers.
+
+use syncr::protocol::{
+    Capabilities, Message, CAP_BATCH_NOTIFY, CAP_CHECK_ACCESS, CAP_CHUNKS, CAP_RETRY, CAP_SHARES,
+    CAP_SKIPPED_ENTRIES,
+};
+
+#[test]
+fn connection_uses_capabilities_both_peers_advertise() {
+    let ours = Capabilities::ours();
+    let newer = Capabilities::from_names([CAP_CHUNKS, "compression"]);
+    let without_chunks = Capabilities::from_names(["compression"]);
+
+    assert_eq!(
+        ours.intersection(&newer),
+        Capabilities::from_names([CAP_CHUNKS])
+    );
+    assert_eq!(newer.intersection(&ours), ours.intersection(&newer));
+    assert_eq!(ours.intersection(&without_chunks), Capabilities::default());
+    assert_eq!(
+        newer.intersection(&without_chunks),
+        Capabilities::from_names(["compression"])
+    );
+}
+
+#[test]
+fn older_peers_have_the_capabilities_of_their_version() {
+    for (version, chunks) in [(1, false), (6, false), (7, true)] {
+        let handshake = Message::handshake(version, &Capabilities::ours());
+        assert!(matches!(handshake, Message::LegacyHandshake { .. }));
+        match handshake.into_current() {
+            Message::Handshake {
+                version: read,
+                capabilities,
+            } => {
+                assert_eq!(read, version);
+                let capabilities = Capabilities::from_names(capabilities);
+                assert_eq!(capabilities.contains(CAP_CHUNKS), chunks, "{}", version);
+            }
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    }
+
+    match Message::handshake(8, &Capabilities::ours()) {
+        Message::Handshake { capabilities, .. } => {
+            assert_eq!(
+                capabilities,
+                vec![
+                    CAP_BATCH_NOTIFY.to_string(),
+                    CAP_CHECK_ACCESS.to_string(),
+                    CAP_CHUNKS.to_string(),
+                    CAP_RETRY.to_string(),
+                    CAP_SHARES.to_string(),
+                    CAP_SKIPPED_ENTRIES.to_string()
+                ]
+            );
+        }
+        msg => panic!("Unexpected message: {:?}", msg),
+    }
+}
@@ -0,0 +1,235 @@
+//! `syncr serve --daemon` detaches and can be found and stopped again.
+#![cfg(unix)]
+
+use iroh::{
+    discovery::static_provider::StaticProvider, Endpoint, EndpointAddr, RelayMode, SecretKey,
+};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+use syncr::{
+    protocol::{ALPN, SUPPORTED_ALPNS},
+    Config, CopyOptions, SyncClient,
+};
+
+fn syncr(home: &Path, args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_syncr"));
+    command
+        .env("SYNCR_HOME", home)
+        .env_remove("SYNCR_CONFIG")
+        .args(args)
+        .stdin(Stdio::null());
+    command
+}
+
+fn is_alive(pid: i32) -> bool {
+    unsafe { libc::kill(pid, 0) == 0 }
+}
+
+/// Start `syncr serve --daemon` in `home`, returning once its PID file is
+/// written.
+fn start_daemon(home: &Path) -> i32 {
+    std::fs::write(
+        home.join("config.toml"),
+        "[discovery]\npkarr = false\ndns = false\nmdns = false\n",
+    )
+    .unwrap();
+
+    let started = Instant::now();
+    let status = syncr(home, &["serve", "--daemon"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(started.elapsed() < Duration::from_secs(10));
+
+    // The detached process writes its PID file once it is running.
+    let pid_path = home.join("syncr.pid");
+    loop {
+        let pid = std::fs::read_to_string(&pid_path)
+            .ok()
+            .and_then(|contents| contents.trim().parse::<i32>().ok());
+        if let Some(pid) = pid {
+            return pid;
+        }
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "no PID file written"
+        );
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn daemon_returns_and_leaves_server_running() {
+    let home = TempDir::new().unwrap();
+    let pid = start_daemon(home.path());
+    let pid_path = home.path().join("syncr.pid");
+    assert!(is_alive(pid));
+
+    let output = syncr(home.path(), &["serve", "--status"]).output().unwrap();
+    assert!(output.status.success());
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        report.contains(&format!("running (pid {})", pid)),
+        "{}",
+        report
+    );
+
+    let output = syncr(home.path(), &["serve", "--stop"]).output().unwrap();
+    assert!(output.status.success());
+    assert!(!is_alive(pid));
+    assert!(!pid_path.exists());
+
+    let output = syncr(home.path(), &["serve", "--status"]).output().unwrap();
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(report.contains("not running"), "{}", report);
+}
+
+#[tokio::test]
+async fn running_server_answers_status_over_the_control_socket() {
+    let home = TempDir::new().unwrap();
+    let pid = start_daemon(home.path());
+
+    // The socket is bound after the PID file is written. The server holds
+    // the store, so `status` only works by asking it.
+    let started = Instant::now();
+    let report = loop {
+        let output = syncr(home.path(), &["status"]).output().unwrap();
+        let report = String::from_utf8(output.stdout).unwrap();
+        if output.status.success() && report.contains("is running") {
+            break report;
+        }
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "no control socket"
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    };
+    assert!(
+        report.contains(&format!("running (pid {}", pid)),
+        "{}",
+        report
+    );
+    assert!(report.contains("Connections: 0\n"), "{}", report);
+    let secret: [u8; 32] = std::fs::read(home.path().join("secret_key"))
+        .unwrap()
+        .try_into()
+        .unwrap();
+    let peer_id = SecretKey::from_bytes(&secret).public();
+    assert!(
+        report.contains(&format!("Peer ID: {}", peer_id)),
+        "{}",
+        report
+    );
+
+    let output = syncr(home.path(), &["serve", "--stop"]).output().unwrap();
+    assert!(output.status.success());
+    assert!(!home.path().join("control.sock").exists());
+    let output = syncr(home.path(), &["status"]).output().unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(report.contains("is not running"), "{}", report);
+}
+
+/// Config for a server bound to `port` on loopback, capped at `limit_rate`.
+fn loopback_config(port: u16, limit_rate: u64) -> String {
+    format!(
+        "bind = \"127.0.0.1:{}\"\nlimit_rate = {}\n\
+         [discovery]\npkarr = false\ndns = false\nmdns = false\n\
+         [relay]\nenabled = false\n",
+        port, limit_rate
+    )
+}
+
+#[tokio::test]
+async fn sighup_applies_a_new_rate_limit_without_dropping_connections() {
+    let home = TempDir::new().unwrap();
+    let served_dir = TempDir::new().unwrap();
+    let served = std::fs::canonicalize(served_dir.path()).unwrap();
+    std::fs::write(served.join("big.bin"), vec![7u8; 256 * 1024]).unwrap();
+    let port = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let config_path = home.path().join("config.toml");
+    std::fs::write(&config_path, loopback_config(port, 64 * 1024)).unwrap();
+    let secret = SecretKey::generate(&mut rand::rng());
+    std::fs::write(home.path().join("secret_key"), secret.to_bytes()).unwrap();
+
+    let addrs = StaticProvider::new();
+    addrs.add_endpoint_info(
+        EndpointAddr::new(secret.public())
+            .with_ip_addr(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)),
+    );
+    let client = Endpoint::builder()
+        .clear_discovery()
+        .discovery(addrs)
+        .secret_key(SecretKey::generate(&mut rand::rng()))
+        .alpns(SUPPORTED_ALPNS.iter().map(|alpn| alpn.to_vec()).collect())
+        .relay_mode(RelayMode::Disabled)
+        .bind_addr_v4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+        .bind()
+        .await
+        .unwrap();
+    let client_id = client.id().to_string();
+    let status = syncr(home.path(), &["allow", &client_id])
+        .arg(&served)
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let mut server = syncr(home.path(), &["serve"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let started = Instant::now();
+    let connection = loop {
+        match client.connect(secret.public(), ALPN).await {
+            Ok(connection) => break connection,
+            Err(e) => assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "server not reachable: {}",
+                e
+            ),
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    };
+
+    let mut config = Config::default();
+    config.discovery.mdns = false;
+    let copier = SyncClient::from_endpoint(client.clone(), config);
+    let remote = served.join("big.bin").to_string_lossy().into_owned();
+    let copy = |local: &str| {
+        copier.copy(
+            secret.public(),
+            remote.clone(),
+            home.path().join(local),
+            CopyOptions::default(),
+        )
+    };
+
+    // The first 64K go out at once, the rest at 64K a second.
+    let started = Instant::now();
+    copy("limited.bin").await.unwrap();
+    assert!(started.elapsed() >= Duration::from_secs(2));
+
+    std::fs::write(&config_path, loopback_config(port, 1 << 30)).unwrap();
+    unsafe { libc::kill(server.id() as i32, libc::SIGHUP) };
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let started = Instant::now();
+    copy("unlimited.bin").await.unwrap();
+    assert!(started.elapsed() < Duration::from_secs(1));
+    assert!(connection.close_reason().is_none());
+
+    unsafe { libc::kill(server.id() as i32, libc::SIGTERM) };
+    server.wait().unwrap();
+}
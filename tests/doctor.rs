@@ -0,0 +1,73 @@
+//! `syncr doctor` reports what is wrong with a setup.
+
+use std::path::Path;
+use std::process::{Command, Output};
+use tempfile::TempDir;
+
+use syncr::Store;
+
+fn doctor(home: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .env("SYNCR_HOME", home)
+        .env_remove("SYNCR_CONFIG")
+        .arg("doctor")
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn healthy_home_passes() {
+    let home = TempDir::new().unwrap();
+    let watched = home.path().join("watched");
+    std::fs::create_dir(&watched).unwrap();
+    {
+        let store = Store::new(home.path()).unwrap();
+        store.add_watch(&watched).unwrap();
+    }
+    let key = iroh::SecretKey::generate(&mut rand::rng());
+    std::fs::write(home.path().join("secret_key"), key.to_bytes()).unwrap();
+
+    let output = doctor(home.path());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("[  ok] Store opens"), "{}", stdout);
+    assert!(
+        stdout.contains(&format!("[  ok] Watched path {:?} exists", watched)),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("All checks passed"), "{}", stdout);
+}
+
+#[test]
+fn missing_watch_path_fails() {
+    let home = TempDir::new().unwrap();
+    let gone = home.path().join("gone");
+    {
+        let store = Store::new(home.path()).unwrap();
+        store.add_watch(&gone).unwrap();
+    }
+
+    let output = doctor(home.path());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(!output.status.success());
+    assert!(stdout.contains("[  ok] Store opens"), "{}", stdout);
+    assert!(
+        stdout.contains(&format!("[FAIL] Watched path {:?} exists", gone)),
+        "{}",
+        stdout
+    );
+    // No identity has been created in this home yet either.
+    assert!(stdout.contains("[FAIL] Secret key loads"), "{}", stdout);
+}
+
+#[test]
+fn locked_store_fails() {
+    let home = TempDir::new().unwrap();
+    let _store = Store::new(home.path()).unwrap();
+
+    let output = doctor(home.path());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(!output.status.success());
+    assert!(stdout.contains("[FAIL] Store opens"), "{}", stdout);
+}
@@ -0,0 +1,94 @@
+//! `syncr config export` and `import` carry a setup between homes.
+
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+use syncr::store::{AccessMode, Store};
+
+fn syncr(home: &Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .env("SYNCR_HOME", home)
+        .env_remove("SYNCR_CONFIG")
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "syncr {:?} failed", args);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn export_import_round_trip() {
+    let source = TempDir::new().unwrap();
+    let target = TempDir::new().unwrap();
+    let peer = iroh::SecretKey::generate(&mut rand::rng()).public();
+
+    {
+        let store = Store::new(source.path()).unwrap();
+        store.add_watch("/srv/data").unwrap();
+        store
+            .allow_peer("/srv/data", peer, AccessMode::Read)
+            .unwrap();
+        store
+            .add_sync(peer, "/remote/docs".to_string(), "/srv/data/docs".into())
+            .unwrap();
+    }
+
+    let export_file = source.path().join("export.json");
+    syncr(
+        source.path(),
+        &["config", "export", "-o", export_file.to_str().unwrap()],
+    );
+    syncr(
+        target.path(),
+        &["config", "import", export_file.to_str().unwrap()],
+    );
+    // Importing twice must not duplicate anything.
+    syncr(
+        target.path(),
+        &["config", "import", export_file.to_str().unwrap()],
+    );
+
+    let original: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&export_file).unwrap()).unwrap();
+    let imported: serde_json::Value =
+        serde_json::from_str(&syncr(target.path(), &["config", "export"])).unwrap();
+    assert_eq!(original, imported);
+}
+
+#[test]
+fn import_rebases_local_paths() {
+    let source = TempDir::new().unwrap();
+    let target = TempDir::new().unwrap();
+    let peer = iroh::SecretKey::generate(&mut rand::rng()).public();
+
+    {
+        let store = Store::new(source.path()).unwrap();
+        store
+            .add_sync(peer, "/remote/docs".to_string(), "/old/docs".into())
+            .unwrap();
+    }
+
+    let export_file = source.path().join("export.json");
+    syncr(
+        source.path(),
+        &["config", "export", "-o", export_file.to_str().unwrap()],
+    );
+    syncr(
+        target.path(),
+        &[
+            "config",
+            "import",
+            export_file.to_str().unwrap(),
+            "--rebase",
+            "/old",
+            "/new",
+        ],
+    );
+
+    let store = Store::new(target.path()).unwrap();
+    let syncs = store.list_syncs().unwrap();
+    assert_eq!(syncs.len(), 1);
+    assert_eq!(syncs[0].0, Path::new("/new/docs"));
+    assert_eq!(syncs[0].1[0].remote_path, "/remote/docs");
+}
@@ -0,0 +1,54 @@
+//! `syncr hash` prints the same BLAKE3 hashes syncs compare.
+
+use std::process::Command;
+use tempfile::TempDir;
+
+fn hash(home: &std::path::Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .env("SYNCR_HOME", home)
+        .env_remove("SYNCR_CONFIG")
+        .env_remove("SYNCR_PROFILE")
+        .arg("hash")
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn hashes_every_file_in_path_order() {
+    let home = TempDir::new().unwrap();
+    let tree = TempDir::new().unwrap();
+    std::fs::create_dir(tree.path().join("sub")).unwrap();
+    std::fs::write(tree.path().join("b.txt"), b"bravo").unwrap();
+    std::fs::write(tree.path().join("a.txt"), b"alpha").unwrap();
+    std::fs::write(tree.path().join("sub/c.txt"), b"charlie").unwrap();
+
+    let expected: String = [
+        ("a.txt", &b"alpha"[..]),
+        ("b.txt", b"bravo"),
+        ("sub/c.txt", b"charlie"),
+    ]
+    .iter()
+    .map(|(path, contents)| format!("{}  {}\n", blake3::hash(contents).to_hex(), path))
+    .collect();
+    let dir = tree.path().to_str().unwrap();
+    assert_eq!(hash(home.path(), &[dir]), expected);
+
+    let json: serde_json::Value =
+        serde_json::from_str(&hash(home.path(), &["--json", dir])).unwrap();
+    assert_eq!(json[2]["path"], "sub/c.txt");
+    assert_eq!(
+        json[2]["hash"],
+        blake3::hash(b"charlie").to_hex().to_string()
+    );
+
+    // A single file is printed as it was given.
+    let file = tree.path().join("a.txt");
+    let file = file.to_str().unwrap();
+    assert_eq!(
+        hash(home.path(), &[file]),
+        format!("{}  {}\n", blake3::hash(b"alpha").to_hex(), file)
+    );
+}
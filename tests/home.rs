@@ -0,0 +1,125 @@
+//! The syncr home directory override keeps all state in one place.
+
+use std::path::PathBuf;
+use std::process::Command;
+use tempfile::TempDir;
+
+use syncr::{config, Config, SyncClient};
+
+#[test]
+fn syncr_home_holds_database() {
+    let home = TempDir::new().unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .env("SYNCR_HOME", home.path())
+        .env_remove("SYNCR_CONFIG")
+        .arg("watch")
+        .status()
+        .unwrap();
+
+    assert!(status.success());
+    assert!(home.path().join("db").is_dir());
+}
+
+#[tokio::test]
+async fn identity_is_created_in_home() {
+    let home = TempDir::new().unwrap();
+    let mut config = Config::load(None, Some(home.path())).unwrap();
+    config.discovery.pkarr = false;
+    config.discovery.dns = false;
+    config.discovery.mdns = false;
+
+    let first = SyncClient::bind(config.clone()).await.unwrap();
+    assert!(home.path().join("secret_key").is_file());
+
+    // The same home yields the same identity.
+    let second = SyncClient::bind(config).await.unwrap();
+    assert_eq!(first.id(), second.id());
+}
+
+/// Run `syncr` with `home` as the syncr home and `args`, returning stdout.
+fn syncr(home: &std::path::Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .env("SYNCR_HOME", home)
+        .env_remove("SYNCR_CONFIG")
+        .env_remove("SYNCR_PROFILE")
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn profiles_have_their_own_identity_and_store() {
+    let home = TempDir::new().unwrap();
+    let work = syncr(home.path(), &["--profile", "work", "key", "show"]);
+    let personal = syncr(home.path(), &["--profile", "personal", "key", "show"]);
+    let default = syncr(home.path(), &["key", "show"]);
+    assert_ne!(work, personal);
+    assert_ne!(work, default);
+    assert_ne!(personal, default);
+    let profiles = home.path().join("profiles");
+    assert!(profiles.join("work/secret_key").is_file());
+    assert!(profiles.join("personal/secret_key").is_file());
+
+    // An alias added in one profile isn't seen from another.
+    let id = personal.trim();
+    syncr(home.path(), &["--profile", "work", "peer", "add", "pc", id]);
+    let listed = syncr(home.path(), &["--profile", "work", "peer", "list"]);
+    assert!(listed.contains(id), "{}", listed);
+    let listed = syncr(home.path(), &["--profile", "personal", "peer", "list"]);
+    assert!(!listed.contains(id), "{}", listed);
+    assert!(profiles.join("work/db").is_dir());
+
+    let listed = syncr(home.path(), &["--profile", "work", "profile", "list"]);
+    assert_eq!(listed, "  personal\n* work\n");
+}
+
+#[test]
+fn xdg_config_home_is_the_default_home() {
+    let xdg = TempDir::new().unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .env("XDG_CONFIG_HOME", xdg.path())
+        .env_remove("SYNCR_HOME")
+        .env_remove("SYNCR_CONFIG")
+        .env_remove("SYNCR_PROFILE")
+        .arg("watch")
+        .status()
+        .unwrap();
+
+    assert!(status.success());
+    assert!(xdg.path().join("syncr/db").is_dir());
+}
+
+#[test]
+fn no_config_dir_is_an_error() {
+    let err = config::config_dir_from(None, None).unwrap_err();
+    assert!(matches!(err, config::ConfigError::NoConfigDir));
+    assert!(err.to_string().contains("SYNCR_HOME"), "{}", err);
+
+    // A relative XDG_CONFIG_HOME is ignored rather than resolved against
+    // wherever syncr happens to run.
+    let platform = PathBuf::from("/etc/xdg");
+    assert_eq!(
+        config::config_dir_from(Some("relative".into()), Some(platform.clone())).unwrap(),
+        platform.join("syncr")
+    );
+    assert!(config::config_dir_from(Some("relative".into()), None).is_err());
+}
+
+#[test]
+fn profile_names_are_plain_directory_names() {
+    let home = TempDir::new().unwrap();
+    for name in ["../escape", ".hidden", "a/b", ""] {
+        let status = Command::new(env!("CARGO_BIN_EXE_syncr"))
+            .env("SYNCR_HOME", home.path())
+            .env_remove("SYNCR_CONFIG")
+            .args(["--profile", name, "key", "show"])
+            .status()
+            .unwrap();
+        assert!(!status.success(), "{:?} was accepted", name);
+    }
+    assert!(!home.path().join("profiles").exists());
+}
@@ -0,0 +1,129 @@
+//! `syncr key` rotates and imports the node's identity.
+
+use std::path::Path;
+use std::process::{Command, Output};
+use tempfile::TempDir;
+
+fn key(home: &Path, args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .env("SYNCR_HOME", home)
+        .env_remove("SYNCR_CONFIG")
+        .arg("key")
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    output
+}
+
+fn peer_id(home: &Path) -> String {
+    let output = key(home, &["show"]);
+    String::from_utf8(output.stdout).unwrap().trim().to_string()
+}
+
+/// Backups of previous keys left in `home`.
+fn backups(home: &Path) -> Vec<std::path::PathBuf> {
+    std::fs::read_dir(home)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "bak"))
+        .collect()
+}
+
+#[test]
+fn rotate_changes_peer_id_and_keeps_the_old_key() {
+    let home = TempDir::new().unwrap();
+    let old_id = peer_id(home.path());
+    let old_key = std::fs::read(home.path().join("secret_key")).unwrap();
+
+    let output = key(home.path(), &["rotate"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    let new_id = peer_id(home.path());
+    assert_ne!(new_id, old_id);
+    assert!(stdout.contains(&new_id), "{}", stdout);
+    assert!(stdout.contains("syncr allow"), "{}", stdout);
+    let backups = backups(home.path());
+    assert_eq!(backups.len(), 1);
+    assert_eq!(std::fs::read(&backups[0]).unwrap(), old_key);
+}
+
+#[test]
+fn import_moves_an_identity_between_homes() {
+    let from = TempDir::new().unwrap();
+    let to = TempDir::new().unwrap();
+    let id = peer_id(from.path());
+    let previous = peer_id(to.path());
+
+    let file = from.path().join("secret_key");
+    key(to.path(), &["import", file.to_str().unwrap()]);
+
+    assert_eq!(peer_id(to.path()), id);
+    assert_ne!(previous, id);
+    assert_eq!(backups(to.path()).len(), 1);
+}
+
+#[test]
+fn import_rejects_a_file_that_is_not_a_key() {
+    let home = TempDir::new().unwrap();
+    let id = peer_id(home.path());
+    let file = home.path().join("not_a_key");
+    std::fs::write(&file, b"too short").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .env("SYNCR_HOME", home.path())
+        .env_remove("SYNCR_CONFIG")
+        .args(["key", "import"])
+        .arg(&file)
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert_eq!(peer_id(home.path()), id);
+    assert!(backups(home.path()).is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn new_key_is_readable_only_by_its_owner() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let home = TempDir::new().unwrap();
+    peer_id(home.path());
+    let mode = std::fs::metadata(home.path().join("secret_key"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    key(home.path(), &["rotate"]);
+    let mode = std::fs::metadata(home.path().join("secret_key"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+}
+
+#[cfg(unix)]
+#[test]
+fn key_others_can_read_is_warned_about() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let home = TempDir::new().unwrap();
+    let id = peer_id(home.path());
+    let file = home.path().join("secret_key");
+    std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .env("SYNCR_HOME", home.path())
+        .env("RUST_LOG", "syncr=warn")
+        .env_remove("SYNCR_CONFIG")
+        .args(["key", "show"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(String::from_utf8(output.stdout).unwrap().trim(), id);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("readable by others"), "{}", stderr);
+    assert!(stderr.contains("chmod 600"), "{}", stderr);
+}
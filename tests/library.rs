@@ -0,0 +1,73 @@
+//! A server and a client set up and driven through the library alone, as an
+//! application embedding syncr would.
+
+use iroh::discovery::static_provider::StaticProvider;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use tempfile::TempDir;
+
+use syncr::{store::AccessMode, Config, CopyOptions, ServeOptions, Store, SyncClient, SyncServer};
+
+/// A config for its own home in `home`, reaching peers only over loopback.
+fn loopback_config(home: &Path) -> Config {
+    let mut config = Config::load(None, Some(home)).unwrap();
+    config.discovery.pkarr = false;
+    config.discovery.dns = false;
+    config.discovery.mdns = false;
+    config.relay.enabled = false;
+    config.bind = Some(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0));
+    config
+}
+
+#[tokio::test]
+async fn bound_client_copies_from_a_bound_server() {
+    let server_home = TempDir::new().unwrap();
+    let client_home = TempDir::new().unwrap();
+    let served = TempDir::new().unwrap();
+    let local = TempDir::new().unwrap();
+    let served = std::fs::canonicalize(served.path()).unwrap();
+    std::fs::write(served.join("notes.txt"), "hello from the server").unwrap();
+
+    let client = SyncClient::bind(loopback_config(client_home.path()))
+        .await
+        .unwrap();
+    let store = Store::new(server_home.path()).unwrap();
+    store
+        .allow_peer(&served, client.id(), AccessMode::Read)
+        .unwrap();
+    let server = SyncServer::bind(
+        loopback_config(server_home.path()),
+        store,
+        ServeOptions::default(),
+    )
+    .await
+    .unwrap();
+    let server_id = server.id();
+    let addrs = StaticProvider::new();
+    addrs.add_endpoint_info(server.addr());
+    client.endpoint().discovery().add(addrs);
+    let (shutdown, stop) = tokio::sync::oneshot::channel::<()>();
+    let server_task = tokio::spawn(server.run_until(async {
+        let _ = stop.await;
+    }));
+
+    let target = local.path().join("notes.txt");
+    let report = client
+        .copy(
+            server_id,
+            served.join("notes.txt").to_string_lossy(),
+            &target,
+            CopyOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(report.stats.files_transferred, 1);
+    assert_eq!(
+        std::fs::read_to_string(&target).unwrap(),
+        "hello from the server"
+    );
+    client.endpoint().close().await;
+    let _ = shutdown.send(());
+    server_task.await.unwrap().unwrap();
+}
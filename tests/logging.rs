@@ -0,0 +1,78 @@
+//! Log lines can go to a file in the syncr home as well as stderr.
+
+use std::process::Command;
+use tempfile::TempDir;
+
+#[test]
+fn log_file_receives_log_lines() {
+    let home = TempDir::new().unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .env("SYNCR_HOME", home.path())
+        .env("RUST_LOG", "syncr=debug")
+        .env_remove("SYNCR_CONFIG")
+        .args(["--log-file", "--no-log-stderr", "watch"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let logs: Vec<_> = std::fs::read_dir(home.path().join("logs"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    assert_eq!(logs.len(), 1);
+    let name = logs[0].file_name().unwrap().to_str().unwrap();
+    assert!(
+        name.starts_with("syncr.") && name.ends_with(".log"),
+        "{}",
+        name
+    );
+    let contents = std::fs::read_to_string(&logs[0]).unwrap();
+    assert!(contents.contains("Running Watch"), "{}", contents);
+}
+
+#[test]
+fn log_file_can_be_enabled_in_config() {
+    let home = TempDir::new().unwrap();
+    let dir = home.path().join("elsewhere");
+    std::fs::write(
+        home.path().join("config.toml"),
+        format!("[log]\nfile = true\ndir = {:?}\n", dir),
+    )
+    .unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .env("SYNCR_HOME", home.path())
+        .env("RUST_LOG", "syncr=debug")
+        .env_remove("SYNCR_CONFIG")
+        .arg("watch")
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let logs: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+    assert_eq!(logs.len(), 1);
+}
+
+#[test]
+fn verbose_flag_sets_level_without_rust_log() {
+    let home = TempDir::new().unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .env("SYNCR_HOME", home.path())
+        .env_remove("RUST_LOG")
+        .env_remove("SYNCR_CONFIG")
+        .args(["-vv", "--log-file", "--no-log-stderr", "watch"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let log = std::fs::read_dir(home.path().join("logs"))
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    let contents = std::fs::read_to_string(log).unwrap();
+    assert!(contents.contains("Running Watch"), "{}", contents);
+}
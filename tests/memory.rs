@@ -0,0 +1,99 @@
+//! Deltas over large files are computed and applied without loading the
+//! files whole.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tempfile::TempDir;
+
+use syncr::sync_utils::{
+    apply_delta, apply_delta_to_file, calculate_delta, calculate_signature, map_file,
+    DEFAULT_STRONG_HASH_SIZE,
+};
+
+/// Tracks live and peak heap usage.
+struct Counting;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            let live = LIVE.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(live, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Held by each test, so one's allocations don't count toward another's peak.
+static MEASURING: Mutex<()> = Mutex::new(());
+
+#[test]
+fn delta_over_mapped_file_has_bounded_heap() {
+    let _measuring = MEASURING.lock().unwrap_or_else(|e| e.into_inner());
+    const LEN: usize = 64 * 1024 * 1024;
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("large.bin");
+
+    let old: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+    let mut new = old.clone();
+    new[LEN / 2..LEN / 2 + 5].copy_from_slice(b"edit!");
+    std::fs::write(&path, &new).unwrap();
+    drop(new);
+    let signature = calculate_signature(&old, None, DEFAULT_STRONG_HASH_SIZE).unwrap();
+
+    let baseline = LIVE.load(Ordering::Relaxed);
+    PEAK.store(baseline, Ordering::Relaxed);
+    let mapped = map_file(&path).unwrap();
+    let delta = calculate_delta(&signature, &mapped).unwrap();
+    let hash = blake3::hash(&mapped);
+    let peak = PEAK.load(Ordering::Relaxed) - baseline;
+    drop(mapped);
+
+    assert!(peak < LEN / 8, "peak heap use {} bytes", peak);
+    let patched = apply_delta(&old, &delta, std::io::sink()).unwrap();
+    assert_eq!(patched, *hash.as_bytes());
+}
+
+#[test]
+fn patching_mapped_file_has_bounded_heap() {
+    let _measuring = MEASURING.lock().unwrap_or_else(|e| e.into_inner());
+    const LEN: usize = 64 * 1024 * 1024;
+    let dir = TempDir::new().unwrap();
+    let (old_path, out_path) = (dir.path().join("old.bin"), dir.path().join("out.bin"));
+
+    let old: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+    let mut new = old.clone();
+    new[LEN / 3..LEN / 3 + 5].copy_from_slice(b"edit!");
+    new.extend_from_slice(b"appended tail");
+    std::fs::write(&old_path, &old).unwrap();
+    let signature = calculate_signature(&old, None, DEFAULT_STRONG_HASH_SIZE).unwrap();
+    let delta = calculate_delta(&signature, &new).unwrap();
+    let hash = blake3::hash(&new);
+    drop((old, new, signature));
+
+    let baseline = LIVE.load(Ordering::Relaxed);
+    PEAK.store(baseline, Ordering::Relaxed);
+    let mapped = map_file(&old_path).unwrap();
+    let patched = apply_delta_to_file(&mapped, &delta, &out_path).unwrap();
+    let peak = PEAK.load(Ordering::Relaxed) - baseline;
+    drop(mapped);
+
+    assert!(peak < LEN / 8, "peak heap use {} bytes", peak);
+    assert_eq!(patched, *hash.as_bytes());
+    assert_eq!(
+        *blake3::hash(&std::fs::read(&out_path).unwrap()).as_bytes(),
+        *hash.as_bytes()
+    );
+}
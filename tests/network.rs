@@ -0,0 +1,122 @@
+//! Endpoint network settings from the config.
+
+use iroh::{Endpoint, RelayMode};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tempfile::TempDir;
+
+use syncr::{
+    protocol::{network_alpn, ALPN},
+    Config, SyncClient,
+};
+
+#[tokio::test]
+async fn endpoint_without_relays_has_only_direct_addresses() {
+    let home = TempDir::new().unwrap();
+    let mut config = Config::load(None, Some(home.path())).unwrap();
+    config.discovery.pkarr = false;
+    config.discovery.dns = false;
+    config.discovery.mdns = false;
+    config.relay.enabled = false;
+    config.bind = Some(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0));
+
+    let client = SyncClient::bind(config).await.unwrap();
+    let addr = client.addr();
+    assert_eq!(addr.relay_urls().count(), 0);
+    let ipv4: Vec<_> = addr.ip_addrs().filter(|a| a.is_ipv4()).collect();
+    assert!(!ipv4.is_empty());
+    assert!(ipv4.iter().all(|a| a.ip() == Ipv4Addr::LOCALHOST));
+}
+
+#[tokio::test]
+async fn config_file_chooses_the_discovery_mechanisms() {
+    for (mdns, services) in [(false, 0), (true, 1)] {
+        let home = TempDir::new().unwrap();
+        std::fs::write(
+            home.path().join("config.toml"),
+            format!(
+                "bind = \"127.0.0.1:0\"\n\
+                 [discovery]\npkarr = false\ndns = false\nmdns = {}\n\
+                 [relay]\nenabled = false\n",
+                mdns
+            ),
+        )
+        .unwrap();
+        let config = Config::load(None, Some(home.path())).unwrap();
+        assert_eq!(config.discovery.mdns, mdns);
+
+        let client = SyncClient::bind(config).await.unwrap();
+        assert_eq!(
+            client.endpoint().discovery().len(),
+            services,
+            "mdns = {}",
+            mdns
+        );
+    }
+}
+
+#[tokio::test]
+async fn unreachable_relay_fails_the_bind() {
+    let home = TempDir::new().unwrap();
+    let mut config = Config::load(None, Some(home.path())).unwrap();
+    config.discovery.pkarr = false;
+    config.discovery.dns = false;
+    config.discovery.mdns = false;
+    // Nothing listens on the discard port.
+    config.relay.url = Some("http://127.0.0.1:9".parse().unwrap());
+    config.relay.timeout_secs = 1;
+    config.bind = Some(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0));
+
+    let Err(e) = SyncClient::bind(config).await else {
+        panic!("bound with an unreachable relay");
+    };
+    let message = format!("{:#}", e);
+    assert!(message.contains("http://127.0.0.1:9"), "{}", message);
+    assert!(message.contains("could not be reached"), "{}", message);
+}
+
+#[tokio::test]
+async fn bound_endpoint_accepts_the_syncr_alpn_of_its_network() {
+    let home = TempDir::new().unwrap();
+    let mut config = Config::load(None, Some(home.path())).unwrap();
+    config.discovery.pkarr = false;
+    config.discovery.dns = false;
+    config.discovery.mdns = false;
+    config.relay.enabled = false;
+    config.network = Some("staging".to_string());
+    config.bind = Some(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0));
+    let client = SyncClient::bind(config).await.unwrap();
+    let dialer = Endpoint::builder()
+        .clear_discovery()
+        .relay_mode(RelayMode::Disabled)
+        .bind_addr_v4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+        .bind()
+        .await
+        .unwrap();
+
+    let alpn = network_alpn(ALPN, Some("staging"));
+    let accepted = async {
+        let incoming = client.endpoint().accept().await.unwrap();
+        incoming.accept().unwrap().await.unwrap()
+    };
+    let (dialed, accepted) = tokio::join!(dialer.connect(client.addr(), &alpn), accepted);
+    dialed.unwrap();
+    assert_eq!(accepted.alpn(), alpn.as_slice());
+
+    // Syncr on another network is refused.
+    let refused = async {
+        let incoming = client.endpoint().accept().await.unwrap();
+        match incoming.accept() {
+            Ok(accepting) => accepting.await.is_err(),
+            Err(_) => true,
+        }
+    };
+    let dialed = dialer.connect(client.addr(), ALPN);
+    let (dialed, refused) = tokio::time::timeout(Duration::from_secs(5), async {
+        tokio::join!(dialed, refused)
+    })
+    .await
+    .unwrap();
+    assert!(dialed.is_err());
+    assert!(refused);
+}
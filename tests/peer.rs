@@ -0,0 +1,66 @@
+//! Peer aliases standing in for peer IDs on the command line.
+
+use std::path::Path;
+use std::process::{Command, Output};
+use tempfile::TempDir;
+
+use syncr::store::{AccessMode, Store};
+
+fn syncr(home: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .env("SYNCR_HOME", home)
+        .env_remove("SYNCR_CONFIG")
+        .env_remove("SYNCR_PROFILE")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn alias_is_resolved_to_its_peer() {
+    let home = TempDir::new().unwrap();
+    let shared = TempDir::new().unwrap();
+    let shared = std::fs::canonicalize(shared.path()).unwrap();
+    let bob = iroh::SecretKey::generate(&mut rand::rng()).public();
+
+    let added = syncr(home.path(), &["peer", "add", "bob", &bob.to_string()]);
+    assert!(added.status.success(), "{:?}", added);
+    let listed = syncr(home.path(), &["peer", "list"]);
+    let listed = String::from_utf8(listed.stdout).unwrap();
+    assert!(
+        listed.contains("bob") && listed.contains(&bob.to_string()),
+        "{}",
+        listed
+    );
+    let allowed = syncr(
+        home.path(),
+        &["allow", "bob", shared.to_str().unwrap(), "--mode", "read"],
+    );
+    assert!(allowed.status.success(), "{:?}", allowed);
+
+    let store = Store::new(home.path()).unwrap();
+    assert_eq!(store.resolve_alias("bob").unwrap(), Some(bob));
+    assert_eq!(
+        store.shares_of(bob).unwrap(),
+        vec![(shared, AccessMode::Read)]
+    );
+}
+
+#[test]
+fn unknown_alias_is_an_error_naming_it() {
+    let home = TempDir::new().unwrap();
+    let shared = TempDir::new().unwrap();
+
+    let output = syncr(
+        home.path(),
+        &["allow", "carol", shared.path().to_str().unwrap()],
+    );
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("'carol' is neither a peer ID nor a known alias"),
+        "{}",
+        stderr
+    );
+}
@@ -0,0 +1,851 @@
+//! Store bookkeeping for syncs and watches.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+use syncr::{
+    store::{
+        AccessMode, ConflictPolicy, Direction, StoreError, SyncConfig, SyncHealth, SyncLogEntry,
+        SyncOutcome, WatchOptions, SCHEMA_VERSION, SYNC_LOG_LEN,
+    },
+    EventMask, Store,
+};
+
+fn peer() -> iroh::PublicKey {
+    iroh::SecretKey::generate(&mut rand::rng()).public()
+}
+
+/// The database under `dir` opened directly, to write what an older or
+/// broken version would have. A store just dropped may hold its lock a
+/// moment longer.
+fn raw_db(dir: &Path) -> sled::Db {
+    let started = Instant::now();
+    loop {
+        match sled::open(dir.join("db")) {
+            Err(_) if started.elapsed() < Duration::from_secs(1) => {
+                std::thread::sleep(Duration::from_millis(20))
+            }
+            db => return db.unwrap(),
+        }
+    }
+}
+
+#[test]
+fn sync_and_watch_are_added_and_removed_together() {
+    let dir = TempDir::new().unwrap();
+    let store = Store::new(dir.path()).unwrap();
+    let peer = peer();
+
+    store
+        .add_sync_with_watch(peer, "/remote/docs".to_string(), "/srv/docs".into())
+        .unwrap();
+    assert_eq!(store.list_syncs().unwrap().len(), 1);
+    assert_eq!(
+        store.list_watches().unwrap(),
+        vec![PathBuf::from("/srv/docs")]
+    );
+
+    assert!(store
+        .remove_sync_with_watch("/srv/docs", peer, "/remote/docs")
+        .unwrap());
+    assert!(store.list_syncs().unwrap().is_empty());
+    assert!(store.list_watches().unwrap().is_empty());
+}
+
+#[test]
+fn sync_log_keeps_the_latest_entries_of_configured_syncs() {
+    let dir = TempDir::new().unwrap();
+    let store = Store::new(dir.path()).unwrap();
+    let peer = peer();
+    let entry = |error: usize| {
+        SyncLogEntry::failed(peer, "/remote/docs", Direction::Pull, error.to_string())
+    };
+
+    assert!(!store.log_sync("/srv/docs", entry(0)).unwrap());
+    store
+        .add_sync_with_watch(peer, "/remote/docs".to_string(), "/srv/docs".into())
+        .unwrap();
+    for i in 0..SYNC_LOG_LEN + 5 {
+        assert!(store.log_sync("/srv/docs", entry(i)).unwrap());
+    }
+
+    let log = store.sync_log("/srv/docs").unwrap();
+    assert_eq!(log.len(), SYNC_LOG_LEN);
+    assert_eq!(log[0].outcome, SyncOutcome::Failed("5".to_string()));
+    assert_eq!(
+        log[SYNC_LOG_LEN - 1].outcome,
+        SyncOutcome::Failed((SYNC_LOG_LEN + 4).to_string())
+    );
+
+    store
+        .remove_sync_with_watch("/srv/docs", peer, "/remote/docs")
+        .unwrap();
+    assert!(store.sync_log("/srv/docs").unwrap().is_empty());
+}
+
+#[test]
+fn watch_stays_while_another_sync_needs_it() {
+    let dir = TempDir::new().unwrap();
+    let store = Store::new(dir.path()).unwrap();
+    let (first, second) = (peer(), peer());
+
+    for peer in [first, second] {
+        store
+            .add_sync_with_watch(peer, "/remote/docs".to_string(), "/srv/docs".into())
+            .unwrap();
+    }
+    store
+        .remove_sync_with_watch("/srv/docs", first, "/remote/docs")
+        .unwrap();
+
+    assert_eq!(store.list_syncs().unwrap()[0].1.len(), 1);
+    assert_eq!(store.list_watches().unwrap().len(), 1);
+}
+
+#[test]
+fn failed_add_leaves_no_watch_behind() {
+    let dir = TempDir::new().unwrap();
+    drop(Store::new(dir.path()).unwrap());
+
+    // A sync entry that can't be decoded makes the add fail after the watch
+    // has been written within the transaction.
+    {
+        let db = raw_db(dir.path());
+        db.open_tree("syncs")
+            .unwrap()
+            .insert("/srv/docs", &[0xff, 0xff])
+            .unwrap();
+        db.flush().unwrap();
+    }
+
+    let store = Store::new(dir.path()).unwrap();
+    let result = store.add_sync_with_watch(peer(), "/remote/docs".to_string(), "/srv/docs".into());
+
+    assert!(result.is_err());
+    assert!(store.list_watches().unwrap().is_empty());
+}
+
+#[test]
+fn corrupt_entry_is_named_and_skipped_when_tolerant() {
+    let dir = TempDir::new().unwrap();
+    let store = Store::new(dir.path()).unwrap();
+    store
+        .add_sync_with_watch(peer(), "/remote/good".to_string(), "/srv/good".into())
+        .unwrap();
+    drop(store);
+    {
+        let db = raw_db(dir.path());
+        db.open_tree("syncs")
+            .unwrap()
+            .insert("/srv/bad", &[0xff, 0xff])
+            .unwrap();
+        db.flush().unwrap();
+    }
+
+    let store = Store::new(dir.path()).unwrap();
+    match store.list_syncs() {
+        Err(StoreError::Corrupt { tree, key, .. }) => {
+            assert_eq!(tree, "syncs");
+            assert_eq!(key, "/srv/bad");
+        }
+        other => panic!("expected a corrupt entry, got {:?}", other),
+    }
+
+    let syncs = store.tolerant().list_syncs().unwrap();
+    assert_eq!(syncs.len(), 1);
+    assert_eq!(syncs[0].0, PathBuf::from("/srv/good"));
+}
+
+#[test]
+fn corrupt_group_costs_only_its_own_members_access() {
+    let dir = TempDir::new().unwrap();
+    let store = Store::new(dir.path()).unwrap();
+    let member = peer();
+    store.create_group("team").unwrap();
+    store.create_group("broken").unwrap();
+    store.add_group_member("team", member).unwrap();
+    store
+        .allow_group_until("/srv/docs", "team", AccessMode::Read, None)
+        .unwrap();
+    store.add_alias("bob", member).unwrap();
+    store.queue_notification(member, "/remote/a").unwrap();
+    drop(store);
+    {
+        let db = raw_db(dir.path());
+        for tree in ["groups", "aliases", "addresses"] {
+            db.open_tree(tree)
+                .unwrap()
+                .insert("bad", &[0xff, 0xff])
+                .unwrap();
+        }
+        db.open_tree("groups")
+            .unwrap()
+            .insert("broken", &[0xff, 0xff])
+            .unwrap();
+        db.open_tree("pending_notifications")
+            .unwrap()
+            .insert(member.as_bytes(), &[0xff, 0xff])
+            .unwrap();
+        db.flush().unwrap();
+    }
+
+    let store = Store::new(dir.path()).unwrap();
+    assert!(store
+        .is_peer_allowed("/srv/docs", member, AccessMode::Read)
+        .unwrap());
+    assert_eq!(store.groups_of(member).unwrap(), vec!["team".to_string()]);
+    match store.group_members("broken") {
+        Err(StoreError::Corrupt { tree, key, .. }) => {
+            assert_eq!(tree, "groups");
+            assert_eq!(key, "broken");
+        }
+        other => panic!("expected a corrupt entry, got {:?}", other),
+    }
+    assert!(matches!(
+        store.pending_notifications(member),
+        Err(StoreError::Corrupt { .. })
+    ));
+    assert!(store.list_groups().is_err());
+    assert!(store.list_aliases().is_err());
+    assert!(store.list_addresses().is_err());
+
+    let tolerant = store.tolerant();
+    assert_eq!(tolerant.list_groups().unwrap().len(), 1);
+    assert_eq!(
+        tolerant.list_aliases().unwrap(),
+        vec![("bob".to_string(), member)]
+    );
+    assert!(tolerant.list_addresses().unwrap().is_empty());
+}
+
+#[test]
+fn relative_and_unresolved_paths_are_refused() {
+    let dir = TempDir::new().unwrap();
+    let store = Store::new(&dir.path().join("data")).unwrap();
+    let missing = dir.path().join("missing/../docs");
+    for path in [PathBuf::from("docs"), missing] {
+        let err = store.add_watch(&path).unwrap_err();
+        assert!(matches!(err, StoreError::InvalidPath(_)), "{:?}", err);
+        let err = store
+            .add_sync_with_watch(peer(), "/remote/docs".to_string(), path)
+            .unwrap_err();
+        assert!(matches!(err, StoreError::InvalidPath(_)), "{:?}", err);
+    }
+    assert!(store.list_watches().unwrap().is_empty());
+    assert!(store.list_syncs().unwrap().is_empty());
+
+    // A `..` that resolves is stored as the path it leads to.
+    let docs = std::fs::canonicalize(dir.path()).unwrap().join("docs");
+    std::fs::create_dir(&docs).unwrap();
+    store.add_watch(docs.join("../docs/")).unwrap();
+    assert_eq!(store.list_watches().unwrap(), vec![docs]);
+}
+
+#[test]
+fn adding_a_sync_twice_keeps_one_entry() {
+    let dir = TempDir::new().unwrap();
+    let store = Store::new(dir.path()).unwrap();
+    let peer = peer();
+
+    store
+        .add_sync(peer, "/remote/docs".to_string(), "/srv/docs".into())
+        .unwrap();
+    store
+        .record_sync_result("/srv/docs", peer, "/remote/docs", Some([1; 32]), 42)
+        .unwrap();
+    store
+        .add_sync(peer, "/remote/docs".to_string(), "/srv/docs".into())
+        .unwrap();
+    store
+        .add_sync_with_watch(peer, "/remote/docs".to_string(), "/srv/docs".into())
+        .unwrap();
+
+    let syncs = store.list_syncs().unwrap();
+    assert_eq!(syncs.len(), 1);
+    assert_eq!(syncs[0].1.len(), 1);
+    // The existing entry, with its last result, is kept.
+    assert_eq!(syncs[0].1[0].last_synced, Some(42));
+}
+
+#[test]
+fn sync_result_is_kept_across_reopening() {
+    let dir = TempDir::new().unwrap();
+    let peer = peer();
+    {
+        let store = Store::new(dir.path()).unwrap();
+        store
+            .add_sync(
+                peer,
+                "/remote/notes.txt".to_string(),
+                "/srv/notes.txt".into(),
+            )
+            .unwrap();
+        store
+            .record_sync_result(
+                "/srv/notes.txt",
+                peer,
+                "/remote/notes.txt",
+                Some([7; 32]),
+                42,
+            )
+            .unwrap();
+    }
+
+    let store = Store::new(dir.path()).unwrap();
+    let syncs = store.list_syncs().unwrap();
+    assert_eq!(syncs[0].1[0].last_hash, Some([7; 32]));
+    assert_eq!(syncs[0].1[0].last_synced, Some(42));
+}
+
+#[test]
+fn sync_changes_are_shared_by_every_handle_and_kept() {
+    let dir = TempDir::new().unwrap();
+    let peer = peer();
+    {
+        let store = Store::new(dir.path()).unwrap();
+        let clone = store.clone();
+        store
+            .add_sync(peer, "/remote/docs".to_string(), "/srv/docs".into())
+            .unwrap();
+        assert!(clone
+            .set_sync_include("/srv/docs", peer, "/remote/docs", vec!["a".to_string()])
+            .unwrap());
+        let listed = store.tolerant().list_syncs().unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].1[0].include, vec!["a".to_string()]);
+        assert!(clone
+            .remove_sync_with_watch("/srv/docs", peer, "/remote/docs")
+            .unwrap());
+        assert!(store.list_syncs().unwrap().is_empty());
+        store
+            .add_sync(peer, "/remote/notes".to_string(), "/srv/notes".into())
+            .unwrap();
+    }
+
+    let store = Store::new(dir.path()).unwrap();
+    let listed = store.list_syncs().unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].0, PathBuf::from("/srv/notes"));
+}
+
+#[test]
+fn top_level_watch_is_remembered() {
+    let dir = TempDir::new().unwrap();
+    {
+        let store = Store::new(dir.path()).unwrap();
+        store
+            .add_watch_with(
+                "/srv/docs",
+                WatchOptions {
+                    recursive: false,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        // A reverse sync registered later leaves the watch as it was.
+        store
+            .add_sync_with_watch(peer(), "/remote/docs".to_string(), "/srv/docs".into())
+            .unwrap();
+    }
+
+    let store = Store::new(dir.path()).unwrap();
+    assert_eq!(
+        store.list_watches_with_options().unwrap(),
+        vec![(
+            PathBuf::from("/srv/docs"),
+            WatchOptions {
+                recursive: false,
+                ..Default::default()
+            }
+        )]
+    );
+}
+
+#[test]
+fn watches_from_before_options_are_recursive() {
+    let dir = TempDir::new().unwrap();
+    {
+        let db = raw_db(dir.path());
+        db.open_tree("watches")
+            .unwrap()
+            .insert("/srv/docs", &[])
+            .unwrap();
+        db.open_tree("meta")
+            .unwrap()
+            .insert("schema_version", &4u32.to_be_bytes())
+            .unwrap();
+        db.flush().unwrap();
+    }
+
+    let store = Store::new(dir.path()).unwrap();
+    assert_eq!(
+        store.list_watches_with_options().unwrap(),
+        vec![(PathBuf::from("/srv/docs"), WatchOptions::default())]
+    );
+}
+
+#[test]
+fn unversioned_store_is_migrated_to_the_current_schema() {
+    let dir = TempDir::new().unwrap();
+    let alice = peer();
+    // As written before the schema was versioned: grants as a bare list of
+    // peers, syncs without any of their later fields, and no version.
+    {
+        let db = raw_db(dir.path());
+        db.open_tree("permissions")
+            .unwrap()
+            .insert("/srv/docs", postcard::to_stdvec(&vec![alice]).unwrap())
+            .unwrap();
+        db.open_tree("syncs")
+            .unwrap()
+            .insert(
+                "/srv/docs",
+                postcard::to_stdvec(&vec![(alice, "/remote/docs")]).unwrap(),
+            )
+            .unwrap();
+        db.flush().unwrap();
+    }
+
+    let store = Store::new(dir.path()).unwrap();
+    assert_eq!(store.schema_version().unwrap(), SCHEMA_VERSION);
+    assert_eq!(
+        store.shares_of(alice).unwrap(),
+        vec![(PathBuf::from("/srv/docs"), AccessMode::ReadWrite)]
+    );
+    let syncs = store.list_syncs().unwrap();
+    assert_eq!(syncs.len(), 1);
+    assert_eq!(syncs[0].0, PathBuf::from("/srv/docs"));
+    let [sync] = syncs[0].1.as_slice() else {
+        panic!("expected one sync, got {:?}", syncs[0].1);
+    };
+    assert_eq!(sync.peer, alice);
+    assert_eq!(sync.remote_path, "/remote/docs");
+    assert_eq!(sync.last_synced, None);
+    assert!(sync.include.is_empty());
+    drop(store);
+
+    // Opening it again finds nothing left to migrate.
+    let store = Store::new(dir.path()).unwrap();
+    assert_eq!(store.list_syncs().unwrap(), syncs);
+}
+
+#[test]
+fn overlapping_syncs_are_found() {
+    let dir = TempDir::new().unwrap();
+    let store = Store::new(dir.path()).unwrap();
+    let (peer, other) = (peer(), peer());
+    store
+        .add_sync(peer, "/remote/docs".to_string(), "/srv/docs".into())
+        .unwrap();
+    store
+        .add_sync(other, "/remote/photos".to_string(), "/srv/photos".into())
+        .unwrap();
+    let roots = |local: &str, peer, remote: &str| -> Vec<PathBuf> {
+        store
+            .overlapping_syncs(Path::new(local), peer, remote)
+            .unwrap()
+            .into_iter()
+            .map(|(root, _)| root)
+            .collect()
+    };
+
+    // The same remote path into a second local path
+    assert_eq!(
+        roots("/home/docs", peer, "/remote/docs"),
+        vec![PathBuf::from("/srv/docs")]
+    );
+    // Local paths inside one another, whatever they sync
+    assert_eq!(
+        roots("/srv/photos/2024", peer, "/remote/other"),
+        vec![PathBuf::from("/srv/photos")]
+    );
+    assert_eq!(
+        roots("/srv", other, "/remote/all").len(),
+        2,
+        "both syncs below /srv overlap it"
+    );
+    // The sync itself, and unrelated ones, don't count.
+    assert!(roots("/srv/docs", peer, "/remote/docs").is_empty());
+    assert!(roots("/srv/docs2", peer, "/remote/docs2").is_empty());
+}
+
+/// Local paths whose names need care: spaces, non-ASCII and, where the
+/// platform allows it, bytes that aren't valid UTF-8.
+fn awkward_paths() -> Vec<PathBuf> {
+    #[allow(unused_mut)]
+    let mut paths = vec![
+        PathBuf::from("/srv/my docs/annual report.txt"),
+        PathBuf::from("/srv/données/日本語 ファイル"),
+    ];
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let name = std::ffi::OsStr::from_bytes(b"latin1-caf\xe9");
+        paths.push(PathBuf::from("/srv").join(name));
+    }
+    paths
+}
+
+#[test]
+fn awkward_paths_survive_a_store_round_trip() {
+    let dir = TempDir::new().unwrap();
+    let peer = peer();
+    {
+        let store = Store::new(dir.path()).unwrap();
+        for path in awkward_paths() {
+            store
+                .add_sync_with_watch(peer, "/remote".to_string(), path.clone())
+                .unwrap();
+            store.allow_peer(&path, peer, AccessMode::Read).unwrap();
+        }
+    }
+
+    let store = Store::new(dir.path()).unwrap();
+    let mut expected = awkward_paths();
+    expected.sort();
+    let mut watches = store.list_watches().unwrap();
+    watches.sort();
+    assert_eq!(watches, expected);
+    let mut synced: Vec<PathBuf> = store
+        .list_syncs()
+        .unwrap()
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect();
+    synced.sort();
+    assert_eq!(synced, expected);
+    for path in &expected {
+        assert!(store.is_peer_allowed(path, peer, AccessMode::Read).unwrap());
+        assert!(store.remove_sync_with_watch(path, peer, "/remote").unwrap());
+    }
+    assert!(store.list_watches().unwrap().is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinked_path_and_its_target_share_an_entry() {
+    let dir = TempDir::new().unwrap();
+    let store = Store::new(&dir.path().join("data")).unwrap();
+    let target = dir.path().join("docs");
+    std::fs::create_dir(&target).unwrap();
+    let target = std::fs::canonicalize(&target).unwrap();
+    let link = dir.path().join("link");
+    std::os::unix::fs::symlink(&target, &link).unwrap();
+    let peer = peer();
+
+    store.allow_peer(&link, peer, AccessMode::Read).unwrap();
+    store
+        .add_sync_with_watch(peer, "/remote/docs".to_string(), link.clone())
+        .unwrap();
+
+    assert_eq!(store.list_watches().unwrap(), vec![target.clone()]);
+    // Either name finds the grant, including for files not written yet.
+    for root in [&link, &target] {
+        assert!(store
+            .is_peer_allowed(root.join("new.txt"), peer, AccessMode::Read)
+            .unwrap());
+    }
+    assert!(store
+        .remove_sync_with_watch(&target, peer, "/remote/docs")
+        .unwrap());
+    assert!(store.list_watches().unwrap().is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn entries_stored_through_a_symlink_move_to_the_target() {
+    let dir = TempDir::new().unwrap();
+    let target = dir.path().join("docs");
+    std::fs::create_dir(&target).unwrap();
+    let target = std::fs::canonicalize(&target).unwrap();
+    let link = dir.path().join("link");
+    std::os::unix::fs::symlink(&target, &link).unwrap();
+    let (first, second) = (peer(), peer());
+    let sync = |peer| {
+        vec![SyncConfig {
+            peer,
+            remote_path: "/remote/docs".to_string(),
+            last_synced: None,
+            last_hash: None,
+            include: Vec::new(),
+            last_error: None,
+            on_conflict: ConflictPolicy::default(),
+            include_hidden: false,
+            requested_by_peer: false,
+            events: EventMask::ALL,
+            max_size: None,
+        }]
+    };
+    {
+        let db = sled::open(dir.path().join("data/db")).unwrap();
+        let key = |path: &Path| path.to_str().unwrap().to_string();
+        let syncs = db.open_tree("syncs").unwrap();
+        syncs
+            .insert(key(&link), postcard::to_stdvec(&sync(first)).unwrap())
+            .unwrap();
+        syncs
+            .insert(key(&target), postcard::to_stdvec(&sync(second)).unwrap())
+            .unwrap();
+        db.open_tree("watches")
+            .unwrap()
+            .insert(
+                key(&link),
+                postcard::to_stdvec(&WatchOptions::default()).unwrap(),
+            )
+            .unwrap();
+        db.open_tree("meta")
+            .unwrap()
+            .insert("schema_version", &5u32.to_be_bytes())
+            .unwrap();
+        db.flush().unwrap();
+    }
+
+    let store = Store::new(&dir.path().join("data")).unwrap();
+    assert_eq!(store.list_watches().unwrap(), vec![target.clone()]);
+    let syncs = store.list_syncs().unwrap();
+    assert_eq!(syncs.len(), 1);
+    assert_eq!(syncs[0].0, target);
+    let mut peers: Vec<_> = syncs[0].1.iter().map(|c| c.peer).collect();
+    peers.sort();
+    let mut expected = vec![first, second];
+    expected.sort();
+    assert_eq!(peers, expected);
+}
+
+#[test]
+fn fingerprint_is_stable_and_follows_every_entry() {
+    let dir = TempDir::new().unwrap();
+    let store = Store::new(dir.path()).unwrap();
+    let (alice, bob) = (peer(), peer());
+    store.add_watch("/srv/watched").unwrap();
+    store
+        .allow_peer("/srv/shared", alice, AccessMode::Read)
+        .unwrap();
+    store
+        .add_sync(alice, "docs".to_string(), PathBuf::from("/srv/docs"))
+        .unwrap();
+    let fingerprint = store.fingerprint().unwrap();
+    assert_eq!(store.fingerprint().unwrap(), fingerprint);
+
+    // Recording a sync is state, not setup.
+    store
+        .record_sync_result("/srv/docs", alice, "docs", Some([7; 32]), 1_700_000_000)
+        .unwrap();
+    assert_eq!(store.fingerprint().unwrap(), fingerprint);
+
+    drop(store);
+    let store = Store::new(dir.path()).unwrap();
+    assert_eq!(store.fingerprint().unwrap(), fingerprint);
+
+    let mut seen = vec![fingerprint];
+    let mut assert_changed = |store: &Store| {
+        let fingerprint = store.fingerprint().unwrap();
+        assert!(!seen.contains(&fingerprint));
+        seen.push(fingerprint);
+    };
+    store
+        .add_watch_with(
+            "/srv/watched",
+            WatchOptions {
+                recursive: false,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert_changed(&store);
+    store
+        .allow_peer("/srv/shared", bob, AccessMode::Read)
+        .unwrap();
+    assert_changed(&store);
+    store
+        .allow_peer("/srv/shared", alice, AccessMode::ReadWrite)
+        .unwrap();
+    assert_changed(&store);
+    store
+        .set_sync_include("/srv/docs", alice, "docs", vec!["a".to_string()])
+        .unwrap();
+    assert_changed(&store);
+    store.remove_watch("/srv/watched").unwrap();
+    assert_changed(&store);
+}
+
+#[test]
+fn store_in_use_elsewhere_says_so() {
+    let dir = TempDir::new().unwrap();
+    let _store = Store::new(dir.path()).unwrap();
+
+    let err = Store::new(dir.path()).unwrap_err();
+    assert!(matches!(err, StoreError::Locked(_)), "{:?}", err);
+    assert!(err.to_string().contains("is a daemon running?"), "{}", err);
+}
+
+#[test]
+fn orphans_are_entries_for_paths_that_are_gone() {
+    let dir = TempDir::new().unwrap();
+    let store = Store::new(&dir.path().join("data")).unwrap();
+    let root = std::fs::canonicalize(dir.path()).unwrap();
+    let (live, gone) = (root.join("live"), root.join("gone"));
+    let revoked = live.join("notes.txt");
+    std::fs::create_dir(&live).unwrap();
+    std::fs::create_dir(&gone).unwrap();
+    std::fs::write(&revoked, "notes").unwrap();
+    let peer = peer();
+    for path in [&live, &gone] {
+        store
+            .add_sync_with_watch(peer, "/remote/docs".to_string(), path.clone())
+            .unwrap();
+        store.allow_peer(path, peer, AccessMode::Read).unwrap();
+    }
+    store.allow_peer(&revoked, peer, AccessMode::Read).unwrap();
+    store.disallow_peer(&revoked, peer).unwrap();
+    std::fs::remove_dir(&gone).unwrap();
+
+    let orphans = store.find_orphans().unwrap();
+    assert_eq!(orphans.watches, vec![gone.clone()]);
+    let syncs: Vec<_> = orphans.syncs.iter().map(|(path, _)| path).collect();
+    assert_eq!(syncs, vec![&gone]);
+    let mut permissions = orphans.permissions.clone();
+    permissions.sort();
+    assert_eq!(permissions, vec![gone.clone(), revoked]);
+
+    store.remove_orphans(&orphans).unwrap();
+    assert!(store.find_orphans().unwrap().is_empty());
+    assert_eq!(store.list_watches().unwrap(), vec![live.clone()]);
+    let syncs = store.list_syncs().unwrap();
+    assert_eq!(syncs.len(), 1);
+    assert_eq!(syncs[0].0, live);
+    let permissions = store.list_all_permissions().unwrap();
+    assert_eq!(permissions.len(), 1);
+    assert_eq!(permissions[0].0, live);
+}
+
+#[test]
+fn sync_health_follows_recorded_state() {
+    let config = |last_synced, last_error: Option<&str>| SyncConfig {
+        peer: peer(),
+        remote_path: "/remote/docs".to_string(),
+        last_synced,
+        last_hash: None,
+        include: Vec::new(),
+        last_error: last_error.map(str::to_string),
+        on_conflict: ConflictPolicy::default(),
+        include_hidden: false,
+        requested_by_peer: false,
+        events: EventMask::ALL,
+        max_size: None,
+    };
+    let (now, stale_after) = (10_000, 3_600);
+
+    // Nothing waiting, however long ago it synced.
+    assert_eq!(
+        config(None, None).health(0, now, stale_after),
+        SyncHealth::Ok
+    );
+    assert_eq!(
+        config(Some(0), None).health(0, now, stale_after),
+        SyncHealth::Ok
+    );
+    // Changes waiting on a recent sync are just in flight.
+    assert_eq!(
+        config(Some(now - 60), None).health(2, now, stale_after),
+        SyncHealth::Ok
+    );
+    assert_eq!(
+        config(Some(now - 7_200), None).health(2, now, stale_after),
+        SyncHealth::Stale
+    );
+    assert_eq!(
+        config(None, None).health(1, now, stale_after),
+        SyncHealth::Stale
+    );
+    // A failure outranks everything else.
+    assert_eq!(
+        config(Some(now), Some("peer unreachable")).health(0, now, stale_after),
+        SyncHealth::Failing
+    );
+    assert!(SyncHealth::Failing > SyncHealth::Stale && SyncHealth::Stale > SyncHealth::Ok);
+}
+
+#[test]
+fn sync_failure_is_kept_until_a_sync_completes() {
+    let dir = TempDir::new().unwrap();
+    let store = Store::new(dir.path()).unwrap();
+    let peer = peer();
+    store
+        .add_sync_with_watch(peer, "/remote/docs".to_string(), "/srv/docs".into())
+        .unwrap();
+    store
+        .queue_notification(peer, "/remote/docs/a.txt")
+        .unwrap();
+    store.queue_notification(peer, "/remote/other.txt").unwrap();
+    assert_eq!(
+        store
+            .pending_notifications_below(peer, "/remote/docs")
+            .unwrap(),
+        1
+    );
+
+    assert!(store
+        .record_sync_failure("/srv/docs", peer, "/remote/docs", "connection lost")
+        .unwrap());
+    let last_error = || store.list_syncs().unwrap()[0].1[0].last_error.clone();
+    assert_eq!(last_error().as_deref(), Some("connection lost"));
+
+    store
+        .record_sync_result("/srv/docs", peer, "/remote/docs", None, 100)
+        .unwrap();
+    assert_eq!(last_error(), None);
+}
+
+#[test]
+fn syncs_a_peer_asked_for_go_with_its_access() {
+    let dir = TempDir::new().unwrap();
+    let store = Store::new(&dir.path().join("data")).unwrap();
+    let path = |name: &str| {
+        let path = dir.path().join(name);
+        std::fs::create_dir(&path).unwrap();
+        std::fs::canonicalize(path).unwrap()
+    };
+    let (shared, own, gone) = (path("shared"), path("own"), path("gone"));
+    let (alice, bob) = (peer(), peer());
+    for (path, peer) in [(&shared, alice), (&own, alice), (&gone, bob)] {
+        store.allow_peer(path, peer, AccessMode::Write).unwrap();
+        let remote = path.to_str().unwrap().to_string();
+        store
+            .add_peer_sync_with_watch(peer, remote, path.clone(), false)
+            .unwrap();
+    }
+    // Set up here, so it stays whatever happens to the peer's access.
+    store
+        .add_sync(alice, "/theirs".to_string(), own.clone())
+        .unwrap();
+    store
+        .queue_notification(alice, &format!("{}/a.txt", shared.display()))
+        .unwrap();
+
+    store.disallow_peer(&shared, alice).unwrap();
+    let syncs = store.list_syncs().unwrap();
+    assert!(syncs.iter().all(|(path, _)| *path != shared), "{:?}", syncs);
+    assert!(!store.list_watches().unwrap().contains(&shared));
+    assert!(store.pending_notifications(alice).unwrap().is_empty());
+
+    store.disallow_peer(&own, alice).unwrap();
+    let own_syncs: Vec<SyncConfig> = store
+        .list_syncs()
+        .unwrap()
+        .into_iter()
+        .filter(|(path, _)| *path == own)
+        .flat_map(|(_, configs)| configs)
+        .collect();
+    assert_eq!(own_syncs.len(), 1);
+    assert_eq!(own_syncs[0].remote_path, "/theirs");
+    assert!(!own_syncs[0].requested_by_peer);
+
+    // The reconcile loop drops those whose path is gone.
+    std::fs::remove_dir(&gone).unwrap();
+    let pruned = store.prune_peer_syncs().unwrap();
+    assert_eq!(pruned.len(), 1);
+    assert_eq!(pruned[0].0, gone);
+    assert_eq!(store.list_syncs().unwrap().len(), 1);
+}
@@ -0,0 +1,244 @@
+//! rsync signatures and deltas, and file hashing.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::TempDir;
+
+use syncr::sync_utils::{
+    apply_delta, block_size_for, blocking, calculate_delta, calculate_signature, hash_file,
+    signature_options, HashQueue, SharedBytes, DEFAULT_STRONG_HASH_SIZE, MAX_BLOCK_SIZE,
+    MIN_BLOCK_SIZE, MIN_STRONG_HASH_SIZE,
+};
+
+/// `old` with `delta` applied.
+fn patched(old: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let hash = apply_delta(old, delta, &mut out).unwrap();
+    assert_eq!(hash, *blake3::hash(&out).as_bytes());
+    out
+}
+
+fn pseudo_random(len: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+#[test]
+fn block_size_grows_with_file_size() {
+    assert_eq!(block_size_for(0), MIN_BLOCK_SIZE);
+    assert_eq!(block_size_for(4 * 1024), MIN_BLOCK_SIZE);
+    assert_eq!(block_size_for(16 * 1024 * 1024), 4096);
+    assert_eq!(block_size_for(1 << 40), MAX_BLOCK_SIZE);
+}
+
+#[test]
+fn large_files_get_compact_signatures() {
+    let small = pseudo_random(64 * 1024);
+    let large = pseudo_random(16 * 1024 * 1024);
+
+    let small_sig = calculate_signature(&small, None, DEFAULT_STRONG_HASH_SIZE).unwrap();
+    let large_sig = calculate_signature(&large, None, DEFAULT_STRONG_HASH_SIZE).unwrap();
+    let large_fixed = calculate_signature(&large, Some(1024), DEFAULT_STRONG_HASH_SIZE).unwrap();
+
+    // 256x the data, but only 32x the blocks.
+    assert!(large_sig.len() < small_sig.len() * 100);
+    assert!(large_sig.len() * 3 < large_fixed.len());
+}
+
+#[test]
+fn delta_round_trips_with_adaptive_and_fixed_blocks() {
+    let old = pseudo_random(4 * 1024 * 1024);
+    let mut new = old.clone();
+    new[1_000_000..1_000_020].copy_from_slice(b"twenty changed bytes");
+    new.extend_from_slice(b"appended tail");
+
+    for block_size in [None, Some(700)] {
+        let signature = calculate_signature(&old, block_size, DEFAULT_STRONG_HASH_SIZE).unwrap();
+        let delta = calculate_delta(&signature, &new).unwrap();
+        assert!(delta.len() < new.len() / 10);
+        assert_eq!(patched(&old, &delta), new);
+    }
+}
+
+#[test]
+fn delta_round_trips_with_empty_inputs() {
+    let data = pseudo_random(10_000);
+    for (old, new) in [
+        (Vec::new(), data.clone()),
+        (data.clone(), Vec::new()),
+        (Vec::new(), Vec::new()),
+    ] {
+        let signature = calculate_signature(&old, None, DEFAULT_STRONG_HASH_SIZE).unwrap();
+        let delta = calculate_delta(&signature, &new).unwrap();
+        assert_eq!(patched(&old, &delta), new);
+    }
+}
+
+#[test]
+fn signature_carries_its_options_to_the_delta() {
+    let old = pseudo_random(100_000);
+    let mut new = old.clone();
+    new[40_000..40_008].copy_from_slice(b"modified");
+
+    let signature = calculate_signature(&old, Some(2048), 12).unwrap();
+    let options = signature_options(&signature).unwrap();
+    assert_eq!(options.block_size, 2048);
+    assert_eq!(options.crypto_hash_size, 12);
+
+    let delta = calculate_delta(&signature, &new).unwrap();
+    assert_eq!(patched(&old, &delta), new);
+}
+
+#[test]
+fn weak_strong_hashes_are_refused() {
+    let data = pseudo_random(10_000);
+    assert!(calculate_signature(&data, None, MIN_STRONG_HASH_SIZE - 1).is_err());
+
+    // A peer could still send one; it isn't diffed against.
+    let mut signature = calculate_signature(&data, None, MIN_STRONG_HASH_SIZE).unwrap();
+    signature[8..12].copy_from_slice(&4u32.to_be_bytes());
+    assert!(calculate_delta(&signature, &data).is_err());
+}
+
+/// Everything queued in `queue`, taking items whenever it asks to.
+async fn hash_all(
+    queue: &mut HashQueue<usize>,
+    files: &[Option<PathBuf>],
+) -> Vec<(usize, Option<[u8; 32]>)> {
+    let mut hashed = Vec::new();
+    for (i, file) in files.iter().enumerate() {
+        queue.push(i, file.clone());
+        while queue.should_pop() {
+            let (i, hash) = queue.pop().await.unwrap();
+            hashed.push((i, hash.unwrap()));
+        }
+    }
+    while let Some((i, hash)) = queue.pop().await {
+        hashed.push((i, hash.unwrap()));
+    }
+    hashed
+}
+
+#[tokio::test]
+async fn parallel_hashes_match_serial_ones_in_order() {
+    let dir = TempDir::new().unwrap();
+    let mut files = Vec::new();
+    for i in 0..64 {
+        // Every third item has no file, like a directory in a listing.
+        if i % 3 == 0 {
+            files.push(None);
+            continue;
+        }
+        let path = dir.path().join(format!("{}.bin", i));
+        std::fs::write(&path, pseudo_random(i * 4096)).unwrap();
+        files.push(Some(path));
+    }
+
+    let mut expected = Vec::new();
+    for (i, file) in files.iter().enumerate() {
+        let hash = match file {
+            Some(path) => Some(hash_file(path).await.unwrap()),
+            None => None,
+        };
+        expected.push((i, hash));
+    }
+    for limit in [1, 4] {
+        let mut queue = HashQueue::new(limit);
+        assert_eq!(hash_all(&mut queue, &files).await, expected);
+    }
+}
+
+/// Files queued together are read at once: the second is only written to
+/// once its reader is there, and the first only after that.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn queued_files_are_hashed_at_once() {
+    use std::io::Write;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::time::{Duration, Instant};
+
+    let dir = TempDir::new().unwrap();
+    let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+    for fifo in [&first, &second] {
+        let path = std::ffi::CString::new(fifo.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+    }
+
+    let writer = {
+        let (first, second) = (first.clone(), second.clone());
+        std::thread::spawn(move || {
+            // Opening a FIFO to write without blocking fails until a reader
+            // has it open.
+            let deadline = Instant::now() + Duration::from_secs(5);
+            let second_read_first = loop {
+                let opened = std::fs::OpenOptions::new()
+                    .write(true)
+                    .custom_flags(libc::O_NONBLOCK)
+                    .open(&second);
+                match opened {
+                    Ok(mut fifo) => {
+                        fifo.write_all(b"second").unwrap();
+                        break true;
+                    }
+                    Err(_) if Instant::now() < deadline => {
+                        std::thread::sleep(Duration::from_millis(10))
+                    }
+                    Err(_) => break false,
+                }
+            };
+            std::fs::write(&first, b"first").unwrap();
+            if !second_read_first {
+                // Let hashing one at a time finish, to fail rather than hang.
+                std::fs::write(&second, b"second").unwrap();
+            }
+            second_read_first
+        })
+    };
+
+    let mut queue = HashQueue::new(2);
+    let hashed = hash_all(&mut queue, &[Some(first), Some(second)]).await;
+    assert!(writer.join().unwrap(), "files were hashed one at a time");
+    assert_eq!(
+        hashed,
+        vec![
+            (0, Some(*blake3::hash(b"first").as_bytes())),
+            (1, Some(*blake3::hash(b"second").as_bytes())),
+        ]
+    );
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn large_delta_does_not_block_the_runtime() {
+    let old = pseudo_random(1 << 20);
+    let mut new = old.clone();
+    new.extend(pseudo_random(16 << 20).iter().rev());
+    let signature = calculate_signature(&old, None, DEFAULT_STRONG_HASH_SIZE).unwrap();
+    let data: SharedBytes = Arc::new(new);
+
+    // The delta only starts once a small task on the same single-threaded
+    // runtime has run, which it can't if the delta holds the only thread.
+    let (tx, rx) = std::sync::mpsc::channel();
+    let small = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        tx.send(()).unwrap();
+    });
+    let (ran_alongside, delta) = blocking(&data, move |data| {
+        let ran_alongside = rx.recv_timeout(Duration::from_secs(10)).is_ok();
+        (ran_alongside, calculate_delta(&signature, data).unwrap())
+    })
+    .await
+    .unwrap();
+    small.await.unwrap();
+
+    assert!(ran_alongside, "the delta blocked the runtime");
+    assert_eq!(patched(&old, &delta), (*data).as_ref());
+}
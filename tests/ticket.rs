@@ -0,0 +1,75 @@
+//! Tickets printed by `syncr info` identify the node to `syncr connect`, and
+//! [`node_info`](syncr::node_info) gives the same identity to embedders.
+
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+use iroh::SecretKey;
+use syncr::{node_info, Config, Store};
+
+fn syncr(home: &Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .env("SYNCR_HOME", home)
+        .env_remove("SYNCR_CONFIG")
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "syncr {:?} failed", args);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Value of the `label: value` line in `output`.
+fn field<'a>(output: &'a str, label: &str) -> &'a str {
+    output
+        .lines()
+        .find_map(|line| line.strip_prefix(label)?.strip_prefix(": "))
+        .unwrap_or_else(|| panic!("no {} in {:?}", label, output))
+}
+
+#[test]
+fn ticket_round_trips_to_the_same_peer() {
+    let server = TempDir::new().unwrap();
+    let client = TempDir::new().unwrap();
+    std::fs::write(
+        server.path().join("config.toml"),
+        "[discovery]\npkarr = false\ndns = false\nmdns = false\n",
+    )
+    .unwrap();
+
+    let info = syncr(server.path(), &["info"]);
+    let peer_id = field(&info, "Peer ID");
+    let ticket = field(&info, "Ticket");
+
+    let connected = syncr(client.path(), &["connect", "--ticket", ticket]);
+    assert_eq!(field(&connected, "Peer ID"), peer_id);
+
+    let store = Store::new(client.path()).unwrap();
+    let addrs = store.list_addresses().unwrap();
+    assert_eq!(addrs.len(), 1);
+    assert_eq!(addrs[0].id.to_string(), peer_id);
+}
+
+#[tokio::test]
+async fn node_info_reports_the_identity_of_the_loaded_key() {
+    let home = TempDir::new().unwrap();
+    let mut config = Config {
+        home: Some(home.path().to_path_buf()),
+        ..Config::default()
+    };
+    config.discovery.pkarr = false;
+    config.discovery.dns = false;
+    config.discovery.mdns = false;
+    config.relay.enabled = false;
+
+    let info = node_info(&config).await.unwrap();
+
+    let bytes: [u8; 32] = std::fs::read(home.path().join("secret_key"))
+        .unwrap()
+        .try_into()
+        .unwrap();
+    let key = SecretKey::from_bytes(&bytes);
+    assert_eq!(info.peer_id, key.public());
+    assert_eq!(info.node_addr.id, info.peer_id);
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+}
@@ -0,0 +1,4682 @@
+//! End-to-end transfers between a server and a client running in-process on
+//! loopback endpoints, with no relay or discovery traffic.
+
+use iroh::{
+    discovery::static_provider::StaticProvider, endpoint::ConnectionError, Endpoint, EndpointAddr,
+    PublicKey, RelayMode, SecretKey,
+};
+use iroh_tickets::endpoint::EndpointTicket;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{broadcast, oneshot};
+use tokio::task::JoinHandle;
+
+use syncr::{
+    advertised_alpns, check_remote_id,
+    protocol::{
+        alpn_version, network_alpn, read_message, read_message_or_eof, write_message, ErrorCode,
+        FileMetadata, FileType, ManifestAction, ManifestEntry, Message, ProtocolError, RemoteError,
+        Share, ALPN, ALPN_V2, ALPN_V3, CAP_CHUNKS, CAP_RETRY, FILE_CHUNK_LEN, LIST_CHUNK_LEN,
+        MAX_FRAME_LEN, REFUSED_CODE, SUPPORTED_ALPNS,
+    },
+    store::{AccessMode, ConflictPolicy, Direction, SignatureStamp, SyncOutcome},
+    sync_utils, BufferBudget, CancellationToken, Cancelled, Config, CopyOptions, FileStatus,
+    IrohUtilsError, Overwrite, Retention, Route, ScheduleWindow, ServeOptions, Store, SyncClient,
+    SyncEvent, SyncServer, TimeOfDay, WindowLimit, TRASH_DIR,
+};
+
+/// A server sharing `served` and a client allowed to read and write it.
+struct Harness {
+    served: PathBuf,
+    local: PathBuf,
+    server_id: PublicKey,
+    server_addr: EndpointAddr,
+    server_store: Store,
+    /// What the server has read and not yet sent
+    server_buffers: BufferBudget,
+    client: SyncClient,
+    client_store: Store,
+    shutdown: Option<oneshot::Sender<()>>,
+    server_task: Option<JoinHandle<anyhow::Result<()>>>,
+    _dirs: Vec<TempDir>,
+}
+
+impl Harness {
+    async fn start() -> Self {
+        Self::start_speaking(SUPPORTED_ALPNS).await
+    }
+
+    /// Like [`Harness::start`], with a server that only accepts `alpns`.
+    async fn start_speaking(alpns: &[&[u8]]) -> Self {
+        Self::start_configured(alpns, test_config()).await
+    }
+
+    /// Like [`Harness::start_speaking`], with both sides using `config`.
+    async fn start_configured(alpns: &[&[u8]], config: Config) -> Self {
+        let served_dir = TempDir::new().unwrap();
+        let local_dir = TempDir::new().unwrap();
+        let server_data = TempDir::new().unwrap();
+        let client_data = TempDir::new().unwrap();
+        let served = std::fs::canonicalize(served_dir.path()).unwrap();
+        let local = std::fs::canonicalize(local_dir.path()).unwrap();
+
+        let server_endpoint = loopback_endpoint(None).await;
+        server_endpoint.set_alpns(alpns.iter().map(|alpn| alpn.to_vec()).collect());
+        let server_id = server_endpoint.id();
+        let server_addr = loopback_addr(&server_endpoint);
+        let addrs = StaticProvider::new();
+        addrs.add_endpoint_info(server_addr.clone());
+        let client_endpoint = loopback_endpoint(Some(addrs)).await;
+
+        let server_store = Store::new(server_data.path()).unwrap();
+        server_store
+            .allow_peer(&served, client_endpoint.id(), AccessMode::ReadWrite)
+            .unwrap();
+        let client_store = Store::new(client_data.path()).unwrap();
+
+        let server = SyncServer::from_endpoint(
+            server_endpoint,
+            config.clone(),
+            server_store.clone(),
+            ServeOptions::default(),
+        );
+        let server_buffers = server.buffers();
+        let (shutdown, stop) = oneshot::channel();
+        let server_task = tokio::spawn(server.run_until(async {
+            let _ = stop.await;
+        }));
+
+        Self {
+            served,
+            local,
+            server_id,
+            server_addr,
+            server_store,
+            server_buffers,
+            client: SyncClient::from_endpoint(client_endpoint, config),
+            client_store,
+            shutdown: Some(shutdown),
+            server_task: Some(server_task),
+            _dirs: vec![served_dir, local_dir, server_data, client_data],
+        }
+    }
+
+    fn remote(&self, relative: &str) -> String {
+        self.served.join(relative).to_string_lossy().into_owned()
+    }
+
+    async fn copy(&self, remote: &str, local: &Path) -> anyhow::Result<()> {
+        self.client
+            .copy(self.server_id, remote, local, CopyOptions::default())
+            .await
+            .map(|_| ())
+    }
+
+    async fn stop(mut self) {
+        let _ = self.shutdown.take().unwrap().send(());
+        self.server_task.take().unwrap().await.unwrap().unwrap();
+    }
+}
+
+fn test_config() -> Config {
+    let mut config = Config::default();
+    config.discovery.pkarr = false;
+    config.discovery.dns = false;
+    config.discovery.mdns = false;
+    config.idle_timeout_secs = 10;
+    config
+}
+
+async fn loopback_endpoint(discovery: Option<StaticProvider>) -> Endpoint {
+    let mut builder = Endpoint::builder()
+        .clear_discovery()
+        .secret_key(SecretKey::generate(&mut rand::rng()))
+        .alpns(SUPPORTED_ALPNS.iter().map(|alpn| alpn.to_vec()).collect())
+        .relay_mode(RelayMode::Disabled)
+        .bind_addr_v4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
+    if let Some(discovery) = discovery {
+        builder = builder.discovery(discovery);
+    }
+    builder.bind().await.unwrap()
+}
+
+/// Address of `endpoint` reachable over loopback.
+fn loopback_addr(endpoint: &Endpoint) -> EndpointAddr {
+    let mut addr = EndpointAddr::new(endpoint.id());
+    for socket in endpoint.bound_sockets() {
+        let ip: IpAddr = if socket.is_ipv4() {
+            Ipv4Addr::LOCALHOST.into()
+        } else {
+            Ipv6Addr::LOCALHOST.into()
+        };
+        addr = addr.with_ip_addr(SocketAddr::new(ip, socket.port()));
+    }
+    addr
+}
+
+fn remote_code(err: &anyhow::Error) -> Option<ErrorCode> {
+    err.downcast_ref::<RemoteError>().map(|e| e.code)
+}
+
+#[tokio::test]
+async fn copies_single_file() {
+    let h = Harness::start().await;
+    std::fs::write(h.served.join("hello.txt"), b"hello world").unwrap();
+
+    let target = h.local.join("hello.txt");
+    h.copy(&h.remote("hello.txt"), &target).await.unwrap();
+
+    assert_eq!(std::fs::read(&target).unwrap(), b"hello world");
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn loopback_peer_is_reached_directly_on_the_local_network() {
+    let h = Harness::start().await;
+    std::fs::write(h.served.join("hello.txt"), b"hello world").unwrap();
+
+    h.copy(&h.remote("hello.txt"), &h.local.join("hello.txt"))
+        .await
+        .unwrap();
+    let route = h.client.route(h.server_id);
+    assert!(
+        matches!(route, Route::Direct(addr) if addr.ip().is_loopback()),
+        "{:?}",
+        route
+    );
+    assert!(route.is_local());
+    assert!(route.to_string().contains("local network"), "{}", route);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn downgrades_to_v2_with_a_server_that_only_speaks_it() {
+    let h = Harness::start_speaking(&[ALPN_V2]).await;
+    std::fs::write(h.served.join("hello.txt"), b"hello world").unwrap();
+
+    let target = h.local.join("hello.txt");
+    h.copy(&h.remote("hello.txt"), &target).await.unwrap();
+    assert_eq!(std::fs::read(&target).unwrap(), b"hello world");
+
+    h.client
+        .push(h.server_id, &target, h.remote("pushed.txt"), None)
+        .await
+        .unwrap();
+    assert_eq!(
+        std::fs::read(h.served.join("pushed.txt")).unwrap(),
+        b"hello world"
+    );
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn server_speaking_only_the_first_version_is_not_connected_to() {
+    assert_eq!(alpn_version(b"syncr/1"), None);
+    let h = Harness::start_speaking(&[b"syncr/1"]).await;
+    std::fs::write(h.served.join("hello.txt"), b"hello world").unwrap();
+
+    let err = h
+        .copy(&h.remote("hello.txt"), &h.local.join("hello.txt"))
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(
+            err.downcast_ref::<IrohUtilsError>(),
+            Some(IrohUtilsError::PeerUnreachable(_))
+        ),
+        "{:#}",
+        err
+    );
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn only_peers_on_the_same_network_connect() {
+    assert_eq!(alpn_version(&network_alpn(ALPN, Some("staging"))), Some(8));
+    let staging = advertised_alpns(Some("staging"));
+    let alpns: Vec<&[u8]> = staging.iter().map(Vec::as_slice).collect();
+    let mut config = test_config();
+    config.network = Some("staging".to_string());
+    let h = Harness::start_configured(&alpns, config).await;
+    std::fs::write(h.served.join("hello.txt"), b"hello world").unwrap();
+
+    let target = h.local.join("hello.txt");
+    h.copy(&h.remote("hello.txt"), &target).await.unwrap();
+    assert_eq!(std::fs::read(&target).unwrap(), b"hello world");
+
+    for network in [Some("production"), None] {
+        let addrs = StaticProvider::new();
+        addrs.add_endpoint_info(h.server_addr.clone());
+        let mut config = test_config();
+        config.network = network.map(str::to_string);
+        let other = SyncClient::from_endpoint(loopback_endpoint(Some(addrs)).await, config);
+        let err = other
+            .copy(
+                h.server_id,
+                h.remote("hello.txt"),
+                h.local.join("other.txt"),
+                CopyOptions::default(),
+            )
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<IrohUtilsError>(),
+                Some(IrohUtilsError::PeerUnreachable(_))
+            ),
+            "{:#}",
+            err
+        );
+    }
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn copies_directory_tree() {
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir_all(tree.join("nested/deeper")).unwrap();
+    std::fs::write(tree.join("a.txt"), b"a").unwrap();
+    std::fs::write(tree.join("nested/b.txt"), b"bb").unwrap();
+    std::fs::write(tree.join("nested/deeper/c.bin"), vec![7u8; 300_000]).unwrap();
+
+    let target = h.local.join("tree");
+    h.copy(&h.remote("tree"), &target).await.unwrap();
+
+    assert_eq!(std::fs::read(target.join("a.txt")).unwrap(), b"a");
+    assert_eq!(std::fs::read(target.join("nested/b.txt")).unwrap(), b"bb");
+    assert_eq!(
+        std::fs::read(target.join("nested/deeper/c.bin")).unwrap(),
+        vec![7u8; 300_000]
+    );
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn files_over_the_max_size_are_left_out() {
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir_all(tree.join("nested")).unwrap();
+    std::fs::write(tree.join("small.txt"), b"small").unwrap();
+    std::fs::write(tree.join("nested/exact.bin"), vec![1u8; 1000]).unwrap();
+    std::fs::write(tree.join("nested/big.bin"), vec![2u8; 1001]).unwrap();
+
+    let target = h.local.join("tree");
+    let opts = CopyOptions {
+        max_size: Some(1000),
+        ..Default::default()
+    };
+    let report = h
+        .client
+        .copy(h.server_id, h.remote("tree"), &target, opts)
+        .await
+        .unwrap();
+
+    assert_eq!(std::fs::read(target.join("small.txt")).unwrap(), b"small");
+    assert_eq!(
+        std::fs::read(target.join("nested/exact.bin")).unwrap(),
+        vec![1u8; 1000]
+    );
+    assert!(!target.join("nested/big.bin").exists());
+    assert_eq!(report.stats.files_transferred, 2);
+    assert_eq!(report.stats.files_too_large, 1);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn trailing_slash_copies_the_directory_itself() {
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir_all(tree.join("nested")).unwrap();
+    std::fs::write(tree.join("a.txt"), b"a").unwrap();
+    std::fs::write(tree.join("nested/b.txt"), b"bb").unwrap();
+
+    let target = h.local.join("tree");
+    let remote = format!("{}/", h.remote("tree"));
+    h.copy(&remote, &target).await.unwrap();
+    assert_eq!(std::fs::read(target.join("a.txt")).unwrap(), b"a");
+    assert_eq!(std::fs::read(target.join("nested/b.txt")).unwrap(), b"bb");
+
+    // Again, now that there is a local copy to compare with.
+    std::fs::write(tree.join("a.txt"), b"changed").unwrap();
+    h.copy(&remote, &target).await.unwrap();
+    assert_eq!(std::fs::read(target.join("a.txt")).unwrap(), b"changed");
+    assert!(target.join("nested").is_dir());
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn file_beside_a_directory_of_the_same_prefix_is_copied_as_a_file() {
+    let h = Harness::start().await;
+    std::fs::write(h.served.join("x"), b"file").unwrap();
+    std::fs::create_dir_all(h.served.join("x.d")).unwrap();
+    std::fs::write(h.served.join("x.d/y"), b"inside").unwrap();
+
+    let file = h.local.join("x");
+    h.copy(&h.remote("x"), &file).await.unwrap();
+    assert!(file.is_file());
+    assert_eq!(std::fs::read(&file).unwrap(), b"file");
+
+    let dir = h.local.join("x.d");
+    h.copy(&format!("{}/", h.remote("x.d")), &dir)
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read(dir.join("y")).unwrap(), b"inside");
+    assert_eq!(std::fs::read(&file).unwrap(), b"file");
+    h.stop().await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn hard_links_are_recreated_as_links() {
+    use std::os::unix::fs::MetadataExt;
+
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir_all(tree.join("nested")).unwrap();
+    std::fs::write(tree.join("a.txt"), b"shared contents").unwrap();
+    std::fs::hard_link(tree.join("a.txt"), tree.join("nested/b.txt")).unwrap();
+
+    let target = h.local.join("tree");
+    let report = h
+        .client
+        .copy(
+            h.server_id,
+            h.remote("tree"),
+            &target,
+            CopyOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    let a = std::fs::metadata(target.join("a.txt")).unwrap();
+    let b = std::fs::metadata(target.join("nested/b.txt")).unwrap();
+    assert_eq!((a.dev(), a.ino()), (b.dev(), b.ino()));
+    assert_eq!(
+        std::fs::read(target.join("nested/b.txt")).unwrap(),
+        b"shared contents"
+    );
+    // The contents were only transferred once.
+    assert_eq!(report.stats.files_transferred, 1);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn copies_file_spanning_several_chunks() {
+    let h = Harness::start().await;
+    let data: Vec<u8> = (0..FILE_CHUNK_LEN * 3 + 17)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    std::fs::write(h.served.join("big.bin"), &data).unwrap();
+
+    let target = h.local.join("big.bin");
+    h.copy(&h.remote("big.bin"), &target).await.unwrap();
+
+    assert_eq!(std::fs::read(&target).unwrap(), data);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn progress_counts_every_byte_of_the_file() {
+    let h = Harness::start().await;
+    let data = vec![3u8; FILE_CHUNK_LEN * 2 + 5];
+    std::fs::write(h.served.join("big.bin"), &data).unwrap();
+    let events = SyncEvent::channel();
+    let mut received = events.subscribe();
+
+    let opts = CopyOptions {
+        progress: true,
+        events: Some(events),
+        ..Default::default()
+    };
+    let report = h
+        .client
+        .copy(
+            h.server_id,
+            h.remote("big.bin"),
+            h.local.join("big.bin"),
+            opts,
+        )
+        .await
+        .unwrap();
+
+    let mut counted = 0;
+    while let Ok(event) = received.try_recv() {
+        if let SyncEvent::FileCompleted { bytes, .. } = event {
+            counted += bytes;
+        }
+    }
+    assert_eq!(counted, data.len() as u64);
+    assert_eq!(report.stats.bytes_transferred, data.len() as u64);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn limited_rate_sets_a_minimum_transfer_time() {
+    const RATE: u64 = 64 * 1024;
+    let h = Harness::start().await;
+    let data = vec![5u8; 3 * RATE as usize];
+    std::fs::write(h.served.join("big.bin"), &data).unwrap();
+
+    let opts = CopyOptions {
+        limit_rate: Some(RATE),
+        ..Default::default()
+    };
+    let target = h.local.join("big.bin");
+    let started = Instant::now();
+    h.client
+        .copy(h.server_id, h.remote("big.bin"), &target, opts)
+        .await
+        .unwrap();
+
+    // The bucket starts full, so the first second's worth isn't held back.
+    let least = Duration::from_secs_f64((data.len() as u64 - RATE) as f64 / RATE as f64);
+    assert!(started.elapsed() >= least, "{:?}", started.elapsed());
+    assert_eq!(std::fs::read(&target).unwrap(), data);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn partial_download_is_resumed_where_it_stopped() {
+    let h = Harness::start().await;
+    let data: Vec<u8> = (0..FILE_CHUNK_LEN * 3).map(|i| (i % 251) as u8).collect();
+    std::fs::write(h.served.join("big.bin"), &data).unwrap();
+    let target = h.local.join("big.bin");
+    let kept = FILE_CHUNK_LEN + 100;
+    std::fs::write(h.local.join("big.bin.syncr-partial"), &data[..kept]).unwrap();
+
+    let report = h
+        .client
+        .copy(
+            h.server_id,
+            h.remote("big.bin"),
+            &target,
+            CopyOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(report.stats.bytes_transferred, (data.len() - kept) as u64);
+    assert_eq!(std::fs::read(&target).unwrap(), data);
+    assert!(!h.local.join("big.bin.syncr-partial").exists());
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn configured_chunk_size_sets_the_number_of_chunks() {
+    let mut config = test_config();
+    config.chunk_size = 1000;
+    let h = Harness::start_configured(SUPPORTED_ALPNS, config).await;
+    std::fs::write(h.served.join("data.bin"), vec![7u8; 3500]).unwrap();
+    let (_client, _connection, mut send, mut recv) = raw_session(&h).await;
+    let timeout = Duration::from_secs(10);
+
+    let request = Message::FileRequest {
+        path: h.remote("data.bin"),
+        offset: 0,
+    };
+    write_message(&mut send, &request).await.unwrap();
+    let mut chunks = Vec::new();
+    loop {
+        match read_message(&mut recv, timeout).await.unwrap() {
+            Message::FileData { data, is_last, .. } => {
+                chunks.push(data.len());
+                if is_last {
+                    break;
+                }
+            }
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    }
+
+    assert_eq!(chunks, vec![1000, 1000, 1000, 500]);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn download_cut_short_is_an_error() {
+    // A peer that lists one file, then ends its stream before the last chunk.
+    let server = loopback_endpoint(None).await;
+    let addrs = StaticProvider::new();
+    addrs.add_endpoint_info(loopback_addr(&server));
+    let client = SyncClient::from_endpoint(loopback_endpoint(Some(addrs)).await, test_config());
+
+    let server_id = server.id();
+    let peer = tokio::spawn(async move {
+        let connection = server
+            .accept()
+            .await
+            .unwrap()
+            .accept()
+            .unwrap()
+            .await
+            .unwrap();
+        loop {
+            let Ok((mut send, mut recv)) = connection.accept_bi().await else {
+                break;
+            };
+            read_message(&mut recv, Duration::from_secs(10))
+                .await
+                .unwrap();
+            write_message(&mut send, &Message::LegacyHandshake { version: 1 })
+                .await
+                .unwrap();
+            let reply = match read_message(&mut recv, Duration::from_secs(10)).await {
+                Ok(Message::ListRequest { path, .. }) => Message::ListResponse {
+                    files: vec![FileMetadata {
+                        path,
+                        len: 10,
+                        modified: 0,
+                        file_type: FileType::File,
+                        link_target: None,
+                        hash: None,
+                        mode: None,
+                    }],
+                    is_last: true,
+                },
+                Ok(Message::FileRequest { path, .. }) => Message::FileData {
+                    path,
+                    data: b"half".to_vec(),
+                    offset: 0,
+                    is_last: false,
+                    hash: None,
+                },
+                _ => break,
+            };
+            write_message(&mut send, &reply).await.unwrap();
+            send.finish().unwrap();
+        }
+    });
+
+    let local = TempDir::new().unwrap();
+    let target = local.path().join("file.txt");
+    let err = client
+        .copy(
+            server_id,
+            "/shared/file.txt",
+            &target,
+            CopyOptions::default(),
+        )
+        .await
+        .unwrap_err();
+
+    assert!(err.to_string().contains("failed to sync"), "{:?}", err);
+    assert!(!target.exists());
+    peer.abort();
+}
+
+#[tokio::test]
+async fn download_shorter_than_listed_is_rejected() {
+    // A peer that lists a file at 10 bytes, then sends 4 of them as if that
+    // were all, hash included.
+    let server = loopback_endpoint(None).await;
+    let addrs = StaticProvider::new();
+    addrs.add_endpoint_info(loopback_addr(&server));
+    let client = SyncClient::from_endpoint(loopback_endpoint(Some(addrs)).await, test_config());
+
+    let server_id = server.id();
+    let peer = tokio::spawn(async move {
+        let connection = server
+            .accept()
+            .await
+            .unwrap()
+            .accept()
+            .unwrap()
+            .await
+            .unwrap();
+        while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+            tokio::spawn(async move {
+                let timeout = Duration::from_secs(10);
+                read_message(&mut recv, timeout).await?;
+                write_message(&mut send, &Message::LegacyHandshake { version: 1 }).await?;
+                while let Some(msg) = read_message_or_eof(&mut recv, timeout).await? {
+                    let reply = match msg {
+                        Message::ListRequest { path, .. } => Message::ListResponse {
+                            files: vec![FileMetadata {
+                                path,
+                                len: 10,
+                                modified: 0,
+                                file_type: FileType::File,
+                                link_target: None,
+                                hash: None,
+                                mode: None,
+                            }],
+                            is_last: true,
+                        },
+                        Message::FileRequest { path, .. } => Message::FileData {
+                            path,
+                            data: b"half".to_vec(),
+                            offset: 0,
+                            is_last: true,
+                            hash: Some(*blake3::hash(b"half").as_bytes()),
+                        },
+                        msg => anyhow::bail!("Unexpected message: {:?}", msg),
+                    };
+                    write_message(&mut send, &reply).await?;
+                }
+                anyhow::Ok(())
+            });
+        }
+    });
+
+    let local = TempDir::new().unwrap();
+    let target = local.path().join("file.txt");
+    let err = client
+        .copy(
+            server_id,
+            "/shared/file.txt",
+            &target,
+            CopyOptions::default(),
+        )
+        .await
+        .unwrap_err();
+
+    let message = format!("{:#}", err);
+    assert!(message.contains("listed at 10"), "{}", message);
+    assert!(!target.exists());
+    assert_eq!(std::fs::read_dir(local.path()).unwrap().count(), 0);
+    peer.abort();
+}
+
+#[tokio::test]
+async fn failed_file_is_retried_without_stopping_the_others() {
+    // A peer listing three files, one of which it always cuts short.
+    let server = loopback_endpoint(None).await;
+    let addrs = StaticProvider::new();
+    addrs.add_endpoint_info(loopback_addr(&server));
+    let client = SyncClient::from_endpoint(loopback_endpoint(Some(addrs)).await, test_config());
+
+    let server_id = server.id();
+    let (requested_tx, mut requested) = tokio::sync::mpsc::unbounded_channel();
+    let peer = tokio::spawn(async move {
+        let connection = server
+            .accept()
+            .await
+            .unwrap()
+            .accept()
+            .unwrap()
+            .await
+            .unwrap();
+        while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+            let requested_tx = requested_tx.clone();
+            tokio::spawn(async move {
+                let timeout = Duration::from_secs(10);
+                read_message(&mut recv, timeout).await?;
+                write_message(&mut send, &Message::LegacyHandshake { version: 1 }).await?;
+                while let Some(msg) = read_message_or_eof(&mut recv, timeout).await? {
+                    let reply = match msg {
+                        Message::ListRequest { .. } => {
+                            let entry = |path: &str, file_type| FileMetadata {
+                                path: path.to_string(),
+                                len: 4,
+                                modified: 0,
+                                file_type,
+                                link_target: None,
+                                hash: None,
+                                mode: None,
+                            };
+                            Message::ListResponse {
+                                files: vec![
+                                    entry("/shared", FileType::Dir),
+                                    entry("/shared/a.txt", FileType::File),
+                                    entry("/shared/bad.txt", FileType::File),
+                                    entry("/shared/c.txt", FileType::File),
+                                ],
+                                is_last: true,
+                            }
+                        }
+                        Message::FileRequest { path, .. } => {
+                            let _ = requested_tx.send(path.clone());
+                            let bad = path.ends_with("bad.txt");
+                            Message::FileData {
+                                path,
+                                data: if bad {
+                                    b"ba".to_vec()
+                                } else {
+                                    b"good".to_vec()
+                                },
+                                offset: 0,
+                                is_last: !bad,
+                                hash: (!bad).then(|| *blake3::hash(b"good").as_bytes()),
+                            }
+                        }
+                        msg => anyhow::bail!("Unexpected message: {:?}", msg),
+                    };
+                    write_message(&mut send, &reply).await?;
+                    if matches!(reply, Message::FileData { is_last: false, .. }) {
+                        send.finish()?;
+                        break;
+                    }
+                }
+                anyhow::Ok(())
+            });
+        }
+    });
+
+    let local = TempDir::new().unwrap();
+    let opts = CopyOptions {
+        retries: 1,
+        ..Default::default()
+    };
+    let err = client
+        .copy(server_id, "/shared", local.path(), opts)
+        .await
+        .unwrap_err();
+
+    let message = format!("{:#}", err);
+    assert!(message.contains("1 of 3 files failed"), "{}", message);
+    assert!(message.contains("2 synced"), "{}", message);
+    assert!(message.contains("/shared/bad.txt"), "{}", message);
+    assert_eq!(std::fs::read(local.path().join("a.txt")).unwrap(), b"good");
+    assert_eq!(std::fs::read(local.path().join("c.txt")).unwrap(), b"good");
+    assert!(!local.path().join("bad.txt").exists());
+
+    // The bad file was tried once more before it was given up on.
+    let mut bad_requests = 0;
+    while let Ok(path) = requested.try_recv() {
+        bad_requests += usize::from(path.ends_with("bad.txt"));
+    }
+    assert_eq!(bad_requests, 2);
+    peer.abort();
+}
+
+#[tokio::test]
+async fn backup_keeps_overwritten_file_in_trash() {
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir(&tree).unwrap();
+    std::fs::write(tree.join("notes.txt"), b"remote version").unwrap();
+
+    let target = h.local.join("tree");
+    std::fs::create_dir(&target).unwrap();
+    std::fs::write(target.join("notes.txt"), b"local version").unwrap();
+
+    let opts = CopyOptions {
+        backup: Some(Retention::default()),
+        ..Default::default()
+    };
+    h.client
+        .copy(h.server_id, h.remote("tree"), &target, opts)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        std::fs::read(target.join("notes.txt")).unwrap(),
+        b"remote version"
+    );
+    let backups: Vec<_> = std::fs::read_dir(target.join(TRASH_DIR))
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .collect();
+    assert_eq!(backups.len(), 1);
+    assert!(backups[0]
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .starts_with("notes.txt."));
+    assert_eq!(std::fs::read(&backups[0]).unwrap(), b"local version");
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn fsync_flushes_file_and_directory() {
+    let h = Harness::start().await;
+    std::fs::write(h.served.join("ledger.txt"), b"balance: 42").unwrap();
+
+    let target = h.local.join("ledger.txt");
+    let before = sync_utils::flush_count();
+    let opts = CopyOptions {
+        fsync: true,
+        ..Default::default()
+    };
+    h.client
+        .copy(h.server_id, h.remote("ledger.txt"), &target, opts)
+        .await
+        .unwrap();
+
+    assert_eq!(std::fs::read(&target).unwrap(), b"balance: 42");
+    // The file before its rename, then the directory after.
+    assert!(sync_utils::flush_count() >= before + 2);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn backups_beyond_the_version_limit_are_pruned() {
+    let h = Harness::start().await;
+    let remote = h.served.join("notes.txt");
+    let target = h.local.join("notes.txt");
+    let opts = CopyOptions {
+        backup: Some(Retention {
+            max_versions: 2,
+            ..Retention::default()
+        }),
+        ..Default::default()
+    };
+
+    for version in 0..4 {
+        std::fs::write(&remote, format!("version {}", version)).unwrap();
+        h.client
+            .copy(h.server_id, h.remote("notes.txt"), &target, opts.clone())
+            .await
+            .unwrap();
+        // Backups are named by the millisecond they were taken.
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    // A single-file sync keeps its backups beside the file.
+    let mut backups: Vec<Vec<u8>> = std::fs::read_dir(h.local.join(TRASH_DIR))
+        .unwrap()
+        .map(|e| std::fs::read(e.unwrap().path()).unwrap())
+        .collect();
+    backups.sort();
+    assert_eq!(backups, vec![b"version 1".to_vec(), b"version 2".to_vec()]);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn copy_to_dash_writes_file_to_stdout() {
+    let h = Harness::start().await;
+    let data: Vec<u8> = (0..FILE_CHUNK_LEN + 100).map(|i| (i % 241) as u8).collect();
+    std::fs::write(h.served.join("log.bin"), &data).unwrap();
+
+    // The binary runs as its own peer, reaching the server through a ticket.
+    let home = TempDir::new().unwrap();
+    std::fs::write(
+        home.path().join("config.toml"),
+        "[discovery]\npkarr = false\ndns = false\nmdns = false\n",
+    )
+    .unwrap();
+    let secret = SecretKey::generate(&mut rand::rng());
+    std::fs::write(home.path().join("secret_key"), secret.to_bytes()).unwrap();
+    h.server_store
+        .allow_peer(&h.served, secret.public(), AccessMode::Read)
+        .unwrap();
+    let ticket = EndpointTicket::new(h.server_addr.clone()).to_string();
+
+    let output = tokio::process::Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .env("SYNCR_HOME", home.path())
+        .env_remove("SYNCR_CONFIG")
+        .args(["copy", &ticket, &h.remote("log.bin"), "-", "--no-progress"])
+        .output()
+        .await
+        .unwrap();
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(output.stdout, data);
+    h.stop().await;
+}
+
+/// A home for running the binary as a peer of its own, which only reaches
+/// others through tickets, and the identity it has there.
+fn binary_home() -> (TempDir, SecretKey) {
+    let home = TempDir::new().unwrap();
+    std::fs::write(
+        home.path().join("config.toml"),
+        "[discovery]\npkarr = false\ndns = false\nmdns = false\n",
+    )
+    .unwrap();
+    let secret = SecretKey::generate(&mut rand::rng());
+    std::fs::write(home.path().join("secret_key"), secret.to_bytes()).unwrap();
+    (home, secret)
+}
+
+#[tokio::test]
+async fn denied_start_sync_exits_with_permission_denied() {
+    let h = Harness::start().await;
+    std::fs::write(h.served.join("notes.txt"), b"notes").unwrap();
+    let (home, secret) = binary_home();
+    // Enough to copy the file, but not to sync it, which is refused before
+    // anything is copied.
+    h.server_store
+        .allow_peer(&h.served, secret.public(), AccessMode::Read)
+        .unwrap();
+    let ticket = EndpointTicket::new(h.server_addr.clone()).to_string();
+    let local = home.path().join("notes.txt");
+
+    let output = tokio::process::Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .env("SYNCR_HOME", home.path())
+        .env_remove("SYNCR_CONFIG")
+        .env_remove("SYNCR_PROFILE")
+        .args(["sync", &ticket, &h.remote("notes.txt")])
+        .arg(&local)
+        .arg("--no-progress")
+        .output()
+        .await
+        .unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2), "{}", stderr);
+    assert!(stderr.contains("has not allowed you to sync"), "{}", stderr);
+    assert!(!local.exists());
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn ndjson_progress_reports_each_file_then_a_summary() {
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir(&tree).unwrap();
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        std::fs::write(tree.join(name), name).unwrap();
+    }
+    let (home, secret) = binary_home();
+    h.server_store
+        .allow_peer(&h.served, secret.public(), AccessMode::Read)
+        .unwrap();
+    let ticket = EndpointTicket::new(h.server_addr.clone()).to_string();
+
+    let output = tokio::process::Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .env("SYNCR_HOME", home.path())
+        .env_remove("SYNCR_CONFIG")
+        .env_remove("SYNCR_PROFILE")
+        .args(["copy", &ticket, &h.remote("tree")])
+        .arg(home.path().join("tree"))
+        .args(["--progress-format", "ndjson", "--jobs", "1"])
+        .output()
+        .await
+        .unwrap();
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let events: Vec<serde_json::Value> = stdout
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let (summary, files) = events.split_last().unwrap();
+    assert_eq!(summary["event"], "summary", "{}", stdout);
+    assert_eq!(summary["files_transferred"], 3, "{}", stdout);
+    // With one job, each file is done before the next starts.
+    let mut paths = Vec::new();
+    for pair in files.chunks(2) {
+        assert_eq!(pair[0]["event"], "file_started", "{}", stdout);
+        assert_eq!(pair[1]["event"], "file_done", "{}", stdout);
+        assert_eq!(pair[0]["path"], pair[1]["path"], "{}", stdout);
+        assert_eq!(pair[1]["bytes"], 5, "{}", stdout);
+        paths.push(pair[0]["path"].as_str().unwrap().to_string());
+    }
+    paths.sort();
+    let expected: Vec<String> = ["a.txt", "b.txt", "c.txt"]
+        .iter()
+        .map(|name| h.remote(&format!("tree/{}", name)))
+        .collect();
+    assert_eq!(paths, expected);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn jobs_cap_the_files_in_flight() {
+    const FILES: usize = 8;
+    const JOBS: usize = 2;
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir(&tree).unwrap();
+    for i in 0..FILES {
+        std::fs::write(tree.join(format!("{}.bin", i)), vec![i as u8; 200_000]).unwrap();
+    }
+    let (home, secret) = binary_home();
+    h.server_store
+        .allow_peer(&h.served, secret.public(), AccessMode::Read)
+        .unwrap();
+    let ticket = EndpointTicket::new(h.server_addr.clone()).to_string();
+
+    let output = tokio::process::Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .env("SYNCR_HOME", home.path())
+        .env_remove("SYNCR_CONFIG")
+        .env_remove("SYNCR_PROFILE")
+        .args(["copy", &ticket, &h.remote("tree")])
+        .arg(home.path().join("tree"))
+        .args(["--progress-format", "ndjson", "--jobs", &JOBS.to_string()])
+        .output()
+        .await
+        .unwrap();
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let (mut running, mut most, mut done) = (0, 0, 0);
+    for line in stdout.lines() {
+        let event: serde_json::Value = serde_json::from_str(line).unwrap();
+        match event["event"].as_str().unwrap() {
+            "file_started" => {
+                running += 1;
+                most = usize::max(most, running);
+            }
+            "file_done" => {
+                running -= 1;
+                done += 1;
+            }
+            _ => {}
+        }
+    }
+    assert_eq!(done, FILES, "{}", stdout);
+    assert!((1..=JOBS).contains(&most), "{} in flight\n{}", most, stdout);
+    for i in 0..FILES {
+        let copied = std::fs::read(home.path().join(format!("tree/{}.bin", i))).unwrap();
+        assert_eq!(copied, vec![i as u8; 200_000]);
+    }
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn unreachable_peer_exits_with_its_own_code() {
+    let (home, _) = binary_home();
+    let nobody = SecretKey::generate(&mut rand::rng()).public().to_string();
+
+    let output = tokio::process::Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .env("SYNCR_HOME", home.path())
+        .env_remove("SYNCR_CONFIG")
+        .env_remove("SYNCR_PROFILE")
+        .args(["--no-relay", "copy", &nobody, "/shared/notes.txt"])
+        .arg(home.path().join("notes.txt"))
+        .output()
+        .await
+        .unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(3), "{}", stderr);
+}
+
+#[tokio::test]
+async fn invalid_arguments_exit_apart_from_denied_access() {
+    let (home, _) = binary_home();
+    let syncr = |args: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_syncr"))
+            .env("SYNCR_HOME", home.path())
+            .env_remove("SYNCR_CONFIG")
+            .env_remove("SYNCR_PROFILE")
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    for args in [&["copy"][..], &["--no-such-flag", "info"], &["frobnicate"]] {
+        let output = syncr(args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(1), "{:?}: {}", args, stderr);
+        assert!(stderr.contains("Usage"), "{:?}: {}", args, stderr);
+    }
+    for args in [&["--help"][..], &["--version"]] {
+        let output = syncr(args);
+        assert_eq!(output.status.code(), Some(0), "{:?}", args);
+        assert!(!output.stdout.is_empty(), "{:?}", args);
+    }
+}
+
+#[tokio::test]
+async fn directory_cannot_be_copied_to_stdout() {
+    let h = Harness::start().await;
+    std::fs::create_dir(h.served.join("tree")).unwrap();
+    std::fs::write(h.served.join("tree/a.txt"), b"a").unwrap();
+
+    let err = h.copy(&h.remote("tree"), Path::new("-")).await.unwrap_err();
+
+    assert!(err.to_string().contains("is a directory"), "{:?}", err);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn updates_existing_file_with_delta() {
+    let h = Harness::start().await;
+    let original: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let mut updated = original.clone();
+    updated[100_000..100_016].copy_from_slice(b"changed in place");
+    std::fs::write(h.served.join("data.bin"), &updated).unwrap();
+
+    let target = h.local.join("data.bin");
+    std::fs::write(&target, &original).unwrap();
+    h.copy(&h.remote("data.bin"), &target).await.unwrap();
+
+    assert_eq!(std::fs::read(&target).unwrap(), updated);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn no_clobber_keeps_differing_local_file() {
+    let h = Harness::start().await;
+    std::fs::write(h.served.join("notes.txt"), b"remote version").unwrap();
+    let target = h.local.join("notes.txt");
+    std::fs::write(&target, b"local version").unwrap();
+
+    let opts = CopyOptions {
+        overwrite: Overwrite::Never,
+        ..Default::default()
+    };
+    let report = h
+        .client
+        .copy(h.server_id, h.remote("notes.txt"), &target, opts)
+        .await
+        .unwrap();
+
+    assert_eq!(std::fs::read(&target).unwrap(), b"local version");
+    assert_eq!(report.stats.files_transferred, 0);
+    assert_eq!(report.stats.files_skipped, 1);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn yes_overwrites_differing_local_file() {
+    let h = Harness::start().await;
+    std::fs::write(h.served.join("notes.txt"), b"remote version").unwrap();
+    let target = h.local.join("notes.txt");
+    std::fs::write(&target, b"local version").unwrap();
+
+    let opts = CopyOptions {
+        overwrite: Overwrite::Always,
+        ..Default::default()
+    };
+    h.client
+        .copy(h.server_id, h.remote("notes.txt"), &target, opts)
+        .await
+        .unwrap();
+
+    assert_eq!(std::fs::read(&target).unwrap(), b"remote version");
+    h.stop().await;
+}
+
+/// Every entry below `root` with its contents and modification time, in
+/// path order.
+fn tree_state(root: &Path) -> Vec<(PathBuf, Vec<u8>, std::time::SystemTime)> {
+    let mut state = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            let meta = std::fs::symlink_metadata(&path).unwrap();
+            let contents = if meta.is_dir() {
+                dirs.push(path.clone());
+                Vec::new()
+            } else {
+                std::fs::read(&path).unwrap()
+            };
+            state.push((path, contents, meta.modified().unwrap()));
+        }
+    }
+    state.sort();
+    state
+}
+
+#[tokio::test]
+async fn dry_run_leaves_the_local_tree_untouched() {
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir_all(tree.join("nested")).unwrap();
+    std::fs::write(tree.join("new.txt"), b"new").unwrap();
+    std::fs::write(tree.join("changed.txt"), b"remote version").unwrap();
+    std::fs::write(tree.join("nested/deep.txt"), b"deep").unwrap();
+    let target = h.local.join("tree");
+    std::fs::create_dir_all(&target).unwrap();
+    std::fs::write(target.join("changed.txt"), b"local version").unwrap();
+    let before = tree_state(&h.local);
+
+    let opts = CopyOptions {
+        dry_run: true,
+        overwrite: Overwrite::Always,
+        ..Default::default()
+    };
+    let report = h
+        .client
+        .copy(h.server_id, h.remote("tree"), &target, opts)
+        .await
+        .unwrap();
+
+    assert!(report.changed.is_empty(), "{:?}", report.changed);
+    assert_eq!(tree_state(&h.local), before);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn empty_files_on_either_side_end_up_identical() {
+    let h = Harness::start().await;
+    let content = b"not empty any more".to_vec();
+    // (local, remote) before the copy
+    let cases = [
+        ("fill.txt", Vec::new(), content.clone()),
+        ("empty.txt", content.clone(), Vec::new()),
+        ("both.txt", Vec::new(), Vec::new()),
+        ("neither.txt", b"old contents".to_vec(), content.clone()),
+    ];
+    for (name, local, remote) in &cases {
+        std::fs::write(h.served.join(name), remote).unwrap();
+        std::fs::write(h.local.join(name), local).unwrap();
+    }
+
+    for (name, _, remote) in &cases {
+        let target = h.local.join(name);
+        h.copy(&h.remote(name), &target).await.unwrap();
+        assert_eq!(&std::fs::read(&target).unwrap(), remote, "{}", name);
+    }
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn missing_path_is_not_found() {
+    let h = Harness::start().await;
+
+    let err = h
+        .copy(&h.remote("missing.txt"), &h.local.join("missing.txt"))
+        .await
+        .unwrap_err();
+
+    assert_eq!(remote_code(&err), Some(ErrorCode::NotFound));
+    assert!(!h.local.join("missing.txt").exists());
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn missing_path_exits_with_not_found() {
+    let h = Harness::start().await;
+    let (home, secret) = binary_home();
+    h.server_store
+        .allow_peer(&h.served, secret.public(), AccessMode::Read)
+        .unwrap();
+    let ticket = EndpointTicket::new(h.server_addr.clone()).to_string();
+
+    let output = tokio::process::Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .env("SYNCR_HOME", home.path())
+        .env_remove("SYNCR_CONFIG")
+        .env_remove("SYNCR_PROFILE")
+        .args(["copy", &ticket, &h.remote("nope")])
+        .arg(home.path().join("dest"))
+        .arg("--no-progress")
+        .output()
+        .await
+        .unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(4), "{}", stderr);
+    assert!(stderr.contains("NotFound"), "{}", stderr);
+    assert!(!home.path().join("dest").exists());
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn empty_directory_is_copied_as_one() {
+    let h = Harness::start().await;
+    std::fs::create_dir(h.served.join("empty")).unwrap();
+
+    let local = h.local.join("empty");
+    h.copy(&h.remote("empty"), &local).await.unwrap();
+
+    assert!(local.is_dir());
+    assert_eq!(std::fs::read_dir(&local).unwrap().count(), 0);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn empty_subdirectories_are_recreated() {
+    let h = Harness::start().await;
+    let tree = h.served.join("project");
+    std::fs::create_dir_all(tree.join("logs")).unwrap();
+    std::fs::create_dir_all(tree.join("build/tmp")).unwrap();
+    std::fs::write(tree.join("main.rs"), b"fn main() {}").unwrap();
+
+    let local = h.local.join("project");
+    h.copy(&h.remote("project"), &local).await.unwrap();
+
+    assert_eq!(
+        std::fs::read(local.join("main.rs")).unwrap(),
+        b"fn main() {}"
+    );
+    for dir in ["logs", "build", "build/tmp"] {
+        assert!(local.join(dir).is_dir(), "{} was not created", dir);
+    }
+    assert_eq!(std::fs::read_dir(local.join("logs")).unwrap().count(), 0);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn path_outside_allowed_dir_is_denied() {
+    let h = Harness::start().await;
+    let elsewhere = TempDir::new().unwrap();
+    std::fs::write(elsewhere.path().join("secret.txt"), b"secret").unwrap();
+    let remote = std::fs::canonicalize(elsewhere.path().join("secret.txt")).unwrap();
+
+    let err = h
+        .copy(&remote.to_string_lossy(), &h.local.join("secret.txt"))
+        .await
+        .unwrap_err();
+
+    assert_eq!(remote_code(&err), Some(ErrorCode::AccessDenied));
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn unknown_peer_is_reported_as_not_found() {
+    let h = Harness::start().await;
+    let bogus = SecretKey::generate(&mut rand::rng()).public();
+    let started = Instant::now();
+
+    let err = h
+        .client
+        .copy(
+            bogus,
+            "/anything",
+            h.local.join("anything"),
+            CopyOptions::default(),
+        )
+        .await
+        .unwrap_err();
+
+    assert!(
+        format!("{:#}", err).contains("not found via discovery"),
+        "{:#}",
+        err
+    );
+    // Well within the connect timeout
+    assert!(started.elapsed() < Duration::from_secs(15));
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn sync_registers_on_both_sides() {
+    let h = Harness::start().await;
+    std::fs::write(h.served.join("notes.txt"), b"v1").unwrap();
+
+    let target = h.local.join("notes.txt");
+    let remote = h.remote("notes.txt");
+    h.client
+        .sync(
+            &h.client_store,
+            h.server_id,
+            remote.clone(),
+            &target,
+            CopyOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(std::fs::read(&target).unwrap(), b"v1");
+    let client_syncs = h.client_store.list_syncs().unwrap();
+    let (local_root, configs) = &client_syncs[0];
+    assert_eq!(local_root, &target);
+    assert_eq!(configs[0].remote_path, remote);
+    assert_eq!(configs[0].last_hash, Some(*blake3::hash(b"v1").as_bytes()));
+
+    // The reverse registration is handled before the server closes the
+    // stream, so it's visible as soon as `sync` returns.
+    let server_syncs = h.server_store.list_syncs().unwrap();
+    assert_eq!(server_syncs.len(), 1);
+    assert_eq!(server_syncs[0].1[0].peer, h.client.id());
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn watch_only_sync_registers_without_copying() {
+    let h = Harness::start().await;
+    std::fs::write(h.served.join("notes.txt"), b"remote").unwrap();
+    let target = h.local.join("notes.txt");
+    std::fs::write(&target, b"local").unwrap();
+
+    let remote = h.remote("notes.txt");
+    let report = h
+        .client
+        .sync(
+            &h.client_store,
+            h.server_id,
+            remote.clone(),
+            &target,
+            CopyOptions {
+                watch_only: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(std::fs::read(&target).unwrap(), b"local");
+    assert_eq!(report.stats, syncr::SyncStats::default());
+    let client_syncs = h.client_store.list_syncs().unwrap();
+    let (local_root, configs) = &client_syncs[0];
+    assert_eq!(local_root, &target);
+    assert_eq!(configs[0].remote_path, remote);
+    assert_eq!(configs[0].last_synced, None);
+    assert_eq!(h.client_store.list_watches().unwrap(), vec![target.clone()]);
+    assert_eq!(h.server_store.list_syncs().unwrap().len(), 1);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn pull_returns_what_it_transferred() {
+    let h = Harness::start().await;
+    std::fs::create_dir(h.served.join("docs")).unwrap();
+    std::fs::write(h.served.join("docs/a.txt"), b"alpha").unwrap();
+    std::fs::write(h.served.join("docs/b.txt"), b"bravo").unwrap();
+    let addrs = StaticProvider::new();
+    addrs.add_endpoint_info(h.server_addr.clone());
+    let endpoint = loopback_endpoint(Some(addrs)).await;
+    h.server_store
+        .allow_peer(&h.served, endpoint.id(), AccessMode::Read)
+        .unwrap();
+
+    let target = h.local.join("docs");
+    let remote = h.remote("docs");
+    let stats = syncr::pull(
+        &endpoint,
+        h.server_id,
+        &remote,
+        &target,
+        syncr::PullOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(stats.files_transferred, 2);
+    assert_eq!(std::fs::read(target.join("b.txt")).unwrap(), b"bravo");
+
+    // Pulled again, nothing needs transferring.
+    let stats = syncr::pull(
+        &endpoint,
+        h.server_id,
+        &remote,
+        &target,
+        syncr::PullOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(stats.files_transferred, 0);
+    assert_eq!(stats.files_skipped, 2);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn sync_fails_fast_without_read_write_access() {
+    let h = Harness::start().await;
+    let shared = TempDir::new().unwrap();
+    let unshared = TempDir::new().unwrap();
+    let read_only = std::fs::canonicalize(shared.path()).unwrap();
+    h.server_store
+        .allow_peer(&read_only, h.client.id(), AccessMode::Read)
+        .unwrap();
+    let unshared = std::fs::canonicalize(unshared.path()).unwrap();
+
+    let target = h.local.join("notes");
+    for (remote, reason) in [(&read_only, "read-only"), (&unshared, "not shared")] {
+        let remote = remote.to_string_lossy().into_owned();
+        let err = h
+            .client
+            .sync(
+                &h.client_store,
+                h.server_id,
+                remote.clone(),
+                &target,
+                CopyOptions::default(),
+            )
+            .await
+            .unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(message.contains(reason), "{}", message);
+        let hint = format!("syncr allow {} {}", h.client.id(), remote);
+        assert!(message.contains(&hint), "{}", message);
+    }
+
+    // Nothing was copied or registered on either side.
+    assert!(!target.exists());
+    assert!(h.client_store.list_syncs().unwrap().is_empty());
+    assert!(h.server_store.list_syncs().unwrap().is_empty());
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn sync_pairs_share_one_connection() {
+    let mut config = test_config();
+    // A second connection to the server would be refused.
+    config.max_connections_per_peer = 1;
+    let h = Harness::start_configured(SUPPORTED_ALPNS, config).await;
+    for name in ["a", "b", "c"] {
+        std::fs::create_dir_all(h.served.join(name)).unwrap();
+        std::fs::write(h.served.join(name).join("file.txt"), name).unwrap();
+    }
+
+    // A pair that fails doesn't stop the ones after it.
+    let pairs = ["a", "missing", "b", "c"]
+        .iter()
+        .map(|name| (h.remote(name), h.local.join(name)))
+        .collect();
+    let reports = h
+        .client
+        .sync_pairs(&h.client_store, h.server_id, pairs, CopyOptions::default())
+        .await
+        .unwrap();
+
+    assert_eq!(reports.len(), 4);
+    assert!(reports[1].result.is_err());
+    for (report, name) in [(&reports[0], "a"), (&reports[2], "b"), (&reports[3], "c")] {
+        assert!(report.result.is_ok(), "{:?}", report);
+        assert_eq!(
+            std::fs::read(h.local.join(name).join("file.txt")).unwrap(),
+            name.as_bytes()
+        );
+    }
+    let client_syncs = h.client_store.list_syncs().unwrap();
+    let roots: Vec<_> = client_syncs.iter().map(|(root, _)| root.clone()).collect();
+    assert_eq!(
+        roots,
+        vec![h.local.join("a"), h.local.join("b"), h.local.join("c")]
+    );
+    assert_eq!(h.client_store.list_watches().unwrap(), roots);
+    assert_eq!(h.server_store.list_syncs().unwrap().len(), 3);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn sync_with_include_copies_only_that_subtree() {
+    let h = Harness::start().await;
+    std::fs::create_dir_all(h.served.join("docs/api")).unwrap();
+    std::fs::create_dir_all(h.served.join("src")).unwrap();
+    std::fs::write(h.served.join("docs/api/index.md"), b"api").unwrap();
+    std::fs::write(h.served.join("docs/readme.md"), b"docs").unwrap();
+    std::fs::write(h.served.join("src/main.rs"), b"fn main() {}").unwrap();
+    std::fs::write(h.served.join("top.txt"), b"top").unwrap();
+
+    let target = h.local.join("project");
+    let opts = CopyOptions {
+        include: vec!["docs/api".to_string()],
+        ..Default::default()
+    };
+    h.client
+        .sync(
+            &h.client_store,
+            h.server_id,
+            h.served.to_str().unwrap(),
+            &target,
+            opts,
+        )
+        .await
+        .unwrap();
+
+    // The directories leading to the included path are created, nothing else.
+    assert_eq!(
+        std::fs::read(target.join("docs/api/index.md")).unwrap(),
+        b"api"
+    );
+    assert!(!target.join("docs/readme.md").exists());
+    assert!(!target.join("src").exists());
+    assert!(!target.join("top.txt").exists());
+
+    let client_syncs = h.client_store.list_syncs().unwrap();
+    assert_eq!(client_syncs[0].1[0].include, vec!["docs/api".to_string()]);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn push_creates_files_on_the_server() {
+    let h = Harness::start().await;
+    let tree = h.local.join("outbox");
+    std::fs::create_dir_all(tree.join("nested")).unwrap();
+    std::fs::write(tree.join("a.txt"), b"a").unwrap();
+    std::fs::write(tree.join("nested/b.txt"), b"bb").unwrap();
+
+    let report = h
+        .client
+        .push(h.server_id, &tree, h.remote("inbox"), None)
+        .await
+        .unwrap();
+
+    assert_eq!(report.pushed.len(), 2);
+    assert_eq!(std::fs::read(h.served.join("inbox/a.txt")).unwrap(), b"a");
+    assert_eq!(
+        std::fs::read(h.served.join("inbox/nested/b.txt")).unwrap(),
+        b"bb"
+    );
+
+    // Pushing again finds nothing to write.
+    let report = h
+        .client
+        .push(h.server_id, &tree, h.remote("inbox"), None)
+        .await
+        .unwrap();
+    assert!(report.pushed.is_empty());
+    assert_eq!(report.unchanged, 2);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn push_updates_existing_file_with_delta() {
+    let h = Harness::start().await;
+    let old: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let mut new = old.clone();
+    new[100_000..100_010].copy_from_slice(b"0123456789");
+    std::fs::write(h.served.join("data.bin"), &old).unwrap();
+    let local = h.local.join("data.bin");
+    std::fs::write(&local, &new).unwrap();
+
+    let report = h
+        .client
+        .push(h.server_id, &local, h.remote("data.bin"), None)
+        .await
+        .unwrap();
+
+    assert_eq!(report.pushed, vec![h.remote("data.bin")]);
+    assert_eq!(std::fs::read(h.served.join("data.bin")).unwrap(), new);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn push_needs_write_access() {
+    let h = Harness::start().await;
+    let read_only_dir = TempDir::new().unwrap();
+    let read_only = std::fs::canonicalize(read_only_dir.path()).unwrap();
+    h.server_store
+        .allow_peer(&read_only, h.client.id(), AccessMode::Read)
+        .unwrap();
+    let local = h.local.join("note.txt");
+    std::fs::write(&local, b"not welcome").unwrap();
+
+    let remote = read_only.join("note.txt").to_string_lossy().into_owned();
+    let err = h
+        .client
+        .push(h.server_id, &local, remote, None)
+        .await
+        .unwrap_err();
+
+    assert_eq!(remote_code(&err), Some(ErrorCode::AccessDenied));
+    assert!(!read_only.join("note.txt").exists());
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn start_sync_needs_write_access() {
+    let h = Harness::start().await;
+    std::fs::write(h.served.join("notes.txt"), b"notes").unwrap();
+    // The raw session's peer may only read the served directory.
+    let (_client, _connection, mut send, mut recv) = raw_session(&h).await;
+
+    let request = Message::StartSync {
+        path: h.remote("notes.txt"),
+    };
+    write_message(&mut send, &request).await.unwrap();
+
+    match read_message(&mut recv, Duration::from_secs(10))
+        .await
+        .unwrap()
+    {
+        Message::Error { code, .. } => assert_eq!(code, ErrorCode::AccessDenied),
+        msg => panic!("Unexpected message: {:?}", msg),
+    }
+    assert!(h.server_store.list_syncs().unwrap().is_empty());
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn every_request_on_an_unshared_path_is_access_denied() {
+    let h = Harness::start().await;
+    let unshared_dir = TempDir::new().unwrap();
+    let unshared = std::fs::canonicalize(unshared_dir.path()).unwrap();
+    std::fs::write(unshared.join("secret.txt"), b"secret").unwrap();
+    let path = unshared.join("secret.txt").to_string_lossy().into_owned();
+    let addrs = StaticProvider::new();
+    addrs.add_endpoint_info(h.server_addr.clone());
+    let client = loopback_endpoint(Some(addrs)).await;
+    h.server_store
+        .allow_peer(&h.served, client.id(), AccessMode::ReadWrite)
+        .unwrap();
+    let connection = client.connect(h.server_id, ALPN).await.unwrap();
+    let (mut send, mut recv) = connection.open_bi().await.unwrap();
+    let timeout = Duration::from_secs(10);
+    let handshake = Message::Handshake {
+        version: 8,
+        capabilities: vec![CAP_CHUNKS.to_string()],
+    };
+    write_message(&mut send, &handshake).await.unwrap();
+    read_message(&mut recv, timeout).await.unwrap();
+
+    let requests = [
+        list_request(path.clone()),
+        Message::ManifestRequest {
+            path: path.clone(),
+            files: Vec::new(),
+            follow_symlinks: false,
+        },
+        Message::FileRequest {
+            path: path.clone(),
+            offset: 0,
+        },
+        Message::FileSignature {
+            path: path.clone(),
+            signature: Vec::new(),
+        },
+        Message::FileSignatureRange {
+            path: path.clone(),
+            offset: 0,
+            len: 6,
+            signature: Vec::new(),
+        },
+        Message::ChunkRequest { path: path.clone() },
+        Message::StartSync { path: path.clone() },
+        Message::FilePush {
+            path: path.clone(),
+            hash: [0; 32],
+        },
+    ];
+    for request in requests {
+        write_message(&mut send, &request).await.unwrap();
+        match read_message(&mut recv, timeout).await.unwrap() {
+            Message::Error { code, .. } => {
+                assert_eq!(code, ErrorCode::AccessDenied, "{:?}", request)
+            }
+            msg => panic!("Unexpected answer to {:?}: {:?}", request, msg),
+        }
+    }
+    assert_eq!(
+        std::fs::read(unshared.join("secret.txt")).unwrap(),
+        b"secret"
+    );
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn file_replaced_by_directory() {
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir(&tree).unwrap();
+    std::fs::write(tree.join("item"), b"was a file").unwrap();
+
+    let target = h.local.join("tree");
+    h.copy(&h.remote("tree"), &target).await.unwrap();
+    assert!(target.join("item").is_file());
+
+    std::fs::remove_file(tree.join("item")).unwrap();
+    std::fs::create_dir(tree.join("item")).unwrap();
+    std::fs::write(tree.join("item/inner.txt"), b"now a dir").unwrap();
+    h.copy(&h.remote("tree"), &target).await.unwrap();
+
+    assert!(target.join("item").is_dir());
+    assert_eq!(
+        std::fs::read(target.join("item/inner.txt")).unwrap(),
+        b"now a dir"
+    );
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn directory_replaced_by_file() {
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir_all(tree.join("item")).unwrap();
+
+    let target = h.local.join("tree");
+    h.copy(&h.remote("tree"), &target).await.unwrap();
+    assert!(target.join("item").is_dir());
+
+    std::fs::remove_dir(tree.join("item")).unwrap();
+    std::fs::write(tree.join("item"), b"now a file").unwrap();
+    h.copy(&h.remote("tree"), &target).await.unwrap();
+
+    assert_eq!(std::fs::read(target.join("item")).unwrap(), b"now a file");
+    h.stop().await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn symlinks_are_recreated_not_downloaded() {
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir(&tree).unwrap();
+    std::fs::write(tree.join("a.txt"), b"target").unwrap();
+    std::os::unix::fs::symlink("a.txt", tree.join("link")).unwrap();
+
+    let target = h.local.join("tree");
+    h.copy(&h.remote("tree"), &target).await.unwrap();
+
+    let link = target.join("link");
+    assert!(std::fs::symlink_metadata(&link)
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    assert_eq!(std::fs::read_link(&link).unwrap(), Path::new("a.txt"));
+    h.stop().await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn symlinks_leaving_the_root_are_skipped() {
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir(&tree).unwrap();
+    std::fs::write(tree.join("a.txt"), b"inside").unwrap();
+    std::os::unix::fs::symlink("../../elsewhere", tree.join("escape")).unwrap();
+    std::os::unix::fs::symlink("/etc/passwd", tree.join("absolute")).unwrap();
+
+    let target = h.local.join("tree");
+    h.copy(&h.remote("tree"), &target).await.unwrap();
+
+    assert!(target.join("a.txt").is_file());
+    assert!(std::fs::symlink_metadata(target.join("escape")).is_err());
+    assert!(std::fs::symlink_metadata(target.join("absolute")).is_err());
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn listed_entries_leading_out_of_the_root_are_skipped() {
+    // A peer whose listing climbs out of the copied directory, which hidden
+    // files being included doesn't let through.
+    let server = loopback_endpoint(None).await;
+    let addrs = StaticProvider::new();
+    addrs.add_endpoint_info(loopback_addr(&server));
+    let client = SyncClient::from_endpoint(loopback_endpoint(Some(addrs)).await, test_config());
+
+    let server_id = server.id();
+    let peer = tokio::spawn(async move {
+        let connection = server
+            .accept()
+            .await
+            .unwrap()
+            .accept()
+            .unwrap()
+            .await
+            .unwrap();
+        let entry = |path: &str, file_type| FileMetadata {
+            path: path.to_string(),
+            len: if file_type == FileType::File { 5 } else { 0 },
+            modified: 0,
+            file_type,
+            link_target: None,
+            hash: None,
+            mode: None,
+        };
+        loop {
+            let Ok((mut send, mut recv)) = connection.accept_bi().await else {
+                break;
+            };
+            read_message(&mut recv, Duration::from_secs(10))
+                .await
+                .unwrap();
+            write_message(&mut send, &Message::LegacyHandshake { version: 1 })
+                .await
+                .unwrap();
+            let reply = match read_message(&mut recv, Duration::from_secs(10)).await {
+                Ok(Message::ListRequest { .. }) => Message::ListResponse {
+                    files: vec![
+                        entry("/shared", FileType::Dir),
+                        entry("/shared/..", FileType::Dir),
+                        entry("/shared/../escaped.txt", FileType::File),
+                        entry("/shared/sub/../../escaped.txt", FileType::File),
+                        entry("/shared/%2E%2E/escaped.txt", FileType::File),
+                        entry("/shared/inside.txt", FileType::File),
+                    ],
+                    is_last: true,
+                },
+                Ok(Message::FileRequest { path, .. }) => Message::FileData {
+                    path,
+                    data: b"owned".to_vec(),
+                    offset: 0,
+                    is_last: true,
+                    hash: Some(*blake3::hash(b"owned").as_bytes()),
+                },
+                _ => break,
+            };
+            write_message(&mut send, &reply).await.unwrap();
+            send.finish().unwrap();
+        }
+    });
+
+    let local = TempDir::new().unwrap();
+    let root = local.path().join("root");
+    client
+        .copy(
+            server_id,
+            "/shared",
+            &root,
+            CopyOptions {
+                include_hidden: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(std::fs::read(root.join("inside.txt")).unwrap(), b"owned");
+    assert!(!local.path().join("escaped.txt").exists());
+    assert!(!root.join("sub").exists());
+    peer.abort();
+}
+
+#[tokio::test]
+async fn deletions_of_paths_not_in_the_manifest_are_ignored() {
+    // A peer that answers a manifest by deleting what it didn't list,
+    // including a file outside the copied directory.
+    let server = loopback_endpoint(None).await;
+    let addrs = StaticProvider::new();
+    addrs.add_endpoint_info(loopback_addr(&server));
+    let client = SyncClient::from_endpoint(loopback_endpoint(Some(addrs)).await, test_config());
+
+    let server_id = server.id();
+    let peer = tokio::spawn(async move {
+        let connection = server
+            .accept()
+            .await
+            .unwrap()
+            .accept()
+            .unwrap()
+            .await
+            .unwrap();
+        let entry = |path: &str, file_type| FileMetadata {
+            path: path.to_string(),
+            len: 0,
+            modified: 0,
+            file_type,
+            link_target: None,
+            hash: None,
+            mode: None,
+        };
+        let Ok((mut send, mut recv)) = connection.accept_bi().await else {
+            return;
+        };
+        let timeout = Duration::from_secs(10);
+        read_message(&mut recv, timeout).await.unwrap();
+        let handshake = Message::Handshake {
+            version: 8,
+            capabilities: Vec::new(),
+        };
+        write_message(&mut send, &handshake).await.unwrap();
+        let Ok(Message::ManifestRequest { .. }) = read_message(&mut recv, timeout).await else {
+            return;
+        };
+        let doomed = [
+            ("/shared", FileType::Dir, ManifestAction::Create),
+            (
+                "/shared/../victim.txt",
+                FileType::File,
+                ManifestAction::Delete,
+            ),
+            ("/shared/.hidden", FileType::File, ManifestAction::Delete),
+            ("/shared/stale.txt", FileType::Dir, ManifestAction::Delete),
+        ];
+        let reply = Message::ManifestResponse {
+            entries: doomed
+                .into_iter()
+                .map(|(path, file_type, action)| ManifestEntry {
+                    file: entry(path, file_type),
+                    action,
+                })
+                .collect(),
+            is_last: true,
+        };
+        write_message(&mut send, &reply).await.unwrap();
+        send.finish().unwrap();
+        let _ = connection.closed().await;
+    });
+
+    let local = TempDir::new().unwrap();
+    let root = local.path().join("root");
+    std::fs::create_dir(&root).unwrap();
+    std::fs::write(root.join("stale.txt"), "stale").unwrap();
+    std::fs::write(root.join("kept.txt"), "kept").unwrap();
+    std::fs::write(root.join("also kept.txt"), "kept").unwrap();
+    std::fs::write(local.path().join("victim.txt"), "victim").unwrap();
+    let report = client
+        .copy(
+            server_id,
+            "/shared",
+            &root,
+            CopyOptions {
+                manifest: true,
+                delete: true,
+                include_hidden: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(report.deleted, vec![root.join("stale.txt")]);
+    assert!(local.path().join("victim.txt").is_file());
+    peer.abort();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn follow_symlinks_copies_link_targets() {
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir(&tree).unwrap();
+    std::fs::write(tree.join("a.txt"), b"target").unwrap();
+    std::os::unix::fs::symlink("a.txt", tree.join("link")).unwrap();
+
+    let target = h.local.join("tree");
+    let opts = CopyOptions {
+        follow_symlinks: true,
+        ..Default::default()
+    };
+    h.client
+        .copy(h.server_id, h.remote("tree"), &target, opts)
+        .await
+        .unwrap();
+
+    let link = target.join("link");
+    assert!(!std::fs::symlink_metadata(&link)
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    assert_eq!(std::fs::read(&link).unwrap(), b"target");
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn resync_restores_corrupted_file() {
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir(&tree).unwrap();
+    std::fs::write(tree.join("good.txt"), b"untouched").unwrap();
+    std::fs::write(tree.join("data.txt"), b"remote contents").unwrap();
+
+    let target = h.local.join("tree");
+    h.client
+        .sync(
+            &h.client_store,
+            h.server_id,
+            h.remote("tree"),
+            &target,
+            CopyOptions::default(),
+        )
+        .await
+        .unwrap();
+    std::fs::write(target.join("data.txt"), b"REMOTE CONTENTS").unwrap();
+
+    let reports = h
+        .client
+        .resync(
+            &h.client_store,
+            Some(target.clone()),
+            CopyOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].changed, vec![target.join("data.txt")]);
+    assert_eq!(
+        std::fs::read(target.join("data.txt")).unwrap(),
+        b"remote contents"
+    );
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn syncs_and_resyncs_are_logged_in_order() {
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir(&tree).unwrap();
+    std::fs::write(tree.join("a.txt"), b"first").unwrap();
+
+    let target = h.local.join("tree");
+    h.client
+        .sync(
+            &h.client_store,
+            h.server_id,
+            h.remote("tree"),
+            &target,
+            CopyOptions::default(),
+        )
+        .await
+        .unwrap();
+    std::fs::write(tree.join("b.txt"), b"second").unwrap();
+    h.client
+        .resync(
+            &h.client_store,
+            Some(target.clone()),
+            CopyOptions::default(),
+        )
+        .await
+        .unwrap();
+    std::fs::remove_dir_all(&tree).unwrap();
+    h.client
+        .resync(
+            &h.client_store,
+            Some(target.clone()),
+            CopyOptions::default(),
+        )
+        .await
+        .unwrap_err();
+
+    let log = h.client_store.sync_log(&target).unwrap();
+    assert_eq!(log.len(), 3);
+    assert!(log
+        .iter()
+        .all(|e| e.peer == h.server_id && e.direction == Direction::Pull));
+    assert_eq!(log[0].outcome, SyncOutcome::Completed);
+    assert_eq!(
+        log[0].changed,
+        vec![target.join("a.txt").display().to_string()]
+    );
+    assert!(log[0].bytes > 0);
+    assert_eq!(
+        log[1].changed,
+        vec![target.join("b.txt").display().to_string()]
+    );
+    assert!(matches!(log[2].outcome, SyncOutcome::Failed(_)));
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn copies_listing_split_across_chunks() {
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    // Directories need no transfer, so they make a cheap large listing.
+    let dirs = LIST_CHUNK_LEN * 2 + 5;
+    for i in 0..dirs {
+        std::fs::create_dir_all(tree.join(format!("d{i}"))).unwrap();
+    }
+    std::fs::write(tree.join("d0/first.txt"), b"first").unwrap();
+    std::fs::write(tree.join(format!("d{}/last.txt", dirs - 1)), b"last").unwrap();
+
+    let target = h.local.join("tree");
+    h.copy(&h.remote("tree"), &target).await.unwrap();
+
+    let copied = std::fs::read_dir(&target).unwrap().count();
+    assert_eq!(copied, dirs);
+    assert_eq!(
+        std::fs::read(target.join("d0/first.txt")).unwrap(),
+        b"first"
+    );
+    assert_eq!(
+        std::fs::read(target.join(format!("d{}/last.txt", dirs - 1))).unwrap(),
+        b"last"
+    );
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn verify_reports_only_the_differing_file() {
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir_all(tree.join("nested")).unwrap();
+    std::fs::write(tree.join("same.txt"), b"same").unwrap();
+    std::fs::write(tree.join("nested/changed.txt"), b"remote").unwrap();
+
+    let target = h.local.join("tree");
+    h.copy(&h.remote("tree"), &target).await.unwrap();
+    std::fs::write(target.join("nested/changed.txt"), b"local edit").unwrap();
+
+    let report = h
+        .client
+        .verify(h.server_id, h.remote("tree"), &target)
+        .await
+        .unwrap();
+
+    assert!(!report.is_in_sync());
+    let mismatches: Vec<_> = report.mismatches().collect();
+    assert_eq!(
+        mismatches,
+        vec![(&target.join("nested/changed.txt"), FileStatus::Differs)]
+    );
+    assert_eq!(
+        report.files.get(&target.join("same.txt")),
+        Some(&FileStatus::InSync)
+    );
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn reuses_signature_of_unchanged_local_file() {
+    let h = Harness::start().await;
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i % 253) as u8).collect();
+    let edited = |at: usize, with: &[u8]| {
+        let mut edited = data.clone();
+        edited[at..at + with.len()].copy_from_slice(with);
+        edited
+    };
+    let first = edited(10_000, b"one!");
+    std::fs::write(h.served.join("data.bin"), &first).unwrap();
+    let target = h.local.join("data.bin");
+    std::fs::write(&target, &data).unwrap();
+
+    let copy = |base_hash: Option<[u8; 32]>| {
+        let opts = CopyOptions {
+            store: Some(h.client_store.clone()),
+            base_hash,
+            ..Default::default()
+        };
+        let (h, target) = (&h, &target);
+        async move {
+            h.client
+                .copy(h.server_id, h.remote("data.bin"), target, opts)
+                .await
+                .unwrap()
+        }
+    };
+
+    // While the local file counts as changed since the last sync, incoming
+    // versions go to a conflict file and the local one stays as it is.
+    let diverged = Some([0u8; 32]);
+    assert_eq!(copy(diverged).await.signatures_reused, 0);
+    assert_eq!(copy(diverged).await.signatures_reused, 1);
+    assert_eq!(std::fs::read(&target).unwrap(), data);
+
+    // Patching the file drops its cached signature.
+    assert_eq!(copy(None).await.signatures_reused, 1);
+    assert_eq!(std::fs::read(&target).unwrap(), first);
+    let second = edited(50_000, b"two!");
+    std::fs::write(h.served.join("data.bin"), &second).unwrap();
+    assert_eq!(copy(None).await.signatures_reused, 0);
+    assert_eq!(std::fs::read(&target).unwrap(), second);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn conflict_policy_decides_which_version_is_kept() {
+    let h = Harness::start().await;
+    let base = *blake3::hash(b"original").as_bytes();
+    let set_modified = |path: &Path, secs: u64| {
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(std::time::UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    };
+    let now = sync_utils::unix_timestamp();
+    let (older, newer) = (now - 3_600, now - 60);
+    // Policy, remote and local modification times, and which side is kept.
+    let cases = [
+        (ConflictPolicy::SourceWins, older, newer, "remote"),
+        (ConflictPolicy::NewerWins, newer, older, "remote"),
+        (ConflictPolicy::NewerWins, older, newer, "local"),
+        (ConflictPolicy::Skip, newer, older, "local"),
+        (ConflictPolicy::ConflictCopy, newer, older, "local"),
+    ];
+    for (i, (policy, remote_modified, local_modified, kept)) in cases.into_iter().enumerate() {
+        let name = format!("notes{}.txt", i);
+        let served = h.served.join(&name);
+        std::fs::write(&served, "remote").unwrap();
+        set_modified(&served, remote_modified);
+        let target = h.local.join(&name);
+        std::fs::write(&target, "local").unwrap();
+        set_modified(&target, local_modified);
+
+        let opts = CopyOptions {
+            base_hash: Some(base),
+            on_conflict: policy,
+            ..Default::default()
+        };
+        h.client
+            .copy(h.server_id, h.remote(&name), &target, opts)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&target).unwrap(),
+            kept,
+            "{}",
+            policy
+        );
+        let conflicts: Vec<_> = std::fs::read_dir(&h.local)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                let file_name = path.file_name().unwrap().to_string_lossy();
+                file_name.starts_with(&format!("{}.conflict-", name))
+            })
+            .collect();
+        if policy == ConflictPolicy::ConflictCopy {
+            assert_eq!(conflicts.len(), 1);
+            assert_eq!(std::fs::read_to_string(&conflicts[0]).unwrap(), "remote");
+        } else {
+            assert!(conflicts.is_empty(), "{}: {:?}", policy, conflicts);
+        }
+    }
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn edits_on_both_sides_keep_the_local_one_and_a_conflict_copy() {
+    let h = Harness::start().await;
+    let base = *blake3::hash(b"original").as_bytes();
+    let conflict = h
+        .local
+        .join(format!("notes.txt.conflict-{}", h.server_id.fmt_short()));
+    let events = SyncEvent::channel();
+    let mut received = events.subscribe();
+    let copy = |target: PathBuf| {
+        let opts = CopyOptions {
+            base_hash: Some(base),
+            events: Some(events.clone()),
+            ..Default::default()
+        };
+        let h = &h;
+        async move {
+            h.client
+                .copy(h.server_id, h.remote("notes.txt"), target, opts)
+                .await
+                .unwrap()
+        }
+    };
+    std::fs::write(h.served.join("notes.txt"), "remote edit").unwrap();
+
+    // Only the remote side changed: nothing to keep.
+    let unchanged = h.local.join("unchanged.txt");
+    std::fs::write(&unchanged, "original").unwrap();
+    let report = copy(unchanged.clone()).await;
+    assert_eq!(std::fs::read_to_string(&unchanged).unwrap(), "remote edit");
+    assert_eq!(report.stats.conflicts, 0);
+
+    // Both changed: the local edit stays and the remote one goes beside it.
+    let target = h.local.join("notes.txt");
+    std::fs::write(&target, "local edit").unwrap();
+    let report = copy(target.clone()).await;
+    assert_eq!(std::fs::read_to_string(&target).unwrap(), "local edit");
+    assert_eq!(std::fs::read_to_string(&conflict).unwrap(), "remote edit");
+    assert_eq!(report.stats.conflicts, 1);
+    let mut detected = Vec::new();
+    while let Ok(event) = received.try_recv() {
+        if let SyncEvent::ConflictDetected { path, incoming } = event {
+            detected.push((path, incoming));
+        }
+    }
+    assert_eq!(detected, vec![(target, conflict)]);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn checksum_ignores_signature_cached_for_same_size_and_mtime() {
+    let h = Harness::start().await;
+    // Random, so every block of the signature is found at one place only.
+    let mut data = vec![0; 100_000];
+    blake3::Hasher::new().finalize_xof().fill(&mut data);
+    let edited = |at: usize, with: &[u8]| {
+        let mut edited = data.clone();
+        edited[at..at + with.len()].copy_from_slice(with);
+        edited
+    };
+    let remote = edited(10_000, b"one!");
+    std::fs::write(h.served.join("data.bin"), &remote).unwrap();
+    let target = h.local.join("data.bin");
+
+    let copy = |base_hash: Option<[u8; 32]>, checksum: bool| {
+        let opts = CopyOptions {
+            store: Some(h.client_store.clone()),
+            base_hash,
+            checksum,
+            ..Default::default()
+        };
+        let (h, target) = (&h, &target);
+        async move {
+            h.client
+                .copy(h.server_id, h.remote("data.bin"), target, opts)
+                .await
+                .unwrap()
+        }
+    };
+    for checksum in [false, true] {
+        // Cache the signature of `data`, then change the local file without
+        // changing its size or modification time.
+        std::fs::write(&target, &data).unwrap();
+        let modified = std::fs::metadata(&target).unwrap().modified().unwrap();
+        copy(Some([0u8; 32]), false).await;
+        std::fs::write(&target, edited(90_000, b"two!")).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&target)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let report = copy(None, checksum).await;
+        assert_eq!(std::fs::read(&target).unwrap(), remote);
+        let sent = report.stats.bytes_transferred;
+        if checksum {
+            assert_eq!(report.signatures_reused, 0);
+            assert!(sent < data.len() as u64 / 2, "{}", sent);
+        } else {
+            // The stale signature yields a patch that doesn't apply, so the
+            // file is downloaded again in full.
+            assert_eq!(report.signatures_reused, 1);
+            assert!(sent >= data.len() as u64, "{}", sent);
+        }
+    }
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn delta_that_does_not_apply_falls_back_to_a_full_download() {
+    let h = Harness::start().await;
+    let mut remote = vec![0; 100_000];
+    blake3::Hasher::new().finalize_xof().fill(&mut remote);
+    std::fs::write(h.served.join("data.bin"), &remote).unwrap();
+    // Garbage of the same length locally, with the signature of the remote
+    // file cached for it, so the delta refers to blocks it doesn't have.
+    let target = h.local.join("data.bin");
+    std::fs::write(&target, vec![9u8; remote.len()]).unwrap();
+    let modified = std::fs::metadata(&target).unwrap().modified().unwrap();
+    let stamp = SignatureStamp {
+        modified_nanos: modified
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64,
+        len: remote.len() as u64,
+        block_size: None,
+    };
+    let opts = CopyOptions {
+        store: Some(h.client_store.clone()),
+        ..Default::default()
+    };
+    let signature = sync_utils::calculate_signature(&remote, None, opts.strong_hash_size).unwrap();
+    h.client_store
+        .cache_signature(&target, stamp, &signature)
+        .unwrap();
+
+    let report = h
+        .client
+        .copy(h.server_id, h.remote("data.bin"), &target, opts)
+        .await
+        .unwrap();
+
+    assert_eq!(report.signatures_reused, 1);
+    assert_eq!(std::fs::read(&target).unwrap(), remote);
+    assert!(
+        report.stats.bytes_transferred >= remote.len() as u64,
+        "{}",
+        report.stats.bytes_transferred
+    );
+    assert_eq!(report.stats.files_transferred, 1);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn stats_count_transferred_and_skipped_files() {
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir_all(&tree).unwrap();
+    let big: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let mut patched = big.clone();
+    patched[1_000..1_004].copy_from_slice(b"edit");
+    std::fs::write(tree.join("same.txt"), b"unchanged").unwrap();
+    std::fs::write(tree.join("new.txt"), b"brand new").unwrap();
+    std::fs::write(tree.join("big.bin"), &patched).unwrap();
+
+    let target = h.local.join("tree");
+    std::fs::create_dir_all(&target).unwrap();
+    std::fs::write(target.join("same.txt"), b"unchanged").unwrap();
+    std::fs::write(target.join("big.bin"), &big).unwrap();
+
+    let report = h
+        .client
+        .copy(
+            h.server_id,
+            h.remote("tree"),
+            &target,
+            CopyOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    let stats = report.stats;
+    assert_eq!(stats.files_transferred, 2);
+    assert_eq!(stats.files_skipped, 1);
+    // The new file comes whole and the patched one as a small delta.
+    assert!(stats.bytes_transferred >= b"brand new".len() as u64);
+    assert!(stats.bytes_transferred < 20_000, "{:?}", stats);
+    assert!(stats.bytes_saved > 180_000, "{:?}", stats);
+    assert_eq!(std::fs::read(target.join("big.bin")).unwrap(), patched);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn manifest_copy_transfers_only_what_differs() {
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir_all(&tree).unwrap();
+    let big: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let mut patched = big.clone();
+    patched[1_000..1_004].copy_from_slice(b"edit");
+    std::fs::write(tree.join("same.txt"), b"unchanged").unwrap();
+    std::fs::write(tree.join("new.txt"), b"brand new").unwrap();
+    std::fs::write(tree.join("small.txt"), b"remote").unwrap();
+    std::fs::write(tree.join("big.bin"), &patched).unwrap();
+
+    let target = h.local.join("tree");
+    std::fs::create_dir_all(&target).unwrap();
+    std::fs::write(target.join("same.txt"), b"unchanged").unwrap();
+    std::fs::write(target.join("small.txt"), b"local").unwrap();
+    std::fs::write(target.join("big.bin"), &big).unwrap();
+    std::fs::write(target.join("extra.txt"), b"only here").unwrap();
+
+    let opts = CopyOptions {
+        manifest: true,
+        ..Default::default()
+    };
+    let report = h
+        .client
+        .copy(h.server_id, h.remote("tree"), &target, opts)
+        .await
+        .unwrap();
+
+    let stats = report.stats;
+    assert_eq!(stats.files_transferred, 3, "{:?}", stats);
+    assert_eq!(stats.files_skipped, 1, "{:?}", stats);
+    assert!(stats.bytes_saved > 180_000, "{:?}", stats);
+    assert_eq!(std::fs::read(target.join("big.bin")).unwrap(), patched);
+    assert_eq!(std::fs::read(target.join("new.txt")).unwrap(), b"brand new");
+    assert_eq!(std::fs::read(target.join("small.txt")).unwrap(), b"remote");
+    // A copy never deletes, whatever the server says.
+    assert_eq!(
+        std::fs::read(target.join("extra.txt")).unwrap(),
+        b"only here"
+    );
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn small_files_and_poor_deltas_download_in_full() {
+    async fn copy(h: &Harness, name: &str) -> syncr::SyncStats {
+        let target = h.local.join(name);
+        h.client
+            .copy(h.server_id, h.remote(name), &target, CopyOptions::default())
+            .await
+            .unwrap()
+            .stats
+    }
+    let big: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let mut patched = big.clone();
+    patched[1_000..1_004].copy_from_slice(b"edit");
+    let setup = |h: &Harness| {
+        std::fs::write(h.served.join("tiny.txt"), b"remote version").unwrap();
+        std::fs::write(h.local.join("tiny.txt"), b"local version").unwrap();
+        std::fs::write(h.served.join("big.bin"), &patched).unwrap();
+        std::fs::write(h.local.join("big.bin"), &big).unwrap();
+    };
+
+    let h = Harness::start().await;
+    setup(&h);
+    // Below delta_min_len the file is fetched whole, above it patched.
+    let stats = copy(&h, "tiny.txt").await;
+    assert_eq!(stats.bytes_saved, 0, "{:?}", stats);
+    assert_eq!(stats.bytes_transferred, b"remote version".len() as u64);
+    let stats = copy(&h, "big.bin").await;
+    assert!(stats.bytes_saved > 180_000, "{:?}", stats);
+    assert_eq!(std::fs::read(h.local.join("big.bin")).unwrap(), patched);
+    h.stop().await;
+
+    // A server wanting deltas to save everything sends none.
+    let mut config = test_config();
+    config.delta_min_savings = 1.0;
+    let h = Harness::start_configured(SUPPORTED_ALPNS, config).await;
+    setup(&h);
+    let stats = copy(&h, "big.bin").await;
+    assert_eq!(stats.files_transferred, 1, "{:?}", stats);
+    assert_eq!(stats.bytes_saved, 0, "{:?}", stats);
+    assert!(
+        stats.bytes_transferred >= patched.len() as u64,
+        "{:?}",
+        stats
+    );
+    assert_eq!(std::fs::read(h.local.join("big.bin")).unwrap(), patched);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn hidden_files_are_only_copied_when_included() {
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir_all(tree.join(".cache")).unwrap();
+    std::fs::write(tree.join("visible.txt"), b"visible").unwrap();
+    std::fs::write(tree.join(".hidden"), b"hidden").unwrap();
+    std::fs::write(tree.join(".cache/data"), b"cached").unwrap();
+
+    for include_hidden in [false, true] {
+        let target = h.local.join(format!("tree-{}", include_hidden));
+        let opts = CopyOptions {
+            include_hidden,
+            ..Default::default()
+        };
+        h.client
+            .copy(h.server_id, h.remote("tree"), &target, opts)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read(target.join("visible.txt")).unwrap(),
+            b"visible"
+        );
+        assert_eq!(target.join(".hidden").exists(), include_hidden);
+        assert_eq!(target.join(".cache/data").exists(), include_hidden);
+    }
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn deleting_more_than_max_delete_needs_force() {
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir_all(&tree).unwrap();
+    std::fs::write(tree.join("kept.txt"), b"kept").unwrap();
+
+    let target = h.local.join("tree");
+    std::fs::create_dir_all(target.join("sub")).unwrap();
+    std::fs::write(target.join("kept.txt"), b"kept").unwrap();
+    for name in ["a.txt", "b.txt", "sub/c.txt"] {
+        std::fs::write(target.join(name), b"gone remotely").unwrap();
+    }
+
+    let opts = |force| CopyOptions {
+        manifest: true,
+        delete: true,
+        max_delete: 0.5,
+        force,
+        ..Default::default()
+    };
+    let err = h
+        .client
+        .copy(h.server_id, h.remote("tree"), &target, opts(false))
+        .await
+        .unwrap_err();
+    assert!(
+        format!("{:#}", err).contains("Refusing to delete 3 of 4 local files"),
+        "{:#}",
+        err
+    );
+    assert!(target.join("sub/c.txt").exists());
+
+    let report = h
+        .client
+        .copy(h.server_id, h.remote("tree"), &target, opts(true))
+        .await
+        .unwrap();
+    assert_eq!(
+        report.deleted,
+        vec![
+            target.join("a.txt"),
+            target.join("b.txt"),
+            target.join("sub"),
+            target.join("sub/c.txt"),
+        ]
+    );
+    assert!(!target.join("sub").exists());
+    assert_eq!(std::fs::read(target.join("kept.txt")).unwrap(), b"kept");
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn prune_empty_dirs_removes_only_what_deletions_emptied() {
+    let h = Harness::start().await;
+    std::fs::create_dir_all(h.served.join("tree")).unwrap();
+
+    let target = h.local.join("tree");
+    for prune_empty_dirs in [false, true] {
+        std::fs::create_dir_all(target.join("sub/deeper")).unwrap();
+        std::fs::create_dir_all(target.join("empty")).unwrap();
+        std::fs::write(target.join("sub/deeper/c.txt"), b"gone remotely").unwrap();
+        let opts = CopyOptions {
+            manifest: true,
+            delete: true,
+            force: true,
+            // Only the file is selected, so the directories holding it
+            // aren't deleted along with it.
+            include: vec!["sub/deeper/c.txt".to_string()],
+            prune_empty_dirs,
+            ..Default::default()
+        };
+        h.client
+            .copy(h.server_id, h.remote("tree"), &target, opts)
+            .await
+            .unwrap();
+
+        assert!(!target.join("sub/deeper/c.txt").exists());
+        assert_eq!(target.join("sub").exists(), !prune_empty_dirs);
+        // No deletion emptied it, and the root stays whatever happens.
+        assert!(target.join("empty").is_dir());
+        assert!(target.is_dir());
+    }
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn manifest_copy_falls_back_to_a_listing_on_older_peers() {
+    let h = Harness::start_speaking(&[ALPN_V3]).await;
+    std::fs::create_dir_all(h.served.join("tree")).unwrap();
+    std::fs::write(h.served.join("tree/a.txt"), b"a").unwrap();
+
+    let target = h.local.join("tree");
+    let opts = CopyOptions {
+        manifest: true,
+        ..Default::default()
+    };
+    h.client
+        .copy(h.server_id, h.remote("tree"), &target, opts)
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read(target.join("a.txt")).unwrap(), b"a");
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn large_file_is_patched_one_range_at_a_time() {
+    let h = Harness::start().await;
+    let old: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+    let mut new = old.clone();
+    new[10_000..10_004].copy_from_slice(b"edit");
+    new[700_000..700_004].copy_from_slice(b"more");
+    // Growing the file adds ranges the local file has nothing for.
+    new.extend_from_slice(&[7; 150_000]);
+    std::fs::write(h.served.join("data.bin"), &new).unwrap();
+    let target = h.local.join("data.bin");
+    std::fs::write(&target, &old).unwrap();
+
+    let opts = CopyOptions {
+        segment_len: 64 * 1024,
+        ..Default::default()
+    };
+    let report = h
+        .client
+        .copy(h.server_id, h.remote("data.bin"), &target, opts)
+        .await
+        .unwrap();
+
+    assert_eq!(std::fs::read(&target).unwrap(), new);
+    let stats = report.stats;
+    assert_eq!(stats.files_transferred, 1, "{:?}", stats);
+    assert!(stats.bytes_saved > 900_000, "{:?}", stats);
+    assert!(!h.local.join("data.bin.syncr-partial").exists());
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn dedup_sends_a_block_shared_by_two_files_once() {
+    let h = Harness::start().await;
+    let mut shared = vec![0; 2 * 1024 * 1024];
+    blake3::Hasher::new().finalize_xof().fill(&mut shared);
+    let tree = h.served.join("tree");
+    std::fs::create_dir(&tree).unwrap();
+    let first = [b"first header".as_slice(), &shared].concat();
+    let second = [&shared, b"second trailer".as_slice()].concat();
+    std::fs::write(tree.join("a.bin"), &first).unwrap();
+    std::fs::write(tree.join("b.bin"), &second).unwrap();
+
+    let target = h.local.join("tree");
+    // One file at a time, so the second finds the first's chunks.
+    let opts = CopyOptions {
+        dedup: true,
+        jobs: 1,
+        ..Default::default()
+    };
+    let report = h
+        .client
+        .copy(h.server_id, h.remote("tree"), &target, opts)
+        .await
+        .unwrap();
+
+    assert_eq!(std::fs::read(target.join("a.bin")).unwrap(), first);
+    assert_eq!(std::fs::read(target.join("b.bin")).unwrap(), second);
+    let stats = report.stats;
+    assert_eq!(stats.files_transferred, 2, "{:?}", stats);
+    // Only the chunks around where the shared block starts and ends differ.
+    assert!(
+        stats.bytes_transferred < shared.len() as u64 + 1024 * 1024,
+        "{:?}",
+        stats
+    );
+    assert!(stats.bytes_saved > shared.len() as u64 / 2, "{:?}", stats);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn identical_file_is_not_transferred() {
+    let h = Harness::start().await;
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i % 253) as u8).collect();
+    std::fs::write(h.served.join("data.bin"), &data).unwrap();
+    let target = h.local.join("data.bin");
+    std::fs::write(&target, &data).unwrap();
+    let modified = std::fs::metadata(&target).unwrap().modified().unwrap();
+
+    let opts = CopyOptions {
+        store: Some(h.client_store.clone()),
+        ..Default::default()
+    };
+    let report = h
+        .client
+        .copy(h.server_id, h.remote("data.bin"), &target, opts)
+        .await
+        .unwrap();
+
+    assert_eq!(report.hash, Some(*blake3::hash(&data).as_bytes()));
+    assert!(report.changed.is_empty());
+    // No signature was computed, so none was cached.
+    let stamp = SignatureStamp {
+        modified_nanos: modified
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64,
+        len: data.len() as u64,
+        block_size: None,
+    };
+    assert!(h
+        .client_store
+        .cached_signature(&target, stamp)
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        std::fs::metadata(&target).unwrap().modified().unwrap(),
+        modified
+    );
+    h.stop().await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn mode_change_alone_is_applied_without_transfer() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let h = Harness::start().await;
+    let remote = h.served.join("run.sh");
+    std::fs::write(&remote, b"#!/bin/sh\necho hi\n").unwrap();
+    let target = h.local.join("run.sh");
+    h.copy(&h.remote("run.sh"), &target).await.unwrap();
+
+    std::fs::set_permissions(&remote, std::fs::Permissions::from_mode(0o750)).unwrap();
+    let report = h
+        .client
+        .copy(
+            h.server_id,
+            h.remote("run.sh"),
+            &target,
+            CopyOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(report.stats.files_transferred, 0);
+    assert_eq!(report.stats.bytes_transferred, 0);
+    assert!(report.changed.is_empty());
+    let metadata = std::fs::metadata(&target).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o7777, 0o750);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn connections_beyond_the_per_peer_cap_are_refused() {
+    let server_endpoint = loopback_endpoint(None).await;
+    let server_id = server_endpoint.id();
+    let addrs = StaticProvider::new();
+    addrs.add_endpoint_info(loopback_addr(&server_endpoint));
+    let client = loopback_endpoint(Some(addrs)).await;
+
+    let data = TempDir::new().unwrap();
+    let mut config = test_config();
+    config.max_connections_per_peer = 2;
+    let server = SyncServer::from_endpoint(
+        server_endpoint,
+        config,
+        Store::new(data.path()).unwrap(),
+        ServeOptions::default(),
+    );
+    let (shutdown, stop) = oneshot::channel();
+    let server_task = tokio::spawn(server.run_until(async {
+        let _ = stop.await;
+    }));
+
+    let mut connections = Vec::new();
+    for _ in 0..3 {
+        connections.push(client.connect(server_id, ALPN).await.unwrap());
+    }
+    let mut refused = 0;
+    for connection in &connections {
+        if let Ok(ConnectionError::ApplicationClosed(close)) =
+            tokio::time::timeout(Duration::from_secs(1), connection.closed()).await
+        {
+            assert_eq!(close.error_code.into_inner(), REFUSED_CODE as u64);
+            refused += 1;
+        }
+    }
+    assert_eq!(refused, 1);
+
+    let _ = shutdown.send(());
+    server_task.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn chunks_are_only_sent_to_peers_advertising_them() {
+    let h = Harness::start().await;
+    std::fs::write(h.served.join("data.bin"), b"some data").unwrap();
+    let addrs = StaticProvider::new();
+    addrs.add_endpoint_info(h.server_addr.clone());
+    let client = loopback_endpoint(Some(addrs)).await;
+    h.server_store
+        .allow_peer(&h.served, client.id(), AccessMode::Read)
+        .unwrap();
+    let connection = client.connect(h.server_id, ALPN).await.unwrap();
+    let timeout = Duration::from_secs(10);
+
+    for (advertised, chunked) in [
+        (vec!["compression"], false),
+        (vec!["compression", CAP_CHUNKS], true),
+    ] {
+        let (mut send, mut recv) = connection.open_bi().await.unwrap();
+        let handshake = Message::Handshake {
+            version: 8,
+            capabilities: advertised.iter().map(|c| c.to_string()).collect(),
+        };
+        write_message(&mut send, &handshake).await.unwrap();
+        match read_message(&mut recv, timeout).await.unwrap() {
+            Message::Handshake { capabilities, .. } => {
+                assert!(capabilities.iter().any(|c| c == CAP_CHUNKS))
+            }
+            msg => panic!("Expected a handshake, got {:?}", msg),
+        }
+
+        let request = Message::ChunkRequest {
+            path: h.remote("data.bin"),
+        };
+        write_message(&mut send, &request).await.unwrap();
+        match read_message(&mut recv, timeout).await.unwrap() {
+            Message::FileChunks { .. } => assert!(chunked, "{:?}", advertised),
+            Message::Error { code, .. } => {
+                assert!(!chunked, "{:?}", advertised);
+                assert_eq!(code, ErrorCode::Unsupported);
+            }
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    }
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn file_written_during_a_download_is_asked_for_again() {
+    // Slow enough that the file can be changed between the first chunk and
+    // the last.
+    let mut config = test_config();
+    config.chunk_size = 1000;
+    config.schedule = vec![ScheduleWindow {
+        start: TimeOfDay(0),
+        end: TimeOfDay(0),
+        limit: WindowLimit::Rate(2000),
+    }];
+    let h = Harness::start_configured(SUPPORTED_ALPNS, config).await;
+    let path = h.served.join("data.bin");
+    std::fs::write(&path, vec![1u8; 6000]).unwrap();
+    let addrs = StaticProvider::new();
+    addrs.add_endpoint_info(h.server_addr.clone());
+    let client = loopback_endpoint(Some(addrs)).await;
+    h.server_store
+        .allow_peer(&h.served, client.id(), AccessMode::Read)
+        .unwrap();
+    let connection = client.connect(h.server_id, ALPN).await.unwrap();
+    let (mut send, mut recv) = connection.open_bi().await.unwrap();
+    let timeout = Duration::from_secs(10);
+    let handshake = Message::Handshake {
+        version: 8,
+        capabilities: vec![CAP_RETRY.to_string()],
+    };
+    write_message(&mut send, &handshake).await.unwrap();
+    read_message(&mut recv, timeout).await.unwrap();
+
+    let request = Message::FileRequest {
+        path: h.remote("data.bin"),
+        offset: 0,
+    };
+    write_message(&mut send, &request).await.unwrap();
+    let mut written = false;
+    loop {
+        match read_message(&mut recv, timeout).await.unwrap() {
+            Message::FileData { is_last, .. } => {
+                assert!(!is_last, "The torn file was sent whole");
+                if !written {
+                    std::fs::write(&path, vec![2u8; 7000]).unwrap();
+                    written = true;
+                }
+            }
+            Message::Error { code, .. } => {
+                assert_eq!(code, ErrorCode::Retry);
+                break;
+            }
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    }
+
+    // Asked again, the server sends the file as it is now.
+    let target = h.local.join("data.bin");
+    h.copy(&h.remote("data.bin"), &target).await.unwrap();
+    assert_eq!(std::fs::read(&target).unwrap(), vec![2u8; 7000]);
+    h.stop().await;
+}
+
+/// A raw client connection to `h`'s server, past the handshake, from an
+/// endpoint that `h` lets read what it serves.
+async fn raw_session(
+    h: &Harness,
+) -> (
+    Endpoint,
+    iroh::endpoint::Connection,
+    iroh::endpoint::SendStream,
+    iroh::endpoint::RecvStream,
+) {
+    let addrs = StaticProvider::new();
+    addrs.add_endpoint_info(h.server_addr.clone());
+    let client = loopback_endpoint(Some(addrs)).await;
+    h.server_store
+        .allow_peer(&h.served, client.id(), AccessMode::Read)
+        .unwrap();
+    let connection = client.connect(h.server_id, ALPN).await.unwrap();
+    let (mut send, mut recv) = connection.open_bi().await.unwrap();
+    write_message(&mut send, &Message::LegacyHandshake { version: 3 })
+        .await
+        .unwrap();
+    read_message(&mut recv, Duration::from_secs(10))
+        .await
+        .unwrap();
+    (client, connection, send, recv)
+}
+
+fn list_request(path: String) -> Message {
+    Message::ListRequest {
+        path,
+        with_hashes: false,
+        follow_symlinks: false,
+    }
+}
+
+/// Number of responses up to the one marked last, and the manifest entries
+/// among them.
+async fn read_all_parts(
+    recv: &mut iroh::endpoint::RecvStream,
+) -> (usize, Vec<(String, ManifestAction)>) {
+    let mut responses = 0;
+    let mut actions = Vec::new();
+    loop {
+        responses += 1;
+        let is_last = match read_message(recv, Duration::from_secs(10)).await.unwrap() {
+            Message::ListResponse { is_last, .. } => is_last,
+            Message::ManifestResponse { entries, is_last } => {
+                actions.extend(entries.into_iter().map(|e| (e.file.path, e.action)));
+                is_last
+            }
+            msg => panic!("Unexpected message: {:?}", msg),
+        };
+        if is_last {
+            return (responses, actions);
+        }
+    }
+}
+
+#[tokio::test]
+async fn manifest_of_a_mostly_synced_tree_needs_one_response() {
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir_all(&tree).unwrap();
+    let files = LIST_CHUNK_LEN * 2;
+    for i in 0..files {
+        std::fs::write(tree.join(format!("f{i}.txt")), format!("file {i}")).unwrap();
+    }
+
+    // The client has everything but one file, and an older version of
+    // another.
+    let mut manifest = Vec::new();
+    for entry in std::fs::read_dir(&tree).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_str().unwrap().to_string();
+        if name == "f1.txt" {
+            continue;
+        }
+        let metadata = std::fs::metadata(&path).unwrap();
+        let hash = if name == "f0.txt" {
+            *blake3::hash(b"older").as_bytes()
+        } else {
+            sync_utils::hash_file(&path).await.unwrap()
+        };
+        manifest.push(FileMetadata {
+            path: h.remote(&format!("tree/{name}")),
+            len: metadata.len(),
+            modified: metadata
+                .modified()
+                .unwrap()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            file_type: FileType::File,
+            link_target: None,
+            hash: Some(hash),
+            mode: sync_utils::file_mode(&metadata),
+        });
+    }
+    let metadata = std::fs::metadata(&tree).unwrap();
+    manifest.push(FileMetadata {
+        path: h.remote("tree"),
+        len: metadata.len(),
+        modified: 0,
+        file_type: FileType::Dir,
+        link_target: None,
+        hash: None,
+        mode: None,
+    });
+
+    let (_client, _connection, mut send, mut recv) = raw_session(&h).await;
+    let listing = Message::ListRequest {
+        path: h.remote("tree"),
+        with_hashes: true,
+        follow_symlinks: false,
+    };
+    write_message(&mut send, &listing).await.unwrap();
+    let (listed, _) = read_all_parts(&mut recv).await;
+
+    let request = Message::ManifestRequest {
+        path: h.remote("tree"),
+        files: manifest,
+        follow_symlinks: false,
+    };
+    write_message(&mut send, &request).await.unwrap();
+    let (diffed, mut actions) = read_all_parts(&mut recv).await;
+
+    // The listing repeats every entry, in parts; the manifest's answer holds
+    // just the two that differ.
+    assert_eq!(listed, 3);
+    assert_eq!(diffed, 1);
+    actions.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        actions,
+        vec![
+            (h.remote("tree/f0.txt"), ManifestAction::Full),
+            (h.remote("tree/f1.txt"), ManifestAction::Create),
+        ]
+    );
+    h.stop().await;
+}
+
+/// The `ListGeneration` that starts the answer to a `ListIfChanged`.
+async fn read_generation(recv: &mut iroh::endpoint::RecvStream) -> (Option<u64>, bool) {
+    match read_message(recv, Duration::from_secs(10)).await.unwrap() {
+        Message::ListGeneration {
+            generation,
+            unchanged,
+        } => (generation, unchanged),
+        msg => panic!("Unexpected message: {:?}", msg),
+    }
+}
+
+#[tokio::test]
+async fn unchanged_tree_is_only_walked_once() {
+    // The server runs on this thread, so its log lines land here.
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish();
+    let _logging = tracing::subscriber::set_default(subscriber);
+    let walks = || logs.contents().matches("Walking").count();
+
+    let mut config = test_config();
+    config.listing_cache_min_entries = 0;
+    let h = Harness::start_configured(SUPPORTED_ALPNS, config).await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir_all(&tree).unwrap();
+    for i in 0..3 {
+        std::fs::write(tree.join(format!("f{i}.txt")), format!("file {i}")).unwrap();
+    }
+    let list = |since| Message::ListIfChanged {
+        path: h.remote("tree"),
+        with_hashes: false,
+        follow_symlinks: false,
+        since,
+    };
+
+    let (_client, _connection, mut send, mut recv) = raw_session(&h).await;
+    write_message(&mut send, &list(None)).await.unwrap();
+    let (generation, unchanged) = read_generation(&mut recv).await;
+    let generation = generation.expect("listing is not kept");
+    assert!(!unchanged);
+    read_all_parts(&mut recv).await;
+
+    // A plain listing is answered from what was kept, and asking about the
+    // generation the client has needs no listing at all.
+    write_message(&mut send, &list_request(h.remote("tree")))
+        .await
+        .unwrap();
+    read_all_parts(&mut recv).await;
+    write_message(&mut send, &list(Some(generation)))
+        .await
+        .unwrap();
+    assert_eq!(read_generation(&mut recv).await, (Some(generation), true));
+    assert_eq!(walks(), 1);
+
+    // Once the watcher reports a change, the tree is walked again.
+    std::fs::write(tree.join("f0.txt"), b"changed").unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        write_message(&mut send, &list(Some(generation)))
+            .await
+            .unwrap();
+        match read_generation(&mut recv).await {
+            (_, true) => {
+                assert!(Instant::now() < deadline, "change was never noticed");
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            (next, false) => {
+                assert_ne!(next, Some(generation));
+                read_all_parts(&mut recv).await;
+                break;
+            }
+        }
+    }
+    assert_eq!(walks(), 2);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn revoking_access_mid_session_denies_the_next_request() {
+    let h = Harness::start().await;
+    std::fs::create_dir(h.served.join("kept")).unwrap();
+    std::fs::create_dir(h.served.join("revoked")).unwrap();
+    let (client, _connection, mut send, mut recv) = raw_session(&h).await;
+    // Only the grants below count once the one on the whole tree is gone.
+    h.server_store
+        .allow_peer(h.served.join("kept"), client.id(), AccessMode::Read)
+        .unwrap();
+    h.server_store
+        .allow_peer(h.served.join("revoked"), client.id(), AccessMode::Read)
+        .unwrap();
+    h.server_store
+        .disallow_peer(&h.served, client.id())
+        .unwrap();
+    let timeout = Duration::from_secs(10);
+
+    write_message(&mut send, &list_request(h.remote("revoked")))
+        .await
+        .unwrap();
+    let msg = read_message(&mut recv, timeout).await.unwrap();
+    assert!(matches!(msg, Message::ListResponse { .. }), "{:?}", msg);
+
+    h.server_store
+        .disallow_peer(h.served.join("revoked"), client.id())
+        .unwrap();
+    write_message(&mut send, &list_request(h.remote("revoked")))
+        .await
+        .unwrap();
+    match read_message(&mut recv, timeout).await.unwrap() {
+        Message::Error { code, .. } => assert_eq!(code, ErrorCode::AccessDenied),
+        msg => panic!("Unexpected message: {:?}", msg),
+    }
+
+    // The rest of its access is untouched.
+    write_message(&mut send, &list_request(h.remote("kept")))
+        .await
+        .unwrap();
+    let msg = read_message(&mut recv, timeout).await.unwrap();
+    assert!(matches!(msg, Message::ListResponse { .. }), "{:?}", msg);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn revoking_all_access_closes_open_connections() {
+    let h = Harness::start().await;
+    let (client, connection, _send, _recv) = raw_session(&h).await;
+
+    h.server_store
+        .disallow_peer_everywhere(client.id())
+        .unwrap();
+
+    match tokio::time::timeout(Duration::from_secs(5), connection.closed()).await {
+        Ok(ConnectionError::ApplicationClosed(close)) => {
+            assert_eq!(close.error_code.into_inner(), REFUSED_CODE as u64);
+        }
+        other => panic!("Connection not closed: {:?}", other),
+    }
+    h.stop().await;
+}
+
+/// Log output captured from the current thread.
+#[derive(Clone, Default)]
+struct CapturedLogs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl CapturedLogs {
+    fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+#[tokio::test]
+async fn malformed_request_is_logged_as_an_error() {
+    // The server runs on this thread, so its log lines land here.
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish();
+    let _logging = tracing::subscriber::set_default(subscriber);
+
+    let h = Harness::start().await;
+    let addrs = StaticProvider::new();
+    addrs.add_endpoint_info(h.server_addr.clone());
+    let client = loopback_endpoint(Some(addrs)).await;
+    let connection = client.connect(h.server_id, ALPN).await.unwrap();
+    let (mut send, mut recv) = connection.open_bi().await.unwrap();
+    write_message(&mut send, &Message::LegacyHandshake { version: 3 })
+        .await
+        .unwrap();
+    read_message(&mut recv, Duration::from_secs(10))
+        .await
+        .unwrap();
+
+    // A well-formed frame holding no message at all.
+    send.write_u32(3).await.unwrap();
+    send.write_all(&[0xff; 3]).await.unwrap();
+    send.flush().await.unwrap();
+
+    // The server gives up on the stream and finishes its side.
+    assert!(read_message_or_eof(&mut recv, Duration::from_secs(10))
+        .await
+        .unwrap()
+        .is_none());
+    let contents = logs.contents();
+    assert!(contents.contains("ERROR"), "{}", contents);
+    assert!(contents.contains("Failed to read request"), "{}", contents);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn oversized_frame_is_refused_before_it_is_read() {
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish();
+    let _logging = tracing::subscriber::set_default(subscriber);
+
+    let h = Harness::start().await;
+    let addrs = StaticProvider::new();
+    addrs.add_endpoint_info(h.server_addr.clone());
+    let client = loopback_endpoint(Some(addrs)).await;
+    let connection = client.connect(h.server_id, ALPN).await.unwrap();
+    let (mut send, mut recv) = connection.open_bi().await.unwrap();
+    write_message(&mut send, &Message::LegacyHandshake { version: 3 })
+        .await
+        .unwrap();
+    read_message(&mut recv, Duration::from_secs(10))
+        .await
+        .unwrap();
+
+    // Announces 4 GiB, then sends nothing more.
+    send.write_u32(u32::MAX).await.unwrap();
+    send.flush().await.unwrap();
+
+    let started = Instant::now();
+    assert!(read_message_or_eof(&mut recv, Duration::from_secs(10))
+        .await
+        .unwrap()
+        .is_none());
+    assert!(started.elapsed() < Duration::from_secs(5));
+    let contents = logs.contents();
+    assert!(contents.contains("over the limit"), "{}", contents);
+    h.stop().await;
+
+    // Nor is one sent.
+    let data = Message::FileData {
+        path: "big.bin".to_string(),
+        data: vec![0; MAX_FRAME_LEN],
+        offset: 0,
+        is_last: true,
+        hash: None,
+    };
+    assert!(matches!(
+        write_message(&mut Vec::new(), &data).await,
+        Err(ProtocolError::FrameTooLarge(_))
+    ));
+}
+
+#[tokio::test]
+async fn server_closes_a_connection_that_withholds_its_handshake() {
+    let mut config = test_config();
+    config.handshake_timeout_secs = 1;
+    let h = Harness::start_configured(SUPPORTED_ALPNS, config).await;
+    let addrs = StaticProvider::new();
+    addrs.add_endpoint_info(h.server_addr.clone());
+    let client = loopback_endpoint(Some(addrs)).await;
+    let connection = client.connect(h.server_id, ALPN).await.unwrap();
+    let (mut send, _recv) = connection.open_bi().await.unwrap();
+
+    // Enough for the server to see the stream, but never the handshake.
+    send.write_u32(16).await.unwrap();
+    send.flush().await.unwrap();
+
+    // Well before the idle timeout.
+    tokio::time::timeout(Duration::from_secs(5), connection.closed())
+        .await
+        .expect("server kept waiting for the handshake");
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn stream_gone_quiet_is_closed_after_the_idle_timeout() {
+    let mut config = test_config();
+    config.idle_timeout_secs = 1;
+    let h = Harness::start_configured(SUPPORTED_ALPNS, config).await;
+    let (_client, _connection, mut send, mut recv) = raw_session(&h).await;
+    let timeout = Duration::from_secs(10);
+
+    // Each request starts the wait over, so pings keep the stream open for
+    // longer than the timeout.
+    let started = Instant::now();
+    for nonce in 0..4 {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        write_message(&mut send, &Message::Ping { nonce })
+            .await
+            .unwrap();
+        match read_message(&mut recv, timeout).await.unwrap() {
+            Message::Pong { nonce: answered } => assert_eq!(answered, nonce),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    }
+    assert!(started.elapsed() > Duration::from_secs(1));
+
+    // Then silence ends the stream once the timeout has passed.
+    let quiet = Instant::now();
+    let result = read_message_or_eof(&mut recv, timeout).await;
+    assert!(!matches!(result, Ok(Some(_))), "{:?}", result);
+    let waited = quiet.elapsed();
+    assert!(waited >= Duration::from_millis(900), "{:?}", waited);
+    assert!(waited < Duration::from_secs(5), "{:?}", waited);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn client_gives_up_on_a_server_that_withholds_its_handshake() {
+    // Accepts connections and streams, and says nothing on them.
+    let server = loopback_endpoint(None).await;
+    let server_id = server.id();
+    let addrs = StaticProvider::new();
+    addrs.add_endpoint_info(loopback_addr(&server));
+    let silent = tokio::spawn(async move {
+        let connection = server
+            .accept()
+            .await
+            .unwrap()
+            .accept()
+            .unwrap()
+            .await
+            .unwrap();
+        let mut streams = Vec::new();
+        while let Ok(stream) = connection.accept_bi().await {
+            streams.push(stream);
+        }
+    });
+
+    let mut config = test_config();
+    config.handshake_timeout_secs = 1;
+    let client = SyncClient::from_endpoint(loopback_endpoint(Some(addrs)).await, config);
+    let target = TempDir::new().unwrap();
+    let started = Instant::now();
+    let err = client
+        .copy(
+            server_id,
+            "/anything",
+            target.path().join("anything"),
+            CopyOptions::default(),
+        )
+        .await
+        .unwrap_err();
+    assert!(format!("{:#}", err).contains("no handshake"), "{:#}", err);
+    assert!(started.elapsed() < Duration::from_secs(5));
+    silent.abort();
+}
+
+#[tokio::test]
+async fn notified_paths_leading_out_of_the_sync_are_not_pulled() {
+    let h = Harness::start().await;
+    let (peer, connection, mut send, _recv) = raw_session(&h).await;
+    let local_root = h.local.join("dir");
+    h.server_store
+        .add_sync_with_watch(peer.id(), "/remote/dir".to_string(), local_root.clone())
+        .unwrap();
+    h.server_store
+        .set_sync_include_hidden(&local_root, peer.id(), "/remote/dir", true)
+        .unwrap();
+
+    for path in ["/remote/dir/../escaped.txt", "/remote/dir/ok.txt"] {
+        let msg = Message::FileUpdateNotification {
+            path: path.to_string(),
+        };
+        write_message(&mut send, &msg).await.unwrap();
+    }
+
+    // Every pull the server starts asks us for a listing first.
+    let mut requested = Vec::new();
+    while let Ok(Ok((mut pull_send, mut pull_recv))) =
+        tokio::time::timeout(Duration::from_secs(2), connection.accept_bi()).await
+    {
+        let timeout = Duration::from_secs(10);
+        read_message(&mut pull_recv, timeout).await.unwrap();
+        write_message(&mut pull_send, &Message::LegacyHandshake { version: 3 })
+            .await
+            .unwrap();
+        if let Message::ListRequest { path, .. } =
+            read_message(&mut pull_recv, timeout).await.unwrap()
+        {
+            requested.push(path);
+        }
+        let err = Message::Error {
+            code: ErrorCode::NotFound,
+            message: "gone".to_string(),
+        };
+        write_message(&mut pull_send, &err).await.unwrap();
+        pull_send.finish().unwrap();
+    }
+
+    assert_eq!(requested, vec!["/remote/dir/ok.txt".to_string()]);
+    assert!(!h.local.join("escaped.txt").exists());
+    drop(connection);
+    peer.close().await;
+    h.stop().await;
+}
+
+/// Have `peer` answer heartbeats and report the notifications it gets.
+fn notification_listener(
+    peer: Endpoint,
+) -> (JoinHandle<()>, tokio::sync::mpsc::UnboundedReceiver<String>) {
+    let (notified_tx, notified) = tokio::sync::mpsc::unbounded_channel();
+    let listener = tokio::spawn(async move {
+        let connection = peer
+            .accept()
+            .await
+            .unwrap()
+            .accept()
+            .unwrap()
+            .await
+            .unwrap();
+        while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+            let notified_tx = notified_tx.clone();
+            tokio::spawn(async move {
+                let timeout = Duration::from_secs(10);
+                read_message(&mut recv, timeout).await?;
+                write_message(&mut send, &Message::LegacyHandshake { version: 2 }).await?;
+                loop {
+                    match read_message(&mut recv, timeout).await? {
+                        Message::Ping { nonce } => {
+                            write_message(&mut send, &Message::Pong { nonce }).await?
+                        }
+                        Message::FileUpdateNotification { path } => {
+                            let _ = notified_tx.send(path);
+                            let _ = send.finish();
+                            return anyhow::Ok(());
+                        }
+                        msg => anyhow::bail!("Unexpected message: {:?}", msg),
+                    }
+                }
+            });
+        }
+        drop(peer);
+    });
+    (listener, notified)
+}
+
+#[tokio::test]
+async fn queued_notification_is_delivered_after_restart() {
+    let peer = loopback_endpoint(None).await;
+    let peer_id = peer.id();
+    let addrs = StaticProvider::new();
+    addrs.add_endpoint_info(loopback_addr(&peer));
+    let server_endpoint = loopback_endpoint(Some(addrs)).await;
+
+    // Queued by an earlier run of the server that couldn't reach the peer.
+    let data = TempDir::new().unwrap();
+    {
+        let store = Store::new(data.path()).unwrap();
+        store
+            .queue_notification(peer_id, "/shared/notes.txt")
+            .unwrap();
+        store
+            .queue_notification(peer_id, "/shared/notes.txt")
+            .unwrap();
+    }
+    let store = Store::new(data.path()).unwrap();
+    assert_eq!(
+        store.pending_notifications(peer_id).unwrap(),
+        vec!["/shared/notes.txt"]
+    );
+
+    let (listener, mut notified) = notification_listener(peer);
+    let server = SyncServer::from_endpoint(
+        server_endpoint,
+        test_config(),
+        store.clone(),
+        ServeOptions::default(),
+    );
+    let (shutdown, stop) = oneshot::channel();
+    let server_task = tokio::spawn(server.run_until(async {
+        let _ = stop.await;
+    }));
+
+    let path = tokio::time::timeout(Duration::from_secs(10), notified.recv())
+        .await
+        .expect("queued notification was not delivered")
+        .unwrap();
+    assert_eq!(path, "/shared/notes.txt");
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !store.pending_notifications(peer_id).unwrap().is_empty() {
+        assert!(
+            Instant::now() < deadline,
+            "delivered notification still queued"
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    let _ = shutdown.send(());
+    server_task.await.unwrap().unwrap();
+    listener.abort();
+}
+
+#[tokio::test]
+async fn paused_schedule_defers_syncs() {
+    let peer = loopback_endpoint(None).await;
+    let peer_id = peer.id();
+    let addrs = StaticProvider::new();
+    addrs.add_endpoint_info(loopback_addr(&peer));
+    let server_endpoint = loopback_endpoint(Some(addrs)).await;
+    let (listener, mut notified) = notification_listener(peer);
+
+    let data = TempDir::new().unwrap();
+    let watched = TempDir::new().unwrap();
+    let shared = std::fs::canonicalize(watched.path())
+        .unwrap()
+        .join("notes.txt");
+    std::fs::write(&shared, b"first").unwrap();
+    let remote = "/shared/notes.txt";
+    let store = Store::new(data.path()).unwrap();
+    store
+        .add_sync_with_watch(peer_id, remote.to_string(), shared.clone())
+        .unwrap();
+
+    // A window covering the whole day pauses transfers whatever the time.
+    let mut config = test_config();
+    config.schedule = vec![ScheduleWindow {
+        start: TimeOfDay(0),
+        end: TimeOfDay(0),
+        limit: WindowLimit::Paused,
+    }];
+    let server = SyncServer::from_endpoint(
+        server_endpoint,
+        config,
+        store.clone(),
+        ServeOptions::default(),
+    );
+    let mut events = server.subscribe();
+    let (shutdown, stop) = oneshot::channel();
+    let server_task = tokio::spawn(server.run_until(async {
+        let _ = stop.await;
+    }));
+
+    // The watch goes in shortly after the server starts; keep changing the
+    // file until the change is seen.
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            std::fs::write(&shared, b"second").unwrap();
+            let wait = tokio::time::timeout(Duration::from_millis(200), events.recv());
+            if let Ok(Ok(SyncEvent::LocalChangeDetected { .. })) = wait.await {
+                break;
+            }
+        }
+    })
+    .await
+    .expect("local change was not detected");
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while store.pending_notifications(peer_id).unwrap().is_empty() {
+        assert!(Instant::now() < deadline, "notification was not queued");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    // The peer could have been told, but wasn't.
+    assert_eq!(store.pending_notifications(peer_id).unwrap(), vec![remote]);
+    assert!(notified.try_recv().is_err());
+
+    let _ = shutdown.send(());
+    server_task.await.unwrap().unwrap();
+    listener.abort();
+}
+
+/// The next event on `events`, failing the test if none comes.
+async fn next_event(events: &mut broadcast::Receiver<SyncEvent>) -> SyncEvent {
+    tokio::time::timeout(Duration::from_secs(10), events.recv())
+        .await
+        .expect("no event arrived")
+        .unwrap()
+}
+
+#[tokio::test]
+async fn sync_reports_its_progress_as_events() {
+    // Two servers that reach each other: `source` watches a file and tells
+    // `puller`, which pulls it.
+    let source_peers = StaticProvider::new();
+    let source_endpoint = loopback_endpoint(Some(source_peers.clone())).await;
+    let puller_peers = StaticProvider::new();
+    let puller_endpoint = loopback_endpoint(Some(puller_peers.clone())).await;
+    source_peers.add_endpoint_info(loopback_addr(&puller_endpoint));
+    puller_peers.add_endpoint_info(loopback_addr(&source_endpoint));
+    let (source_id, puller_id) = (source_endpoint.id(), puller_endpoint.id());
+
+    let dirs: Vec<TempDir> = (0..4).map(|_| TempDir::new().unwrap()).collect();
+    let shared = std::fs::canonicalize(dirs[0].path())
+        .unwrap()
+        .join("notes.txt");
+    let pulled = std::fs::canonicalize(dirs[1].path())
+        .unwrap()
+        .join("notes.txt");
+    std::fs::write(&shared, b"first").unwrap();
+    let remote = shared.to_string_lossy().into_owned();
+
+    let source_store = Store::new(dirs[2].path()).unwrap();
+    source_store
+        .allow_peer(&shared, puller_id, AccessMode::Read)
+        .unwrap();
+    source_store
+        .add_sync_with_watch(puller_id, remote.clone(), shared.clone())
+        .unwrap();
+    let puller_store = Store::new(dirs[3].path()).unwrap();
+    puller_store
+        .add_sync(source_id, remote.clone(), pulled.clone())
+        .unwrap();
+
+    let source = SyncServer::from_endpoint(
+        source_endpoint,
+        test_config(),
+        source_store,
+        ServeOptions::default(),
+    );
+    let puller = SyncServer::from_endpoint(
+        puller_endpoint,
+        test_config(),
+        puller_store,
+        ServeOptions::default(),
+    );
+    let mut source_events = source.subscribe();
+    let mut puller_events = puller.subscribe();
+    let mut servers = Vec::new();
+    for server in [source, puller] {
+        let (shutdown, stop) = oneshot::channel();
+        let task = tokio::spawn(server.run_until(async {
+            let _ = stop.await;
+        }));
+        servers.push((shutdown, task));
+    }
+
+    // The watch goes in shortly after the server starts; keep changing the
+    // file until the change is seen.
+    let contents = b"second version";
+    let detected = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            std::fs::write(&shared, contents).unwrap();
+            let wait = tokio::time::timeout(Duration::from_millis(200), source_events.recv());
+            if let Ok(Ok(event)) = wait.await {
+                return event;
+            }
+        }
+    })
+    .await
+    .expect("local change was not detected");
+    assert_eq!(
+        detected,
+        SyncEvent::LocalChangeDetected {
+            path: shared.clone()
+        }
+    );
+    // A write may be seen as more than one change.
+    let notified = loop {
+        match next_event(&mut source_events).await {
+            SyncEvent::LocalChangeDetected { .. } => continue,
+            event => break event,
+        }
+    };
+    assert_eq!(
+        notified,
+        SyncEvent::NotifiedPeer {
+            peer: puller_id,
+            path: remote.clone()
+        }
+    );
+
+    assert_eq!(
+        next_event(&mut puller_events).await,
+        SyncEvent::PullStarted {
+            peer: source_id,
+            path: remote.clone(),
+            local: pulled.clone()
+        }
+    );
+    let completed = loop {
+        match next_event(&mut puller_events).await {
+            SyncEvent::PullStarted { .. } => continue,
+            event => break event,
+        }
+    };
+    assert_eq!(
+        completed,
+        SyncEvent::FileCompleted {
+            path: pulled.clone(),
+            bytes: contents.len() as u64
+        }
+    );
+    assert_eq!(std::fs::read(&pulled).unwrap(), contents);
+
+    for (shutdown, task) in servers {
+        let _ = shutdown.send(());
+        task.await.unwrap().unwrap();
+    }
+}
+
+#[tokio::test]
+async fn pulled_change_is_not_sent_back_to_its_source() {
+    // Two servers syncing one file both ways, each pulling the other's
+    // changes. As with `syncr sync`, both know the sync by A's path.
+    let a_peers = StaticProvider::new();
+    let a_endpoint = loopback_endpoint(Some(a_peers.clone())).await;
+    let b_peers = StaticProvider::new();
+    let b_endpoint = loopback_endpoint(Some(b_peers.clone())).await;
+    a_peers.add_endpoint_info(loopback_addr(&b_endpoint));
+    b_peers.add_endpoint_info(loopback_addr(&a_endpoint));
+    let (a_id, b_id) = (a_endpoint.id(), b_endpoint.id());
+
+    let dirs: Vec<TempDir> = (0..4).map(|_| TempDir::new().unwrap()).collect();
+    let a_file = std::fs::canonicalize(dirs[0].path())
+        .unwrap()
+        .join("notes.txt");
+    let b_file = std::fs::canonicalize(dirs[1].path())
+        .unwrap()
+        .join("notes.txt");
+    std::fs::write(&a_file, b"first").unwrap();
+    std::fs::write(&b_file, b"first").unwrap();
+    let remote = a_file.to_string_lossy().into_owned();
+    let mut servers = Vec::new();
+    let mut events = Vec::new();
+    for (endpoint, data, own, other) in [
+        (a_endpoint, &dirs[2], &a_file, b_id),
+        (b_endpoint, &dirs[3], &b_file, a_id),
+    ] {
+        let store = Store::new(data.path()).unwrap();
+        store.allow_peer(own, other, AccessMode::ReadWrite).unwrap();
+        store
+            .add_sync_with_watch(other, remote.clone(), own.clone())
+            .unwrap();
+        let server =
+            SyncServer::from_endpoint(endpoint, test_config(), store, ServeOptions::default());
+        events.push(server.subscribe());
+        let (shutdown, stop) = oneshot::channel();
+        let task = tokio::spawn(server.run_until(async {
+            let _ = stop.await;
+        }));
+        servers.push((shutdown, task));
+    }
+    let (mut a_events, mut b_events) = (events.remove(0), events.remove(0));
+
+    // The watches go in shortly after the servers start; keep changing the
+    // file until the change is seen.
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            std::fs::write(&a_file, b"second").unwrap();
+            let wait = tokio::time::timeout(Duration::from_millis(200), a_events.recv());
+            if let Ok(Ok(SyncEvent::LocalChangeDetected { .. })) = wait.await {
+                break;
+            }
+        }
+    })
+    .await
+    .expect("local change was not detected");
+
+    // B pulls the change and sees its own write of it...
+    loop {
+        if let SyncEvent::LocalChangeDetected { path } = next_event(&mut b_events).await {
+            assert_eq!(path, b_file);
+            break;
+        }
+    }
+    assert_eq!(std::fs::read(&b_file).unwrap(), b"second");
+    // ...but doesn't tell A, so A pulls nothing back.
+    let bounced = tokio::time::timeout(Duration::from_secs(2), async {
+        loop {
+            tokio::select! {
+                Ok(event @ SyncEvent::NotifiedPeer { .. }) = b_events.recv() => break event,
+                Ok(event @ SyncEvent::PullStarted { .. }) = a_events.recv() => break event,
+                else => {}
+            }
+        }
+    })
+    .await;
+    assert!(bounced.is_err(), "change bounced back: {:?}", bounced);
+
+    for (shutdown, task) in servers {
+        let _ = shutdown.send(());
+        task.await.unwrap().unwrap();
+    }
+}
+
+#[tokio::test]
+async fn notified_pull_reuses_the_notifying_connection() {
+    // Both servers run on this thread, so their log lines land here.
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish();
+    let _logging = tracing::subscriber::set_default(subscriber);
+
+    // Only `source` knows how to reach the other; `puller` can't dial back.
+    let source_peers = StaticProvider::new();
+    let source_endpoint = loopback_endpoint(Some(source_peers.clone())).await;
+    let puller_endpoint = loopback_endpoint(None).await;
+    source_peers.add_endpoint_info(loopback_addr(&puller_endpoint));
+    let (source_id, puller_id) = (source_endpoint.id(), puller_endpoint.id());
+
+    let dirs: Vec<TempDir> = (0..4).map(|_| TempDir::new().unwrap()).collect();
+    let shared = std::fs::canonicalize(dirs[0].path())
+        .unwrap()
+        .join("notes.txt");
+    let pulled = std::fs::canonicalize(dirs[1].path())
+        .unwrap()
+        .join("notes.txt");
+    std::fs::write(&shared, b"first").unwrap();
+    let remote = shared.to_string_lossy().into_owned();
+
+    let source_store = Store::new(dirs[2].path()).unwrap();
+    source_store
+        .allow_peer(&shared, puller_id, AccessMode::Read)
+        .unwrap();
+    source_store
+        .add_sync_with_watch(puller_id, remote.clone(), shared.clone())
+        .unwrap();
+    let puller_store = Store::new(dirs[3].path()).unwrap();
+    puller_store
+        .add_sync(source_id, remote.clone(), pulled.clone())
+        .unwrap();
+
+    let source = SyncServer::from_endpoint(
+        source_endpoint,
+        test_config(),
+        source_store,
+        ServeOptions::default(),
+    );
+    let puller = SyncServer::from_endpoint(
+        puller_endpoint,
+        test_config(),
+        puller_store,
+        ServeOptions::default(),
+    );
+    let mut puller_events = puller.subscribe();
+    let mut servers = Vec::new();
+    for server in [source, puller] {
+        let (shutdown, stop) = oneshot::channel();
+        let task = tokio::spawn(server.run_until(async {
+            let _ = stop.await;
+        }));
+        servers.push((shutdown, task));
+    }
+
+    // Keep changing the file until the watch is in and the pull lands.
+    let contents = b"second version";
+    let completed = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            std::fs::write(&shared, contents).unwrap();
+            let wait = tokio::time::timeout(Duration::from_millis(200), puller_events.recv());
+            if let Ok(Ok(event @ SyncEvent::FileCompleted { .. })) = wait.await {
+                return event;
+            }
+        }
+    })
+    .await
+    .expect("notified change was not pulled");
+    assert_eq!(
+        completed,
+        SyncEvent::FileCompleted {
+            path: pulled.clone(),
+            bytes: contents.len() as u64
+        }
+    );
+    assert_eq!(std::fs::read(&pulled).unwrap(), contents);
+
+    // `source` only ever accepted a connection if `puller` dialed it.
+    let contents = logs.contents();
+    assert!(
+        contents.contains(&format!("Accepted connection from {}", source_id)),
+        "{}",
+        contents
+    );
+    assert!(
+        !contents.contains(&format!("Accepted connection from {}", puller_id)),
+        "{}",
+        contents
+    );
+
+    for (shutdown, task) in servers {
+        let _ = shutdown.send(());
+        task.await.unwrap().unwrap();
+    }
+}
+
+#[tokio::test]
+async fn notified_pulls_never_exceed_the_concurrency_limit() {
+    const FILES: usize = 8;
+    const LIMIT: usize = 2;
+    // `source` watches a directory `puller` syncs; changing every file in it
+    // at once notifies `puller` of them all together.
+    let source_peers = StaticProvider::new();
+    let source_endpoint = loopback_endpoint(Some(source_peers.clone())).await;
+    let puller_peers = StaticProvider::new();
+    let puller_endpoint = loopback_endpoint(Some(puller_peers.clone())).await;
+    source_peers.add_endpoint_info(loopback_addr(&puller_endpoint));
+    puller_peers.add_endpoint_info(loopback_addr(&source_endpoint));
+    let (source_id, puller_id) = (source_endpoint.id(), puller_endpoint.id());
+
+    let dirs: Vec<TempDir> = (0..4).map(|_| TempDir::new().unwrap()).collect();
+    let shared = std::fs::canonicalize(dirs[0].path()).unwrap();
+    let pulled = std::fs::canonicalize(dirs[1].path()).unwrap();
+    let names: Vec<String> = (0..FILES).map(|i| format!("file{}.txt", i)).collect();
+    for name in &names {
+        std::fs::write(shared.join(name), b"first").unwrap();
+    }
+    let remote = shared.to_string_lossy().into_owned();
+
+    let source_store = Store::new(dirs[2].path()).unwrap();
+    source_store
+        .allow_peer(&shared, puller_id, AccessMode::Read)
+        .unwrap();
+    source_store
+        .add_sync_with_watch(puller_id, remote.clone(), shared.clone())
+        .unwrap();
+    let puller_store = Store::new(dirs[3].path()).unwrap();
+    puller_store
+        .add_sync(source_id, remote.clone(), pulled.clone())
+        .unwrap();
+
+    let mut config = test_config();
+    config.max_concurrent_pulls = LIMIT;
+    let source = SyncServer::from_endpoint(
+        source_endpoint,
+        test_config(),
+        source_store,
+        ServeOptions::default(),
+    );
+    let puller = SyncServer::from_endpoint(
+        puller_endpoint,
+        config,
+        puller_store,
+        ServeOptions::default(),
+    );
+    let mut source_events = source.subscribe();
+    let mut puller_events = puller.subscribe();
+    let mut servers = Vec::new();
+    for server in [source, puller] {
+        let (shutdown, stop) = oneshot::channel();
+        let task = tokio::spawn(server.run_until(async {
+            let _ = stop.await;
+        }));
+        servers.push((shutdown, task));
+    }
+
+    // The watch goes in shortly after the server starts; keep changing the
+    // files until a change is seen.
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            for name in &names {
+                std::fs::write(shared.join(name), b"second").unwrap();
+            }
+            let wait = tokio::time::timeout(Duration::from_millis(200), source_events.recv());
+            if let Ok(Ok(SyncEvent::LocalChangeDetected { .. })) = wait.await {
+                break;
+            }
+        }
+    })
+    .await
+    .expect("local change was not detected");
+
+    // A pull only starts once another one is done with its slot.
+    let wanted: Vec<String> = names
+        .iter()
+        .map(|name| shared.join(name).to_string_lossy().into_owned())
+        .collect();
+    let mut completed = Vec::new();
+    let (mut running, mut most) = (0, 0);
+    while !wanted.iter().all(|path| completed.contains(path)) {
+        match next_event(&mut puller_events).await {
+            SyncEvent::PullStarted { .. } => {
+                running += 1;
+                most = most.max(running);
+            }
+            SyncEvent::PullCompleted { path, .. } => {
+                running -= 1;
+                completed.push(path);
+            }
+            SyncEvent::PullFailed { path, error, .. } => {
+                panic!("pull of {} failed: {}", path, error)
+            }
+            _ => {}
+        }
+    }
+    assert!(most <= LIMIT, "{} pulls ran at once", most);
+    for name in &names {
+        assert_eq!(std::fs::read(pulled.join(name)).unwrap(), b"second");
+    }
+
+    for (shutdown, task) in servers {
+        let _ = shutdown.send(());
+        task.await.unwrap().unwrap();
+    }
+}
+
+#[tokio::test]
+async fn concurrent_downloads_stay_within_the_buffer_cap() {
+    const CAP: usize = 3_000;
+    let mut config = test_config();
+    config.chunk_size = 1_000;
+    config.max_buffered = CAP;
+    let h = Harness::start_configured(SUPPORTED_ALPNS, config).await;
+    let files: Vec<Vec<u8>> = (0..6u8)
+        .map(|n| (0..40_000u32).map(|i| (i % 253) as u8 ^ n).collect())
+        .collect();
+    for (n, data) in files.iter().enumerate() {
+        std::fs::write(h.served.join(format!("{}.bin", n)), data).unwrap();
+    }
+
+    // The copies run at once, their reads all drawing on one budget.
+    let copies: Vec<_> = (0..files.len())
+        .map(|n| {
+            let name = format!("{}.bin", n);
+            let target = h.local.join(&name);
+            let remote = h.remote(&name);
+            let client = h.client.clone();
+            let server_id = h.server_id;
+            tokio::spawn(async move {
+                client
+                    .copy(server_id, remote, target, CopyOptions::default())
+                    .await
+            })
+        })
+        .collect();
+    for copy in copies {
+        copy.await.unwrap().unwrap();
+    }
+
+    for (n, data) in files.iter().enumerate() {
+        assert_eq!(
+            &std::fs::read(h.local.join(format!("{}.bin", n))).unwrap(),
+            data
+        );
+    }
+    let peak = h.server_buffers.peak();
+    assert!(peak > 0 && peak <= CAP, "peak of {} bytes", peak);
+    assert_eq!(h.server_buffers.in_flight(), 0);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn frames_being_received_count_against_the_buffer_cap() {
+    const CAP: usize = 64 * 1024;
+    let mut config = test_config();
+    config.max_buffered = CAP;
+    let h = Harness::start_configured(SUPPORTED_ALPNS, config).await;
+    let (peer, connection, _send, _recv) = raw_session(&h).await;
+
+    // A ping padded out to the whole budget.
+    let mut frame = postcard::to_stdvec(&Message::Ping { nonce: 7 }).unwrap();
+    frame.resize(CAP, 0);
+    let mut streams = Vec::new();
+    for _ in 0..2 {
+        let (mut send, mut recv) = connection.open_bi().await.unwrap();
+        write_message(&mut send, &Message::LegacyHandshake { version: 3 })
+            .await
+            .unwrap();
+        read_message(&mut recv, Duration::from_secs(10))
+            .await
+            .unwrap();
+        send.write_u32(CAP as u32).await.unwrap();
+        streams.push((send, recv));
+    }
+    let (first, second) = streams.split_at_mut(1);
+    let (first_send, first_recv) = &mut first[0];
+    let (second_send, second_recv) = &mut second[0];
+
+    // The first frame holds the budget while it arrives...
+    first_send.write_all(&frame[..CAP / 2]).await.unwrap();
+    first_send.flush().await.unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while h.server_buffers.in_flight() < CAP {
+        assert!(Instant::now() < deadline, "frame not counted");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // ...so the second isn't read until it is done.
+    second_send.write_all(&frame).await.unwrap();
+    second_send.flush().await.unwrap();
+    assert!(tokio::time::timeout(
+        Duration::from_millis(500),
+        read_message(second_recv, Duration::from_secs(10))
+    )
+    .await
+    .is_err());
+    assert_eq!(h.server_buffers.in_flight(), CAP);
+
+    first_send.write_all(&frame[CAP / 2..]).await.unwrap();
+    for recv in [first_recv, second_recv] {
+        let pong = read_message(recv, Duration::from_secs(10)).await.unwrap();
+        assert!(matches!(pong, Message::Pong { nonce: 7 }), "{:?}", pong);
+    }
+    assert_eq!(h.server_buffers.in_flight(), 0);
+    assert_eq!(h.server_buffers.peak(), CAP);
+    drop(streams);
+    drop(connection);
+    peer.close().await;
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn interrupted_copy_resumes_with_the_remaining_files() {
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir(&tree).unwrap();
+    for name in ["a.txt", "b.txt", "c.txt", "d.txt"] {
+        std::fs::write(tree.join(name), format!("{} as first written", name)).unwrap();
+    }
+    let remote = h.remote("tree");
+    let target = h.local.join("tree");
+    // A directory in the way of c.txt's download fails that file and
+    // nothing else.
+    let in_the_way = target.join("c.txt.syncr-partial");
+    std::fs::create_dir_all(in_the_way.join("in-the-way")).unwrap();
+    let opts = CopyOptions {
+        checkpoints: Some(h.client_store.clone()),
+        ..Default::default()
+    };
+
+    let err = h
+        .client
+        .copy(h.server_id, &remote, &target, opts.clone())
+        .await
+        .unwrap_err();
+    assert!(format!("{:#}", err).contains("c.txt"), "{:#}", err);
+    let done = h
+        .client_store
+        .checkpoint(&target, h.server_id, &remote)
+        .unwrap();
+    let mut completed: Vec<_> = done.keys().cloned().collect();
+    completed.sort();
+    assert_eq!(
+        completed,
+        ["a.txt", "b.txt", "d.txt"].map(|name| format!("{}/{}", remote, name))
+    );
+
+    // b.txt changes on the remote before the copy is run again.
+    std::fs::write(tree.join("b.txt"), "b.txt as rewritten").unwrap();
+    std::fs::remove_dir_all(&in_the_way).unwrap();
+    let report = h
+        .client
+        .copy(h.server_id, &remote, &target, opts)
+        .await
+        .unwrap();
+
+    assert_eq!(report.resumed, 2);
+    assert_eq!(report.stats.files_transferred, 2);
+    assert_eq!(
+        std::fs::read_to_string(target.join("b.txt")).unwrap(),
+        "b.txt as rewritten"
+    );
+    assert_eq!(
+        std::fs::read_to_string(target.join("c.txt")).unwrap(),
+        "c.txt as first written"
+    );
+    assert!(h
+        .client_store
+        .checkpoint(&target, h.server_id, &remote)
+        .unwrap()
+        .is_empty());
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn peers_see_only_the_paths_shared_with_them() {
+    let h = Harness::start().await;
+    let addrs = StaticProvider::new();
+    addrs.add_endpoint_info(h.server_addr.clone());
+    let other = SyncClient::from_endpoint(loopback_endpoint(Some(addrs)).await, test_config());
+    let docs = h.served.join("docs");
+    let photos = h.served.join("photos");
+    std::fs::create_dir(&docs).unwrap();
+    std::fs::create_dir(&photos).unwrap();
+    h.server_store
+        .allow_peer(&docs, other.id(), AccessMode::Read)
+        .unwrap();
+    h.server_store
+        .allow_peer(&photos, other.id(), AccessMode::ReadWrite)
+        .unwrap();
+
+    let ours = h.client.shares(h.server_id).await.unwrap();
+    assert_eq!(
+        ours,
+        vec![Share {
+            path: h.served.to_string_lossy().into_owned(),
+            read: true,
+            write: true,
+        }]
+    );
+    let theirs = other.shares(h.server_id).await.unwrap();
+    assert_eq!(
+        theirs,
+        vec![
+            Share {
+                path: h.remote("docs"),
+                read: true,
+                write: false,
+            },
+            Share {
+                path: h.remote("photos"),
+                read: true,
+                write: true,
+            },
+        ]
+    );
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn list_prints_the_shared_tree_without_transferring_it() {
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir_all(tree.join("nested")).unwrap();
+    std::fs::write(tree.join("a.txt"), b"first").unwrap();
+    std::fs::write(tree.join("nested/b.txt"), b"second!").unwrap();
+
+    let mut listing: Vec<_> = h
+        .client
+        .list(h.server_id, h.remote("tree"))
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|f| (f.path, f.file_type, f.len, f.hash))
+        .collect();
+    listing.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        listing
+            .iter()
+            .map(|(path, file_type, _, hash)| (path.as_str(), *file_type, *hash))
+            .collect::<Vec<_>>(),
+        vec![
+            (h.remote("tree").as_str(), FileType::Dir, None),
+            (h.remote("tree/a.txt").as_str(), FileType::File, None),
+            (h.remote("tree/nested").as_str(), FileType::Dir, None),
+            (h.remote("tree/nested/b.txt").as_str(), FileType::File, None),
+        ]
+    );
+    assert_eq!(listing[1].2, 5);
+    assert_eq!(listing[3].2, 7);
+    assert_eq!(std::fs::read_dir(&h.local).unwrap().count(), 0);
+
+    // Listing is reading, so it stops at what the peer may read.
+    let elsewhere = TempDir::new().unwrap();
+    let remote = std::fs::canonicalize(elsewhere.path()).unwrap();
+    let err = h
+        .client
+        .list(h.server_id, remote.to_string_lossy())
+        .await
+        .unwrap_err();
+    assert_eq!(remote_code(&err), Some(ErrorCode::AccessDenied));
+    h.stop().await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unreadable_entry_is_left_out_of_the_listing() {
+    let h = Harness::start().await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir(&tree).unwrap();
+    std::fs::write(tree.join("a.txt"), "first").unwrap();
+    std::fs::write(tree.join("b.txt"), "second").unwrap();
+    // A socket can be walked over but never opened, not even by root.
+    let _socket = std::os::unix::net::UnixListener::bind(tree.join("socket")).unwrap();
+
+    let target = h.local.join("tree");
+    let report = h
+        .client
+        .copy(
+            h.server_id,
+            h.remote("tree"),
+            &target,
+            CopyOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(report.unlisted, 1);
+    assert_eq!(report.stats.files_transferred, 2);
+    assert_eq!(
+        std::fs::read_to_string(target.join("a.txt")).unwrap(),
+        "first"
+    );
+    assert_eq!(
+        std::fs::read_to_string(target.join("b.txt")).unwrap(),
+        "second"
+    );
+    assert!(!target.join("socket").exists());
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn cancelled_copy_leaves_no_partial_file() {
+    let mut config = test_config();
+    config.chunk_size = 16 * 1024;
+    let h = Harness::start_configured(SUPPORTED_ALPNS, config).await;
+    let data: Vec<u8> = (0..2_000_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(h.served.join("big.bin"), &data).unwrap();
+    let target = h.local.join("big.bin");
+    let partial = h.local.join("big.bin.syncr-partial");
+
+    // Slow enough that the download is still going when it is cancelled.
+    let cancel = CancellationToken::new();
+    let opts = CopyOptions {
+        limit_rate: Some(256 * 1024),
+        cancel: cancel.clone(),
+        ..Default::default()
+    };
+    let client = h.client.clone();
+    let (server_id, remote) = (h.server_id, h.remote("big.bin"));
+    let copy = tokio::spawn({
+        let target = target.clone();
+        async move { client.copy(server_id, remote, target, opts).await }
+    });
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while std::fs::metadata(&partial).map_or(true, |m| m.len() == 0) {
+        assert!(Instant::now() < deadline, "the download never started");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    cancel.cancel();
+
+    let err = copy.await.unwrap().unwrap_err();
+    assert!(err.is::<Cancelled>(), "{:?}", err);
+    assert!(!target.exists());
+    assert!(!partial.exists());
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn one_shot_server_stops_after_its_connection() {
+    let dirs: Vec<_> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let served = std::fs::canonicalize(dirs[0].path()).unwrap();
+    std::fs::write(served.join("notes.txt"), b"once").unwrap();
+
+    let server_endpoint = loopback_endpoint(None).await;
+    let server_id = server_endpoint.id();
+    let addrs = StaticProvider::new();
+    addrs.add_endpoint_info(loopback_addr(&server_endpoint));
+    let client_endpoint = loopback_endpoint(Some(addrs)).await;
+    let store = Store::new(dirs[1].path()).unwrap();
+    store
+        .allow_peer(&served, client_endpoint.id(), AccessMode::Read)
+        .unwrap();
+    let opts = ServeOptions {
+        one_shot: true,
+        ..Default::default()
+    };
+    let server = SyncServer::from_endpoint(server_endpoint, test_config(), store, opts);
+    let task = tokio::spawn(server.run_until(std::future::pending()));
+
+    let client = SyncClient::from_endpoint(client_endpoint, test_config());
+    let target = std::fs::canonicalize(dirs[2].path())
+        .unwrap()
+        .join("notes.txt");
+    let remote = served.join("notes.txt").to_string_lossy().into_owned();
+    client
+        .copy(server_id, remote, &target, CopyOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read(&target).unwrap(), b"once");
+
+    // The copy closed its connection, so the server returns without being
+    // told to.
+    tokio::time::timeout(Duration::from_secs(10), task)
+        .await
+        .expect("one-shot server kept accepting")
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn cancelled_server_closes_its_endpoint_and_returns() {
+    let dirs: Vec<_> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let served = std::fs::canonicalize(dirs[0].path()).unwrap();
+    std::fs::write(served.join("notes.txt"), b"served").unwrap();
+
+    let server_endpoint = loopback_endpoint(None).await;
+    let server_id = server_endpoint.id();
+    let addrs = StaticProvider::new();
+    addrs.add_endpoint_info(loopback_addr(&server_endpoint));
+    let client_endpoint = loopback_endpoint(Some(addrs)).await;
+    let store = Store::new(dirs[1].path()).unwrap();
+    store
+        .allow_peer(&served, client_endpoint.id(), AccessMode::Read)
+        .unwrap();
+    let server = SyncServer::from_endpoint(
+        server_endpoint.clone(),
+        test_config(),
+        store,
+        ServeOptions::default(),
+    );
+    let cancel = CancellationToken::new();
+    let task = tokio::spawn(server.run_until(cancel.clone().cancelled_owned()));
+
+    let client = SyncClient::from_endpoint(client_endpoint, test_config());
+    let target = std::fs::canonicalize(dirs[2].path())
+        .unwrap()
+        .join("notes.txt");
+    let remote = served.join("notes.txt").to_string_lossy().into_owned();
+    client
+        .copy(server_id, remote, &target, CopyOptions::default())
+        .await
+        .unwrap();
+
+    cancel.cancel();
+    tokio::time::timeout(Duration::from_secs(10), task)
+        .await
+        .expect("server kept running after being cancelled")
+        .unwrap()
+        .unwrap();
+    assert!(server_endpoint.is_closed());
+}
+
+#[test]
+fn reaching_another_node_than_the_peer_dialed_is_refused() {
+    let intended = SecretKey::generate(&mut rand::rng()).public();
+    let reached = SecretKey::generate(&mut rand::rng()).public();
+    assert!(check_remote_id(intended, intended).is_ok());
+
+    let err = check_remote_id(intended, reached).unwrap_err();
+    assert!(
+        matches!(err, IrohUtilsError::PeerMismatch { expected, actual }
+            if expected == intended && actual == reached),
+        "{:?}",
+        err
+    );
+    let message = err.to_string();
+    assert!(message.contains(&intended.to_string()), "{}", message);
+    assert!(message.contains(&reached.to_string()), "{}", message);
+}
+
+#[tokio::test]
+async fn one_shot_server_refuses_a_peer_it_does_not_expect() {
+    let dirs: Vec<_> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let served = std::fs::canonicalize(dirs[0].path()).unwrap();
+    std::fs::write(served.join("notes.txt"), b"once").unwrap();
+
+    let server_endpoint = loopback_endpoint(None).await;
+    let server_id = server_endpoint.id();
+    let addrs = StaticProvider::new();
+    addrs.add_endpoint_info(loopback_addr(&server_endpoint));
+    let client_endpoint = loopback_endpoint(Some(addrs)).await;
+    let store = Store::new(dirs[1].path()).unwrap();
+    store
+        .allow_peer(&served, client_endpoint.id(), AccessMode::Read)
+        .unwrap();
+    let opts = ServeOptions {
+        one_shot: true,
+        expect_peer: Some(SecretKey::generate(&mut rand::rng()).public()),
+        ..Default::default()
+    };
+    let server = SyncServer::from_endpoint(server_endpoint, test_config(), store, opts);
+    let task = tokio::spawn(server.run_until(std::future::pending()));
+
+    let client = SyncClient::from_endpoint(client_endpoint, test_config());
+    let target = std::fs::canonicalize(dirs[2].path())
+        .unwrap()
+        .join("notes.txt");
+    let remote = served.join("notes.txt").to_string_lossy().into_owned();
+    assert!(client
+        .copy(server_id, remote, &target, CopyOptions::default())
+        .await
+        .is_err());
+    assert!(!target.exists());
+
+    // The refused connection was the one it served.
+    tokio::time::timeout(Duration::from_secs(10), task)
+        .await
+        .expect("one-shot server kept accepting")
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn snapshot_command_serves_the_snapshot_rather_than_the_live_tree() {
+    let snapshot_dir = TempDir::new().unwrap();
+    let snapshots = std::fs::canonicalize(snapshot_dir.path()).unwrap();
+    let frozen = snapshots.join("frozen");
+    std::fs::create_dir_all(frozen.join("tree/nested")).unwrap();
+    std::fs::write(frozen.join("tree/a.txt"), b"snapshot").unwrap();
+    std::fs::write(frozen.join("tree/nested/b.txt"), b"snapshot too").unwrap();
+
+    // Notes the root it was run for and points at the prepared copy.
+    let mut config = test_config();
+    config.snapshot_command = Some(format!(
+        "echo \"$1\" > '{0}/root' && echo '{0}/frozen'",
+        snapshots.display()
+    ));
+    let h = Harness::start_configured(SUPPORTED_ALPNS, config).await;
+    let tree = h.served.join("tree");
+    std::fs::create_dir_all(tree.join("nested")).unwrap();
+    std::fs::write(tree.join("a.txt"), b"live").unwrap();
+    std::fs::write(tree.join("nested/b.txt"), b"live too").unwrap();
+
+    let target = h.local.join("tree");
+    h.copy(&h.remote("tree"), &target).await.unwrap();
+    assert_eq!(std::fs::read(target.join("a.txt")).unwrap(), b"snapshot");
+    assert_eq!(
+        std::fs::read(target.join("nested/b.txt")).unwrap(),
+        b"snapshot too"
+    );
+    let root = std::fs::read_to_string(snapshots.join("root")).unwrap();
+    assert_eq!(root.trim_end(), h.served.to_string_lossy());
+    h.stop().await;
+}
@@ -0,0 +1,78 @@
+//! `syncr watch` adds and removes several watches in one go.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use tempfile::TempDir;
+
+use syncr::Store;
+
+fn watch(home: &Path, args: &[&Path]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .env("SYNCR_HOME", home)
+        .env_remove("SYNCR_CONFIG")
+        .arg("watch")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Three fresh directories below `home`, canonicalized as watches store them.
+fn dirs(home: &Path) -> Vec<PathBuf> {
+    ["a", "b", "c"]
+        .iter()
+        .map(|name| {
+            let dir = home.join(name);
+            std::fs::create_dir(&dir).unwrap();
+            std::fs::canonicalize(dir).unwrap()
+        })
+        .collect()
+}
+
+#[test]
+fn adds_every_path_given() {
+    let home = TempDir::new().unwrap();
+    let dirs = dirs(home.path());
+
+    let args: Vec<&Path> = dirs.iter().map(PathBuf::as_path).collect();
+    let output = watch(home.path(), &args);
+    assert!(output.status.success(), "{:?}", output);
+
+    let store = Store::new(home.path()).unwrap();
+    let mut watches = store.list_watches().unwrap();
+    watches.sort();
+    assert_eq!(watches, dirs);
+}
+
+#[test]
+fn unresolvable_path_does_not_stop_the_rest() {
+    let home = TempDir::new().unwrap();
+    let dirs = dirs(home.path());
+    // Nothing can be created below a regular file.
+    let file = home.path().join("file");
+    std::fs::write(&file, b"").unwrap();
+    let unresolvable = file.join("below");
+
+    let output = watch(home.path(), &[&dirs[0], &unresolvable, &dirs[1]]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("below"), "{}", stderr);
+
+    let store = Store::new(home.path()).unwrap();
+    let mut watches = store.list_watches().unwrap();
+    watches.sort();
+    assert_eq!(watches, dirs[..2]);
+}
+
+#[test]
+fn path_can_be_watched_before_it_exists() {
+    let home = TempDir::new().unwrap();
+    let missing = std::fs::canonicalize(home.path())
+        .unwrap()
+        .join("not/yet/created");
+
+    let output = watch(home.path(), &[&missing]);
+    assert!(output.status.success(), "{:?}", output);
+
+    let store = Store::new(home.path()).unwrap();
+    assert_eq!(store.list_watches().unwrap(), vec![missing]);
+}
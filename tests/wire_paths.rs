@@ -0,0 +1,96 @@
+//! Paths on the wire look the same whichever platform produced them, and
+//! map back to exactly the names they came from.
+
+use std::path::{Path, PathBuf};
+
+use syncr::protocol::{from_wire_path, from_wire_relative, strip_wire_prefix, to_wire_path};
+
+#[test]
+fn relative_path_round_trips() {
+    let local: PathBuf = ["dir", "sub dir", "file.txt"].iter().collect();
+    assert_eq!(to_wire_path(&local), "dir/sub dir/file.txt");
+    assert_eq!(from_wire_path("dir/sub dir/file.txt"), local);
+}
+
+#[test]
+fn absolute_path_round_trips() {
+    let local = Path::new(std::path::MAIN_SEPARATOR_STR)
+        .join("srv")
+        .join("data");
+    assert_eq!(to_wire_path(&local), "/srv/data");
+    assert_eq!(from_wire_path("/srv/data"), local);
+}
+
+#[cfg(windows)]
+#[test]
+fn windows_paths_use_forward_slashes() {
+    assert_eq!(
+        to_wire_path(Path::new(r"dir\sub\file.txt")),
+        "dir/sub/file.txt"
+    );
+    assert_eq!(to_wire_path(Path::new(r"C:\srv\data")), "/srv/data");
+    assert_eq!(
+        from_wire_path("dir/sub/file.txt"),
+        Path::new(r"dir\sub\file.txt")
+    );
+}
+
+#[test]
+fn strips_only_whole_components() {
+    assert_eq!(strip_wire_prefix("/srv/data/a/b", "/srv/data"), Some("a/b"));
+    assert_eq!(strip_wire_prefix("/srv/data/a", "/srv/data/"), Some("a"));
+    assert_eq!(strip_wire_prefix("/srv/data", "/srv/data"), Some(""));
+    assert_eq!(strip_wire_prefix("/srv/database", "/srv/data"), None);
+    assert_eq!(strip_wire_prefix("/other", "/srv/data"), None);
+}
+
+#[test]
+fn relative_path_stays_below_its_root() {
+    assert_eq!(
+        from_wire_relative("dir/file.txt"),
+        Some(Path::new("dir").join("file.txt"))
+    );
+    assert_eq!(from_wire_relative(""), Some(PathBuf::new()));
+    for escape in [
+        "..",
+        "dir/../../x",
+        "./x",
+        "/etc/passwd",
+        "%2E%2E/x",
+        "a%2F..%2F..%2Fx",
+    ] {
+        assert_eq!(from_wire_relative(escape), None, "{}", escape);
+    }
+}
+
+#[test]
+fn spaced_and_unicode_names_are_sent_as_is() {
+    let local: PathBuf = ["my docs", "données", "日本語 ファイル.txt"]
+        .iter()
+        .collect();
+    let wire = to_wire_path(&local);
+    assert_eq!(wire, "my docs/données/日本語 ファイル.txt");
+    assert_eq!(from_wire_path(&wire), local);
+}
+
+#[test]
+fn percent_signs_are_escaped() {
+    let local: PathBuf = ["100%", "%41"].iter().collect();
+    let wire = to_wire_path(&local);
+    assert_eq!(wire, "100%25/%2541");
+    assert_eq!(from_wire_path(&wire), local);
+    // Older peers send `%` unescaped; one that escapes nothing is kept.
+    assert_eq!(from_wire_path("100%/a%zz"), Path::new("100%").join("a%zz"));
+}
+
+#[cfg(unix)]
+#[test]
+fn non_utf8_names_round_trip() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let name = std::ffi::OsStr::from_bytes(b"caf\xe9 \xff.txt");
+    let local = Path::new("dir").join(name);
+    let wire = to_wire_path(&local);
+    assert_eq!(wire, "dir/caf%E9 %FF.txt");
+    assert_eq!(from_wire_path(&wire), local);
+}